@@ -0,0 +1,77 @@
+mod mirror;
+
+use crate::mirror::{verify, Remote};
+use anyhow::anyhow;
+use clap::{arg, command, ArgMatches, Command};
+use publib::append_current_path;
+
+const DEFAULT_PARALLEL: &str = "4";
+
+/// Verify local mirror against signed manifest of server, exit with error if any file is
+/// still broken
+async fn verify_command(command: &ArgMatches) -> anyhow::Result<()> {
+    let token = match command.get_one::<String>("token-file") {
+        Some(path) => Some(
+            tokio::fs::read_to_string(path)
+                .await
+                .map_err(|e| anyhow!("Unable to read token file: {:?}", e))?
+                .trim()
+                .to_string(),
+        ),
+        None => None,
+    };
+    let secret = tokio::fs::read_to_string(command.get_one::<String>("secret-file").unwrap())
+        .await
+        .map_err(|e| anyhow!("Unable to read secret file: {:?}", e))?;
+    let remote = Remote::new(command.get_one::<String>("REMOTE").unwrap(), token)?;
+
+    let summary = verify(
+        &remote,
+        append_current_path(command.get_one::<String>("LOCAL_DIR").unwrap()),
+        secret.trim().as_bytes(),
+        *command.get_one::<usize>("parallel").unwrap(),
+        command.get_flag("repair"),
+    )
+    .await?;
+    println!(
+        "Checked {} file(s), {} failed, {} repaired",
+        summary.checked, summary.failed, summary.repaired
+    );
+    if summary.failed > summary.repaired {
+        return Err(anyhow!(
+            "{} file(s) failed verification",
+            summary.failed - summary.repaired
+        ));
+    }
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let matches = command!()
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("verify")
+                .about("Check local mirror against signed manifest of server")
+                .args(&[
+                    arg!(<REMOTE> "Server address, e.g. https://example.com:8080"),
+                    arg!(<LOCAL_DIR> "Mirror of working directory of server"),
+                    arg!(--"secret-file" <PATH> "File contains signing secret of server")
+                        .required(true),
+                    arg!(--"token-file" <PATH> "File contains bearer token"),
+                    arg!(--parallel <COUNT> "Files hashed at once")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value(DEFAULT_PARALLEL),
+                    arg!(--repair "Download mismatched and missing files again"),
+                ]),
+        )
+        .get_matches();
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    match matches.subcommand() {
+        Some(("verify", command)) => runtime.block_on(verify_command(command)),
+        _ => unreachable!(),
+    }
+}
@@ -0,0 +1,208 @@
+mod remote {
+    use anyhow::anyhow;
+    use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+    use publib::types::Manifest;
+    use serde_json::Value;
+    use std::path::Path;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+    const MANIFEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+    const URL_PATH: &AsciiSet = &NON_ALPHANUMERIC
+        .remove(b'/')
+        .remove(b'-')
+        .remove(b'_')
+        .remove(b'.')
+        .remove(b'~');
+
+    /// Server at `base` (e.g. `https://example.com:8080`) accessed by bearer `token`
+    pub struct Remote {
+        client: reqwest::Client,
+        base: String,
+        token: Option<String>,
+    }
+
+    impl Remote {
+        pub fn new(base: &str, token: Option<String>) -> anyhow::Result<Self> {
+            let client = reqwest::Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .build()?;
+            Ok(Self {
+                client,
+                base: base.trim_end_matches('/').to_string(),
+                token,
+            })
+        }
+
+        fn get(&self, route: &str) -> reqwest::RequestBuilder {
+            let request = self.client.get(format!("{}{}", self.base, route));
+            match &self.token {
+                Some(token) => request.header("Authorization", format!("bearer {}", token)),
+                None => request,
+            }
+        }
+
+        /// Manifest of files authorized to token, signature is not checked here
+        pub async fn manifest(&self) -> anyhow::Result<Manifest> {
+            let response = self
+                .get("/manifest")
+                .timeout(MANIFEST_TIMEOUT)
+                .send()
+                .await
+                .map_err(|e| anyhow!("Unable to fetch manifest: {:?}", e))?;
+            let status = response.status();
+            let body = response.bytes().await?;
+            let mut body: Value = serde_json::from_slice(&body)
+                .map_err(|e| anyhow!("Unable to parse manifest response: {:?}", e))?;
+            if !status.is_success() {
+                return Err(anyhow!(
+                    "Unable to fetch manifest: {} {}",
+                    status,
+                    body["reason"].as_str().unwrap_or_default()
+                ));
+            }
+            serde_json::from_value(body["result"].take())
+                .map_err(|e| anyhow!("Unable to parse manifest: {:?}", e))
+        }
+
+        /// Download index path (`./foo/bar`) to `target`
+        pub async fn download(&self, path: &str, target: &Path) -> anyhow::Result<()> {
+            let mut response = self
+                .get(&file_route(path))
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())?;
+            let mut file = tokio::fs::File::create(target).await?;
+            while let Some(chunk) = response.chunk().await? {
+                file.write_all(&chunk).await?;
+            }
+            file.sync_all().await?;
+            Ok(())
+        }
+    }
+
+    pub(super) fn file_route(path: &str) -> String {
+        format!(
+            "/file/{}",
+            utf8_percent_encode(path.trim_start_matches("./"), URL_PATH)
+        )
+    }
+}
+
+mod verify {
+    use super::remote::Remote;
+    use anyhow::anyhow;
+    use publib::file::{get_file_hash, HashAlgo, HashOption, DEFAULT_BUFFER_SIZE};
+    use publib::mirror::{local_path, verify_manifest, verify_mirror};
+    use publib::types::{VerifyResult, VerifyState};
+    use std::path::{Path, PathBuf};
+
+    /// Files failed verification, `repaired` of them are downloaded again
+    #[derive(Debug, Default)]
+    pub struct VerifySummary {
+        pub checked: usize,
+        pub failed: usize,
+        pub repaired: usize,
+    }
+
+    /// Download `result.path()` next to its local copy, replace local copy only if hash of
+    /// download is same as manifest
+    async fn repair(remote: &Remote, root: &Path, result: &VerifyResult) -> anyhow::Result<()> {
+        let path = result.path();
+        let expected = result
+            .expected()
+            .ok_or_else(|| anyhow!("{} has no hash in manifest", path))?;
+        let target = local_path(root, path).ok_or_else(|| anyhow!("{} escapes mirror", path))?;
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let name = target
+            .file_name()
+            .ok_or_else(|| anyhow!("{} has no file name", path))?
+            .to_string_lossy();
+        let partial = target.with_file_name(format!(".{}.repair", name));
+
+        let replaced = async {
+            remote.download(path, &partial).await?;
+            let algo = HashAlgo::from_digest(expected).unwrap_or_default();
+            let actual =
+                get_file_hash(&partial, &HashOption::new(algo, DEFAULT_BUFFER_SIZE, false)).await?;
+            if actual != expected {
+                return Err(anyhow!(
+                    "Downloaded {} has hash {}, expected {}",
+                    path,
+                    actual,
+                    expected
+                ));
+            }
+            tokio::fs::rename(&partial, &target).await?;
+            Ok(())
+        }
+        .await;
+        if replaced.is_err() {
+            tokio::fs::remove_file(&partial).await.ok();
+        }
+        replaced
+    }
+
+    /// Check mirror under `root` against signed manifest of `remote`, at most `parallel`
+    /// files are hashed at once, failed files are downloaded again if `fix` is set
+    pub async fn verify(
+        remote: &Remote,
+        root: PathBuf,
+        secret: &[u8],
+        parallel: usize,
+        fix: bool,
+    ) -> anyhow::Result<VerifySummary> {
+        let manifest = remote.manifest().await?;
+        if !verify_manifest(secret, &manifest) {
+            return Err(anyhow!("Manifest signature is invalid"));
+        }
+        let results = verify_mirror(&root, manifest.into_files(), parallel).await;
+
+        let mut summary = VerifySummary {
+            checked: results.len(),
+            ..Default::default()
+        };
+        for result in results
+            .iter()
+            .filter(|result| result.state() != VerifyState::Ok)
+        {
+            summary.failed += 1;
+            println!(
+                "{:?} {} expected {} actual {}",
+                result.state(),
+                result.path(),
+                result.expected().unwrap_or("-"),
+                result.actual().unwrap_or("-")
+            );
+            if !fix {
+                continue;
+            }
+            match repair(remote, &root, result).await {
+                Ok(()) => {
+                    summary.repaired += 1;
+                    println!("Repaired {}", result.path());
+                }
+                Err(e) => eprintln!("Unable to repair {}: {:?}", result.path(), e),
+            }
+        }
+        Ok(summary)
+    }
+}
+
+pub use remote::Remote;
+pub use verify::verify;
+
+#[cfg(test)]
+mod test {
+    use super::remote::file_route;
+
+    #[test]
+    fn test_file_route() {
+        assert_eq!(file_route("./dir/a b.txt"), "/file/dir/a%20b.txt");
+        assert_eq!(file_route("./é/#?"), "/file/%C3%A9/%23%3F");
+    }
+}
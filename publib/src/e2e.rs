@@ -0,0 +1,188 @@
+mod envelope {
+    use std::path::Path;
+    use tokio::fs::File;
+    use tokio::io::AsyncReadExt;
+
+    /// Client encrypted file starts with `MAGIC`, followed by nonce and AES-256-GCM ciphertext
+    pub const MAGIC: &[u8; 8] = b"FWE2E\x00\x00\x01";
+    pub const NONCE_SIZE: usize = 12;
+
+    pub fn is_envelope(data: &[u8]) -> bool {
+        data.starts_with(MAGIC)
+    }
+
+    /// Check file is encrypted by client, server only see ciphertext of such file
+    pub async fn is_e2e_encrypted<P: AsRef<Path>>(path: P) -> Result<bool, std::io::Error> {
+        if path.as_ref().is_dir() {
+            return Ok(false);
+        }
+        let mut magic = [0u8; MAGIC.len()];
+        let mut file = File::open(path).await?;
+        match file.read_exact(&mut magic).await {
+            Ok(_) => Ok(is_envelope(&magic)),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+mod key {
+    use super::{is_envelope, MAGIC, NONCE_SIZE};
+    use aes_gcm::aead::rand_core::RngCore;
+    use aes_gcm::aead::{Aead, OsRng};
+    use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+    use serde_derive::{Deserialize, Serialize};
+
+    pub const KEY_SIZE: usize = 32;
+    /// Encrypted file name is `NAME_PREFIX` followed by hex of nonce and ciphertext
+    pub const NAME_PREFIX: &str = "e2e.";
+    const NAME_KEY_CONTEXT: &str = "fantastic-waffle 2023 e2e file name";
+
+    /// Key kept in client profile, serialized as hex string
+    #[derive(Clone, Deserialize, Serialize, PartialEq, Eq)]
+    #[serde(try_from = "String", into = "String")]
+    pub struct E2eKey([u8; KEY_SIZE]);
+
+    impl E2eKey {
+        pub fn generate() -> Self {
+            let mut key = [0u8; KEY_SIZE];
+            OsRng.fill_bytes(&mut key);
+            Self(key)
+        }
+
+        pub fn from_hex(s: &str) -> Option<Self> {
+            let s = s.trim();
+            if s.len() != KEY_SIZE * 2 || !s.is_ascii() {
+                return None;
+            }
+            let mut key = [0u8; KEY_SIZE];
+            for (i, byte) in key.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+            }
+            Some(Self(key))
+        }
+
+        pub fn to_hex(&self) -> String {
+            self.0.iter().fold(String::new(), |mut s, byte| {
+                s.push_str(&format!("{:02x}", byte));
+                s
+            })
+        }
+
+        fn cipher(&self) -> Aes256Gcm {
+            Aes256Gcm::new_from_slice(&self.0).unwrap()
+        }
+
+        fn name_key(&self) -> [u8; KEY_SIZE] {
+            blake3::derive_key(NAME_KEY_CONTEXT, &self.0)
+        }
+
+        /// Encrypt file content before upload
+        pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+            let mut nonce = [0u8; NONCE_SIZE];
+            OsRng.fill_bytes(&mut nonce);
+            let ciphertext = self
+                .cipher()
+                .encrypt(Nonce::from_slice(&nonce), plaintext)
+                .expect("AES-GCM encrypt should not fail");
+            [&MAGIC[..], &nonce, &ciphertext].concat()
+        }
+
+        /// Decrypt downloaded file content, `None` if data is not envelope or key mismatch
+        pub fn open(&self, data: &[u8]) -> Option<Vec<u8>> {
+            if !is_envelope(data) || data.len() < MAGIC.len() + NONCE_SIZE {
+                return None;
+            }
+            let (nonce, ciphertext) = data[MAGIC.len()..].split_at(NONCE_SIZE);
+            self.cipher()
+                .decrypt(Nonce::from_slice(nonce), ciphertext)
+                .ok()
+        }
+
+        /// Encrypt one file name, same name always gives same result so client can find
+        /// uploaded file again
+        ///
+        /// Nonce is keyed hash of name, so only equality of names is revealed
+        pub fn seal_name(&self, name: &str) -> String {
+            let key = self.name_key();
+            let hash = blake3::keyed_hash(&key, name.as_bytes());
+            let nonce = &hash.as_bytes()[..NONCE_SIZE];
+            let ciphertext = Aes256Gcm::new_from_slice(&key)
+                .unwrap()
+                .encrypt(Nonce::from_slice(nonce), name.as_bytes())
+                .expect("AES-GCM encrypt should not fail");
+            [nonce, &ciphertext]
+                .concat()
+                .iter()
+                .fold(NAME_PREFIX.to_string(), |mut s, byte| {
+                    s.push_str(&format!("{:02x}", byte));
+                    s
+                })
+        }
+
+        /// Decrypt file name sealed by `seal_name`, `None` if name is not sealed or key
+        /// mismatch
+        pub fn open_name(&self, name: &str) -> Option<String> {
+            let hex = name.strip_prefix(NAME_PREFIX)?;
+            if hex.len() % 2 != 0 || hex.len() < NONCE_SIZE * 2 || !hex.is_ascii() {
+                return None;
+            }
+            let data = (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                .collect::<Result<Vec<_>, _>>()
+                .ok()?;
+            let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+            let plaintext = Aes256Gcm::new_from_slice(&self.name_key())
+                .unwrap()
+                .decrypt(Nonce::from_slice(nonce), ciphertext)
+                .ok()?;
+            String::from_utf8(plaintext).ok()
+        }
+
+        /// Encrypt every name of `/` separated path, `.` and `..` are kept
+        pub fn seal_path(&self, path: &str) -> String {
+            path.split('/')
+                .map(|name| match name {
+                    "" | "." | ".." => name.to_string(),
+                    name => self.seal_name(name),
+                })
+                .collect::<Vec<_>>()
+                .join("/")
+        }
+
+        /// Decrypt path sealed by `seal_path`, `None` if any name fails to decrypt
+        pub fn open_path(&self, path: &str) -> Option<String> {
+            path.split('/')
+                .map(|name| match name {
+                    "" | "." | ".." => Some(name.to_string()),
+                    name => self.open_name(name),
+                })
+                .collect::<Option<Vec<_>>>()
+                .map(|names| names.join("/"))
+        }
+    }
+
+    impl TryFrom<String> for E2eKey {
+        type Error = String;
+
+        fn try_from(value: String) -> Result<Self, Self::Error> {
+            Self::from_hex(&value).ok_or_else(|| "Key should be 64 hex characters".to_string())
+        }
+    }
+
+    impl From<E2eKey> for String {
+        fn from(value: E2eKey) -> Self {
+            value.to_hex()
+        }
+    }
+
+    impl std::fmt::Debug for E2eKey {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str("E2eKey(..)")
+        }
+    }
+}
+
+pub use envelope::{is_e2e_encrypted, is_envelope, MAGIC, NONCE_SIZE};
+pub use key::{E2eKey, KEY_SIZE, NAME_PREFIX};
@@ -1,33 +1,249 @@
+mod algo {
+    use serde_derive::{Deserialize, Serialize};
+    use sha2::{Digest, Sha256};
+    use xxhash_rust::xxh3::Xxh3;
+
+    #[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum HashAlgo {
+        #[default]
+        Xxh3,
+        Blake3,
+        Sha256,
+    }
+
+    impl HashAlgo {
+        pub const ALL: [HashAlgo; 3] = [HashAlgo::Xxh3, HashAlgo::Blake3, HashAlgo::Sha256];
+
+        pub fn name(&self) -> &'static str {
+            match self {
+                HashAlgo::Xxh3 => "xxh3",
+                HashAlgo::Blake3 => "blake3",
+                HashAlgo::Sha256 => "sha256",
+            }
+        }
+
+        pub fn from_name(name: &str) -> Option<Self> {
+            Self::ALL.into_iter().find(|algo| algo.name() == name)
+        }
+
+        /// Algorithm of stored digest (`algo:hex`), `None` if digest has no known prefix
+        pub fn from_digest(digest: &str) -> Option<Self> {
+            Self::from_name(digest.split_once(':')?.0)
+        }
+
+        pub fn hasher(&self) -> Hasher {
+            match self {
+                HashAlgo::Xxh3 => Hasher::Xxh3(Box::new(Xxh3::new())),
+                HashAlgo::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+                HashAlgo::Sha256 => Hasher::Sha256(Sha256::new()),
+            }
+        }
+    }
+
+    /// Streaming hasher of `HashAlgo`, can be cloned to keep state
+    #[derive(Clone)]
+    pub enum Hasher {
+        Xxh3(Box<Xxh3>),
+        Blake3(Box<blake3::Hasher>),
+        Sha256(Sha256),
+    }
+
+    impl Hasher {
+        pub fn algo(&self) -> HashAlgo {
+            match self {
+                Hasher::Xxh3(_) => HashAlgo::Xxh3,
+                Hasher::Blake3(_) => HashAlgo::Blake3,
+                Hasher::Sha256(_) => HashAlgo::Sha256,
+            }
+        }
+
+        pub fn update(&mut self, data: &[u8]) {
+            match self {
+                Hasher::Xxh3(hasher) => hasher.update(data),
+                Hasher::Blake3(hasher) => {
+                    hasher.update(data);
+                }
+                Hasher::Sha256(hasher) => hasher.update(data),
+            }
+        }
+
+        /// Digest in `algo:hex` form, which is stored in database
+        pub fn digest(&self) -> String {
+            let hex = match self {
+                Hasher::Xxh3(hasher) => format!("{:016x}", hasher.digest()),
+                Hasher::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+                Hasher::Sha256(hasher) => format!("{:x}", hasher.clone().finalize()),
+            };
+            format!("{}:{}", self.algo().name(), hex)
+        }
+    }
+}
+
 mod hash {
+    use super::{HashAlgo, Hasher};
+    use serde_derive::Deserialize;
     use std::path::Path;
     use tokio::fs::File;
-    use tokio::io::AsyncReadExt;
-    use xxhash_rust::xxh3::Xxh3;
+    use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
+
+    pub const MIN_BUFFER_SIZE: usize = 64 * 1024;
+    pub const MAX_BUFFER_SIZE: usize = 256 * 1024;
+    pub const DEFAULT_BUFFER_SIZE: usize = 128 * 1024;
+
+    /// How file is hashed
+    ///
+    /// `buffer_size` is clamped into `MIN_BUFFER_SIZE..=MAX_BUFFER_SIZE`,
+    /// `buffered` wrap file with `BufReader` of same capacity,
+    /// every `chunk_size` bytes of file is hashed separately too, disabled if 0
+    #[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+    #[serde(default)]
+    pub struct HashOption {
+        algo: HashAlgo,
+        buffer_size: usize,
+        buffered: bool,
+        chunk_size: u64,
+    }
 
-    const BUFFER_SIZE: usize = 1024;
+    impl HashOption {
+        pub fn new(algo: HashAlgo, buffer_size: usize, buffered: bool) -> Self {
+            Self {
+                algo,
+                buffer_size,
+                buffered,
+                chunk_size: 0,
+            }
+        }
+        pub fn with_chunk_size(self, chunk_size: u64) -> Self {
+            Self { chunk_size, ..self }
+        }
+        pub fn algo(&self) -> HashAlgo {
+            self.algo
+        }
+        pub fn buffer_size(&self) -> usize {
+            self.buffer_size.clamp(MIN_BUFFER_SIZE, MAX_BUFFER_SIZE)
+        }
+        pub fn buffered(&self) -> bool {
+            self.buffered
+        }
+        pub fn chunk_size(&self) -> Option<u64> {
+            (self.chunk_size > 0).then_some(self.chunk_size)
+        }
+    }
 
-    pub async fn get_file_hash<P: AsRef<Path>>(path: P) -> Result<u64, std::io::Error> {
+    impl Default for HashOption {
+        fn default() -> Self {
+            Self::new(HashAlgo::default(), DEFAULT_BUFFER_SIZE, false)
+        }
+    }
+
+    /// Feed all bytes from `reader` into `hasher`, only bytes actually read are hashed
+    pub async fn update_hasher<R: AsyncRead + Unpin>(
+        hasher: &mut Hasher,
+        mut reader: R,
+        option: &HashOption,
+    ) -> Result<(), std::io::Error> {
+        let mut buffer = vec![0u8; option.buffer_size()];
+        loop {
+            let read_size = reader.read(&mut buffer).await?;
+            if read_size == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read_size]);
+        }
+        Ok(())
+    }
+
+    /// Read whole file into hasher, caller can keep feeding bytes appended to file
+    pub async fn get_file_hasher<P: AsRef<Path>>(
+        path: P,
+        option: &HashOption,
+    ) -> Result<Hasher, std::io::Error> {
+        let mut hasher = option.algo().hasher();
+        let file = File::open(path).await?;
+        if option.buffered() {
+            update_hasher(
+                &mut hasher,
+                BufReader::with_capacity(option.buffer_size(), file),
+                option,
+            )
+            .await?;
+        } else {
+            update_hasher(&mut hasher, file, option).await?;
+        }
+        Ok(hasher)
+    }
+
+    /// Digest of file in `algo:hex` form, directory has empty digest
+    pub async fn get_file_hash<P: AsRef<Path>>(
+        path: P,
+        option: &HashOption,
+    ) -> Result<String, std::io::Error> {
         if path.as_ref().is_dir() {
-            return Ok(0);
+            return Ok(String::new());
         }
-        let mut buffer = [0u8; BUFFER_SIZE];
-        let mut xxhash = Xxh3::new();
+        get_file_hasher(path, option)
+            .await
+            .map(|hasher| hasher.digest())
+    }
+
+    /// Digest of file and digests of each `chunk_size` bytes of it, read in one pass
+    ///
+    /// No chunk digest if chunking is disabled, file fitting in one chunk (empty file as well)
+    /// has one chunk digest
+    pub async fn get_file_chunks<P: AsRef<Path>>(
+        path: P,
+        option: &HashOption,
+    ) -> Result<(String, Vec<String>), std::io::Error> {
+        let Some(chunk_size) = option.chunk_size() else {
+            return get_file_hash(path, option)
+                .await
+                .map(|hash| (hash, Vec::new()));
+        };
         let mut file = File::open(path).await?;
-        while let Ok(read_size) = file.read(&mut buffer).await {
-            xxhash.update(&buffer);
-            if read_size < BUFFER_SIZE {
+        let mut hasher = option.algo().hasher();
+        let mut chunk = option.algo().hasher();
+        let mut chunk_len = 0;
+        let mut chunks = Vec::new();
+        let mut buffer = vec![0u8; option.buffer_size()];
+        loop {
+            let read_size = file.read(&mut buffer).await?;
+            if read_size == 0 {
                 break;
             }
+            hasher.update(&buffer[..read_size]);
+            let mut rest = &buffer[..read_size];
+            while !rest.is_empty() {
+                let take = rest.len().min((chunk_size - chunk_len) as usize);
+                chunk.update(&rest[..take]);
+                chunk_len += take as u64;
+                rest = &rest[take..];
+                if chunk_len == chunk_size {
+                    chunks.push(chunk.digest());
+                    chunk = option.algo().hasher();
+                    chunk_len = 0;
+                }
+            }
+        }
+        if chunk_len > 0 || chunks.is_empty() {
+            chunks.push(chunk.digest());
         }
-        Ok(xxhash.digest())
+        Ok((hasher.digest(), chunks))
     }
 
-    pub async fn get_hash<P: AsRef<Path>>(path: P) -> Result<Option<u64>, std::io::Error> {
+    pub async fn get_hash<P: AsRef<Path>>(
+        path: P,
+        option: &HashOption,
+    ) -> Result<Option<String>, std::io::Error> {
         if path.as_ref().is_dir() {
             return Ok(None);
         }
-        get_file_hash(path).await.map(|hash| Some(hash))
+        get_file_hash(path, option).await.map(Some)
     }
 }
 
-pub use hash::{get_file_hash, get_hash};
+pub use algo::{HashAlgo, Hasher};
+pub use hash::{
+    get_file_chunks, get_file_hash, get_file_hasher, get_hash, update_hasher, HashOption,
+    DEFAULT_BUFFER_SIZE, MAX_BUFFER_SIZE, MIN_BUFFER_SIZE,
+};
@@ -1,9 +1,13 @@
 #![feature(async_closure)]
 #![feature(generators)]
 
+pub mod e2e;
 pub mod file;
+pub mod mirror;
 pub mod types;
 
+use unicode_normalization::UnicodeNormalization;
+
 pub const PATH_UTF8_ERROR: &str = "Unexpect non UTF-8 path";
 
 pub fn check_penetration(path: &str) -> bool {
@@ -18,6 +22,82 @@ pub fn check_penetration(path: &str) -> bool {
     }
 }
 
+/// Convert path to the relative form stored in database (`./foo/bar`)
+///
+/// Absolute path under current directory will be stripped to relative path, other absolute
+/// path is kept absolute (`/foo/bar`), non-ASCII characters are composed (NFC), so name from
+/// macOS (NFD) is same as from other platforms
+pub fn normalize_path<P: AsRef<std::path::Path>>(path: P) -> String {
+    use std::path::Component;
+    let path = path.as_ref();
+    let current_dir = std::env::current_dir().ok();
+    let path = current_dir
+        .as_ref()
+        .and_then(|dir| path.strip_prefix(dir).ok())
+        .unwrap_or(path);
+    let components = path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(s) => Some(s.to_str().expect(PATH_UTF8_ERROR)),
+            Component::ParentDir => Some(".."),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let path = if path.has_root() {
+        format!("/{}", components.join("/"))
+    } else if components.is_empty() {
+        return ".".to_string();
+    } else {
+        format!("./{}", components.join("/"))
+    };
+    if path.is_ascii() {
+        return path;
+    }
+    path.nfc().collect()
+}
+
+/// Decomposed (NFD) form of `path`, file created on macOS keeps this name on other file system
+pub fn nfd_path(path: &str) -> String {
+    path.nfd().collect()
+}
+
+fn take_digits<I: Iterator<Item = char>>(chars: &mut std::iter::Peekable<I>) -> String {
+    let mut digits = String::new();
+    while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+        digits.push(c);
+    }
+    digits
+}
+
+/// Compare strings in natural order (`file2` before `file10`), letters compare case-insensitive
+///
+/// If two strings are equal in natural order, fallback to byte order to keep result stable
+pub fn natural_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    use std::cmp::Ordering;
+    let (mut a_chars, mut b_chars) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        let ordering = match (a_chars.peek().copied(), b_chars.peek().copied()) {
+            (None, None) => break,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (x, y) = (take_digits(&mut a_chars), take_digits(&mut b_chars));
+                let (x, y) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                x.len().cmp(&y.len()).then_with(|| x.cmp(y))
+            }
+            (Some(x), Some(y)) => {
+                a_chars.next();
+                b_chars.next();
+                x.to_lowercase().cmp(y.to_lowercase())
+            }
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.cmp(b)
+}
+
 pub fn append_current_path(path: &str) -> std::path::PathBuf {
     let mut current_dir = std::env::current_dir().unwrap();
     current_dir.push(path);
@@ -26,13 +106,391 @@ pub fn append_current_path(path: &str) -> std::path::PathBuf {
 
 #[cfg(test)]
 mod test {
-    use crate::check_penetration;
+    use crate::e2e::{is_envelope, E2eKey, NAME_PREFIX};
+    use crate::file::{
+        get_file_chunks, get_file_hash, update_hasher, HashAlgo, HashOption, MAX_BUFFER_SIZE,
+    };
+    use crate::mirror::{local_path, sign_manifest, verify_manifest, verify_mirror};
+    use crate::types::{Cursor, FileMeta, Manifest, OptionFile, Page, Pagination, VerifyState};
+    use crate::{check_penetration, natural_cmp, normalize_path};
+    use std::cmp::Ordering;
+    use xxhash_rust::xxh3::xxh3_64;
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
 
     #[test]
     fn test_path_check() {
-        assert_eq!(check_penetration("../"), false);
-        assert_eq!(check_penetration("../publib/Cargo.toml"), true);
-        assert_eq!(check_penetration("Cargo.toml"), true);
-        assert_eq!(check_penetration("../publib/src/lib.rs"), true);
+        assert!(!check_penetration("../"));
+        assert!(check_penetration("../publib/Cargo.toml"));
+        assert!(check_penetration("Cargo.toml"));
+        assert!(check_penetration("../publib/src/lib.rs"));
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        let mut current = std::env::current_dir().unwrap();
+        assert_eq!(normalize_path("Cargo.toml"), "./Cargo.toml");
+        assert_eq!(normalize_path("./src/lib.rs"), "./src/lib.rs");
+        assert_eq!(normalize_path("/src/./lib.rs"), "/src/lib.rs");
+        assert_eq!(normalize_path("/"), "/");
+        assert_eq!(normalize_path(""), ".");
+        assert_eq!(normalize_path("./cafe\u{301}"), "./caf\u{e9}");
+        current.push(".");
+        current.push("src");
+        assert_eq!(normalize_path(current), "./src");
+    }
+
+    #[test]
+    fn test_natural_cmp() {
+        assert_eq!(natural_cmp("file2", "file10"), Ordering::Less);
+        assert_eq!(natural_cmp("file10", "file2"), Ordering::Greater);
+        assert_eq!(natural_cmp("file02", "file2"), Ordering::Less);
+        assert_eq!(natural_cmp("File1", "file2"), Ordering::Less);
+        assert_eq!(natural_cmp("a/b10/c", "a/b9/c"), Ordering::Greater);
+        assert_eq!(natural_cmp("v1.10", "v1.9"), Ordering::Greater);
+        assert_eq!(natural_cmp("abc", "abc"), Ordering::Equal);
+        assert_eq!(natural_cmp("abc", "abcd"), Ordering::Less);
+
+        let mut v = vec!["file10", "file1", "File3", "file2"];
+        v.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(v, vec!["file1", "file2", "File3", "file10"]);
+    }
+
+    #[tokio::test]
+    async fn test_hash_known_digest() {
+        // Digests of `pattern(len)`, hash stored by server should never change for same content
+        for (len, digest) in [
+            (0, 3244421341483603138u64),
+            (1025, 16815387850662484078),
+            (300_000, 6706585657264128453),
+        ] {
+            let mut hasher = HashAlgo::Xxh3.hasher();
+            update_hasher(&mut hasher, &pattern(len)[..], &HashOption::default())
+                .await
+                .unwrap();
+            assert_eq!(
+                hasher.digest(),
+                format!("xxh3:{:016x}", digest),
+                "len: {}",
+                len
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hash_algo() {
+        for (algo, digest) in [
+            (HashAlgo::Xxh3, "xxh3:2d06800538d394c2"),
+            (
+                HashAlgo::Blake3,
+                "blake3:af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+            ),
+            (
+                HashAlgo::Sha256,
+                "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            ),
+        ] {
+            let option = HashOption::new(algo, 0, false);
+            let mut hasher = algo.hasher();
+            update_hasher(&mut hasher, &b""[..], &option).await.unwrap();
+            assert_eq!(hasher.digest(), digest);
+            assert_eq!(HashAlgo::from_digest(digest), Some(algo));
+            assert_eq!(HashAlgo::from_name(algo.name()), Some(algo));
+        }
+
+        let mut hasher = HashAlgo::Sha256.hasher();
+        update_hasher(&mut hasher, &b"abc"[..], &HashOption::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            hasher.digest(),
+            "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(HashAlgo::from_digest("12345"), None);
+    }
+
+    #[tokio::test]
+    async fn test_file_hash() {
+        let path = std::env::temp_dir().join(format!("publib-hash-{}", std::process::id()));
+        for len in [0, 1, 1024, 65536, 65537, MAX_BUFFER_SIZE * 2 + 7] {
+            let data = pattern(len);
+            tokio::fs::write(&path, &data).await.unwrap();
+            for option in [
+                HashOption::default(),
+                HashOption::new(HashAlgo::Xxh3, 0, true),
+                HashOption::new(HashAlgo::Xxh3, usize::MAX, false),
+            ] {
+                assert_eq!(
+                    get_file_hash(&path, &option).await.unwrap(),
+                    format!("xxh3:{:016x}", xxh3_64(&data)),
+                    "len: {}, option: {:?}",
+                    len,
+                    option
+                );
+            }
+        }
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_file_chunks() {
+        let path = std::env::temp_dir().join(format!("publib-chunks-{}", std::process::id()));
+        let option = HashOption::new(HashAlgo::Xxh3, 1000, true).with_chunk_size(1024);
+        for (len, count) in [(0, 1), (1, 1), (1024, 1), (1025, 2), (4096, 4)] {
+            let data = pattern(len);
+            tokio::fs::write(&path, &data).await.unwrap();
+            let (hash, chunks) = get_file_chunks(&path, &option).await.unwrap();
+            assert_eq!(hash, format!("xxh3:{:016x}", xxh3_64(&data)));
+            assert_eq!(chunks.len(), count, "len: {}", len);
+            for (chunk, expected) in chunks.iter().zip(data.chunks(1024)) {
+                assert_eq!(*chunk, format!("xxh3:{:016x}", xxh3_64(expected)));
+            }
+        }
+        let (_, chunks) = get_file_chunks(&path, &HashOption::default())
+            .await
+            .unwrap();
+        assert!(chunks.is_empty());
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+
+    #[test]
+    fn test_e2e_envelope() {
+        let key = E2eKey::generate();
+        let sealed = key.seal(b"secret");
+        assert!(is_envelope(&sealed));
+        assert_eq!(key.open(&sealed).as_deref(), Some(&b"secret"[..]));
+        assert_eq!(E2eKey::from_hex(&key.to_hex()), Some(key.clone()));
+
+        assert_eq!(E2eKey::generate().open(&sealed), None);
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(key.open(&tampered), None);
+        assert_eq!(key.open(b"secret"), None);
+        assert!(E2eKey::from_hex("abcd").is_none());
+    }
+
+    #[test]
+    fn test_e2e_file_name() {
+        let key = E2eKey::generate();
+        let sealed = key.seal_name("report.txt");
+        assert!(sealed.starts_with(NAME_PREFIX));
+        assert!(!sealed.contains("report"));
+        assert_eq!(key.seal_name("report.txt"), sealed);
+        assert_ne!(key.seal_name("report.txt2"), sealed);
+        assert_eq!(key.open_name(&sealed).as_deref(), Some("report.txt"));
+        assert_eq!(E2eKey::generate().open_name(&sealed), None);
+        assert_eq!(key.open_name("report.txt"), None);
+        assert_eq!(key.open_name("e2e.zz"), None);
+
+        let path = key.seal_path("./dir/sub/report.txt");
+        assert!(path.starts_with("./e2e."));
+        assert_eq!(path.split('/').count(), 4);
+        assert_eq!(
+            key.open_path(&path).as_deref(),
+            Some("./dir/sub/report.txt")
+        );
+        assert_eq!(key.open_path("./dir"), None);
+    }
+
+    #[test]
+    fn test_hash_option() {
+        assert_eq!(
+            HashOption::new(HashAlgo::Xxh3, 0, false).buffer_size(),
+            64 * 1024
+        );
+        assert_eq!(
+            HashOption::new(HashAlgo::Xxh3, usize::MAX, false).buffer_size(),
+            256 * 1024
+        );
+        assert_eq!(HashOption::default().buffer_size(), 128 * 1024);
+    }
+
+    #[test]
+    fn test_pagination_validate() {
+        let page = Pagination::default();
+        assert!(page.is_empty());
+        assert_eq!(page.limit(), Pagination::DEFAULT_LIMIT);
+        assert_eq!(page.offset(), 0);
+        assert!(page.validate().is_ok());
+
+        for limit in [1, Pagination::MAX_LIMIT] {
+            assert_eq!(
+                Pagination::new(Some(limit), None)
+                    .validate()
+                    .unwrap()
+                    .limit(),
+                limit
+            );
+        }
+        for limit in [i64::MIN, -1, 0, Pagination::MAX_LIMIT + 1] {
+            assert!(Pagination::new(Some(limit), None).validate().is_err());
+        }
+        assert_eq!(Pagination::new(Some(0), None).limit(), 1);
+        assert_eq!(
+            Pagination::new(Some(Pagination::MAX_LIMIT + 1), None).limit(),
+            Pagination::MAX_LIMIT
+        );
+
+        assert_eq!(
+            Pagination::new(None, Some(0)).validate().unwrap().offset(),
+            0
+        );
+        assert_eq!(
+            Pagination::new(None, Some(20)).validate().unwrap().offset(),
+            20
+        );
+        assert!(Pagination::new(None, Some(-1)).validate().is_err());
+        assert_eq!(Pagination::new(None, Some(-1)).offset(), 0);
+
+        let page: Pagination = serde_json::from_str(r#"{"limit":5}"#).unwrap();
+        assert!(!page.is_empty());
+        assert_eq!((page.limit(), page.offset()), (5, 0));
+        assert_eq!(
+            serde_json::to_string(&Pagination::new(Some(5), Some(10))).unwrap(),
+            r#"{"limit":5,"offset":10}"#
+        );
+    }
+
+    #[test]
+    fn test_cursor_after() {
+        let items = ["./a", "./b", "./c"].map(String::from);
+        let key = String::as_str;
+        assert_eq!(Cursor::after(&items, 3, key), Some(Cursor::new("./c")));
+        assert_eq!(Cursor::after(&items, 2, key), Some(Cursor::new("./c")));
+        assert_eq!(Cursor::after(&items, 4, key), None);
+        assert_eq!(Cursor::after(&items, 0, key), None);
+        assert_eq!(Cursor::after(&items[..0], 1, key), None);
+
+        let cursor = Cursor::new("./c");
+        assert_eq!(cursor.as_str(), "./c");
+        assert!(!cursor.is_empty());
+        assert!(Cursor::default().is_empty());
+        assert_eq!(serde_json::to_string(&cursor).unwrap(), r#""./c""#);
+        assert_eq!(serde_json::from_str::<Cursor>(r#""./c""#).unwrap(), cursor);
+        assert_eq!(String::from(cursor), "./c");
+    }
+
+    #[test]
+    fn test_page_serde() {
+        let page = Page::from_limit(
+            vec!["./a".to_string(), "./b".to_string()],
+            2,
+            String::as_str,
+        );
+        assert!(!page.is_last());
+        assert_eq!(page.next(), Some(&Cursor::new("./b")));
+        let json = r#"{"items":["./a","./b"],"next":"./b"}"#;
+        assert_eq!(serde_json::to_string(&page).unwrap(), json);
+        assert_eq!(serde_json::from_str::<Page<String>>(json).unwrap(), page);
+
+        // Cursor of page fetches items after it
+        let items = ["./a", "./b", "./c"].map(String::from);
+        let cursor: Cursor = serde_json::from_str(r#""./b""#).unwrap();
+        let rest = items
+            .iter()
+            .filter(|item| item.as_str() > cursor.as_str())
+            .cloned()
+            .collect::<Vec<_>>();
+        let last = Page::from_limit(rest, 2, String::as_str);
+        assert!(last.is_last());
+        assert_eq!(last.items(), ["./c"]);
+        assert_eq!(
+            serde_json::to_string(&last).unwrap(),
+            r#"{"items":["./c"],"next":null}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Page<String>>(r#"{"items":["./c"]}"#).unwrap(),
+            last
+        );
+        assert_eq!(
+            serde_json::to_string(&Page::<String>::default()).unwrap(),
+            r#"{"items":[],"next":null}"#
+        );
+
+        let (items, next) = page.into_parts();
+        assert_eq!(items.len(), 2);
+        assert_eq!(serde_json::to_string(&next).unwrap(), r#""./b""#);
+    }
+
+    #[tokio::test]
+    async fn test_verify_mirror() {
+        let root = std::env::temp_dir().join(format!("publib-mirror-{}", std::process::id()));
+        tokio::fs::create_dir_all(root.join("dir")).await.unwrap();
+        let option = HashOption::default();
+        let mut manifest = vec![OptionFile::new(
+            "./dir".to_string(),
+            Some(FileMeta::new(String::new(), 0, 0, true)),
+        )];
+        for (name, data) in [("ok", "same"), ("changed", "before"), ("resized", "short")] {
+            let path = root.join("dir").join(name);
+            tokio::fs::write(&path, data).await.unwrap();
+            let hash = get_file_hash(&path, &option).await.unwrap();
+            manifest.push(OptionFile::new(
+                format!("./dir/{}", name),
+                Some(FileMeta::new(hash, 0, data.len() as i64, false)),
+            ));
+        }
+        tokio::fs::write(root.join("dir/changed"), "after!")
+            .await
+            .unwrap();
+        tokio::fs::write(root.join("dir/resized"), "longer")
+            .await
+            .unwrap();
+        for path in ["./dir/missing", "../escape"] {
+            manifest.push(OptionFile::new(
+                path.to_string(),
+                Some(FileMeta::new("xxh3:0".to_string(), 0, 0, false)),
+            ));
+        }
+
+        let results = verify_mirror(&root, manifest, 2).await;
+        let states = results
+            .iter()
+            .map(|result| (result.path(), result.state()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            states,
+            [
+                ("../escape", VerifyState::Missing),
+                ("./dir/changed", VerifyState::Mismatch),
+                ("./dir/missing", VerifyState::Missing),
+                ("./dir/ok", VerifyState::Ok),
+                ("./dir/resized", VerifyState::Mismatch),
+            ]
+        );
+        assert!(results[4].actual().is_none());
+        assert_eq!(local_path(&root, "../escape"), None);
+        assert_eq!(local_path(&root, "./dir/ok"), Some(root.join("./dir/ok")));
+        tokio::fs::remove_dir_all(&root).await.unwrap();
+    }
+
+    #[test]
+    fn test_manifest_signature() {
+        let files = vec![OptionFile::new(
+            "./a".to_string(),
+            Some(FileMeta::new("xxh3:0".to_string(), 0, 1, false)),
+        )];
+        let manifest = sign_manifest(b"secret", 100, files);
+        assert!(verify_manifest(b"secret", &manifest));
+        assert!(!verify_manifest(b"other", &manifest));
+
+        // Round trip keeps signed content
+        let manifest: Manifest =
+            serde_json::from_str(&serde_json::to_string(&manifest).unwrap()).unwrap();
+        assert!(verify_manifest(b"secret", &manifest));
+
+        let tampered = Manifest::new(
+            manifest.generated_at(),
+            vec![OptionFile::new(
+                "./a".to_string(),
+                Some(FileMeta::new("xxh3:1".to_string(), 0, 1, false)),
+            )],
+            manifest.signature().to_string(),
+        );
+        assert!(!verify_manifest(b"secret", &tampered));
+        let truncated = Manifest::new(100, manifest.files().to_vec(), "abc".to_string());
+        assert!(!verify_manifest(b"secret", &truncated));
     }
 }
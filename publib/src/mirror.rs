@@ -0,0 +1,121 @@
+mod manifest {
+    use crate::types::{Manifest, OptionFile};
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    type HmacSha256 = Hmac<Sha256>;
+
+    fn mac(secret: &[u8], generated_at: i64, files: &[OptionFile]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(secret).unwrap();
+        mac.update(generated_at.to_string().as_bytes());
+        mac.update(b"\n");
+        mac.update(&serde_json::to_vec(files).unwrap());
+        mac
+    }
+
+    /// Sign `files` listed at `generated_at` (unix timestamp), signature is in hex
+    pub fn sign_manifest(secret: &[u8], generated_at: i64, files: Vec<OptionFile>) -> Manifest {
+        let signature = mac(secret, generated_at, &files)
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        Manifest::new(generated_at, files, signature)
+    }
+
+    /// Manifest is signed by `secret` and not modified since
+    pub fn verify_manifest(secret: &[u8], manifest: &Manifest) -> bool {
+        let signature = manifest.signature();
+        if !signature.len().is_multiple_of(2) || !signature.is_ascii() {
+            return false;
+        }
+        let Ok(signature) = (0..signature.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&signature[i..i + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+        else {
+            return false;
+        };
+        mac(secret, manifest.generated_at(), manifest.files())
+            .verify_slice(&signature)
+            .is_ok()
+    }
+}
+
+mod verify {
+    use crate::file::{get_file_hash, HashAlgo, HashOption, DEFAULT_BUFFER_SIZE};
+    use crate::types::{OptionFile, VerifyResult};
+    use std::path::{Component, Path, PathBuf};
+    use tokio::task::JoinSet;
+
+    /// Local path of index path (`./foo/bar`), `None` if it escapes `root`
+    pub fn local_path(root: &Path, path: &str) -> Option<PathBuf> {
+        let relative = Path::new(path);
+        relative
+            .components()
+            .all(|component| matches!(component, Component::CurDir | Component::Normal(_)))
+            .then(|| root.join(relative))
+    }
+
+    async fn verify_file(root: PathBuf, file: OptionFile) -> VerifyResult {
+        let path = file.path().to_string();
+        let Some(meta) = file.meta() else {
+            return VerifyResult::new(path, None, None);
+        };
+        let expected = Some(meta.hash().to_string());
+        let Some(local) = local_path(&root, &path) else {
+            return VerifyResult::new(path, expected, None);
+        };
+        let size = match tokio::fs::metadata(&local).await {
+            Ok(metadata) if metadata.is_file() => metadata.len(),
+            _ => return VerifyResult::new(path, expected, None),
+        };
+        // Size differs, no need to read whole file
+        if size as i64 != meta.size() {
+            return VerifyResult::size_mismatch(path, expected);
+        }
+        let algo = meta
+            .algo()
+            .or_else(|| HashAlgo::from_digest(meta.hash()))
+            .unwrap_or_default();
+        let option = HashOption::new(algo, DEFAULT_BUFFER_SIZE, false);
+        let actual = get_file_hash(&local, &option).await.ok();
+        VerifyResult::new(path, expected, actual)
+    }
+
+    /// Check local copy under `root` of every file in `manifest` (e.g. files of `Manifest`),
+    /// at most `parallel` files are read at once
+    ///
+    /// Directories are skipped, file escapes `root` is reported as missing, repair should
+    /// resolve destination by `local_path` which refuses such path
+    pub async fn verify_mirror(
+        root: &Path,
+        manifest: Vec<OptionFile>,
+        parallel: usize,
+    ) -> Vec<VerifyResult> {
+        let mut files = manifest
+            .into_iter()
+            .filter(|file| !file.meta().as_ref().is_some_and(|meta| meta.is_dir()));
+        let mut tasks = JoinSet::new();
+        let mut results = Vec::new();
+        loop {
+            while tasks.len() < parallel.max(1) {
+                let Some(file) = files.next() else {
+                    break;
+                };
+                tasks.spawn(verify_file(root.to_path_buf(), file));
+            }
+            match tasks.join_next().await {
+                Some(Ok(result)) => results.push(result),
+                Some(Err(e)) => std::panic::resume_unwind(e.into_panic()),
+                None => break,
+            }
+        }
+        results.sort_by(|a, b| a.path().cmp(b.path()));
+        results
+    }
+}
+
+pub use manifest::{sign_manifest, verify_manifest};
+pub use verify::{local_path, verify_mirror};
@@ -17,6 +17,12 @@ mod file_entry {
         mtime: i64,
         size: i64,
         is_dir: bool,
+        /// Content is encrypted by client (see `crate::e2e`), `hash` is digest of ciphertext
+        #[serde(default)]
+        encrypted: bool,
+        /// Directory only, subtree is walked by a finished scan, so empty directory is really empty
+        #[serde(default)]
+        children_scanned: bool,
     }
 
     impl FileEntry {
@@ -28,9 +34,6 @@ mod file_entry {
         }
 
         pub fn mtime(&self) -> i64 {
-            if self.is_dir {
-                return 0;
-            }
             self.mtime
         }
         pub fn size(&self) -> i64 {
@@ -42,6 +45,12 @@ mod file_entry {
         pub fn is_dir(&self) -> bool {
             self.is_dir
         }
+        pub fn encrypted(&self) -> bool {
+            self.encrypted
+        }
+        pub fn children_scanned(&self) -> bool {
+            self.children_scanned
+        }
         pub fn new<D: Display>(path: String, hash: D, mtime: i64, size: i64, is_dir: bool) -> Self {
             Self {
                 path,
@@ -49,9 +58,21 @@ mod file_entry {
                 mtime,
                 size,
                 is_dir,
+                encrypted: false,
+                children_scanned: false,
             }
         }
 
+        pub fn with_encrypted(mut self, encrypted: bool) -> Self {
+            self.encrypted = encrypted;
+            self
+        }
+
+        pub fn with_children_scanned(mut self, children_scanned: bool) -> Self {
+            self.children_scanned = children_scanned;
+            self
+        }
+
         pub fn check_hash_only(&self, other: &Self) -> bool {
             if self.is_dir {
                 return self.is_dir == other.is_dir;
@@ -136,7 +157,9 @@ mod file_entry {
                 row.try_get(2)?,
                 row.try_get(3)?,
                 row.try_get::<i32, _>(4)? != 0,
-            ))
+            )
+            .with_encrypted(row.try_get::<i32, _>("encrypted")? != 0)
+            .with_children_scanned(row.try_get::<i32, _>("children_scanned")? != 0))
         }
     }
 
@@ -144,12 +167,11 @@ mod file_entry {
         fn from(value: FileEntry) -> Self {
             Self::new(
                 value.path,
-                Some(FileMeta::new(
-                    value.hash,
-                    value.mtime,
-                    value.size,
-                    value.is_dir,
-                )),
+                Some(
+                    FileMeta::new(value.hash, value.mtime, value.size, value.is_dir)
+                        .with_encrypted(value.encrypted)
+                        .with_children_scanned(value.children_scanned),
+                ),
             )
         }
     }
@@ -157,34 +179,80 @@ mod file_entry {
     impl From<FileEntry> for FileMeta {
         fn from(value: FileEntry) -> Self {
             Self::new(value.hash, value.mtime, value.size, value.is_dir)
+                .with_encrypted(value.encrypted)
+                .with_children_scanned(value.children_scanned)
         }
     }
 }
 
 mod option_file_entry {
+    use crate::file::HashAlgo;
     use crate::types::FileEntry;
     use serde_derive::{Deserialize, Serialize};
 
     #[derive(Clone, Debug, Deserialize, Serialize)]
     pub struct FileMeta {
         hash: String,
+        /// Algorithm of `hash`, `None` for directory or digest without algorithm prefix
+        #[serde(default)]
+        algo: Option<HashAlgo>,
         mtime: i64,
         size: i64,
         is_dir: bool,
+        #[serde(default)]
+        encrypted: bool,
+        #[serde(default)]
+        children_scanned: bool,
     }
 
     impl FileMeta {
         pub fn new(hash: String, mtime: i64, size: i64, is_dir: bool) -> Self {
             Self {
+                algo: HashAlgo::from_digest(&hash),
                 hash,
                 mtime,
                 size,
                 is_dir,
+                encrypted: false,
+                children_scanned: false,
             }
         }
 
+        pub fn with_encrypted(mut self, encrypted: bool) -> Self {
+            self.encrypted = encrypted;
+            self
+        }
+
+        pub fn with_children_scanned(mut self, children_scanned: bool) -> Self {
+            self.children_scanned = children_scanned;
+            self
+        }
+
         pub fn into_file_entry(self, path: String) -> FileEntry {
             FileEntry::new(path, self.hash, self.mtime, self.size, self.is_dir)
+                .with_encrypted(self.encrypted)
+                .with_children_scanned(self.children_scanned)
+        }
+        pub fn hash(&self) -> &str {
+            &self.hash
+        }
+        pub fn algo(&self) -> Option<HashAlgo> {
+            self.algo
+        }
+        pub fn mtime(&self) -> i64 {
+            self.mtime
+        }
+        pub fn size(&self) -> i64 {
+            self.size
+        }
+        pub fn is_dir(&self) -> bool {
+            self.is_dir
+        }
+        pub fn encrypted(&self) -> bool {
+            self.encrypted
+        }
+        pub fn children_scanned(&self) -> bool {
+            self.children_scanned
         }
     }
 
@@ -196,11 +264,17 @@ mod option_file_entry {
 
     impl OptionFile {
         pub fn is_exist(&self) -> bool {
-            return self.meta.is_some();
+            self.meta.is_some()
         }
         pub fn new(path: String, meta: Option<FileMeta>) -> Self {
             Self { path, meta }
         }
+        pub fn path(&self) -> &str {
+            &self.path
+        }
+        pub fn meta(&self) -> &Option<FileMeta> {
+            &self.meta
+        }
 
         pub fn new_empty(path: String) -> Self {
             Self::new(path, None)
@@ -219,6 +293,1383 @@ mod option_file_entry {
     }
 }
 
+mod list_option {
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum SortBy {
+        #[default]
+        Path,
+        Mtime,
+        Size,
+    }
+
+    #[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum SortOrder {
+        #[default]
+        Asc,
+        Desc,
+    }
+
+    #[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum Collation {
+        /// Byte order (SQLite default)
+        #[default]
+        Binary,
+        /// Natural order, `file2` before `file10`
+        Natural,
+    }
+
+    /// Sort and filter options for listing endpoints
+    #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+    pub struct ListOption {
+        #[serde(default)]
+        sort: SortBy,
+        #[serde(default)]
+        order: SortOrder,
+        #[serde(default)]
+        collation: Collation,
+        min_size: Option<i64>,
+        max_size: Option<i64>,
+        modified_after: Option<i64>,
+    }
+
+    /// Limit and offset pagination parameters
+    #[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+    pub struct Pagination {
+        limit: Option<i64>,
+        offset: Option<i64>,
+    }
+
+    impl Pagination {
+        pub const DEFAULT_LIMIT: i64 = 100;
+        pub const MAX_LIMIT: i64 = 1000;
+
+        pub fn new(limit: Option<i64>, offset: Option<i64>) -> Self {
+            Self { limit, offset }
+        }
+        pub fn limit(&self) -> i64 {
+            self.limit
+                .unwrap_or(Self::DEFAULT_LIMIT)
+                .clamp(1, Self::MAX_LIMIT)
+        }
+        pub fn offset(&self) -> i64 {
+            self.offset.unwrap_or_default().max(0)
+        }
+        /// Neither limit nor offset is given
+        pub fn is_empty(&self) -> bool {
+            self.limit.is_none() && self.offset.is_none()
+        }
+        /// Reject values `limit` and `offset` would clamp, for endpoints answer bad request
+        pub fn validate(&self) -> Result<Self, String> {
+            if let Some(limit) = self.limit {
+                if !(1..=Self::MAX_LIMIT).contains(&limit) {
+                    return Err(format!(
+                        "limit should be between 1 and {}, got {}",
+                        Self::MAX_LIMIT,
+                        limit
+                    ));
+                }
+            }
+            if let Some(offset) = self.offset.filter(|offset| *offset < 0) {
+                return Err(format!("offset should not be negative, got {}", offset));
+            }
+            Ok(*self)
+        }
+    }
+
+    impl ListOption {
+        pub fn sort(&self) -> SortBy {
+            self.sort
+        }
+        pub fn order(&self) -> SortOrder {
+            self.order
+        }
+        pub fn collation(&self) -> Collation {
+            self.collation
+        }
+        pub fn min_size(&self) -> Option<i64> {
+            self.min_size
+        }
+        pub fn max_size(&self) -> Option<i64> {
+            self.max_size
+        }
+        pub fn modified_after(&self) -> Option<i64> {
+            self.modified_after
+        }
+    }
+}
+
+mod page {
+    use serde_derive::{Deserialize, Serialize};
+
+    /// Position after last item of page, serialized as plain string, e.g. last path
+    #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+    #[serde(transparent)]
+    pub struct Cursor(String);
+
+    impl Cursor {
+        pub fn new<S: Into<String>>(cursor: S) -> Self {
+            Self(cursor.into())
+        }
+        /// Cursor of last item if page is full, `None` if there are no more items
+        pub fn after<T, F: Fn(&T) -> &str>(items: &[T], limit: i64, key: F) -> Option<Self> {
+            items
+                .last()
+                .filter(|_| limit > 0 && items.len() as i64 >= limit)
+                .map(|item| Self::new(key(item)))
+        }
+        pub fn as_str(&self) -> &str {
+            &self.0
+        }
+        pub fn is_empty(&self) -> bool {
+            self.0.is_empty()
+        }
+    }
+
+    impl From<Cursor> for String {
+        fn from(value: Cursor) -> Self {
+            value.0
+        }
+    }
+
+    /// One page of listing, pass `next` back as `cursor` to fetch next page
+    #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+    pub struct Page<T> {
+        items: Vec<T>,
+        /// `None` if this is the last page
+        #[serde(default)]
+        next: Option<Cursor>,
+    }
+
+    impl<T> Page<T> {
+        pub fn new(items: Vec<T>, next: Option<Cursor>) -> Self {
+            Self { items, next }
+        }
+        /// Page fetched with `limit`, cursor is taken from last item if page is full
+        pub fn from_limit<F: Fn(&T) -> &str>(items: Vec<T>, limit: i64, key: F) -> Self {
+            let next = Cursor::after(&items, limit, key);
+            Self::new(items, next)
+        }
+        pub fn items(&self) -> &[T] {
+            &self.items
+        }
+        pub fn next(&self) -> Option<&Cursor> {
+            self.next.as_ref()
+        }
+        pub fn is_last(&self) -> bool {
+            self.next.is_none()
+        }
+        pub fn into_parts(self) -> (Vec<T>, Option<Cursor>) {
+            (self.items, self.next)
+        }
+    }
+
+    impl<T> Default for Page<T> {
+        fn default() -> Self {
+            Self::new(Vec::new(), None)
+        }
+    }
+}
+
+mod file_info {
+    use crate::file::HashAlgo;
+    use serde_derive::{Deserialize, Serialize};
+
+    /// Download features accepted by server for a file
+    #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+    pub struct DownloadFeatures {
+        /// `Range` requests are honored
+        ranges: bool,
+        /// `Accept-Encoding` values can be used
+        compression: Vec<String>,
+        /// Chunk manifest can be fetched for parallel download
+        chunk_manifest: bool,
+    }
+
+    impl DownloadFeatures {
+        pub fn new(ranges: bool, compression: Vec<String>, chunk_manifest: bool) -> Self {
+            Self {
+                ranges,
+                compression,
+                chunk_manifest,
+            }
+        }
+        pub fn ranges(&self) -> bool {
+            self.ranges
+        }
+        pub fn compression(&self) -> &Vec<String> {
+            &self.compression
+        }
+        pub fn chunk_manifest(&self) -> bool {
+            self.chunk_manifest
+        }
+    }
+
+    /// Everything client need to plan a resumable download
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct FileInfo {
+        path: String,
+        /// Size of downloaded content
+        size: i64,
+        hash: String,
+        algo: Option<HashAlgo>,
+        mtime: i64,
+        etag: Option<String>,
+        /// Content is encrypted by client
+        encrypted: bool,
+        features: DownloadFeatures,
+    }
+
+    impl FileInfo {
+        pub fn new(
+            path: String,
+            size: i64,
+            hash: String,
+            mtime: i64,
+            etag: Option<String>,
+            encrypted: bool,
+            features: DownloadFeatures,
+        ) -> Self {
+            Self {
+                path,
+                size,
+                algo: HashAlgo::from_digest(&hash),
+                hash,
+                mtime,
+                etag,
+                encrypted,
+                features,
+            }
+        }
+        pub fn path(&self) -> &str {
+            &self.path
+        }
+        pub fn size(&self) -> i64 {
+            self.size
+        }
+        pub fn hash(&self) -> &str {
+            &self.hash
+        }
+        pub fn algo(&self) -> Option<HashAlgo> {
+            self.algo
+        }
+        pub fn mtime(&self) -> i64 {
+            self.mtime
+        }
+        pub fn etag(&self) -> Option<&str> {
+            self.etag.as_deref()
+        }
+        pub fn encrypted(&self) -> bool {
+            self.encrypted
+        }
+        pub fn features(&self) -> &DownloadFeatures {
+            &self.features
+        }
+    }
+}
+
+mod token {
+    use serde_derive::{Deserialize, Serialize};
+
+    /// Operation group a token can be granted
+    #[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+    #[serde(rename_all = "snake_case")]
+    pub enum Scope {
+        /// Download file, archive and tail, sign download url
+        Read,
+        /// Query metadata of files, subscribe changes
+        List,
+        /// Upload or append file
+        Write,
+        Admin,
+    }
+
+    impl Scope {
+        /// Scopes granted if token not specify any, write and admin must be granted explicitly
+        pub const DEFAULT: &'static [Scope] = &[Scope::Read, Scope::List];
+    }
+
+    /// Token managed by admin API, stored in database
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct TokenInfo {
+        id: i64,
+        token: String,
+        paths: Vec<String>,
+        admin: bool,
+        concurrent_requests: Option<usize>,
+        #[serde(default)]
+        scopes: Option<Vec<Scope>>,
+        created_at: i64,
+    }
+
+    impl TokenInfo {
+        pub fn new(
+            id: i64,
+            token: String,
+            paths: Vec<String>,
+            admin: bool,
+            concurrent_requests: Option<usize>,
+            created_at: i64,
+        ) -> Self {
+            Self {
+                id,
+                token,
+                paths,
+                admin,
+                concurrent_requests,
+                scopes: None,
+                created_at,
+            }
+        }
+        pub fn id(&self) -> i64 {
+            self.id
+        }
+        pub fn token(&self) -> &str {
+            &self.token
+        }
+        pub fn paths(&self) -> &Vec<String> {
+            &self.paths
+        }
+        pub fn admin(&self) -> bool {
+            self.admin
+        }
+        pub fn concurrent_requests(&self) -> Option<usize> {
+            self.concurrent_requests
+        }
+        pub fn scopes(&self) -> Option<&Vec<Scope>> {
+            self.scopes.as_ref()
+        }
+        pub fn created_at(&self) -> i64 {
+            self.created_at
+        }
+
+        pub fn with_token(mut self, token: String) -> Self {
+            self.token = token;
+            self
+        }
+
+        pub fn with_scopes(mut self, scopes: Option<Vec<Scope>>) -> Self {
+            self.scopes = scopes;
+            self
+        }
+    }
+}
+
+mod transfer {
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum TransferKind {
+        Download,
+        Upload,
+    }
+
+    /// In-flight transfer, `token` is masked and `rate` is average byte/s since started
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct TransferStatus {
+        id: u64,
+        kind: TransferKind,
+        token: String,
+        path: String,
+        bytes: u64,
+        rate: u64,
+        started_at: i64,
+    }
+
+    impl TransferStatus {
+        pub fn new(
+            id: u64,
+            kind: TransferKind,
+            token: String,
+            path: String,
+            bytes: u64,
+            rate: u64,
+            started_at: i64,
+        ) -> Self {
+            Self {
+                id,
+                kind,
+                token,
+                path,
+                bytes,
+                rate,
+                started_at,
+            }
+        }
+        pub fn id(&self) -> u64 {
+            self.id
+        }
+        pub fn kind(&self) -> TransferKind {
+            self.kind
+        }
+        pub fn token(&self) -> &str {
+            &self.token
+        }
+        pub fn path(&self) -> &str {
+            &self.path
+        }
+        pub fn bytes(&self) -> u64 {
+            self.bytes
+        }
+        pub fn rate(&self) -> u64 {
+            self.rate
+        }
+        pub fn started_at(&self) -> i64 {
+            self.started_at
+        }
+    }
+}
+
+mod scan {
+    use serde_derive::{Deserialize, Serialize};
+
+    /// Progress of latest scan of working directory, timestamps are unix timestamp
+    #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+    pub struct ScanStatus {
+        running: bool,
+        /// `false` if index is still being built, query result is incomplete
+        ready: bool,
+        /// Entries walked
+        scanned: u64,
+        /// Files hashed, unchanged files are not hashed
+        hashed: u64,
+        /// Total size of scanned files
+        bytes: u64,
+        started_at: Option<i64>,
+        finished_at: Option<i64>,
+    }
+
+    impl ScanStatus {
+        pub fn new(
+            running: bool,
+            ready: bool,
+            scanned: u64,
+            hashed: u64,
+            bytes: u64,
+            started_at: Option<i64>,
+            finished_at: Option<i64>,
+        ) -> Self {
+            Self {
+                running,
+                ready,
+                scanned,
+                hashed,
+                bytes,
+                started_at,
+                finished_at,
+            }
+        }
+        pub fn running(&self) -> bool {
+            self.running
+        }
+        pub fn ready(&self) -> bool {
+            self.ready
+        }
+        pub fn scanned(&self) -> u64 {
+            self.scanned
+        }
+        pub fn hashed(&self) -> u64 {
+            self.hashed
+        }
+        pub fn bytes(&self) -> u64 {
+            self.bytes
+        }
+        pub fn started_at(&self) -> Option<i64> {
+            self.started_at
+        }
+        pub fn finished_at(&self) -> Option<i64> {
+            self.finished_at
+        }
+    }
+}
+
+mod stats {
+    use serde_derive::{Deserialize, Serialize};
+
+    /// Aggregate of files under directory (recursive), maintained by index
+    #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+    pub struct DirStats {
+        path: String,
+        files: i64,
+        size: i64,
+        /// Latest mtime of files, not decreased when file removed
+        mtime: i64,
+    }
+
+    impl DirStats {
+        pub fn new(path: String, files: i64, size: i64, mtime: i64) -> Self {
+            Self {
+                path,
+                files,
+                size,
+                mtime,
+            }
+        }
+        pub fn path(&self) -> &str {
+            &self.path
+        }
+        pub fn files(&self) -> i64 {
+            self.files
+        }
+        pub fn size(&self) -> i64 {
+            self.size
+        }
+        pub fn mtime(&self) -> i64 {
+            self.mtime
+        }
+    }
+}
+
+mod hold {
+    use serde_derive::{Deserialize, Serialize};
+
+    /// Legal hold, path and everything under it can't be modified or deleted through server
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct HoldInfo {
+        path: String,
+        reason: Option<String>,
+        created_at: i64,
+    }
+
+    impl HoldInfo {
+        pub fn new(path: String, reason: Option<String>, created_at: i64) -> Self {
+            Self {
+                path,
+                reason,
+                created_at,
+            }
+        }
+        pub fn path(&self) -> &str {
+            &self.path
+        }
+        pub fn reason(&self) -> Option<&str> {
+            self.reason.as_deref()
+        }
+        pub fn created_at(&self) -> i64 {
+            self.created_at
+        }
+    }
+}
+
+mod link {
+    use serde_derive::{Deserialize, Serialize};
+
+    /// Signed download URL minted by token, signature is only accepted while link is stored
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct LinkInfo {
+        id: i64,
+        /// Minted by, never sent to client
+        #[serde(skip)]
+        token: String,
+        path: String,
+        signature: String,
+        expires: i64,
+        created_at: i64,
+    }
+
+    impl LinkInfo {
+        pub fn new(
+            id: i64,
+            token: String,
+            path: String,
+            signature: String,
+            expires: i64,
+            created_at: i64,
+        ) -> Self {
+            Self {
+                id,
+                token,
+                path,
+                signature,
+                expires,
+                created_at,
+            }
+        }
+        pub fn id(&self) -> i64 {
+            self.id
+        }
+        pub fn token(&self) -> &str {
+            &self.token
+        }
+        pub fn path(&self) -> &str {
+            &self.path
+        }
+        pub fn signature(&self) -> &str {
+            &self.signature
+        }
+        pub fn expires(&self) -> i64 {
+            self.expires
+        }
+        pub fn created_at(&self) -> i64 {
+            self.created_at
+        }
+    }
+}
+
+mod manifest {
+    use crate::types::OptionFile;
+    use serde_derive::{Deserialize, Serialize};
+
+    /// Authorized files of token at `generated_at`, signed by signing secret of server
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct Manifest {
+        generated_at: i64,
+        files: Vec<OptionFile>,
+        /// HMAC-SHA256 in hex, see `mirror::sign_manifest`
+        signature: String,
+    }
+
+    impl Manifest {
+        pub fn new(generated_at: i64, files: Vec<OptionFile>, signature: String) -> Self {
+            Self {
+                generated_at,
+                files,
+                signature,
+            }
+        }
+        pub fn generated_at(&self) -> i64 {
+            self.generated_at
+        }
+        pub fn files(&self) -> &[OptionFile] {
+            &self.files
+        }
+        pub fn signature(&self) -> &str {
+            &self.signature
+        }
+        pub fn into_files(self) -> Vec<OptionFile> {
+            self.files
+        }
+    }
+}
+
+mod upload {
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum UploadState {
+        /// Written to disk, not visible to consumers yet
+        Pending,
+        /// Indexed, consumers can query it
+        Published,
+        Rejected,
+    }
+
+    impl UploadState {
+        pub fn name(&self) -> &'static str {
+            match self {
+                UploadState::Pending => "pending",
+                UploadState::Published => "published",
+                UploadState::Rejected => "rejected",
+            }
+        }
+
+        /// Unknown name is `Pending`
+        pub fn from_name(name: &str) -> Self {
+            match name {
+                "published" => UploadState::Published,
+                "rejected" => UploadState::Rejected,
+                _ => UploadState::Pending,
+            }
+        }
+    }
+
+    /// Post-processing status of uploaded file, timestamps are unix timestamp
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct UploadStatus {
+        id: u64,
+        /// Uploaded by, never sent to client
+        #[serde(skip)]
+        token: String,
+        path: String,
+        state: UploadState,
+        reason: Option<String>,
+        created_at: i64,
+        finished_at: Option<i64>,
+    }
+
+    impl UploadStatus {
+        pub fn new(id: u64, token: String, path: String, now: i64) -> Self {
+            Self {
+                id,
+                token,
+                path,
+                state: UploadState::Pending,
+                reason: None,
+                created_at: now,
+                finished_at: None,
+            }
+        }
+        /// Status loaded from database
+        pub fn with_state(
+            mut self,
+            state: UploadState,
+            reason: Option<String>,
+            finished_at: Option<i64>,
+        ) -> Self {
+            self.state = state;
+            self.reason = reason;
+            self.finished_at = finished_at;
+            self
+        }
+        pub fn id(&self) -> u64 {
+            self.id
+        }
+        pub fn token(&self) -> &str {
+            &self.token
+        }
+        pub fn path(&self) -> &str {
+            &self.path
+        }
+        pub fn state(&self) -> UploadState {
+            self.state
+        }
+        pub fn reason(&self) -> Option<&str> {
+            self.reason.as_deref()
+        }
+        pub fn created_at(&self) -> i64 {
+            self.created_at
+        }
+        pub fn finished_at(&self) -> Option<i64> {
+            self.finished_at
+        }
+        pub fn is_finished(&self) -> bool {
+            self.state != UploadState::Pending
+        }
+
+        pub fn publish(&mut self, now: i64) {
+            self.state = UploadState::Published;
+            self.finished_at = Some(now);
+        }
+        pub fn reject(&mut self, now: i64, reason: String) {
+            self.state = UploadState::Rejected;
+            self.reason = Some(reason);
+            self.finished_at = Some(now);
+        }
+    }
+}
+
+mod integrity {
+    use serde_derive::{Deserialize, Serialize};
+
+    /// Indexed file whose content changed while mtime and size did not, found by scrub,
+    /// hashes are in `algo:hex` form
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct IntegrityIssue {
+        path: String,
+        expected: String,
+        actual: String,
+        /// Unix timestamp
+        detected_at: i64,
+    }
+
+    impl IntegrityIssue {
+        pub fn new(path: String, expected: String, actual: String, detected_at: i64) -> Self {
+            Self {
+                path,
+                expected,
+                actual,
+                detected_at,
+            }
+        }
+        pub fn path(&self) -> &str {
+            &self.path
+        }
+        pub fn expected(&self) -> &str {
+            &self.expected
+        }
+        pub fn actual(&self) -> &str {
+            &self.actual
+        }
+        pub fn detected_at(&self) -> i64 {
+            self.detected_at
+        }
+    }
+}
+
+mod chunks {
+    use serde_derive::{Deserialize, Serialize};
+
+    /// Digests of each `chunk_size` bytes of file, last chunk may be shorter, sync client
+    /// downloads only chunks differ from its copy by `Range`
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct FileChunks {
+        path: String,
+        /// Digest of whole file chunks are computed from
+        hash: String,
+        size: i64,
+        chunk_size: i64,
+        chunks: Vec<String>,
+    }
+
+    impl FileChunks {
+        pub fn new(
+            path: String,
+            hash: String,
+            size: i64,
+            chunk_size: i64,
+            chunks: Vec<String>,
+        ) -> Self {
+            Self {
+                path,
+                hash,
+                size,
+                chunk_size,
+                chunks,
+            }
+        }
+        pub fn path(&self) -> &str {
+            &self.path
+        }
+        pub fn hash(&self) -> &str {
+            &self.hash
+        }
+        pub fn size(&self) -> i64 {
+            self.size
+        }
+        pub fn chunk_size(&self) -> i64 {
+            self.chunk_size
+        }
+        pub fn chunks(&self) -> &[String] {
+            &self.chunks
+        }
+    }
+}
+
+mod access {
+    use serde_derive::{Deserialize, Serialize};
+
+    /// Download of `/file`, `bytes` is less than file size if transfer is interrupted
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct AccessRecord {
+        /// Label of token, see `label` of auth entry
+        token: String,
+        path: String,
+        bytes: u64,
+        duration_ms: u64,
+        status: u16,
+        /// Unix timestamp
+        accessed_at: i64,
+    }
+
+    impl AccessRecord {
+        pub fn new(
+            token: String,
+            path: String,
+            bytes: u64,
+            duration_ms: u64,
+            status: u16,
+            accessed_at: i64,
+        ) -> Self {
+            Self {
+                token,
+                path,
+                bytes,
+                duration_ms,
+                status,
+                accessed_at,
+            }
+        }
+        pub fn token(&self) -> &str {
+            &self.token
+        }
+        pub fn path(&self) -> &str {
+            &self.path
+        }
+        pub fn bytes(&self) -> u64 {
+            self.bytes
+        }
+        pub fn duration_ms(&self) -> u64 {
+            self.duration_ms
+        }
+        pub fn status(&self) -> u16 {
+            self.status
+        }
+        pub fn accessed_at(&self) -> i64 {
+            self.accessed_at
+        }
+    }
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct FileDownloads {
+        path: String,
+        downloads: i64,
+        bytes: i64,
+    }
+
+    impl FileDownloads {
+        pub fn new(path: String, downloads: i64, bytes: i64) -> Self {
+            Self {
+                path,
+                downloads,
+                bytes,
+            }
+        }
+        pub fn path(&self) -> &str {
+            &self.path
+        }
+        pub fn downloads(&self) -> i64 {
+            self.downloads
+        }
+        pub fn bytes(&self) -> i64 {
+            self.bytes
+        }
+    }
+
+    /// Bytes sent to token in a UTC day
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct TokenUsage {
+        token: String,
+        /// Unix timestamp of start of day
+        day: i64,
+        downloads: i64,
+        bytes: i64,
+    }
+
+    impl TokenUsage {
+        pub fn new(token: String, day: i64, downloads: i64, bytes: i64) -> Self {
+            Self {
+                token,
+                day,
+                downloads,
+                bytes,
+            }
+        }
+        pub fn token(&self) -> &str {
+            &self.token
+        }
+        pub fn day(&self) -> i64 {
+            self.day
+        }
+        pub fn downloads(&self) -> i64 {
+            self.downloads
+        }
+        pub fn bytes(&self) -> i64 {
+            self.bytes
+        }
+    }
+
+    /// Aggregated access log since `since`
+    #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+    pub struct AccessStats {
+        since: i64,
+        top_files: Vec<FileDownloads>,
+        tokens: Vec<TokenUsage>,
+    }
+
+    impl AccessStats {
+        pub fn new(since: i64, top_files: Vec<FileDownloads>, tokens: Vec<TokenUsage>) -> Self {
+            Self {
+                since,
+                top_files,
+                tokens,
+            }
+        }
+        pub fn top_files(&self) -> &[FileDownloads] {
+            &self.top_files
+        }
+        pub fn tokens(&self) -> &[TokenUsage] {
+            &self.tokens
+        }
+    }
+}
+
+mod index_error {
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ErrorClass {
+        PermissionDenied,
+        /// Other I/O error, e.g. read failure of bad sector
+        Io,
+        Database,
+        Other,
+    }
+
+    impl ErrorClass {
+        pub fn name(&self) -> &'static str {
+            match self {
+                ErrorClass::PermissionDenied => "permission_denied",
+                ErrorClass::Io => "io",
+                ErrorClass::Database => "database",
+                ErrorClass::Other => "other",
+            }
+        }
+
+        /// Unknown name is `Other`
+        pub fn from_name(name: &str) -> Self {
+            match name {
+                "permission_denied" => ErrorClass::PermissionDenied,
+                "io" => ErrorClass::Io,
+                "database" => ErrorClass::Database,
+                _ => ErrorClass::Other,
+            }
+        }
+    }
+
+    /// Path file daemon failed to index, kept until it is indexed or retried successfully
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct IndexError {
+        path: String,
+        class: ErrorClass,
+        message: String,
+        /// Unix timestamp
+        occurred_at: i64,
+    }
+
+    impl IndexError {
+        pub fn new(path: String, class: ErrorClass, message: String, occurred_at: i64) -> Self {
+            Self {
+                path,
+                class,
+                message,
+                occurred_at,
+            }
+        }
+        pub fn path(&self) -> &str {
+            &self.path
+        }
+        pub fn class(&self) -> ErrorClass {
+            self.class
+        }
+        pub fn message(&self) -> &str {
+            &self.message
+        }
+        pub fn occurred_at(&self) -> i64 {
+            self.occurred_at
+        }
+    }
+}
+
+mod verify {
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum VerifyState {
+        /// Hash of file on disk is same as indexed hash
+        Ok,
+        Mismatch,
+        /// File is not on disk or not indexed
+        Missing,
+    }
+
+    /// Result of re-hashing one file, hashes are in `algo:hex` form
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct VerifyResult {
+        path: String,
+        state: VerifyState,
+        /// Indexed hash
+        expected: Option<String>,
+        /// Hash of file on disk
+        actual: Option<String>,
+    }
+
+    impl VerifyResult {
+        pub fn new(path: String, expected: Option<String>, actual: Option<String>) -> Self {
+            let state = match (&expected, &actual) {
+                (Some(expected), Some(actual)) if expected == actual => VerifyState::Ok,
+                (Some(_), Some(_)) => VerifyState::Mismatch,
+                _ => VerifyState::Missing,
+            };
+            Self {
+                path,
+                state,
+                expected,
+                actual,
+            }
+        }
+        /// Size of file on disk differs, so it is not hashed
+        pub fn size_mismatch(path: String, expected: Option<String>) -> Self {
+            Self {
+                path,
+                state: VerifyState::Mismatch,
+                expected,
+                actual: None,
+            }
+        }
+        pub fn path(&self) -> &str {
+            &self.path
+        }
+        pub fn state(&self) -> VerifyState {
+            self.state
+        }
+        pub fn expected(&self) -> Option<&str> {
+            self.expected.as_deref()
+        }
+        pub fn actual(&self) -> Option<&str> {
+            self.actual.as_deref()
+        }
+    }
+}
+
+mod job {
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum JobState {
+        Pending,
+        Running,
+        Done,
+        Failed,
+    }
+
+    /// Background job status, timestamps are unix timestamp
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct JobStatus {
+        id: u64,
+        kind: String,
+        state: JobState,
+        /// Processed entries count
+        processed: u64,
+        started_at: Option<i64>,
+        finished_at: Option<i64>,
+        error: Option<String>,
+    }
+
+    impl JobStatus {
+        pub fn new(id: u64, kind: String) -> Self {
+            Self {
+                id,
+                kind,
+                state: JobState::Pending,
+                processed: 0,
+                started_at: None,
+                finished_at: None,
+                error: None,
+            }
+        }
+        pub fn id(&self) -> u64 {
+            self.id
+        }
+        pub fn kind(&self) -> &str {
+            &self.kind
+        }
+        pub fn state(&self) -> JobState {
+            self.state
+        }
+        pub fn processed(&self) -> u64 {
+            self.processed
+        }
+        pub fn started_at(&self) -> Option<i64> {
+            self.started_at
+        }
+        pub fn finished_at(&self) -> Option<i64> {
+            self.finished_at
+        }
+        pub fn error(&self) -> Option<&str> {
+            self.error.as_deref()
+        }
+        pub fn is_finished(&self) -> bool {
+            matches!(self.state, JobState::Done | JobState::Failed)
+        }
+
+        pub fn start(&mut self, now: i64) {
+            self.state = JobState::Running;
+            self.started_at = Some(now);
+        }
+        pub fn add_processed(&mut self, count: u64) {
+            self.processed += count;
+        }
+        pub fn finish(&mut self, now: i64, error: Option<String>) {
+            self.state = if error.is_some() {
+                JobState::Failed
+            } else {
+                JobState::Done
+            };
+            self.finished_at = Some(now);
+            self.error = error;
+        }
+    }
+}
+
+mod report {
+    use serde_derive::{Deserialize, Serialize};
+
+    /// Files share same content (hash and size)
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct DuplicateGroup {
+        hash: String,
+        size: i64,
+        count: i64,
+        wasted: i64,
+        paths: Vec<String>,
+    }
+
+    impl DuplicateGroup {
+        pub fn new(hash: String, size: i64, paths: Vec<String>) -> Self {
+            let count = paths.len() as i64;
+            Self {
+                hash,
+                size,
+                count,
+                wasted: size * (count - 1).max(0),
+                paths,
+            }
+        }
+        pub fn hash(&self) -> &str {
+            &self.hash
+        }
+        pub fn size(&self) -> i64 {
+            self.size
+        }
+        pub fn count(&self) -> i64 {
+            self.count
+        }
+        pub fn wasted(&self) -> i64 {
+            self.wasted
+        }
+        pub fn paths(&self) -> &Vec<String> {
+            &self.paths
+        }
+    }
+
+    #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+    pub struct DuplicateReport {
+        total_groups: i64,
+        total_wasted: i64,
+        groups: Vec<DuplicateGroup>,
+    }
+
+    impl DuplicateReport {
+        pub fn new(total_groups: i64, total_wasted: i64, groups: Vec<DuplicateGroup>) -> Self {
+            Self {
+                total_groups,
+                total_wasted,
+                groups,
+            }
+        }
+        pub fn total_groups(&self) -> i64 {
+            self.total_groups
+        }
+        pub fn total_wasted(&self) -> i64 {
+            self.total_wasted
+        }
+        pub fn groups(&self) -> &Vec<DuplicateGroup> {
+            &self.groups
+        }
+
+        /// Keep paths matched by `f` in groups of current page, group with less than
+        /// two paths left is dropped and no longer counted
+        pub fn retain_paths<F: Fn(&str) -> bool>(&mut self, f: F) {
+            let groups = std::mem::take(&mut self.groups);
+            for group in groups {
+                let paths = group
+                    .paths
+                    .iter()
+                    .filter(|path| f(path))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                self.total_wasted -= group.wasted;
+                if paths.len() < 2 {
+                    self.total_groups -= 1;
+                    continue;
+                }
+                let group = DuplicateGroup::new(group.hash, group.size, paths);
+                self.total_wasted += group.wasted;
+                self.groups.push(group);
+            }
+        }
+    }
+}
+
+mod change_event {
+    use serde_derive::{Deserialize, Serialize};
+
+    /// File change applied by server, paths are relative to working directory (`./foo`)
+    #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    pub enum ChangeEvent {
+        New { path: String },
+        Update { path: String },
+        Remove { path: String },
+        Rename { from: String, to: String },
+    }
+
+    impl ChangeEvent {
+        pub fn paths(&self) -> Vec<&str> {
+            match self {
+                ChangeEvent::New { path }
+                | ChangeEvent::Update { path }
+                | ChangeEvent::Remove { path } => vec![path],
+                ChangeEvent::Rename { from, to } => vec![from, to],
+            }
+        }
+    }
+
+    /// Change event recorded in event log, `timestamp` is unix timestamp
+    #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+    pub struct EventRecord {
+        seq: u64,
+        timestamp: i64,
+        event: ChangeEvent,
+    }
+
+    impl EventRecord {
+        pub fn new(seq: u64, timestamp: i64, event: ChangeEvent) -> Self {
+            Self {
+                seq,
+                timestamp,
+                event,
+            }
+        }
+        pub fn seq(&self) -> u64 {
+            self.seq
+        }
+        pub fn timestamp(&self) -> i64 {
+            self.timestamp
+        }
+        pub fn event(&self) -> &ChangeEvent {
+            &self.event
+        }
+    }
+}
+
+mod ws {
+    use super::{ChangeEvent, OptionFile};
+    use serde_derive::{Deserialize, Serialize};
+
+    /// Message sent by client over `/ws`
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    pub enum WsRequest {
+        /// Query `paths`, all authorized paths is queried if `paths` is empty
+        Query {
+            id: u64,
+            #[serde(default)]
+            paths: Vec<String>,
+        },
+        /// Receive change events of authorized paths
+        Subscribe,
+        Unsubscribe,
+    }
+
+    /// Message sent by server over `/ws`, `id` is same as request
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    pub enum WsMessage {
+        Result { id: u64, files: Vec<OptionFile> },
+        Change { event: ChangeEvent },
+        Error { id: Option<u64>, reason: String },
+    }
+
+    impl WsMessage {
+        pub fn error<S: Into<String>>(id: Option<u64>, reason: S) -> Self {
+            Self::Error {
+                id,
+                reason: reason.into(),
+            }
+        }
+    }
+}
+
 mod thread_controller {
 
     #[async_trait::async_trait]
@@ -269,6 +1720,26 @@ mod thread_controller {
     }
 }
 
+pub use access::{AccessRecord, AccessStats, FileDownloads, TokenUsage};
+pub use change_event::{ChangeEvent, EventRecord};
+pub use chunks::FileChunks;
 pub use file_entry::FileEntry;
+pub use file_info::{DownloadFeatures, FileInfo};
+pub use hold::HoldInfo;
+pub use index_error::{ErrorClass, IndexError};
+pub use integrity::IntegrityIssue;
+pub use job::{JobState, JobStatus};
+pub use link::LinkInfo;
+pub use list_option::{Collation, ListOption, Pagination, SortBy, SortOrder};
+pub use manifest::Manifest;
 pub use option_file_entry::{FileMeta, OptionFile};
+pub use page::{Cursor, Page};
+pub use report::{DuplicateGroup, DuplicateReport};
+pub use scan::ScanStatus;
+pub use stats::DirStats;
 pub use thread_controller::{AsyncExitExt, ExitExt};
+pub use token::{Scope, TokenInfo};
+pub use transfer::{TransferKind, TransferStatus};
+pub use upload::{UploadState, UploadStatus};
+pub use verify::{VerifyResult, VerifyState};
+pub use ws::{WsMessage, WsRequest};
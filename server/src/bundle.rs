@@ -0,0 +1,856 @@
+mod types {
+    use publib::types::OptionFile;
+    use serde_derive::{Deserialize, Serialize};
+    use std::collections::{HashMap, HashSet};
+
+    /// Version 2 adds delta bundle, version 1 bundle is still accepted
+    pub const BUNDLE_VERSION: u32 = 2;
+    /// First entry of bundle, files are stored under `FILES_DIR` by index path
+    pub const MANIFEST_NAME: &str = "manifest.json";
+    pub const FILES_DIR: &str = "files";
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Manifest {
+        version: u32,
+        prefix: String,
+        created_at: i64,
+        /// Every file under prefix, only changed files are archived if bundle is delta
+        files: Vec<OptionFile>,
+        /// `created_at` of manifest this bundle is based on
+        #[serde(default)]
+        base: Option<i64>,
+        #[serde(default)]
+        deleted: Vec<String>,
+    }
+
+    impl Manifest {
+        pub fn new(prefix: String, created_at: i64, files: Vec<OptionFile>) -> Self {
+            Self {
+                version: BUNDLE_VERSION,
+                prefix,
+                created_at,
+                files,
+                base: None,
+                deleted: Vec::new(),
+            }
+        }
+
+        /// Turn into delta of `base`, return files added or changed since `base`
+        pub fn diff(&mut self, base: &Manifest) -> Vec<OptionFile> {
+            let previous = base
+                .files
+                .iter()
+                .map(|file| (file.path(), file.meta().as_ref().map(|meta| meta.hash())))
+                .collect::<HashMap<_, _>>();
+            let current = self
+                .files
+                .iter()
+                .map(|file| file.path())
+                .collect::<HashSet<_>>();
+            self.base = Some(base.created_at);
+            self.deleted = base
+                .files
+                .iter()
+                .map(|file| file.path())
+                .filter(|path| !current.contains(path))
+                .map(str::to_string)
+                .collect();
+            self.files
+                .iter()
+                .filter(|file| {
+                    previous.get(file.path()).copied()
+                        != Some(file.meta().as_ref().map(|meta| meta.hash()))
+                })
+                .cloned()
+                .collect()
+        }
+        pub fn version(&self) -> u32 {
+            self.version
+        }
+        pub fn prefix(&self) -> &str {
+            &self.prefix
+        }
+        pub fn is_delta(&self) -> bool {
+            self.base.is_some()
+        }
+        pub fn deleted(&self) -> &[String] {
+            &self.deleted
+        }
+        pub fn files(&self) -> &[OptionFile] {
+            &self.files
+        }
+        pub fn into_files(self) -> Vec<OptionFile> {
+            self.files
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct ImportSummary {
+        pub written: usize,
+        /// Local file is same as file in bundle
+        pub skipped: usize,
+        pub deleted: usize,
+    }
+}
+
+mod export {
+    use super::types::{Manifest, FILES_DIR, MANIFEST_NAME};
+    use crate::configure::ExcludeSet;
+    use crate::crypto::{decrypt_to, encrypt_stream, is_encrypted, plaintext_len, EncryptionKey};
+    use crate::file::unix_now;
+    use crate::mount::Mounts;
+    use anyhow::anyhow;
+    use async_compression::tokio::write::ZstdEncoder;
+    use async_walkdir::WalkDir;
+    use futures::{Future, StreamExt};
+    use publib::file::{get_file_hash, update_hasher, HashOption, DEFAULT_BUFFER_SIZE};
+    use publib::normalize_path;
+    use publib::types::{FileEntry, OptionFile};
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+    use tokio::fs::File;
+    use tokio::io::{AsyncWrite, AsyncWriteExt, DuplexStream};
+
+    /// Plaintext of file encrypted at rest, read side is fed only while returned future runs
+    fn decrypting(
+        path: &Path,
+        key: EncryptionKey,
+    ) -> (DuplexStream, impl Future<Output = std::io::Result<()>> + '_) {
+        let (mut writer, reader) = tokio::io::duplex(DEFAULT_BUFFER_SIZE);
+        let decrypt = async move {
+            decrypt_to(path, key, &mut writer).await?;
+            writer.shutdown().await
+        };
+        (reader, decrypt)
+    }
+
+    /// Key of file encrypted at rest, `None` if file is plaintext
+    async fn rest_key(
+        path: &Path,
+        key: Option<&EncryptionKey>,
+    ) -> anyhow::Result<Option<EncryptionKey>> {
+        if !is_encrypted(path).await? {
+            return Ok(None);
+        }
+        key.cloned().map(Some).ok_or_else(|| {
+            anyhow!(
+                "{} is encrypted at rest but key is not configured",
+                path.display()
+            )
+        })
+    }
+
+    /// Files under `root` with fresh hash, so bundle can be verified without index. Hash and
+    /// size of file encrypted at rest are of its plaintext, which is archived
+    async fn collect(
+        root: &Path,
+        option: &HashOption,
+        exclude: &ExcludeSet,
+        key: Option<&EncryptionKey>,
+    ) -> anyhow::Result<Vec<OptionFile>> {
+        let mut paths = Vec::new();
+        if root.is_dir() {
+            let mut entries = WalkDir::new(root);
+            while let Some(entry) = entries.next().await {
+                let entry = entry?;
+                if entry.file_type().await?.is_file() {
+                    paths.push(entry.path());
+                }
+            }
+        } else {
+            paths.push(root.to_path_buf());
+        }
+        let mut files = Vec::new();
+        for path in paths {
+            let index = Mounts::global().to_index(&path);
+            if exclude.is_match(&index) {
+                continue;
+            }
+            let metadata = tokio::fs::metadata(&path).await?;
+            let entry = match rest_key(&path, key).await? {
+                None => {
+                    let hash = get_file_hash(&path, option).await?;
+                    FileEntry::from_metadata(&index, metadata, Some(hash))
+                }
+                Some(key) => {
+                    let size = plaintext_len(metadata.len())
+                        .ok_or_else(|| anyhow!("{} is not valid encrypted file", path.display()))?;
+                    let mut hasher = option.algo().hasher();
+                    let (reader, decrypt) = decrypting(&path, key);
+                    tokio::try_join!(update_hasher(&mut hasher, reader, option), decrypt)?;
+                    FileEntry::new(index, hasher.digest(), metadata.mtime(), size as i64, false)
+                }
+            };
+            files.push(entry.into());
+        }
+        files.sort_by(|a: &OptionFile, b| a.path().cmp(b.path()));
+        Ok(files)
+    }
+
+    async fn write_bundle<W: AsyncWrite + Unpin + Send + Sync + 'static>(
+        writer: W,
+        manifest: &[u8],
+        files: &[OptionFile],
+        key: Option<&EncryptionKey>,
+    ) -> anyhow::Result<W> {
+        let mut builder = tokio_tar::Builder::new(writer);
+        let mut header = tokio_tar::Header::new_gnu();
+        header.set_size(manifest.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(unix_now() as u64);
+        builder
+            .append_data(&mut header, MANIFEST_NAME, manifest)
+            .await?;
+        for file in files {
+            let name = Path::new(FILES_DIR).join(file.path().trim_start_matches("./"));
+            let path = Mounts::global().resolve(file.path());
+            let Some(key) = rest_key(&path, key).await? else {
+                builder.append_path_with_name(&path, name).await?;
+                continue;
+            };
+            let metadata = tokio::fs::metadata(&path).await?;
+            let mut header = tokio_tar::Header::new_gnu();
+            header.set_metadata(&metadata);
+            header.set_size(
+                plaintext_len(metadata.len())
+                    .ok_or_else(|| anyhow!("{} is not valid encrypted file", path.display()))?,
+            );
+            let (reader, decrypt) = decrypting(&path, key);
+            tokio::try_join!(builder.append_data(&mut header, name, reader), decrypt)?;
+        }
+        Ok(builder.into_inner().await?)
+    }
+
+    /// Tar stream of bundle to `writer`, compressed by zstd if `compress` is set
+    async fn write_archive<W: AsyncWrite + Unpin + Send + Sync + 'static>(
+        writer: W,
+        compress: bool,
+        manifest: &[u8],
+        files: &[OptionFile],
+        key: Option<&EncryptionKey>,
+    ) -> anyhow::Result<()> {
+        if compress {
+            write_bundle(ZstdEncoder::new(writer), manifest, files, key)
+                .await?
+                .shutdown()
+                .await?;
+        } else {
+            write_bundle(writer, manifest, files, key)
+                .await?
+                .shutdown()
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// How bundle is written, plain tar of every file if nothing is set
+    #[derive(Default)]
+    pub struct ExportOption<'a> {
+        /// Compress archive with zstd
+        pub compress: bool,
+        /// Encrypt bundle while it is written
+        pub key: Option<EncryptionKey>,
+        /// Manifest of previous bundle, only files changed since it are archived
+        pub base: Option<&'a Manifest>,
+    }
+
+    /// Write files under `prefix` with manifest to `output`, return archived file count
+    ///
+    /// Only files added or changed since `base` of `export` are archived if it is set, files
+    /// removed since then are listed in manifest.
+    /// Files encrypted at rest are decrypted by `rest_key`, so bundle can be imported by
+    /// server without that key
+    pub async fn export_bundle(
+        prefix: &str,
+        output: &Path,
+        option: &HashOption,
+        exclude: &ExcludeSet,
+        rest_key: Option<&EncryptionKey>,
+        export: ExportOption<'_>,
+    ) -> anyhow::Result<usize> {
+        let ExportOption {
+            compress,
+            key,
+            base,
+        } = export;
+        let prefix = normalize_path(prefix);
+        let files = collect(
+            &Mounts::global().resolve(&prefix),
+            option,
+            exclude,
+            rest_key,
+        )
+        .await?;
+        let mut manifest = Manifest::new(prefix, unix_now(), files);
+        let files = match base {
+            Some(base) if base.prefix() != manifest.prefix() => {
+                return Err(anyhow!("Base bundle is exported from {}", base.prefix()));
+            }
+            Some(base) => manifest.diff(base),
+            None => manifest.files().to_vec(),
+        };
+        let count = files.len();
+        let manifest = serde_json::to_vec_pretty(&manifest)?;
+
+        let mut file = File::create(output).await?;
+        match key {
+            Some(key) => {
+                // Plaintext archive is never written to disk
+                let (writer, reader) = tokio::io::duplex(DEFAULT_BUFFER_SIZE);
+                tokio::try_join!(
+                    write_archive(writer, compress, &manifest, &files, rest_key),
+                    async { Ok(encrypt_stream(reader, &mut file, key).await?) }
+                )?;
+            }
+            None => write_archive(file, compress, &manifest, &files, rest_key).await?,
+        }
+        Ok(count)
+    }
+}
+
+mod import {
+    use super::types::{ImportSummary, Manifest, BUNDLE_VERSION, FILES_DIR, MANIFEST_NAME};
+    use crate::crypto::{is_encrypted, spawn_decrypt, EncryptionKey};
+    use crate::import::is_safe_relative;
+    use crate::mount::Mounts;
+    use anyhow::anyhow;
+    use async_compression::tokio::bufread::ZstdDecoder;
+    use futures::StreamExt;
+    use publib::file::{get_file_hash, HashAlgo, HashOption, DEFAULT_BUFFER_SIZE};
+    use publib::normalize_path;
+    use publib::types::FileMeta;
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+    use tokio::fs::File;
+    use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+    use tokio_tar::Entries;
+    use tracing::{info, warn};
+
+    const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+    type Reader = Box<dyn AsyncRead + Unpin + Send + Sync>;
+
+    /// Plaintext tar stream of bundle, encryption and compression are detected
+    async fn open(input: &Path, key: Option<EncryptionKey>) -> anyhow::Result<Reader> {
+        let reader: Reader = if is_encrypted(input).await? {
+            let key =
+                key.ok_or_else(|| anyhow!("Bundle is encrypted but key is not configured"))?;
+            Box::new(spawn_decrypt(input.to_path_buf(), key))
+        } else {
+            Box::new(File::open(input).await?)
+        };
+        let mut reader = BufReader::new(reader);
+        if reader.fill_buf().await?.starts_with(&ZSTD_MAGIC) {
+            Ok(Box::new(ZstdDecoder::new(reader)))
+        } else {
+            Ok(Box::new(reader))
+        }
+    }
+
+    async fn hash_of(path: &Path, meta: &FileMeta) -> std::io::Result<String> {
+        let algo = meta
+            .algo()
+            .or_else(|| HashAlgo::from_digest(meta.hash()))
+            .unwrap_or_default();
+        get_file_hash(path, &HashOption::new(algo, DEFAULT_BUFFER_SIZE, false)).await
+    }
+
+    /// Write file to `partial`, it is renamed into place once whole bundle is verified
+    async fn write_file<R: AsyncRead + Unpin>(
+        entry: &mut R,
+        target: &Path,
+        partial: &Path,
+        meta: &FileMeta,
+    ) -> anyhow::Result<()> {
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut file = File::create(partial).await?;
+        tokio::io::copy(entry, &mut file).await?;
+        file.sync_all().await?;
+        let actual = hash_of(partial, meta).await?;
+        if actual != meta.hash() {
+            return Err(anyhow!("expect {}, got {}", meta.hash(), actual));
+        }
+        Ok(())
+    }
+
+    /// Normalized `path` is `prefix` of bundle or inside it
+    fn is_in_prefix(path: &str, prefix: &str) -> bool {
+        is_safe_relative(path)
+            && normalize_path(path)
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    /// `target` with `.old` and partial suffix, so scanner ignores it until it is removed
+    fn backup_of(target: &Path, partial_suffix: &str) -> PathBuf {
+        let mut backup = target.as_os_str().to_os_string();
+        backup.push(".old");
+        backup.push(partial_suffix);
+        PathBuf::from(backup)
+    }
+
+    /// Change made to working tree, undone if bundle is not applied completely
+    struct Applied {
+        target: PathBuf,
+        /// Previous file moved aside
+        backup: Option<PathBuf>,
+        /// File of bundle is renamed to `target`
+        written: bool,
+    }
+
+    /// Return backup `target` is moved to, `None` if it does not exist
+    async fn move_aside(target: &Path, partial_suffix: &str) -> std::io::Result<Option<PathBuf>> {
+        let backup = backup_of(target, partial_suffix);
+        match tokio::fs::rename(target, &backup).await {
+            Ok(_) => Ok(Some(backup)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Rename staged `(partial, target)` files into place and move `deleted` aside, return
+    /// deleted file count. Previous files are kept as backup in `applied`
+    async fn apply(
+        staged: &[(PathBuf, PathBuf)],
+        deleted: &[PathBuf],
+        partial_suffix: &str,
+        applied: &mut Vec<Applied>,
+    ) -> std::io::Result<usize> {
+        for (partial, target) in staged {
+            let backup = move_aside(target, partial_suffix).await?;
+            applied.push(Applied {
+                target: target.clone(),
+                backup,
+                written: false,
+            });
+            tokio::fs::rename(partial, target).await?;
+            if let Some(applied) = applied.last_mut() {
+                applied.written = true;
+            }
+        }
+        let mut count = 0;
+        for target in deleted {
+            if let Some(backup) = move_aside(target, partial_suffix).await? {
+                applied.push(Applied {
+                    target: target.clone(),
+                    backup: Some(backup),
+                    written: false,
+                });
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Restore files changed by `apply` in reverse order
+    async fn rollback(applied: Vec<Applied>) {
+        for applied in applied.into_iter().rev() {
+            if applied.written {
+                if let Err(e) = tokio::fs::remove_file(&applied.target).await {
+                    warn!("Unable to remove {}: {:?}", applied.target.display(), e);
+                }
+            }
+            if let Some(backup) = applied.backup {
+                if let Err(e) = tokio::fs::rename(&backup, &applied.target).await {
+                    warn!(
+                        "Unable to restore {} from {}: {:?}",
+                        applied.target.display(),
+                        backup.display(),
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    /// Remove staged files of bundle which is not applied
+    async fn discard(staged: &[(PathBuf, PathBuf)]) {
+        for (partial, _) in staged {
+            tokio::fs::remove_file(partial).await.ok();
+        }
+    }
+
+    async fn next_manifest(entries: &mut Entries<Reader>) -> anyhow::Result<Manifest> {
+        let mut entry = entries
+            .next()
+            .await
+            .ok_or_else(|| anyhow!("Bundle is empty"))??;
+        if entry.path()?.as_ref() != Path::new(MANIFEST_NAME) {
+            return Err(anyhow!("Bundle does not start with manifest"));
+        }
+        let mut manifest = Vec::new();
+        entry.read_to_end(&mut manifest).await?;
+        let manifest = serde_json::from_slice::<Manifest>(&manifest)
+            .map_err(|e| anyhow!("Unable to parse manifest: {:?}", e))?;
+        if manifest.version() > BUNDLE_VERSION {
+            return Err(anyhow!("Unsupported bundle version {}", manifest.version()));
+        }
+        Ok(manifest)
+    }
+
+    /// Manifest of bundle, used as base of delta bundle
+    pub async fn read_manifest(
+        input: &Path,
+        key: Option<EncryptionKey>,
+    ) -> anyhow::Result<Manifest> {
+        let mut archive = tokio_tar::Archive::new(open(input, key).await?);
+        next_manifest(&mut archive.entries()?).await
+    }
+
+    /// Verify every file of bundle and stage it under `partial_suffix`, return staged
+    /// `(partial, target)` files
+    async fn stage(
+        entries: &mut Entries<Reader>,
+        mut expected: HashMap<String, FileMeta>,
+        is_delta: bool,
+        partial_suffix: &str,
+        summary: &mut ImportSummary,
+        staged: &mut Vec<(PathBuf, PathBuf)>,
+    ) -> anyhow::Result<()> {
+        while let Some(entry) = entries.next().await {
+            let mut entry = entry?;
+            let name = entry.path()?.to_path_buf();
+            let relative = name
+                .strip_prefix(FILES_DIR)
+                .ok()
+                .and_then(|path| path.to_str())
+                .filter(|path| is_safe_relative(path))
+                .ok_or_else(|| anyhow!("Unexpected entry {} in bundle", name.display()))?;
+            let path = normalize_path(relative);
+            let meta = expected
+                .remove(&path)
+                .ok_or_else(|| anyhow!("{} is not in manifest", path))?;
+            let target = Mounts::global().resolve(&path);
+            if target.is_file()
+                && hash_of(&target, &meta).await.ok().as_deref() == Some(meta.hash())
+            {
+                summary.skipped += 1;
+                continue;
+            }
+            let mut partial = target.clone().into_os_string();
+            partial.push(partial_suffix);
+            let partial = PathBuf::from(partial);
+            staged.push((partial.clone(), target.clone()));
+            write_file(&mut entry, &target, &partial, &meta)
+                .await
+                .map_err(|e| anyhow!("Unable to import {}: {:#}", path, e))?;
+            summary.written += 1;
+        }
+        // Unchanged files of delta bundle, which should be present already
+        if let Some(path) = expected
+            .keys()
+            .find(|path| !is_delta || !Mounts::global().resolve(path).is_file())
+        {
+            return Err(if is_delta {
+                anyhow!("{} is missing, base of bundle is not imported", path)
+            } else {
+                anyhow!("{} is in manifest but missing from bundle", path)
+            });
+        }
+        Ok(())
+    }
+
+    /// Extract bundle into working directory, files are written under `partial_suffix`,
+    /// so scanner indexes them once they are complete
+    ///
+    /// Only paths under prefix of bundle are written or deleted. Working tree is changed
+    /// once every file is verified, and restored if any change fails. Delta bundle is only
+    /// applied to tree at its base, files removed since base are deleted
+    pub async fn import_bundle(
+        input: &Path,
+        key: Option<EncryptionKey>,
+        partial_suffix: &str,
+    ) -> anyhow::Result<ImportSummary> {
+        let mut archive = tokio_tar::Archive::new(open(input, key).await?);
+        let mut entries = archive.entries()?;
+
+        let manifest = next_manifest(&mut entries).await?;
+        info!("Import bundle of {}", manifest.prefix());
+        let prefix = normalize_path(manifest.prefix());
+        if !is_in_prefix(&prefix, ".") {
+            return Err(anyhow!("Unexpected prefix {} of bundle", manifest.prefix()));
+        }
+        let is_delta = manifest.is_delta();
+        let mut deleted = Vec::new();
+        for path in manifest.deleted() {
+            if !is_in_prefix(path, &prefix) {
+                return Err(anyhow!("Unexpected deleted path {} in bundle", path));
+            }
+            let target = Mounts::global().resolve(path);
+            if target.is_dir() {
+                return Err(anyhow!("Deleted path {} of bundle is directory", path));
+            }
+            deleted.push(target);
+        }
+        if let Some(file) = manifest
+            .files()
+            .iter()
+            .find(|file| !is_in_prefix(file.path(), &prefix))
+        {
+            return Err(anyhow!("Unexpected path {} in manifest", file.path()));
+        }
+        let expected = manifest
+            .into_files()
+            .into_iter()
+            .filter_map(|file| Some((normalize_path(file.path()), file.meta().clone()?)))
+            .collect::<HashMap<_, _>>();
+
+        let mut summary = ImportSummary::default();
+        let mut staged = Vec::new();
+        if let Err(e) = stage(
+            &mut entries,
+            expected,
+            is_delta,
+            partial_suffix,
+            &mut summary,
+            &mut staged,
+        )
+        .await
+        {
+            discard(&staged).await;
+            return Err(e);
+        }
+
+        let mut applied = Vec::new();
+        match apply(&staged, &deleted, partial_suffix, &mut applied).await {
+            Ok(count) => summary.deleted = count,
+            Err(e) => {
+                rollback(applied).await;
+                discard(&staged).await;
+                return Err(anyhow!(
+                    "Unable to apply bundle, changes are undone: {:?}",
+                    e
+                ));
+            }
+        }
+        for backup in applied.into_iter().filter_map(|applied| applied.backup) {
+            if let Err(e) = tokio::fs::remove_file(&backup).await {
+                warn!("Unable to remove backup {}: {:?}", backup.display(), e);
+            }
+        }
+        Ok(summary)
+    }
+}
+
+pub use export::{export_bundle, ExportOption};
+pub use import::{import_bundle, read_manifest};
+
+#[cfg(test)]
+mod test {
+    use super::types::Manifest;
+    use super::{export_bundle, import_bundle, read_manifest, ExportOption};
+    use crate::configure::ExcludeSet;
+    use crate::crypto::{encrypt_file, is_encrypted, EncryptionKey};
+    use publib::file::{get_file_hash, HashOption};
+    use publib::types::{FileMeta, OptionFile};
+    use serde_json::json;
+    use std::path::Path;
+    use tokio::io::AsyncWriteExt;
+
+    /// Directory under `./target` for files created by test
+    fn test_dir(name: &str) -> String {
+        format!("./target/bundle-test-{}-{}", name, std::process::id())
+    }
+
+    fn file(path: &str, hash: &str) -> OptionFile {
+        OptionFile::new(
+            path.to_string(),
+            Some(FileMeta::new(hash.to_string(), 0, 1, false)),
+        )
+    }
+
+    /// Bundle of `manifest` and `(name, content)` entries, written as crafted by hand
+    async fn write_bundle(path: &str, manifest: serde_json::Value, entries: &[(&str, &[u8])]) {
+        let manifest = serde_json::to_vec(&manifest).unwrap();
+        let mut builder = tokio_tar::Builder::new(tokio::fs::File::create(path).await.unwrap());
+        for (name, data) in [("manifest.json", manifest.as_slice())]
+            .into_iter()
+            .chain(entries.iter().copied())
+        {
+            let mut header = tokio_tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, data).await.unwrap();
+        }
+        builder
+            .into_inner()
+            .await
+            .unwrap()
+            .shutdown()
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_manifest_diff() {
+        let base = Manifest::new(
+            "./a".to_string(),
+            100,
+            vec![
+                file("./a/same", "xxh3:01"),
+                file("./a/changed", "xxh3:02"),
+                file("./a/removed", "xxh3:03"),
+            ],
+        );
+        let mut manifest = Manifest::new(
+            "./a".to_string(),
+            200,
+            vec![
+                file("./a/same", "xxh3:01"),
+                file("./a/changed", "xxh3:12"),
+                file("./a/added", "xxh3:04"),
+            ],
+        );
+        assert!(!manifest.is_delta());
+        let changed = manifest.diff(&base);
+        assert_eq!(
+            changed.iter().map(OptionFile::path).collect::<Vec<_>>(),
+            ["./a/changed", "./a/added"]
+        );
+        assert!(manifest.is_delta());
+        assert_eq!(manifest.deleted(), ["./a/removed"]);
+        // Every file is still listed
+        assert_eq!(manifest.files().len(), 3);
+
+        // Diff of same tree is empty
+        let mut again = Manifest::new("./a".to_string(), 300, base.files().to_vec());
+        assert!(again.diff(&base).is_empty());
+        assert!(again.deleted().is_empty());
+    }
+
+    #[tokio::test]
+    async fn import_is_confined_to_prefix() {
+        let root = test_dir("confined");
+        std::fs::create_dir_all(format!("{}/prefix", root)).unwrap();
+        let victim = format!("{}/victim.txt", root);
+        std::fs::write(&victim, b"victim").unwrap();
+        let bundle = format!("{}/bundle.tar", root);
+
+        write_bundle(
+            &bundle,
+            json!({
+                "version": 2,
+                "prefix": format!("{}/prefix", root),
+                "created_at": 200,
+                "files": [],
+                "base": 100,
+                "deleted": [victim],
+            }),
+            &[],
+        )
+        .await;
+        assert!(import_bundle(bundle.as_ref(), None, ".part").await.is_err());
+
+        let name = format!("files/{}", victim.trim_start_matches("./"));
+        write_bundle(
+            &bundle,
+            json!({
+                "version": 2,
+                "prefix": format!("{}/prefix", root),
+                "created_at": 200,
+                "files": [{
+                    "path": victim,
+                    "meta": FileMeta::new("xxh3:00".to_string(), 0, 8, false),
+                }],
+            }),
+            &[(name.as_str(), b"replaced".as_slice())],
+        )
+        .await;
+        assert!(import_bundle(bundle.as_ref(), None, ".part").await.is_err());
+        assert_eq!(std::fs::read(&victim).unwrap(), b"victim");
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn failed_import_keeps_tree() {
+        let root = test_dir("atomic");
+        std::fs::create_dir_all(&root).unwrap();
+        let first = format!("{}/first.txt", root);
+        let second = format!("{}/second.txt", root);
+        std::fs::write(&first, b"new").unwrap();
+        let hash = get_file_hash(&first, &HashOption::default()).await.unwrap();
+        std::fs::write(&first, b"old").unwrap();
+        let bundle = format!("{}/bundle.tar", root);
+
+        // Second file does not match its hash, first one is verified before it
+        let entry = |path: &str| format!("files/{}", path.trim_start_matches("./"));
+        write_bundle(
+            &bundle,
+            json!({
+                "version": 2,
+                "prefix": root,
+                "created_at": 200,
+                "files": [
+                    {"path": first, "meta": FileMeta::new(hash.clone(), 0, 3, false)},
+                    {"path": second, "meta": FileMeta::new(hash, 0, 3, false)},
+                ],
+            }),
+            &[
+                (entry(&first).as_str(), b"new".as_slice()),
+                (entry(&second).as_str(), b"bad".as_slice()),
+            ],
+        )
+        .await;
+        assert!(import_bundle(bundle.as_ref(), None, ".part").await.is_err());
+        assert_eq!(std::fs::read(&first).unwrap(), b"old");
+        assert!(!Path::new(&second).exists());
+        assert!(!Path::new(&format!("{}.part", first)).exists());
+        assert!(!Path::new(&format!("{}.part", second)).exists());
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn export_import_roundtrip() {
+        let root = test_dir("roundtrip");
+        let files = format!("{}/files", root);
+        let large = vec![7u8; 200 * 1024];
+        std::fs::create_dir_all(format!("{}/sub", files)).unwrap();
+        std::fs::write(format!("{}/a.txt", files), b"a").unwrap();
+        std::fs::write(format!("{}/sub/b.bin", files), &large).unwrap();
+        let key = EncryptionKey::from_bytes(&[1u8; 32]).unwrap();
+        let encrypted = format!("{}/c.txt", files);
+        std::fs::write(&encrypted, b"encrypted at rest").unwrap();
+        encrypt_file(Path::new(&encrypted), key.clone())
+            .await
+            .unwrap();
+
+        let output = format!("{}/bundle.tar.zst", root);
+        let count = export_bundle(
+            &files,
+            output.as_ref(),
+            &HashOption::default(),
+            &ExcludeSet::default(),
+            Some(&key),
+            ExportOption {
+                compress: true,
+                key: Some(key.clone()),
+                base: None,
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(count, 3);
+        assert!(is_encrypted(&output).await.unwrap());
+        assert!(read_manifest(output.as_ref(), None).await.is_err());
+        let manifest = read_manifest(output.as_ref(), Some(key.clone()))
+            .await
+            .unwrap();
+        assert_eq!(manifest.files().len(), 3);
+
+        std::fs::remove_dir_all(&files).unwrap();
+        let summary = import_bundle(output.as_ref(), Some(key), ".part")
+            .await
+            .unwrap();
+        assert_eq!(summary.written, 3);
+        assert_eq!(std::fs::read(format!("{}/a.txt", files)).unwrap(), b"a");
+        assert_eq!(
+            std::fs::read(format!("{}/sub/b.bin", files)).unwrap(),
+            large
+        );
+        // File encrypted at rest is archived as plaintext
+        assert_eq!(std::fs::read(&encrypted).unwrap(), b"encrypted at rest");
+        std::fs::remove_dir_all(&root).ok();
+    }
+}
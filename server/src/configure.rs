@@ -1,10 +1,24 @@
 pub mod v1 {
-    use crate::configure::PoolType;
+    use crate::configure::{
+        mask_token, token_fingerprint, ExcludeSet, IpNetwork, PathMatcher, Permissions, PoolType,
+    };
+    use crate::crypto::{EncryptionKey, UrlSigner};
+    use crate::git::build_gitignore;
+    use crate::import::{is_safe_relative, ImportKind};
+    use crate::mount::Mounts;
+    use crate::server::{join_root, HashRing};
+    use crate::sink::{SinkFormat, SinkKind};
     use anyhow::anyhow;
+    use publib::file::HashOption;
+    use publib::types::{Scope, TokenInfo};
     use serde_derive::Deserialize;
     use std::collections::HashMap;
-    use std::path::Path;
+    use std::net::IpAddr;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::Duration;
     use tokio::fs::read_to_string;
+    use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer, ExposeHeaders};
 
     pub const DEFAULT_DATABASE_LOCATION: &str = "files.db";
 
@@ -12,52 +26,1166 @@ pub mod v1 {
     pub struct AuthEntry {
         token: String,
         path: Vec<String>,
+        #[serde(default)]
+        admin: bool,
+        /// Override `limits.concurrent_requests` for this token
+        concurrent_requests: Option<usize>,
+        /// Default to read and list, `admin = true` grant admin scope as well
+        scopes: Option<Vec<Scope>>,
+        /// Token sees this subtree as working directory, `path` is relative to it
+        root: Option<String>,
+        /// Token is only accepted from these networks, any if empty
+        #[serde(default)]
+        allowed_ips: Vec<IpNetwork>,
+        /// Name of token in access stats
+        label: Option<String>,
+        /// Set for token stored in database
+        #[serde(skip)]
+        id: Option<i64>,
+        #[serde(skip)]
+        matcher: PathMatcher,
+    }
+
+    impl AuthEntry {
+        pub fn new(
+            token: String,
+            path: Vec<String>,
+            admin: bool,
+            concurrent_requests: Option<usize>,
+            scopes: Option<Vec<Scope>>,
+        ) -> Result<Self, globset::Error> {
+            Self {
+                token,
+                path,
+                admin,
+                concurrent_requests,
+                scopes,
+                root: None,
+                allowed_ips: Vec::new(),
+                label: None,
+                id: None,
+                matcher: Default::default(),
+            }
+            .compile()
+        }
+
+        /// Build matcher from `path`, should be called after deserialized
+        fn compile(mut self) -> Result<Self, globset::Error> {
+            self.matcher = match self.root {
+                Some(ref root) => PathMatcher::new(
+                    &self
+                        .path
+                        .iter()
+                        .map(|path| join_root(root, path))
+                        .collect::<Vec<_>>(),
+                )?,
+                None => PathMatcher::new(&self.path)?,
+            };
+            Ok(self)
+        }
+
+        pub fn token(&self) -> &str {
+            &self.token
+        }
+        pub fn matcher(&self) -> &PathMatcher {
+            &self.matcher
+        }
+        pub fn concurrent_requests(&self) -> Option<usize> {
+            self.concurrent_requests
+        }
+        pub fn root(&self) -> Option<&str> {
+            self.root.as_deref()
+        }
+        /// Key of token in access stats, `label` if set, then id of stored token, fingerprint
+        /// of token otherwise
+        pub fn label(&self) -> String {
+            match (&self.label, self.id) {
+                (Some(label), _) => label.clone(),
+                (None, Some(id)) => format!("#{}", id),
+                (None, None) => token_fingerprint(&self.token),
+            }
+        }
+        /// Address is unknown only if server is not serving connections, e.g. in tests
+        pub fn permits(&self, ip: Option<IpAddr>) -> bool {
+            self.allowed_ips.is_empty()
+                || ip.is_some_and(|ip| self.allowed_ips.iter().any(|net| net.contains(ip)))
+        }
+
+        pub fn permissions(&self) -> Permissions {
+            let permissions = Permissions::new(self.scopes.as_deref().unwrap_or(Scope::DEFAULT));
+            if self.admin {
+                permissions.with(Scope::Admin)
+            } else {
+                permissions
+            }
+        }
+    }
+
+    pub const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 30;
+
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct Server {
+        host: String,
+        port: u16,
+        /// Seconds in-flight requests can take after shutdown started, connections still open
+        /// are aborted then, 0 aborts them immediately
+        shutdown_timeout: Option<u64>,
+    }
+
+    impl TryFrom<&TokenInfo> for AuthEntry {
+        type Error = globset::Error;
+
+        fn try_from(value: &TokenInfo) -> Result<Self, Self::Error> {
+            Ok(Self {
+                id: Some(value.id()),
+                ..Self::new(
+                    value.token().to_string(),
+                    value.paths().clone(),
+                    value.admin(),
+                    value.concurrent_requests(),
+                    value.scopes().cloned(),
+                )?
+            })
+        }
+    }
+
+    impl Server {
+        pub fn host(&self) -> &str {
+            &self.host
+        }
+        pub fn port(&self) -> u16 {
+            self.port
+        }
+
+        pub fn get_bind(&self) -> String {
+            format!("{}:{}", self.host, self.port)
+        }
+        pub fn shutdown_timeout(&self) -> Duration {
+            Duration::from_secs(self.shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT))
+        }
+
+        fn new(host: String, port: u16) -> Self {
+            Self {
+                host,
+                port,
+                shutdown_timeout: None,
+            }
+        }
+    }
+
+    impl Default for Server {
+        fn default() -> Self {
+            Self::new("127.0.0.1".to_string(), 24146)
+        }
+    }
+
+    /// Encryption at rest, `key_command` (e.g. KMS client) is used if `key_file` is not set
+    #[derive(Clone, Debug, Default, Deserialize)]
+    pub struct Encryption {
+        key_file: Option<String>,
+        key_command: Option<String>,
+    }
+
+    impl Encryption {
+        pub fn key_file(&self) -> Option<&str> {
+            self.key_file.as_deref()
+        }
+        pub fn key_command(&self) -> Option<&str> {
+            self.key_command.as_deref()
+        }
+
+        /// Return `None` if encryption is not configured
+        pub async fn load_key(&self) -> anyhow::Result<Option<EncryptionKey>> {
+            if let Some(path) = self.key_file() {
+                return EncryptionKey::from_file(path).await.map(Some);
+            }
+            if let Some(command) = self.key_command() {
+                return EncryptionKey::from_command(command).await.map(Some);
+            }
+            Ok(None)
+        }
+    }
+
+    pub const DEFAULT_MAX_EXPIRES_IN: u64 = 7 * 24 * 60 * 60;
+
+    /// Secret of signed download URL, signing is disabled if `secret` is not set
+    #[derive(Clone, Debug, Default, Deserialize)]
+    pub struct Signing {
+        secret: Option<String>,
+        max_expires_in: Option<u64>,
+    }
+
+    impl Signing {
+        pub fn max_expires_in(&self) -> u64 {
+            self.max_expires_in.unwrap_or(DEFAULT_MAX_EXPIRES_IN)
+        }
+
+        pub fn build_signer(&self) -> Option<UrlSigner> {
+            self.secret
+                .as_deref()
+                .filter(|secret| !secret.is_empty())
+                .map(|secret| UrlSigner::new(secret.as_bytes(), self.max_expires_in()))
+        }
+    }
+
+    pub const DEFAULT_RETRY_AFTER: u64 = 1;
+
+    /// Download rate limits in byte/s, simultaneous requests per token,
+    /// and load shedding thresholds of metadata requests, absent or `0` means unlimited
+    #[derive(Clone, Debug, Default, Deserialize)]
+    pub struct Limits {
+        connection_rate: Option<u64>,
+        global_rate: Option<u64>,
+        concurrent_requests: Option<usize>,
+        shed_queue_depth: Option<usize>,
+        shed_in_flight: Option<usize>,
+        retry_after: Option<u64>,
+        #[serde(default)]
+        path: PathLimits,
+    }
+
+    impl Limits {
+        pub fn connection_rate(&self) -> Option<u64> {
+            self.connection_rate.filter(|rate| *rate > 0)
+        }
+        pub fn global_rate(&self) -> Option<u64> {
+            self.global_rate.filter(|rate| *rate > 0)
+        }
+        pub fn concurrent_requests(&self) -> Option<usize> {
+            self.concurrent_requests
+        }
+        pub fn shed_queue_depth(&self) -> Option<usize> {
+            self.shed_queue_depth.filter(|depth| *depth > 0)
+        }
+        pub fn shed_in_flight(&self) -> Option<usize> {
+            self.shed_in_flight.filter(|count| *count > 0)
+        }
+        /// Seconds in `Retry-After` of shed response
+        pub fn retry_after(&self) -> u64 {
+            self.retry_after.unwrap_or(DEFAULT_RETRY_AFTER)
+        }
+        pub fn path(&self) -> PathLimits {
+            self.path
+        }
+    }
+
+    pub const DEFAULT_MAX_PATH_DEPTH: usize = 64;
+    pub const DEFAULT_MAX_NAME_LENGTH: usize = 255;
+    pub const DEFAULT_MAX_PATH_LENGTH: usize = 4096;
+
+    /// Limits of index path, lengths are in bytes
+    ///
+    /// Entries beyond them are skipped by scan and rejected by uploads
+    #[derive(Clone, Copy, Debug, Deserialize)]
+    #[serde(default)]
+    pub struct PathLimits {
+        max_depth: usize,
+        max_name_length: usize,
+        max_length: usize,
+    }
+
+    impl Default for PathLimits {
+        fn default() -> Self {
+            Self {
+                max_depth: DEFAULT_MAX_PATH_DEPTH,
+                max_name_length: DEFAULT_MAX_NAME_LENGTH,
+                max_length: DEFAULT_MAX_PATH_LENGTH,
+            }
+        }
+    }
+
+    impl PathLimits {
+        #[cfg(test)]
+        pub fn max_depth(&self) -> usize {
+            self.max_depth
+        }
+        #[cfg(test)]
+        pub fn max_name_length(&self) -> usize {
+            self.max_name_length
+        }
+
+        /// Check index path (`./foo/bar`), error is reason of rejection
+        pub fn check(&self, path: &str) -> Result<(), String> {
+            if path.len() > self.max_length {
+                return Err(format!("Path is longer than {} bytes", self.max_length));
+            }
+            let components = path
+                .strip_prefix("./")
+                .unwrap_or(path)
+                .split('/')
+                .collect::<Vec<_>>();
+            if components.len() > self.max_depth {
+                return Err(format!("Path is deeper than {} levels", self.max_depth));
+            }
+            match components
+                .iter()
+                .find(|component| component.len() > self.max_name_length)
+            {
+                Some(name) => Err(format!(
+                    "Name {:?} is longer than {} bytes",
+                    name, self.max_name_length
+                )),
+                None => Ok(()),
+            }
+        }
+    }
+
+    /// Files matched by `warm` patterns are read into page cache at startup and after updated
+    #[derive(Clone, Debug, Default, Deserialize)]
+    pub struct Cache {
+        #[serde(default)]
+        warm: Vec<String>,
+    }
+
+    impl Cache {
+        pub fn warm(&self) -> &Vec<String> {
+            &self.warm
+        }
+    }
+
+    pub const DEFAULT_EVENT_LOG_MAX_SIZE: u64 = 16 * 1024 * 1024;
+    pub const DEFAULT_EVENT_LOG_KEEP: usize = 3;
+
+    /// Log of events applied by file daemon, rotated once larger than `max_size`,
+    /// disabled if `path` is not set
+    #[derive(Clone, Debug, Default, Deserialize)]
+    pub struct EventLogOption {
+        path: Option<String>,
+        max_size: Option<u64>,
+        keep: Option<usize>,
+    }
+
+    impl EventLogOption {
+        pub fn path(&self) -> Option<&str> {
+            self.path.as_deref()
+        }
+        pub fn max_size(&self) -> u64 {
+            self.max_size.unwrap_or(DEFAULT_EVENT_LOG_MAX_SIZE)
+        }
+        /// Count of rotated files kept
+        pub fn keep(&self) -> usize {
+            self.keep.unwrap_or(DEFAULT_EVENT_LOG_KEEP)
+        }
+    }
+
+    pub const DEFAULT_ACCESS_LOG_RETENTION_DAYS: u64 = 90;
+
+    /// Downloads recorded for admin stats
+    #[derive(Clone, Copy, Debug, Default, Deserialize)]
+    pub struct AccessLogOption {
+        /// Records older than this are pruned, 0 keeps them forever
+        retention_days: Option<u64>,
+    }
+
+    impl AccessLogOption {
+        pub fn retention(&self) -> Option<Duration> {
+            match self
+                .retention_days
+                .unwrap_or(DEFAULT_ACCESS_LOG_RETENTION_DAYS)
+            {
+                0 => None,
+                days => Some(Duration::from_secs(days * 86400)),
+            }
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum LogFormat {
+        #[default]
+        Text,
+        /// One json object per line, for shipping to log aggregator
+        Json,
+    }
+
+    #[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum CaseSensitivity {
+        #[default]
+        Sensitive,
+        /// File system ignores case (e.g. macOS, Windows or casefold ext4), paths differ only
+        /// in case are the same file
+        Insensitive,
+    }
+
+    impl CaseSensitivity {
+        pub fn is_insensitive(&self) -> bool {
+            *self == Self::Insensitive
+        }
+    }
+
+    pub const DEFAULT_POLL_INTERVAL: u64 = 5;
+
+    #[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum WatcherBackend {
+        /// Poll if working directory is on network file system, otherwise native
+        #[default]
+        Auto,
+        /// Native notification of platform, e.g. inotify on Linux
+        Inotify,
+        /// Compare metadata of all files every `poll_interval`
+        Poll,
+    }
+
+    #[derive(Clone, Copy, Debug, Default, Deserialize)]
+    #[serde(default)]
+    pub struct WatcherOption {
+        backend: WatcherBackend,
+        /// Seconds between polls
+        poll_interval: Option<u64>,
+        /// Seconds size and mtime of recently modified file must stay unchanged before it is
+        /// hashed, so file being copied is not indexed partially, disabled if absent or 0
+        settle_interval: Option<u64>,
+    }
+
+    impl WatcherOption {
+        pub fn backend(&self) -> WatcherBackend {
+            self.backend
+        }
+        pub fn poll_interval(&self) -> std::time::Duration {
+            std::time::Duration::from_secs(
+                self.poll_interval
+                    .filter(|interval| *interval > 0)
+                    .unwrap_or(DEFAULT_POLL_INTERVAL),
+            )
+        }
+        pub fn settle_interval(&self) -> Option<std::time::Duration> {
+            self.settle_interval
+                .filter(|interval| *interval > 0)
+                .map(std::time::Duration::from_secs)
+        }
+    }
+
+    pub const DEFAULT_PARTIAL_SUFFIXES: [&str; 1] = [".part"];
+
+    #[derive(Clone, Debug, Deserialize)]
+    #[serde(default)]
+    pub struct ScannerOption {
+        /// Producer writes `<name><suffix>` then renames it to `<name>`, file with suffix is
+        /// not indexed and the rename is a new file
+        partial_suffixes: Vec<String>,
+    }
+
+    impl Default for ScannerOption {
+        fn default() -> Self {
+            Self {
+                partial_suffixes: DEFAULT_PARTIAL_SUFFIXES
+                    .iter()
+                    .map(|suffix| suffix.to_string())
+                    .collect(),
+            }
+        }
+    }
+
+    impl ScannerOption {
+        pub fn partial_suffixes(&self) -> &[String] {
+            &self.partial_suffixes
+        }
+    }
+
+    pub const DEFAULT_SCRUB_BATCH: i64 = 100;
+
+    /// Background re-hash of indexed files, a batch is checked every `interval` seconds
+    #[derive(Clone, Copy, Debug, Default, Deserialize)]
+    #[serde(default)]
+    pub struct ScrubOption {
+        /// Disabled if absent or 0
+        interval: Option<u64>,
+        /// Files checked per interval
+        batch: Option<i64>,
+    }
+
+    impl ScrubOption {
+        pub fn interval(&self) -> Option<std::time::Duration> {
+            self.interval
+                .filter(|interval| *interval > 0)
+                .map(std::time::Duration::from_secs)
+        }
+        pub fn batch(&self) -> i64 {
+            self.batch
+                .filter(|batch| *batch > 0)
+                .unwrap_or(DEFAULT_SCRUB_BATCH)
+        }
+    }
+
+    pub const DEFAULT_BUSY_TIMEOUT: u64 = 5000;
+    pub const DEFAULT_BUSY_RETRIES: u32 = 3;
+    pub const DEFAULT_BUSY_RETRY_DELAY: u64 = 50;
+
+    /// Contention of SQLite file shared with other processes, ignored by other backends
+    #[derive(Clone, Copy, Debug, Default, Deserialize)]
+    #[serde(default)]
+    pub struct SqliteOption {
+        /// Milliseconds SQLite itself waits lock before `SQLITE_BUSY`
+        busy_timeout: Option<u64>,
+        /// Statement still busy is run again this many times, 0 disables retry
+        busy_retries: Option<u32>,
+        /// Milliseconds before first retry, doubled on each retry with jitter
+        busy_retry_delay: Option<u64>,
+    }
+
+    impl SqliteOption {
+        pub fn busy_timeout(&self) -> Duration {
+            Duration::from_millis(self.busy_timeout.unwrap_or(DEFAULT_BUSY_TIMEOUT))
+        }
+        pub fn busy_retries(&self) -> u32 {
+            self.busy_retries.unwrap_or(DEFAULT_BUSY_RETRIES)
+        }
+        pub fn busy_retry_delay(&self) -> Duration {
+            Duration::from_millis(
+                self.busy_retry_delay
+                    .filter(|delay| *delay > 0)
+                    .unwrap_or(DEFAULT_BUSY_RETRY_DELAY),
+            )
+        }
+    }
+
+    pub const DEFAULT_TIERING_AFTER_DAYS: u64 = 90;
+    pub const DEFAULT_TIERING_INTERVAL: u64 = 3600;
+    pub const DEFAULT_TIERING_BATCH: i64 = 100;
+    pub const DEFAULT_RECALL_RETRY_AFTER: u64 = 60;
+
+    #[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum RecallMode {
+        /// Answer 503 with `Retry-After` while file is copied back
+        #[default]
+        Retry,
+        /// Download waits file is copied back
+        Blocking,
+    }
+
+    /// Move files not modified or downloaded for `after_days` to `cold_dir`, disabled if
+    /// `cold_dir` is absent
+    #[derive(Clone, Debug, Default, Deserialize)]
+    #[serde(default)]
+    pub struct TieringOption {
+        cold_dir: Option<String>,
+        after_days: Option<u64>,
+        /// Seconds between looking for cold files
+        interval: Option<u64>,
+        /// Files moved per interval
+        batch: Option<i64>,
+        recall: RecallMode,
+        /// Seconds
+        retry_after: Option<u64>,
+    }
+
+    impl TieringOption {
+        pub fn cold_dir(&self) -> Option<&str> {
+            self.cold_dir.as_deref()
+        }
+        pub fn after(&self) -> Duration {
+            Duration::from_secs(self.after_days.unwrap_or(DEFAULT_TIERING_AFTER_DAYS) * 86400)
+        }
+        pub fn interval(&self) -> Duration {
+            Duration::from_secs(
+                self.interval
+                    .filter(|interval| *interval > 0)
+                    .unwrap_or(DEFAULT_TIERING_INTERVAL),
+            )
+        }
+        pub fn batch(&self) -> i64 {
+            self.batch
+                .filter(|batch| *batch > 0)
+                .unwrap_or(DEFAULT_TIERING_BATCH)
+        }
+        pub fn recall(&self) -> RecallMode {
+            self.recall
+        }
+        pub fn retry_after(&self) -> u64 {
+            self.retry_after.unwrap_or(DEFAULT_RECALL_RETRY_AFTER)
+        }
+    }
+
+    /// Log level is still controlled by `RUST_LOG`
+    #[derive(Clone, Debug, Default, Deserialize)]
+    pub struct LogOption {
+        #[serde(default)]
+        format: LogFormat,
+    }
+
+    impl LogOption {
+        pub fn format(&self) -> LogFormat {
+            self.format
+        }
+    }
+
+    pub const DEFAULT_CLUSTER_REPLICAS: usize = 64;
+
+    /// Nodes serving shared backend, download is redirected to node owns the path,
+    /// disabled if `nodes` is empty
+    #[derive(Clone, Debug, Default, Deserialize)]
+    pub struct Cluster {
+        /// Base URL of this node, should be one of `nodes`
+        node: Option<String>,
+        #[serde(default)]
+        nodes: Vec<String>,
+        /// Virtual nodes of each node on hash ring
+        replicas: Option<usize>,
+    }
+
+    impl Cluster {
+        pub fn replicas(&self) -> usize {
+            self.replicas
+                .filter(|replicas| *replicas > 0)
+                .unwrap_or(DEFAULT_CLUSTER_REPLICAS)
+        }
+
+        /// Return `None` if cluster mode is disabled
+        pub fn build_ring(&self) -> anyhow::Result<Option<HashRing>> {
+            if self.nodes.is_empty() {
+                return Ok(None);
+            }
+            let node = self
+                .node
+                .as_ref()
+                .filter(|node| self.nodes.contains(node))
+                .ok_or_else(|| anyhow!("Cluster node should be one of cluster nodes"))?;
+            Ok(Some(HashRing::new(
+                node.clone(),
+                self.nodes.clone(),
+                self.replicas(),
+            )))
+        }
+    }
+
+    pub const DEFAULT_CORS_HEADERS: [&str; 5] = [
+        "authorization",
+        "content-type",
+        "range",
+        "if-none-match",
+        "if-modified-since",
+    ];
+    pub const DEFAULT_CORS_METHODS: [&str; 5] = ["GET", "HEAD", "POST", "PATCH", "DELETE"];
+    /// Response headers readable by script besides CORS-safelisted ones
+    pub const CORS_EXPOSE_HEADERS: [&str; 6] = [
+        "etag",
+        "digest",
+        "content-disposition",
+        "x-request-id",
+        "x-total-files",
+        "x-total-size",
+    ];
+
+    pub const DEFAULT_DELETE_WAIT_TIMEOUT: u64 = 30;
+    pub const DEFAULT_TRASH_DIR: &str = ".trash";
+
+    #[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum DeletePolicy {
+        /// Wait downloads under path finish, reject delete if they last `wait_timeout`
+        #[default]
+        Wait,
+        /// Refuse new downloads and delete once downloads under path finish
+        Defer,
+        /// Move to `trash_dir` at once, trashed copy is deleted once downloads finish
+        Trash,
+    }
+
+    /// Delete of paths still being downloaded
+    #[derive(Clone, Debug, Default, Deserialize)]
+    #[serde(default)]
+    pub struct Deletion {
+        policy: DeletePolicy,
+        /// Seconds
+        wait_timeout: Option<u64>,
+        /// Relative to working directory, or to mount of deleted path so it is renamed on
+        /// same file system, excluded from index
+        trash_dir: Option<String>,
+    }
+
+    impl Deletion {
+        pub fn policy(&self) -> DeletePolicy {
+            self.policy
+        }
+        pub fn wait_timeout(&self) -> Duration {
+            Duration::from_secs(self.wait_timeout.unwrap_or(DEFAULT_DELETE_WAIT_TIMEOUT))
+        }
+        pub fn trash_dir(&self) -> &str {
+            self.trash_dir.as_deref().unwrap_or(DEFAULT_TRASH_DIR)
+        }
+    }
+
+    /// Networks requests are accepted from, checked before token
+    #[derive(Clone, Debug, Default, Deserialize)]
+    #[serde(default)]
+    pub struct Firewall {
+        /// Any network is allowed if empty
+        allowed_ips: Vec<IpNetwork>,
+        /// Take precedence over `allowed_ips`
+        denied_ips: Vec<IpNetwork>,
+        /// Header reverse proxy puts client address in, e.g. `X-Real-IP`, used instead of
+        /// `Forwarded` and only honored from `trusted_proxies`
+        client_ip_header: Option<String>,
+        /// Peers whose client address header is honored
+        trusted_proxies: Vec<IpNetwork>,
+    }
+
+    impl Firewall {
+        pub fn client_ip_header(&self) -> Option<&str> {
+            self.client_ip_header.as_deref()
+        }
+        pub fn has_trusted_proxies(&self) -> bool {
+            !self.trusted_proxies.is_empty()
+        }
+        pub fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+            self.trusted_proxies.iter().any(|net| net.contains(ip))
+        }
+        pub fn permits(&self, ip: Option<IpAddr>) -> bool {
+            if self.allowed_ips.is_empty() && self.denied_ips.is_empty() {
+                return true;
+            }
+            let Some(ip) = ip else {
+                return false;
+            };
+            !self.denied_ips.iter().any(|net| net.contains(ip))
+                && (self.allowed_ips.is_empty()
+                    || self.allowed_ips.iter().any(|net| net.contains(ip)))
+        }
+    }
+
+    /// Cross-origin requests from browsers, disabled if `allowed_origins` is empty,
+    /// `*` in origins or headers allows any
+    #[derive(Clone, Debug, Default, Deserialize)]
+    #[serde(default)]
+    pub struct Cors {
+        allowed_origins: Vec<String>,
+        allowed_headers: Vec<String>,
+        allowed_methods: Vec<String>,
+        /// Seconds preflight response can be cached by browser
+        max_age: Option<u64>,
+    }
+
+    impl Cors {
+        /// Return `None` if no origin is allowed, preflight is answered before authorization
+        pub fn build_layer(&self) -> anyhow::Result<Option<CorsLayer>> {
+            if self.allowed_origins.is_empty() {
+                return Ok(None);
+            }
+            let origin = if self.allowed_origins.iter().any(|origin| origin == "*") {
+                AllowOrigin::any()
+            } else {
+                AllowOrigin::list(
+                    self.allowed_origins
+                        .iter()
+                        .map(|origin| origin.parse())
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|e| anyhow!("Invalid CORS origin: {:?}", e))?,
+                )
+            };
+            let headers = if self.allowed_headers.iter().any(|header| header == "*") {
+                AllowHeaders::any()
+            } else if self.allowed_headers.is_empty() {
+                AllowHeaders::list(DEFAULT_CORS_HEADERS.map(|header| header.parse().unwrap()))
+            } else {
+                AllowHeaders::list(
+                    self.allowed_headers
+                        .iter()
+                        .map(|header| header.parse())
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|e| anyhow!("Invalid CORS header: {:?}", e))?,
+                )
+            };
+            let methods = if self.allowed_methods.is_empty() {
+                AllowMethods::list(DEFAULT_CORS_METHODS.map(|method| method.parse().unwrap()))
+            } else {
+                AllowMethods::list(
+                    self.allowed_methods
+                        .iter()
+                        .map(|method| method.to_uppercase().parse())
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|e| anyhow!("Invalid CORS method: {:?}", e))?,
+                )
+            };
+            let mut layer = CorsLayer::new()
+                .allow_origin(origin)
+                .allow_headers(headers)
+                .allow_methods(methods)
+                .expose_headers(ExposeHeaders::list(
+                    CORS_EXPOSE_HEADERS.map(|header| header.parse().unwrap()),
+                ));
+            if let Some(max_age) = self.max_age {
+                layer = layer.max_age(Duration::from_secs(max_age));
+            }
+            Ok(Some(layer))
+        }
+    }
+
+    /// Paths can be downloaded and queried without token, same syntax as `path` of auth entry
+    #[derive(Clone, Debug, Default, Deserialize)]
+    pub struct Public {
+        #[serde(default)]
+        path: Vec<String>,
+    }
+
+    impl Public {
+        /// Return `None` if no public path is configured
+        pub fn build_matcher(&self) -> anyhow::Result<Option<PathMatcher>> {
+            if self.path.is_empty() {
+                return Ok(None);
+            }
+            PathMatcher::new(&self.path)
+                .map(Some)
+                .map_err(|e| anyhow!("Unable to parse public path pattern: {:?}", e))
+        }
+    }
+
+    /// Publish change events to message broker, `url` is broker list of kafka,
+    /// server url of nats and mqtt, `topic` is subject of nats
+    ///
+    /// `url` and `topic` are only read by sinks enabled by cargo feature
+    #[derive(Clone, Debug, Deserialize)]
+    #[cfg_attr(
+        not(any(feature = "kafka", feature = "mqtt", feature = "nats")),
+        allow(dead_code)
+    )]
+    pub struct EventSinkOption {
+        kind: SinkKind,
+        url: String,
+        topic: String,
+        #[serde(default)]
+        format: SinkFormat,
+    }
+
+    #[cfg_attr(
+        not(any(feature = "kafka", feature = "mqtt", feature = "nats")),
+        allow(dead_code)
+    )]
+    impl EventSinkOption {
+        pub fn kind(&self) -> SinkKind {
+            self.kind
+        }
+        pub fn url(&self) -> &str {
+            &self.url
+        }
+        pub fn topic(&self) -> &str {
+            &self.topic
+        }
+        pub fn format(&self) -> SinkFormat {
+            self.format
+        }
+    }
+
+    pub const DEFAULT_IMPORT_INTERVAL: u64 = 60 * 60;
+    pub const DEFAULT_IMPORT_MAX_DEPTH: usize = 8;
+
+    /// Mirror upstream `url` into `target` (relative to working directory) every
+    /// `interval` seconds, `url` is directory index page or `sha256sum` manifest
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct ImportOption {
+        url: String,
+        target: String,
+        #[serde(default)]
+        kind: ImportKind,
+        interval: Option<u64>,
+        /// Levels of subdirectories followed in directory index
+        max_depth: Option<usize>,
+    }
+
+    impl ImportOption {
+        pub fn url(&self) -> &str {
+            &self.url
+        }
+        pub fn target(&self) -> &str {
+            &self.target
+        }
+        pub fn kind(&self) -> ImportKind {
+            self.kind
+        }
+        pub fn interval(&self) -> std::time::Duration {
+            std::time::Duration::from_secs(
+                self.interval
+                    .filter(|interval| *interval > 0)
+                    .unwrap_or(DEFAULT_IMPORT_INTERVAL),
+            )
+        }
+        pub fn max_depth(&self) -> usize {
+            self.max_depth.unwrap_or(DEFAULT_IMPORT_MAX_DEPTH)
+        }
+    }
+
+    /// Directory outside of working directory published as `./<name>`
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct MountOption {
+        name: String,
+        path: String,
+    }
+
+    impl MountOption {
+        pub fn name(&self) -> &str {
+            &self.name
+        }
+        pub fn path(&self) -> &str {
+            &self.path
+        }
+    }
+
+    /// Index prefix stored in another directory, e.g. `archive` on HDD and `hot` on NVMe
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct PlacementOption {
+        /// Relative to working directory, e.g. `archive` or `data/cold`
+        prefix: String,
+        /// Local directory, remote storage (e.g. `s3://`) is not supported
+        path: String,
+    }
+
+    impl PlacementOption {
+        pub fn prefix(&self) -> &str {
+            &self.prefix
+        }
+        pub fn path(&self) -> &str {
+            &self.path
+        }
+    }
+
+    /// Encodings responses can be compressed with, chosen by `Accept-Encoding` of client
+    #[derive(Clone, Copy, Debug, Default, Deserialize)]
+    #[serde(default)]
+    pub struct Compression {
+        gzip: bool,
+        zstd: bool,
+        /// Serve `foo.txt.zst` or `foo.txt.gz` in place of `foo.txt` if client accepts it
+        precompressed: bool,
+        /// Files named like sidecar are not indexed, name is checked only, so `foo.tar.gz` is
+        /// hidden even if `foo.tar` does not exist
+        hide_sidecars: bool,
+    }
+
+    impl Compression {
+        pub fn gzip(&self) -> bool {
+            self.gzip
+        }
+        pub fn zstd(&self) -> bool {
+            self.zstd
+        }
+        pub fn enabled(&self) -> bool {
+            self.gzip || self.zstd
+        }
+        /// `Content-Encoding` names responses can be compressed with
+        pub fn encodings(&self) -> Vec<String> {
+            [("gzip", self.gzip), ("zstd", self.zstd)]
+                .into_iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(name, _)| name.to_string())
+                .collect()
+        }
+        pub fn precompressed(&self) -> bool {
+            self.precompressed
+        }
+        pub fn hide_sidecars(&self) -> bool {
+            self.hide_sidecars
+        }
+    }
+
+    pub const DEFAULT_FRESH_DIGEST_MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+    /// Digest of download not covered by index, only stored digests are sent by default
+    #[derive(Clone, Copy, Debug, Default, Deserialize)]
+    #[serde(default)]
+    pub struct DigestOption {
+        /// Hash file on request if client wants blake3 and index has other algorithm
+        fresh_blake3: bool,
+        /// Larger file is not hashed on request, default 64 MiB, `0` means unlimited
+        fresh_max_size: Option<u64>,
+    }
+
+    impl DigestOption {
+        /// File of `size` bytes may be hashed on request
+        pub fn fresh_allowed(&self, size: u64) -> bool {
+            let max_size = self.fresh_max_size.unwrap_or(DEFAULT_FRESH_DIGEST_MAX_SIZE);
+            self.fresh_blake3 && (max_size == 0 || size <= max_size)
+        }
+        pub fn fresh_blake3(&self) -> bool {
+            self.fresh_blake3
+        }
+    }
+
+    /// Release manifest compared with running version, newer release is only logged
+    #[derive(Clone, Debug, Default, Deserialize)]
+    #[serde(default)]
+    pub struct UpdateOption {
+        /// JSON `{"version": "x.y.z", "url": "..."}`, check is disabled if absent
+        manifest_url: Option<String>,
+        /// Seconds between checks of running server, disabled if absent or 0
+        check_interval: Option<u64>,
+    }
+
+    impl UpdateOption {
+        pub fn manifest_url(&self) -> Option<&str> {
+            self.manifest_url.as_deref().filter(|url| !url.is_empty())
+        }
+        pub fn check_interval(&self) -> Option<Duration> {
+            self.check_interval
+                .filter(|interval| *interval > 0)
+                .map(Duration::from_secs)
+        }
+    }
+
+    /// Thread counts of runtime, default of tokio is used if absent or 0
+    #[derive(Clone, Copy, Debug, Default, Deserialize)]
+    #[serde(default)]
+    pub struct Tuning {
+        worker_threads: Option<usize>,
+        /// Threads of file system operations
+        blocking_threads: Option<usize>,
+        /// Files hashed at once by scan, number of CPUs if absent
+        hash_workers: Option<usize>,
+    }
+
+    impl Tuning {
+        pub fn worker_threads(&self) -> Option<usize> {
+            self.worker_threads.filter(|threads| *threads > 0)
+        }
+        pub fn blocking_threads(&self) -> Option<usize> {
+            self.blocking_threads.filter(|threads| *threads > 0)
+        }
+        pub fn hash_workers(&self) -> usize {
+            self.hash_workers
+                .filter(|workers| *workers > 0)
+                .unwrap_or_else(|| {
+                    std::thread::available_parallelism()
+                        .map(|count| count.get())
+                        .unwrap_or(1)
+                })
+        }
+    }
+
+    /// Working directory is git repository, commit checked out is recorded by scanner
+    #[derive(Clone, Copy, Debug, Default, Deserialize)]
+    #[serde(default)]
+    pub struct GitOption {
+        enabled: bool,
+        /// Exclude files ignored by `.gitignore`, works like `exclude`
+        exclude_ignored: bool,
     }
 
-    impl AuthEntry {
-        pub fn token(&self) -> &str {
-            &self.token
+    impl GitOption {
+        pub fn enabled(&self) -> bool {
+            self.enabled
         }
-        pub fn path(&self) -> &Vec<String> {
-            &self.path
+        pub fn exclude_ignored(&self) -> bool {
+            self.enabled && self.exclude_ignored
         }
     }
 
-    #[derive(Clone, Debug, Deserialize)]
-    pub struct Server {
-        host: String,
-        port: u16,
+    /// Optional subsystems toggled in configure, checked by `validate` on startup,
+    /// everything is disabled unless enabled explicitly
+    #[derive(Clone, Copy, Debug, Default, Deserialize)]
+    #[serde(default)]
+    pub struct Features {
+        uploads: bool,
+        admin: bool,
+        metrics: bool,
+        web_ui: bool,
+        webhooks: bool,
     }
 
-    impl Server {
-        pub fn host(&self) -> &str {
-            &self.host
+    impl Features {
+        pub fn uploads(&self) -> bool {
+            self.uploads
         }
-        pub fn port(&self) -> u16 {
-            self.port
+        pub fn admin(&self) -> bool {
+            self.admin
         }
-
-        pub fn get_bind(&self) -> String {
-            format!("{}:{}", self.host, self.port)
+        pub fn metrics(&self) -> bool {
+            self.metrics
         }
-
-        fn new(host: String, port: u16) -> Self {
-            Self { host, port }
+        pub fn web_ui(&self) -> bool {
+            self.web_ui
+        }
+        pub fn webhooks(&self) -> bool {
+            self.webhooks
         }
-    }
 
-    impl Default for Server {
-        fn default() -> Self {
-            Self::new("127.0.0.1".to_string(), 24146)
+        /// Reject features enabled in configure but not provided by this build
+        pub fn validate(&self) -> anyhow::Result<()> {
+            for (name, enabled) in [
+                ("metrics", self.metrics),
+                ("web_ui", self.web_ui),
+                ("webhooks", self.webhooks),
+            ] {
+                if enabled {
+                    return Err(anyhow!(
+                        "Feature {:?} is enabled but not supported by this server",
+                        name
+                    ));
+                }
+            }
+            Ok(())
         }
     }
 
     #[derive(Clone, Debug, Deserialize)]
     pub struct Configure {
         working_directory: String,
+        /// SQLite file, `:memory:` keeps index in memory and scan on every startup
         database: Option<String>,
+        /// e.g. `postgres://user@localhost/waffle`, take precedence over `database`
+        database_url: Option<String>,
+        #[serde(default)]
+        sqlite: SqliteOption,
         #[serde(default)]
         server: Server,
+        #[serde(default)]
+        exclude: Vec<String>,
+        #[serde(default)]
+        case_sensitivity: CaseSensitivity,
+        #[serde(default)]
+        hash: HashOption,
+        #[serde(default)]
+        encryption: Encryption,
+        #[serde(default)]
+        limits: Limits,
+        #[serde(default)]
+        features: Features,
+        #[serde(default)]
+        cache: Cache,
+        #[serde(default)]
+        signing: Signing,
+        #[serde(default)]
+        event_log: EventLogOption,
+        #[serde(default)]
+        access_log: AccessLogOption,
+        #[serde(default)]
+        event_sink: Vec<EventSinkOption>,
+        #[serde(default)]
+        import: Vec<ImportOption>,
+        #[serde(default)]
+        mount: Vec<MountOption>,
+        #[serde(default)]
+        placement: Vec<PlacementOption>,
+        #[serde(default)]
+        git: GitOption,
+        #[serde(default)]
+        scanner: ScannerOption,
+        #[serde(default)]
+        watcher: WatcherOption,
+        #[serde(default)]
+        scrub: ScrubOption,
+        #[serde(default)]
+        tiering: TieringOption,
+        #[serde(default)]
+        public: Public,
+        #[serde(default)]
+        cluster: Cluster,
+        #[serde(default)]
+        cors: Cors,
+        #[serde(default)]
+        compression: Compression,
+        #[serde(default)]
+        digest: DigestOption,
+        #[serde(default)]
+        firewall: Firewall,
+        #[serde(default)]
+        deletion: Deletion,
+        #[serde(default)]
+        update: UpdateOption,
+        #[serde(default)]
+        tuning: Tuning,
+        #[serde(default)]
+        log: LogOption,
         auth_entry: Vec<AuthEntry>,
     }
 
@@ -69,6 +1197,135 @@ pub mod v1 {
             &self.auth_entry
         }
 
+        pub fn hash(&self) -> HashOption {
+            self.hash
+        }
+
+        pub fn encryption(&self) -> &Encryption {
+            &self.encryption
+        }
+
+        pub fn limits(&self) -> &Limits {
+            &self.limits
+        }
+
+        pub fn features(&self) -> Features {
+            self.features
+        }
+
+        pub fn cache(&self) -> &Cache {
+            &self.cache
+        }
+
+        pub fn signing(&self) -> &Signing {
+            &self.signing
+        }
+
+        pub fn event_log(&self) -> &EventLogOption {
+            &self.event_log
+        }
+
+        pub fn access_log(&self) -> AccessLogOption {
+            self.access_log
+        }
+
+        pub fn event_sink(&self) -> &Vec<EventSinkOption> {
+            &self.event_sink
+        }
+
+        pub fn import(&self) -> &Vec<ImportOption> {
+            &self.import
+        }
+
+        pub fn mount(&self) -> &Vec<MountOption> {
+            &self.mount
+        }
+
+        pub fn placement(&self) -> &[PlacementOption] {
+            &self.placement
+        }
+
+        pub fn git(&self) -> GitOption {
+            self.git
+        }
+
+        pub fn scanner(&self) -> &ScannerOption {
+            &self.scanner
+        }
+
+        pub fn watcher(&self) -> WatcherOption {
+            self.watcher
+        }
+
+        pub fn scrub(&self) -> ScrubOption {
+            self.scrub
+        }
+
+        pub fn tiering(&self) -> &TieringOption {
+            &self.tiering
+        }
+
+        pub fn public(&self) -> &Public {
+            &self.public
+        }
+
+        pub fn cluster(&self) -> &Cluster {
+            &self.cluster
+        }
+
+        pub fn cors(&self) -> &Cors {
+            &self.cors
+        }
+
+        pub fn compression(&self) -> Compression {
+            self.compression
+        }
+
+        pub fn digest(&self) -> DigestOption {
+            self.digest
+        }
+
+        pub fn firewall(&self) -> &Firewall {
+            &self.firewall
+        }
+
+        pub fn deletion(&self) -> &Deletion {
+            &self.deletion
+        }
+
+        pub fn update(&self) -> &UpdateOption {
+            &self.update
+        }
+
+        pub fn tuning(&self) -> Tuning {
+            self.tuning
+        }
+
+        /// Values from command line take precedence
+        pub fn override_tuning(
+            &mut self,
+            worker_threads: Option<usize>,
+            blocking_threads: Option<usize>,
+            hash_workers: Option<usize>,
+        ) {
+            let tuning = &mut self.tuning;
+            tuning.worker_threads = worker_threads.or(tuning.worker_threads);
+            tuning.blocking_threads = blocking_threads.or(tuning.blocking_threads);
+            tuning.hash_workers = hash_workers.or(tuning.hash_workers);
+        }
+
+        pub fn log(&self) -> &LogOption {
+            &self.log
+        }
+
+        pub fn exclude(&self) -> &Vec<String> {
+            &self.exclude
+        }
+
+        pub fn case_sensitivity(&self) -> CaseSensitivity {
+            self.case_sensitivity
+        }
+
         pub fn database(&self) -> String {
             if let Some(ref database) = self.database {
                 database.clone()
@@ -77,6 +1334,14 @@ pub mod v1 {
             }
         }
 
+        pub fn database_url(&self) -> Option<&str> {
+            self.database_url.as_deref()
+        }
+
+        pub fn sqlite(&self) -> SqliteOption {
+            self.sqlite
+        }
+
         pub async fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
             let file = read_to_string(path)
                 .await
@@ -89,8 +1354,8 @@ pub mod v1 {
         }
 
         pub fn parse_host_and_port(&self, host: Option<&String>, port: Option<&u16>) -> String {
-            if host.is_some() && port.is_some() {
-                return format!("{}:{}", host.unwrap(), port.unwrap());
+            if let (Some(host), Some(port)) = (host, port) {
+                return format!("{}:{}", host, port);
             }
             if let Some(host) = host {
                 return format!("{}:{}", host, self.server().port());
@@ -101,18 +1366,642 @@ pub mod v1 {
             self.server().get_bind()
         }
 
-        pub fn build_hashmap(&self) -> PoolType {
+        pub fn build_hashmap(&self) -> anyhow::Result<PoolType> {
             let mut m = HashMap::new();
             for auth_entry in self.auth_entry() {
-                m.insert(auth_entry.token().to_string(), auth_entry.path().clone());
+                if auth_entry
+                    .root()
+                    .is_some_and(|root| !is_safe_relative(root))
+                {
+                    return Err(anyhow!(
+                        "Root of token {} should be relative to working directory",
+                        mask_token(auth_entry.token())
+                    ));
+                }
+                let auth_entry = auth_entry.clone().compile().map_err(|e| {
+                    anyhow!(
+                        "Unable to parse path pattern of token {}: {:?}",
+                        mask_token(auth_entry.token()),
+                        e
+                    )
+                })?;
+                m.insert(auth_entry.token().to_string(), Arc::new(auth_entry));
+            }
+            Ok(m)
+        }
+
+        /// Should be called in working directory, `.gitignore` is read from it
+        pub fn build_exclude_set(&self) -> anyhow::Result<ExcludeSet> {
+            let case_insensitive = self.case_sensitivity.is_insensitive();
+            let exclude = ExcludeSet::new(self.exclude(), case_insensitive)
+                .map_err(|e| anyhow!("Unable to parse exclude pattern: {:?}", e))?
+                .with_partial_suffixes(self.scanner.partial_suffixes())
+                .with_hidden_sidecars(self.compression.hide_sidecars())
+                .with_trash_dir(self.deletion.trash_dir());
+            if !self.git.exclude_ignored() {
+                return Ok(exclude);
+            }
+            let gitignore = build_gitignore(Path::new("."), case_insensitive)
+                .map_err(|e| anyhow!("Unable to load gitignore rules: {:?}", e))?;
+            Ok(exclude.with_gitignore(gitignore))
+        }
+
+        /// Should be called in working directory, mount or placement can not shadow entry
+        /// of it
+        pub fn build_mounts(&self) -> anyhow::Result<Mounts> {
+            if let Some(placement) = self
+                .placement()
+                .iter()
+                .find(|placement| placement.path().contains("://"))
+            {
+                return Err(anyhow!(
+                    "Placement {:?} is not a local directory, remote storage is not supported",
+                    placement.prefix()
+                ));
+            }
+            let mounts = self
+                .mount()
+                .iter()
+                .map(|mount| (mount.name(), mount.path()));
+            let placements = self
+                .placement()
+                .iter()
+                .map(|placement| (placement.prefix(), placement.path()));
+            Mounts::new(mounts.chain(placements).map(|(name, path)| {
+                (
+                    name.to_string(),
+                    PathBuf::from(shellexpand::tilde(path).as_ref()),
+                )
+            }))
+        }
+    }
+}
+
+mod exclude {
+    use crate::mount::Mounts;
+    use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+    use ignore::gitignore::Gitignore;
+    use publib::normalize_path;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    /// Suffix of pre-compressed sidecar and its `Content-Encoding`, in order of preference
+    pub const SIDECAR_ENCODINGS: [(&str, &str); 2] = [(".zst", "zstd"), (".gz", "gzip")];
+
+    /// Compiled `exclude` patterns, matched against paths relative to working directory
+    #[derive(Clone, Debug, Default)]
+    pub struct ExcludeSet {
+        set: GlobSet,
+        gitignore: Option<Arc<Gitignore>>,
+        /// Suffixes of files still being written by producer
+        partial_suffixes: Vec<String>,
+        hide_sidecars: bool,
+        /// Deleted paths are moved to this directory of working directory or of their mount
+        trash_dir: Option<String>,
+        /// Working directory when set is built, absolute path is matched relative to it
+        base: Option<PathBuf>,
+    }
+
+    impl ExcludeSet {
+        /// Build after changing into working directory, absolute paths are matched relative
+        /// to directory current at that time
+        pub fn new<S: AsRef<str>>(
+            patterns: &[S],
+            case_insensitive: bool,
+        ) -> Result<Self, globset::Error> {
+            let mut builder = GlobSetBuilder::new();
+            for pattern in patterns {
+                builder.add(
+                    GlobBuilder::new(pattern.as_ref())
+                        .case_insensitive(case_insensitive)
+                        .build()?,
+                );
+            }
+            Ok(Self {
+                set: builder.build()?,
+                gitignore: None,
+                partial_suffixes: Vec::new(),
+                hide_sidecars: false,
+                trash_dir: None,
+                base: std::env::current_dir().ok(),
+            })
+        }
+
+        pub fn with_gitignore(self, gitignore: Gitignore) -> Self {
+            Self {
+                gitignore: Some(Arc::new(gitignore)),
+                ..self
+            }
+        }
+
+        pub fn with_partial_suffixes(self, suffixes: &[String]) -> Self {
+            Self {
+                partial_suffixes: suffixes
+                    .iter()
+                    .filter(|suffix| !suffix.is_empty())
+                    .cloned()
+                    .collect(),
+                ..self
+            }
+        }
+
+        pub fn with_hidden_sidecars(self, hide_sidecars: bool) -> Self {
+            Self {
+                hide_sidecars,
+                ..self
+            }
+        }
+
+        pub fn with_trash_dir(self, trash_dir: &str) -> Self {
+            Self {
+                trash_dir: Some(normalize_path(trash_dir)).filter(|trash_dir| trash_dir != "."),
+                ..self
+            }
+        }
+
+        /// Path is in trash of working directory or of mount it is under
+        pub fn is_trash<P: AsRef<Path>>(&self, path: P) -> bool {
+            let Some(trash_dir) = self.trash_dir.as_deref() else {
+                return false;
+            };
+            let mounts = Mounts::global();
+            let path = mounts.to_index(path);
+            let trash = normalize_path(Path::new(&mounts.root_of(&path)).join(trash_dir));
+            path.strip_prefix(trash.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        }
+
+        /// Compressed copy of file next to it, e.g. `foo.txt.zst` of `foo.txt`. Only file name
+        /// is checked, original is not looked up, so name of file in mount matches the same
+        /// way. Name without extension once suffix is stripped, e.g. `foo.gz`, is not sidecar
+        pub fn is_sidecar<P: AsRef<Path>>(&self, path: P) -> bool {
+            let Some(name) = path.as_ref().file_name().and_then(|name| name.to_str()) else {
+                return false;
+            };
+            SIDECAR_ENCODINGS.iter().any(|(suffix, _)| {
+                name.strip_suffix(suffix)
+                    .and_then(|original| original.rsplit_once('.'))
+                    .is_some_and(|(stem, extension)| !stem.is_empty() && !extension.is_empty())
+            })
+        }
+
+        /// Suffix of temporary file written by server itself, `None` if none is configured
+        pub fn partial_suffix(&self) -> Option<&str> {
+            self.partial_suffixes.first().map(String::as_str)
+        }
+
+        /// File is written by producer under temporary name, e.g. `foo.part`
+        pub fn is_partial<P: AsRef<Path>>(&self, path: P) -> bool {
+            path.as_ref().to_str().is_some_and(|path| {
+                self.partial_suffixes
+                    .iter()
+                    .any(|suffix| path.ends_with(suffix.as_str()))
+            })
+        }
+
+        /// Like `is_match_entry`, path of unknown type is taken as file, so directory only
+        /// gitignore rule (`build/`) matches paths under directory but not directory itself
+        pub fn is_match<P: AsRef<Path>>(&self, path: P) -> bool {
+            self.is_match_entry(path, false)
+        }
+
+        /// Partial and trashed files are excluded too, so are sidecars if they are hidden,
+        /// `is_dir` is type of path known by caller, e.g. from walk entry or watcher event
+        pub fn is_match_entry<P: AsRef<Path>>(&self, path: P, is_dir: bool) -> bool {
+            if self.is_partial(&path)
+                || self.is_trash(&path)
+                || (self.hide_sidecars && self.is_sidecar(&path))
+            {
+                return true;
+            }
+            if self.set.is_empty() && self.gitignore.is_none() {
+                return false;
+            }
+            let path = path.as_ref();
+            let path = self
+                .base
+                .as_ref()
+                .and_then(|dir| path.strip_prefix(dir).ok())
+                .unwrap_or(path);
+            let path = path.strip_prefix(".").unwrap_or(path);
+            if self.set.is_match(path) {
+                return true;
+            }
+            // Paths outside working directory are never ignored by git
+            self.gitignore.as_ref().is_some_and(|gitignore| {
+                path.is_relative()
+                    && gitignore
+                        .matched_path_or_any_parents(path, is_dir)
+                        .is_ignore()
+            })
+        }
+    }
+}
+
+mod matcher {
+    use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+    use publib::normalize_path;
+    use std::path::{Component, Path};
+    use std::sync::Arc;
+
+    fn is_glob(path: &str) -> bool {
+        path.contains(['*', '?', '[', '{'])
+    }
+
+    /// Leading components of pattern without glob meta character
+    fn glob_base(pattern: &str) -> String {
+        let base = pattern
+            .split('/')
+            .take_while(|component| !is_glob(component))
+            .collect::<Vec<_>>();
+        if base.is_empty() {
+            ".".to_string()
+        } else {
+            base.join("/")
+        }
+    }
+
+    /// Normalized prefixes kept sorted, path is matched by looking up each of its ancestors,
+    /// so cost grows with depth of path instead of number of prefixes
+    #[derive(Clone, Debug, Default)]
+    struct PrefixSet(Arc<[String]>);
+
+    impl PrefixSet {
+        fn new(mut prefixes: Vec<String>) -> Self {
+            prefixes.sort_unstable();
+            prefixes.dedup();
+            Self(prefixes.into())
+        }
+
+        /// `path` should be normalized
+        fn contains_ancestor(&self, path: &str) -> bool {
+            if self.0.is_empty() {
+                return false;
+            }
+            let mut current = path;
+            loop {
+                if self
+                    .0
+                    .binary_search_by(|prefix| prefix.as_str().cmp(current))
+                    .is_ok()
+                {
+                    return true;
+                }
+                match current.rfind('/') {
+                    Some(index) => current = &current[..index],
+                    None => return false,
+                }
+            }
+        }
+    }
+
+    /// Authorized paths of token, entry contains glob meta character is matched as pattern
+    /// (e.g. `docs/**/*.pdf`), otherwise as path prefix (compare by component)
+    ///
+    /// Cloned into every request, so all fields are shared
+    #[derive(Clone, Debug, Default)]
+    pub struct PathMatcher {
+        prefixes: PrefixSet,
+        globs: Arc<GlobSet>,
+        bases: Arc<[String]>,
+    }
+
+    impl PathMatcher {
+        pub fn new<S: AsRef<str>>(paths: &[S]) -> Result<Self, globset::Error> {
+            let mut prefixes = Vec::new();
+            let mut bases = Vec::new();
+            let mut builder = GlobSetBuilder::new();
+            for path in paths {
+                let path = path.as_ref();
+                if is_glob(path) {
+                    let pattern = path.trim_start_matches("./");
+                    builder.add(GlobBuilder::new(pattern).literal_separator(true).build()?);
+                    bases.push(glob_base(pattern));
+                } else {
+                    prefixes.push(normalize_path(path));
+                    bases.push(path.to_string());
+                }
+            }
+            Ok(Self {
+                prefixes: PrefixSet::new(prefixes),
+                globs: Arc::new(builder.build()?),
+                bases: bases.into(),
+            })
+        }
+
+        /// Match `paths` as prefix only, used by signed url
+        pub fn prefix(paths: Vec<String>) -> Self {
+            Self {
+                prefixes: PrefixSet::new(paths.iter().map(normalize_path).collect()),
+                globs: Default::default(),
+                bases: paths.into(),
+            }
+        }
+
+        /// `false` if all entries are prefix, so result of querying `bases` need not filter
+        pub fn has_pattern(&self) -> bool {
+            !self.globs.is_empty()
+        }
+
+        /// Prefixes used to query index, should filter by `is_match` if `has_pattern`
+        pub fn bases(&self) -> &Arc<[String]> {
+            &self.bases
+        }
+
+        /// Path contains `..` is always unmatched
+        pub fn is_match(&self, path: &str) -> bool {
+            let normalized = normalize_path(path);
+            let path = Path::new(&normalized);
+            if path
+                .components()
+                .any(|component| component == Component::ParentDir)
+            {
+                return false;
+            }
+            self.prefixes.contains_ancestor(&normalized)
+                || (self.has_pattern()
+                    && self.globs.is_match(path.strip_prefix(".").unwrap_or(path)))
+        }
+    }
+}
+
+mod network {
+    use serde::{Deserialize, Deserializer};
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    /// Address with prefix length, e.g. `10.0.0.0/8`, single address if prefix is absent
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct IpNetwork {
+        addr: IpAddr,
+        prefix: u8,
+    }
+
+    impl IpNetwork {
+        fn max_prefix(addr: IpAddr) -> u8 {
+            match addr {
+                IpAddr::V4(_) => 32,
+                IpAddr::V6(_) => 128,
+            }
+        }
+
+        /// IPv4-mapped IPv6 address is treated as IPv4
+        pub fn contains(&self, ip: IpAddr) -> bool {
+            match (self.addr, ip.to_canonical()) {
+                (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                    let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                    u32::from(net) & mask == u32::from(ip) & mask
+                }
+                (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                    let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                    u128::from(net) & mask == u128::from(ip) & mask
+                }
+                _ => false,
             }
-            m
         }
     }
+
+    impl FromStr for IpNetwork {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let (addr, prefix) = match s.trim().split_once('/') {
+                Some((addr, prefix)) => (addr, Some(prefix)),
+                None => (s.trim(), None),
+            };
+            let addr = IpAddr::from_str(addr)
+                .map_err(|e| format!("Invalid address {:?}: {}", s, e))?
+                .to_canonical();
+            let prefix = match prefix {
+                None => Self::max_prefix(addr),
+                Some(prefix) => prefix
+                    .parse()
+                    .ok()
+                    .filter(|prefix| *prefix <= Self::max_prefix(addr))
+                    .ok_or_else(|| format!("Invalid prefix length of {:?}", s))?,
+            };
+            Ok(Self { addr, prefix })
+        }
+    }
+
+    impl<'de> Deserialize<'de> for IpNetwork {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            String::deserialize(deserializer)?
+                .parse()
+                .map_err(serde::de::Error::custom)
+        }
+    }
+}
+
+mod permissions {
+    use publib::types::Scope;
+
+    /// Scopes granted to request, set as request extension by auth layer
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct Permissions(u8);
+
+    impl Permissions {
+        fn bit(scope: Scope) -> u8 {
+            1 << scope as u8
+        }
+
+        pub fn new(scopes: &[Scope]) -> Self {
+            Self(
+                scopes
+                    .iter()
+                    .fold(0, |bits, scope| bits | Self::bit(*scope)),
+            )
+        }
+
+        pub fn with(self, scope: Scope) -> Self {
+            Self(self.0 | Self::bit(scope))
+        }
+
+        pub fn allows(&self, scope: Scope) -> bool {
+            self.0 & Self::bit(scope) != 0
+        }
+    }
+}
+
+pub use exclude::{ExcludeSet, SIDECAR_ENCODINGS};
+pub use matcher::PathMatcher;
+pub use network::IpNetwork;
+pub use permissions::Permissions;
+
+/// Only prefix of token is shown in admin API, it is enough to tell clients apart
+pub fn mask_token(token: &str) -> String {
+    token.chars().take(4).chain("***".chars()).collect()
+}
+
+/// Prefix of token digest, tells tokens sharing prefix apart without revealing them
+pub fn token_fingerprint(token: &str) -> String {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .take(6)
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
 }
 
 use std::collections::HashMap;
+use std::sync::Arc;
 use tokio::sync::RwLock;
 pub use v1 as current;
-pub type PoolType = HashMap<String, Vec<String>>;
+pub type PoolType = HashMap<String, Arc<current::AuthEntry>>;
 pub type RwPoolType = RwLock<PoolType>;
+
+#[cfg(test)]
+mod test {
+    use super::current::{AccessLogOption, AuthEntry, Firewall};
+    use super::{ExcludeSet, IpNetwork};
+    use crate::git::build_gitignore;
+    use publib::types::TokenInfo;
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn net(s: &str) -> IpNetwork {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_ip_network_parse() {
+        assert_eq!(net("10.0.0.1"), net("10.0.0.1/32"));
+        assert_eq!(net(" 2001:db8::1 "), net("2001:db8::1/128"));
+        assert_eq!(net("::ffff:10.0.0.1/8"), net("10.0.0.1/8"));
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("2001:db8::/129".parse::<IpNetwork>().is_err());
+        assert!("10.0.0.0/".parse::<IpNetwork>().is_err());
+        assert!("10.0.0.0/-1".parse::<IpNetwork>().is_err());
+        assert!("example.com".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn test_ip_network_contains() {
+        let v4 = net("10.1.0.0/16");
+        assert!(v4.contains(ip("10.1.255.255")));
+        assert!(!v4.contains(ip("10.2.0.1")));
+        assert!(v4.contains(ip("::ffff:10.1.2.3")));
+        assert!(!v4.contains(ip("2001:db8::1")));
+        assert!(net("0.0.0.0/0").contains(ip("192.0.2.1")));
+        assert!(net("192.0.2.1").contains(ip("192.0.2.1")));
+        assert!(!net("192.0.2.1").contains(ip("192.0.2.2")));
+
+        let v6 = net("2001:db8::/32");
+        assert!(v6.contains(ip("2001:db8:ffff::1")));
+        assert!(!v6.contains(ip("2001:db9::1")));
+        assert!(!v6.contains(ip("10.1.2.3")));
+        assert!(net("::/0").contains(ip("::1")));
+    }
+
+    #[test]
+    fn test_firewall_permits() {
+        let firewall = Firewall::default();
+        assert!(firewall.permits(Some(ip("192.0.2.1"))));
+        assert!(firewall.permits(None));
+
+        let firewall: Firewall = toml::from_str(
+            r#"
+            allowed_ips = ["10.0.0.0/8", "2001:db8::/32"]
+            denied_ips = ["10.0.0.13"]
+            "#,
+        )
+        .unwrap();
+        assert!(firewall.permits(Some(ip("10.2.3.4"))));
+        assert!(firewall.permits(Some(ip("2001:db8::1"))));
+        assert!(!firewall.permits(Some(ip("192.0.2.1"))));
+        // Deny takes precedence over allow
+        assert!(!firewall.permits(Some(ip("10.0.0.13"))));
+        assert!(!firewall.permits(None));
+
+        let firewall: Firewall = toml::from_str(r#"denied_ips = ["192.0.2.0/24"]"#).unwrap();
+        assert!(firewall.permits(Some(ip("198.51.100.1"))));
+        assert!(!firewall.permits(Some(ip("192.0.2.9"))));
+        assert!(!firewall.permits(None));
+
+        assert!(toml::from_str::<Firewall>(r#"allowed_ips = ["10.0.0.0/40"]"#).is_err());
+    }
+
+    #[test]
+    fn test_exclude_absolute_path() {
+        let exclude = ExcludeSet::new(&["target/*.tmp"], false).unwrap();
+        let current_dir = std::env::current_dir().unwrap();
+        assert!(exclude.is_match("./target/a.tmp"));
+        assert!(exclude.is_match(current_dir.join("target/a.tmp")));
+        assert!(!exclude.is_match(current_dir.join("target/a.txt")));
+        assert!(!exclude.is_match("/elsewhere/target/a.tmp"));
+    }
+
+    #[test]
+    fn test_directory_rule_uses_given_type() {
+        // No ignore file is there, only `.git/` rule is added
+        let root = std::path::Path::new("./target/exclude-test-gitignore");
+        let exclude = ExcludeSet::default().with_gitignore(build_gitignore(root, false).unwrap());
+        assert!(exclude.is_match_entry("./.git", true));
+        assert!(!exclude.is_match_entry("./.git", false));
+        assert!(exclude.is_match("./.git/config"));
+        assert!(exclude.is_match_entry("./.git/objects", true));
+    }
+
+    #[test]
+    fn test_trash_is_excluded() {
+        let exclude = ExcludeSet::default().with_trash_dir(".trash");
+        assert!(exclude.is_match("./.trash"));
+        assert!(exclude.is_match("./.trash/1700000000-a.txt"));
+        assert!(exclude.is_match(".trash/1700000000-dir/b.txt"));
+        assert!(!exclude.is_match("./.trashed"));
+        assert!(!exclude.is_match("./a/.trash/b.txt"));
+        assert!(!ExcludeSet::default().is_match("./.trash/a.txt"));
+    }
+
+    #[test]
+    fn test_sidecar_is_lexical() {
+        let exclude = ExcludeSet::default().with_hidden_sidecars(true);
+        // Original is never looked up
+        assert!(exclude.is_match("./missing/foo.txt.zst"));
+        assert!(exclude.is_match("/mnt/media/foo.txt.gz"));
+        assert!(exclude.is_match("./foo.tar.gz"));
+        assert!(!exclude.is_match("./foo.gz"));
+        assert!(!exclude.is_match("./.bashrc.gz"));
+        assert!(!exclude.is_match("./foo.txt.gz.bak"));
+        assert!(!exclude.is_match("./foo.txt"));
+        assert!(!ExcludeSet::default().is_match("./foo.txt.zst"));
+    }
+
+    #[test]
+    fn test_token_label() {
+        let entry =
+            |token: &str| AuthEntry::new(token.to_string(), vec![], false, None, None).unwrap();
+        // Tokens sharing prefix are told apart
+        assert_ne!(entry("abcd-1").label(), entry("abcd-2").label());
+        assert_eq!(entry("abcd-1").label(), entry("abcd-1").label());
+        assert!(!entry("abcd-1").label().contains("abcd"));
+
+        let labeled: AuthEntry = toml::from_str(
+            r#"
+            token = "abcd-1"
+            path = ["**"]
+            label = "backup"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(labeled.label(), "backup");
+
+        let stored = TokenInfo::new(7, "abcd-1".to_string(), vec![], false, None, 0);
+        assert_eq!(AuthEntry::try_from(&stored).unwrap().label(), "#7");
+    }
+
+    #[test]
+    fn test_access_log_retention() {
+        let option = |s: &str| toml::from_str::<AccessLogOption>(s).unwrap().retention();
+        assert_eq!(option(""), Some(Duration::from_secs(90 * 86400)));
+        assert_eq!(
+            option("retention_days = 7"),
+            Some(Duration::from_secs(7 * 86400))
+        );
+        assert_eq!(option("retention_days = 0"), None);
+    }
+}
@@ -0,0 +1,445 @@
+mod key {
+    use aes_gcm::{Aes256Gcm, KeyInit};
+    use anyhow::anyhow;
+    use std::sync::Arc;
+
+    pub const KEY_SIZE: usize = 32;
+
+    pub fn parse_hex(s: &str) -> Option<Vec<u8>> {
+        if !s.len().is_multiple_of(2) || !s.is_ascii() {
+            return None;
+        }
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+            .collect()
+    }
+
+    /// AES-256-GCM key used to encrypt files at rest
+    #[derive(Clone)]
+    pub struct EncryptionKey {
+        cipher: Arc<Aes256Gcm>,
+    }
+
+    impl EncryptionKey {
+        /// Accept 32 raw bytes or 64 hex characters (surrounding whitespace is ignored)
+        pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+            let key = if bytes.len() == KEY_SIZE {
+                bytes.to_vec()
+            } else {
+                std::str::from_utf8(bytes)
+                    .ok()
+                    .and_then(|s| parse_hex(s.trim()))
+                    .filter(|key| key.len() == KEY_SIZE)
+                    .ok_or_else(|| {
+                        anyhow!("Encryption key should be 32 bytes or 64 hex characters")
+                    })?
+            };
+            Ok(Self {
+                cipher: Arc::new(Aes256Gcm::new_from_slice(&key).unwrap()),
+            })
+        }
+
+        pub async fn from_file(path: &str) -> anyhow::Result<Self> {
+            let bytes = tokio::fs::read(shellexpand::tilde(path).as_ref())
+                .await
+                .map_err(|e| anyhow!("Unable to read key file: {:?}", e))?;
+            Self::from_bytes(&bytes)
+        }
+
+        /// Run `command` by `sh -c`, key is read from stdout (for KMS or secret manager)
+        pub async fn from_command(command: &str) -> anyhow::Result<Self> {
+            let output = tokio::process::Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .output()
+                .await
+                .map_err(|e| anyhow!("Unable to run key command: {:?}", e))?;
+            if !output.status.success() {
+                return Err(anyhow!("Key command exited with {}", output.status));
+            }
+            Self::from_bytes(&output.stdout)
+        }
+
+        pub(super) fn cipher(&self) -> &Aes256Gcm {
+            &self.cipher
+        }
+    }
+}
+
+mod format {
+    use super::EncryptionKey;
+    use aes_gcm::aead::rand_core::RngCore;
+    use aes_gcm::aead::{Aead, OsRng};
+    use aes_gcm::Nonce;
+    use std::io::{Error, ErrorKind};
+    use std::path::Path;
+    use tokio::fs::File;
+    use tokio::io::AsyncReadExt;
+
+    /// File starts with `MAGIC`, followed by nonce prefix and sealed chunks
+    pub const MAGIC: &[u8; 8] = b"FWENC\x00\x00\x01";
+    pub const PREFIX_SIZE: usize = 7;
+    pub const HEADER_SIZE: u64 = (MAGIC.len() + PREFIX_SIZE) as u64;
+    /// Plaintext size of each chunk, only last chunk can be shorter
+    pub const CHUNK_SIZE: usize = 64 * 1024;
+    pub const TAG_SIZE: usize = 16;
+    pub const SEALED_CHUNK_SIZE: u64 = (CHUNK_SIZE + TAG_SIZE) as u64;
+
+    /// Chunk count of encrypted file, `None` if `len` is not valid encrypted file length
+    pub fn chunk_count(len: u64) -> Option<u64> {
+        let body = len.checked_sub(HEADER_SIZE)?;
+        let count = body.div_ceil(SEALED_CHUNK_SIZE);
+        // Last chunk must contain tag
+        (count > 0 && body - (count - 1) * SEALED_CHUNK_SIZE >= TAG_SIZE as u64).then_some(count)
+    }
+
+    /// Plaintext length of encrypted file which size is `len`
+    pub fn plaintext_len(len: u64) -> Option<u64> {
+        chunk_count(len).map(|count| len - HEADER_SIZE - count * TAG_SIZE as u64)
+    }
+
+    pub async fn is_encrypted<P: AsRef<Path>>(path: P) -> std::io::Result<bool> {
+        let mut file = File::open(path).await?;
+        let mut magic = [0u8; MAGIC.len()];
+        match file.read_exact(&mut magic).await {
+            Ok(_) => Ok(&magic == MAGIC),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Seal and open chunks, nonce is `prefix || counter (BE32) || last flag` like STREAM
+    pub struct ChunkCipher {
+        key: EncryptionKey,
+        prefix: [u8; PREFIX_SIZE],
+    }
+
+    impl ChunkCipher {
+        pub fn new(key: EncryptionKey, prefix: [u8; PREFIX_SIZE]) -> Self {
+            Self { key, prefix }
+        }
+
+        pub fn random(key: EncryptionKey) -> Self {
+            let mut prefix = [0u8; PREFIX_SIZE];
+            OsRng.fill_bytes(&mut prefix);
+            Self::new(key, prefix)
+        }
+
+        pub fn header(&self) -> Vec<u8> {
+            [&MAGIC[..], &self.prefix].concat()
+        }
+
+        fn nonce(&self, counter: u64, last: bool) -> std::io::Result<[u8; 12]> {
+            let counter = u32::try_from(counter)
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, "Encrypted file is too large"))?;
+            let mut nonce = [0u8; 12];
+            nonce[..PREFIX_SIZE].copy_from_slice(&self.prefix);
+            nonce[PREFIX_SIZE..11].copy_from_slice(&counter.to_be_bytes());
+            nonce[11] = last as u8;
+            Ok(nonce)
+        }
+
+        pub fn seal(&self, counter: u64, last: bool, data: &[u8]) -> std::io::Result<Vec<u8>> {
+            self.key
+                .cipher()
+                .encrypt(Nonce::from_slice(&self.nonce(counter, last)?), data)
+                .map_err(|_| Error::other("Unable to encrypt chunk"))
+        }
+
+        pub fn open(&self, counter: u64, last: bool, data: &[u8]) -> std::io::Result<Vec<u8>> {
+            self.key
+                .cipher()
+                .decrypt(Nonce::from_slice(&self.nonce(counter, last)?), data)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "Unable to decrypt chunk"))
+        }
+    }
+
+    /// Read header of encrypted file, return cipher and chunk count
+    pub async fn read_header(
+        file: &mut File,
+        key: EncryptionKey,
+    ) -> std::io::Result<(ChunkCipher, u64)> {
+        let count = chunk_count(file.metadata().await?.len())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid encrypted file length"))?;
+        let mut header = [0u8; HEADER_SIZE as usize];
+        file.read_exact(&mut header).await?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "File is not encrypted"));
+        }
+        let mut prefix = [0u8; PREFIX_SIZE];
+        prefix.copy_from_slice(&header[MAGIC.len()..]);
+        Ok((ChunkCipher::new(key, prefix), count))
+    }
+}
+
+mod stream {
+    use super::format::{read_header, ChunkCipher, CHUNK_SIZE, HEADER_SIZE, SEALED_CHUNK_SIZE};
+    use super::EncryptionKey;
+    use crate::configure::ExcludeSet;
+    use async_walkdir::WalkDir;
+    use axum::body::Bytes;
+    use futures::{Stream, StreamExt};
+    use std::io::SeekFrom;
+    use std::path::{Path, PathBuf};
+    use tokio::fs::{File, OpenOptions};
+    use tokio::io::{
+        AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, DuplexStream,
+    };
+    use tracing::warn;
+
+    const DECRYPT_BUFFER_SIZE: usize = 64 * 1024;
+
+    /// Read at most `len` bytes, shorter only if reach end of file
+    async fn read_chunk<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        len: usize,
+    ) -> std::io::Result<Vec<u8>> {
+        let mut buffer = Vec::with_capacity(len);
+        reader.take(len as u64).read_to_end(&mut buffer).await?;
+        Ok(buffer)
+    }
+
+    pub async fn decrypt_to<W: AsyncWrite + Unpin>(
+        path: &Path,
+        key: EncryptionKey,
+        writer: &mut W,
+    ) -> std::io::Result<()> {
+        let mut file = File::open(path).await?;
+        let (cipher, count) = read_header(&mut file, key).await?;
+        for counter in 0..count {
+            let chunk = read_chunk(&mut file, SEALED_CHUNK_SIZE as usize).await?;
+            writer
+                .write_all(&cipher.open(counter, counter + 1 == count, &chunk)?)
+                .await?;
+        }
+        writer.flush().await
+    }
+
+    /// Decrypt file in background task, return reader side of plaintext stream
+    pub fn spawn_decrypt(path: PathBuf, key: EncryptionKey) -> DuplexStream {
+        let (mut writer, reader) = tokio::io::duplex(DECRYPT_BUFFER_SIZE);
+        tokio::spawn(async move {
+            if let Err(e) = decrypt_to(&path, key, &mut writer).await {
+                // Client disconnected will also cause broken pipe
+                if e.kind() != std::io::ErrorKind::BrokenPipe {
+                    warn!("Unable to decrypt {}: {:?}", path.display(), e);
+                }
+            }
+        });
+        reader
+    }
+
+    /// Encrypt plaintext file in place, file is written to temporary file then renamed
+    pub async fn encrypt_file(path: &Path, key: EncryptionKey) -> std::io::Result<()> {
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(".encrypting");
+        let temp = path.with_file_name(name);
+        encrypt_to(path, &temp, key).await?;
+        tokio::fs::rename(&temp, path).await
+    }
+
+    /// Encrypt plaintext read from `reader` until its end, length is not known in advance, so
+    /// each chunk is sealed once next one is read
+    pub async fn encrypt_stream<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+        mut reader: R,
+        output: &mut W,
+        key: EncryptionKey,
+    ) -> std::io::Result<()> {
+        let cipher = ChunkCipher::random(key);
+        output.write_all(&cipher.header()).await?;
+        let mut chunk = read_chunk(&mut reader, CHUNK_SIZE).await?;
+        let mut counter = 0;
+        loop {
+            // Empty plaintext is one empty chunk, like file of whole chunks ends with full one
+            let next = if chunk.len() == CHUNK_SIZE {
+                read_chunk(&mut reader, CHUNK_SIZE).await?
+            } else {
+                Vec::new()
+            };
+            let last = next.is_empty();
+            output
+                .write_all(&cipher.seal(counter, last, &chunk)?)
+                .await?;
+            if last {
+                break;
+            }
+            chunk = next;
+            counter += 1;
+        }
+        output.flush().await
+    }
+
+    /// Write encrypted copy of plaintext file `path` to `target`, `target` is removed on error
+    pub async fn encrypt_to(path: &Path, target: &Path, key: EncryptionKey) -> std::io::Result<()> {
+        let file = File::open(path).await?;
+        let mut output = File::create(target).await?;
+        let result = async {
+            encrypt_stream(file, &mut output, key).await?;
+            output.sync_all().await
+        }
+        .await;
+        if result.is_err() {
+            tokio::fs::remove_file(target).await.ok();
+        }
+        result
+    }
+
+    /// Encrypt all plaintext files under `root` (or `root` itself), return encrypted file count
+    pub async fn encrypt_tree(
+        root: &Path,
+        key: EncryptionKey,
+        exclude: &ExcludeSet,
+    ) -> std::io::Result<usize> {
+        let mut files = Vec::new();
+        if root.is_dir() {
+            let mut entries = WalkDir::new(root);
+            while let Some(entry) = entries.next().await {
+                let entry = entry?;
+                if entry.file_type().await?.is_file() && !exclude.is_match(entry.path()) {
+                    files.push(entry.path());
+                }
+            }
+        } else {
+            files.push(root.to_path_buf());
+        }
+
+        let mut count = 0;
+        for file in files {
+            if super::is_encrypted(&file).await? {
+                continue;
+            }
+            encrypt_file(&file, key.clone()).await?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Append plaintext `body` to encrypted file, last chunk is decrypted and sealed again
+    ///
+    /// File is modified in place, interrupted append may leave file undecryptable
+    pub async fn append_encrypted<S, E>(
+        path: &Path,
+        key: EncryptionKey,
+        mut body: S,
+    ) -> std::io::Result<()>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let mut file = OpenOptions::new().read(true).write(true).open(path).await?;
+        let (cipher, count) = read_header(&mut file, key).await?;
+        let mut counter = count - 1;
+        let last_offset = HEADER_SIZE + counter * SEALED_CHUNK_SIZE;
+        file.seek(SeekFrom::Start(last_offset)).await?;
+        let mut buffer = cipher.open(
+            counter,
+            true,
+            &read_chunk(&mut file, SEALED_CHUNK_SIZE as usize).await?,
+        )?;
+
+        // Keep whole body before rewrite last chunk, so body error will not break file
+        let mut pending = Vec::new();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|e| std::io::Error::other(e))?;
+            pending.push(chunk);
+        }
+
+        file.set_len(last_offset).await?;
+        file.seek(SeekFrom::Start(last_offset)).await?;
+        for chunk in pending {
+            buffer.extend_from_slice(&chunk);
+            while buffer.len() > CHUNK_SIZE {
+                let rest = buffer.split_off(CHUNK_SIZE);
+                file.write_all(&cipher.seal(counter, false, &buffer)?)
+                    .await?;
+                buffer = rest;
+                counter += 1;
+            }
+        }
+        file.write_all(&cipher.seal(counter, true, &buffer)?)
+            .await?;
+        file.flush().await
+    }
+}
+
+mod signing {
+    use super::key::parse_hex;
+    use hmac::{Hmac, Mac};
+    use publib::mirror::sign_manifest;
+    use publib::types::{Manifest, OptionFile};
+    use sha2::Sha256;
+    use std::sync::Arc;
+
+    type HmacSha256 = Hmac<Sha256>;
+
+    /// HMAC-SHA256 signer of download URL, link can be shared without bearer token
+    #[derive(Clone)]
+    pub struct UrlSigner {
+        secret: Arc<Vec<u8>>,
+        max_expires_in: u64,
+    }
+
+    impl UrlSigner {
+        pub fn new(secret: &[u8], max_expires_in: u64) -> Self {
+            Self {
+                secret: Arc::new(secret.to_vec()),
+                max_expires_in,
+            }
+        }
+
+        pub fn max_expires_in(&self) -> u64 {
+            self.max_expires_in
+        }
+
+        fn mac(&self, path: &str, expires: i64) -> HmacSha256 {
+            let mut mac = HmacSha256::new_from_slice(&self.secret).unwrap();
+            mac.update(path.as_bytes());
+            mac.update(b"\n");
+            mac.update(expires.to_string().as_bytes());
+            mac
+        }
+
+        /// Signature of normalized `path` in hex, valid until `expires` (unix timestamp)
+        pub fn sign(&self, path: &str, expires: i64) -> String {
+            self.mac(path, expires).finalize().into_bytes().iter().fold(
+                String::new(),
+                |mut s, byte| {
+                    s.push_str(&format!("{:02x}", byte));
+                    s
+                },
+            )
+        }
+
+        /// Manifest of `files` for mirror verification, signed by same secret as URL
+        pub fn sign_manifest(&self, files: Vec<OptionFile>, now: i64) -> Manifest {
+            sign_manifest(&self.secret, now, files)
+        }
+
+        pub fn verify(&self, path: &str, expires: i64, signature: &str, now: i64) -> bool {
+            if expires < now {
+                return false;
+            }
+            match parse_hex(signature) {
+                Some(signature) => self.mac(path, expires).verify_slice(&signature).is_ok(),
+                None => false,
+            }
+        }
+    }
+
+    impl std::fmt::Debug for UrlSigner {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("UrlSigner")
+                .field("max_expires_in", &self.max_expires_in)
+                .finish_non_exhaustive()
+        }
+    }
+}
+
+pub use format::{is_encrypted, plaintext_len};
+pub use key::{parse_hex, EncryptionKey};
+pub use signing::UrlSigner;
+pub use stream::{
+    append_encrypted, decrypt_to, encrypt_file, encrypt_stream, encrypt_to, encrypt_tree,
+    spawn_decrypt,
+};
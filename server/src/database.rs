@@ -1,12 +1,35 @@
 pub mod v1 {
     use format_sql_query::QuotedData;
-    use publib::types::FileEntry;
-    use publib::PATH_UTF8_ERROR;
-    use sqlx::{Result, SqliteConnection};
+    use publib::file::HashAlgo;
+    use publib::types::{
+        AccessRecord, AccessStats, Collation, DirStats, DuplicateGroup, DuplicateReport,
+        ErrorClass, FileDownloads, FileEntry, HoldInfo, IndexError, IntegrityIssue, LinkInfo,
+        ListOption, Pagination, SortBy, SortOrder, TokenInfo, TokenUsage, UploadState,
+        UploadStatus,
+    };
+    use publib::{normalize_path, PATH_UTF8_ERROR};
+    use sqlx::{Connection, Result, SqliteConnection};
+    use std::collections::HashMap;
     use std::path::Path;
 
+    /// Schema version created by `CREATE_TABLE`, later versions are applied by migrations
     pub const VERSION: &str = "1";
 
+    /// Collation registered in `load_database`, see `publib::natural_cmp`
+    pub const NATURAL_COLLATION: &str = "natural_order";
+
+    /// Bump when digest of same content changed, stored hashes will be recomputed on startup
+    pub const HASH_VERSION: &str = "3";
+    pub(super) const HASH_VERSION_KEY: &str = "hash_version";
+    pub(super) const EVENT_CHECKPOINT_KEY: &str = "event_checkpoint";
+    pub(super) const GIT_COMMIT_KEY: &str = "git_commit";
+    pub(super) const SCRUB_CURSOR_KEY: &str = "scrub_cursor";
+
+    /// Stored hash version, changing algorithm also need recompute hashes
+    pub(super) fn hash_version(algo: HashAlgo) -> String {
+        format!("{}-{}", HASH_VERSION, algo.name())
+    }
+
     pub(super) const CREATE_TABLE: &str = r#"
         CREATE TABLE "files" (
             "path"	TEXT NOT NULL,
@@ -15,6 +38,7 @@ pub mod v1 {
             "size"	INTEGER NOT NULL DEFAULT 0,
             "is_dir"	INTEGER NOT NULL DEFAULT 0,
             "marked"    INTEGER NOT NULL DEFAULT 0,
+            "encrypted" INTEGER NOT NULL DEFAULT 0,
             PRIMARY KEY("path")
         );
                 
@@ -24,146 +48,3890 @@ pub mod v1 {
         );
         "#;
 
-    pub async fn query_path<P: AsRef<Path>>(
-        conn: &mut SqliteConnection,
-        path: P,
-    ) -> Result<Option<FileEntry>> {
-        query(conn, path.as_ref().to_str().expect(PATH_UTF8_ERROR)).await
+    pub(super) const CREATE_TOKENS_TABLE: &str = r#"
+        CREATE TABLE IF NOT EXISTS "tokens" (
+            "id"	INTEGER NOT NULL,
+            "token"	TEXT NOT NULL UNIQUE,
+            "paths"	TEXT NOT NULL,
+            "admin"	INTEGER NOT NULL DEFAULT 0,
+            "concurrent_requests"	INTEGER,
+            "scopes"	TEXT,
+            "created_at"	INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY("id" AUTOINCREMENT)
+        );
+        "#;
+
+    /// Aggregate of files under each directory, kept up to date by functions modify "files"
+    pub(super) const CREATE_STATS_TABLE: &str = r#"
+        CREATE TABLE IF NOT EXISTS "dir_stats" (
+            "path"	TEXT NOT NULL,
+            "files"	INTEGER NOT NULL DEFAULT 0,
+            "size"	INTEGER NOT NULL DEFAULT 0,
+            "mtime"	INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY("path")
+        );
+        "#;
+
+    pub(super) type TokenRow = (i64, String, String, bool, Option<i64>, Option<String>, i64);
+
+    pub(super) fn token_from_row(row: TokenRow) -> TokenInfo {
+        let (id, token, paths, admin, concurrent_requests, scopes, created_at) = row;
+        TokenInfo::new(
+            id,
+            token,
+            serde_json::from_str(&paths).unwrap_or_default(),
+            admin,
+            concurrent_requests.map(|count| count as usize),
+            created_at,
+        )
+        .with_scopes(scopes.and_then(|scopes| serde_json::from_str(&scopes).ok()))
     }
 
-    #[allow(unused)]
-    pub async fn query_by_path(
-        conn: &mut SqliteConnection,
-        path: String,
-    ) -> Result<Vec<FileEntry>> {
-        let quoted = insert_percent(path);
-        sqlx::query_as::<_, FileEntry>(&format!(
-            r#"SELECT * FROM "files" WHERE "path" LIKE {}"#,
-            quoted
-        ))
+    pub async fn query_tokens(conn: &mut SqliteConnection) -> Result<Vec<TokenInfo>> {
+        Ok(sqlx::query_as::<_, TokenRow>(
+            r#"SELECT "id", "token", "paths", "admin", "concurrent_requests", "scopes", "created_at"
+            FROM "tokens" ORDER BY "id""#,
+        )
         .fetch_all(conn)
-        .await
+        .await?
+        .into_iter()
+        .map(token_from_row)
+        .collect())
     }
 
-    pub async fn query(conn: &mut SqliteConnection, path: &str) -> Result<Option<FileEntry>> {
-        sqlx::query_as::<_, FileEntry>(r#"SELECT * FROM "files" WHERE "path" = ?"#)
+    /// `id` of `token` is ignored, return token with id assigned by database
+    pub async fn insert_token(conn: &mut SqliteConnection, token: &TokenInfo) -> Result<TokenInfo> {
+        let (id,) = sqlx::query_as::<_, (i64,)>(
+            r#"INSERT INTO "tokens" ("token", "paths", "admin", "concurrent_requests", "scopes", "created_at")
+            VALUES (?, ?, ?, ?, ?, ?) RETURNING "id""#,
+        )
+        .bind(token.token())
+        .bind(serde_json::to_string(token.paths()).unwrap())
+        .bind(token.admin())
+        .bind(token.concurrent_requests().map(|count| count as i64))
+        .bind(token.scopes().map(|scopes| serde_json::to_string(scopes).unwrap()))
+        .bind(token.created_at())
+        .fetch_one(conn)
+        .await?;
+        Ok(TokenInfo::new(
+            id,
+            token.token().to_string(),
+            token.paths().clone(),
+            token.admin(),
+            token.concurrent_requests(),
+            token.created_at(),
+        )
+        .with_scopes(token.scopes().cloned()))
+    }
+
+    /// Return `false` if token is not found
+    pub async fn delete_token(conn: &mut SqliteConnection, id: i64) -> Result<bool> {
+        let result = sqlx::query(r#"DELETE FROM "tokens" WHERE "id" = ?"#)
+            .bind(id)
+            .execute(conn)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn query_holds(conn: &mut SqliteConnection) -> Result<Vec<HoldInfo>> {
+        Ok(sqlx::query_as::<_, (String, Option<String>, i64)>(
+            r#"SELECT "path", "reason", "created_at" FROM "holds" ORDER BY "path""#,
+        )
+        .fetch_all(conn)
+        .await?
+        .into_iter()
+        .map(|(path, reason, created_at)| HoldInfo::new(path, reason, created_at))
+        .collect())
+    }
+
+    /// Replace hold of same path
+    pub async fn insert_hold(conn: &mut SqliteConnection, hold: &HoldInfo) -> Result<()> {
+        sqlx::query(r#"INSERT OR REPLACE INTO "holds" VALUES (?, ?, ?)"#)
+            .bind(hold.path())
+            .bind(hold.reason())
+            .bind(hold.created_at())
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Return `false` if path is not held
+    pub async fn delete_hold(conn: &mut SqliteConnection, path: &str) -> Result<bool> {
+        let result = sqlx::query(r#"DELETE FROM "holds" WHERE "path" = ?"#)
             .bind(path)
-            .fetch_optional(conn)
-            .await
+            .execute(conn)
+            .await?;
+        Ok(result.rows_affected() > 0)
     }
 
-    pub async fn update(conn: &mut SqliteConnection, entry: FileEntry) -> Result<()> {
-        sqlx::query(r#"UPDATE "files" SET "hash" = ?, "mtime" = ?, "size" = ?, "marked" = 1" WHERE "path" = ?"#)
-            .bind(entry.hash())
-            .bind(entry.mtime())
-            .bind(entry.size())
-            .bind(entry.path())
+    pub(super) type LinkRow = (i64, String, String, String, i64, i64);
+
+    pub(super) fn link_from_row(row: LinkRow) -> LinkInfo {
+        let (id, token, path, signature, expires, created_at) = row;
+        LinkInfo::new(id, token, path, signature, expires, created_at)
+    }
+
+    pub async fn query_links(conn: &mut SqliteConnection) -> Result<Vec<LinkInfo>> {
+        Ok(sqlx::query_as::<_, LinkRow>(
+            r#"SELECT "id", "token", "path", "signature", "expires", "created_at"
+            FROM "links" ORDER BY "id""#,
+        )
+        .fetch_all(conn)
+        .await?
+        .into_iter()
+        .map(link_from_row)
+        .collect())
+    }
+
+    /// `id` of `link` is ignored, return link with id assigned by database
+    pub async fn insert_link(conn: &mut SqliteConnection, link: &LinkInfo) -> Result<LinkInfo> {
+        let (id,) = sqlx::query_as::<_, (i64,)>(
+            r#"INSERT INTO "links" ("token", "path", "signature", "expires", "created_at")
+            VALUES (?, ?, ?, ?, ?) RETURNING "id""#,
+        )
+        .bind(link.token())
+        .bind(link.path())
+        .bind(link.signature())
+        .bind(link.expires())
+        .bind(link.created_at())
+        .fetch_one(conn)
+        .await?;
+        Ok(LinkInfo::new(
+            id,
+            link.token().to_string(),
+            link.path().to_string(),
+            link.signature().to_string(),
+            link.expires(),
+            link.created_at(),
+        ))
+    }
+
+    /// Return `false` if link is not found or minted by other token
+    pub async fn delete_link(conn: &mut SqliteConnection, token: &str, id: i64) -> Result<bool> {
+        let result = sqlx::query(r#"DELETE FROM "links" WHERE "id" = ? AND "token" = ?"#)
+            .bind(id)
+            .bind(token)
+            .execute(conn)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Return number of links expired before `now`
+    pub async fn delete_expired_links(conn: &mut SqliteConnection, now: i64) -> Result<u64> {
+        let result = sqlx::query(r#"DELETE FROM "links" WHERE "expires" < ?"#)
+            .bind(now)
             .execute(conn)
             .await?;
+        Ok(result.rows_affected())
+    }
+
+    pub(super) type UploadRow = (
+        i64,
+        String,
+        String,
+        String,
+        Option<String>,
+        i64,
+        Option<i64>,
+    );
+
+    pub(super) fn upload_from_row(row: UploadRow) -> UploadStatus {
+        let (id, token, path, state, reason, created_at, finished_at) = row;
+        UploadStatus::new(id as u64, token, path, created_at).with_state(
+            UploadState::from_name(&state),
+            reason,
+            finished_at,
+        )
+    }
+
+    /// `id` of `upload` is ignored, return upload with id assigned by database
+    pub async fn insert_upload(
+        conn: &mut SqliteConnection,
+        upload: &UploadStatus,
+    ) -> Result<UploadStatus> {
+        let (id,) = sqlx::query_as::<_, (i64,)>(
+            r#"INSERT INTO "uploads" ("token", "path", "state", "reason", "created_at", "finished_at")
+            VALUES (?, ?, ?, ?, ?, ?) RETURNING "id""#,
+        )
+        .bind(upload.token())
+        .bind(upload.path())
+        .bind(upload.state().name())
+        .bind(upload.reason())
+        .bind(upload.created_at())
+        .bind(upload.finished_at())
+        .fetch_one(conn)
+        .await?;
+        Ok(UploadStatus::new(
+            id as u64,
+            upload.token().to_string(),
+            upload.path().to_string(),
+            upload.created_at(),
+        )
+        .with_state(
+            upload.state(),
+            upload.reason().map(str::to_string),
+            upload.finished_at(),
+        ))
+    }
+
+    /// Store state, reason and finish time of `upload`
+    pub async fn update_upload(conn: &mut SqliteConnection, upload: &UploadStatus) -> Result<()> {
+        sqlx::query(
+            r#"UPDATE "uploads" SET "state" = ?, "reason" = ?, "finished_at" = ? WHERE "id" = ?"#,
+        )
+        .bind(upload.state().name())
+        .bind(upload.reason())
+        .bind(upload.finished_at())
+        .bind(upload.id() as i64)
+        .execute(conn)
+        .await?;
         Ok(())
     }
 
-    #[allow(unused)]
-    pub async fn mark_path<P: AsRef<Path>>(conn: &mut SqliteConnection, path: P) -> Result<()> {
-        mark_path_str(conn, path.as_ref().to_str().expect(PATH_UTF8_ERROR)).await
+    pub async fn query_upload(
+        conn: &mut SqliteConnection,
+        id: u64,
+    ) -> Result<Option<UploadStatus>> {
+        Ok(sqlx::query_as::<_, UploadRow>(
+            r#"SELECT "id", "token", "path", "state", "reason", "created_at", "finished_at"
+            FROM "uploads" WHERE "id" = ?"#,
+        )
+        .bind(id as i64)
+        .fetch_optional(conn)
+        .await?
+        .map(upload_from_row))
     }
 
-    pub async fn mark_path_str(conn: &mut SqliteConnection, path: &str) -> Result<()> {
-        sqlx::query(r#"UPDATE "files" SET "marked" = 1 WHERE "path" = ?"#)
-            .bind(path)
+    /// Return number of uploads finished before `before`, pending uploads are kept
+    pub async fn delete_finished_uploads(conn: &mut SqliteConnection, before: i64) -> Result<u64> {
+        let result = sqlx::query(r#"DELETE FROM "uploads" WHERE "finished_at" < ?"#)
+            .bind(before)
             .execute(conn)
-            .await
-            .map(|_| ())
+            .await?;
+        Ok(result.rows_affected())
     }
 
-    pub async fn mark(conn: &mut SqliteConnection, entry: FileEntry) -> Result<()> {
-        mark_path_str(conn, entry.path()).await
+    pub async fn query_integrity_issues(
+        conn: &mut SqliteConnection,
+    ) -> Result<Vec<IntegrityIssue>> {
+        Ok(sqlx::query_as::<_, (String, String, String, i64)>(
+            r#"SELECT "path", "expected", "actual", "detected_at" FROM "integrity_issues"
+            ORDER BY "path""#,
+        )
+        .fetch_all(conn)
+        .await?
+        .into_iter()
+        .map(|(path, expected, actual, detected_at)| {
+            IntegrityIssue::new(path, expected, actual, detected_at)
+        })
+        .collect())
     }
 
-    pub async fn reset_all_mark(conn: &mut SqliteConnection) -> Result<()> {
-        sqlx::query(r#"UPDATE "files" SET "marked" = 0"#)
+    /// Replace issue of same path
+    pub async fn insert_integrity_issue(
+        conn: &mut SqliteConnection,
+        issue: &IntegrityIssue,
+    ) -> Result<()> {
+        sqlx::query(r#"INSERT OR REPLACE INTO "integrity_issues" VALUES (?, ?, ?, ?)"#)
+            .bind(issue.path())
+            .bind(issue.expected())
+            .bind(issue.actual())
+            .bind(issue.detected_at())
             .execute(conn)
             .await?;
         Ok(())
     }
 
-    pub async fn delete(conn: &mut SqliteConnection, path: String) -> Result<()> {
-        let p: &Path = path.as_ref();
-        if p.is_dir() {
-            let quoted = insert_percent(path);
-            sqlx::query(&format!(
-                r#"DELETE FROM "files" WHERE "path" LIKE {}"#,
-                quoted
-            ))
+    /// Remove issue of `path`, and issues of paths not indexed anymore
+    pub async fn delete_integrity_issue(conn: &mut SqliteConnection, path: &str) -> Result<()> {
+        sqlx::query(
+            r#"DELETE FROM "integrity_issues" WHERE "path" = ?
+            OR "path" NOT IN (SELECT "path" FROM "files")"#,
+        )
+        .bind(path)
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    pub(super) type ErrorRow = (String, String, String, i64);
+
+    pub(super) fn error_from_row(row: ErrorRow) -> IndexError {
+        let (path, class, message, occurred_at) = row;
+        IndexError::new(path, ErrorClass::from_name(&class), message, occurred_at)
+    }
+
+    pub async fn query_errors(conn: &mut SqliteConnection) -> Result<Vec<IndexError>> {
+        Ok(sqlx::query_as::<_, ErrorRow>(
+            r#"SELECT "path", "class", "message", "occurred_at" FROM "errors" ORDER BY "path""#,
+        )
+        .fetch_all(conn)
+        .await?
+        .into_iter()
+        .map(error_from_row)
+        .collect())
+    }
+
+    /// Replace error of same path
+    pub async fn insert_error(conn: &mut SqliteConnection, error: &IndexError) -> Result<()> {
+        sqlx::query(r#"INSERT OR REPLACE INTO "errors" VALUES (?, ?, ?, ?)"#)
+            .bind(error.path())
+            .bind(error.class().name())
+            .bind(error.message())
+            .bind(error.occurred_at())
             .execute(conn)
             .await?;
-        } else {
-            sqlx::query(r#"DELETE FROM "files" WHERE "path" = ?"#)
-                .bind(path)
-                .execute(conn)
-                .await?;
-        }
         Ok(())
     }
 
-    pub async fn delete_all_unmarked(conn: &mut SqliteConnection) -> Result<()> {
-        sqlx::query(r#"DELETE FROM "files" WHERE "marked" = 0"#)
+    pub async fn delete_error(conn: &mut SqliteConnection, path: &str) -> Result<()> {
+        sqlx::query(r#"DELETE FROM "errors" WHERE "path" = ?"#)
+            .bind(path)
             .execute(conn)
             .await?;
         Ok(())
     }
 
-    pub async fn insert(conn: &mut SqliteConnection, entry: FileEntry) -> Result<()> {
-        if entry.is_dir() {
-            sqlx::query(r#"INSERT INTO "files" ("path", "is_dir") VALUES (?, ?)"#)
-                .bind(entry.path())
-                .bind(1)
-                .execute(conn)
-                .await?;
-        } else {
-            sqlx::query(r#"INSERT INTO "files" VALUES (?, ?, ?, ?, ?, ?)"#)
-                .bind(entry.path())
-                .bind(entry.hash())
-                .bind(entry.mtime())
-                .bind(entry.size())
-                .bind(0)
-                .bind(1)
-                .execute(conn)
-                .await?;
-        }
+    /// Errors under `path` are recorded again by scan if they still happen
+    pub async fn delete_errors_under(conn: &mut SqliteConnection, path: &str) -> Result<()> {
+        sqlx::query(&format!(
+            r#"DELETE FROM "errors" WHERE {}"#,
+            prefix_condition(path)
+        ))
+        .execute(conn)
+        .await?;
         Ok(())
     }
 
-    pub fn insert_percent(s: String) -> String {
-        let mut quoted = QuotedData(&if s.ends_with('/') {
-            s
-        } else {
-            format!("{}/", s)
-        })
-        .to_string();
-        debug_assert!(quoted.ends_with("'"));
-        quoted.insert(quoted.len() - 1, '%');
-        quoted
+    pub async fn insert_access(conn: &mut SqliteConnection, record: &AccessRecord) -> Result<()> {
+        sqlx::query(
+            r#"INSERT INTO "access_log" ("token", "path", "bytes", "duration_ms", "status",
+            "accessed_at") VALUES (?, ?, ?, ?, ?, ?)"#,
+        )
+        .bind(record.token())
+        .bind(record.path())
+        .bind(record.bytes() as i64)
+        .bind(record.duration_ms() as i64)
+        .bind(record.status())
+        .bind(record.accessed_at())
+        .execute(conn)
+        .await?;
+        Ok(())
     }
-}
 
-pub async fn load_database(path: &str) -> sqlx::Result<sqlx::SqliteConnection> {
-    let mut conn = SqliteConnectOptions::new()
-        .create_if_missing(true)
-        .filename(path)
-        .connect()
+    /// Return number of records accessed before `before`
+    pub async fn delete_access_before(conn: &mut SqliteConnection, before: i64) -> Result<u64> {
+        let result = sqlx::query(r#"DELETE FROM "access_log" WHERE "accessed_at" < ?"#)
+            .bind(before)
+            .execute(conn)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Top `limit` files by successful downloads, and bytes sent to each token per UTC day
+    pub async fn query_access_stats(
+        conn: &mut SqliteConnection,
+        since: i64,
+        limit: i64,
+    ) -> Result<AccessStats> {
+        let top_files = sqlx::query_as::<_, (String, i64, i64)>(
+            r#"SELECT "path", COUNT(*), SUM("bytes") FROM "access_log"
+            WHERE "accessed_at" >= ? AND "status" < 300
+            GROUP BY "path" ORDER BY COUNT(*) DESC, "path" LIMIT ?"#,
+        )
+        .bind(since)
+        .bind(limit)
+        .fetch_all(&mut *conn)
+        .await?
+        .into_iter()
+        .map(|(path, downloads, bytes)| FileDownloads::new(path, downloads, bytes))
+        .collect();
+        let tokens = sqlx::query_as::<_, (String, i64, i64, i64)>(
+            r#"SELECT "token", "accessed_at" / 86400 * 86400 AS "day", COUNT(*), SUM("bytes")
+            FROM "access_log" WHERE "accessed_at" >= ?
+            GROUP BY "token", "day" ORDER BY "day", "token""#,
+        )
+        .bind(since)
+        .fetch_all(conn)
+        .await?
+        .into_iter()
+        .map(|(token, day, downloads, bytes)| TokenUsage::new(token, day, downloads, bytes))
+        .collect();
+        Ok(AccessStats::new(since, top_files, tokens))
+    }
+
+    /// Chunk digests are stored as JSON array, replaced whenever file is hashed again
+    pub async fn upsert_chunks(
+        conn: &mut SqliteConnection,
+        path: &str,
+        hash: &str,
+        chunk_size: i64,
+        chunks: &[String],
+    ) -> Result<()> {
+        sqlx::query(r#"INSERT OR REPLACE INTO "chunks" VALUES (?, ?, ?, ?)"#)
+            .bind(path)
+            .bind(hash)
+            .bind(chunk_size)
+            .bind(serde_json::to_string(chunks).unwrap())
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+
+    /// `(hash, chunk_size, chunks)` of `path`, hash is digest of file when chunks computed
+    pub async fn query_chunks(
+        conn: &mut SqliteConnection,
+        path: &str,
+    ) -> Result<Option<(String, i64, Vec<String>)>> {
+        Ok(sqlx::query_as::<_, (String, i64, String)>(
+            r#"SELECT "hash", "chunk_size", "chunks" FROM "chunks" WHERE "path" = ?"#,
+        )
+        .bind(path)
+        .fetch_optional(conn)
+        .await?
+        .map(|(hash, chunk_size, chunks)| {
+            (
+                hash,
+                chunk_size,
+                serde_json::from_str(&chunks).unwrap_or_default(),
+            )
+        }))
+    }
+
+    /// Chunks of removed files or of older content of files
+    pub async fn delete_stale_chunks(conn: &mut SqliteConnection) -> Result<u64> {
+        Ok(sqlx::query(
+            r#"DELETE FROM "chunks" WHERE NOT EXISTS (SELECT 1 FROM "files"
+            WHERE "files"."path" = "chunks"."path" AND "files"."hash" = "chunks"."hash")"#,
+        )
+        .execute(conn)
+        .await?
+        .rows_affected())
+    }
+
+    /// `LIKE` patterns of rows under directory `path` and rows deeper than its children
+    pub fn children_patterns(path: &str) -> (String, String) {
+        (under_pattern(path, "%"), under_pattern(path, "%/%"))
+    }
+
+    /// Rows right under directory `path`, deeper descendants are not counted
+    pub async fn count_children(conn: &mut SqliteConnection, path: &str) -> Result<i64> {
+        let (children, descendants) = children_patterns(path);
+        let (count,) = sqlx::query_as::<_, (i64,)>(&format!(
+            r#"SELECT COUNT(*) FROM "files" WHERE "path" LIKE {} AND "path" NOT LIKE {}"#,
+            children, descendants
+        ))
+        .fetch_one(conn)
         .await?;
-    if !check_database(&mut conn, "meta").await? {
-        sqlx::query(current::CREATE_TABLE)
-            .execute(&mut conn)
+        Ok(count)
+    }
+
+    /// Files not modified or downloaded since `before`, tiered files are skipped
+    pub async fn query_cold_files(
+        conn: &mut SqliteConnection,
+        before: i64,
+        limit: i64,
+    ) -> Result<Vec<FileEntry>> {
+        sqlx::query_as::<_, FileEntry>(
+            r#"SELECT * FROM "files" WHERE "is_dir" = 0 AND "mtime" < ?
+            AND "path" NOT IN (SELECT "path" FROM "tiered")
+            AND "path" NOT IN (SELECT "path" FROM "access_log" WHERE "accessed_at" >= ?)
+            ORDER BY "mtime" LIMIT ?"#,
+        )
+        .bind(before)
+        .bind(before)
+        .bind(limit)
+        .fetch_all(conn)
+        .await
+    }
+
+    /// `(path, location)` of files moved to cold storage
+    pub async fn query_tiered(conn: &mut SqliteConnection) -> Result<Vec<(String, String)>> {
+        sqlx::query_as::<_, (String, String)>(
+            r#"SELECT "path", "location" FROM "tiered" ORDER BY "path""#,
+        )
+        .fetch_all(conn)
+        .await
+    }
+
+    pub async fn insert_tiered(
+        conn: &mut SqliteConnection,
+        path: &str,
+        location: &str,
+        tiered_at: i64,
+    ) -> Result<()> {
+        sqlx::query(r#"INSERT OR REPLACE INTO "tiered" VALUES (?, ?, ?)"#)
+            .bind(path)
+            .bind(location)
+            .bind(tiered_at)
+            .execute(conn)
             .await?;
-        insert_database_version(&mut conn, "meta", VERSION).await?;
+        Ok(())
     }
-    Ok(conn)
-}
 
-use kstool::sqlx::{check_database, insert_database_version};
-use sqlx::sqlite::SqliteConnectOptions;
-use sqlx::ConnectOptions;
-pub use v1 as current;
-pub use v1::VERSION;
+    pub async fn delete_tiered(conn: &mut SqliteConnection, path: &str) -> Result<()> {
+        sqlx::query(r#"DELETE FROM "tiered" WHERE "path" = ?"#)
+            .bind(path)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+
+    /// At most `limit` files after `cursor` in byte order
+    pub async fn query_scrub_batch(
+        conn: &mut SqliteConnection,
+        cursor: &str,
+        limit: i64,
+    ) -> Result<Vec<FileEntry>> {
+        sqlx::query_as::<_, FileEntry>(
+            r#"SELECT * FROM "files" WHERE "is_dir" = 0 AND "path" > ? ORDER BY "path" LIMIT ?"#,
+        )
+        .bind(cursor)
+        .bind(limit)
+        .fetch_all(conn)
+        .await
+    }
+
+    /// Path of last scrubbed file, empty if scrub has not started
+    pub async fn query_scrub_cursor(conn: &mut SqliteConnection) -> Result<String> {
+        Ok(
+            sqlx::query_as::<_, (Option<String>,)>(r#"SELECT "value" FROM "meta" WHERE "key" = ?"#)
+                .bind(SCRUB_CURSOR_KEY)
+                .fetch_optional(conn)
+                .await?
+                .and_then(|(cursor,)| cursor)
+                .unwrap_or_default(),
+        )
+    }
+
+    pub async fn update_scrub_cursor(conn: &mut SqliteConnection, cursor: &str) -> Result<()> {
+        let mut transaction = conn.begin().await?;
+        sqlx::query(r#"DELETE FROM "meta" WHERE "key" = ?"#)
+            .bind(SCRUB_CURSOR_KEY)
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query(r#"INSERT INTO "meta" VALUES (?, ?)"#)
+            .bind(SCRUB_CURSOR_KEY)
+            .bind(cursor)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await
+    }
+
+    #[allow(unused)]
+    pub async fn query_path<P: AsRef<Path>>(
+        conn: &mut SqliteConnection,
+        path: P,
+    ) -> Result<Option<FileEntry>> {
+        query(conn, path.as_ref().to_str().expect(PATH_UTF8_ERROR)).await
+    }
+
+    #[allow(unused)]
+    pub async fn query_by_path(
+        conn: &mut SqliteConnection,
+        path: String,
+    ) -> Result<Vec<FileEntry>> {
+        let quoted = insert_percent(path);
+        sqlx::query_as::<_, FileEntry>(&format!(
+            r#"SELECT * FROM "files" WHERE "path" LIKE {}"#,
+            quoted
+        ))
+        .fetch_all(conn)
+        .await
+    }
+
+    /// Query all entries under `prefixes` (include prefix itself), sort, filter and paginate
+    /// in SQL, all matched entries are returned if `page` is `None`
+    pub async fn query_by_prefixes(
+        conn: &mut SqliteConnection,
+        prefixes: &[String],
+        option: &ListOption,
+        page: Option<&Pagination>,
+    ) -> Result<Vec<FileEntry>> {
+        let Some(condition) = prefixes_condition(prefixes) else {
+            return Ok(Vec::new());
+        };
+        let mut sql = format!(r#"SELECT * FROM "files" WHERE {}"#, condition);
+        if option.min_size().is_some() {
+            sql.push_str(r#" AND "size" >= ?"#);
+        }
+        if option.max_size().is_some() {
+            sql.push_str(r#" AND "size" <= ?"#);
+        }
+        if option.modified_after().is_some() {
+            sql.push_str(r#" AND "mtime" > ?"#);
+        }
+        let order = match option.order() {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        };
+        let path_order = match option.collation() {
+            Collation::Binary => format!(r#""path" {}"#, order),
+            Collation::Natural => format!(r#""path" COLLATE {} {}"#, NATURAL_COLLATION, order),
+        };
+        sql.push_str(&match option.sort() {
+            SortBy::Path => format!(" ORDER BY {}", path_order),
+            SortBy::Mtime => format!(r#" ORDER BY "mtime" {}, {}"#, order, path_order),
+            SortBy::Size => format!(r#" ORDER BY "size" {}, {}"#, order, path_order),
+        });
+        if page.is_some() {
+            sql.push_str(" LIMIT ? OFFSET ?");
+        }
+        let mut query = sqlx::query_as::<_, FileEntry>(&sql);
+        if let Some(min_size) = option.min_size() {
+            query = query.bind(min_size);
+        }
+        if let Some(max_size) = option.max_size() {
+            query = query.bind(max_size);
+        }
+        if let Some(modified_after) = option.modified_after() {
+            query = query.bind(modified_after);
+        }
+        if let Some(page) = page {
+            query = query.bind(page.limit()).bind(page.offset());
+        }
+        query.fetch_all(conn).await
+    }
+
+    /// Build SQL condition match `prefix` and its children
+    pub(super) fn prefix_condition(prefix: &str) -> String {
+        let prefix = normalize_path(prefix);
+        format!(
+            r#""path" = {} OR "path" LIKE {}"#,
+            QuotedData(&prefix),
+            insert_percent(prefix.clone())
+        )
+    }
+
+    /// Build SQL condition match `prefixes` and their children, `None` if `prefixes` is empty,
+    /// caller returns empty result then instead of querying
+    pub(super) fn prefixes_condition(prefixes: &[String]) -> Option<String> {
+        if prefixes.is_empty() {
+            return None;
+        }
+        let conditions = prefixes
+            .iter()
+            .map(|prefix| prefix_condition(prefix))
+            .collect::<Vec<_>>();
+        Some(format!("({})", conditions.join(" OR ")))
+    }
+
+    /// Top `limit` largest files under `prefixes`
+    pub async fn query_largest(
+        conn: &mut SqliteConnection,
+        prefixes: &[String],
+        limit: i64,
+    ) -> Result<Vec<FileEntry>> {
+        let Some(condition) = prefixes_condition(prefixes) else {
+            return Ok(Vec::new());
+        };
+        sqlx::query_as::<_, FileEntry>(&format!(
+            r#"SELECT * FROM "files" WHERE "is_dir" = 0 AND {}
+            ORDER BY "size" DESC, "path" LIMIT ?"#,
+            condition
+        ))
+        .bind(limit)
+        .fetch_all(conn)
+        .await
+    }
+
+    /// Files under `prefixes` not modified since `before` (unix timestamp), oldest first
+    pub async fn query_stale(
+        conn: &mut SqliteConnection,
+        prefixes: &[String],
+        before: i64,
+        limit: i64,
+    ) -> Result<Vec<FileEntry>> {
+        let Some(condition) = prefixes_condition(prefixes) else {
+            return Ok(Vec::new());
+        };
+        sqlx::query_as::<_, FileEntry>(&format!(
+            r#"SELECT * FROM "files" WHERE "is_dir" = 0 AND {} AND "mtime" < ?
+            ORDER BY "mtime", "path" LIMIT ?"#,
+            condition
+        ))
+        .bind(before)
+        .bind(limit)
+        .fetch_all(conn)
+        .await
+    }
+
+    /// Files and directories under `prefixes` whose path matches `glob` case-insensitively,
+    /// at most `limit` rows after `cursor` in byte order
+    pub async fn search(
+        conn: &mut SqliteConnection,
+        prefixes: &[String],
+        glob: &str,
+        cursor: &str,
+        limit: i64,
+    ) -> Result<Vec<FileEntry>> {
+        let Some(condition) = prefixes_condition(prefixes) else {
+            return Ok(Vec::new());
+        };
+        sqlx::query_as::<_, FileEntry>(&format!(
+            r#"SELECT * FROM "files" WHERE {} AND "path" LIKE ? ESCAPE '\' AND "path" > ?
+            ORDER BY "path" LIMIT ?"#,
+            condition
+        ))
+        .bind(glob_to_like(glob))
+        .bind(cursor)
+        .bind(limit)
+        .fetch_all(conn)
+        .await
+    }
+
+    /// Files under `prefixes` with content `hash` (`algo:hex`)
+    pub async fn query_by_hash(
+        conn: &mut SqliteConnection,
+        prefixes: &[String],
+        hash: &str,
+    ) -> Result<Vec<FileEntry>> {
+        let Some(condition) = prefixes_condition(prefixes) else {
+            return Ok(Vec::new());
+        };
+        sqlx::query_as::<_, FileEntry>(&format!(
+            r#"SELECT * FROM "files" WHERE "hash" = ? AND "is_dir" = 0 AND {} ORDER BY "path""#,
+            condition
+        ))
+        .bind(hash)
+        .fetch_all(conn)
+        .await
+    }
+
+    /// Group files by hash and size, `prefixes` is `None` means query all files
+    pub async fn query_duplicates(
+        conn: &mut SqliteConnection,
+        prefixes: Option<&[String]>,
+        page: &Pagination,
+    ) -> Result<DuplicateReport> {
+        let scope = match prefixes.map(prefixes_condition) {
+            Some(Some(condition)) => format!(" AND {}", condition),
+            Some(None) => return Ok(DuplicateReport::default()),
+            None => String::new(),
+        };
+        let groups_sql = format!(
+            r#"SELECT "hash", "size", COUNT(*) AS "count" FROM "files"
+            WHERE "is_dir" = 0 AND "hash" IS NOT NULL AND "hash" != ''{}
+            GROUP BY "hash", "size" HAVING COUNT(*) > 1"#,
+            scope
+        );
+
+        let (total_groups, total_wasted) = sqlx::query_as::<_, (i64, i64)>(&format!(
+            r#"SELECT COUNT(*), COALESCE(SUM("size" * ("count" - 1)), 0) FROM ({})"#,
+            groups_sql
+        ))
+        .fetch_one(&mut *conn)
+        .await?;
+
+        let page_groups = sqlx::query_as::<_, (String, i64)>(&format!(
+            r#"SELECT "hash", "size" FROM ({})
+            ORDER BY "size" * ("count" - 1) DESC, "hash" LIMIT ? OFFSET ?"#,
+            groups_sql
+        ))
+        .bind(page.limit())
+        .bind(page.offset())
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let mut groups = Vec::new();
+        for (hash, size) in page_groups {
+            let paths = sqlx::query_as::<_, (String,)>(&format!(
+                r#"SELECT "path" FROM "files"
+                WHERE "hash" = ? AND "size" = ? AND "is_dir" = 0{} ORDER BY "path""#,
+                scope
+            ))
+            .bind(&hash)
+            .bind(size)
+            .fetch_all(&mut *conn)
+            .await?
+            .into_iter()
+            .map(|(path,)| path)
+            .collect();
+            groups.push(DuplicateGroup::new(hash, size, paths));
+        }
+
+        Ok(DuplicateReport::new(total_groups, total_wasted, groups))
+    }
+
+    /// Directories contain `path`, nearest first
+    pub(super) fn ancestors(path: &str) -> Vec<&str> {
+        let mut result = Vec::new();
+        let mut current = path;
+        while let Some(index) = current.rfind('/') {
+            current = &current[..index];
+            result.push(current);
+        }
+        result
+    }
+
+    /// Add delta to stats of all directories contain `path`
+    async fn add_stats(
+        conn: &mut SqliteConnection,
+        path: &str,
+        files: i64,
+        size: i64,
+        mtime: i64,
+    ) -> Result<()> {
+        for dir in ancestors(path) {
+            sqlx::query(
+                r#"INSERT INTO "dir_stats" VALUES (?, ?, ?, ?) ON CONFLICT("path") DO UPDATE
+                SET "files" = "files" + excluded."files", "size" = "size" + excluded."size",
+                "mtime" = MAX("mtime", excluded."mtime")"#,
+            )
+            .bind(dir)
+            .bind(files)
+            .bind(size)
+            .bind(mtime)
+            .execute(&mut *conn)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Stats of row `path` include rows under it, `(0, 0, 0)` if not exists
+    async fn subtree_stats(conn: &mut SqliteConnection, path: &str) -> Result<(i64, i64, i64)> {
+        let (files, size, mtime) = match query(conn, path).await? {
+            Some(entry) if !entry.is_dir() => (1, entry.size(), entry.mtime()),
+            _ => (0, 0, 0),
+        };
+        let children = sqlx::query_as::<_, (i64, i64, i64)>(
+            r#"SELECT "files", "size", "mtime" FROM "dir_stats" WHERE "path" = ?"#,
+        )
+        .bind(path)
+        .fetch_optional(conn)
+        .await?
+        .unwrap_or_default();
+        Ok((files + children.0, size + children.1, mtime.max(children.2)))
+    }
+
+    /// Stats of directories in `paths`, directory without files is zero, other path is skipped
+    pub async fn query_stats(
+        conn: &mut SqliteConnection,
+        paths: &[String],
+    ) -> Result<Vec<DirStats>> {
+        let mut result = Vec::new();
+        for path in paths {
+            let path = normalize_path(path);
+            let stats = sqlx::query_as::<_, (i64, i64, i64)>(
+                r#"SELECT "files", "size", "mtime" FROM "dir_stats" WHERE "path" = ?"#,
+            )
+            .bind(&path)
+            .fetch_optional(&mut *conn)
+            .await?;
+            if let Some((files, size, mtime)) = stats {
+                result.push(DirStats::new(path, files, size, mtime));
+                continue;
+            }
+            let is_dir = path == "."
+                || query(conn, &path)
+                    .await?
+                    .is_some_and(|entry| entry.is_dir());
+            if is_dir {
+                result.push(DirStats::new(path, 0, 0, 0));
+            }
+        }
+        Ok(result)
+    }
+
+    /// Recompute stats of all directories from "files"
+    pub async fn rebuild_stats(conn: &mut SqliteConnection) -> Result<()> {
+        let rows = sqlx::query_as::<_, (String, i64, i64)>(
+            r#"SELECT "path", "size", "mtime" FROM "files" WHERE "is_dir" = 0"#,
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        let mut stats: HashMap<&str, (i64, i64, i64)> = HashMap::new();
+        for (path, size, mtime) in &rows {
+            for dir in ancestors(path) {
+                let entry = stats.entry(dir).or_default();
+                entry.0 += 1;
+                entry.1 += size;
+                entry.2 = entry.2.max(*mtime);
+            }
+        }
+        let mut transaction = conn.begin().await?;
+        sqlx::query(r#"DELETE FROM "dir_stats""#)
+            .execute(&mut *transaction)
+            .await?;
+        for (path, (files, size, mtime)) in stats {
+            sqlx::query(r#"INSERT INTO "dir_stats" VALUES (?, ?, ?, ?)"#)
+                .bind(path)
+                .bind(files)
+                .bind(size)
+                .bind(mtime)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        transaction.commit().await
+    }
+
+    pub async fn query(conn: &mut SqliteConnection, path: &str) -> Result<Option<FileEntry>> {
+        sqlx::query_as::<_, FileEntry>(r#"SELECT * FROM "files" WHERE "path" = ?"#)
+            .bind(path)
+            .fetch_optional(conn)
+            .await
+    }
+
+    /// Stored paths equal to `path` ignoring ASCII case (`path` itself included), in byte order
+    pub async fn query_case_variants(
+        conn: &mut SqliteConnection,
+        path: &str,
+    ) -> Result<Vec<String>> {
+        Ok(sqlx::query_as::<_, (String,)>(
+            r#"SELECT "path" FROM "files" WHERE "path" = ? COLLATE NOCASE ORDER BY "path""#,
+        )
+        .bind(path)
+        .fetch_all(conn)
+        .await?
+        .into_iter()
+        .map(|(path,)| path)
+        .collect())
+    }
+
+    /// Insert `entry`, or replace existing row of same path
+    pub async fn upsert(conn: &mut SqliteConnection, entry: &FileEntry) -> Result<()> {
+        let mut transaction = conn.begin().await?;
+        let (old_files, old_size) = match query(&mut transaction, entry.path()).await? {
+            Some(old) if !old.is_dir() => (1, old.size()),
+            _ => (0, 0),
+        };
+        let (files, size) = if entry.is_dir() {
+            (0, 0)
+        } else {
+            (1, entry.size())
+        };
+        sqlx::query(
+            r#"INSERT INTO "files" ("path", "hash", "mtime", "size", "is_dir", "marked", "encrypted")
+            VALUES (?, ?, ?, ?, ?, 1, ?) ON CONFLICT("path") DO UPDATE
+            SET "hash" = excluded."hash", "mtime" = excluded."mtime", "size" = excluded."size",
+            "is_dir" = excluded."is_dir", "marked" = 1, "encrypted" = excluded."encrypted""#,
+        )
+        .bind(entry.path())
+        .bind((!entry.is_dir()).then(|| entry.hash()))
+        .bind(entry.mtime())
+        .bind(entry.size())
+        .bind(entry.is_dir())
+        .bind(entry.encrypted())
+        .execute(&mut *transaction)
+        .await?;
+        if files != 0 || old_files != 0 {
+            add_stats(
+                &mut transaction,
+                entry.path(),
+                files - old_files,
+                size - old_size,
+                entry.mtime(),
+            )
+            .await?;
+        }
+        transaction.commit().await
+    }
+
+    pub async fn update(conn: &mut SqliteConnection, entry: &FileEntry) -> Result<()> {
+        let mut transaction = conn.begin().await?;
+        if !entry.is_dir() {
+            let old = sqlx::query_as::<_, (i64,)>(
+                r#"SELECT "size" FROM "files" WHERE "path" = ? AND "is_dir" = 0"#,
+            )
+            .bind(entry.path())
+            .fetch_optional(&mut *transaction)
+            .await?;
+            if let Some((size,)) = old {
+                add_stats(
+                    &mut transaction,
+                    entry.path(),
+                    0,
+                    entry.size() - size,
+                    entry.mtime(),
+                )
+                .await?;
+            }
+        }
+        sqlx::query(r#"UPDATE "files" SET "hash" = ?, "mtime" = ?, "size" = ?, "encrypted" = ?, "marked" = 1 WHERE "path" = ?"#)
+            .bind((!entry.is_dir()).then(|| entry.hash()))
+            .bind(entry.mtime())
+            .bind(entry.size())
+            .bind(entry.encrypted())
+            .bind(entry.path())
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await
+    }
+
+    #[allow(unused)]
+    pub async fn mark_path<P: AsRef<Path>>(conn: &mut SqliteConnection, path: P) -> Result<()> {
+        mark_path_str(conn, path.as_ref().to_str().expect(PATH_UTF8_ERROR)).await
+    }
+
+    pub async fn mark_path_str(conn: &mut SqliteConnection, path: &str) -> Result<()> {
+        sqlx::query(r#"UPDATE "files" SET "marked" = 1 WHERE "path" = ?"#)
+            .bind(path)
+            .execute(conn)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn mark(conn: &mut SqliteConnection, entry: &FileEntry) -> Result<()> {
+        mark_path_str(conn, entry.path()).await
+    }
+
+    pub async fn reset_all_mark(conn: &mut SqliteConnection) -> Result<()> {
+        sqlx::query(r#"UPDATE "files" SET "marked" = 0"#)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Same as `reset_all_mark` but only `path` and rows under it are reset
+    pub async fn reset_mark_under(conn: &mut SqliteConnection, path: &str) -> Result<()> {
+        sqlx::query(&format!(
+            r#"UPDATE "files" SET "marked" = 0 WHERE "path" = ? OR "path" LIKE {}"#,
+            insert_percent(path.to_string())
+        ))
+        .bind(path)
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    /// Delete row of `path`, rows under it are deleted as well if stored row is directory
+    ///
+    /// Path is usually gone from disk already, so file system is not checked
+    pub async fn delete(conn: &mut SqliteConnection, path: &str) -> Result<()> {
+        let mut transaction = conn.begin().await?;
+        match query(&mut transaction, path).await? {
+            Some(entry) if entry.is_dir() => {
+                let (files, size, _) = subtree_stats(&mut transaction, path).await?;
+                add_stats(&mut transaction, path, -files, -size, 0).await?;
+                delete_stats_under(&mut transaction, path).await?;
+                sqlx::query(&format!(
+                    r#"DELETE FROM "files" WHERE "path" = ? OR "path" LIKE {}"#,
+                    insert_percent(path.to_string())
+                ))
+                .bind(path)
+                .execute(&mut *transaction)
+                .await?;
+            }
+            Some(entry) => {
+                add_stats(&mut transaction, path, -1, -entry.size(), 0).await?;
+                sqlx::query(r#"DELETE FROM "files" WHERE "path" = ?"#)
+                    .bind(path)
+                    .execute(&mut *transaction)
+                    .await?;
+            }
+            None => {}
+        }
+        transaction.commit().await
+    }
+
+    /// Remove stats of `path` and directories under it
+    async fn delete_stats_under(conn: &mut SqliteConnection, path: &str) -> Result<()> {
+        sqlx::query(&format!(
+            r#"DELETE FROM "dir_stats" WHERE "path" = {} OR "path" LIKE {}"#,
+            QuotedData(path),
+            insert_percent(path.to_string())
+        ))
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    /// Move row (and children rows if it is directory) from `from` to `to`
+    ///
+    /// Return `false` if `from` is not exists in database
+    pub async fn rename(conn: &mut SqliteConnection, from: &str, to: &str) -> Result<bool> {
+        let mut transaction = conn.begin().await?;
+        let (files, size, _) = subtree_stats(&mut transaction, to).await?;
+        add_stats(&mut transaction, to, -files, -size, 0).await?;
+        delete_stats_under(&mut transaction, to).await?;
+        sqlx::query(r#"DELETE FROM "files" WHERE "path" = ?"#)
+            .bind(to)
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query(&format!(
+            r#"DELETE FROM "files" WHERE "path" LIKE {}"#,
+            insert_percent(to.to_string())
+        ))
+        .execute(&mut *transaction)
+        .await?;
+        let (files, size, mtime) = subtree_stats(&mut transaction, from).await?;
+        add_stats(&mut transaction, from, -files, -size, 0).await?;
+        add_stats(&mut transaction, to, files, size, mtime).await?;
+        let result = sqlx::query(r#"UPDATE "files" SET "path" = ? WHERE "path" = ?"#)
+            .bind(to)
+            .bind(from)
+            .execute(&mut *transaction)
+            .await?;
+        for table in ["files", "dir_stats"] {
+            sqlx::query(&format!(
+                r#"UPDATE "{}" SET "path" = ? || substr("path", ?) WHERE "path" LIKE {}"#,
+                table,
+                insert_percent(from.to_string())
+            ))
+            .bind(to)
+            .bind(from.chars().count() as i64 + 1)
+            .execute(&mut *transaction)
+            .await?;
+        }
+        sqlx::query(r#"UPDATE "dir_stats" SET "path" = ? WHERE "path" = ?"#)
+            .bind(to)
+            .bind(from)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Check hashes in database are computed by current `HASH_VERSION` and `algo`
+    pub async fn check_hash_version(conn: &mut SqliteConnection, algo: HashAlgo) -> Result<bool> {
+        let version =
+            sqlx::query_as::<_, (Option<String>,)>(r#"SELECT "value" FROM "meta" WHERE "key" = ?"#)
+                .bind(HASH_VERSION_KEY)
+                .fetch_optional(conn)
+                .await?;
+        Ok(version.and_then(|(version,)| version) == Some(hash_version(algo)))
+    }
+
+    pub async fn update_hash_version(conn: &mut SqliteConnection, algo: HashAlgo) -> Result<()> {
+        let mut transaction = conn.begin().await?;
+        sqlx::query(r#"DELETE FROM "meta" WHERE "key" = ?"#)
+            .bind(HASH_VERSION_KEY)
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query(r#"INSERT INTO "meta" VALUES (?, ?)"#)
+            .bind(HASH_VERSION_KEY)
+            .bind(hash_version(algo))
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await
+    }
+
+    /// Sequence of last event log record applied to index, `0` if never recorded
+    pub async fn query_event_checkpoint(conn: &mut SqliteConnection) -> Result<u64> {
+        let checkpoint =
+            sqlx::query_as::<_, (Option<String>,)>(r#"SELECT "value" FROM "meta" WHERE "key" = ?"#)
+                .bind(EVENT_CHECKPOINT_KEY)
+                .fetch_optional(conn)
+                .await?;
+        Ok(checkpoint
+            .and_then(|(checkpoint,)| checkpoint)
+            .and_then(|checkpoint| checkpoint.parse().ok())
+            .unwrap_or_default())
+    }
+
+    pub async fn update_event_checkpoint(conn: &mut SqliteConnection, seq: u64) -> Result<()> {
+        let mut transaction = conn.begin().await?;
+        sqlx::query(r#"DELETE FROM "meta" WHERE "key" = ?"#)
+            .bind(EVENT_CHECKPOINT_KEY)
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query(r#"INSERT INTO "meta" VALUES (?, ?)"#)
+            .bind(EVENT_CHECKPOINT_KEY)
+            .bind(seq.to_string())
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await
+    }
+
+    /// Commit of working directory recorded by last scan, see `GitOption`
+    pub async fn query_git_commit(conn: &mut SqliteConnection) -> Result<Option<String>> {
+        Ok(
+            sqlx::query_as::<_, (Option<String>,)>(r#"SELECT "value" FROM "meta" WHERE "key" = ?"#)
+                .bind(GIT_COMMIT_KEY)
+                .fetch_optional(conn)
+                .await?
+                .and_then(|(commit,)| commit),
+        )
+    }
+
+    /// Row is removed if `commit` is `None`
+    pub async fn update_git_commit(
+        conn: &mut SqliteConnection,
+        commit: Option<&str>,
+    ) -> Result<()> {
+        let mut transaction = conn.begin().await?;
+        sqlx::query(r#"DELETE FROM "meta" WHERE "key" = ?"#)
+            .bind(GIT_COMMIT_KEY)
+            .execute(&mut *transaction)
+            .await?;
+        if let Some(commit) = commit {
+            sqlx::query(r#"INSERT INTO "meta" VALUES (?, ?)"#)
+                .bind(GIT_COMMIT_KEY)
+                .bind(commit)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        transaction.commit().await
+    }
+
+    /// Add tables and columns missing in version 1 database created by older build, schema
+    /// changes after version 1 belong to `migration`
+    ///
+    /// Hash version is reset, so new columns will be filled by next scan
+    pub(super) async fn migrate(conn: &mut SqliteConnection) -> Result<()> {
+        sqlx::query(CREATE_TOKENS_TABLE).execute(&mut *conn).await?;
+        let (has_stats,) = sqlx::query_as::<_, (bool,)>(
+            r#"SELECT COUNT(*) > 0 FROM sqlite_master WHERE "type" = 'table' AND "name" = 'dir_stats'"#,
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+        if !has_stats {
+            sqlx::query(CREATE_STATS_TABLE).execute(&mut *conn).await?;
+            rebuild_stats(conn).await?;
+        }
+        let columns =
+            sqlx::query_as::<_, (String,)>(r#"SELECT "name" FROM pragma_table_info('files')"#)
+                .fetch_all(&mut *conn)
+                .await?;
+        if columns.iter().any(|(name,)| name == "encrypted") {
+            return Ok(());
+        }
+        let mut transaction = conn.begin().await?;
+        sqlx::query(r#"ALTER TABLE "files" ADD COLUMN "encrypted" INTEGER NOT NULL DEFAULT 0"#)
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query(r#"DELETE FROM "meta" WHERE "key" = ?"#)
+            .bind(HASH_VERSION_KEY)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await
+    }
+
+    /// Stats are rebuilt after unmarked rows deleted
+    pub async fn delete_all_unmarked(conn: &mut SqliteConnection) -> Result<()> {
+        sqlx::query(r#"DELETE FROM "files" WHERE "marked" = 0"#)
+            .execute(&mut *conn)
+            .await?;
+        rebuild_stats(conn).await
+    }
+
+    /// Same as `delete_all_unmarked` but only `path` and rows under it are checked
+    pub async fn delete_unmarked_under(conn: &mut SqliteConnection, path: &str) -> Result<()> {
+        sqlx::query(&format!(
+            r#"DELETE FROM "files" WHERE "marked" = 0 AND ("path" = ? OR "path" LIKE {})"#,
+            insert_percent(path.to_string())
+        ))
+        .bind(path)
+        .execute(&mut *conn)
+        .await?;
+        rebuild_stats(conn).await
+    }
+
+    /// Directories at or under `path` still marked are walked by finished scan
+    pub async fn mark_children_scanned(conn: &mut SqliteConnection, path: &str) -> Result<()> {
+        sqlx::query(&format!(
+            r#"UPDATE "files" SET "children_scanned" = 1
+            WHERE "is_dir" = 1 AND "marked" = 1 AND ("path" = ? OR "path" LIKE {})"#,
+            insert_percent(path.to_string())
+        ))
+        .bind(path)
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn insert(conn: &mut SqliteConnection, entry: &FileEntry) -> Result<()> {
+        let mut transaction = conn.begin().await?;
+        if entry.is_dir() {
+            sqlx::query(
+                r#"INSERT INTO "files" ("path", "mtime", "is_dir", "marked") VALUES (?, ?, ?, ?)"#,
+            )
+            .bind(entry.path())
+            .bind(entry.mtime())
+            .bind(1)
+            .bind(1)
+            .execute(&mut *transaction)
+            .await?;
+        } else {
+            sqlx::query(
+                r#"INSERT INTO "files" ("path", "hash", "mtime", "size", "is_dir", "marked", "encrypted")
+                VALUES (?, ?, ?, ?, ?, ?, ?)"#,
+            )
+            .bind(entry.path())
+            .bind(entry.hash())
+            .bind(entry.mtime())
+            .bind(entry.size())
+            .bind(0)
+            .bind(1)
+            .bind(entry.encrypted())
+            .execute(&mut *transaction)
+            .await?;
+            add_stats(
+                &mut transaction,
+                entry.path(),
+                1,
+                entry.size(),
+                entry.mtime(),
+            )
+            .await?;
+        }
+        transaction.commit().await
+    }
+
+    /// Glob (`*`, `?`) as `LIKE` pattern escaped by `\`, pattern without wildcard matches
+    /// substring of path
+    pub(super) fn glob_to_like(glob: &str) -> String {
+        let mut pattern = String::with_capacity(glob.len() + 2);
+        let wildcard = glob.contains(['*', '?']);
+        if !wildcard {
+            pattern.push('%');
+        }
+        for c in glob.chars() {
+            match c {
+                '*' => pattern.push('%'),
+                '?' => pattern.push('_'),
+                '%' | '_' | '\\' => {
+                    pattern.push('\\');
+                    pattern.push(c);
+                }
+                _ => pattern.push(c),
+            }
+        }
+        if !wildcard {
+            pattern.push('%');
+        }
+        pattern
+    }
+
+    /// `%`, `_` and `\` of `s` match literally in `LIKE` pattern escaped by `\`
+    fn escape_like(s: &str) -> String {
+        let mut escaped = String::with_capacity(s.len());
+        for c in s.chars() {
+            if matches!(c, '%' | '_' | '\\') {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    }
+
+    /// Quoted `LIKE` operand with escape clause of rows under directory `path`, `suffix` is
+    /// appended to the pattern unescaped
+    fn under_pattern(path: &str, suffix: &str) -> String {
+        let dir = escape_like(path.strip_suffix('/').unwrap_or(path));
+        // Backslash is literal in SQL string, `QuotedData` doubles it, which breaks escapes
+        format!(r#"'{}/{}' ESCAPE '\'"#, dir.replace('\'', "''"), suffix)
+    }
+
+    pub fn insert_percent(s: String) -> String {
+        under_pattern(&s, "%")
+    }
+}
+
+/// Bounded retry of SQLite statements failed by lock of other connection, installed once
+/// on startup
+mod busy {
+    use rand::Rng;
+    use serde_derive::Serialize;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::OnceLock;
+    use std::time::{Duration, Instant};
+
+    static POLICY: OnceLock<BusyPolicy> = OnceLock::new();
+
+    /// Primary result codes, extended code keeps primary one in lowest byte
+    const SQLITE_BUSY: i32 = 5;
+    const SQLITE_LOCKED: i32 = 6;
+
+    /// Same as default of sqlx
+    const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+    /// Longest wait before one retry, jitter included
+    pub const MAX_RETRY_DELAY: Duration = Duration::from_millis(500);
+    /// Statement is not run again once this long passed since first attempt, so retries
+    /// end before request timeout (3 seconds at most)
+    pub const RETRY_BUDGET: Duration = Duration::from_secs(2);
+
+    /// Statement failed because database is locked, may succeed once lock is released
+    pub fn is_busy(e: &sqlx::Error) -> bool {
+        match e {
+            sqlx::Error::Database(e) => e
+                .code()
+                .and_then(|code| code.parse::<i32>().ok())
+                .is_some_and(|code| matches!(code & 0xff, SQLITE_BUSY | SQLITE_LOCKED)),
+            _ => false,
+        }
+    }
+
+    /// Counters of contention since startup
+    #[derive(Clone, Copy, Debug, Serialize)]
+    pub struct BusyStats {
+        /// Statements run again after busy
+        retried: u64,
+        /// Statements still busy after all retries
+        exhausted: u64,
+    }
+
+    #[derive(Debug)]
+    pub struct BusyPolicy {
+        timeout: Duration,
+        retries: u32,
+        delay: Duration,
+        retried: AtomicU64,
+        exhausted: AtomicU64,
+    }
+
+    impl Default for BusyPolicy {
+        fn default() -> Self {
+            Self::new(DEFAULT_TIMEOUT, 0, Duration::ZERO)
+        }
+    }
+
+    impl BusyPolicy {
+        pub fn new(timeout: Duration, retries: u32, delay: Duration) -> Self {
+            Self {
+                timeout,
+                retries,
+                delay,
+                retried: AtomicU64::new(0),
+                exhausted: AtomicU64::new(0),
+            }
+        }
+
+        /// Install as policy of SQLite connections opened later, only first call works
+        pub fn install(self) {
+            POLICY.set(self).ok();
+        }
+
+        /// Installed policy, SQLite waits lock but statements are not retried if none
+        pub fn global() -> &'static Self {
+            POLICY.get_or_init(Self::default)
+        }
+
+        /// Passed to SQLite as `busy_timeout`
+        pub fn timeout(&self) -> Duration {
+            self.timeout
+        }
+
+        /// Wait before `attempt`-th retry, delay is doubled each time and up to same amount
+        /// of jitter is added, so contending connections don't retry together
+        pub fn delay(&self, attempt: u32) -> Duration {
+            let delay = self
+                .delay
+                .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+                .min(MAX_RETRY_DELAY / 2);
+            let jitter = rand::thread_rng().gen_range(0..=delay.as_millis() as u64);
+            delay + Duration::from_millis(jitter)
+        }
+
+        /// Wait before `attempt`-th retry of statement first run at `started`, `false` if
+        /// retries or time budget is used up
+        pub async fn backoff(&self, attempt: u32, started: Instant) -> bool {
+            if attempt > self.retries {
+                return false;
+            }
+            let delay = self.delay(attempt);
+            if started.elapsed() + delay >= RETRY_BUDGET {
+                return false;
+            }
+            self.retried.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(delay).await;
+            true
+        }
+
+        pub fn exhaust(&self) {
+            self.exhausted.fetch_add(1, Ordering::Relaxed);
+        }
+
+        pub fn stats(&self) -> BusyStats {
+            BusyStats {
+                retried: self.retried.load(Ordering::Relaxed),
+                exhausted: self.exhausted.load(Ordering::Relaxed),
+            }
+        }
+    }
+}
+
+mod backend {
+    use super::busy::{is_busy, BusyPolicy};
+    use super::current;
+    use async_trait::async_trait;
+    use publib::file::HashAlgo;
+    use publib::types::{
+        AccessRecord, AccessStats, DirStats, DuplicateReport, FileEntry, HoldInfo, IndexError,
+        IntegrityIssue, LinkInfo, ListOption, Pagination, TokenInfo, UploadStatus,
+    };
+    use publib::PATH_UTF8_ERROR;
+    use sqlx::sqlite::SqliteTransactionManager;
+    use sqlx::{Result, SqliteConnection, TransactionManager};
+    use std::path::Path;
+    use tracing::warn;
+
+    /// Await `$call` again while SQLite is busy, at most `BusyPolicy::retries` times and
+    /// within `RETRY_BUDGET`
+    macro_rules! retry_busy {
+        ($call:expr) => {{
+            let policy = BusyPolicy::global();
+            let started = std::time::Instant::now();
+            let mut attempt = 0;
+            loop {
+                match $call.await {
+                    Err(e) if is_busy(&e) => {
+                        attempt += 1;
+                        if !policy.backoff(attempt, started).await {
+                            warn!("Database is still busy after {} retries", attempt - 1);
+                            policy.exhaust();
+                            break Err(e);
+                        }
+                    }
+                    result => break result,
+                }
+            }
+        }};
+    }
+
+    /// Index storage used by file daemon, see `current` for meaning of each method
+    #[async_trait]
+    pub trait Database: Send {
+        async fn query(&mut self, path: &str) -> Result<Option<FileEntry>>;
+        async fn query_case_variants(&mut self, path: &str) -> Result<Vec<String>>;
+        async fn query_by_prefixes(
+            &mut self,
+            prefixes: &[String],
+            option: &ListOption,
+            page: Option<&Pagination>,
+        ) -> Result<Vec<FileEntry>>;
+        async fn query_largest(
+            &mut self,
+            prefixes: &[String],
+            limit: i64,
+        ) -> Result<Vec<FileEntry>>;
+        async fn query_stale(
+            &mut self,
+            prefixes: &[String],
+            before: i64,
+            limit: i64,
+        ) -> Result<Vec<FileEntry>>;
+        async fn query_by_hash(
+            &mut self,
+            prefixes: &[String],
+            hash: &str,
+        ) -> Result<Vec<FileEntry>>;
+        async fn search(
+            &mut self,
+            prefixes: &[String],
+            glob: &str,
+            cursor: &str,
+            limit: i64,
+        ) -> Result<Vec<FileEntry>>;
+        async fn query_duplicates(
+            &mut self,
+            prefixes: Option<&[String]>,
+            page: &Pagination,
+        ) -> Result<DuplicateReport>;
+        async fn query_stats(&mut self, paths: &[String]) -> Result<Vec<DirStats>>;
+        async fn insert(&mut self, entry: FileEntry) -> Result<()>;
+        async fn upsert(&mut self, entry: FileEntry) -> Result<()>;
+        async fn update(&mut self, entry: FileEntry) -> Result<()>;
+        async fn delete(&mut self, path: String) -> Result<()>;
+        async fn rename(&mut self, from: &str, to: &str) -> Result<bool>;
+        async fn mark(&mut self, entry: FileEntry) -> Result<()>;
+        async fn reset_all_mark(&mut self) -> Result<()>;
+        async fn delete_all_unmarked(&mut self) -> Result<()>;
+        async fn reset_mark_under(&mut self, path: &str) -> Result<()>;
+        async fn delete_unmarked_under(&mut self, path: &str) -> Result<()>;
+        async fn mark_children_scanned(&mut self, path: &str) -> Result<()>;
+        async fn check_hash_version(&mut self, algo: HashAlgo) -> Result<bool>;
+        async fn update_hash_version(&mut self, algo: HashAlgo) -> Result<()>;
+        async fn query_event_checkpoint(&mut self) -> Result<u64>;
+        async fn update_event_checkpoint(&mut self, seq: u64) -> Result<()>;
+        async fn query_git_commit(&mut self) -> Result<Option<String>>;
+        async fn update_git_commit(&mut self, commit: Option<&str>) -> Result<()>;
+        async fn query_tokens(&mut self) -> Result<Vec<TokenInfo>>;
+        async fn insert_token(&mut self, token: TokenInfo) -> Result<TokenInfo>;
+        async fn delete_token(&mut self, id: i64) -> Result<bool>;
+        async fn query_holds(&mut self) -> Result<Vec<HoldInfo>>;
+        async fn insert_hold(&mut self, hold: HoldInfo) -> Result<()>;
+        async fn delete_hold(&mut self, path: &str) -> Result<bool>;
+        async fn query_links(&mut self) -> Result<Vec<LinkInfo>>;
+        async fn insert_link(&mut self, link: LinkInfo) -> Result<LinkInfo>;
+        async fn delete_link(&mut self, token: &str, id: i64) -> Result<bool>;
+        async fn delete_expired_links(&mut self, now: i64) -> Result<u64>;
+        async fn insert_upload(&mut self, upload: UploadStatus) -> Result<UploadStatus>;
+        async fn update_upload(&mut self, upload: &UploadStatus) -> Result<()>;
+        async fn query_upload(&mut self, id: u64) -> Result<Option<UploadStatus>>;
+        async fn delete_finished_uploads(&mut self, before: i64) -> Result<u64>;
+        async fn query_integrity_issues(&mut self) -> Result<Vec<IntegrityIssue>>;
+        async fn insert_integrity_issue(&mut self, issue: IntegrityIssue) -> Result<()>;
+        async fn delete_integrity_issue(&mut self, path: &str) -> Result<()>;
+        async fn query_scrub_batch(&mut self, cursor: &str, limit: i64) -> Result<Vec<FileEntry>>;
+        async fn query_errors(&mut self) -> Result<Vec<IndexError>>;
+        async fn insert_error(&mut self, error: IndexError) -> Result<()>;
+        async fn delete_error(&mut self, path: &str) -> Result<()>;
+        async fn delete_errors_under(&mut self, path: &str) -> Result<()>;
+        async fn insert_access(&mut self, record: AccessRecord) -> Result<()>;
+        async fn delete_access_before(&mut self, before: i64) -> Result<u64>;
+        async fn query_access_stats(&mut self, since: i64, limit: i64) -> Result<AccessStats>;
+        async fn query_scrub_cursor(&mut self) -> Result<String>;
+        async fn update_scrub_cursor(&mut self, cursor: &str) -> Result<()>;
+        async fn query_cold_files(&mut self, before: i64, limit: i64) -> Result<Vec<FileEntry>>;
+        async fn query_tiered(&mut self) -> Result<Vec<(String, String)>>;
+        async fn count_children(&mut self, path: &str) -> Result<i64>;
+        async fn upsert_chunks(
+            &mut self,
+            path: &str,
+            hash: &str,
+            chunk_size: i64,
+            chunks: &[String],
+        ) -> Result<()>;
+        async fn query_chunks(&mut self, path: &str) -> Result<Option<(String, i64, Vec<String>)>>;
+        async fn delete_stale_chunks(&mut self) -> Result<u64>;
+        async fn insert_tiered(&mut self, path: &str, location: &str, tiered_at: i64)
+            -> Result<()>;
+        async fn delete_tiered(&mut self, path: &str) -> Result<()>;
+
+        /// Following writes are committed together by `commit_batch`, transactions of write
+        /// methods become savepoints
+        async fn begin_batch(&mut self) -> Result<()>;
+        async fn commit_batch(&mut self) -> Result<()>;
+        async fn rollback_batch(&mut self) -> Result<()>;
+
+        async fn query_path(&mut self, path: &Path) -> Result<Option<FileEntry>> {
+            self.query(path.to_str().expect(PATH_UTF8_ERROR)).await
+        }
+    }
+
+    #[async_trait]
+    impl Database for SqliteConnection {
+        async fn query(&mut self, path: &str) -> Result<Option<FileEntry>> {
+            retry_busy!(current::query(self, path))
+        }
+        async fn query_case_variants(&mut self, path: &str) -> Result<Vec<String>> {
+            retry_busy!(current::query_case_variants(self, path))
+        }
+        async fn query_by_prefixes(
+            &mut self,
+            prefixes: &[String],
+            option: &ListOption,
+            page: Option<&Pagination>,
+        ) -> Result<Vec<FileEntry>> {
+            retry_busy!(current::query_by_prefixes(self, prefixes, option, page))
+        }
+        async fn query_largest(
+            &mut self,
+            prefixes: &[String],
+            limit: i64,
+        ) -> Result<Vec<FileEntry>> {
+            retry_busy!(current::query_largest(self, prefixes, limit))
+        }
+        async fn query_stale(
+            &mut self,
+            prefixes: &[String],
+            before: i64,
+            limit: i64,
+        ) -> Result<Vec<FileEntry>> {
+            retry_busy!(current::query_stale(self, prefixes, before, limit))
+        }
+        async fn query_by_hash(
+            &mut self,
+            prefixes: &[String],
+            hash: &str,
+        ) -> Result<Vec<FileEntry>> {
+            retry_busy!(current::query_by_hash(self, prefixes, hash))
+        }
+        async fn search(
+            &mut self,
+            prefixes: &[String],
+            glob: &str,
+            cursor: &str,
+            limit: i64,
+        ) -> Result<Vec<FileEntry>> {
+            retry_busy!(current::search(self, prefixes, glob, cursor, limit))
+        }
+        async fn query_duplicates(
+            &mut self,
+            prefixes: Option<&[String]>,
+            page: &Pagination,
+        ) -> Result<DuplicateReport> {
+            retry_busy!(current::query_duplicates(self, prefixes, page))
+        }
+        async fn query_stats(&mut self, paths: &[String]) -> Result<Vec<DirStats>> {
+            retry_busy!(current::query_stats(self, paths))
+        }
+        async fn insert(&mut self, entry: FileEntry) -> Result<()> {
+            retry_busy!(current::insert(self, &entry))
+        }
+        async fn upsert(&mut self, entry: FileEntry) -> Result<()> {
+            retry_busy!(current::upsert(self, &entry))
+        }
+        async fn update(&mut self, entry: FileEntry) -> Result<()> {
+            retry_busy!(current::update(self, &entry))
+        }
+        async fn delete(&mut self, path: String) -> Result<()> {
+            retry_busy!(current::delete(self, &path))
+        }
+        async fn rename(&mut self, from: &str, to: &str) -> Result<bool> {
+            retry_busy!(current::rename(self, from, to))
+        }
+        async fn mark(&mut self, entry: FileEntry) -> Result<()> {
+            retry_busy!(current::mark(self, &entry))
+        }
+        async fn reset_all_mark(&mut self) -> Result<()> {
+            retry_busy!(current::reset_all_mark(self))
+        }
+        async fn delete_all_unmarked(&mut self) -> Result<()> {
+            retry_busy!(current::delete_all_unmarked(self))
+        }
+        async fn reset_mark_under(&mut self, path: &str) -> Result<()> {
+            retry_busy!(current::reset_mark_under(self, path))
+        }
+        async fn delete_unmarked_under(&mut self, path: &str) -> Result<()> {
+            retry_busy!(current::delete_unmarked_under(self, path))
+        }
+        async fn mark_children_scanned(&mut self, path: &str) -> Result<()> {
+            retry_busy!(current::mark_children_scanned(self, path))
+        }
+        async fn check_hash_version(&mut self, algo: HashAlgo) -> Result<bool> {
+            retry_busy!(current::check_hash_version(self, algo))
+        }
+        async fn update_hash_version(&mut self, algo: HashAlgo) -> Result<()> {
+            retry_busy!(current::update_hash_version(self, algo))
+        }
+        async fn query_event_checkpoint(&mut self) -> Result<u64> {
+            retry_busy!(current::query_event_checkpoint(self))
+        }
+        async fn update_event_checkpoint(&mut self, seq: u64) -> Result<()> {
+            retry_busy!(current::update_event_checkpoint(self, seq))
+        }
+        async fn query_git_commit(&mut self) -> Result<Option<String>> {
+            retry_busy!(current::query_git_commit(self))
+        }
+        async fn update_git_commit(&mut self, commit: Option<&str>) -> Result<()> {
+            retry_busy!(current::update_git_commit(self, commit))
+        }
+        async fn query_tokens(&mut self) -> Result<Vec<TokenInfo>> {
+            retry_busy!(current::query_tokens(self))
+        }
+        async fn insert_token(&mut self, token: TokenInfo) -> Result<TokenInfo> {
+            retry_busy!(current::insert_token(self, &token))
+        }
+        async fn delete_token(&mut self, id: i64) -> Result<bool> {
+            retry_busy!(current::delete_token(self, id))
+        }
+        async fn query_holds(&mut self) -> Result<Vec<HoldInfo>> {
+            retry_busy!(current::query_holds(self))
+        }
+        async fn insert_hold(&mut self, hold: HoldInfo) -> Result<()> {
+            retry_busy!(current::insert_hold(self, &hold))
+        }
+        async fn delete_hold(&mut self, path: &str) -> Result<bool> {
+            retry_busy!(current::delete_hold(self, path))
+        }
+        async fn query_links(&mut self) -> Result<Vec<LinkInfo>> {
+            retry_busy!(current::query_links(self))
+        }
+        async fn insert_link(&mut self, link: LinkInfo) -> Result<LinkInfo> {
+            retry_busy!(current::insert_link(self, &link))
+        }
+        async fn delete_link(&mut self, token: &str, id: i64) -> Result<bool> {
+            retry_busy!(current::delete_link(self, token, id))
+        }
+        async fn delete_expired_links(&mut self, now: i64) -> Result<u64> {
+            retry_busy!(current::delete_expired_links(self, now))
+        }
+        async fn insert_upload(&mut self, upload: UploadStatus) -> Result<UploadStatus> {
+            retry_busy!(current::insert_upload(self, &upload))
+        }
+        async fn update_upload(&mut self, upload: &UploadStatus) -> Result<()> {
+            retry_busy!(current::update_upload(self, upload))
+        }
+        async fn query_upload(&mut self, id: u64) -> Result<Option<UploadStatus>> {
+            retry_busy!(current::query_upload(self, id))
+        }
+        async fn delete_finished_uploads(&mut self, before: i64) -> Result<u64> {
+            retry_busy!(current::delete_finished_uploads(self, before))
+        }
+        async fn query_integrity_issues(&mut self) -> Result<Vec<IntegrityIssue>> {
+            retry_busy!(current::query_integrity_issues(self))
+        }
+        async fn insert_integrity_issue(&mut self, issue: IntegrityIssue) -> Result<()> {
+            retry_busy!(current::insert_integrity_issue(self, &issue))
+        }
+        async fn delete_integrity_issue(&mut self, path: &str) -> Result<()> {
+            retry_busy!(current::delete_integrity_issue(self, path))
+        }
+        async fn query_scrub_batch(&mut self, cursor: &str, limit: i64) -> Result<Vec<FileEntry>> {
+            retry_busy!(current::query_scrub_batch(self, cursor, limit))
+        }
+        async fn query_scrub_cursor(&mut self) -> Result<String> {
+            retry_busy!(current::query_scrub_cursor(self))
+        }
+        async fn query_errors(&mut self) -> Result<Vec<IndexError>> {
+            retry_busy!(current::query_errors(self))
+        }
+        async fn insert_error(&mut self, error: IndexError) -> Result<()> {
+            retry_busy!(current::insert_error(self, &error))
+        }
+        async fn delete_error(&mut self, path: &str) -> Result<()> {
+            retry_busy!(current::delete_error(self, path))
+        }
+        async fn delete_errors_under(&mut self, path: &str) -> Result<()> {
+            retry_busy!(current::delete_errors_under(self, path))
+        }
+        async fn insert_access(&mut self, record: AccessRecord) -> Result<()> {
+            retry_busy!(current::insert_access(self, &record))
+        }
+        async fn delete_access_before(&mut self, before: i64) -> Result<u64> {
+            retry_busy!(current::delete_access_before(self, before))
+        }
+        async fn query_access_stats(&mut self, since: i64, limit: i64) -> Result<AccessStats> {
+            retry_busy!(current::query_access_stats(self, since, limit))
+        }
+        async fn update_scrub_cursor(&mut self, cursor: &str) -> Result<()> {
+            retry_busy!(current::update_scrub_cursor(self, cursor))
+        }
+        async fn query_cold_files(&mut self, before: i64, limit: i64) -> Result<Vec<FileEntry>> {
+            retry_busy!(current::query_cold_files(self, before, limit))
+        }
+        async fn query_tiered(&mut self) -> Result<Vec<(String, String)>> {
+            retry_busy!(current::query_tiered(self))
+        }
+        async fn count_children(&mut self, path: &str) -> Result<i64> {
+            retry_busy!(current::count_children(self, path))
+        }
+        async fn upsert_chunks(
+            &mut self,
+            path: &str,
+            hash: &str,
+            chunk_size: i64,
+            chunks: &[String],
+        ) -> Result<()> {
+            retry_busy!(current::upsert_chunks(self, path, hash, chunk_size, chunks))
+        }
+        async fn query_chunks(&mut self, path: &str) -> Result<Option<(String, i64, Vec<String>)>> {
+            retry_busy!(current::query_chunks(self, path))
+        }
+        async fn delete_stale_chunks(&mut self) -> Result<u64> {
+            retry_busy!(current::delete_stale_chunks(self))
+        }
+        async fn insert_tiered(
+            &mut self,
+            path: &str,
+            location: &str,
+            tiered_at: i64,
+        ) -> Result<()> {
+            retry_busy!(current::insert_tiered(self, path, location, tiered_at))
+        }
+        async fn delete_tiered(&mut self, path: &str) -> Result<()> {
+            retry_busy!(current::delete_tiered(self, path))
+        }
+        async fn begin_batch(&mut self) -> Result<()> {
+            let result = retry_busy!(SqliteTransactionManager::begin(self));
+            if result.as_ref().is_err_and(is_busy) {
+                // Don't leave half opened batch, later writes would nest in it
+                SqliteTransactionManager::rollback(self).await.ok();
+            }
+            result
+        }
+        async fn commit_batch(&mut self) -> Result<()> {
+            // Transaction is kept open if commit is busy
+            retry_busy!(SqliteTransactionManager::commit(self))
+        }
+        async fn rollback_batch(&mut self) -> Result<()> {
+            SqliteTransactionManager::rollback(self).await
+        }
+    }
+}
+
+/// Same schema as `current`, path order of natural collation is sorted after fetched
+#[cfg(feature = "postgres")]
+mod postgres {
+    use super::current::{
+        ancestors, children_patterns, error_from_row, glob_to_like, hash_version, insert_percent,
+        link_from_row, prefix_condition, prefixes_condition, token_from_row, upload_from_row,
+        ErrorRow, LinkRow, TokenRow, UploadRow, EVENT_CHECKPOINT_KEY, GIT_COMMIT_KEY,
+        HASH_VERSION_KEY, SCRUB_CURSOR_KEY,
+    };
+    use super::Database;
+    use async_trait::async_trait;
+    use format_sql_query::QuotedData;
+    use publib::file::HashAlgo;
+    use publib::types::{
+        AccessRecord, AccessStats, Collation, DirStats, DuplicateGroup, DuplicateReport,
+        ErrorClass, FileDownloads, FileEntry, HoldInfo, IndexError, IntegrityIssue, LinkInfo,
+        ListOption, Pagination, SortBy, SortOrder, TokenInfo, TokenUsage, UploadStatus,
+    };
+    use publib::{natural_cmp, normalize_path};
+    use sqlx::postgres::PgTransactionManager;
+    use sqlx::{Connection, PgConnection, Result, TransactionManager};
+    use std::collections::HashMap;
+
+    const CREATE_TABLES: [&str; 16] = [
+        r#"CREATE TABLE IF NOT EXISTS "files" (
+            "path" TEXT NOT NULL PRIMARY KEY,
+            "hash" TEXT,
+            "mtime" BIGINT NOT NULL DEFAULT 0,
+            "size" BIGINT NOT NULL DEFAULT 0,
+            "is_dir" BOOLEAN NOT NULL DEFAULT FALSE,
+            "marked" BOOLEAN NOT NULL DEFAULT FALSE,
+            "encrypted" BOOLEAN NOT NULL DEFAULT FALSE,
+            "children_scanned" BOOLEAN NOT NULL DEFAULT FALSE
+        )"#,
+        r#"CREATE TABLE IF NOT EXISTS "meta" (
+            "key" TEXT NOT NULL PRIMARY KEY,
+            "value" TEXT
+        )"#,
+        r#"CREATE TABLE IF NOT EXISTS "tokens" (
+            "id" BIGSERIAL PRIMARY KEY,
+            "token" TEXT NOT NULL UNIQUE,
+            "paths" TEXT NOT NULL,
+            "admin" BOOLEAN NOT NULL DEFAULT FALSE,
+            "concurrent_requests" BIGINT,
+            "scopes" TEXT,
+            "created_at" BIGINT NOT NULL DEFAULT 0
+        )"#,
+        r#"CREATE TABLE IF NOT EXISTS "dir_stats" (
+            "path" TEXT NOT NULL PRIMARY KEY,
+            "files" BIGINT NOT NULL DEFAULT 0,
+            "size" BIGINT NOT NULL DEFAULT 0,
+            "mtime" BIGINT NOT NULL DEFAULT 0
+        )"#,
+        r#"CREATE TABLE IF NOT EXISTS "holds" (
+            "path" TEXT NOT NULL PRIMARY KEY,
+            "reason" TEXT,
+            "created_at" BIGINT NOT NULL DEFAULT 0
+        )"#,
+        r#"CREATE TABLE IF NOT EXISTS "links" (
+            "id" BIGSERIAL PRIMARY KEY,
+            "token" TEXT NOT NULL,
+            "path" TEXT NOT NULL,
+            "signature" TEXT NOT NULL,
+            "expires" BIGINT NOT NULL,
+            "created_at" BIGINT NOT NULL DEFAULT 0
+        )"#,
+        r#"ALTER TABLE "files" ADD COLUMN IF NOT EXISTS "children_scanned" BOOLEAN NOT NULL DEFAULT FALSE"#,
+        r#"CREATE INDEX IF NOT EXISTS "files_path_lower" ON "files" (lower("path"))"#,
+        r#"CREATE INDEX IF NOT EXISTS "files_hash_size" ON "files" ("hash", "size")"#,
+        r#"CREATE TABLE IF NOT EXISTS "integrity_issues" (
+            "path" TEXT NOT NULL PRIMARY KEY,
+            "expected" TEXT NOT NULL,
+            "actual" TEXT NOT NULL,
+            "detected_at" BIGINT NOT NULL DEFAULT 0
+        )"#,
+        r#"CREATE TABLE IF NOT EXISTS "errors" (
+            "path" TEXT NOT NULL PRIMARY KEY,
+            "class" TEXT NOT NULL,
+            "message" TEXT NOT NULL,
+            "occurred_at" BIGINT NOT NULL DEFAULT 0
+        )"#,
+        r#"CREATE TABLE IF NOT EXISTS "access_log" (
+            "id" BIGSERIAL PRIMARY KEY,
+            "token" TEXT NOT NULL,
+            "path" TEXT NOT NULL,
+            "bytes" BIGINT NOT NULL DEFAULT 0,
+            "duration_ms" BIGINT NOT NULL DEFAULT 0,
+            "status" INTEGER NOT NULL,
+            "accessed_at" BIGINT NOT NULL DEFAULT 0
+        )"#,
+        r#"CREATE INDEX IF NOT EXISTS "access_log_accessed_at" ON "access_log" ("accessed_at")"#,
+        r#"CREATE TABLE IF NOT EXISTS "tiered" (
+            "path" TEXT NOT NULL PRIMARY KEY,
+            "location" TEXT NOT NULL,
+            "tiered_at" BIGINT NOT NULL DEFAULT 0
+        )"#,
+        r#"CREATE TABLE IF NOT EXISTS "chunks" (
+            "path" TEXT NOT NULL PRIMARY KEY,
+            "hash" TEXT NOT NULL,
+            "chunk_size" BIGINT NOT NULL,
+            "chunks" TEXT NOT NULL
+        )"#,
+        r#"CREATE TABLE IF NOT EXISTS "uploads" (
+            "id" BIGSERIAL PRIMARY KEY,
+            "token" TEXT NOT NULL,
+            "path" TEXT NOT NULL,
+            "state" TEXT NOT NULL,
+            "reason" TEXT,
+            "created_at" BIGINT NOT NULL DEFAULT 0,
+            "finished_at" BIGINT
+        )"#,
+        r#"CREATE INDEX IF NOT EXISTS "uploads_finished_at" ON "uploads" ("finished_at")"#,
+    ];
+
+    const FILE_COLUMNS: &str =
+        r#""path", "hash", "mtime", "size", "is_dir", "encrypted", "children_scanned""#;
+
+    type FileRow = (String, Option<String>, i64, i64, bool, bool, bool);
+
+    fn entry_from_row(row: FileRow) -> FileEntry {
+        let (path, hash, mtime, size, is_dir, encrypted, children_scanned) = row;
+        FileEntry::new(path, hash.unwrap_or_default(), mtime, size, is_dir)
+            .with_encrypted(encrypted)
+            .with_children_scanned(children_scanned)
+    }
+
+    async fn fetch_entries(conn: &mut PgConnection, sql: &str) -> Result<Vec<FileEntry>> {
+        Ok(sqlx::query_as::<_, FileRow>(sql)
+            .fetch_all(conn)
+            .await?
+            .into_iter()
+            .map(entry_from_row)
+            .collect())
+    }
+
+    pub async fn load_database(url: &str) -> Result<PgConnection> {
+        let mut conn = PgConnection::connect(url).await?;
+        let (has_stats,) = sqlx::query_as::<_, (bool,)>(
+            r#"SELECT EXISTS (SELECT 1 FROM information_schema.tables WHERE "table_name" = 'dir_stats')"#,
+        )
+        .fetch_one(&mut conn)
+        .await?;
+        for sql in CREATE_TABLES {
+            sqlx::query(sql).execute(&mut conn).await?;
+        }
+        if !has_stats {
+            rebuild_stats(&mut conn).await?;
+        }
+        Ok(conn)
+    }
+
+    async fn add_stats(
+        conn: &mut PgConnection,
+        path: &str,
+        files: i64,
+        size: i64,
+        mtime: i64,
+    ) -> Result<()> {
+        for dir in ancestors(path) {
+            sqlx::query(
+                r#"INSERT INTO "dir_stats" VALUES ($1, $2, $3, $4) ON CONFLICT ("path") DO UPDATE
+                SET "files" = "dir_stats"."files" + excluded."files",
+                "size" = "dir_stats"."size" + excluded."size",
+                "mtime" = GREATEST("dir_stats"."mtime", excluded."mtime")"#,
+            )
+            .bind(dir)
+            .bind(files)
+            .bind(size)
+            .bind(mtime)
+            .execute(&mut *conn)
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn subtree_stats(conn: &mut PgConnection, path: &str) -> Result<(i64, i64, i64)> {
+        let (files, size, mtime) = match query(conn, path).await? {
+            Some(entry) if !entry.is_dir() => (1, entry.size(), entry.mtime()),
+            _ => (0, 0, 0),
+        };
+        let children = sqlx::query_as::<_, (i64, i64, i64)>(
+            r#"SELECT "files", "size", "mtime" FROM "dir_stats" WHERE "path" = $1"#,
+        )
+        .bind(path)
+        .fetch_optional(conn)
+        .await?
+        .unwrap_or_default();
+        Ok((files + children.0, size + children.1, mtime.max(children.2)))
+    }
+
+    async fn delete_stats_under(conn: &mut PgConnection, path: &str) -> Result<()> {
+        sqlx::query(&format!(
+            r#"DELETE FROM "dir_stats" WHERE "path" = {} OR "path" LIKE {}"#,
+            QuotedData(path),
+            insert_percent(path.to_string())
+        ))
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    async fn rebuild_stats(conn: &mut PgConnection) -> Result<()> {
+        let rows = sqlx::query_as::<_, (String, i64, i64)>(
+            r#"SELECT "path", "size", "mtime" FROM "files" WHERE NOT "is_dir""#,
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        let mut stats: HashMap<&str, (i64, i64, i64)> = HashMap::new();
+        for (path, size, mtime) in &rows {
+            for dir in ancestors(path) {
+                let entry = stats.entry(dir).or_default();
+                entry.0 += 1;
+                entry.1 += size;
+                entry.2 = entry.2.max(*mtime);
+            }
+        }
+        let mut transaction = conn.begin().await?;
+        sqlx::query(r#"DELETE FROM "dir_stats""#)
+            .execute(&mut *transaction)
+            .await?;
+        for (path, (files, size, mtime)) in stats {
+            sqlx::query(r#"INSERT INTO "dir_stats" VALUES ($1, $2, $3, $4)"#)
+                .bind(path)
+                .bind(files)
+                .bind(size)
+                .bind(mtime)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        transaction.commit().await
+    }
+
+    async fn query(conn: &mut PgConnection, path: &str) -> Result<Option<FileEntry>> {
+        Ok(sqlx::query_as::<_, FileRow>(&format!(
+            r#"SELECT {} FROM "files" WHERE "path" = $1"#,
+            FILE_COLUMNS
+        ))
+        .bind(path)
+        .fetch_optional(conn)
+        .await?
+        .map(entry_from_row))
+    }
+
+    async fn set_meta(conn: &mut PgConnection, key: &str, value: String) -> Result<()> {
+        sqlx::query(
+            r#"INSERT INTO "meta" VALUES ($1, $2) ON CONFLICT ("key") DO UPDATE SET "value" = excluded."value""#,
+        )
+        .bind(key)
+        .bind(value)
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_meta(conn: &mut PgConnection, key: &str) -> Result<Option<String>> {
+        Ok(
+            sqlx::query_as::<_, (Option<String>,)>(
+                r#"SELECT "value" FROM "meta" WHERE "key" = $1"#,
+            )
+            .bind(key)
+            .fetch_optional(conn)
+            .await?
+            .and_then(|(value,)| value),
+        )
+    }
+
+    #[async_trait]
+    impl Database for PgConnection {
+        async fn query(&mut self, path: &str) -> Result<Option<FileEntry>> {
+            query(self, path).await
+        }
+
+        async fn query_case_variants(&mut self, path: &str) -> Result<Vec<String>> {
+            Ok(sqlx::query_as::<_, (String,)>(
+                r#"SELECT "path" FROM "files" WHERE lower("path") = lower($1) ORDER BY "path""#,
+            )
+            .bind(path)
+            .fetch_all(self)
+            .await?
+            .into_iter()
+            .map(|(path,)| path)
+            .collect())
+        }
+
+        async fn query_by_prefixes(
+            &mut self,
+            prefixes: &[String],
+            option: &ListOption,
+            page: Option<&Pagination>,
+        ) -> Result<Vec<FileEntry>> {
+            let Some(condition) = prefixes_condition(prefixes) else {
+                return Ok(Vec::new());
+            };
+            let mut sql = format!(
+                r#"SELECT {} FROM "files" WHERE {}"#,
+                FILE_COLUMNS, condition
+            );
+            let mut index = 0;
+            if option.min_size().is_some() {
+                index += 1;
+                sql.push_str(&format!(r#" AND "size" >= ${}"#, index));
+            }
+            if option.max_size().is_some() {
+                index += 1;
+                sql.push_str(&format!(r#" AND "size" <= ${}"#, index));
+            }
+            if option.modified_after().is_some() {
+                index += 1;
+                sql.push_str(&format!(r#" AND "mtime" > ${}"#, index));
+            }
+            let order = match option.order() {
+                SortOrder::Asc => "ASC",
+                SortOrder::Desc => "DESC",
+            };
+            sql.push_str(&match option.sort() {
+                SortBy::Path => format!(r#" ORDER BY "path" {}"#, order),
+                SortBy::Mtime => format!(r#" ORDER BY "mtime" {0}, "path" {0}"#, order),
+                SortBy::Size => format!(r#" ORDER BY "size" {0}, "path" {0}"#, order),
+            });
+            // Natural order is sorted after fetched, so is its page
+            let natural = matches!(option.collation(), Collation::Natural);
+            if let Some(page) = page.filter(|_| !natural) {
+                sql.push_str(&format!(" LIMIT {} OFFSET {}", page.limit(), page.offset()));
+            }
+            let mut query = sqlx::query_as::<_, FileRow>(&sql);
+            if let Some(min_size) = option.min_size() {
+                query = query.bind(min_size);
+            }
+            if let Some(max_size) = option.max_size() {
+                query = query.bind(max_size);
+            }
+            if let Some(modified_after) = option.modified_after() {
+                query = query.bind(modified_after);
+            }
+            let mut entries = query
+                .fetch_all(self)
+                .await?
+                .into_iter()
+                .map(entry_from_row)
+                .collect::<Vec<_>>();
+            if natural {
+                entries.sort_by(|a, b| {
+                    let ordering = match option.sort() {
+                        SortBy::Path => std::cmp::Ordering::Equal,
+                        SortBy::Mtime => a.mtime().cmp(&b.mtime()),
+                        SortBy::Size => a.size().cmp(&b.size()),
+                    }
+                    .then_with(|| natural_cmp(a.path(), b.path()));
+                    match option.order() {
+                        SortOrder::Asc => ordering,
+                        SortOrder::Desc => ordering.reverse(),
+                    }
+                });
+                if let Some(page) = page {
+                    entries = entries
+                        .into_iter()
+                        .skip(page.offset() as usize)
+                        .take(page.limit() as usize)
+                        .collect();
+                }
+            }
+            Ok(entries)
+        }
+
+        async fn query_largest(
+            &mut self,
+            prefixes: &[String],
+            limit: i64,
+        ) -> Result<Vec<FileEntry>> {
+            let Some(condition) = prefixes_condition(prefixes) else {
+                return Ok(Vec::new());
+            };
+            fetch_entries(
+                self,
+                &format!(
+                    r#"SELECT {} FROM "files" WHERE NOT "is_dir" AND {}
+                    ORDER BY "size" DESC, "path" LIMIT {}"#,
+                    FILE_COLUMNS, condition, limit
+                ),
+            )
+            .await
+        }
+
+        async fn query_stale(
+            &mut self,
+            prefixes: &[String],
+            before: i64,
+            limit: i64,
+        ) -> Result<Vec<FileEntry>> {
+            let Some(condition) = prefixes_condition(prefixes) else {
+                return Ok(Vec::new());
+            };
+            fetch_entries(
+                self,
+                &format!(
+                    r#"SELECT {} FROM "files" WHERE NOT "is_dir" AND {} AND "mtime" < {}
+                    ORDER BY "mtime", "path" LIMIT {}"#,
+                    FILE_COLUMNS, condition, before, limit
+                ),
+            )
+            .await
+        }
+
+        async fn search(
+            &mut self,
+            prefixes: &[String],
+            glob: &str,
+            cursor: &str,
+            limit: i64,
+        ) -> Result<Vec<FileEntry>> {
+            let Some(condition) = prefixes_condition(prefixes) else {
+                return Ok(Vec::new());
+            };
+            Ok(sqlx::query_as::<_, FileRow>(&format!(
+                r#"SELECT {} FROM "files" WHERE {} AND "path" ILIKE $1 ESCAPE '\'
+                AND "path" COLLATE "C" > $2 ORDER BY "path" COLLATE "C" LIMIT $3"#,
+                FILE_COLUMNS, condition
+            ))
+            .bind(glob_to_like(glob))
+            .bind(cursor)
+            .bind(limit)
+            .fetch_all(self)
+            .await?
+            .into_iter()
+            .map(entry_from_row)
+            .collect())
+        }
+
+        async fn query_by_hash(
+            &mut self,
+            prefixes: &[String],
+            hash: &str,
+        ) -> Result<Vec<FileEntry>> {
+            let Some(condition) = prefixes_condition(prefixes) else {
+                return Ok(Vec::new());
+            };
+            fetch_entries(
+                self,
+                &format!(
+                    r#"SELECT {} FROM "files" WHERE "hash" = {} AND NOT "is_dir" AND {}
+                    ORDER BY "path""#,
+                    FILE_COLUMNS,
+                    QuotedData(hash),
+                    condition,
+                ),
+            )
+            .await
+        }
+
+        async fn query_duplicates(
+            &mut self,
+            prefixes: Option<&[String]>,
+            page: &Pagination,
+        ) -> Result<DuplicateReport> {
+            let scope = match prefixes.map(prefixes_condition) {
+                Some(Some(condition)) => format!(" AND {}", condition),
+                Some(None) => return Ok(DuplicateReport::default()),
+                None => String::new(),
+            };
+            let groups_sql = format!(
+                r#"SELECT "hash", "size", COUNT(*) AS "count" FROM "files"
+                WHERE NOT "is_dir" AND "hash" IS NOT NULL AND "hash" != ''{}
+                GROUP BY "hash", "size" HAVING COUNT(*) > 1"#,
+                scope
+            );
+
+            let (total_groups, total_wasted) = sqlx::query_as::<_, (i64, i64)>(&format!(
+                r#"SELECT COUNT(*), COALESCE(SUM("size" * ("count" - 1)), 0)::BIGINT FROM ({}) AS "groups""#,
+                groups_sql
+            ))
+            .fetch_one(&mut *self)
+            .await?;
+
+            let page_groups = sqlx::query_as::<_, (String, i64)>(&format!(
+                r#"SELECT "hash", "size" FROM ({}) AS "groups"
+                ORDER BY "size" * ("count" - 1) DESC, "hash" LIMIT $1 OFFSET $2"#,
+                groups_sql
+            ))
+            .bind(page.limit())
+            .bind(page.offset())
+            .fetch_all(&mut *self)
+            .await?;
+
+            let mut groups = Vec::new();
+            for (hash, size) in page_groups {
+                let paths = sqlx::query_as::<_, (String,)>(&format!(
+                    r#"SELECT "path" FROM "files"
+                    WHERE "hash" = $1 AND "size" = $2 AND NOT "is_dir"{} ORDER BY "path""#,
+                    scope
+                ))
+                .bind(&hash)
+                .bind(size)
+                .fetch_all(&mut *self)
+                .await?
+                .into_iter()
+                .map(|(path,)| path)
+                .collect();
+                groups.push(DuplicateGroup::new(hash, size, paths));
+            }
+
+            Ok(DuplicateReport::new(total_groups, total_wasted, groups))
+        }
+
+        async fn query_stats(&mut self, paths: &[String]) -> Result<Vec<DirStats>> {
+            let mut result = Vec::new();
+            for path in paths {
+                let path = normalize_path(path);
+                let stats = sqlx::query_as::<_, (i64, i64, i64)>(
+                    r#"SELECT "files", "size", "mtime" FROM "dir_stats" WHERE "path" = $1"#,
+                )
+                .bind(&path)
+                .fetch_optional(&mut *self)
+                .await?;
+                if let Some((files, size, mtime)) = stats {
+                    result.push(DirStats::new(path, files, size, mtime));
+                    continue;
+                }
+                let is_dir = path == "."
+                    || query(self, &path)
+                        .await?
+                        .is_some_and(|entry| entry.is_dir());
+                if is_dir {
+                    result.push(DirStats::new(path, 0, 0, 0));
+                }
+            }
+            Ok(result)
+        }
+
+        async fn insert(&mut self, entry: FileEntry) -> Result<()> {
+            let mut transaction = self.begin().await?;
+            sqlx::query(
+                r#"INSERT INTO "files" ("path", "hash", "mtime", "size", "is_dir", "marked", "encrypted")
+                VALUES ($1, $2, $3, $4, $5, TRUE, $6)"#,
+            )
+            .bind(entry.path())
+            .bind((!entry.is_dir()).then(|| entry.hash()))
+            .bind(entry.mtime())
+            .bind(entry.size())
+            .bind(entry.is_dir())
+            .bind(entry.encrypted())
+            .execute(&mut *transaction)
+            .await?;
+            if !entry.is_dir() {
+                add_stats(
+                    &mut transaction,
+                    entry.path(),
+                    1,
+                    entry.size(),
+                    entry.mtime(),
+                )
+                .await?;
+            }
+            transaction.commit().await
+        }
+
+        async fn upsert(&mut self, entry: FileEntry) -> Result<()> {
+            let mut transaction = self.begin().await?;
+            let (old_files, old_size) = match query(&mut transaction, entry.path()).await? {
+                Some(old) if !old.is_dir() => (1, old.size()),
+                _ => (0, 0),
+            };
+            let (files, size) = if entry.is_dir() {
+                (0, 0)
+            } else {
+                (1, entry.size())
+            };
+            sqlx::query(
+                r#"INSERT INTO "files" ("path", "hash", "mtime", "size", "is_dir", "marked", "encrypted")
+                VALUES ($1, $2, $3, $4, $5, TRUE, $6) ON CONFLICT ("path") DO UPDATE
+                SET "hash" = excluded."hash", "mtime" = excluded."mtime", "size" = excluded."size",
+                "is_dir" = excluded."is_dir", "marked" = TRUE, "encrypted" = excluded."encrypted""#,
+            )
+            .bind(entry.path())
+            .bind((!entry.is_dir()).then(|| entry.hash()))
+            .bind(entry.mtime())
+            .bind(entry.size())
+            .bind(entry.is_dir())
+            .bind(entry.encrypted())
+            .execute(&mut *transaction)
+            .await?;
+            if files != 0 || old_files != 0 {
+                add_stats(
+                    &mut transaction,
+                    entry.path(),
+                    files - old_files,
+                    size - old_size,
+                    entry.mtime(),
+                )
+                .await?;
+            }
+            transaction.commit().await
+        }
+
+        async fn update(&mut self, entry: FileEntry) -> Result<()> {
+            let mut transaction = self.begin().await?;
+            if !entry.is_dir() {
+                let old = sqlx::query_as::<_, (i64,)>(
+                    r#"SELECT "size" FROM "files" WHERE "path" = $1 AND NOT "is_dir""#,
+                )
+                .bind(entry.path())
+                .fetch_optional(&mut *transaction)
+                .await?;
+                if let Some((size,)) = old {
+                    add_stats(
+                        &mut transaction,
+                        entry.path(),
+                        0,
+                        entry.size() - size,
+                        entry.mtime(),
+                    )
+                    .await?;
+                }
+            }
+            sqlx::query(
+                r#"UPDATE "files" SET "hash" = $1, "mtime" = $2, "size" = $3, "encrypted" = $4,
+                "marked" = TRUE WHERE "path" = $5"#,
+            )
+            .bind((!entry.is_dir()).then(|| entry.hash()))
+            .bind(entry.mtime())
+            .bind(entry.size())
+            .bind(entry.encrypted())
+            .bind(entry.path())
+            .execute(&mut *transaction)
+            .await?;
+            transaction.commit().await
+        }
+
+        async fn delete(&mut self, path: String) -> Result<()> {
+            let mut transaction = self.begin().await?;
+            match query(&mut transaction, &path).await? {
+                Some(entry) if entry.is_dir() => {
+                    let (files, size, _) = subtree_stats(&mut transaction, &path).await?;
+                    add_stats(&mut transaction, &path, -files, -size, 0).await?;
+                    delete_stats_under(&mut transaction, &path).await?;
+                    sqlx::query(&format!(
+                        r#"DELETE FROM "files" WHERE "path" = $1 OR "path" LIKE {}"#,
+                        insert_percent(path.clone())
+                    ))
+                    .bind(path)
+                    .execute(&mut *transaction)
+                    .await?;
+                }
+                Some(entry) => {
+                    add_stats(&mut transaction, &path, -1, -entry.size(), 0).await?;
+                    sqlx::query(r#"DELETE FROM "files" WHERE "path" = $1"#)
+                        .bind(path)
+                        .execute(&mut *transaction)
+                        .await?;
+                }
+                None => {}
+            }
+            transaction.commit().await
+        }
+
+        async fn rename(&mut self, from: &str, to: &str) -> Result<bool> {
+            let mut transaction = self.begin().await?;
+            let (files, size, _) = subtree_stats(&mut transaction, to).await?;
+            add_stats(&mut transaction, to, -files, -size, 0).await?;
+            delete_stats_under(&mut transaction, to).await?;
+            sqlx::query(&format!(
+                r#"DELETE FROM "files" WHERE "path" = $1 OR "path" LIKE {}"#,
+                insert_percent(to.to_string())
+            ))
+            .bind(to)
+            .execute(&mut *transaction)
+            .await?;
+            let (files, size, mtime) = subtree_stats(&mut transaction, from).await?;
+            add_stats(&mut transaction, from, -files, -size, 0).await?;
+            add_stats(&mut transaction, to, files, size, mtime).await?;
+            let result = sqlx::query(r#"UPDATE "files" SET "path" = $1 WHERE "path" = $2"#)
+                .bind(to)
+                .bind(from)
+                .execute(&mut *transaction)
+                .await?;
+            for table in ["files", "dir_stats"] {
+                sqlx::query(&format!(
+                    r#"UPDATE "{}" SET "path" = $1 || substr("path", $2) WHERE "path" LIKE {}"#,
+                    table,
+                    insert_percent(from.to_string())
+                ))
+                .bind(to)
+                .bind(from.chars().count() as i32 + 1)
+                .execute(&mut *transaction)
+                .await?;
+            }
+            sqlx::query(r#"UPDATE "dir_stats" SET "path" = $1 WHERE "path" = $2"#)
+                .bind(to)
+                .bind(from)
+                .execute(&mut *transaction)
+                .await?;
+            transaction.commit().await?;
+            Ok(result.rows_affected() > 0)
+        }
+
+        async fn mark(&mut self, entry: FileEntry) -> Result<()> {
+            sqlx::query(r#"UPDATE "files" SET "marked" = TRUE WHERE "path" = $1"#)
+                .bind(entry.path())
+                .execute(self)
+                .await?;
+            Ok(())
+        }
+
+        async fn reset_all_mark(&mut self) -> Result<()> {
+            sqlx::query(r#"UPDATE "files" SET "marked" = FALSE"#)
+                .execute(self)
+                .await?;
+            Ok(())
+        }
+
+        async fn delete_all_unmarked(&mut self) -> Result<()> {
+            sqlx::query(r#"DELETE FROM "files" WHERE NOT "marked""#)
+                .execute(&mut *self)
+                .await?;
+            rebuild_stats(self).await
+        }
+
+        async fn reset_mark_under(&mut self, path: &str) -> Result<()> {
+            sqlx::query(&format!(
+                r#"UPDATE "files" SET "marked" = FALSE WHERE "path" = $1 OR "path" LIKE {}"#,
+                insert_percent(path.to_string())
+            ))
+            .bind(path)
+            .execute(self)
+            .await?;
+            Ok(())
+        }
+
+        async fn delete_unmarked_under(&mut self, path: &str) -> Result<()> {
+            sqlx::query(&format!(
+                r#"DELETE FROM "files" WHERE NOT "marked" AND ("path" = $1 OR "path" LIKE {})"#,
+                insert_percent(path.to_string())
+            ))
+            .bind(path)
+            .execute(&mut *self)
+            .await?;
+            rebuild_stats(self).await
+        }
+
+        async fn mark_children_scanned(&mut self, path: &str) -> Result<()> {
+            sqlx::query(&format!(
+                r#"UPDATE "files" SET "children_scanned" = TRUE
+                WHERE "is_dir" AND "marked" AND ("path" = $1 OR "path" LIKE {})"#,
+                insert_percent(path.to_string())
+            ))
+            .bind(path)
+            .execute(self)
+            .await?;
+            Ok(())
+        }
+
+        async fn check_hash_version(&mut self, algo: HashAlgo) -> Result<bool> {
+            Ok(get_meta(self, HASH_VERSION_KEY).await? == Some(hash_version(algo)))
+        }
+
+        async fn update_hash_version(&mut self, algo: HashAlgo) -> Result<()> {
+            set_meta(self, HASH_VERSION_KEY, hash_version(algo)).await
+        }
+
+        async fn query_event_checkpoint(&mut self) -> Result<u64> {
+            Ok(get_meta(self, EVENT_CHECKPOINT_KEY)
+                .await?
+                .and_then(|checkpoint| checkpoint.parse().ok())
+                .unwrap_or_default())
+        }
+
+        async fn update_event_checkpoint(&mut self, seq: u64) -> Result<()> {
+            set_meta(self, EVENT_CHECKPOINT_KEY, seq.to_string()).await
+        }
+
+        async fn query_git_commit(&mut self) -> Result<Option<String>> {
+            get_meta(self, GIT_COMMIT_KEY).await
+        }
+
+        async fn update_git_commit(&mut self, commit: Option<&str>) -> Result<()> {
+            match commit {
+                Some(commit) => set_meta(self, GIT_COMMIT_KEY, commit.to_string()).await,
+                None => {
+                    sqlx::query(r#"DELETE FROM "meta" WHERE "key" = $1"#)
+                        .bind(GIT_COMMIT_KEY)
+                        .execute(self)
+                        .await?;
+                    Ok(())
+                }
+            }
+        }
+
+        async fn query_tokens(&mut self) -> Result<Vec<TokenInfo>> {
+            Ok(sqlx::query_as::<_, TokenRow>(
+                r#"SELECT "id", "token", "paths", "admin", "concurrent_requests", "scopes", "created_at"
+                FROM "tokens" ORDER BY "id""#,
+            )
+            .fetch_all(self)
+            .await?
+            .into_iter()
+            .map(token_from_row)
+            .collect())
+        }
+
+        async fn insert_token(&mut self, token: TokenInfo) -> Result<TokenInfo> {
+            let (id,) = sqlx::query_as::<_, (i64,)>(
+                r#"INSERT INTO "tokens" ("token", "paths", "admin", "concurrent_requests", "scopes", "created_at")
+                VALUES ($1, $2, $3, $4, $5, $6) RETURNING "id""#,
+            )
+            .bind(token.token())
+            .bind(serde_json::to_string(token.paths()).unwrap())
+            .bind(token.admin())
+            .bind(token.concurrent_requests().map(|count| count as i64))
+            .bind(token.scopes().map(|scopes| serde_json::to_string(scopes).unwrap()))
+            .bind(token.created_at())
+            .fetch_one(self)
+            .await?;
+            Ok(TokenInfo::new(
+                id,
+                token.token().to_string(),
+                token.paths().clone(),
+                token.admin(),
+                token.concurrent_requests(),
+                token.created_at(),
+            )
+            .with_scopes(token.scopes().cloned()))
+        }
+
+        async fn delete_token(&mut self, id: i64) -> Result<bool> {
+            let result = sqlx::query(r#"DELETE FROM "tokens" WHERE "id" = $1"#)
+                .bind(id)
+                .execute(self)
+                .await?;
+            Ok(result.rows_affected() > 0)
+        }
+
+        async fn query_holds(&mut self) -> Result<Vec<HoldInfo>> {
+            Ok(sqlx::query_as::<_, (String, Option<String>, i64)>(
+                r#"SELECT "path", "reason", "created_at" FROM "holds" ORDER BY "path""#,
+            )
+            .fetch_all(self)
+            .await?
+            .into_iter()
+            .map(|(path, reason, created_at)| HoldInfo::new(path, reason, created_at))
+            .collect())
+        }
+
+        async fn insert_hold(&mut self, hold: HoldInfo) -> Result<()> {
+            sqlx::query(
+                r#"INSERT INTO "holds" VALUES ($1, $2, $3) ON CONFLICT ("path") DO UPDATE
+                SET "reason" = excluded."reason", "created_at" = excluded."created_at""#,
+            )
+            .bind(hold.path())
+            .bind(hold.reason())
+            .bind(hold.created_at())
+            .execute(self)
+            .await?;
+            Ok(())
+        }
+
+        async fn delete_hold(&mut self, path: &str) -> Result<bool> {
+            let result = sqlx::query(r#"DELETE FROM "holds" WHERE "path" = $1"#)
+                .bind(path)
+                .execute(self)
+                .await?;
+            Ok(result.rows_affected() > 0)
+        }
+
+        async fn query_links(&mut self) -> Result<Vec<LinkInfo>> {
+            Ok(sqlx::query_as::<_, LinkRow>(
+                r#"SELECT "id", "token", "path", "signature", "expires", "created_at"
+                FROM "links" ORDER BY "id""#,
+            )
+            .fetch_all(self)
+            .await?
+            .into_iter()
+            .map(link_from_row)
+            .collect())
+        }
+
+        async fn insert_link(&mut self, link: LinkInfo) -> Result<LinkInfo> {
+            let (id,) = sqlx::query_as::<_, (i64,)>(
+                r#"INSERT INTO "links" ("token", "path", "signature", "expires", "created_at")
+                VALUES ($1, $2, $3, $4, $5) RETURNING "id""#,
+            )
+            .bind(link.token())
+            .bind(link.path())
+            .bind(link.signature())
+            .bind(link.expires())
+            .bind(link.created_at())
+            .fetch_one(self)
+            .await?;
+            Ok(LinkInfo::new(
+                id,
+                link.token().to_string(),
+                link.path().to_string(),
+                link.signature().to_string(),
+                link.expires(),
+                link.created_at(),
+            ))
+        }
+
+        async fn delete_link(&mut self, token: &str, id: i64) -> Result<bool> {
+            let result = sqlx::query(r#"DELETE FROM "links" WHERE "id" = $1 AND "token" = $2"#)
+                .bind(id)
+                .bind(token)
+                .execute(self)
+                .await?;
+            Ok(result.rows_affected() > 0)
+        }
+
+        async fn delete_expired_links(&mut self, now: i64) -> Result<u64> {
+            let result = sqlx::query(r#"DELETE FROM "links" WHERE "expires" < $1"#)
+                .bind(now)
+                .execute(self)
+                .await?;
+            Ok(result.rows_affected())
+        }
+
+        async fn insert_upload(&mut self, upload: UploadStatus) -> Result<UploadStatus> {
+            let (id,) = sqlx::query_as::<_, (i64,)>(
+                r#"INSERT INTO "uploads" ("token", "path", "state", "reason", "created_at", "finished_at")
+                VALUES ($1, $2, $3, $4, $5, $6) RETURNING "id""#,
+            )
+            .bind(upload.token())
+            .bind(upload.path())
+            .bind(upload.state().name())
+            .bind(upload.reason())
+            .bind(upload.created_at())
+            .bind(upload.finished_at())
+            .fetch_one(self)
+            .await?;
+            Ok(UploadStatus::new(
+                id as u64,
+                upload.token().to_string(),
+                upload.path().to_string(),
+                upload.created_at(),
+            )
+            .with_state(
+                upload.state(),
+                upload.reason().map(str::to_string),
+                upload.finished_at(),
+            ))
+        }
+
+        async fn update_upload(&mut self, upload: &UploadStatus) -> Result<()> {
+            sqlx::query(
+                r#"UPDATE "uploads" SET "state" = $1, "reason" = $2, "finished_at" = $3
+                WHERE "id" = $4"#,
+            )
+            .bind(upload.state().name())
+            .bind(upload.reason())
+            .bind(upload.finished_at())
+            .bind(upload.id() as i64)
+            .execute(self)
+            .await?;
+            Ok(())
+        }
+
+        async fn query_upload(&mut self, id: u64) -> Result<Option<UploadStatus>> {
+            Ok(sqlx::query_as::<_, UploadRow>(
+                r#"SELECT "id", "token", "path", "state", "reason", "created_at", "finished_at"
+                FROM "uploads" WHERE "id" = $1"#,
+            )
+            .bind(id as i64)
+            .fetch_optional(self)
+            .await?
+            .map(upload_from_row))
+        }
+
+        async fn delete_finished_uploads(&mut self, before: i64) -> Result<u64> {
+            let result = sqlx::query(r#"DELETE FROM "uploads" WHERE "finished_at" < $1"#)
+                .bind(before)
+                .execute(self)
+                .await?;
+            Ok(result.rows_affected())
+        }
+
+        async fn query_integrity_issues(&mut self) -> Result<Vec<IntegrityIssue>> {
+            Ok(sqlx::query_as::<_, (String, String, String, i64)>(
+                r#"SELECT "path", "expected", "actual", "detected_at" FROM "integrity_issues"
+                ORDER BY "path""#,
+            )
+            .fetch_all(self)
+            .await?
+            .into_iter()
+            .map(|(path, expected, actual, detected_at)| {
+                IntegrityIssue::new(path, expected, actual, detected_at)
+            })
+            .collect())
+        }
+
+        async fn insert_integrity_issue(&mut self, issue: IntegrityIssue) -> Result<()> {
+            sqlx::query(
+                r#"INSERT INTO "integrity_issues" VALUES ($1, $2, $3, $4) ON CONFLICT ("path")
+                DO UPDATE SET "expected" = excluded."expected", "actual" = excluded."actual",
+                "detected_at" = excluded."detected_at""#,
+            )
+            .bind(issue.path())
+            .bind(issue.expected())
+            .bind(issue.actual())
+            .bind(issue.detected_at())
+            .execute(self)
+            .await?;
+            Ok(())
+        }
+
+        async fn delete_integrity_issue(&mut self, path: &str) -> Result<()> {
+            sqlx::query(
+                r#"DELETE FROM "integrity_issues" WHERE "path" = $1
+                OR "path" NOT IN (SELECT "path" FROM "files")"#,
+            )
+            .bind(path)
+            .execute(self)
+            .await?;
+            Ok(())
+        }
+
+        async fn query_scrub_batch(&mut self, cursor: &str, limit: i64) -> Result<Vec<FileEntry>> {
+            Ok(sqlx::query_as::<_, FileRow>(&format!(
+                r#"SELECT {} FROM "files" WHERE NOT "is_dir" AND "path" COLLATE "C" > $1
+                ORDER BY "path" COLLATE "C" LIMIT $2"#,
+                FILE_COLUMNS
+            ))
+            .bind(cursor)
+            .bind(limit)
+            .fetch_all(self)
+            .await?
+            .into_iter()
+            .map(entry_from_row)
+            .collect())
+        }
+
+        async fn query_scrub_cursor(&mut self) -> Result<String> {
+            Ok(get_meta(self, SCRUB_CURSOR_KEY).await?.unwrap_or_default())
+        }
+
+        async fn query_errors(&mut self) -> Result<Vec<IndexError>> {
+            Ok(sqlx::query_as::<_, ErrorRow>(
+                r#"SELECT "path", "class", "message", "occurred_at" FROM "errors" ORDER BY "path""#,
+            )
+            .fetch_all(self)
+            .await?
+            .into_iter()
+            .map(error_from_row)
+            .collect())
+        }
+
+        async fn insert_error(&mut self, error: IndexError) -> Result<()> {
+            sqlx::query(
+                r#"INSERT INTO "errors" VALUES ($1, $2, $3, $4) ON CONFLICT ("path")
+                DO UPDATE SET "class" = excluded."class", "message" = excluded."message",
+                "occurred_at" = excluded."occurred_at""#,
+            )
+            .bind(error.path())
+            .bind(error.class().name())
+            .bind(error.message())
+            .bind(error.occurred_at())
+            .execute(self)
+            .await?;
+            Ok(())
+        }
+
+        async fn delete_error(&mut self, path: &str) -> Result<()> {
+            sqlx::query(r#"DELETE FROM "errors" WHERE "path" = $1"#)
+                .bind(path)
+                .execute(self)
+                .await?;
+            Ok(())
+        }
+
+        async fn delete_errors_under(&mut self, path: &str) -> Result<()> {
+            sqlx::query(&format!(
+                r#"DELETE FROM "errors" WHERE {}"#,
+                prefix_condition(path)
+            ))
+            .execute(self)
+            .await?;
+            Ok(())
+        }
+
+        async fn update_scrub_cursor(&mut self, cursor: &str) -> Result<()> {
+            set_meta(self, SCRUB_CURSOR_KEY, cursor.to_string()).await
+        }
+
+        async fn query_cold_files(&mut self, before: i64, limit: i64) -> Result<Vec<FileEntry>> {
+            Ok(sqlx::query_as::<_, FileRow>(&format!(
+                r#"SELECT {} FROM "files" WHERE NOT "is_dir" AND "mtime" < $1
+                AND "path" NOT IN (SELECT "path" FROM "tiered")
+                AND "path" NOT IN (SELECT "path" FROM "access_log" WHERE "accessed_at" >= $1)
+                ORDER BY "mtime" LIMIT $2"#,
+                FILE_COLUMNS
+            ))
+            .bind(before)
+            .bind(limit)
+            .fetch_all(self)
+            .await?
+            .into_iter()
+            .map(entry_from_row)
+            .collect())
+        }
+
+        async fn query_tiered(&mut self) -> Result<Vec<(String, String)>> {
+            sqlx::query_as::<_, (String, String)>(
+                r#"SELECT "path", "location" FROM "tiered" ORDER BY "path""#,
+            )
+            .fetch_all(self)
+            .await
+        }
+
+        async fn count_children(&mut self, path: &str) -> Result<i64> {
+            let (children, descendants) = children_patterns(path);
+            let (count,) = sqlx::query_as::<_, (i64,)>(&format!(
+                r#"SELECT COUNT(*) FROM "files" WHERE "path" LIKE {} AND "path" NOT LIKE {}"#,
+                children, descendants
+            ))
+            .fetch_one(self)
+            .await?;
+            Ok(count)
+        }
+
+        async fn upsert_chunks(
+            &mut self,
+            path: &str,
+            hash: &str,
+            chunk_size: i64,
+            chunks: &[String],
+        ) -> Result<()> {
+            sqlx::query(
+                r#"INSERT INTO "chunks" VALUES ($1, $2, $3, $4) ON CONFLICT ("path") DO UPDATE
+                SET "hash" = excluded."hash", "chunk_size" = excluded."chunk_size",
+                "chunks" = excluded."chunks""#,
+            )
+            .bind(path)
+            .bind(hash)
+            .bind(chunk_size)
+            .bind(serde_json::to_string(chunks).unwrap())
+            .execute(self)
+            .await?;
+            Ok(())
+        }
+
+        async fn query_chunks(&mut self, path: &str) -> Result<Option<(String, i64, Vec<String>)>> {
+            Ok(sqlx::query_as::<_, (String, i64, String)>(
+                r#"SELECT "hash", "chunk_size", "chunks" FROM "chunks" WHERE "path" = $1"#,
+            )
+            .bind(path)
+            .fetch_optional(self)
+            .await?
+            .map(|(hash, chunk_size, chunks)| {
+                (
+                    hash,
+                    chunk_size,
+                    serde_json::from_str(&chunks).unwrap_or_default(),
+                )
+            }))
+        }
+
+        async fn delete_stale_chunks(&mut self) -> Result<u64> {
+            Ok(sqlx::query(
+                r#"DELETE FROM "chunks" WHERE NOT EXISTS (SELECT 1 FROM "files"
+                WHERE "files"."path" = "chunks"."path" AND "files"."hash" = "chunks"."hash")"#,
+            )
+            .execute(self)
+            .await?
+            .rows_affected())
+        }
+
+        async fn insert_tiered(
+            &mut self,
+            path: &str,
+            location: &str,
+            tiered_at: i64,
+        ) -> Result<()> {
+            sqlx::query(
+                r#"INSERT INTO "tiered" VALUES ($1, $2, $3) ON CONFLICT ("path") DO UPDATE
+                SET "location" = excluded."location", "tiered_at" = excluded."tiered_at""#,
+            )
+            .bind(path)
+            .bind(location)
+            .bind(tiered_at)
+            .execute(self)
+            .await?;
+            Ok(())
+        }
+
+        async fn delete_tiered(&mut self, path: &str) -> Result<()> {
+            sqlx::query(r#"DELETE FROM "tiered" WHERE "path" = $1"#)
+                .bind(path)
+                .execute(self)
+                .await?;
+            Ok(())
+        }
+
+        async fn insert_access(&mut self, record: AccessRecord) -> Result<()> {
+            sqlx::query(
+                r#"INSERT INTO "access_log" ("token", "path", "bytes", "duration_ms", "status",
+                "accessed_at") VALUES ($1, $2, $3, $4, $5, $6)"#,
+            )
+            .bind(record.token())
+            .bind(record.path())
+            .bind(record.bytes() as i64)
+            .bind(record.duration_ms() as i64)
+            .bind(record.status() as i32)
+            .bind(record.accessed_at())
+            .execute(self)
+            .await?;
+            Ok(())
+        }
+
+        async fn delete_access_before(&mut self, before: i64) -> Result<u64> {
+            let result = sqlx::query(r#"DELETE FROM "access_log" WHERE "accessed_at" < $1"#)
+                .bind(before)
+                .execute(self)
+                .await?;
+            Ok(result.rows_affected())
+        }
+
+        async fn query_access_stats(&mut self, since: i64, limit: i64) -> Result<AccessStats> {
+            let top_files = sqlx::query_as::<_, (String, i64, i64)>(
+                r#"SELECT "path", COUNT(*), CAST(SUM("bytes") AS BIGINT) FROM "access_log"
+                WHERE "accessed_at" >= $1 AND "status" < 300
+                GROUP BY "path" ORDER BY COUNT(*) DESC, "path" LIMIT $2"#,
+            )
+            .bind(since)
+            .bind(limit)
+            .fetch_all(&mut *self)
+            .await?
+            .into_iter()
+            .map(|(path, downloads, bytes)| FileDownloads::new(path, downloads, bytes))
+            .collect();
+            let tokens = sqlx::query_as::<_, (String, i64, i64, i64)>(
+                r#"SELECT "token", "accessed_at" / 86400 * 86400 AS "day", COUNT(*),
+                CAST(SUM("bytes") AS BIGINT) FROM "access_log" WHERE "accessed_at" >= $1
+                GROUP BY "token", "day" ORDER BY "day", "token""#,
+            )
+            .bind(since)
+            .fetch_all(self)
+            .await?
+            .into_iter()
+            .map(|(token, day, downloads, bytes)| TokenUsage::new(token, day, downloads, bytes))
+            .collect();
+            Ok(AccessStats::new(since, top_files, tokens))
+        }
+
+        async fn begin_batch(&mut self) -> Result<()> {
+            PgTransactionManager::begin(self).await
+        }
+
+        async fn commit_batch(&mut self) -> Result<()> {
+            PgTransactionManager::commit(self).await
+        }
+
+        async fn rollback_batch(&mut self) -> Result<()> {
+            PgTransactionManager::rollback(self).await
+        }
+    }
+}
+
+/// Fallback to in-memory index if SQLite file is read-only
+async fn open_sqlite(path: &str) -> anyhow::Result<(Box<dyn Database>, bool)> {
+    match load_database(path).await {
+        Ok(conn) => Ok((Box::new(conn), path == MEMORY_DATABASE)),
+        Err(e) if is_read_only(&e) => {
+            warn!(
+                "Database {} is read-only ({}), index is kept in memory until scan finished",
+                path, e
+            );
+            Ok((Box::new(load_memory_database().await?), true))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Connect backend selected by `url`, or SQLite file at `path` if `url` is not set
+///
+/// Return `true` if index is in memory, which is empty and should be filled by scan
+pub async fn open_database(
+    path: &str,
+    url: Option<&str>,
+) -> anyhow::Result<(Box<dyn Database>, bool)> {
+    match url {
+        None => open_sqlite(path).await,
+        Some(url) if url.starts_with("sqlite://") => open_sqlite(&url["sqlite://".len()..]).await,
+        #[cfg(feature = "postgres")]
+        Some(url) if url.starts_with("postgres://") || url.starts_with("postgresql://") => {
+            Ok((Box::new(postgres::load_database(url).await?), false))
+        }
+        Some(url) => Err(anyhow::anyhow!(
+            "Database url {:?} is not supported by this build",
+            url
+        )),
+    }
+}
+
+mod migration {
+    use anyhow::anyhow;
+    use sqlx::{Connection, SqliteConnection};
+    use tracing::info;
+
+    /// Key of schema version row in "meta", written by `insert_database_version`
+    const VERSION_KEY: &str = "version";
+
+    /// Scripts upgrade schema from previous version to `version`
+    struct Migration {
+        version: u32,
+        scripts: &'static [&'static str],
+    }
+
+    /// Ordered by version, append new migration to the end and never modify applied one
+    const MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: 2,
+            scripts: &[
+                r#"CREATE INDEX IF NOT EXISTS "files_hash_size" ON "files" ("hash", "size")"#,
+                r#"CREATE INDEX IF NOT EXISTS "files_mtime" ON "files" ("mtime")"#,
+            ],
+        },
+        Migration {
+            version: 3,
+            scripts: &[r#"CREATE TABLE IF NOT EXISTS "holds" (
+                "path"	TEXT NOT NULL,
+                "reason"	TEXT,
+                "created_at"	INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY("path")
+            )"#],
+        },
+        Migration {
+            version: 4,
+            scripts: &[
+                r#"CREATE TABLE IF NOT EXISTS "links" (
+                "id"	INTEGER NOT NULL,
+                "token"	TEXT NOT NULL,
+                "path"	TEXT NOT NULL,
+                "signature"	TEXT NOT NULL,
+                "expires"	INTEGER NOT NULL,
+                "created_at"	INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY("id" AUTOINCREMENT)
+            )"#,
+                r#"CREATE INDEX IF NOT EXISTS "links_expires" ON "links" ("expires")"#,
+            ],
+        },
+        Migration {
+            version: 5,
+            scripts: &[
+                r#"ALTER TABLE "files" ADD COLUMN "children_scanned" INTEGER NOT NULL DEFAULT 0"#,
+            ],
+        },
+        Migration {
+            version: 6,
+            scripts: &[
+                r#"CREATE INDEX IF NOT EXISTS "files_path_nocase" ON "files" ("path" COLLATE NOCASE)"#,
+            ],
+        },
+        Migration {
+            version: 7,
+            scripts: &[r#"CREATE TABLE IF NOT EXISTS "integrity_issues" (
+                "path"	TEXT NOT NULL,
+                "expected"	TEXT NOT NULL,
+                "actual"	TEXT NOT NULL,
+                "detected_at"	INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY("path")
+            )"#],
+        },
+        Migration {
+            version: 8,
+            scripts: &[r#"CREATE TABLE IF NOT EXISTS "errors" (
+                "path"	TEXT NOT NULL,
+                "class"	TEXT NOT NULL,
+                "message"	TEXT NOT NULL,
+                "occurred_at"	INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY("path")
+            )"#],
+        },
+        Migration {
+            version: 9,
+            scripts: &[
+                r#"CREATE TABLE IF NOT EXISTS "access_log" (
+                "id"	INTEGER NOT NULL,
+                "token"	TEXT NOT NULL,
+                "path"	TEXT NOT NULL,
+                "bytes"	INTEGER NOT NULL DEFAULT 0,
+                "duration_ms"	INTEGER NOT NULL DEFAULT 0,
+                "status"	INTEGER NOT NULL,
+                "accessed_at"	INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY("id" AUTOINCREMENT)
+            )"#,
+                r#"CREATE INDEX IF NOT EXISTS "access_log_accessed_at" ON "access_log" ("accessed_at")"#,
+            ],
+        },
+        Migration {
+            version: 10,
+            scripts: &[r#"CREATE TABLE IF NOT EXISTS "tiered" (
+                "path"	TEXT NOT NULL,
+                "location"	TEXT NOT NULL,
+                "tiered_at"	INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY("path")
+            )"#],
+        },
+        Migration {
+            version: 11,
+            scripts: &[r#"CREATE TABLE IF NOT EXISTS "chunks" (
+                "path"	TEXT NOT NULL,
+                "hash"	TEXT NOT NULL,
+                "chunk_size"	INTEGER NOT NULL,
+                "chunks"	TEXT NOT NULL,
+                PRIMARY KEY("path")
+            )"#],
+        },
+        Migration {
+            version: 12,
+            scripts: &[
+                r#"CREATE TABLE IF NOT EXISTS "uploads" (
+                "id"	INTEGER NOT NULL,
+                "token"	TEXT NOT NULL,
+                "path"	TEXT NOT NULL,
+                "state"	TEXT NOT NULL,
+                "reason"	TEXT,
+                "created_at"	INTEGER NOT NULL DEFAULT 0,
+                "finished_at"	INTEGER,
+                PRIMARY KEY("id" AUTOINCREMENT)
+            )"#,
+                r#"CREATE INDEX IF NOT EXISTS "uploads_finished_at" ON "uploads" ("finished_at")"#,
+            ],
+        },
+    ];
+
+    pub fn latest_version() -> u32 {
+        MIGRATIONS
+            .last()
+            .map(|migration| migration.version)
+            .unwrap_or(1)
+    }
+
+    async fn query_version(conn: &mut SqliteConnection) -> anyhow::Result<u32> {
+        let (version,) =
+            sqlx::query_as::<_, (String,)>(r#"SELECT "value" FROM "meta" WHERE "key" = ?"#)
+                .bind(VERSION_KEY)
+                .fetch_optional(conn)
+                .await?
+                .ok_or_else(|| anyhow!("Database version is missing"))?;
+        version
+            .parse()
+            .map_err(|e| anyhow!("Unable to parse database version {:?}: {:?}", version, e))
+    }
+
+    /// Copy database to `<path>.v<version>.bak`, previous backup of same version is replaced
+    async fn backup(conn: &mut SqliteConnection, path: &str, version: u32) -> anyhow::Result<()> {
+        let backup = format!("{}.v{}.bak", path, version);
+        match tokio::fs::remove_file(&backup).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(anyhow!("Unable to remove old backup {:?}: {:?}", backup, e))
+            }
+            _ => {}
+        }
+        sqlx::query("VACUUM INTO ?")
+            .bind(&backup)
+            .execute(conn)
+            .await
+            .map_err(|e| anyhow!("Unable to backup database to {:?}: {:?}", backup, e))?;
+        info!("Database backup to {}", backup);
+        Ok(())
+    }
+
+    /// Apply migrations newer than stored version, each in its own transaction
+    ///
+    /// Database is backed up before upgrading unless it is just created
+    pub async fn run(conn: &mut SqliteConnection, path: &str, created: bool) -> anyhow::Result<()> {
+        let version = query_version(conn).await?;
+        let latest = latest_version();
+        if version > latest {
+            return Err(anyhow!(
+                "Database version {} is newer than supported version {}",
+                version,
+                latest
+            ));
+        }
+        let pending = MIGRATIONS
+            .iter()
+            .filter(|migration| migration.version > version)
+            .collect::<Vec<_>>();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        if !created {
+            backup(conn, path, version).await?;
+        }
+        for migration in pending {
+            let mut transaction = conn.begin().await?;
+            for script in migration.scripts {
+                sqlx::query(script).execute(&mut *transaction).await?;
+            }
+            sqlx::query(r#"UPDATE "meta" SET "value" = ? WHERE "key" = ?"#)
+                .bind(migration.version.to_string())
+                .bind(VERSION_KEY)
+                .execute(&mut *transaction)
+                .await?;
+            transaction.commit().await?;
+            info!("Database migrated to version {}", migration.version);
+        }
+        Ok(())
+    }
+}
+
+/// SQLite result codes mean database can't be written
+const SQLITE_PERM: i32 = 3;
+const SQLITE_READONLY: i32 = 8;
+const SQLITE_CANTOPEN: i32 = 14;
+/// `EROFS` on Linux
+const READ_ONLY_FILESYSTEM: i32 = 30;
+
+/// Database file or its directory is not writable, e.g. on read-only snapshot mount
+fn is_read_only(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Database(e)) => e
+            .code()
+            .and_then(|code| code.parse::<i32>().ok())
+            // Extended result code keeps primary code in lower 8 bits
+            .is_some_and(|code| {
+                matches!(code & 0xff, SQLITE_PERM | SQLITE_READONLY | SQLITE_CANTOPEN)
+            }),
+        Some(sqlx::Error::Io(e)) => {
+            e.kind() == std::io::ErrorKind::PermissionDenied
+                || e.raw_os_error() == Some(READ_ONLY_FILESYSTEM)
+        }
+        _ => false,
+    }
+}
+
+/// SQLite opens file as read-only silently if it can't be written, so take write lock once
+async fn check_writable(conn: &mut sqlx::SqliteConnection) -> sqlx::Result<()> {
+    sqlx::query("BEGIN IMMEDIATE").execute(&mut *conn).await?;
+    sqlx::query("ROLLBACK").execute(conn).await?;
+    Ok(())
+}
+
+/// Database path keeps index in memory only, it is built by scan on every startup
+pub const MEMORY_DATABASE: &str = ":memory:";
+
+pub async fn load_database(path: &str) -> anyhow::Result<sqlx::SqliteConnection> {
+    if path == MEMORY_DATABASE {
+        return load_memory_database().await;
+    }
+    let mut conn = SqliteConnectOptions::new()
+        .create_if_missing(true)
+        .filename(path)
+        .busy_timeout(BusyPolicy::global().timeout())
+        .collation(current::NATURAL_COLLATION, publib::natural_cmp)
+        .connect()
+        .await?;
+    check_writable(&mut conn).await?;
+    prepare_database(conn, path).await
+}
+
+/// Index is lost on exit, should be filled by scan
+async fn load_memory_database() -> anyhow::Result<sqlx::SqliteConnection> {
+    let conn = SqliteConnectOptions::from_str("sqlite::memory:")?
+        .collation(current::NATURAL_COLLATION, publib::natural_cmp)
+        .connect()
+        .await?;
+    prepare_database(conn, MEMORY_DATABASE).await
+}
+
+async fn prepare_database(
+    mut conn: sqlx::SqliteConnection,
+    path: &str,
+) -> anyhow::Result<sqlx::SqliteConnection> {
+    let created = !check_database(&mut conn, "meta").await?;
+    if created {
+        sqlx::query(current::CREATE_TABLE)
+            .execute(&mut conn)
+            .await?;
+        insert_database_version(&mut conn, "meta", VERSION).await?;
+    }
+    current::migrate(&mut conn).await?;
+    migration::run(&mut conn, path, created).await?;
+    Ok(conn)
+}
+
+pub use backend::Database;
+pub use busy::BusyPolicy;
+use kstool::sqlx::{check_database, insert_database_version};
+use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::ConnectOptions;
+use std::str::FromStr;
+use tracing::warn;
+pub use v1 as current;
+pub use v1::VERSION;
+
+#[cfg(test)]
+mod test {
+    use super::busy::{is_busy, BusyPolicy, MAX_RETRY_DELAY, RETRY_BUDGET};
+    use super::current;
+    use super::load_memory_database;
+    use publib::types::{
+        AccessRecord, FileEntry, ListOption, Pagination, UploadState, UploadStatus,
+    };
+    use sqlx::sqlite::SqliteConnectOptions;
+    use sqlx::{ConnectOptions, Connection, SqliteConnection};
+    use std::time::{Duration, Instant};
+
+    /// `./a/b/c.txt`, `./a/d.txt` and `./e.txt`, `./a` and `./a/b` are directories
+    async fn prepare_tree() -> SqliteConnection {
+        let mut conn = load_memory_database().await.unwrap();
+        for (path, is_dir) in [
+            ("./a", true),
+            ("./a/b", true),
+            ("./a/b/c.txt", false),
+            ("./a/d.txt", false),
+            ("./e.txt", false),
+        ] {
+            current::insert(
+                &mut conn,
+                &FileEntry::new(path.to_string(), "xxh3:0", 1, 10, is_dir),
+            )
+            .await
+            .unwrap();
+        }
+        conn
+    }
+
+    async fn exists(conn: &mut SqliteConnection, path: &str) -> bool {
+        current::query(conn, path).await.unwrap().is_some()
+    }
+
+    async fn root_files(conn: &mut SqliteConnection) -> i64 {
+        current::query_stats(conn, &[".".to_string()])
+            .await
+            .unwrap()[0]
+            .files()
+    }
+
+    async fn list(
+        conn: &mut SqliteConnection,
+        prefix: &str,
+        page: Option<Pagination>,
+    ) -> Vec<String> {
+        current::query_by_prefixes(
+            conn,
+            &[prefix.to_string()],
+            &ListOption::default(),
+            page.as_ref(),
+        )
+        .await
+        .unwrap()
+        .iter()
+        .map(|entry| entry.path().to_string())
+        .collect()
+    }
+
+    #[tokio::test]
+    async fn list_by_prefix_is_paged_in_sql() {
+        let mut conn = prepare_tree().await;
+        assert_eq!(
+            list(&mut conn, "./a", None).await,
+            ["./a", "./a/b", "./a/b/c.txt", "./a/d.txt"]
+        );
+        assert_eq!(
+            list(&mut conn, "./a", Some(Pagination::new(Some(2), Some(1)))).await,
+            ["./a/b", "./a/b/c.txt"]
+        );
+        assert!(list(&mut conn, "./a", Some(Pagination::new(None, Some(4))))
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn empty_prefixes_match_nothing() {
+        let mut conn = prepare_tree().await;
+        assert!(current::prefixes_condition(&[]).is_none());
+        assert!(current::query_largest(&mut conn, &[], 10)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(current::query_by_hash(&mut conn, &[], "xxh3:0")
+            .await
+            .unwrap()
+            .is_empty());
+        let report = current::query_duplicates(&mut conn, Some(&[]), &Pagination::default())
+            .await
+            .unwrap();
+        assert_eq!(report.total_groups(), 0);
+        // Not scoped, duplicates of all files
+        let report = current::query_duplicates(&mut conn, None, &Pagination::default())
+            .await
+            .unwrap();
+        assert_eq!(report.total_groups(), 1);
+    }
+
+    #[tokio::test]
+    async fn list_prefix_matches_wildcard_literally() {
+        let mut conn = prepare_tree().await;
+        for path in [
+            "./a_b/x.txt",
+            "./aXb/y.txt",
+            "./100%/z.txt",
+            "./100 percent/z.txt",
+        ] {
+            current::insert(
+                &mut conn,
+                &FileEntry::new(path.to_string(), "xxh3:0", 1, 10, false),
+            )
+            .await
+            .unwrap();
+        }
+        assert_eq!(list(&mut conn, "./a_b", None).await, ["./a_b/x.txt"]);
+        assert_eq!(list(&mut conn, "./100%", None).await, ["./100%/z.txt"]);
+    }
+
+    #[tokio::test]
+    async fn delete_directory_removes_children() {
+        let mut conn = prepare_tree().await;
+        // Only directory event is received, path does not exist on disk
+        current::delete(&mut conn, "./a").await.unwrap();
+        for path in ["./a", "./a/b", "./a/b/c.txt", "./a/d.txt"] {
+            assert!(!exists(&mut conn, path).await, "{} is left behind", path);
+        }
+        assert!(exists(&mut conn, "./e.txt").await);
+        assert_eq!(root_files(&mut conn).await, 1);
+    }
+
+    #[tokio::test]
+    async fn delete_tree_bottom_up() {
+        let mut conn = prepare_tree().await;
+        // Order of events emitted by `rm -rf ./a`
+        for path in ["./a/b/c.txt", "./a/b", "./a/d.txt", "./a"] {
+            current::delete(&mut conn, path).await.unwrap();
+        }
+        for path in ["./a", "./a/b", "./a/b/c.txt", "./a/d.txt"] {
+            assert!(!exists(&mut conn, path).await, "{} is left behind", path);
+        }
+        assert_eq!(root_files(&mut conn).await, 1);
+    }
+
+    #[tokio::test]
+    async fn delete_file_keeps_sibling_prefix() {
+        let mut conn = prepare_tree().await;
+        current::insert(
+            &mut conn,
+            &FileEntry::new("./a/d.txt.bak".to_string(), "xxh3:0", 1, 10, false),
+        )
+        .await
+        .unwrap();
+        current::delete(&mut conn, "./a/d.txt").await.unwrap();
+        current::delete(&mut conn, "./missing").await.unwrap();
+        assert!(exists(&mut conn, "./a/d.txt.bak").await);
+        assert!(exists(&mut conn, "./a/b/c.txt").await);
+        assert_eq!(root_files(&mut conn).await, 3);
+    }
+
+    #[tokio::test]
+    async fn count_children_of_directory() {
+        let mut conn = prepare_tree().await;
+        for path in ["./a_b/x.txt", "./aXb/y.txt", "./aXb/z.txt"] {
+            current::insert(
+                &mut conn,
+                &FileEntry::new(path.to_string(), "xxh3:0", 1, 10, false),
+            )
+            .await
+            .unwrap();
+        }
+        // Deeper descendants and siblings sharing prefix are not counted
+        assert_eq!(current::count_children(&mut conn, "./a").await.unwrap(), 2);
+        assert_eq!(current::count_children(&mut conn, "./a/").await.unwrap(), 2);
+        assert_eq!(
+            current::count_children(&mut conn, "./a/b").await.unwrap(),
+            1
+        );
+        assert_eq!(
+            current::count_children(&mut conn, "./a_b").await.unwrap(),
+            1
+        );
+        assert_eq!(
+            current::count_children(&mut conn, "./e.txt").await.unwrap(),
+            0
+        );
+        assert_eq!(current::count_children(&mut conn, ".").await.unwrap(), 2);
+        let entry = current::query(&mut conn, "./a/b").await.unwrap().unwrap();
+        assert!(entry.is_dir());
+    }
+
+    #[tokio::test]
+    async fn rename_moves_children_only() {
+        let mut conn = prepare_tree().await;
+        for path in ["./a_b/x.txt", "./aXb/y.txt", "./a_b.txt"] {
+            current::insert(
+                &mut conn,
+                &FileEntry::new(path.to_string(), "xxh3:0", 1, 10, false),
+            )
+            .await
+            .unwrap();
+        }
+        assert!(current::rename(&mut conn, "./a", "./f").await.unwrap());
+        assert!(!current::rename(&mut conn, "./a_b", "./g").await.unwrap());
+        for path in [
+            "./f/b/c.txt",
+            "./f/d.txt",
+            "./g/x.txt",
+            "./aXb/y.txt",
+            "./a_b.txt",
+        ] {
+            assert!(exists(&mut conn, path).await, "{} is missing", path);
+        }
+        for path in ["./a", "./a/b/c.txt", "./a_b/x.txt"] {
+            assert!(!exists(&mut conn, path).await, "{} is left behind", path);
+        }
+    }
+
+    /// First path matched by `glob` after `cursor`
+    async fn search(conn: &mut SqliteConnection, glob: &str, cursor: &str) -> Vec<String> {
+        current::search(conn, &[".".to_string()], glob, cursor, 1)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.path().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn search_glob_and_cursor() {
+        let mut conn = prepare_tree().await;
+        assert_eq!(search(&mut conn, "*.TXT", "").await, ["./a/b/c.txt"]);
+        assert_eq!(
+            search(&mut conn, "*.txt", "./a/b/c.txt").await,
+            ["./a/d.txt"]
+        );
+        assert_eq!(search(&mut conn, "b/", "").await, ["./a/b/c.txt"]);
+        assert!(search(&mut conn, "_.txt", "").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn query_by_hash_under_prefix() {
+        let mut conn = prepare_tree().await;
+        let paths = current::query_by_hash(&mut conn, &["./a".to_string()], "xxh3:0")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.path().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["./a/b/c.txt", "./a/d.txt"]);
+        assert!(
+            current::query_by_hash(&mut conn, &[".".to_string()], "xxh3:1")
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn access_stats_aggregate() {
+        let mut conn = load_memory_database().await.unwrap();
+        for (token, path, bytes, status, accessed_at) in [
+            ("ab***", "./a.txt", 10, 200, 86400),
+            ("ab***", "./a.txt", 4, 200, 86401),
+            ("cd***", "./b.txt", 20, 200, 2 * 86400),
+            ("cd***", "./b.txt", 0, 404, 2 * 86400),
+            ("cd***", "./c.txt", 30, 200, 0),
+        ] {
+            let record = AccessRecord::new(
+                token.to_string(),
+                path.to_string(),
+                bytes,
+                1,
+                status,
+                accessed_at,
+            );
+            current::insert_access(&mut conn, &record).await.unwrap();
+        }
+        let stats = current::query_access_stats(&mut conn, 86400, 10)
+            .await
+            .unwrap();
+        let top = stats
+            .top_files()
+            .iter()
+            .map(|file| (file.path(), file.downloads(), file.bytes()))
+            .collect::<Vec<_>>();
+        assert_eq!(top, [("./a.txt", 2, 14), ("./b.txt", 1, 20)]);
+        let tokens = stats
+            .tokens()
+            .iter()
+            .map(|usage| (usage.token(), usage.day(), usage.downloads(), usage.bytes()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [("ab***", 86400, 2, 14), ("cd***", 2 * 86400, 2, 20)]
+        );
+
+        assert_eq!(
+            current::delete_access_before(&mut conn, 86400)
+                .await
+                .unwrap(),
+            1
+        );
+        let stats = current::query_access_stats(&mut conn, 0, 10).await.unwrap();
+        assert_eq!(stats.top_files().len(), 2);
+        assert_eq!(
+            current::delete_access_before(&mut conn, 86400)
+                .await
+                .unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn upload_status_is_persisted() {
+        let mut conn = load_memory_database().await.unwrap();
+        let pending = UploadStatus::new(0, "token".to_string(), "./a.txt".to_string(), 10);
+        let first = current::insert_upload(&mut conn, &pending).await.unwrap();
+        let second = current::insert_upload(&mut conn, &pending).await.unwrap();
+        assert_ne!(first.id(), second.id());
+
+        let mut rejected = second.clone();
+        rejected.reject(20, "Unable to index file".to_string());
+        current::update_upload(&mut conn, &rejected).await.unwrap();
+        let loaded = current::query_upload(&mut conn, second.id())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.token(), "token");
+        assert_eq!(loaded.path(), "./a.txt");
+        assert_eq!(loaded.state(), UploadState::Rejected);
+        assert_eq!(loaded.reason(), Some("Unable to index file"));
+        assert_eq!(loaded.created_at(), 10);
+        assert_eq!(loaded.finished_at(), Some(20));
+        assert!(current::query_upload(&mut conn, second.id() + 1)
+            .await
+            .unwrap()
+            .is_none());
+
+        // Pending upload is kept however old it is
+        assert_eq!(
+            current::delete_finished_uploads(&mut conn, 30)
+                .await
+                .unwrap(),
+            1
+        );
+        let loaded = current::query_upload(&mut conn, first.id())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.state(), UploadState::Pending);
+        assert!(current::query_upload(&mut conn, second.id())
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn busy_error_is_detected() {
+        let path = format!("./target/database-test-busy-{}.db", std::process::id());
+        let options = SqliteConnectOptions::new()
+            .filename(&path)
+            .create_if_missing(true)
+            .busy_timeout(Duration::ZERO);
+        let mut writer = options.connect().await.unwrap();
+        sqlx::query("CREATE TABLE t (v INTEGER)")
+            .execute(&mut writer)
+            .await
+            .unwrap();
+        let mut other = options.connect().await.unwrap();
+        sqlx::query("BEGIN IMMEDIATE")
+            .execute(&mut writer)
+            .await
+            .unwrap();
+        let e = sqlx::query("INSERT INTO t VALUES (1)")
+            .execute(&mut other)
+            .await
+            .unwrap_err();
+        assert!(is_busy(&e), "{:?}", e);
+        let e = sqlx::query("SELECT * FROM missing")
+            .execute(&mut other)
+            .await
+            .unwrap_err();
+        assert!(!is_busy(&e));
+        assert!(!is_busy(&sqlx::Error::RowNotFound));
+
+        writer.close().await.unwrap();
+        other.close().await.unwrap();
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn backoff_is_bounded() {
+        let policy = BusyPolicy::new(Duration::ZERO, 3, Duration::from_millis(10));
+        for attempt in 1..=3 {
+            let base = Duration::from_millis(10 << (attempt - 1));
+            let delay = policy.delay(attempt);
+            assert!(delay >= base && delay <= base * 2, "{:?}", delay);
+        }
+        // Long configured delay is capped
+        let slow = BusyPolicy::new(Duration::ZERO, 30, Duration::from_secs(10));
+        for attempt in [1, 10, 30] {
+            assert!(slow.delay(attempt) <= MAX_RETRY_DELAY);
+        }
+
+        let started = Instant::now();
+        assert!(policy.backoff(1, started).await);
+        assert!(!policy.backoff(4, started).await);
+        // Retry which would end after budget is given up
+        assert!(!slow.backoff(1, started - RETRY_BUDGET).await);
+        assert!(
+            !slow
+                .backoff(1, started - (RETRY_BUDGET - MAX_RETRY_DELAY / 4))
+                .await
+        );
+        let stats = serde_json::to_value(policy.stats()).unwrap();
+        assert_eq!(stats["retried"], 1);
+    }
+}
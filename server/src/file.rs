@@ -1,368 +1,5451 @@
 mod files {
-    use super::FileEventHelper;
-    use crate::configure::current::Configure;
-    use crate::configure::RwPoolType;
-    use crate::database::current::{
-        delete, delete_all_unmarked, insert, mark, query, query_path, reset_all_mark, update,
+    use super::{
+        EventLog, FileEventHelper, Holds, Jobs, Links, ScanProgress, Tiered, UploadSessions,
     };
-    use crate::file::types::FileEvent;
+    use crate::configure::current::AuthEntry;
+    use crate::configure::current::{
+        CaseSensitivity, Configure, PathLimits, ScrubOption, TieringOption,
+    };
+    use crate::configure::PoolType;
+    use crate::configure::{ExcludeSet, RwPoolType};
+    use crate::database::Database;
+    use crate::file::jobs::unix_now;
+    use crate::file::types::{FileEvent, ReportKind};
+    use crate::git::head_commit;
+    use crate::mount::Mounts;
     use anyhow::anyhow;
-    use async_walkdir::WalkDir;
-    use futures::StreamExt;
-    use log::{error, info, warn};
-    use publib::file::get_hash;
-    use publib::types::{FileEntry, OptionFile};
-    use publib::PATH_UTF8_ERROR;
-    use sqlx::SqliteConnection;
-    use std::path::Path;
+    use async_walkdir::{Filtering, WalkDir};
+    use futures::future::BoxFuture;
+    use futures::{FutureExt, StreamExt};
+    use publib::e2e::is_e2e_encrypted;
+    use publib::file::{get_file_chunks, get_hash, HashAlgo, HashOption};
+    use publib::types::{
+        ChangeEvent, ErrorClass, FileChunks, FileEntry, IndexError, IntegrityIssue, OptionFile,
+        UploadStatus,
+    };
+    use publib::{normalize_path, PATH_UTF8_ERROR};
+    use std::collections::{HashMap, VecDeque};
+    use std::panic::AssertUnwindSafe;
+    use std::path::{Path, PathBuf};
     use std::sync::Arc;
-    use tokio::sync::mpsc;
-    use tokio::task::JoinHandle;
+    use std::time::Duration;
+    use tokio::sync::{broadcast, mpsc, oneshot};
+    use tokio::task::{JoinError, JoinSet};
+    use tokio::time::Instant;
+    use tracing::{debug, error, info, warn};
 
-    pub async fn init_files(conn: &mut SqliteConnection, path: &str) -> anyhow::Result<()> {
-        reset_all_mark(conn).await?;
-        let mut entries = WalkDir::new(path);
-        while let Some(Ok(entry)) = entries.next().await {
-            process_file(conn, entry).await?;
-        }
-        delete_all_unmarked(conn).await?;
-        Ok(())
+    /// Expired links and upload sessions are pruned in this interval
+    const LINK_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+    /// Upload session without chunk for this many seconds is dropped with its temporary file
+    const UPLOAD_SESSION_EXPIRE: i64 = 86400;
+
+    /// Status of finished upload is kept for this many seconds
+    const UPLOAD_STATUS_EXPIRE: i64 = 7 * 86400;
+
+    /// Walked file waiting for its hash, row is written once hash worker finished
+    pub(super) struct Pending {
+        path: String,
+        fs_path: PathBuf,
+        metadata: std::fs::Metadata,
+        /// `None` if file is not indexed yet
+        stored: Option<FileEntry>,
     }
 
-    async fn process_file(
-        conn: &mut SqliteConnection,
-        entry: async_walkdir::DirEntry,
-    ) -> anyhow::Result<()> {
-        match query_path(conn, entry.path()).await? {
-            None => {
-                let hash = get_hash(entry.path()).await?.map(|x| format!("{}", x));
-                insert(conn, FileEntry::try_from_entry(entry, hash).await?).await?;
-            }
-            Some(sql_entry) => {
-                let entry = FileEntry::try_from_entry::<String>(entry, None).await?;
-                if sql_entry == entry {
-                    mark(conn, entry).await?;
-                    return Ok(());
-                }
-                // mtime || size not match
-                let hash = get_hash(entry.path()).await?;
-                let entry = entry.override_hash(hash);
-                // maybe mtime change but hash same
-                if sql_entry.check_hash_only(&entry) {
-                    info!("{} changed but hash is same", entry.path());
-                } else {
-                    info!("{} updated", entry.path());
-                }
-                update(conn, entry).await?;
+    impl Pending {
+        #[cfg(test)]
+        pub(super) fn new(path: &str, stored: Option<FileEntry>) -> Self {
+            let fs_path = Mounts::global().resolve(path);
+            Self {
+                path: path.to_string(),
+                metadata: std::fs::metadata(&fs_path).unwrap(),
+                fs_path,
+                stored,
             }
         }
-
-        Ok(())
     }
 
-    #[derive(Debug)]
-    pub struct FileDaemon {
-        handler: JoinHandle<anyhow::Result<()>>,
+    /// Hash, chunk hashes and whether file is end-to-end encrypted
+    pub(super) type HashResult = (Option<String>, Vec<String>, bool);
+
+    /// Pending file with its hash result
+    type Hashed = (Pending, std::io::Result<HashResult>);
+
+    enum ScanItem {
+        Entry(async_walkdir::DirEntry),
+        Hashed(Result<Box<Hashed>, JoinError>),
+        /// Walk is finished and no file is being hashed
+        Finished,
     }
 
-    impl FileDaemon {
-        async fn event_handler(
-            conn: &mut SqliteConnection,
-            event: FileEvent,
-        ) -> anyhow::Result<()> {
-            match event {
-                FileEvent::New(ref paths) | FileEvent::Update(ref paths) => {
-                    let event_type = if let FileEvent::New(_) = event {
-                        "new"
-                    } else {
-                        "update"
-                    };
-                    for path in paths {
-                        let path: &Path = path.as_ref();
-                        let hash = get_hash(path)
-                            .await
-                            .map_err(|e| anyhow!("Get file hash error({}): {:?}", event_type, e))?;
+    /// Walk of working directory, advanced by file daemon one entry at a time between
+    /// other events, so index can be queried while scan is running
+    ///
+    /// Index is not consistent until scan finished, entries not walked are removed at last
+    struct Scan {
+        /// Working directory and each mount are walked one after another
+        entries: Vec<WalkDir>,
+        /// Rows outside of root are not touched, `.` is whole working directory
+        root: String,
+        rehash: bool,
+        job: u64,
+        /// Record git commit of working directory once finished
+        git: bool,
+        /// Files are hashed in parallel, rows are still written by file daemon in order of
+        /// hash finished
+        hashing: JoinSet<Hashed>,
+        workers: usize,
+    }
 
-                        insert(
-                            conn,
-                            FileEntry::try_from_path(path, hash).map_err(|e| {
-                                anyhow!("Unable read metadata({}): {:?}", event_type, e)
-                            })?,
-                        )
-                        .await
-                        .map_err(|e| anyhow!("Unable insert file({}): {:?}", event_type, e))?;
-                    }
+    impl Scan {
+        async fn start(
+            conn: &mut dyn Database,
+            root: String,
+            option: &HashOption,
+            job: u64,
+            git: bool,
+            limits: PathLimits,
+            workers: usize,
+        ) -> anyhow::Result<Self> {
+            let rehash = !conn.check_hash_version(option.algo()).await?;
+            // Stored hashes outside of root are stale as well
+            let root = if rehash { ".".to_string() } else { root };
+            conn.delete_errors_under(&root).await?;
+            if rehash {
+                info!("Hash version changed, recompute hash of all files");
+            }
+            let mounts = Mounts::global();
+            let mut entries = Vec::new();
+            if root == "." {
+                conn.reset_all_mark().await?;
+                for (path, mount) in mounts.roots() {
+                    Self::mark_root(conn, &path, mount).await?;
+                    entries.push(Self::walk(mount, limits));
+                }
+                entries.push(Self::walk(".", limits));
+            } else {
+                conn.reset_mark_under(&root).await?;
+                for (path, mount) in mounts.roots_under(&root) {
+                    Self::mark_root(conn, &path, mount).await?;
+                    entries.push(Self::walk(mount, limits));
                 }
+                let path = mounts.resolve(&root);
+                Self::mark_root(conn, &root, &path).await?;
+                entries.push(Self::walk(path, limits));
+            }
+            Ok(Self {
+                entries,
+                root,
+                rehash,
+                job,
+                git,
+                hashing: JoinSet::new(),
+                workers: workers.max(1),
+            })
+        }
 
-                FileEvent::Remove(paths) => {
-                    for path in paths {
-                        let path: &Path = path.as_ref();
-                        delete(conn, path.to_str().expect(PATH_UTF8_ERROR).to_string())
-                            .await
-                            .map_err(|e| anyhow!("Unable delete path {:?}: {:?}", path, e))?;
+        /// Entry beyond path limits is skipped with its children
+        fn walk<P: AsRef<Path>>(path: P, limits: PathLimits) -> WalkDir {
+            WalkDir::new(path).filter(move |entry| async move {
+                let path = Mounts::global().to_index(entry.path());
+                match limits.check(&path) {
+                    Ok(()) => Filtering::Continue,
+                    Err(reason) => {
+                        warn!("Skip {}: {}", path, reason);
+                        Filtering::IgnoreDir
                     }
                 }
-                _ => unreachable!(),
-            }
-            Ok(())
+            })
         }
 
-        async fn handler(
-            mut conn: SqliteConnection,
-            mut receiver: mpsc::Receiver<FileEvent>,
-            user_pool: Arc<RwPoolType>,
+        /// Walk yields entries under root only, keep row of root directory itself
+        async fn mark_root(
+            conn: &mut dyn Database,
+            path: &str,
+            fs_path: &Path,
         ) -> anyhow::Result<()> {
-            while let Some(event) = receiver.recv().await {
-                match event {
-                    FileEvent::New(_) | FileEvent::Update(_) | FileEvent::Remove(_) => {
-                        Self::event_handler(&mut conn, event)
-                            .await
-                            .inspect_err(|e| error!("{}", e))
-                            .ok();
-                    }
-                    FileEvent::Terminate => break,
-                    FileEvent::Unknown => {
-                        unreachable!()
-                    }
-                    FileEvent::Request(paths, sender) => {
-                        let mut v = Vec::new();
-                        for path in paths {
-                            let q = query(&mut conn, &path)
-                                .await
-                                .inspect_err(|e| error!("Query file error: {:?}", e))?;
-                            v.push(OptionFile::from_option_entry(path, q));
-                        }
-                        sender
-                            .send(v)
-                            .inspect_err(|_| error!("Unable to send query result to client"))
-                            .ok();
+            match tokio::fs::metadata(fs_path).await {
+                Ok(metadata) if metadata.is_dir() => Ok(conn
+                    .upsert(FileEntry::from_metadata(path, metadata, None::<String>))
+                    .await?),
+                _ => Ok(()),
+            }
+        }
+
+        /// Walk is paused while all hash workers are busy, stop walking directory at first
+        /// error as before
+        async fn next(scan: &mut Option<Self>) -> ScanItem {
+            let Some(scan) = scan else {
+                return std::future::pending().await;
+            };
+            if scan.hashing.len() < scan.workers {
+                while let Some(entries) = scan.entries.last_mut() {
+                    tokio::select! {
+                        biased;
+                        Some(hashed) = scan.hashing.join_next() => return ScanItem::Hashed(hashed.map(Box::new)),
+                        entry = entries.next() => match entry {
+                            Some(Ok(entry)) => return ScanItem::Entry(entry),
+                            _ => {
+                                scan.entries.pop();
+                            }
+                        },
                     }
-                    FileEvent::ConfigureUpdated(path) => match Configure::load(path).await {
-                        Ok(config) => {
-                            let mut pool = user_pool.write().await;
-                            *pool = config.build_hashmap();
-                            info!("User pool update, current size: {}", pool.len());
-                        }
-                        Err(e) => {
-                            warn!("Unable to reload configure file: {:?}", e);
-                        }
-                    },
                 }
             }
-            Ok(())
+            match scan.hashing.join_next().await {
+                Some(hashed) => ScanItem::Hashed(hashed.map(Box::new)),
+                None => ScanItem::Finished,
+            }
         }
 
-        pub fn start(
-            conn: SqliteConnection,
-            user_pool: Arc<RwPoolType>,
-        ) -> (Self, FileEventHelper) {
-            let (helper, receiver) = FileEventHelper::new();
-            let handler = tokio::spawn(Self::handler(conn, receiver, user_pool));
-            (Self { handler }, helper)
+        /// Panic of worker is returned as error of file, so its row is kept like unreadable
+        /// file
+        fn hash(&mut self, pending: Pending, option: HashOption) {
+            self.hashing.spawn(async move {
+                let result = AssertUnwindSafe(async {
+                    let (hash, chunks) = hash_file(&pending.fs_path, &option).await?;
+                    let encrypted = is_e2e_encrypted(&pending.fs_path).await?;
+                    Ok::<_, std::io::Error>((hash, chunks, encrypted))
+                })
+                .catch_unwind()
+                .await
+                .unwrap_or_else(|_| Err(std::io::Error::other("Hash worker panicked")));
+                (pending, result)
+            });
         }
 
-        pub fn into_inner(self) -> JoinHandle<anyhow::Result<()>> {
-            self.handler
+        /// Fields are passed instead of `&self`, walk stream is not `Sync`, so future holding
+        /// reference of scan is not `Send`
+        async fn finish(
+            root: &str,
+            rehash: bool,
+            git: bool,
+            conn: &mut dyn Database,
+            option: &HashOption,
+            progress: &ScanProgress,
+        ) -> anyhow::Result<()> {
+            conn.mark_children_scanned(root).await?;
+            // Tiered files are not walked, keep their rows
+            for (path, _) in conn.query_tiered().await? {
+                if super::transfers::is_under(&path, root) {
+                    FileDaemon::keep_row(conn, &path).await?;
+                }
+            }
+            if root != "." {
+                return Ok(conn.delete_unmarked_under(root).await?);
+            }
+            conn.delete_all_unmarked().await?;
+            conn.delete_stale_chunks().await?;
+            if rehash {
+                conn.update_hash_version(option.algo()).await?;
+            }
+            if git {
+                let commit = head_commit(Path::new("."))
+                    .await
+                    .map_err(|e| anyhow!("Unable to read git commit: {:?}", e))?;
+                conn.update_git_commit(commit.as_deref()).await?;
+                progress.set_commit(commit);
+            }
+            Ok(())
         }
     }
-}
 
-mod types {
-    use notify::{Event, EventKind};
-    use publib::types::OptionFile;
-    use publib::PATH_UTF8_ERROR;
-    use std::path::PathBuf;
-    use tokio::sync::{mpsc, oneshot};
+    /// Remove rows differ from `path` only in case, file system keeps one of them
+    pub(super) async fn fold_case(
+        conn: &mut dyn Database,
+        path: &str,
+        case: CaseSensitivity,
+    ) -> anyhow::Result<()> {
+        fold_case_except(conn, path, path, case).await
+    }
 
-    pub(super) enum FileEvent {
-        New(Vec<String>),
-        Update(Vec<String>),
-        Remove(Vec<String>),
-        ConfigureUpdated(String),
-        /// Request files (from https)
-        Request(Vec<String>, oneshot::Sender<Vec<OptionFile>>),
-        Terminate,
-        Unknown,
+    /// Like `fold_case`, but row of `except` is kept as well, e.g. source of rename which
+    /// only changes case
+    pub(super) async fn fold_case_except(
+        conn: &mut dyn Database,
+        path: &str,
+        except: &str,
+        case: CaseSensitivity,
+    ) -> anyhow::Result<()> {
+        if !case.is_insensitive() {
+            return Ok(());
+        }
+        for variant in conn.query_case_variants(path).await? {
+            if variant != path && variant != except {
+                info!("{} is replaced by {}", variant, path);
+                conn.delete(variant).await?;
+            }
+        }
+        Ok(())
     }
 
-    fn convert(paths: Vec<PathBuf>) -> Option<Vec<String>> {
-        paths
-            .iter()
-            .map(|path| path.to_str().map(|s| s.to_string()))
-            .collect::<Option<Vec<_>>>()
+    /// Stored row of `path`, other case of it is used if `path` is not indexed and case is
+    /// ignored, first one in byte order wins if there are more than one
+    pub(super) async fn query_folded(
+        conn: &mut dyn Database,
+        path: &str,
+        case: CaseSensitivity,
+    ) -> sqlx::Result<Option<FileEntry>> {
+        let entry = conn.query(path).await?;
+        if entry.is_some() || !case.is_insensitive() {
+            return Ok(entry);
+        }
+        match conn.query_case_variants(path).await?.into_iter().next() {
+            Some(variant) => conn.query(&variant).await,
+            None => Ok(None),
+        }
     }
 
-    impl From<Event> for FileEvent {
-        fn from(value: Event) -> Self {
-            let paths = convert(value.paths).expect(PATH_UTF8_ERROR);
-            match value.kind {
-                EventKind::Create(_) => Self::New(paths),
-                EventKind::Modify(_) => Self::Update(paths),
-                EventKind::Remove(_) => Self::Remove(paths),
-                _ => Self::Unknown,
+    fn error_class(e: &anyhow::Error) -> ErrorClass {
+        for cause in e.chain() {
+            if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+                return match e.kind() {
+                    std::io::ErrorKind::PermissionDenied => ErrorClass::PermissionDenied,
+                    _ => ErrorClass::Io,
+                };
+            }
+            if cause.is::<sqlx::Error>() {
+                return ErrorClass::Database;
             }
         }
+        ErrorClass::Other
     }
 
-    #[derive(Clone, Debug)]
-    pub struct FileEventHelper {
-        upstream: mpsc::Sender<FileEvent>,
+    /// Keep failure of indexing `path` for `/admin/errors`, file removed before it is read
+    /// is not an error
+    async fn record_error(
+        conn: &mut dyn Database,
+        path: &str,
+        e: &anyhow::Error,
+    ) -> anyhow::Result<()> {
+        let removed = e.chain().any(|cause| {
+            cause
+                .downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+        });
+        if removed {
+            debug!("{} is removed before indexed: {:#}", path, e);
+            return Ok(());
+        }
+        error!("Unable to index {}: {:#}", path, e);
+        conn.insert_error(IndexError::new(
+            path.to_string(),
+            error_class(e),
+            format!("{:#}", e),
+            unix_now(),
+        ))
+        .await?;
+        Ok(())
     }
 
-    impl FileEventHelper {
-        pub(super) fn new() -> (Self, mpsc::Receiver<FileEvent>) {
-            let (sender, receiver) = mpsc::channel(2048);
-            (Self { upstream: sender }, receiver)
-        }
+    /// Paths read again by `/admin/errors/retry`, reply is sent once rows are written
+    type Retried = (
+        Vec<(String, anyhow::Result<Reconciled>)>,
+        tokio::sync::oneshot::Sender<Vec<IndexError>>,
+    );
 
-        pub(super) async fn send(&self, event: Event) -> Option<()> {
-            self.upstream.send(event.into()).await.ok()
+    /// Read `paths` off file daemon loop, rows are written by `apply_retried`
+    async fn read_retried(
+        paths: Vec<String>,
+        exclude: Arc<ExcludeSet>,
+        option: HashOption,
+    ) -> Vec<(String, anyhow::Result<Reconciled>)> {
+        let mut results = Vec::with_capacity(paths.len());
+        for path in paths {
+            let result = read_reconciled(&path, &exclude, &option).await;
+            results.push((path, result));
         }
+        results
+    }
 
-        pub(super) async fn send_configure_updated(&self, path: String) -> Option<()> {
-            self.upstream
-                .send(FileEvent::ConfigureUpdated(path))
-                .await
-                .ok()
+    /// Error of path is cleared if it is indexed this time
+    async fn apply_retried(
+        conn: &mut dyn Database,
+        (results, sender): Retried,
+        option: &HashOption,
+        case: CaseSensitivity,
+    ) -> anyhow::Result<()> {
+        for (path, result) in results {
+            let result = match result {
+                Ok(reconciled) => apply_reconciled(conn, &path, reconciled, option, case).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => conn.delete_error(&path).await?,
+                Err(e) => record_error(conn, &path, &e).await?,
+            }
         }
+        sender
+            .send(conn.query_errors().await?)
+            .inspect_err(|_| error!("Unable to send errors to client"))
+            .ok();
+        Ok(())
+    }
 
-        pub async fn send_terminate(&self) -> Option<()> {
-            self.upstream.send(FileEvent::Terminate).await.ok()
+    /// `path` is index path of `entry`, differs from file system path under mount
+    ///
+    /// File needs to be hashed is returned, its row is written by `apply_hash`
+    async fn process_file(
+        conn: &mut dyn Database,
+        path: &str,
+        entry: async_walkdir::DirEntry,
+        rehash: bool,
+        progress: &ScanProgress,
+    ) -> anyhow::Result<Option<Pending>> {
+        let pending = Pending {
+            path: path.to_string(),
+            fs_path: entry.path(),
+            metadata: entry.metadata().await?,
+            stored: None,
+        };
+        let Some(sql_entry) = conn.query_path(path.as_ref()).await? else {
+            return Ok(Some(pending));
+        };
+        let entry = FileEntry::from_metadata::<_, String>(path, pending.metadata.clone(), None);
+        progress.add_scanned(entry.size().max(0) as u64);
+        if sql_entry == entry && (!rehash || entry.is_dir()) {
+            // Directory row has nothing to hash, only mtime may change
+            if entry.is_dir() && sql_entry.mtime() != entry.mtime() {
+                conn.update(entry).await?;
+            } else {
+                conn.mark(entry).await?;
+            }
+            return Ok(None);
         }
+        progress.add_hashed();
+        // mtime || size not match, or hash is computed by old version
+        Ok(Some(Pending {
+            stored: Some(sql_entry),
+            ..pending
+        }))
+    }
 
-        pub async fn send_request(
-            &self,
-            paths: Vec<String>,
-        ) -> Option<oneshot::Receiver<Vec<OptionFile>>> {
-            let (sender, receiver) = oneshot::channel();
-            self.upstream
-                .send(FileEvent::Request(paths, sender))
-                .await
-                .ok()?;
-            Some(receiver)
+    /// Directory has no hash, chunk hashes are empty unless chunking is enabled
+    async fn hash_file(
+        fs_path: &Path,
+        option: &HashOption,
+    ) -> std::io::Result<(Option<String>, Vec<String>)> {
+        if fs_path.is_dir() {
+            return Ok((None, Vec::new()));
         }
+        let (hash, chunks) = get_file_chunks(fs_path, option).await?;
+        Ok((Some(hash), chunks))
     }
-}
-
-mod watcher {
-    use crate::file::types::FileEventHelper;
-    use log::{error, warn};
-    use notify::{Event, EventKind, RecursiveMode, Watcher};
-    use publib::types::ExitExt;
-    use std::path::{Path, PathBuf};
-    use std::sync::atomic::{AtomicBool, Ordering};
-    use std::sync::Arc;
-    use std::thread::JoinHandle;
-    use std::time::Duration;
-    use tap::TapOptional;
 
-    #[derive(Debug)]
-    pub struct FileWatcher {
-        handler: JoinHandle<Result<(), notify::Error>>,
-        exit_shot: Arc<AtomicBool>,
+    /// Chunk hashes are kept with file hash, so outdated chunks are never served
+    async fn store_chunks(
+        conn: &mut dyn Database,
+        entry: &FileEntry,
+        chunks: &[String],
+        option: &HashOption,
+    ) -> anyhow::Result<()> {
+        let Some(chunk_size) = option.chunk_size() else {
+            return Ok(());
+        };
+        if chunks.is_empty() {
+            return Ok(());
+        }
+        Ok(conn
+            .upsert_chunks(entry.path(), entry.hash(), chunk_size as i64, chunks)
+            .await?)
     }
 
-    impl FileWatcher {
-        fn watcher<P: AsRef<Path>>(
-            path: P,
-            config_path: PathBuf,
-            exit_signal: Arc<AtomicBool>,
-            upstream: FileEventHelper,
-        ) -> Result<(), notify::Error> {
-            let sub_path = config_path.clone();
-            let mut watcher = notify::recommended_watcher(move |res| match res {
-                Ok(event) => {
-                    Self::event_handler(event, &upstream, &config_path);
-                }
-                Err(e) => {
-                    warn!("[file watcher] Watcher got error: {:?}", e);
-                }
-            })?;
-            watcher
-                .watch(sub_path.as_ref(), RecursiveMode::NonRecursive)
-                .inspect_err(|e| {
-                    error!("[file watcher] Unable to watch configure file: {:?}", e)
-                })?;
-            watcher
-                .watch(path.as_ref(), RecursiveMode::Recursive)
-                .inspect_err(|e| error!("[file watcher] Unable to watch directory: {:?}", e))?;
-
-            loop {
-                if exit_signal.load(Ordering::Relaxed) {
-                    break;
-                }
-                std::thread::sleep(Duration::from_millis(10));
+    /// Row is not written since `stored` was read by walk
+    pub(super) fn is_unchanged(stored: Option<&FileEntry>, current: Option<&FileEntry>) -> bool {
+        match (stored, current) {
+            (None, None) => true,
+            (Some(stored), Some(current)) => {
+                stored.hash() == current.hash()
+                    && stored.mtime() == current.mtime()
+                    && stored.size() == current.size()
             }
-
-            watcher
-                .unwatch(path.as_ref())
-                .inspect_err(|e| error!("[file watcher] Unable to unwatch directory: {:?}", e))?;
-            Ok(())
+            _ => false,
         }
+    }
 
-        fn event_handler(event: Event, upstream: &FileEventHelper, configure: &PathBuf) {
-            if let EventKind::Modify(notify::event::ModifyKind::Data(
-                notify::event::DataChange::Any,
-            )) = event.kind
-            {
-                for file in event.paths.iter() {
-                    if configure.eq(file) {
-                        tokio::runtime::Builder::new_multi_thread()
-                            .enable_all()
-                            .build()
-                            .unwrap()
-                            .block_on(
-                                upstream.send_configure_updated(
-                                    configure.to_str().unwrap().to_string(),
-                                ),
-                            )
-                            .tap_none(|| warn!("Unable send event to file daemon"));
-                        break;
-                    }
+    /// Row written by watcher event while file was hashed is kept, hash of walked metadata
+    /// may be stale
+    pub(super) async fn apply_hash(
+        conn: &mut dyn Database,
+        pending: Pending,
+        (hash, chunks, encrypted): HashResult,
+        case: CaseSensitivity,
+        option: &HashOption,
+        progress: &ScanProgress,
+    ) -> anyhow::Result<()> {
+        let Pending {
+            path,
+            metadata,
+            stored,
+            ..
+        } = pending;
+        let current = conn.query_path(path.as_ref()).await?;
+        if !is_unchanged(stored.as_ref(), current.as_ref()) {
+            debug!(
+                "{} is indexed again while it is hashed, keep newer row",
+                path
+            );
+            if let Some(current) = current {
+                conn.mark(current).await?;
+            }
+            return Ok(());
+        }
+        let entry = FileEntry::from_metadata(&path, metadata, hash).with_encrypted(encrypted);
+        store_chunks(conn, &entry, &chunks, option).await?;
+        match stored {
+            None => {
+                progress.add_scanned(entry.size().max(0) as u64);
+                if !entry.is_dir() {
+                    progress.add_hashed();
                 }
+                fold_case(conn, &path, case).await?;
+                conn.insert(entry).await?;
             }
-
-            match event.kind {
-                EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) => {
-                    tokio::runtime::Builder::new_multi_thread()
-                        .enable_all()
-                        .build()
-                        .unwrap()
-                        .block_on(upstream.send(event))
-                        .tap_none(|| warn!("Unable send event to file daemon"));
+            Some(sql_entry) => {
+                // maybe mtime change but hash same
+                if sql_entry.check_hash_only(&entry) {
+                    info!("{} changed but hash is same", entry.path());
+                } else {
+                    info!("{} updated", entry.path());
                 }
-                _ => {}
+                conn.update(entry).await?;
             }
         }
+        Ok(())
+    }
 
-        pub fn start<P: AsRef<Path> + Send + 'static>(
-            path: P,
-            config_path: PathBuf,
-            event_helper: FileEventHelper,
-        ) -> Self {
-            let signal = Arc::new(AtomicBool::new(false));
-            let signal2 = Arc::clone(&signal);
-            let handler =
-                std::thread::spawn(move || Self::watcher(path, config_path, signal, event_helper));
-            Self::new(handler, signal2)
-        }
+    /// `path` is index path, file is read from mount if it is under one
+    async fn read_entry(
+        path: &str,
+        event_type: &str,
+        option: &HashOption,
+    ) -> anyhow::Result<(FileEntry, Vec<String>)> {
+        // Source is kept, so error can be classified by `error_class`
+        let fs_path = Mounts::global().resolve(path);
+        let (hash, chunks) = hash_file(&fs_path, option).await.map_err(|e| {
+            anyhow::Error::new(e).context(format!("Get file hash error({})", event_type))
+        })?;
+        let encrypted = is_e2e_encrypted(&fs_path).await.map_err(|e| {
+            anyhow::Error::new(e).context(format!("Unable read file({})", event_type))
+        })?;
+        let metadata = fs_path.metadata().map_err(|e| {
+            anyhow::Error::new(e).context(format!("Unable read metadata({})", event_type))
+        })?;
+        Ok((
+            FileEntry::from_metadata(path, metadata, hash).with_encrypted(encrypted),
+            chunks,
+        ))
+    }
 
-        fn new(handler: JoinHandle<Result<(), notify::Error>>, exit_shot: Arc<AtomicBool>) -> Self {
-            Self { handler, exit_shot }
-        }
+    /// File system state of path, read without touching index
+    enum Reconciled {
+        Excluded,
+        Removed,
+        Read(FileEntry, Vec<String>),
     }
 
-    impl ExitExt for FileWatcher {
-        fn _send_terminate(&self) -> Option<()> {
-            Some(self.exit_shot.store(true, Ordering::Relaxed))
+    async fn read_reconciled(
+        path: &str,
+        exclude: &ExcludeSet,
+        option: &HashOption,
+    ) -> anyhow::Result<Reconciled> {
+        if exclude.is_match(path) {
+            return Ok(Reconciled::Excluded);
         }
+        if !Mounts::global().resolve(path).exists() {
+            return Ok(Reconciled::Removed);
+        }
+        let (entry, chunks) = read_entry(path, "replay", option).await?;
+        Ok(Reconciled::Read(entry, chunks))
+    }
+
+    async fn apply_reconciled(
+        conn: &mut dyn Database,
+        path: &str,
+        reconciled: Reconciled,
+        option: &HashOption,
+        case: CaseSensitivity,
+    ) -> anyhow::Result<()> {
+        match reconciled {
+            Reconciled::Excluded => Ok(()),
+            Reconciled::Removed => conn
+                .delete(path.to_string())
+                .await
+                .map_err(|e| anyhow!("Unable delete path {:?}: {:?}", path, e)),
+            Reconciled::Read(entry, chunks) => {
+                fold_case(conn, path, case).await?;
+                store_chunks(conn, &entry, &chunks, option).await?;
+                conn.upsert(entry)
+                    .await
+                    .map_err(|e| anyhow!("Unable store file(replay): {:?}", e))
+            }
+        }
+    }
+
+    /// Path beyond limits is skipped like excluded one, same as scan does
+    pub(super) fn within_limits(path: &str, limits: &PathLimits) -> bool {
+        limits
+            .check(path)
+            .inspect_err(|reason| warn!("Skip {}: {}", path, reason))
+            .is_ok()
+    }
+
+    /// Bring index of path up to date with file system
+    async fn reconcile_path(
+        conn: &mut dyn Database,
+        path: &str,
+        exclude: &ExcludeSet,
+        option: &HashOption,
+        case: CaseSensitivity,
+    ) -> anyhow::Result<()> {
+        let reconciled = read_reconciled(path, exclude, option).await?;
+        apply_reconciled(conn, path, reconciled, option, case).await
+    }
+
+    /// Re-check paths touched by events after checkpoint, used after daemon stopped
+    /// unexpectedly, so index can be recovered without full rescan
+    pub async fn replay_events(
+        conn: &mut dyn Database,
+        event_log: &EventLog,
+        exclude: &ExcludeSet,
+        option: &HashOption,
+        case: CaseSensitivity,
+    ) -> anyhow::Result<usize> {
+        let checkpoint = conn.query_event_checkpoint().await?;
+        let records = event_log
+            .reader()
+            .read_after(checkpoint)
+            .map_err(|e| anyhow!("Unable read event log: {:?}", e))?;
+        let mut paths = records
+            .iter()
+            .flat_map(|record| record.event().paths())
+            .collect::<Vec<_>>();
+        paths.sort_unstable();
+        paths.dedup();
+        for path in &paths {
+            // One bad record shouldn't keep daemon from starting, path is fixed by next scan
+            if let Err(e) = reconcile_path(conn, path, exclude, option, case).await {
+                warn!("Skip replay of {}: {:?}", path, e);
+            }
+        }
+        if let Some(record) = records.last() {
+            conn.update_event_checkpoint(record.seq()).await?;
+        }
+        Ok(records.len())
+    }
+
+    /// Max events committed in one transaction
+    const BATCH_SIZE: usize = 256;
+    /// Open batch is committed after this time even if it is not full
+    const BATCH_INTERVAL: Duration = Duration::from_millis(200);
+
+    /// Writes of file events are committed together, so copying many files into working
+    /// directory don't sync database on every event
+    #[derive(Debug, Default)]
+    struct Batch {
+        /// Commit deadline, `None` if no batch is open
+        deadline: Option<Instant>,
+        count: usize,
+    }
+
+    impl Batch {
+        async fn begin(&mut self, conn: &mut dyn Database) {
+            if self.deadline.is_some() {
+                return;
+            }
+            match conn.begin_batch().await {
+                Ok(()) => self.deadline = Some(Instant::now() + BATCH_INTERVAL),
+                Err(e) => error!("Unable to begin batch: {:?}", e),
+            }
+        }
+
+        /// Commit batch if it is full
+        async fn add(&mut self, conn: &mut dyn Database) {
+            if self.deadline.is_none() {
+                return;
+            }
+            self.count += 1;
+            if self.count >= BATCH_SIZE {
+                self.flush(conn).await;
+            }
+        }
+
+        async fn flush(&mut self, conn: &mut dyn Database) {
+            if self.deadline.take().is_none() {
+                return;
+            }
+            let count = std::mem::take(&mut self.count);
+            if let Err(e) = conn.commit_batch().await {
+                error!("Unable to commit {} event(s): {:?}", count, e);
+                conn.rollback_batch()
+                    .await
+                    .inspect_err(|e| error!("Unable to rollback batch: {:?}", e))
+                    .ok();
+            }
+        }
+
+        async fn expired(deadline: Option<Instant>) {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    /// Indexed entry and hash of its file, `None` if file changed after indexed
+    type Checked = (FileEntry, std::io::Result<Option<String>>);
+
+    /// Rolling re-hash of indexed files, `batch` files after stored cursor are checked every
+    /// interval
+    ///
+    /// Files are hashed in parallel like scan, result is applied by file daemon
+    struct Scrub {
+        /// `None` if scrub is disabled
+        interval: Option<tokio::time::Interval>,
+        batch: i64,
+        pending: VecDeque<FileEntry>,
+        checking: JoinSet<Checked>,
+        workers: usize,
+        option: HashOption,
+    }
+
+    impl Scrub {
+        fn new(scrub: ScrubOption, option: HashOption, workers: usize) -> Self {
+            let interval = scrub.interval().map(|period| {
+                let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+                interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                interval
+            });
+            Self {
+                interval,
+                batch: scrub.batch(),
+                pending: VecDeque::new(),
+                checking: JoinSet::new(),
+                workers: workers.max(1),
+                option,
+            }
+        }
+
+        /// Hashed file of current batch, `None` once interval elapsed and previous batch is
+        /// done
+        async fn next(&mut self) -> Option<Result<Checked, JoinError>> {
+            while self.checking.len() < self.workers {
+                let Some(entry) = self.pending.pop_front() else {
+                    break;
+                };
+                self.checking.spawn(Self::hash(entry, self.option));
+            }
+            if let Some(checked) = self.checking.join_next().await {
+                return Some(checked);
+            }
+            match self.interval.as_mut() {
+                Some(interval) => {
+                    interval.tick().await;
+                    None
+                }
+                None => std::future::pending().await,
+            }
+        }
+
+        fn unchanged(entry: &FileEntry, metadata: &std::fs::Metadata) -> bool {
+            FileEntry::from_metadata::<_, String>(entry.path(), metadata.clone(), None) == *entry
+        }
+
+        /// Hash file in stored algorithm, file changed after indexed is left to watcher and
+        /// scan
+        async fn hash(entry: FileEntry, option: HashOption) -> Checked {
+            let fs_path = Mounts::global().resolve(entry.path());
+            let result = async {
+                let Ok(metadata) = tokio::fs::metadata(&fs_path).await else {
+                    return Ok(None);
+                };
+                if !Self::unchanged(&entry, &metadata) {
+                    return Ok(None);
+                }
+                let algo = HashAlgo::from_digest(entry.hash()).unwrap_or(option.algo());
+                let option = HashOption::new(algo, option.buffer_size(), option.buffered());
+                Ok(Some(get_hash(&fs_path, &option).await?.unwrap_or_default()))
+            }
+            .await;
+            (entry, result)
+        }
+
+        /// Load next batch, start over from first file once cursor reaches the end
+        async fn start_batch(&mut self, conn: &mut dyn Database) -> anyhow::Result<()> {
+            let cursor = conn.query_scrub_cursor().await?;
+            let mut entries = conn.query_scrub_batch(&cursor, self.batch).await?;
+            if entries.is_empty() && !cursor.is_empty() {
+                entries = conn.query_scrub_batch("", self.batch).await?;
+            }
+            if let Some(last) = entries.last() {
+                conn.update_scrub_cursor(last.path()).await?;
+            }
+            self.pending = entries.into();
+            Ok(())
+        }
+
+        /// Record hash mismatch, file changed while it was hashed is skipped
+        async fn apply(conn: &mut dyn Database, (entry, result): Checked) -> anyhow::Result<()> {
+            let Some(actual) = result? else {
+                return Ok(());
+            };
+            let fs_path = Mounts::global().resolve(entry.path());
+            match tokio::fs::metadata(&fs_path).await {
+                Ok(metadata) if Self::unchanged(&entry, &metadata) => {}
+                _ => return Ok(()),
+            }
+            if actual == entry.hash() {
+                conn.delete_integrity_issue(entry.path()).await?;
+                return Ok(());
+            }
+            warn!(
+                "{} hash mismatch, expected {} but got {}",
+                entry.path(),
+                entry.hash(),
+                actual
+            );
+            conn.insert_integrity_issue(IntegrityIssue::new(
+                entry.path().to_string(),
+                entry.hash().to_string(),
+                actual,
+                unix_now(),
+            ))
+            .await?;
+            Ok(())
+        }
+    }
+
+    /// Files copied to cold storage in background, copy is applied by file daemon, so
+    /// original is removed only if it is unchanged since copy started
+    pub(super) struct Tiering {
+        /// `None` if tiering is disabled
+        interval: Option<tokio::time::Interval>,
+        cold_dir: PathBuf,
+        after: Duration,
+        batch: i64,
+        copying: JoinSet<Copied>,
+    }
+
+    /// Indexed entry and its copy in cold storage
+    type Copied = (FileEntry, PathBuf, std::io::Result<()>);
+
+    impl Tiering {
+        fn new(option: TieringOption) -> Self {
+            let interval = option.cold_dir().map(|_| {
+                let period = option.interval();
+                let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+                interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                interval
+            });
+            Self {
+                interval,
+                cold_dir: PathBuf::from(option.cold_dir().unwrap_or_default()),
+                after: option.after(),
+                batch: option.batch(),
+                copying: JoinSet::new(),
+            }
+        }
+
+        /// Finished copy, `None` once interval elapsed
+        async fn next(&mut self) -> Option<Result<Copied, JoinError>> {
+            let Some(interval) = self.interval.as_mut() else {
+                return std::future::pending().await;
+            };
+            tokio::select! {
+                Some(copied) = self.copying.join_next() => Some(copied),
+                _ = interval.tick() => None,
+            }
+        }
+
+        /// Copy files neither modified nor downloaded recently, held files stay in place
+        async fn start_batch(
+            &mut self,
+            conn: &mut dyn Database,
+            holds: &Holds,
+        ) -> anyhow::Result<()> {
+            if !self.copying.is_empty() {
+                return Ok(());
+            }
+            let before = unix_now() - self.after.as_secs() as i64;
+            for entry in conn.query_cold_files(before, self.batch).await? {
+                if holds.find(entry.path()).is_some() {
+                    continue;
+                }
+                let fs_path = Mounts::global().resolve(entry.path());
+                let location = self.cold_dir.join(entry.path().trim_start_matches("./"));
+                self.copying.spawn(async move {
+                    let result = async {
+                        if let Some(parent) = location.parent() {
+                            tokio::fs::create_dir_all(parent).await?;
+                        }
+                        tokio::fs::copy(&fs_path, &location).await.map(|_| ())
+                    }
+                    .await;
+                    (entry, location, result)
+                });
+            }
+            Ok(())
+        }
+
+        /// Row of file is kept as stub, copy is dropped if file changed in the meantime
+        pub(super) async fn apply(
+            conn: &mut dyn Database,
+            tiered: &Tiered,
+            (entry, location, result): Copied,
+        ) -> anyhow::Result<()> {
+            if let Err(e) = result {
+                tokio::fs::remove_file(&location).await.ok();
+                return Err(anyhow!("Unable to copy {}: {:?}", entry.path(), e));
+            }
+            let fs_path = Mounts::global().resolve(entry.path());
+            let unchanged = match tokio::fs::metadata(&fs_path).await {
+                Ok(metadata) => {
+                    FileEntry::from_metadata::<_, String>(entry.path(), metadata, None) == entry
+                }
+                Err(_) => false,
+            };
+            if !unchanged {
+                tokio::fs::remove_file(&location).await.ok();
+                return Ok(());
+            }
+            conn.insert_tiered(
+                entry.path(),
+                location.to_str().expect(PATH_UTF8_ERROR),
+                unix_now(),
+            )
+            .await?;
+            tiered.insert(entry.path().to_string(), location);
+            tokio::fs::remove_file(&fs_path).await?;
+            info!("{} is moved to cold storage", entry.path());
+            Ok(())
+        }
+
+        /// File is back in working directory, copy in cold storage is dropped
+        async fn untier(
+            conn: &mut dyn Database,
+            tiered: &Tiered,
+            path: &str,
+        ) -> anyhow::Result<()> {
+            let Some(location) = tiered.location(path) else {
+                return Ok(());
+            };
+            conn.delete_tiered(path).await?;
+            tiered.remove(path);
+            tokio::fs::remove_file(&location)
+                .await
+                .inspect_err(|e| warn!("Unable to remove cold copy of {}: {:?}", path, e))
+                .ok();
+            Ok(())
+        }
+
+        /// Stubs are moved with their directory, cold copy stays where it is
+        async fn rename(
+            conn: &mut dyn Database,
+            tiered: &Tiered,
+            from: &str,
+            to: &str,
+        ) -> anyhow::Result<()> {
+            for (path, location) in tiered.under(from) {
+                let renamed = format!("{}{}", to, &path[from.len()..]);
+                conn.delete_tiered(&path).await?;
+                conn.insert_tiered(
+                    &renamed,
+                    location.to_str().expect(PATH_UTF8_ERROR),
+                    unix_now(),
+                )
+                .await?;
+                tiered.remove(&path);
+                tiered.insert(renamed, location);
+            }
+            Ok(())
+        }
+    }
+
+    /// Transient failures of a path are retried this many times before recorded as error
+    const MAX_RETRIES: u32 = 5;
+    /// Delay before first retry, doubled on each failure
+    const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+    /// `errno` on Linux, file is briefly locked by tools copying it
+    const EAGAIN: i32 = 11;
+    const EBUSY: i32 = 16;
+    const ETXTBSY: i32 = 26;
+
+    /// Error may go away if file is read again later
+    fn is_transient(e: &anyhow::Error) -> bool {
+        e.chain()
+            .filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+            .any(|e| {
+                matches!(e.raw_os_error(), Some(EAGAIN | EBUSY | ETXTBSY))
+                    || matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock
+                            | std::io::ErrorKind::Interrupted
+                            | std::io::ErrorKind::TimedOut
+                    )
+            })
+    }
+
+    /// Paths failed by transient error, indexed again with exponential backoff
+    #[derive(Debug, Default)]
+    struct Retries {
+        /// Failed attempts of each path, kept until indexed or given up
+        attempts: HashMap<String, u32>,
+        due: HashMap<String, Instant>,
+    }
+
+    impl Retries {
+        /// Retry `path` later, `false` if error is not transient or attempts are used up
+        fn schedule(&mut self, path: &str, e: &anyhow::Error) -> bool {
+            if !is_transient(e) {
+                self.clear(path);
+                return false;
+            }
+            let attempts = self.attempts.entry(path.to_string()).or_default();
+            if *attempts >= MAX_RETRIES {
+                self.clear(path);
+                return false;
+            }
+            let delay = RETRY_DELAY * 2u32.pow(*attempts);
+            *attempts += 1;
+            debug!("Retry {} in {:?}: {:#}", path, delay, e);
+            self.due.insert(path.to_string(), Instant::now() + delay);
+            true
+        }
+
+        fn clear(&mut self, path: &str) {
+            self.attempts.remove(path);
+            self.due.remove(path);
+        }
+
+        fn deadline(&self) -> Option<Instant> {
+            self.due.values().min().copied()
+        }
+
+        /// Paths should be retried now, attempts are kept until `clear`
+        fn take_due(&mut self) -> Vec<String> {
+            let now = Instant::now();
+            let paths = self
+                .due
+                .iter()
+                .filter(|(_, at)| **at <= now)
+                .map(|(path, _)| path.clone())
+                .collect::<Vec<_>>();
+            for path in &paths {
+                self.due.remove(path);
+            }
+            paths
+        }
+    }
+
+    /// Wait until `deadline`, forever if it is `None`
+    async fn until(deadline: Option<Instant>) {
+        match deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Size and mtime of file
+    type Snapshot = (u64, Option<std::time::SystemTime>);
+
+    fn snapshot(path: &str) -> Option<Snapshot> {
+        let metadata = Mounts::global().resolve(path).metadata().ok()?;
+        (!metadata.is_dir()).then(|| (metadata.len(), metadata.modified().ok()))
+    }
+
+    /// Recently modified files are indexed once size and mtime stop changing for `interval`,
+    /// so file being copied into working directory is not hashed partially, by watcher or
+    /// by scan
+    #[derive(Debug)]
+    pub(super) struct Settling {
+        /// `None` if files are indexed on event
+        interval: Option<Duration>,
+        /// Snapshot seen last time and when to compare again
+        pending: HashMap<String, (Snapshot, Instant)>,
+    }
+
+    impl Settling {
+        pub(super) fn new(interval: Option<Duration>) -> Self {
+            Self {
+                interval,
+                pending: HashMap::new(),
+            }
+        }
+
+        /// `true` if indexing `path` is deferred, waiting file is deferred again on each event
+        pub(super) fn defer(&mut self, path: &str) -> bool {
+            let Some(interval) = self.interval else {
+                return false;
+            };
+            // Failure is reported by indexing
+            let Some(snapshot) = snapshot(path) else {
+                self.pending.remove(path);
+                return false;
+            };
+            let recent = snapshot
+                .1
+                .and_then(|mtime| mtime.elapsed().ok())
+                .is_some_and(|elapsed| elapsed < interval);
+            if !recent && !self.pending.contains_key(path) {
+                return false;
+            }
+            self.pending
+                .insert(path.to_string(), (snapshot, Instant::now() + interval));
+            true
+        }
+
+        fn remove(&mut self, path: &str) {
+            self.pending.remove(path);
+        }
+
+        pub(super) fn deadline(&self) -> Option<Instant> {
+            self.pending.values().map(|(_, at)| *at).min()
+        }
+
+        /// Due paths not changed since last seen, changed ones are deferred again
+        pub(super) fn take_settled(&mut self) -> Vec<String> {
+            let now = Instant::now();
+            let due = self
+                .pending
+                .iter()
+                .filter(|(_, (_, at))| *at <= now)
+                .map(|(path, (snapshot, _))| (path.clone(), *snapshot))
+                .collect::<Vec<_>>();
+            let mut settled = Vec::new();
+            for (path, previous) in due {
+                match snapshot(&path) {
+                    Some(current) if current != previous => {
+                        debug!("{} is still being written", path);
+                        if let Some(interval) = self.interval {
+                            self.pending.insert(path, (current, now + interval));
+                        }
+                    }
+                    Some(_) => {
+                        self.pending.remove(&path);
+                        settled.push(path);
+                    }
+                    // Removed, left to remove event
+                    None => {
+                        self.pending.remove(&path);
+                    }
+                }
+            }
+            settled
+        }
+    }
+
+    /// Options of file daemon, taken from configure on startup
+    #[derive(Clone)]
+    pub struct DaemonOptions {
+        pub exclude: Arc<ExcludeSet>,
+        pub hash: HashOption,
+        pub git: bool,
+        pub case: CaseSensitivity,
+        pub limits: PathLimits,
+        pub scrub: ScrubOption,
+        pub tiering: TieringOption,
+        pub settle: Option<Duration>,
+        pub hash_workers: usize,
+        pub access_retention: Option<Duration>,
+    }
+
+    /// State shared with `FileEventHelper`, upstream sender is not kept so daemon stops
+    /// once every helper is dropped
+    struct Shared {
+        changes: broadcast::Sender<ChangeEvent>,
+        jobs: Jobs,
+        progress: ScanProgress,
+        holds: Holds,
+        links: Links,
+        upload_sessions: UploadSessions,
+        tiered: Tiered,
+    }
+
+    impl Shared {
+        fn of(helper: &FileEventHelper) -> Self {
+            Self {
+                changes: helper.change_sender(),
+                jobs: helper.jobs().clone(),
+                progress: helper.scan_progress().clone(),
+                holds: helper.holds().clone(),
+                links: helper.links().clone(),
+                upload_sessions: helper.upload_sessions().clone(),
+                tiered: helper.tiered().clone(),
+            }
+        }
+    }
+
+    /// Handler of file events, nothing runs until `run` is awaited
+    pub struct FileDaemon {
+        handler: BoxFuture<'static, anyhow::Result<()>>,
+    }
+
+    impl FileDaemon {
+        /// Row is replaced if path already exists, so repeated events are harmless
+        async fn store_path(
+            conn: &mut dyn Database,
+            path: &str,
+            event_type: &str,
+            option: &HashOption,
+            case: CaseSensitivity,
+        ) -> anyhow::Result<()> {
+            let (entry, chunks) = read_entry(path, event_type, option).await?;
+            fold_case(conn, path, case).await?;
+            store_chunks(conn, &entry, &chunks, option).await?;
+            conn.upsert(entry)
+                .await
+                .map_err(|e| anyhow!("Unable store file({}): {:?}", event_type, e))
+        }
+
+        /// Like `store_path`, but failure is retried later or recorded instead of returned,
+        /// other paths of event are still indexed
+        async fn index_path(
+            conn: &mut dyn Database,
+            path: &str,
+            event_type: &str,
+            option: &HashOption,
+            case: CaseSensitivity,
+            retries: &mut Retries,
+        ) -> anyhow::Result<()> {
+            match Self::store_path(conn, path, event_type, option, case).await {
+                Ok(()) => {
+                    retries.clear(path);
+                    Ok(conn.delete_error(path).await?)
+                }
+                Err(e) if retries.schedule(path, &e) => Ok(()),
+                Err(e) => record_error(conn, path, &e).await,
+            }
+        }
+
+        async fn event_handler(
+            conn: &mut dyn Database,
+            event: FileEvent,
+            options: &DaemonOptions,
+            retries: &mut Retries,
+            settling: &mut Settling,
+            tiered: &Tiered,
+        ) -> anyhow::Result<()> {
+            let (exclude, limits, option, case) = (
+                &options.exclude,
+                &options.limits,
+                &options.hash,
+                options.case,
+            );
+            match event {
+                FileEvent::New(ref paths) | FileEvent::Update(ref paths) => {
+                    let event_type = if let FileEvent::New(_) = event {
+                        "new"
+                    } else {
+                        "update"
+                    };
+                    for path in paths {
+                        if exclude.is_match(path) || !within_limits(path, limits) {
+                            continue;
+                        }
+                        // Written again while it is in cold storage
+                        Tiering::untier(conn, tiered, path).await?;
+                        if settling.defer(path) {
+                            continue;
+                        }
+                        Self::index_path(conn, path, event_type, option, case, retries).await?;
+                    }
+                }
+
+                FileEvent::Rename(from, to) => {
+                    settling.remove(&from);
+                    if exclude.is_match(&to) || !within_limits(&to, limits) {
+                        conn.delete(from.clone())
+                            .await
+                            .map_err(|e| anyhow!("Unable delete path {:?}: {:?}", from, e))?;
+                        return Ok(());
+                    }
+                    Tiering::untier(conn, tiered, &to).await?;
+                    // Applied already, e.g. moved by `/move` before watcher reported it
+                    if conn.query_path(from.as_ref()).await?.is_none()
+                        && conn.query_path(to.as_ref()).await?.is_some()
+                    {
+                        return Ok(());
+                    }
+                    fold_case_except(conn, &to, &from, case).await?;
+                    let renamed = conn
+                        .rename(&from, &to)
+                        .await
+                        .map_err(|e| anyhow!("Unable rename {:?} to {:?}: {:?}", from, to, e))?;
+                    Tiering::rename(conn, tiered, &from, &to).await?;
+                    if !renamed && !settling.defer(&to) {
+                        // Source is not indexed, index destination as new file
+                        Self::index_path(conn, &to, "rename", option, case, retries).await?;
+                    }
+                }
+
+                // Producer renames file once it is written, no need to wait it settle
+                FileEvent::Completed(path) => {
+                    settling.remove(&path);
+                    if !within_limits(&path, limits) {
+                        return Ok(());
+                    }
+                    Tiering::untier(conn, tiered, &path).await?;
+                    Self::index_path(conn, &path, "new", option, case, retries).await?;
+                }
+
+                FileEvent::Remove(paths) => {
+                    for path in paths {
+                        let path: &Path = path.as_ref();
+                        // Removed by tiering, row is kept as stub
+                        if exclude.is_match(path)
+                            || tiered.contains(path.to_str().expect(PATH_UTF8_ERROR))
+                        {
+                            continue;
+                        }
+                        settling.remove(path.to_str().expect(PATH_UTF8_ERROR));
+                        // Cold copies of stubs under removed directory are orphaned otherwise
+                        for (tiered_path, _) in tiered.under(path.to_str().expect(PATH_UTF8_ERROR))
+                        {
+                            Tiering::untier(conn, tiered, &tiered_path).await?;
+                        }
+                        conn.delete(path.to_str().expect(PATH_UTF8_ERROR).to_string())
+                            .await
+                            .map_err(|e| anyhow!("Unable delete path {:?}: {:?}", path, e))?;
+                    }
+                }
+                _ => unreachable!(),
+            }
+            Ok(())
+        }
+
+        async fn keep_row(conn: &mut dyn Database, path: &str) -> anyhow::Result<()> {
+            if let Some(entry) = conn.query_path(path.as_ref()).await? {
+                conn.mark(entry).await?;
+            }
+            Ok(())
+        }
+
+        /// Process walked entry or hashed file, finish scan if walk is finished or index can't
+        /// be written
+        async fn scan_step(
+            conn: &mut dyn Database,
+            scan: &mut Option<Scan>,
+            item: ScanItem,
+            options: &DaemonOptions,
+            shared: &Shared,
+            retries: &mut Retries,
+            settling: &mut Settling,
+        ) {
+            let (exclude, option, case) = (&options.exclude, &options.hash, options.case);
+            let (jobs, progress) = (&shared.jobs, &shared.progress);
+            let Some(current) = scan.as_mut() else {
+                return;
+            };
+            let (path, result) = match item {
+                ScanItem::Entry(entry) => {
+                    let path = Mounts::global().to_index(entry.path());
+                    let is_dir = entry
+                        .file_type()
+                        .await
+                        .is_ok_and(|file_type| file_type.is_dir());
+                    if exclude.is_match_entry(&path, is_dir) {
+                        return;
+                    }
+                    match process_file(conn, &path, entry, current.rehash, progress).await {
+                        Ok(Some(pending)) => {
+                            if !settling.defer(&path) {
+                                current.hash(pending, *option);
+                                return;
+                            }
+                            // Indexed once it settled, stored row is kept until then
+                            let result = Self::keep_row(conn, &path).await;
+                            (path, result)
+                        }
+                        Ok(None) => (path, Ok(())),
+                        Err(e) => (path, Err(e)),
+                    }
+                }
+                ScanItem::Hashed(Ok(hashed)) => {
+                    let (pending, result) = *hashed;
+                    let path = pending.path.clone();
+                    let result = match result {
+                        Ok(hashed) => {
+                            apply_hash(conn, pending, hashed, case, option, progress).await
+                        }
+                        Err(e) => Err(e.into()),
+                    };
+                    (path, result)
+                }
+                ScanItem::Hashed(Err(e)) => {
+                    // Worker panic is caught in worker, only cancelled worker gets here
+                    error!("Hash worker failure: {:?}", e);
+                    return;
+                }
+                ScanItem::Finished => {
+                    let (root, rehash, git) = (current.root.clone(), current.rehash, current.git);
+                    let result = Scan::finish(&root, rehash, git, conn, option, progress).await;
+                    return Self::end_scan(scan, result, jobs, progress);
+                }
+            };
+            let e = match result {
+                Ok(()) => {
+                    jobs.update(current.job, |job| job.add_processed(1));
+                    return;
+                }
+                Err(e) => e,
+            };
+            // One unreadable file does not fail whole scan, stored row is kept so it is not
+            // removed when scan finished
+            let result = async {
+                Self::keep_row(conn, &path).await?;
+                if !retries.schedule(&path, &e) {
+                    record_error(conn, &path, &e).await?;
+                }
+                Ok::<_, anyhow::Error>(())
+            }
+            .await;
+            if let Err(e) = result {
+                Self::end_scan(scan, Err(e), jobs, progress);
+            }
+        }
+
+        /// Files still being hashed are dropped with scan
+        fn end_scan(
+            scan: &mut Option<Scan>,
+            result: anyhow::Result<()>,
+            jobs: &Jobs,
+            progress: &ScanProgress,
+        ) {
+            let Some(id) = scan.take().map(|scan| scan.job) else {
+                return;
+            };
+            progress.finish(result.is_ok());
+            match result {
+                Ok(_) => info!("Scan job {} finished", id),
+                Err(ref e) => error!("Scan job {} failure: {:?}", id, e),
+            }
+            jobs.update(id, |job| {
+                job.finish(unix_now(), result.err().map(|e| e.to_string()))
+            });
+        }
+
+        /// Start scan of `root` reported to job `id`, job is failed if scan can't be started
+        async fn start_scan(
+            conn: &mut dyn Database,
+            scan: &mut Option<Scan>,
+            root: &str,
+            id: u64,
+            options: &DaemonOptions,
+            shared: &Shared,
+        ) {
+            let jobs = &shared.jobs;
+            jobs.update(id, |job| job.start(unix_now()));
+            match Scan::start(
+                conn,
+                root.to_string(),
+                &options.hash,
+                id,
+                options.git,
+                options.limits,
+                options.hash_workers,
+            )
+            .await
+            {
+                Ok(started) => {
+                    shared.progress.start();
+                    *scan = Some(started);
+                }
+                Err(e) => {
+                    error!("Scan job {} failure: {:?}", id, e);
+                    jobs.update(id, |job| job.finish(unix_now(), Some(e.to_string())));
+                }
+            }
+        }
+
+        async fn refresh_links(conn: &mut dyn Database, links: &Links) {
+            match conn.query_links().await {
+                Ok(stored) => links.set(stored),
+                Err(e) => error!("Unable to load signed links: {:?}", e),
+            }
+        }
+
+        /// Delete expired links, so they are not listed anymore
+        async fn prune_links(conn: &mut dyn Database, links: &Links) {
+            match conn.delete_expired_links(unix_now()).await {
+                Ok(0) => {}
+                Ok(count) => {
+                    debug!("Pruned {} expired link(s)", count);
+                    Self::refresh_links(conn, links).await;
+                }
+                Err(e) => error!("Unable to prune expired links: {:?}", e),
+            }
+        }
+
+        async fn prune_access_log(conn: &mut dyn Database, retention: Duration) {
+            match conn
+                .delete_access_before(unix_now() - retention.as_secs() as i64)
+                .await
+            {
+                Ok(0) => {}
+                Ok(count) => debug!("Pruned {} access record(s)", count),
+                Err(e) => error!("Unable to prune access log: {:?}", e),
+            }
+        }
+
+        async fn prune_uploads(conn: &mut dyn Database) {
+            match conn
+                .delete_finished_uploads(unix_now() - UPLOAD_STATUS_EXPIRE)
+                .await
+            {
+                Ok(0) => {}
+                Ok(count) => debug!("Pruned {} upload status(es)", count),
+                Err(e) => error!("Unable to prune upload status: {:?}", e),
+            }
+        }
+
+        /// Store pending status and send its id to client, `None` if it is not stored
+        async fn create_upload(
+            conn: &mut dyn Database,
+            upload: UploadStatus,
+            sender: oneshot::Sender<Option<u64>>,
+        ) -> Option<UploadStatus> {
+            let upload = conn
+                .insert_upload(upload)
+                .await
+                .inspect_err(|e| error!("Unable to insert upload: {:?}", e))
+                .ok();
+            sender
+                .send(upload.as_ref().map(|upload| upload.id()))
+                .inspect_err(|_| error!("Unable to send upload id to client"))
+                .ok();
+            upload
+        }
+
+        /// Mark upload published, or rejected with `reason`
+        async fn finish_upload(
+            conn: &mut dyn Database,
+            upload: Option<UploadStatus>,
+            reason: Option<&str>,
+        ) {
+            let Some(mut upload) = upload else {
+                return;
+            };
+            match reason {
+                None => upload.publish(unix_now()),
+                Some(reason) => upload.reject(unix_now(), reason.to_string()),
+            }
+            if let Err(e) = conn.update_upload(&upload).await {
+                error!("Unable to update status of upload {}: {:?}", upload.id(), e);
+            }
+        }
+
+        async fn prune_upload_sessions(sessions: &UploadSessions) {
+            for session in sessions.prune(unix_now() - UPLOAD_SESSION_EXPIRE) {
+                info!(
+                    "Upload session {} of {} expired",
+                    session.id(),
+                    session.path()
+                );
+                tokio::fs::remove_file(Mounts::global().resolve(session.temp()))
+                    .await
+                    .ok();
+            }
+        }
+
+        async fn refresh_tiered(conn: &mut dyn Database, tiered: &Tiered) {
+            match conn.query_tiered().await {
+                Ok(stored) => tiered.set(stored),
+                Err(e) => error!("Unable to load tiered files: {:?}", e),
+            }
+        }
+
+        async fn refresh_holds(conn: &mut dyn Database, holds: &Holds) {
+            match conn.query_holds().await {
+                Ok(stored) => holds.set(stored),
+                Err(e) => error!("Unable to load legal holds: {:?}", e),
+            }
+        }
+
+        /// Merge tokens in database into pool, token in configure file take precedence
+        async fn refresh_pool(
+            conn: &mut dyn Database,
+            user_pool: &RwPoolType,
+            config_pool: &PoolType,
+        ) {
+            let tokens = match conn.query_tokens().await {
+                Ok(tokens) => tokens,
+                Err(e) => {
+                    error!("Unable to load tokens: {:?}", e);
+                    return;
+                }
+            };
+            let mut pool = tokens
+                .iter()
+                .filter_map(|token| {
+                    AuthEntry::try_from(token)
+                        .inspect_err(|e| error!("Unable to load token {}: {:?}", token.id(), e))
+                        .ok()
+                        .map(|entry| (token.token().to_string(), Arc::new(entry)))
+                })
+                .collect::<PoolType>();
+            pool.extend(config_pool.clone());
+            let mut user_pool = user_pool.write().await;
+            *user_pool = pool;
+            info!("User pool update, current size: {}", user_pool.len());
+        }
+
+        async fn handler(
+            mut conn: Box<dyn Database>,
+            mut receiver: mpsc::Receiver<FileEvent>,
+            user_pool: Arc<RwPoolType>,
+            mut event_log: Option<EventLog>,
+            options: DaemonOptions,
+            shared: Shared,
+        ) -> anyhow::Result<()> {
+            let DaemonOptions {
+                ref exclude,
+                hash: option,
+                case,
+                ..
+            } = options;
+            let Shared {
+                ref changes,
+                ref jobs,
+                ref progress,
+                ref holds,
+                ref links,
+                ref upload_sessions,
+                ref tiered,
+            } = shared;
+            // Pool is built from configure file on startup
+            let mut config_pool = user_pool.read().await.clone();
+            Self::refresh_pool(conn.as_mut(), &user_pool, &config_pool).await;
+            Self::refresh_holds(conn.as_mut(), holds).await;
+            Self::refresh_links(conn.as_mut(), links).await;
+            Self::refresh_tiered(conn.as_mut(), tiered).await;
+            let mut scan = None;
+            let mut batch = Batch::default();
+            let mut prune = tokio::time::interval(LINK_PRUNE_INTERVAL);
+            prune.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            let mut scrub = Scrub::new(options.scrub, option, options.hash_workers);
+            let mut tiering = Tiering::new(options.tiering.clone());
+            let mut retries = Retries::default();
+            let mut settling = Settling::new(options.settle);
+            let mut retrying = JoinSet::<Retried>::new();
+            loop {
+                let event = tokio::select! {
+                    event = receiver.recv() => match event {
+                        Some(event) => event,
+                        None => break,
+                    },
+                    item = Scan::next(&mut scan) => {
+                        batch.begin(conn.as_mut()).await;
+                        Self::scan_step(conn.as_mut(), &mut scan, item, &options, &shared, &mut retries, &mut settling)
+                            .await;
+                        batch.add(conn.as_mut()).await;
+                        continue;
+                    }
+                    _ = Batch::expired(batch.deadline) => {
+                        batch.flush(conn.as_mut()).await;
+                        continue;
+                    }
+                    _ = until(settling.deadline()) => {
+                        batch.flush(conn.as_mut()).await;
+                        for path in settling.take_settled() {
+                            Self::index_path(conn.as_mut(), &path, "settled", &option, case, &mut retries)
+                                .await
+                                .inspect_err(|e| error!("Unable to index {}: {:?}", path, e))
+                                .ok();
+                        }
+                        continue;
+                    }
+                    _ = until(retries.deadline()) => {
+                        batch.flush(conn.as_mut()).await;
+                        for path in retries.take_due() {
+                            Self::index_path(conn.as_mut(), &path, "retry", &option, case, &mut retries)
+                                .await
+                                .inspect_err(|e| error!("Unable to retry {}: {:?}", path, e))
+                                .ok();
+                        }
+                        continue;
+                    }
+                    _ = prune.tick() => {
+                        batch.flush(conn.as_mut()).await;
+                        Self::prune_links(conn.as_mut(), links).await;
+                        Self::prune_upload_sessions(upload_sessions).await;
+                        Self::prune_uploads(conn.as_mut()).await;
+                        if let Some(retention) = options.access_retention {
+                            Self::prune_access_log(conn.as_mut(), retention).await;
+                        }
+                        continue;
+                    }
+                    checked = scrub.next() => {
+                        batch.flush(conn.as_mut()).await;
+                        match checked {
+                            Some(Ok(checked)) => {
+                                let path = checked.0.path().to_string();
+                                Scrub::apply(conn.as_mut(), checked)
+                                    .await
+                                    .inspect_err(|e| error!("Unable to scrub {}: {:?}", path, e))
+                                    .ok();
+                            }
+                            Some(Err(e)) => error!("Scrub worker failure: {:?}", e),
+                            // Hashes may be recomputed by running scan
+                            None if scan.is_some() => {}
+                            None => {
+                                if let Err(e) = scrub.start_batch(conn.as_mut()).await {
+                                    error!("Unable to start scrub: {:?}", e);
+                                }
+                            }
+                        }
+                        continue;
+                    }
+                    Some(retried) = retrying.join_next() => {
+                        batch.flush(conn.as_mut()).await;
+                        match retried {
+                            Ok(retried) => {
+                                apply_retried(conn.as_mut(), retried, &option, case)
+                                    .await
+                                    .inspect_err(|e| error!("Unable to retry errors: {:?}", e))
+                                    .ok();
+                            }
+                            Err(e) => error!("Retry worker failure: {:?}", e),
+                        }
+                        continue;
+                    }
+                    copied = tiering.next() => {
+                        batch.flush(conn.as_mut()).await;
+                        match copied {
+                            Some(Ok(copied)) => {
+                                Tiering::apply(conn.as_mut(), tiered, copied)
+                                    .await
+                                    .inspect_err(|e| error!("Unable to tier file: {:?}", e))
+                                    .ok();
+                            }
+                            Some(Err(e)) => error!("Tiering worker failure: {:?}", e),
+                            // Row of moved file would be removed by running scan
+                            None if scan.is_some() => {}
+                            None => {
+                                if let Err(e) = tiering.start_batch(conn.as_mut(), holds).await {
+                                    error!("Unable to start tiering: {:?}", e);
+                                }
+                            }
+                        }
+                        continue;
+                    }
+                };
+                // Other events are rare, commit pending writes so they are applied in order
+                let batched = matches!(
+                    event,
+                    FileEvent::New(_)
+                        | FileEvent::Update(_)
+                        | FileEvent::Remove(_)
+                        | FileEvent::Rename(_, _)
+                        | FileEvent::Completed(_)
+                        | FileEvent::Appended(_, _, _)
+                        | FileEvent::Uploaded(_, _, _)
+                );
+                if batched {
+                    batch.begin(conn.as_mut()).await;
+                } else {
+                    batch.flush(conn.as_mut()).await;
+                }
+                match event {
+                    FileEvent::New(_)
+                    | FileEvent::Update(_)
+                    | FileEvent::Remove(_)
+                    | FileEvent::Rename(_, _)
+                    | FileEvent::Completed(_) => {
+                        let events = event.changes();
+                        // Record before applying, so event can be replayed if daemon crashed
+                        let mut seq = None;
+                        if let Some(ref mut event_log) = event_log {
+                            for change in &events {
+                                match event_log.append(change.clone()) {
+                                    Ok(s) => seq = Some(s),
+                                    Err(e) => error!("Unable write event log: {:?}", e),
+                                }
+                            }
+                        }
+                        Self::event_handler(
+                            conn.as_mut(),
+                            event,
+                            &options,
+                            &mut retries,
+                            &mut settling,
+                            tiered,
+                        )
+                        .await
+                        .inspect_err(|e| error!("{:#}", e))
+                        .ok();
+                        if let Some(seq) = seq {
+                            conn.update_event_checkpoint(seq)
+                                .await
+                                .inspect_err(|e| error!("Unable update event checkpoint: {:?}", e))
+                                .ok();
+                        }
+                        progress.bump_generation();
+                        // No subscriber is not an error
+                        for change in events
+                            .into_iter()
+                            .filter(|change| !change.paths().iter().any(|p| exclude.is_match(p)))
+                        {
+                            changes.send(change).ok();
+                        }
+                    }
+                    FileEvent::Appended(entry, upload, sender) => {
+                        let upload = Self::create_upload(conn.as_mut(), upload, sender).await;
+                        let path = entry.path().to_string();
+                        // Path of request may differ from indexed one in case
+                        let result = async {
+                            fold_case(conn.as_mut(), &path, case).await?;
+                            Ok::<_, anyhow::Error>(conn.upsert(entry).await?)
+                        }
+                        .await;
+                        match result {
+                            Ok(()) => {
+                                progress.bump_generation();
+                                Self::finish_upload(conn.as_mut(), upload, None).await;
+                            }
+                            Err(e) => {
+                                error!("Unable update appended file {}: {:?}", path, e);
+                                Self::finish_upload(
+                                    conn.as_mut(),
+                                    upload,
+                                    Some("Unable to index file"),
+                                )
+                                .await;
+                            }
+                        }
+                    }
+                    FileEvent::Uploaded(entry, upload, sender) => {
+                        let upload = Self::create_upload(conn.as_mut(), upload, sender).await;
+                        let path = entry.path().to_string();
+                        let result = async {
+                            fold_case(conn.as_mut(), &path, case).await?;
+                            Ok::<_, anyhow::Error>(conn.upsert(entry).await?)
+                        }
+                        .await;
+                        match result {
+                            Ok(()) => {
+                                progress.bump_generation();
+                                Self::finish_upload(conn.as_mut(), upload, None).await;
+                            }
+                            Err(e) => {
+                                error!("Unable index uploaded file {}: {:?}", path, e);
+                                Self::finish_upload(
+                                    conn.as_mut(),
+                                    upload,
+                                    Some("Unable to index file"),
+                                )
+                                .await;
+                            }
+                        }
+                    }
+                    FileEvent::Upload(id, sender) => {
+                        let upload = conn
+                            .query_upload(id)
+                            .await
+                            .inspect_err(|e| error!("Unable to query upload {}: {:?}", id, e))
+                            .ok()
+                            .flatten();
+                        sender
+                            .send(upload)
+                            .inspect_err(|_| error!("Unable to send upload to client"))
+                            .ok();
+                    }
+                    FileEvent::Rescan(id) => {
+                        if scan.is_some() {
+                            jobs.update(id, |job| {
+                                job.finish(unix_now(), Some("Scan is already running".to_string()))
+                            });
+                            continue;
+                        }
+                        Self::start_scan(conn.as_mut(), &mut scan, ".", id, &options, &shared)
+                            .await;
+                    }
+                    FileEvent::RescanPath(path) => {
+                        // Running scan may have walked past lost events, restart it
+                        let root = match scan.take() {
+                            Some(running) => {
+                                jobs.update(running.job, |job| {
+                                    job.finish(
+                                        unix_now(),
+                                        Some(format!("Restarted by rescan of {}", path)),
+                                    )
+                                });
+                                progress.finish(false);
+                                if running.root == path {
+                                    path
+                                } else {
+                                    ".".to_string()
+                                }
+                            }
+                            None => path,
+                        };
+                        warn!("Events under {} may be lost, rescan it", root);
+                        let id = jobs.create(&format!("rescan {}", root));
+                        Self::start_scan(conn.as_mut(), &mut scan, &root, id, &options, &shared)
+                            .await;
+                    }
+                    FileEvent::Recalled(path) | FileEvent::Untier(path) => {
+                        Tiering::untier(conn.as_mut(), tiered, &path)
+                            .await
+                            .inspect_err(|e| error!("Unable to untier {}: {:?}", path, e))
+                            .ok();
+                    }
+                    FileEvent::Terminate => break,
+                    FileEvent::Unknown => {
+                        unreachable!()
+                    }
+                    FileEvent::Request(paths, sender) => {
+                        let mut v = Vec::with_capacity(paths.len());
+                        for path in paths.iter() {
+                            let q = query_folded(conn.as_mut(), &normalize_path(path), case)
+                                .await
+                                .inspect_err(|e| error!("Query file error: {:?}", e))?;
+                            v.push(OptionFile::from_option_entry(path.clone(), q));
+                        }
+                        sender
+                            .send(v)
+                            .inspect_err(|_| error!("Unable to send query result to client"))
+                            .ok();
+                    }
+                    FileEvent::List(paths, option, page, sender) => {
+                        match conn.query_by_prefixes(&paths, &option, page.as_ref()).await {
+                            Ok(entries) => {
+                                sender
+                                    .send(entries.into_iter().map(|entry| entry.into()).collect())
+                                    .inspect_err(|_| error!("Unable to send list result to client"))
+                                    .ok();
+                            }
+                            Err(e) => error!("List files error: {:?}", e),
+                        }
+                    }
+                    FileEvent::Duplicates(paths, page, sender) => {
+                        match conn.query_duplicates(paths.as_deref(), &page).await {
+                            Ok(report) => {
+                                sender
+                                    .send(report)
+                                    .inspect_err(|_| {
+                                        error!("Unable to send duplicates report to client")
+                                    })
+                                    .ok();
+                            }
+                            Err(e) => error!("Query duplicates error: {:?}", e),
+                        }
+                    }
+                    FileEvent::Report(paths, kind, sender) => {
+                        let result = match kind {
+                            ReportKind::Largest { limit } => {
+                                conn.query_largest(&paths, limit).await
+                            }
+                            ReportKind::Stale { before, limit } => {
+                                conn.query_stale(&paths, before, limit).await
+                            }
+                        };
+                        match result {
+                            Ok(entries) => {
+                                sender
+                                    .send(entries.into_iter().map(|entry| entry.into()).collect())
+                                    .inspect_err(|_| error!("Unable to send report to client"))
+                                    .ok();
+                            }
+                            Err(e) => error!("Query report error: {:?}", e),
+                        }
+                    }
+                    FileEvent::Search(paths, query, sender) => {
+                        match conn
+                            .search(&paths, &query.glob, &query.cursor, query.limit)
+                            .await
+                        {
+                            Ok(entries) => {
+                                sender
+                                    .send(entries.into_iter().map(|entry| entry.into()).collect())
+                                    .inspect_err(|_| error!("Unable to send files to client"))
+                                    .ok();
+                            }
+                            Err(e) => error!("Search files error: {:?}", e),
+                        }
+                    }
+                    FileEvent::Hash(hash, paths, sender) => {
+                        match conn.query_by_hash(&paths, &hash).await {
+                            Ok(entries) => {
+                                sender
+                                    .send(entries.into_iter().map(|entry| entry.into()).collect())
+                                    .inspect_err(|_| error!("Unable to send files to client"))
+                                    .ok();
+                            }
+                            Err(e) => error!("Query hash error: {:?}", e),
+                        }
+                    }
+                    FileEvent::Stats(paths, sender) => match conn.query_stats(&paths).await {
+                        Ok(stats) => {
+                            sender
+                                .send(stats)
+                                .inspect_err(|_| error!("Unable to send stats to client"))
+                                .ok();
+                        }
+                        Err(e) => error!("Query stats error: {:?}", e),
+                    },
+                    FileEvent::Stat(path, sender) => {
+                        let result = async {
+                            let Some(entry) = query_folded(conn.as_mut(), &path, case).await?
+                            else {
+                                return Ok(None);
+                            };
+                            let children = if entry.is_dir() {
+                                Some(conn.count_children(entry.path()).await?)
+                            } else {
+                                None
+                            };
+                            Ok::<_, sqlx::Error>(Some((entry, children)))
+                        }
+                        .await;
+                        match result {
+                            Ok(stat) => {
+                                sender
+                                    .send(stat)
+                                    .inspect_err(|_| error!("Unable to send stat to client"))
+                                    .ok();
+                            }
+                            Err(e) => error!("Query stat error: {:?}", e),
+                        }
+                    }
+                    FileEvent::Chunks(path, sender) => {
+                        let result = async {
+                            let Some(entry) = query_folded(conn.as_mut(), &path, case).await?
+                            else {
+                                return Ok(None);
+                            };
+                            let Some((hash, chunk_size, chunks)) =
+                                conn.query_chunks(entry.path()).await?
+                            else {
+                                // Indexed before single chunk was stored, its chunk digest is
+                                // file digest
+                                let single = option.chunk_size().filter(|chunk_size| {
+                                    !entry.hash().is_empty() && entry.size() as u64 <= *chunk_size
+                                });
+                                return Ok(single.map(|chunk_size| {
+                                    FileChunks::new(
+                                        entry.path().to_string(),
+                                        entry.hash().to_string(),
+                                        entry.size(),
+                                        chunk_size as i64,
+                                        vec![entry.hash().to_string()],
+                                    )
+                                }));
+                            };
+                            // Chunks of older content are not pruned until next scan
+                            if hash != entry.hash() {
+                                return Ok(None);
+                            }
+                            Ok::<_, sqlx::Error>(Some(FileChunks::new(
+                                entry.path().to_string(),
+                                hash,
+                                entry.size(),
+                                chunk_size,
+                                chunks,
+                            )))
+                        }
+                        .await;
+                        match result {
+                            Ok(chunks) => {
+                                sender
+                                    .send(chunks)
+                                    .inspect_err(|_| error!("Unable to send chunks to client"))
+                                    .ok();
+                            }
+                            Err(e) => error!("Query chunks error: {:?}", e),
+                        }
+                    }
+                    FileEvent::IntegrityIssues(sender) => {
+                        match conn.query_integrity_issues().await {
+                            Ok(issues) => {
+                                sender
+                                    .send(issues)
+                                    .inspect_err(|_| error!("Unable to send issues to client"))
+                                    .ok();
+                            }
+                            Err(e) => error!("Query integrity issues error: {:?}", e),
+                        }
+                    }
+                    FileEvent::ListErrors(sender) => match conn.query_errors().await {
+                        Ok(errors) => {
+                            sender
+                                .send(errors)
+                                .inspect_err(|_| error!("Unable to send errors to client"))
+                                .ok();
+                        }
+                        Err(e) => error!("Query errors error: {:?}", e),
+                    },
+                    FileEvent::Access(record) => {
+                        conn.insert_access(record)
+                            .await
+                            .inspect_err(|e| error!("Insert access log error: {:?}", e))
+                            .ok();
+                    }
+                    FileEvent::AccessStats(since, limit, sender) => {
+                        match conn.query_access_stats(since, limit).await {
+                            Ok(stats) => {
+                                sender
+                                    .send(stats)
+                                    .inspect_err(|_| error!("Unable to send stats to client"))
+                                    .ok();
+                            }
+                            Err(e) => error!("Query access stats error: {:?}", e),
+                        }
+                    }
+                    FileEvent::RetryErrors(paths, sender) => {
+                        let paths = if paths.is_empty() {
+                            match conn.query_errors().await {
+                                Ok(errors) => errors
+                                    .into_iter()
+                                    .map(|error| error.path().to_string())
+                                    .collect(),
+                                Err(e) => {
+                                    error!("Query errors error: {:?}", e);
+                                    continue;
+                                }
+                            }
+                        } else {
+                            paths
+                        };
+                        let exclude = exclude.clone();
+                        retrying.spawn(async move {
+                            (read_retried(paths, exclude, option).await, sender)
+                        });
+                    }
+                    FileEvent::ListTokens(sender) => match conn.query_tokens().await {
+                        Ok(tokens) => {
+                            sender
+                                .send(tokens)
+                                .inspect_err(|_| error!("Unable to send tokens to client"))
+                                .ok();
+                        }
+                        Err(e) => error!("Query tokens error: {:?}", e),
+                    },
+                    FileEvent::CreateToken(token, sender) => {
+                        let result = if user_pool.read().await.contains_key(token.token()) {
+                            Err("Token already exists".to_string())
+                        } else {
+                            conn.insert_token(token)
+                                .await
+                                .map_err(|e| format!("Unable to insert token: {:?}", e))
+                        };
+                        if result.is_ok() {
+                            Self::refresh_pool(conn.as_mut(), &user_pool, &config_pool).await;
+                        }
+                        sender
+                            .send(result)
+                            .inspect_err(|_| error!("Unable to send created token to client"))
+                            .ok();
+                    }
+                    FileEvent::RevokeToken(id, sender) => {
+                        let revoked = conn
+                            .delete_token(id)
+                            .await
+                            .inspect_err(|e| error!("Unable to delete token {}: {:?}", id, e))
+                            .unwrap_or(false);
+                        if revoked {
+                            Self::refresh_pool(conn.as_mut(), &user_pool, &config_pool).await;
+                        }
+                        sender
+                            .send(revoked)
+                            .inspect_err(|_| error!("Unable to send revoke result to client"))
+                            .ok();
+                    }
+                    FileEvent::CreateHold(hold, sender) => {
+                        let result = conn
+                            .insert_hold(hold)
+                            .await
+                            .map_err(|e| format!("Unable to insert hold: {:?}", e));
+                        if result.is_ok() {
+                            Self::refresh_holds(conn.as_mut(), holds).await;
+                        }
+                        sender
+                            .send(result)
+                            .inspect_err(|_| error!("Unable to send created hold to client"))
+                            .ok();
+                    }
+                    FileEvent::ReleaseHold(path, sender) => {
+                        let released = conn
+                            .delete_hold(&path)
+                            .await
+                            .inspect_err(|e| error!("Unable to delete hold {}: {:?}", path, e))
+                            .unwrap_or(false);
+                        if released {
+                            Self::refresh_holds(conn.as_mut(), holds).await;
+                        }
+                        sender
+                            .send(released)
+                            .inspect_err(|_| error!("Unable to send release result to client"))
+                            .ok();
+                    }
+                    FileEvent::CreateLink(link, sender) => {
+                        let result = conn
+                            .insert_link(link)
+                            .await
+                            .map_err(|e| format!("Unable to insert link: {:?}", e));
+                        if result.is_ok() {
+                            Self::refresh_links(conn.as_mut(), links).await;
+                        }
+                        sender
+                            .send(result)
+                            .inspect_err(|_| error!("Unable to send created link to client"))
+                            .ok();
+                    }
+                    FileEvent::RevokeLink(token, id, sender) => {
+                        let revoked = conn
+                            .delete_link(&token, id)
+                            .await
+                            .inspect_err(|e| error!("Unable to delete link {}: {:?}", id, e))
+                            .unwrap_or(false);
+                        if revoked {
+                            Self::refresh_links(conn.as_mut(), links).await;
+                        }
+                        sender
+                            .send(revoked)
+                            .inspect_err(|_| error!("Unable to send revoke result to client"))
+                            .ok();
+                    }
+                    FileEvent::ConfigureUpdated(path) => match Configure::load(path)
+                        .await
+                        .and_then(|config| config.build_hashmap())
+                    {
+                        Ok(pool) => {
+                            config_pool = pool;
+                            Self::refresh_pool(conn.as_mut(), &user_pool, &config_pool).await;
+                        }
+                        Err(e) => {
+                            warn!("Unable to reload configure file: {:?}", e);
+                        }
+                    },
+                }
+                if batched {
+                    batch.add(conn.as_mut()).await;
+                }
+            }
+            batch.flush(conn.as_mut()).await;
+            Ok(())
+        }
+
+        pub fn start(
+            conn: Box<dyn Database>,
+            user_pool: Arc<RwPoolType>,
+            event_log: Option<EventLog>,
+            options: DaemonOptions,
+        ) -> (Self, FileEventHelper) {
+            let (helper, receiver) =
+                FileEventHelper::new(event_log.as_ref().map(|event_log| event_log.reader()));
+            let handler = Box::pin(Self::handler(
+                conn,
+                receiver,
+                user_pool,
+                event_log,
+                options,
+                Shared::of(&helper),
+            ));
+            (Self { handler }, helper)
+        }
+
+        /// Finish once terminate event is received
+        pub async fn run(self) -> anyhow::Result<()> {
+            self.handler.await
+        }
+    }
+}
+
+mod types {
+    use super::{
+        ChangeStream, EventLogReader, Holds, Jobs, Links, ScanProgress, Tiered, UploadSessions,
+    };
+    use crate::mount::Mounts;
+    use notify::{Event, EventKind};
+    use publib::normalize_path;
+    use publib::types::{
+        AccessRecord, AccessStats, ChangeEvent, DirStats, DuplicateReport, FileChunks, FileEntry,
+        HoldInfo, IndexError, IntegrityIssue, LinkInfo, ListOption, OptionFile, Pagination,
+        TokenInfo, UploadStatus,
+    };
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use tokio::sync::{broadcast, mpsc, oneshot};
+    use tracing::warn;
+
+    const CHANGE_BUFFER_SIZE: usize = 1024;
+
+    #[derive(Clone, Copy, Debug)]
+    pub enum ReportKind {
+        Largest {
+            limit: i64,
+        },
+        /// Files not modified since `before` (unix timestamp)
+        Stale {
+            before: i64,
+            limit: i64,
+        },
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct SearchQuery {
+        pub glob: String,
+        /// Last path of previous page, empty for first page
+        pub cursor: String,
+        pub limit: i64,
+    }
+
+    pub(super) enum FileEvent {
+        New(Vec<String>),
+        Update(Vec<String>),
+        Remove(Vec<String>),
+        /// Rename file or directory (from, to)
+        Rename(String, String),
+        /// Partial file renamed to final name by producer, indexed without settling
+        Completed(String),
+        ConfigureUpdated(String),
+        /// Request files (from https)
+        Request(Arc<[String]>, oneshot::Sender<Vec<OptionFile>>),
+        /// List files under authorized prefixes, `None` means all matched files (from https)
+        List(
+            Arc<[String]>,
+            ListOption,
+            Option<Pagination>,
+            oneshot::Sender<Vec<OptionFile>>,
+        ),
+        /// Duplicate files report, `None` means all files (from https)
+        Duplicates(
+            Option<Arc<[String]>>,
+            Pagination,
+            oneshot::Sender<DuplicateReport>,
+        ),
+        /// Housekeeping report under authorized prefixes (from https)
+        Report(Arc<[String]>, ReportKind, oneshot::Sender<Vec<OptionFile>>),
+        /// Paths match glob under authorized prefixes, page after cursor (from https)
+        Search(Arc<[String]>, SearchQuery, oneshot::Sender<Vec<OptionFile>>),
+        /// Files with content hash under authorized prefixes (from https)
+        Hash(String, Arc<[String]>, oneshot::Sender<Vec<OptionFile>>),
+        /// Aggregate stats of directories (from https)
+        Stats(Arc<[String]>, oneshot::Sender<Vec<DirStats>>),
+        /// Entry of path and count of its children if it is a directory, `None` if path is
+        /// not indexed (from https)
+        Stat(String, oneshot::Sender<Option<(FileEntry, Option<i64>)>>),
+        /// Chunk hashes of file, `None` if not computed for current content (from https)
+        Chunks(String, oneshot::Sender<Option<FileChunks>>),
+        /// Status of upload by id, `None` if not found or pruned (from https)
+        Upload(u64, oneshot::Sender<Option<UploadStatus>>),
+        /// File appended through server, entry contains incremental hash, pending status of
+        /// upload is stored and its id is sent back before entry is applied (from https)
+        Appended(FileEntry, UploadStatus, oneshot::Sender<Option<u64>>),
+        /// File of finished upload session is renamed into place, entry contains verified
+        /// hash, status of upload is stored like `Appended` (from https)
+        Uploaded(FileEntry, UploadStatus, oneshot::Sender<Option<u64>>),
+        /// Scan working directory in background, progress is reported to job (from https)
+        Rescan(u64),
+        /// Events under path are lost, scan it again even if scan is running (from watcher)
+        RescanPath(String),
+        /// Tokens stored in database (from https)
+        ListTokens(oneshot::Sender<Vec<TokenInfo>>),
+        /// Store token and refresh user pool, `Err` if token already exists (from https)
+        CreateToken(TokenInfo, oneshot::Sender<Result<TokenInfo, String>>),
+        /// Delete token by id and refresh user pool, `false` if not found (from https)
+        RevokeToken(i64, oneshot::Sender<bool>),
+        /// Store legal hold and refresh shared holds (from https)
+        CreateHold(HoldInfo, oneshot::Sender<Result<(), String>>),
+        /// Delete legal hold of path, `false` if path is not held (from https)
+        ReleaseHold(String, oneshot::Sender<bool>),
+        /// Store signed link and refresh shared links (from https)
+        CreateLink(LinkInfo, oneshot::Sender<Result<LinkInfo, String>>),
+        /// Delete link by token and id, `false` if not found (from https)
+        RevokeLink(String, i64, oneshot::Sender<bool>),
+        /// Hash mismatches found by scrub (from https)
+        IntegrityIssues(oneshot::Sender<Vec<IntegrityIssue>>),
+        /// Paths failed to be indexed (from https)
+        ListErrors(oneshot::Sender<Vec<IndexError>>),
+        /// Index paths again, all recorded paths if empty, errors left are returned
+        /// (from https)
+        RetryErrors(Vec<String>, oneshot::Sender<Vec<IndexError>>),
+        /// Finished download of `/file` (from https)
+        Access(AccessRecord),
+        /// Access log since timestamp, at most `limit` top files (from https)
+        AccessStats(i64, i64, oneshot::Sender<AccessStats>),
+        /// Tiered file is copied back to its place (from https)
+        Recalled(String),
+        /// Tiered file is deleted, cold copy is dropped before its row is removed (from https)
+        Untier(String),
+        Terminate,
+        Unknown,
+    }
+
+    impl FileEvent {
+        /// Changes will be published after this event applied
+        pub(super) fn changes(&self) -> Vec<ChangeEvent> {
+            match self {
+                FileEvent::New(paths) => paths
+                    .iter()
+                    .map(|path| ChangeEvent::New { path: path.clone() })
+                    .collect(),
+                FileEvent::Update(paths) => paths
+                    .iter()
+                    .map(|path| ChangeEvent::Update { path: path.clone() })
+                    .collect(),
+                FileEvent::Remove(paths) => paths
+                    .iter()
+                    .map(|path| ChangeEvent::Remove { path: path.clone() })
+                    .collect(),
+                FileEvent::Rename(from, to) => vec![ChangeEvent::Rename {
+                    from: from.clone(),
+                    to: to.clone(),
+                }],
+                FileEvent::Completed(path) => vec![ChangeEvent::New { path: path.clone() }],
+                _ => Vec::new(),
+            }
+        }
+    }
+
+    fn convert(paths: Vec<PathBuf>) -> Vec<String> {
+        let mounts = Mounts::global();
+        paths.iter().map(|path| mounts.to_index(path)).collect()
+    }
+
+    /// Paths of event pushed by client are normalized before applied
+    impl From<ChangeEvent> for FileEvent {
+        fn from(value: ChangeEvent) -> Self {
+            match value {
+                ChangeEvent::New { path } => Self::New(vec![normalize_path(path)]),
+                ChangeEvent::Update { path } => Self::Update(vec![normalize_path(path)]),
+                ChangeEvent::Remove { path } => Self::Remove(vec![normalize_path(path)]),
+                ChangeEvent::Rename { from, to } => {
+                    Self::Rename(normalize_path(from), normalize_path(to))
+                }
+            }
+        }
+    }
+
+    impl From<Event> for FileEvent {
+        fn from(value: Event) -> Self {
+            let paths = convert(value.paths);
+            match value.kind {
+                EventKind::Create(_) => Self::New(paths),
+                EventKind::Modify(_) => Self::Update(paths),
+                EventKind::Remove(_) => Self::Remove(paths),
+                _ => Self::Unknown,
+            }
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct FileEventHelper {
+        upstream: mpsc::Sender<FileEvent>,
+        changes: broadcast::Sender<ChangeEvent>,
+        jobs: Jobs,
+        progress: ScanProgress,
+        holds: Holds,
+        links: Links,
+        upload_sessions: UploadSessions,
+        tiered: Tiered,
+        event_log: Option<EventLogReader>,
+    }
+
+    impl FileEventHelper {
+        pub(super) fn new(event_log: Option<EventLogReader>) -> (Self, mpsc::Receiver<FileEvent>) {
+            let (sender, receiver) = mpsc::channel(2048);
+            let (changes, _) = broadcast::channel(CHANGE_BUFFER_SIZE);
+            (
+                Self {
+                    upstream: sender,
+                    changes,
+                    jobs: Jobs::default(),
+                    progress: ScanProgress::default(),
+                    holds: Holds::default(),
+                    links: Links::default(),
+                    upload_sessions: UploadSessions::default(),
+                    tiered: Tiered::default(),
+                    event_log,
+                },
+                receiver,
+            )
+        }
+
+        /// Helper without file daemon, events are received and dropped, used by handler tests
+        #[cfg(test)]
+        pub fn detached() -> Self {
+            let (helper, mut receiver) = Self::new(None);
+            tokio::spawn(async move { while receiver.recv().await.is_some() {} });
+            helper
+        }
+
+        /// Like `detached`, but requested paths are answered from `entries` as if indexed,
+        /// search matches pattern as substring of path
+        #[cfg(test)]
+        pub fn serving(mut entries: Vec<FileEntry>) -> Self {
+            let (helper, mut receiver) = Self::new(None);
+            entries.sort_by(|a, b| a.path().cmp(b.path()));
+            tokio::spawn(async move {
+                while let Some(event) = receiver.recv().await {
+                    match event {
+                        FileEvent::Request(paths, sender) => {
+                            let files = paths
+                                .iter()
+                                .map(|path| {
+                                    let path = normalize_path(path);
+                                    let entry = entries.iter().find(|entry| entry.path() == path);
+                                    OptionFile::from_option_entry(path, entry.cloned())
+                                })
+                                .collect();
+                            sender.send(files).ok();
+                        }
+                        FileEvent::Search(_, query, sender) => {
+                            let files = entries
+                                .iter()
+                                .filter(|entry| {
+                                    entry.path() > query.cursor.as_str()
+                                        && entry.path().contains(&query.glob)
+                                })
+                                .take(query.limit as usize)
+                                .cloned()
+                                .map(OptionFile::from)
+                                .collect();
+                            sender.send(files).ok();
+                        }
+                        _ => {}
+                    }
+                }
+            });
+            helper
+        }
+
+        pub(super) fn change_sender(&self) -> broadcast::Sender<ChangeEvent> {
+            self.changes.clone()
+        }
+
+        pub fn jobs(&self) -> &Jobs {
+            &self.jobs
+        }
+
+        pub fn scan_progress(&self) -> &ScanProgress {
+            &self.progress
+        }
+
+        pub fn holds(&self) -> &Holds {
+            &self.holds
+        }
+
+        pub fn links(&self) -> &Links {
+            &self.links
+        }
+
+        pub fn upload_sessions(&self) -> &UploadSessions {
+            &self.upload_sessions
+        }
+
+        pub fn tiered(&self) -> &Tiered {
+            &self.tiered
+        }
+
+        /// `None` if event log is disabled
+        pub fn event_log(&self) -> Option<&EventLogReader> {
+            self.event_log.as_ref()
+        }
+
+        /// Events waiting to be handled by file daemon
+        pub fn queue_depth(&self) -> usize {
+            self.upstream.max_capacity() - self.upstream.capacity()
+        }
+
+        /// Subscribe changes applied by file daemon
+        pub fn subscribe(&self) -> ChangeStream {
+            ChangeStream::new(self.changes.subscribe())
+        }
+
+        pub(super) async fn send(&self, event: Event) -> Option<()> {
+            self.upstream.send(event.into()).await.ok()
+        }
+
+        pub(super) async fn send_event(&self, event: FileEvent) -> Option<()> {
+            self.upstream.send(event).await.ok()
+        }
+
+        /// Apply changes produced outside of file watcher (from https)
+        pub async fn send_changes(&self, events: Vec<ChangeEvent>) -> Option<()> {
+            for event in events {
+                self.upstream.send(event.into()).await.ok()?;
+            }
+            Some(())
+        }
+
+        pub async fn send_configure_updated(&self, path: String) -> Option<()> {
+            self.upstream
+                .send(FileEvent::ConfigureUpdated(path))
+                .await
+                .ok()
+        }
+
+        /// Called when download finished, record is dropped if daemon is busy
+        pub fn record_access(&self, record: AccessRecord) {
+            self.upstream
+                .try_send(FileEvent::Access(record))
+                .inspect_err(|e| warn!("Unable to record access: {}", e))
+                .ok();
+        }
+
+        pub async fn send_access_stats(
+            &self,
+            since: i64,
+            limit: i64,
+        ) -> Option<oneshot::Receiver<AccessStats>> {
+            let (sender, receiver) = oneshot::channel();
+            self.upstream
+                .send(FileEvent::AccessStats(since, limit, sender))
+                .await
+                .ok()?;
+            Some(receiver)
+        }
+
+        pub async fn send_recalled(&self, path: String) -> Option<()> {
+            self.upstream.send(FileEvent::Recalled(path)).await.ok()
+        }
+
+        pub async fn send_untier(&self, path: String) -> Option<()> {
+            self.upstream.send(FileEvent::Untier(path)).await.ok()
+        }
+
+        pub async fn send_terminate(&self) -> Option<()> {
+            self.upstream.send(FileEvent::Terminate).await.ok()
+        }
+
+        /// Authorized prefixes of token can be sent without copying
+        pub async fn send_request(
+            &self,
+            paths: Arc<[String]>,
+        ) -> Option<oneshot::Receiver<Vec<OptionFile>>> {
+            let (sender, receiver) = oneshot::channel();
+            self.upstream
+                .send(FileEvent::Request(paths, sender))
+                .await
+                .ok()?;
+            Some(receiver)
+        }
+
+        pub async fn send_list(
+            &self,
+            paths: Arc<[String]>,
+            option: ListOption,
+            page: Option<Pagination>,
+        ) -> Option<oneshot::Receiver<Vec<OptionFile>>> {
+            let (sender, receiver) = oneshot::channel();
+            self.upstream
+                .send(FileEvent::List(paths, option, page, sender))
+                .await
+                .ok()?;
+            Some(receiver)
+        }
+
+        pub async fn send_duplicates(
+            &self,
+            paths: Option<Arc<[String]>>,
+            page: Pagination,
+        ) -> Option<oneshot::Receiver<DuplicateReport>> {
+            let (sender, receiver) = oneshot::channel();
+            self.upstream
+                .send(FileEvent::Duplicates(paths, page, sender))
+                .await
+                .ok()?;
+            Some(receiver)
+        }
+
+        pub async fn send_report(
+            &self,
+            paths: Arc<[String]>,
+            kind: ReportKind,
+        ) -> Option<oneshot::Receiver<Vec<OptionFile>>> {
+            let (sender, receiver) = oneshot::channel();
+            self.upstream
+                .send(FileEvent::Report(paths, kind, sender))
+                .await
+                .ok()?;
+            Some(receiver)
+        }
+
+        pub async fn send_search(
+            &self,
+            paths: Arc<[String]>,
+            query: SearchQuery,
+        ) -> Option<oneshot::Receiver<Vec<OptionFile>>> {
+            let (sender, receiver) = oneshot::channel();
+            self.upstream
+                .send(FileEvent::Search(paths, query, sender))
+                .await
+                .ok()?;
+            Some(receiver)
+        }
+
+        pub async fn send_hash(
+            &self,
+            hash: String,
+            paths: Arc<[String]>,
+        ) -> Option<oneshot::Receiver<Vec<OptionFile>>> {
+            let (sender, receiver) = oneshot::channel();
+            self.upstream
+                .send(FileEvent::Hash(hash, paths, sender))
+                .await
+                .ok()?;
+            Some(receiver)
+        }
+
+        pub async fn send_stats(
+            &self,
+            paths: Arc<[String]>,
+        ) -> Option<oneshot::Receiver<Vec<DirStats>>> {
+            let (sender, receiver) = oneshot::channel();
+            self.upstream
+                .send(FileEvent::Stats(paths, sender))
+                .await
+                .ok()?;
+            Some(receiver)
+        }
+
+        pub async fn send_stat(
+            &self,
+            path: String,
+        ) -> Option<oneshot::Receiver<Option<(FileEntry, Option<i64>)>>> {
+            let (sender, receiver) = oneshot::channel();
+            self.upstream
+                .send(FileEvent::Stat(path, sender))
+                .await
+                .ok()?;
+            Some(receiver)
+        }
+
+        pub async fn send_chunks(
+            &self,
+            path: String,
+        ) -> Option<oneshot::Receiver<Option<FileChunks>>> {
+            let (sender, receiver) = oneshot::channel();
+            self.upstream
+                .send(FileEvent::Chunks(path, sender))
+                .await
+                .ok()?;
+            Some(receiver)
+        }
+
+        pub async fn send_integrity_issues(
+            &self,
+        ) -> Option<oneshot::Receiver<Vec<IntegrityIssue>>> {
+            let (sender, receiver) = oneshot::channel();
+            self.upstream
+                .send(FileEvent::IntegrityIssues(sender))
+                .await
+                .ok()?;
+            Some(receiver)
+        }
+
+        pub async fn send_list_errors(&self) -> Option<oneshot::Receiver<Vec<IndexError>>> {
+            let (sender, receiver) = oneshot::channel();
+            self.upstream
+                .send(FileEvent::ListErrors(sender))
+                .await
+                .ok()?;
+            Some(receiver)
+        }
+
+        pub async fn send_retry_errors(
+            &self,
+            paths: Vec<String>,
+        ) -> Option<oneshot::Receiver<Vec<IndexError>>> {
+            let (sender, receiver) = oneshot::channel();
+            self.upstream
+                .send(FileEvent::RetryErrors(paths, sender))
+                .await
+                .ok()?;
+            Some(receiver)
+        }
+
+        pub async fn send_list_tokens(&self) -> Option<oneshot::Receiver<Vec<TokenInfo>>> {
+            let (sender, receiver) = oneshot::channel();
+            self.upstream
+                .send(FileEvent::ListTokens(sender))
+                .await
+                .ok()?;
+            Some(receiver)
+        }
+
+        pub async fn send_create_token(
+            &self,
+            token: TokenInfo,
+        ) -> Option<oneshot::Receiver<Result<TokenInfo, String>>> {
+            let (sender, receiver) = oneshot::channel();
+            self.upstream
+                .send(FileEvent::CreateToken(token, sender))
+                .await
+                .ok()?;
+            Some(receiver)
+        }
+
+        pub async fn send_revoke_token(&self, id: i64) -> Option<oneshot::Receiver<bool>> {
+            let (sender, receiver) = oneshot::channel();
+            self.upstream
+                .send(FileEvent::RevokeToken(id, sender))
+                .await
+                .ok()?;
+            Some(receiver)
+        }
+
+        pub async fn send_create_hold(
+            &self,
+            hold: HoldInfo,
+        ) -> Option<oneshot::Receiver<Result<(), String>>> {
+            let (sender, receiver) = oneshot::channel();
+            self.upstream
+                .send(FileEvent::CreateHold(hold, sender))
+                .await
+                .ok()?;
+            Some(receiver)
+        }
+
+        pub async fn send_release_hold(&self, path: String) -> Option<oneshot::Receiver<bool>> {
+            let (sender, receiver) = oneshot::channel();
+            self.upstream
+                .send(FileEvent::ReleaseHold(path, sender))
+                .await
+                .ok()?;
+            Some(receiver)
+        }
+
+        pub async fn send_create_link(
+            &self,
+            link: LinkInfo,
+        ) -> Option<oneshot::Receiver<Result<LinkInfo, String>>> {
+            let (sender, receiver) = oneshot::channel();
+            self.upstream
+                .send(FileEvent::CreateLink(link, sender))
+                .await
+                .ok()?;
+            Some(receiver)
+        }
+
+        pub async fn send_revoke_link(
+            &self,
+            token: String,
+            id: i64,
+        ) -> Option<oneshot::Receiver<bool>> {
+            let (sender, receiver) = oneshot::channel();
+            self.upstream
+                .send(FileEvent::RevokeLink(token, id, sender))
+                .await
+                .ok()?;
+            Some(receiver)
+        }
+
+        pub async fn send_upload(
+            &self,
+            id: u64,
+        ) -> Option<oneshot::Receiver<Option<UploadStatus>>> {
+            let (sender, receiver) = oneshot::channel();
+            self.upstream
+                .send(FileEvent::Upload(id, sender))
+                .await
+                .ok()?;
+            Some(receiver)
+        }
+
+        /// Receiver gets id of upload status, `None` if it is not stored
+        pub async fn send_appended(
+            &self,
+            entry: FileEntry,
+            upload: UploadStatus,
+        ) -> Option<oneshot::Receiver<Option<u64>>> {
+            let (sender, receiver) = oneshot::channel();
+            self.upstream
+                .send(FileEvent::Appended(entry, upload, sender))
+                .await
+                .ok()?;
+            Some(receiver)
+        }
+
+        /// Receiver gets id of upload status, `None` if it is not stored
+        pub async fn send_uploaded(
+            &self,
+            entry: FileEntry,
+            upload: UploadStatus,
+        ) -> Option<oneshot::Receiver<Option<u64>>> {
+            let (sender, receiver) = oneshot::channel();
+            self.upstream
+                .send(FileEvent::Uploaded(entry, upload, sender))
+                .await
+                .ok()?;
+            Some(receiver)
+        }
+
+        /// Return job id of scan, `kind` is shown in job status
+        pub async fn send_rescan(&self, kind: &str) -> Option<u64> {
+            let id = self.jobs.create(kind);
+            if self.upstream.send(FileEvent::Rescan(id)).await.is_err() {
+                self.jobs.update(id, |job| {
+                    job.finish(
+                        super::jobs::unix_now(),
+                        Some("File daemon stopped".to_string()),
+                    )
+                });
+                return None;
+            }
+            Some(id)
+        }
+    }
+}
+
+mod watcher {
+    use crate::configure::current::{WatcherBackend, WatcherOption};
+    use crate::configure::ExcludeSet;
+    use crate::file::types::{FileEvent, FileEventHelper};
+    use crate::mount::Mounts;
+    use notify::event::{CreateKind, ModifyKind, RenameMode};
+    use notify::{Event, EventKind, PollWatcher, RecursiveMode, Watcher};
+    use publib::types::ExitExt;
+    use std::future::Future;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread::JoinHandle;
+    use std::time::{Duration, Instant};
+    use tap::TapOptional;
+    use tracing::{error, info, warn};
+
+    /// File systems don't deliver inotify events for changes made by other hosts
+    const NETWORK_FILE_SYSTEMS: &[&str] = &[
+        "nfs",
+        "nfs4",
+        "cifs",
+        "smb3",
+        "smbfs",
+        "9p",
+        "fuse.sshfs",
+        "ceph",
+        "glusterfs",
+    ];
+
+    /// Type of file system mounted at nearest mount point of `path` from `/proc/mounts`
+    fn file_system_type(path: &Path) -> Option<String> {
+        let path = path.canonicalize().ok()?;
+        let mounts = std::fs::read_to_string("/proc/mounts").ok()?;
+        mounts
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let (_, mount_point, fs_type) = (fields.next()?, fields.next()?, fields.next()?);
+                // Space in mount point is escaped as `\040`
+                let mount_point = PathBuf::from(mount_point.replace("\\040", " "));
+                path.starts_with(&mount_point)
+                    .then(|| (mount_point.as_os_str().len(), fs_type.to_string()))
+            })
+            .max_by_key(|(length, _)| *length)
+            .map(|(_, fs_type)| fs_type)
+    }
+
+    /// Resolve `Auto` by file system of `path`
+    fn select_backend(option: &WatcherOption, path: &Path) -> WatcherBackend {
+        match option.backend() {
+            WatcherBackend::Auto => match file_system_type(path) {
+                Some(fs_type) if NETWORK_FILE_SYSTEMS.contains(&fs_type.as_str()) => {
+                    info!(
+                        "[file watcher] Working directory is on {}, poll changes",
+                        fs_type
+                    );
+                    WatcherBackend::Poll
+                }
+                _ => WatcherBackend::Inotify,
+            },
+            backend => backend,
+        }
+    }
+
+    /// If `RenameMode::To` not arrived in this duration, treat `RenameMode::From` as remove
+    const RENAME_PAIR_TIMEOUT: Duration = Duration::from_millis(500);
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    /// Index path, path under mount is prefixed with mount name
+    fn path_to_string(path: &Path) -> String {
+        Mounts::global().to_index(path)
+    }
+
+    /// Event of renaming index path `from` to `to`, excluded side is treated as missing
+    fn rename_event(from: String, to: String, exclude: &ExcludeSet) -> Option<FileEvent> {
+        match (exclude.is_match(&from), exclude.is_match(&to)) {
+            (false, false) => Some(FileEvent::Rename(from, to)),
+            (true, false) if exclude.is_partial(&from) => Some(FileEvent::Completed(to)),
+            (true, false) => Some(FileEvent::New(vec![to])),
+            (false, true) => Some(FileEvent::Remove(vec![from])),
+            (true, true) => None,
+        }
+    }
+
+    /// Pair `RenameMode::From` and `RenameMode::To` event by tracker, `RenameMode::Both`
+    /// is used if backend does not send them
+    #[derive(Debug, Default)]
+    pub(super) struct RenameTracker {
+        pending: Option<(Option<usize>, PathBuf, Instant)>,
+        /// Last pair sent, `Both` event following it is the same rename
+        paired: Option<(PathBuf, PathBuf)>,
+    }
+
+    impl RenameTracker {
+        /// Take pending `From` event, send it as remove event
+        fn flush(&mut self, upstream: &FileEventHelper, exclude: &ExcludeSet) {
+            if let Some((_, from, _)) = self.pending.take() {
+                let from = path_to_string(&from);
+                if exclude.is_match(&from) {
+                    return;
+                }
+                block_on(upstream.send_event(FileEvent::Remove(vec![from])))
+                    .tap_none(|| warn!("Unable send event to file daemon"));
+            }
+        }
+
+        fn flush_timeout(&mut self, upstream: &FileEventHelper, exclude: &ExcludeSet) {
+            let expired = self
+                .pending
+                .as_ref()
+                .is_some_and(|(_, _, instant)| instant.elapsed() > RENAME_PAIR_TIMEOUT);
+            if expired {
+                self.flush(upstream, exclude);
+            }
+        }
+
+        pub(super) fn from(
+            &mut self,
+            tracker: Option<usize>,
+            path: PathBuf,
+            upstream: &FileEventHelper,
+            exclude: &ExcludeSet,
+        ) {
+            self.flush(upstream, exclude);
+            self.paired = None;
+            self.pending = Some((tracker, path, Instant::now()));
+        }
+
+        pub(super) fn to(
+            &mut self,
+            tracker: Option<usize>,
+            path: PathBuf,
+            upstream: &FileEventHelper,
+            exclude: &ExcludeSet,
+        ) {
+            let event = match self.pending.take() {
+                Some((from_tracker, from, _)) if tracker.is_some() && from_tracker == tracker => {
+                    let event = rename_event(path_to_string(&from), path_to_string(&path), exclude);
+                    self.paired = Some((from, path));
+                    match event {
+                        Some(event) => event,
+                        None => return,
+                    }
+                }
+                pending => {
+                    self.pending = pending;
+                    self.flush(upstream, exclude);
+                    let path = path_to_string(&path);
+                    if exclude.is_match(&path) {
+                        return;
+                    }
+                    FileEvent::New(vec![path])
+                }
+            };
+            block_on(upstream.send_event(event))
+                .tap_none(|| warn!("Unable send event to file daemon"));
+        }
+
+        /// Rename with both paths, skipped if `From` and `To` of it were paired already
+        pub(super) fn both(
+            &mut self,
+            from: PathBuf,
+            to: PathBuf,
+            upstream: &FileEventHelper,
+            exclude: &ExcludeSet,
+        ) {
+            if self
+                .paired
+                .take()
+                .is_some_and(|(paired_from, paired_to)| paired_from == from && paired_to == to)
+            {
+                return;
+            }
+            if self
+                .pending
+                .as_ref()
+                .is_some_and(|(_, pending, _)| *pending == from)
+            {
+                self.pending = None;
+            }
+            self.flush(upstream, exclude);
+            if let Some(event) = rename_event(path_to_string(&from), path_to_string(&to), exclude) {
+                block_on(upstream.send_event(event))
+                    .tap_none(|| warn!("Unable send event to file daemon"));
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct FileWatcher {
+        handler: JoinHandle<Result<(), notify::Error>>,
+        exit_shot: Arc<AtomicBool>,
+    }
+
+    impl FileWatcher {
+        fn watcher<P: AsRef<Path>>(
+            path: P,
+            config_path: PathBuf,
+            exit_signal: Arc<AtomicBool>,
+            upstream: FileEventHelper,
+            exclude: Arc<ExcludeSet>,
+            option: WatcherOption,
+        ) -> Result<(), notify::Error> {
+            let sub_path = config_path.clone();
+            let tracker = Arc::new(Mutex::new(RenameTracker::default()));
+            let (sub_tracker, sub_upstream, sub_exclude) =
+                (tracker.clone(), upstream.clone(), exclude.clone());
+            let root = path_to_string(path.as_ref());
+            let handler = move |res: notify::Result<Event>| match res {
+                // Inotify queue overflowed, events in it are dropped by kernel
+                Ok(event) if event.need_rescan() => {
+                    warn!("[file watcher] Event queue overflowed, request rescan");
+                    block_on(upstream.send_event(FileEvent::RescanPath(root.clone())))
+                        .tap_none(|| warn!("Unable send event to file daemon"));
+                }
+                Ok(event) => {
+                    Self::event_handler(
+                        event,
+                        &upstream,
+                        &config_path,
+                        &exclude,
+                        &mut tracker.lock().unwrap(),
+                    );
+                }
+                Err(e) => {
+                    warn!("[file watcher] Watcher got error: {:?}", e);
+                }
+            };
+            let mut watcher: Box<dyn Watcher> = match select_backend(&option, path.as_ref()) {
+                WatcherBackend::Poll => Box::new(PollWatcher::new(
+                    handler,
+                    notify::Config::default().with_poll_interval(option.poll_interval()),
+                )?),
+                _ => Box::new(notify::recommended_watcher(handler)?),
+            };
+            watcher
+                .watch(sub_path.as_ref(), RecursiveMode::NonRecursive)
+                .inspect_err(|e| {
+                    error!("[file watcher] Unable to watch configure file: {:?}", e)
+                })?;
+            watcher
+                .watch(path.as_ref(), RecursiveMode::Recursive)
+                .inspect_err(|e| error!("[file watcher] Unable to watch directory: {:?}", e))?;
+            for (name, mount) in Mounts::global().roots() {
+                watcher
+                    .watch(mount, RecursiveMode::Recursive)
+                    .inspect_err(|e| {
+                        error!("[file watcher] Unable to watch mount {}: {:?}", name, e)
+                    })?;
+            }
+
+            loop {
+                if exit_signal.load(Ordering::Relaxed) {
+                    break;
+                }
+                sub_tracker
+                    .lock()
+                    .unwrap()
+                    .flush_timeout(&sub_upstream, &sub_exclude);
+                std::thread::sleep(Duration::from_millis(10));
+            }
+
+            watcher
+                .unwatch(path.as_ref())
+                .inspect_err(|e| error!("[file watcher] Unable to unwatch directory: {:?}", e))?;
+            for (_, mount) in Mounts::global().roots() {
+                watcher.unwatch(mount).ok();
+            }
+            Ok(())
+        }
+
+        fn event_handler(
+            mut event: Event,
+            upstream: &FileEventHelper,
+            configure: &PathBuf,
+            exclude: &ExcludeSet,
+            tracker: &mut RenameTracker,
+        ) {
+            if let EventKind::Modify(notify::event::ModifyKind::Data(
+                notify::event::DataChange::Any,
+            )) = event.kind
+            {
+                for file in event.paths.iter() {
+                    if configure.eq(file) {
+                        block_on(
+                            upstream
+                                .send_configure_updated(configure.to_str().unwrap().to_string()),
+                        )
+                        .tap_none(|| warn!("Unable send event to file daemon"));
+                        break;
+                    }
+                }
+            }
+
+            match event.kind {
+                EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                    if let Some(path) = event.paths.into_iter().next() {
+                        tracker.from(event.attrs.tracker(), path, upstream, exclude);
+                    }
+                    return;
+                }
+                EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                    if let Some(path) = event.paths.into_iter().next() {
+                        tracker.to(event.attrs.tracker(), path, upstream, exclude);
+                    }
+                    return;
+                }
+                EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                    let mut paths = event.paths.into_iter();
+                    if let (Some(from), Some(to)) = (paths.next(), paths.next()) {
+                        tracker.both(from, to, upstream, exclude);
+                    }
+                    return;
+                }
+                _ => tracker.flush(upstream, exclude),
+            }
+
+            let is_dir = matches!(event.kind, EventKind::Create(CreateKind::Folder));
+            event
+                .paths
+                .retain(|path| !exclude.is_match_entry(path_to_string(path), is_dir));
+            if event.paths.is_empty() {
+                return;
+            }
+
+            match event.kind {
+                EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) => {
+                    block_on(upstream.send(event))
+                        .tap_none(|| warn!("Unable send event to file daemon"));
+                }
+                _ => {}
+            }
+        }
+
+        pub fn start<P: AsRef<Path> + Send + 'static>(
+            path: P,
+            config_path: PathBuf,
+            event_helper: FileEventHelper,
+            exclude: Arc<ExcludeSet>,
+            option: WatcherOption,
+        ) -> Self {
+            let signal = Arc::new(AtomicBool::new(false));
+            let signal2 = Arc::clone(&signal);
+            let handler = std::thread::spawn(move || {
+                Self::watcher(path, config_path, signal, event_helper, exclude, option)
+            });
+            Self::new(handler, signal2)
+        }
+
+        fn new(handler: JoinHandle<Result<(), notify::Error>>, exit_shot: Arc<AtomicBool>) -> Self {
+            Self { handler, exit_shot }
+        }
+    }
+
+    impl ExitExt for FileWatcher {
+        fn _send_terminate(&self) -> Option<()> {
+            self.exit_shot.store(true, Ordering::Relaxed);
+            Some(())
+        }
+
+        fn is_finished(&self) -> bool {
+            self.handler.is_finished()
+        }
+    }
+}
+
+mod archive {
+    use crate::configure::ExcludeSet;
+    use crate::crypto::{is_encrypted, plaintext_len, spawn_decrypt, EncryptionKey};
+    use crate::mount::Mounts;
+    use async_compression::tokio::write::ZstdEncoder;
+    use async_walkdir::WalkDir;
+    use futures::StreamExt;
+    use serde_derive::Deserialize;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use tokio::io::{AsyncWrite, AsyncWriteExt, DuplexStream};
+    use tracing::warn;
+
+    const ARCHIVE_BUFFER_SIZE: usize = 64 * 1024;
+
+    #[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+    pub enum ArchiveFormat {
+        #[default]
+        #[serde(rename = "tar")]
+        Tar,
+        #[serde(rename = "tar.zst")]
+        TarZst,
+    }
+
+    impl ArchiveFormat {
+        pub const ALL: [ArchiveFormat; 2] = [ArchiveFormat::Tar, ArchiveFormat::TarZst];
+
+        pub fn extension(&self) -> &'static str {
+            match self {
+                ArchiveFormat::Tar => "tar",
+                ArchiveFormat::TarZst => "tar.zst",
+            }
+        }
+
+        pub fn content_type(&self) -> &'static str {
+            match self {
+                ArchiveFormat::Tar => "application/x-tar",
+                ArchiveFormat::TarZst => "application/zstd",
+            }
+        }
+    }
+
+    /// Append decrypted file, header is built from metadata with plaintext size
+    async fn append_encrypted<W: AsyncWrite + Unpin + Send + Sync + 'static>(
+        builder: &mut tokio_tar::Builder<W>,
+        path: &Path,
+        name: &Path,
+        key: &EncryptionKey,
+    ) -> std::io::Result<()> {
+        let metadata = tokio::fs::metadata(path).await?;
+        let size = plaintext_len(metadata.len()).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Invalid encrypted file length",
+            )
+        })?;
+        let mut header = tokio_tar::Header::new_gnu();
+        header.set_metadata(&metadata);
+        header.set_size(size);
+        builder
+            .append_data(
+                &mut header,
+                name,
+                spawn_decrypt(path.to_path_buf(), key.clone()),
+            )
+            .await
+    }
+
+    async fn write_tar<W: AsyncWrite + Unpin + Send + Sync + 'static>(
+        writer: W,
+        root: &Path,
+        exclude: &ExcludeSet,
+        key: Option<&EncryptionKey>,
+    ) -> std::io::Result<W> {
+        let base = root.parent().unwrap_or(Path::new(""));
+        let mut builder = tokio_tar::Builder::new(writer);
+        builder.follow_symlinks(false);
+        builder
+            .append_dir(root.strip_prefix(base).unwrap(), root)
+            .await?;
+
+        let mut entries = WalkDir::new(root);
+        while let Some(entry) = entries.next().await {
+            let entry = entry?;
+            let path = entry.path();
+            let is_dir = entry.file_type().await?.is_dir();
+            if exclude.is_match_entry(Mounts::global().to_index(&path), is_dir) {
+                continue;
+            }
+            let name = path.strip_prefix(base).unwrap();
+            if is_dir {
+                builder.append_dir(name, &path).await?;
+            } else if let Some(key) = key.filter(|_| path.is_file()) {
+                if is_encrypted(&path).await? {
+                    append_encrypted(&mut builder, &path, name, key).await?;
+                } else {
+                    builder.append_path_with_name(&path, name).await?;
+                }
+            } else {
+                builder.append_path_with_name(&path, name).await?;
+            }
+        }
+        builder.into_inner().await
+    }
+
+    async fn write_archive(
+        writer: DuplexStream,
+        root: PathBuf,
+        format: ArchiveFormat,
+        exclude: Arc<ExcludeSet>,
+        key: Option<EncryptionKey>,
+    ) -> std::io::Result<()> {
+        let key = key.as_ref();
+        match format {
+            ArchiveFormat::Tar => {
+                write_tar(writer, &root, &exclude, key)
+                    .await?
+                    .shutdown()
+                    .await
+            }
+            ArchiveFormat::TarZst => {
+                write_tar(ZstdEncoder::new(writer), &root, &exclude, key)
+                    .await?
+                    .shutdown()
+                    .await
+            }
+        }
+    }
+
+    /// Build archive of `root` in background task, return reader side of archive stream
+    pub fn spawn_archive(
+        root: PathBuf,
+        format: ArchiveFormat,
+        exclude: Arc<ExcludeSet>,
+        key: Option<EncryptionKey>,
+    ) -> DuplexStream {
+        let (writer, reader) = tokio::io::duplex(ARCHIVE_BUFFER_SIZE);
+        tokio::spawn(async move {
+            let shown = root.display().to_string();
+            if let Err(e) = write_archive(writer, root, format, exclude, key).await {
+                warn!("Unable to build archive of {}: {:?}", shown, e);
+            }
+        });
+        reader
+    }
+}
+
+mod tail {
+    use super::{Change, ChangeStream};
+    use crate::mount::Mounts;
+    use publib::types::ChangeEvent;
+    use std::io::SeekFrom;
+    use tokio::fs::File;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, DuplexStream};
+    use tracing::warn;
+
+    const TAIL_BUFFER_SIZE: usize = 16 * 1024;
+
+    /// Copy bytes from `position` to current end of file, return new position
+    ///
+    /// If file is truncated, start from beginning
+    async fn copy_from(
+        file: &mut File,
+        mut position: u64,
+        writer: &mut DuplexStream,
+    ) -> std::io::Result<u64> {
+        let len = file.metadata().await?.len();
+        if len < position {
+            position = 0;
+        }
+        file.seek(SeekFrom::Start(position)).await?;
+        let mut reader = file.take(len - position);
+        position += tokio::io::copy(&mut reader, writer).await?;
+        writer.flush().await?;
+        Ok(position)
+    }
+
+    async fn tail(
+        mut writer: DuplexStream,
+        path: String,
+        bytes: u64,
+        mut changes: Option<ChangeStream>,
+    ) -> std::io::Result<()> {
+        let fs_path = Mounts::global().resolve(&path);
+        let mut file = File::open(&fs_path).await?;
+        let len = file.metadata().await?.len();
+        let mut position = copy_from(&mut file, len.saturating_sub(bytes), &mut writer).await?;
+
+        while let Some(ref mut receiver) = changes {
+            match receiver.recv().await {
+                Some(Change::Event(ChangeEvent::Update { path: ref changed }))
+                    if changed == &path =>
+                {
+                    position = copy_from(&mut file, position, &mut writer).await?;
+                }
+                // File is replaced, read from beginning of new file
+                Some(Change::Event(
+                    ChangeEvent::New { path: ref changed }
+                    | ChangeEvent::Rename {
+                        to: ref changed, ..
+                    },
+                )) if changed == &path => {
+                    file = File::open(&fs_path).await?;
+                    position = copy_from(&mut file, 0, &mut writer).await?;
+                }
+                Some(Change::Event(
+                    ChangeEvent::Remove { path: ref changed }
+                    | ChangeEvent::Rename {
+                        from: ref changed, ..
+                    },
+                )) if changed == &path => {
+                    break;
+                }
+                Some(Change::Event(_)) => {}
+                // Some events are missed, check file directly
+                Some(Change::Missed(_)) => {
+                    position = copy_from(&mut file, position, &mut writer).await?;
+                }
+                None => break,
+            }
+        }
+        writer.shutdown().await
+    }
+
+    /// Stream last `bytes` of file, then appended bytes until file removed if `changes` is set
+    ///
+    /// `path` should be normalized path, which is same as path in change events
+    pub fn spawn_tail(path: String, bytes: u64, changes: Option<ChangeStream>) -> DuplexStream {
+        let (writer, reader) = tokio::io::duplex(TAIL_BUFFER_SIZE);
+        tokio::spawn(async move {
+            let shown = path.clone();
+            if let Err(e) = tail(writer, path, bytes, changes).await {
+                // Client disconnected will also cause broken pipe
+                if e.kind() != std::io::ErrorKind::BrokenPipe {
+                    warn!("Unable to tail {}: {:?}", shown, e);
+                }
+            }
+        });
+        reader
+    }
+}
+
+mod event_log {
+    use super::jobs::unix_now;
+    use publib::types::{ChangeEvent, EventRecord};
+    use std::fs::{File, OpenOptions};
+    use std::io::{Read, Write};
+    use std::path::{Path, PathBuf};
+    use tracing::warn;
+
+    const KIND_NEW: u8 = 0;
+    const KIND_UPDATE: u8 = 1;
+    const KIND_REMOVE: u8 = 2;
+    const KIND_RENAME: u8 = 3;
+    /// seq + timestamp + kind + path count
+    const HEADER_SIZE: usize = 8 + 8 + 1 + 2;
+
+    // Record layout (little endian):
+    // [u32 length of rest][u64 seq][i64 timestamp][u8 kind][u16 path count]([u16 length][bytes])*
+    pub(super) fn encode(record: &EventRecord) -> Vec<u8> {
+        let (kind, paths) = match record.event() {
+            ChangeEvent::New { path } => (KIND_NEW, vec![path]),
+            ChangeEvent::Update { path } => (KIND_UPDATE, vec![path]),
+            ChangeEvent::Remove { path } => (KIND_REMOVE, vec![path]),
+            ChangeEvent::Rename { from, to } => (KIND_RENAME, vec![from, to]),
+        };
+        let mut body =
+            Vec::with_capacity(HEADER_SIZE + paths.iter().map(|p| p.len() + 2).sum::<usize>());
+        body.extend_from_slice(&record.seq().to_le_bytes());
+        body.extend_from_slice(&record.timestamp().to_le_bytes());
+        body.push(kind);
+        body.extend_from_slice(&(paths.len() as u16).to_le_bytes());
+        for path in paths {
+            body.extend_from_slice(&(path.len() as u16).to_le_bytes());
+            body.extend_from_slice(path.as_bytes());
+        }
+        let mut buf = Vec::with_capacity(body.len() + 4);
+        buf.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&body);
+        buf
+    }
+
+    fn decode(body: &[u8]) -> Option<EventRecord> {
+        if body.len() < HEADER_SIZE {
+            return None;
+        }
+        let seq = u64::from_le_bytes(body[0..8].try_into().ok()?);
+        let timestamp = i64::from_le_bytes(body[8..16].try_into().ok()?);
+        let kind = body[16];
+        let count = u16::from_le_bytes(body[17..19].try_into().ok()?) as usize;
+        let mut paths = Vec::with_capacity(count);
+        let mut rest = &body[HEADER_SIZE..];
+        for _ in 0..count {
+            let len = u16::from_le_bytes(rest.get(0..2)?.try_into().ok()?) as usize;
+            paths.push(String::from_utf8(rest.get(2..2 + len)?.to_vec()).ok()?);
+            rest = &rest[2 + len..];
+        }
+        let mut paths = paths.into_iter();
+        let event = match kind {
+            KIND_NEW => ChangeEvent::New {
+                path: paths.next()?,
+            },
+            KIND_UPDATE => ChangeEvent::Update {
+                path: paths.next()?,
+            },
+            KIND_REMOVE => ChangeEvent::Remove {
+                path: paths.next()?,
+            },
+            KIND_RENAME => ChangeEvent::Rename {
+                from: paths.next()?,
+                to: paths.next()?,
+            },
+            _ => return None,
+        };
+        Some(EventRecord::new(seq, timestamp, event))
+    }
+
+    /// Decode records in buffer, return records and length of valid part,
+    /// a partial record is expected at the end if daemon crashed while writing
+    ///
+    /// Complete record which can't be decoded is skipped, so records after it are kept
+    pub(super) fn decode_all(buf: &[u8]) -> (Vec<EventRecord>, usize) {
+        let mut records = Vec::new();
+        let mut offset = 0;
+        while let Some(len) = buf.get(offset..offset + 4) {
+            let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+            let Some(body) = buf.get(offset + 4..offset + 4 + len) else {
+                break;
+            };
+            match decode(body) {
+                Some(record) => records.push(record),
+                None => warn!("Skip bad record at offset {} of event log", offset),
+            }
+            offset += 4 + len;
+        }
+        (records, offset)
+    }
+
+    fn rotated_path(path: &Path, index: usize) -> PathBuf {
+        let mut name = path.as_os_str().to_os_string();
+        name.push(format!(".{}", index));
+        name.into()
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct EventLogReader {
+        path: PathBuf,
+        keep: usize,
+    }
+
+    impl EventLogReader {
+        /// Records in all log files, oldest first
+        pub fn read(&self) -> std::io::Result<Vec<EventRecord>> {
+            let mut records = Vec::new();
+            let files = (1..=self.keep)
+                .rev()
+                .map(|index| rotated_path(&self.path, index))
+                .chain(std::iter::once(self.path.clone()));
+            for path in files {
+                let mut buf = Vec::new();
+                match File::open(&path) {
+                    Ok(mut file) => file.read_to_end(&mut buf)?,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e),
+                };
+                records.extend(decode_all(&buf).0);
+            }
+            Ok(records)
+        }
+
+        /// Records with sequence greater than `seq`
+        pub fn read_after(&self, seq: u64) -> std::io::Result<Vec<EventRecord>> {
+            Ok(self
+                .read()?
+                .into_iter()
+                .filter(|record| record.seq() > seq)
+                .collect())
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct EventLog {
+        file: File,
+        size: u64,
+        max_size: u64,
+        last_seq: u64,
+        reader: EventLogReader,
+    }
+
+    impl EventLog {
+        /// Open log for append, sequence continue from `floor` if log is behind it
+        pub fn open<P: AsRef<Path>>(
+            path: P,
+            max_size: u64,
+            keep: usize,
+            floor: u64,
+        ) -> std::io::Result<Self> {
+            let reader = EventLogReader {
+                path: path.as_ref().to_path_buf(),
+                keep,
+            };
+            let mut file = OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(path)?;
+            let mut buf = Vec::new();
+            file.read_to_end(&mut buf)?;
+            let (records, valid) = decode_all(&buf);
+            if valid < buf.len() {
+                warn!(
+                    "Drop {} byte(s) of partial record in event log",
+                    buf.len() - valid
+                );
+                file.set_len(valid as u64)?;
+            }
+            let last_seq = match records.last() {
+                Some(record) => record.seq(),
+                // Current file may be just rotated
+                None => reader
+                    .read()?
+                    .last()
+                    .map(|record| record.seq())
+                    .unwrap_or_default(),
+            };
+            Ok(Self {
+                file,
+                size: valid as u64,
+                max_size,
+                last_seq: last_seq.max(floor),
+                reader,
+            })
+        }
+
+        pub fn reader(&self) -> EventLogReader {
+            self.reader.clone()
+        }
+
+        fn rotate(&mut self) -> std::io::Result<()> {
+            let path = &self.reader.path;
+            if self.reader.keep == 0 {
+                self.file.set_len(0)?;
+            } else {
+                for index in (1..self.reader.keep).rev() {
+                    let from = rotated_path(path, index);
+                    if from.exists() {
+                        std::fs::rename(&from, rotated_path(path, index + 1))?;
+                    }
+                }
+                std::fs::rename(path, rotated_path(path, 1))?;
+                self.file = OpenOptions::new()
+                    .read(true)
+                    .append(true)
+                    .create(true)
+                    .open(path)?;
+            }
+            self.size = 0;
+            Ok(())
+        }
+
+        /// Write event to log, return sequence of record
+        pub fn append(&mut self, event: ChangeEvent) -> std::io::Result<u64> {
+            if self.size >= self.max_size {
+                self.rotate()?;
+            }
+            let record = EventRecord::new(self.last_seq + 1, unix_now(), event);
+            let buf = encode(&record);
+            self.file.write_all(&buf)?;
+            self.size += buf.len() as u64;
+            self.last_seq = record.seq();
+            Ok(self.last_seq)
+        }
+    }
+}
+
+mod jobs {
+    use publib::types::JobStatus;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    /// Finished jobs exceed this count will be removed (oldest first)
+    const MAX_FINISHED_JOBS: usize = 100;
+
+    pub fn unix_now() -> i64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    #[derive(Debug, Default)]
+    struct JobsInner {
+        next_id: u64,
+        jobs: BTreeMap<u64, JobStatus>,
+    }
+
+    /// Status of background jobs run by file daemon, shared with web server
+    #[derive(Clone, Debug, Default)]
+    pub struct Jobs {
+        inner: Arc<Mutex<JobsInner>>,
+    }
+
+    impl Jobs {
+        pub fn create(&self, kind: &str) -> u64 {
+            let mut inner = self.inner.lock().unwrap();
+            inner.next_id += 1;
+            let id = inner.next_id;
+            inner.jobs.insert(id, JobStatus::new(id, kind.to_string()));
+
+            let finished = inner
+                .jobs
+                .values()
+                .filter(|job| job.is_finished())
+                .map(|job| job.id())
+                .collect::<Vec<_>>();
+            for id in finished
+                .iter()
+                .take(finished.len().saturating_sub(MAX_FINISHED_JOBS))
+            {
+                inner.jobs.remove(id);
+            }
+            id
+        }
+
+        pub fn get(&self, id: u64) -> Option<JobStatus> {
+            self.inner.lock().unwrap().jobs.get(&id).cloned()
+        }
+
+        pub fn update(&self, id: u64, f: impl FnOnce(&mut JobStatus)) {
+            if let Some(job) = self.inner.lock().unwrap().jobs.get_mut(&id) {
+                f(job);
+            }
+        }
+    }
+}
+
+mod sessions {
+    use super::unix_now;
+    use serde_derive::Serialize;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    /// Resumable upload, chunks are appended to `temp` until `size` bytes are received
+    #[derive(Clone, Debug, Serialize)]
+    pub struct UploadSession {
+        id: u64,
+        /// Created by, never sent to client
+        #[serde(skip)]
+        token: String,
+        path: String,
+        /// Index path of temporary file, excluded as partial file
+        #[serde(skip)]
+        temp: String,
+        size: u64,
+        hash: String,
+        /// Bytes received so far
+        offset: u64,
+        created_at: i64,
+        updated_at: i64,
+    }
+
+    impl UploadSession {
+        pub fn id(&self) -> u64 {
+            self.id
+        }
+        pub fn token(&self) -> &str {
+            &self.token
+        }
+        pub fn path(&self) -> &str {
+            &self.path
+        }
+        pub fn temp(&self) -> &str {
+            &self.temp
+        }
+        pub fn size(&self) -> u64 {
+            self.size
+        }
+        pub fn hash(&self) -> &str {
+            &self.hash
+        }
+        pub fn offset(&self) -> u64 {
+            self.offset
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct SessionsInner {
+        next_id: u64,
+        sessions: BTreeMap<u64, UploadSession>,
+    }
+
+    /// Upload sessions in progress, lost on restart. Temporary file left behind is truncated
+    /// by next session of same path or removed once session expired
+    #[derive(Clone, Debug, Default)]
+    pub struct UploadSessions {
+        inner: Arc<Mutex<SessionsInner>>,
+    }
+
+    impl UploadSessions {
+        /// `None` if another session uploads to `path`
+        pub fn create(
+            &self,
+            token: &str,
+            path: &str,
+            temp: &str,
+            size: u64,
+            hash: &str,
+        ) -> Option<UploadSession> {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.sessions.values().any(|session| session.path == path) {
+                return None;
+            }
+            inner.next_id += 1;
+            let now = unix_now();
+            let session = UploadSession {
+                id: inner.next_id,
+                token: token.to_string(),
+                path: path.to_string(),
+                temp: temp.to_string(),
+                size,
+                hash: hash.to_string(),
+                offset: 0,
+                created_at: now,
+                updated_at: now,
+            };
+            inner.sessions.insert(session.id, session.clone());
+            Some(session)
+        }
+
+        pub fn get(&self, id: u64) -> Option<UploadSession> {
+            self.inner.lock().unwrap().sessions.get(&id).cloned()
+        }
+
+        /// Record received bytes, return updated session
+        pub fn advance(&self, id: u64, offset: u64) -> Option<UploadSession> {
+            let mut inner = self.inner.lock().unwrap();
+            let session = inner.sessions.get_mut(&id)?;
+            session.offset = offset;
+            session.updated_at = unix_now();
+            Some(session.clone())
+        }
+
+        pub fn remove(&self, id: u64) -> Option<UploadSession> {
+            self.inner.lock().unwrap().sessions.remove(&id)
+        }
+
+        /// Remove sessions without chunk since `before`, their temporary files are left to
+        /// caller
+        pub fn prune(&self, before: i64) -> Vec<UploadSession> {
+            let mut inner = self.inner.lock().unwrap();
+            let expired = inner
+                .sessions
+                .values()
+                .filter(|session| session.updated_at < before)
+                .map(|session| session.id)
+                .collect::<Vec<_>>();
+            expired
+                .iter()
+                .filter_map(|id| inner.sessions.remove(id))
+                .collect()
+        }
+    }
+}
+
+mod links {
+    use publib::types::LinkInfo;
+    use std::sync::{Arc, RwLock};
+
+    /// Signed links stored in database, loaded by file daemon and shared with web server
+    #[derive(Clone, Debug, Default)]
+    pub struct Links {
+        inner: Arc<RwLock<Vec<LinkInfo>>>,
+    }
+
+    impl Links {
+        pub fn set(&self, links: Vec<LinkInfo>) {
+            *self.inner.write().unwrap() = links;
+        }
+
+        /// Links minted by `token`
+        pub fn list(&self, token: &str) -> Vec<LinkInfo> {
+            self.inner
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|link| link.token() == token)
+                .cloned()
+                .collect()
+        }
+
+        /// Path of link neither revoked nor pruned, signature itself is verified by
+        /// `UrlSigner`
+        pub fn path_of(&self, signature: &str) -> Option<String> {
+            self.inner
+                .read()
+                .unwrap()
+                .iter()
+                .find(|link| link.signature() == signature)
+                .map(|link| link.path().to_string())
+        }
+    }
+}
+
+mod holds {
+    use publib::types::HoldInfo;
+    use std::sync::{Arc, RwLock};
+
+    /// Legal holds stored in database, loaded by file daemon and shared with web server
+    #[derive(Clone, Debug, Default)]
+    pub struct Holds {
+        inner: Arc<RwLock<Vec<HoldInfo>>>,
+    }
+
+    impl Holds {
+        pub fn set(&self, holds: Vec<HoldInfo>) {
+            *self.inner.write().unwrap() = holds;
+        }
+
+        pub fn list(&self) -> Vec<HoldInfo> {
+            self.inner.read().unwrap().clone()
+        }
+
+        /// Hold on normalized `path` or any directory contains it
+        pub fn find(&self, path: &str) -> Option<HoldInfo> {
+            self.inner
+                .read()
+                .unwrap()
+                .iter()
+                .find(|hold| {
+                    hold.path() == "."
+                        || path
+                            .strip_prefix(hold.path())
+                            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+                })
+                .cloned()
+        }
+    }
+}
+
+mod tiered {
+    use crate::mount::Mounts;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex, RwLock};
+    use tokio::sync::Notify;
+
+    /// Files moved to cold storage, loaded by file daemon and shared with web server
+    #[derive(Clone, Debug, Default)]
+    pub struct Tiered {
+        locations: Arc<RwLock<HashMap<String, PathBuf>>>,
+        /// Paths being copied back, waiters are notified once copy finished
+        recalling: Arc<Mutex<HashMap<String, Arc<Notify>>>>,
+    }
+
+    impl Tiered {
+        pub fn set(&self, tiered: Vec<(String, String)>) {
+            *self.locations.write().unwrap() = tiered
+                .into_iter()
+                .map(|(path, location)| (path, PathBuf::from(location)))
+                .collect();
+        }
+
+        pub fn insert(&self, path: String, location: PathBuf) {
+            self.locations.write().unwrap().insert(path, location);
+        }
+
+        pub fn remove(&self, path: &str) -> Option<PathBuf> {
+            self.locations.write().unwrap().remove(path)
+        }
+
+        /// Copy of normalized `path` in cold storage
+        pub fn location(&self, path: &str) -> Option<PathBuf> {
+            self.locations.read().unwrap().get(path).cloned()
+        }
+
+        pub fn contains(&self, path: &str) -> bool {
+            self.locations.read().unwrap().contains_key(path)
+        }
+
+        /// Tiered files of `path` or under directory `path`
+        pub fn under(&self, path: &str) -> Vec<(String, PathBuf)> {
+            self.locations
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|(tiered, _)| super::transfers::is_under(tiered, path))
+                .map(|(tiered, location)| (tiered.clone(), location.clone()))
+                .collect()
+        }
+
+        pub fn is_recalling(&self, path: &str) -> bool {
+            self.recalling.lock().unwrap().contains_key(path)
+        }
+
+        /// Copy file back to its place through `suffix` temporary file, waits recall started
+        /// by others instead of copying twice
+        ///
+        /// `true` if file is copied by this call, it is still listed until file daemon
+        /// handled recall
+        pub async fn recall(&self, path: &str, suffix: &str) -> std::io::Result<bool> {
+            let Some(location) = self.location(path) else {
+                return Ok(false);
+            };
+            let (notify, owner) = {
+                let mut recalling = self.recalling.lock().unwrap();
+                match recalling.get(path) {
+                    Some(notify) => (notify.clone(), false),
+                    None => {
+                        let notify = Arc::new(Notify::new());
+                        recalling.insert(path.to_string(), notify.clone());
+                        (notify, true)
+                    }
+                }
+            };
+            if !owner {
+                let notified = notify.notified();
+                if self.is_recalling(path) {
+                    notified.await;
+                }
+                return Ok(false);
+            }
+            let result = async {
+                let target = Mounts::global().resolve(path);
+                if let Some(parent) = target.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                let mut partial = target.clone().into_os_string();
+                partial.push(suffix);
+                tokio::fs::copy(&location, &partial).await?;
+                tokio::fs::rename(&partial, &target).await
+            }
+            .await;
+            self.recalling.lock().unwrap().remove(path);
+            notify.notify_waiters();
+            result.map(|_| true)
+        }
+    }
+}
+
+mod progress {
+    use super::jobs::unix_now;
+    use publib::types::ScanStatus;
+    use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+    use std::sync::{Arc, RwLock};
+
+    #[derive(Debug, Default)]
+    struct ScanProgressInner {
+        running: AtomicBool,
+        /// Index can be used to answer query, see `set_ready`
+        ready: AtomicBool,
+        scanned: AtomicU64,
+        hashed: AtomicU64,
+        bytes: AtomicU64,
+        /// `0` means not set
+        started_at: AtomicI64,
+        finished_at: AtomicI64,
+        /// Git commit indexed by latest scan
+        commit: RwLock<Option<String>>,
+        /// Bumped after index is changed by scan or file event
+        generation: AtomicU64,
+    }
+
+    /// Progress of latest scan run by file daemon, shared with web server
+    #[derive(Clone, Debug, Default)]
+    pub struct ScanProgress {
+        inner: Arc<ScanProgressInner>,
+    }
+
+    impl ScanProgress {
+        pub fn start(&self) {
+            let inner = &self.inner;
+            inner.scanned.store(0, Ordering::Relaxed);
+            inner.hashed.store(0, Ordering::Relaxed);
+            inner.bytes.store(0, Ordering::Relaxed);
+            inner.started_at.store(unix_now(), Ordering::Relaxed);
+            inner.finished_at.store(0, Ordering::Relaxed);
+            inner.running.store(true, Ordering::Relaxed);
+        }
+
+        pub fn add_scanned(&self, bytes: u64) {
+            self.inner.scanned.fetch_add(1, Ordering::Relaxed);
+            self.inner.bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+
+        pub fn add_hashed(&self) {
+            self.inner.hashed.fetch_add(1, Ordering::Relaxed);
+        }
+
+        /// Index become ready once a scan succeed
+        pub fn finish(&self, success: bool) {
+            self.inner.finished_at.store(unix_now(), Ordering::Relaxed);
+            self.inner.running.store(false, Ordering::Relaxed);
+            if success {
+                self.set_ready();
+                self.bump_generation();
+            }
+        }
+
+        pub fn bump_generation(&self) {
+            self.inner.generation.fetch_add(1, Ordering::Relaxed);
+        }
+
+        /// Changes of index since startup, same generation means same index
+        pub fn generation(&self) -> u64 {
+            self.inner.generation.load(Ordering::Relaxed)
+        }
+
+        /// Persistent index is ready before scan, in-memory index is not ready until first scan
+        /// finished
+        pub fn set_ready(&self) {
+            self.inner.ready.store(true, Ordering::Relaxed);
+        }
+
+        pub fn set_commit(&self, commit: Option<String>) {
+            *self.inner.commit.write().unwrap() = commit;
+        }
+
+        pub fn commit(&self) -> Option<String> {
+            self.inner.commit.read().unwrap().clone()
+        }
+
+        pub fn status(&self) -> ScanStatus {
+            let inner = &self.inner;
+            let timestamp =
+                |value: &AtomicI64| Some(value.load(Ordering::Relaxed)).filter(|t| *t > 0);
+            ScanStatus::new(
+                inner.running.load(Ordering::Relaxed),
+                inner.ready.load(Ordering::Relaxed),
+                inner.scanned.load(Ordering::Relaxed),
+                inner.hashed.load(Ordering::Relaxed),
+                inner.bytes.load(Ordering::Relaxed),
+                timestamp(&inner.started_at),
+                timestamp(&inner.finished_at),
+            )
+        }
+    }
+}
+
+mod throttle {
+    use axum::body::Bytes;
+    use futures::{Stream, StreamExt};
+    use std::sync::{Arc, Mutex};
+    use tokio::time::{sleep_until, Duration, Instant};
+
+    /// Leaky bucket, bytes leave at `rate` byte/s
+    #[derive(Clone, Debug)]
+    struct RateLimiter {
+        rate: u64,
+        next: Arc<Mutex<Instant>>,
+    }
+
+    impl RateLimiter {
+        fn new(rate: u64) -> Self {
+            Self {
+                rate,
+                next: Arc::new(Mutex::new(Instant::now())),
+            }
+        }
+
+        /// Wait until `size` bytes are allowed to leave
+        async fn acquire(&self, size: usize) {
+            let cost = Duration::from_secs_f64(size as f64 / self.rate as f64);
+            let at = {
+                let mut next = self.next.lock().unwrap();
+                let at = (*next).max(Instant::now());
+                *next = at + cost;
+                at
+            };
+            sleep_until(at).await;
+        }
+    }
+
+    /// Download rate limits, global limiter is shared by all connections
+    #[derive(Clone, Debug, Default)]
+    pub struct Throttle {
+        connection_rate: Option<u64>,
+        global: Option<RateLimiter>,
+    }
+
+    impl Throttle {
+        pub fn new(connection_rate: Option<u64>, global_rate: Option<u64>) -> Self {
+            Self {
+                connection_rate,
+                global: global_rate.map(RateLimiter::new),
+            }
+        }
+
+        /// Delay each chunk of `stream` to keep download within limits
+        pub fn wrap<S, E>(&self, stream: S) -> impl Stream<Item = Result<Bytes, E>>
+        where
+            S: Stream<Item = Result<Bytes, E>>,
+        {
+            let limiters = self
+                .connection_rate
+                .map(RateLimiter::new)
+                .into_iter()
+                .chain(self.global.clone())
+                .collect::<Vec<_>>();
+            stream.then(move |chunk| {
+                let limiters = limiters.clone();
+                async move {
+                    if let Ok(ref bytes) = chunk {
+                        for limiter in &limiters {
+                            limiter.acquire(bytes.len()).await;
+                        }
+                    }
+                    chunk
+                }
+            })
+        }
+    }
+}
+
+mod transfers {
+    use super::jobs::unix_now;
+    use crate::configure::mask_token;
+    use axum::body::Bytes;
+    use futures::{Stream, StreamExt};
+    use publib::types::{TransferKind, TransferStatus};
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+    use tokio::sync::Notify;
+    use tokio_util::sync::CancellationToken;
+
+    /// `path` is `dir` or inside it, both are normalized
+    pub(super) fn is_under(path: &str, dir: &str) -> bool {
+        path.strip_prefix(dir)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    #[derive(Debug)]
+    struct TransferState {
+        kind: TransferKind,
+        token: String,
+        path: String,
+        bytes: AtomicU64,
+        started: Instant,
+        started_at: i64,
+        cancel: CancellationToken,
+    }
+
+    impl TransferState {
+        fn status(&self, id: u64) -> TransferStatus {
+            let bytes = self.bytes.load(Ordering::Relaxed);
+            let elapsed = self.started.elapsed().as_secs_f64();
+            let rate = if elapsed > 0.0 {
+                (bytes as f64 / elapsed) as u64
+            } else {
+                0
+            };
+            TransferStatus::new(
+                id,
+                self.kind,
+                mask_token(&self.token),
+                self.path.clone(),
+                bytes,
+                rate,
+                self.started_at,
+            )
+        }
+    }
 
-        fn is_finished(&self) -> bool {
-            self.handler.is_finished()
+    #[derive(Debug, Default)]
+    struct TransfersInner {
+        next_id: u64,
+        transfers: BTreeMap<u64, Arc<TransferState>>,
+    }
+
+    /// Active downloads and uploads, entry is removed once its guard is dropped
+    #[derive(Clone, Debug, Default)]
+    pub struct Transfers {
+        inner: Arc<Mutex<TransfersInner>>,
+        /// Notified once any transfer finished
+        finished: Arc<Notify>,
+    }
+
+    impl Transfers {
+        pub fn register(&self, kind: TransferKind, token: &str, path: &str) -> TransferGuard {
+            let state = Arc::new(TransferState {
+                kind,
+                token: token.to_string(),
+                path: path.to_string(),
+                bytes: AtomicU64::new(0),
+                started: Instant::now(),
+                started_at: unix_now(),
+                cancel: CancellationToken::new(),
+            });
+            let mut inner = self.inner.lock().unwrap();
+            inner.next_id += 1;
+            let id = inner.next_id;
+            inner.transfers.insert(id, state.clone());
+            TransferGuard {
+                id,
+                state,
+                transfers: self.clone(),
+            }
+        }
+
+        pub fn list(&self) -> Vec<TransferStatus> {
+            self.inner
+                .lock()
+                .unwrap()
+                .transfers
+                .iter()
+                .map(|(id, state)| state.status(*id))
+                .collect()
+        }
+
+        /// Transfers of `path` or files under it
+        pub fn count_under(&self, path: &str) -> usize {
+            self.inner
+                .lock()
+                .unwrap()
+                .transfers
+                .values()
+                .filter(|state| is_under(&state.path, path))
+                .count()
+        }
+
+        /// Wait until no transfer is under `path`
+        pub async fn wait_idle(&self, path: &str) {
+            loop {
+                // Created before count, so transfer finished in between is not missed
+                let finished = self.finished.notified();
+                if self.count_under(path) == 0 {
+                    return;
+                }
+                finished.await;
+            }
+        }
+
+        /// Return `false` if transfer is not found
+        pub fn cancel(&self, id: u64) -> bool {
+            match self.inner.lock().unwrap().transfers.get(&id) {
+                Some(state) => {
+                    state.cancel.cancel();
+                    true
+                }
+                None => false,
+            }
+        }
+    }
+
+    pub struct TransferGuard {
+        id: u64,
+        state: Arc<TransferState>,
+        transfers: Transfers,
+    }
+
+    impl TransferGuard {
+        /// Count bytes pass through `stream`, stream yields error once transfer is cancelled
+        pub fn track<S, E>(self, stream: S) -> impl Stream<Item = Result<Bytes, E>>
+        where
+            S: Stream<Item = Result<Bytes, E>>,
+            E: From<std::io::Error>,
+        {
+            futures::stream::unfold(Some((Box::pin(stream), self)), |state| async move {
+                let (mut stream, guard) = state?;
+                let cancel = guard.state.cancel.clone();
+                tokio::select! {
+                    _ = cancel.cancelled() => Some((
+                        Err(E::from(std::io::Error::new(
+                            std::io::ErrorKind::Interrupted,
+                            "Transfer cancelled",
+                        ))),
+                        None,
+                    )),
+                    chunk = stream.next() => {
+                        let chunk = chunk?;
+                        if let Ok(ref bytes) = chunk {
+                            guard
+                                .state
+                                .bytes
+                                .fetch_add(bytes.len() as u64, Ordering::Relaxed);
+                        }
+                        Some((chunk, Some((stream, guard))))
+                    }
+                }
+            })
+        }
+    }
+
+    impl Drop for TransferGuard {
+        fn drop(&mut self) {
+            self.transfers
+                .inner
+                .lock()
+                .unwrap()
+                .transfers
+                .remove(&self.id);
+            self.transfers.finished.notify_waiters();
+        }
+    }
+}
+
+mod removal {
+    use super::jobs::unix_now;
+    use super::types::FileEvent;
+    use super::{FileEventHelper, Transfers};
+    use crate::configure::current::{DeletePolicy, Deletion};
+    use crate::mount::Mounts;
+    use std::collections::HashSet;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tracing::{error, warn};
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Removal {
+        Removed,
+        /// Removed once downloads under path finish
+        Deferred,
+        /// Moved to trash, trashed copy is removed once downloads under path finish
+        Trashed,
+    }
+
+    #[derive(Debug)]
+    pub enum RemoveError {
+        /// Downloads under path did not finish in time, count of them
+        Busy(usize),
+        Io(std::io::Error),
+    }
+
+    impl From<std::io::Error> for RemoveError {
+        fn from(e: std::io::Error) -> Self {
+            Self::Io(e)
+        }
+    }
+
+    async fn remove_path(path: &Path) -> std::io::Result<()> {
+        match tokio::fs::symlink_metadata(path).await {
+            Ok(metadata) if metadata.is_dir() => tokio::fs::remove_dir_all(path).await,
+            Ok(_) => tokio::fs::remove_file(path).await,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Remove file or directory without breaking downloads under it, index is updated once
+    /// path is gone from its location
+    #[derive(Clone, Debug)]
+    pub struct Remover {
+        policy: DeletePolicy,
+        wait_timeout: Duration,
+        trash_dir: PathBuf,
+        transfers: Transfers,
+        sender: FileEventHelper,
+        /// Deferred paths, new download under them is refused
+        pending: Arc<Mutex<HashSet<String>>>,
+    }
+
+    impl Remover {
+        pub fn new(deletion: &Deletion, transfers: Transfers, sender: FileEventHelper) -> Self {
+            Self {
+                policy: deletion.policy(),
+                wait_timeout: deletion.wait_timeout(),
+                trash_dir: PathBuf::from(deletion.trash_dir()),
+                transfers,
+                sender,
+                pending: Default::default(),
+            }
+        }
+
+        /// `path` or directory contains it is waiting to be removed
+        pub fn is_pending(&self, path: &str) -> bool {
+            self.pending
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|pending| super::transfers::is_under(path, pending))
+        }
+
+        async fn send_remove(&self, path: &str) {
+            if self
+                .sender
+                .send_event(FileEvent::Remove(vec![path.to_string()]))
+                .await
+                .is_none()
+            {
+                warn!(
+                    "File daemon stopped, {} is removed from index by next scan",
+                    path
+                );
+            }
+        }
+
+        /// `path` should be normalized and checked by caller
+        pub async fn remove(&self, path: &str) -> Result<Removal, RemoveError> {
+            // New download is refused from now on, download registered before is counted
+            self.pending.lock().unwrap().insert(path.to_string());
+            let result = self.remove_pending(path).await;
+            if !matches!(result, Ok(Removal::Deferred)) {
+                self.pending.lock().unwrap().remove(path);
+            }
+            result
+        }
+
+        async fn remove_pending(&self, path: &str) -> Result<Removal, RemoveError> {
+            let fs_path = Mounts::global().resolve(path);
+            if self.transfers.count_under(path) == 0 {
+                remove_path(&fs_path).await?;
+                self.send_remove(path).await;
+                return Ok(Removal::Removed);
+            }
+            match self.policy {
+                DeletePolicy::Wait => {
+                    if tokio::time::timeout(self.wait_timeout, self.transfers.wait_idle(path))
+                        .await
+                        .is_err()
+                    {
+                        return Err(RemoveError::Busy(self.transfers.count_under(path)));
+                    }
+                    remove_path(&fs_path).await?;
+                    self.send_remove(path).await;
+                    Ok(Removal::Removed)
+                }
+                DeletePolicy::Defer => Ok(self.defer(path, fs_path)),
+                DeletePolicy::Trash => match self.trash(path, &fs_path).await {
+                    Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+                        warn!(
+                            "Unable to move {} to trash on other file system, remove it once \
+                            downloads finish",
+                            path
+                        );
+                        Ok(self.defer(path, fs_path))
+                    }
+                    result => result.map_err(RemoveError::Io),
+                },
+            }
+        }
+
+        /// Remove once downloads under `path` finish, `path` stays pending until then
+        fn defer(&self, path: &str, fs_path: PathBuf) -> Removal {
+            let remover = self.clone();
+            let path = path.to_string();
+            tokio::spawn(async move {
+                remover.transfers.wait_idle(&path).await;
+                match remove_path(&fs_path).await {
+                    Ok(_) => remover.send_remove(&path).await,
+                    Err(e) => error!("Unable to remove deferred {}: {:?}", path, e),
+                }
+                remover.pending.lock().unwrap().remove(&path);
+            });
+            Removal::Deferred
+        }
+
+        /// Trash of working directory or of mount `path` is under, so rename stays on same
+        /// file system
+        fn trash_dir(&self, path: &str) -> PathBuf {
+            let mounts = Mounts::global();
+            mounts.resolve(&mounts.root_of(path)).join(&self.trash_dir)
+        }
+
+        async fn trash(&self, path: &str, fs_path: &Path) -> std::io::Result<Removal> {
+            // Open files keep being readable after rename
+            let trash_dir = self.trash_dir(path);
+            tokio::fs::create_dir_all(&trash_dir).await?;
+            let name = fs_path.file_name().unwrap_or_default().to_string_lossy();
+            let trashed = trash_dir.join(format!("{}-{}", unix_now(), name));
+            tokio::fs::rename(fs_path, &trashed).await?;
+            self.send_remove(path).await;
+            let transfers = self.transfers.clone();
+            let path = path.to_string();
+            tokio::spawn(async move {
+                transfers.wait_idle(&path).await;
+                if let Err(e) = remove_path(&trashed).await {
+                    error!("Unable to remove trashed {:?}: {:?}", trashed, e);
+                }
+            });
+            Ok(Removal::Trashed)
+        }
+    }
+}
+
+mod warm {
+    use super::{Change, ChangeStream};
+    use crate::configure::ExcludeSet;
+    use crate::mount::Mounts;
+    use async_walkdir::WalkDir;
+    use futures::StreamExt;
+    use globset::{Glob, GlobSet, GlobSetBuilder};
+    use publib::normalize_path;
+    use publib::types::ChangeEvent;
+    use std::path::Path;
+    use std::sync::Arc;
+    use tokio::fs::File;
+    use tokio::io::AsyncReadExt;
+    use tracing::{debug, warn};
+
+    const WARM_BUFFER_SIZE: usize = 1024 * 1024;
+
+    /// Keep hot files in page cache, so first download after release is not served from disk
+    #[derive(Clone, Debug)]
+    pub struct WarmCache {
+        set: GlobSet,
+    }
+
+    impl WarmCache {
+        pub fn new<S: AsRef<str>>(patterns: &[S]) -> Result<Self, globset::Error> {
+            let mut builder = GlobSetBuilder::new();
+            for pattern in patterns {
+                builder.add(Glob::new(pattern.as_ref())?);
+            }
+            Ok(Self {
+                set: builder.build()?,
+            })
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.set.is_empty()
+        }
+
+        /// `path` is normalized path, patterns are relative to working directory
+        fn is_match(&self, path: &str) -> bool {
+            self.set.is_match(path.strip_prefix("./").unwrap_or(path))
+        }
+
+        /// Warm matched files under current directory, then rewarm them once changed
+        pub async fn run(self, exclude: Arc<ExcludeSet>, changes: ChangeStream) {
+            self.warm_all(&exclude).await;
+            self.follow(changes).await;
+        }
+
+        async fn warm_all(&self, exclude: &ExcludeSet) {
+            let mut entries = WalkDir::new(".");
+            while let Some(Ok(entry)) = entries.next().await {
+                let path = entry.path();
+                let is_file = entry
+                    .file_type()
+                    .await
+                    .is_ok_and(|file_type| !file_type.is_dir());
+                if !is_file || exclude.is_match(&path) {
+                    continue;
+                }
+                let path = normalize_path(&path);
+                if self.is_match(&path) {
+                    warm(&path).await;
+                }
+            }
+        }
+
+        async fn follow(&self, mut changes: ChangeStream) {
+            while let Some(change) = changes.recv().await {
+                match change {
+                    Change::Event(
+                        ChangeEvent::New { ref path }
+                        | ChangeEvent::Update { ref path }
+                        | ChangeEvent::Rename { to: ref path, .. },
+                    ) => {
+                        if self.is_match(path) {
+                            warm(Mounts::global().resolve(path)).await;
+                        }
+                    }
+                    Change::Event(ChangeEvent::Remove { .. }) => {}
+                    Change::Missed(count) => {
+                        warn!("Warm cache missed {} change event(s)", count);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Read whole file and drop the content, kernel keeps it in page cache
+    async fn warm<P: AsRef<Path>>(path: P) {
+        let result = async {
+            let mut file = File::open(&path).await?;
+            let mut buffer = vec![0u8; WARM_BUFFER_SIZE];
+            let mut total = 0;
+            loop {
+                let read_size = file.read(&mut buffer).await?;
+                if read_size == 0 {
+                    break;
+                }
+                total += read_size;
+            }
+            Ok::<_, std::io::Error>(total)
+        }
+        .await;
+        match result {
+            Ok(size) => debug!("Warmed {:?} ({} bytes)", path.as_ref(), size),
+            Err(e) => warn!("Unable to warm {:?}: {:?}", path.as_ref(), e),
+        }
+    }
+}
+
+mod change_stream {
+    use publib::types::ChangeEvent;
+    use tokio::sync::broadcast;
+    use tokio::sync::broadcast::error::RecvError;
+
+    #[derive(Clone, Debug)]
+    pub enum Change {
+        Event(ChangeEvent),
+        /// Subscriber is too slow and `count` events are dropped, should query index to resync
+        Missed(u64),
+    }
+
+    /// Subscription of changes applied by file daemon, for consumers in same process
+    #[derive(Debug)]
+    pub struct ChangeStream {
+        receiver: broadcast::Receiver<ChangeEvent>,
+    }
+
+    impl ChangeStream {
+        pub(super) fn new(receiver: broadcast::Receiver<ChangeEvent>) -> Self {
+            Self { receiver }
+        }
+
+        /// `None` if file daemon stopped
+        pub async fn recv(&mut self) -> Option<Change> {
+            match self.receiver.recv().await {
+                Ok(event) => Some(Change::Event(event)),
+                Err(RecvError::Lagged(count)) => Some(Change::Missed(count)),
+                Err(RecvError::Closed) => None,
+            }
+        }
+    }
+}
+
+mod append {
+    use crate::crypto::{
+        append_encrypted, encrypt_file, is_encrypted, plaintext_len, EncryptionKey,
+    };
+    use crate::mount::Mounts;
+    use axum::body::Bytes;
+    use futures::{Stream, StreamExt};
+    use publib::e2e::is_e2e_encrypted;
+    use publib::file::{get_file_hash, get_file_hasher, HashOption, Hasher};
+    use publib::types::FileEntry;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tokio::fs::OpenOptions;
+    use tokio::io::AsyncWriteExt;
+    use tokio::sync::Mutex;
+
+    #[derive(Debug)]
+    pub enum AppendError {
+        /// Request offset is not current file size, contains current file size
+        OffsetMismatch(u64),
+        Io(std::io::Error),
+    }
+
+    impl From<std::io::Error> for AppendError {
+        fn from(value: std::io::Error) -> Self {
+            Self::Io(value)
+        }
+    }
+
+    /// Keep hasher state of appended files, so next append only need to hash new bytes
+    ///
+    /// State is only reused if file size is same as last append
+    #[derive(Clone)]
+    pub struct AppendHasher {
+        states: Arc<Mutex<HashMap<String, (u64, Hasher)>>>,
+        option: HashOption,
+    }
+
+    impl AppendHasher {
+        pub fn new(option: HashOption) -> Self {
+            Self {
+                states: Default::default(),
+                option,
+            }
+        }
+
+        pub fn option(&self) -> &HashOption {
+            &self.option
+        }
+
+        /// Drop hasher state of `path`, e.g. temporary file renamed away
+        pub async fn forget(&self, path: &str) {
+            self.states.lock().await.remove(path);
+        }
+
+        /// Append `body` to end of `path` (should be normalized path), return updated entry
+        ///
+        /// If `offset` is set, it must be equal to current file size (plaintext size if encrypted)
+        ///
+        /// Plaintext file is encrypted before append if `key` is set
+        pub async fn append<S, E>(
+            &self,
+            path: &str,
+            offset: Option<u64>,
+            mut body: S,
+            key: Option<&EncryptionKey>,
+        ) -> Result<FileEntry, AppendError>
+        where
+            S: Stream<Item = Result<Bytes, E>> + Unpin,
+            E: std::error::Error + Send + Sync + 'static,
+        {
+            let fs_path = Mounts::global().resolve(path);
+            // Appends are serialized, so cached size always match file written by us
+            let mut states = self.states.lock().await;
+
+            // Ciphertext of last chunk is changed, so hash can't be computed incrementally
+            if let Some(key) = key {
+                states.remove(path);
+                // Written before encryption was enabled or by producer on disk
+                if !is_encrypted(&fs_path).await? {
+                    encrypt_file(&fs_path, key.clone()).await?;
+                }
+                let size = tokio::fs::metadata(&fs_path).await?.len();
+                let size = plaintext_len(size).ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "Invalid encrypted file length",
+                    )
+                })?;
+                if offset.is_some_and(|offset| offset != size) {
+                    return Err(AppendError::OffsetMismatch(size));
+                }
+                append_encrypted(&fs_path, key.clone(), body).await?;
+                let hash = get_file_hash(&fs_path, &self.option).await?;
+                return Ok(
+                    FileEntry::from_metadata(path, fs_path.metadata()?, Some(hash))
+                        .with_encrypted(is_e2e_encrypted(&fs_path).await?),
+                );
+            }
+
+            let mut file = OpenOptions::new().append(true).open(&fs_path).await?;
+            let mut size = file.metadata().await?.len();
+            if offset.is_some_and(|offset| offset != size) {
+                return Err(AppendError::OffsetMismatch(size));
+            }
+
+            let mut hasher = match states.remove(path) {
+                Some((cached_size, hasher)) if cached_size == size => hasher,
+                _ => get_file_hasher(&fs_path, &self.option).await?,
+            };
+
+            // Hasher state is dropped on error, since file may be partial written
+            while let Some(chunk) = body.next().await {
+                let chunk = chunk.map_err(|e| std::io::Error::other(e))?;
+                file.write_all(&chunk).await?;
+                hasher.update(&chunk);
+                size += chunk.len() as u64;
+            }
+            file.flush().await?;
+
+            let hash = hasher.digest();
+            states.insert(path.to_string(), (size, hasher));
+            Ok(
+                FileEntry::from_metadata(path, fs_path.metadata()?, Some(hash))
+                    .with_encrypted(is_e2e_encrypted(&fs_path).await?),
+            )
         }
     }
 }
 
-pub use files::{init_files, FileDaemon};
-pub use types::FileEventHelper;
+pub use append::{AppendError, AppendHasher};
+pub use archive::{spawn_archive, ArchiveFormat};
+pub use change_stream::{Change, ChangeStream};
+pub use event_log::{EventLog, EventLogReader};
+pub use files::{replay_events, DaemonOptions, FileDaemon};
+pub use holds::Holds;
+pub use jobs::{unix_now, Jobs};
+pub use links::Links;
+pub use progress::ScanProgress;
+pub use removal::{Removal, RemoveError, Remover};
+pub use sessions::{UploadSession, UploadSessions};
+pub use tail::spawn_tail;
+pub use throttle::Throttle;
+pub use tiered::Tiered;
+pub use transfers::{TransferGuard, Transfers};
+pub use types::{FileEventHelper, ReportKind, SearchQuery};
+pub use warm::WarmCache;
 pub use watcher::FileWatcher;
+
+#[cfg(test)]
+mod test {
+    use super::event_log::{decode_all, encode};
+    use super::files::{
+        apply_hash, fold_case, fold_case_except, is_unchanged, query_folded, within_limits,
+        Pending, Settling, Tiering,
+    };
+    use super::transfers::is_under;
+    use super::types::FileEvent;
+    use super::watcher::RenameTracker;
+    use super::{FileEventHelper, Removal, RemoveError, Remover, Tiered, Transfers};
+    use crate::configure::current::{CaseSensitivity, Deletion, PathLimits};
+    use crate::configure::ExcludeSet;
+    use crate::database::{load_database, Database};
+    use publib::file::HashOption;
+    use publib::types::{ChangeEvent, EventRecord, FileEntry, TransferKind};
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    /// Directory under `./target` for files created by test
+    fn test_dir(name: &str) -> String {
+        format!("./target/file-test-{}-{}", name, std::process::id())
+    }
+
+    /// File of `content` and its copy in cold storage
+    fn prepare_cold(root: &str, name: &str, content: &[u8]) -> (String, PathBuf) {
+        let cold = PathBuf::from(root).join("cold");
+        std::fs::create_dir_all(&cold).unwrap();
+        let path = format!("{}/{}", root, name);
+        std::fs::write(&path, content).unwrap();
+        let location = cold.join(name);
+        std::fs::copy(&path, &location).unwrap();
+        (path, location)
+    }
+
+    fn entry_of(path: &str) -> FileEntry {
+        FileEntry::from_metadata::<_, String>(path, std::fs::metadata(path).unwrap(), None)
+    }
+
+    fn hashed_entry(path: &str, hash: &str) -> FileEntry {
+        FileEntry::from_metadata(path, std::fs::metadata(path).unwrap(), Some(hash))
+    }
+
+    /// Apply hash of scan which walked `path` when its row was `stored`
+    async fn apply_scan_hash(conn: &mut dyn Database, path: &str, stored: Option<FileEntry>) {
+        apply_hash(
+            conn,
+            Pending::new(path, stored),
+            (Some("scanned".to_string()), vec![], false),
+            CaseSensitivity::Sensitive,
+            &HashOption::default(),
+            &super::ScanProgress::default(),
+        )
+        .await
+        .unwrap();
+    }
+
+    const SETTLE: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn watched_path_beyond_limits_is_skipped() {
+        let limits = PathLimits::default();
+        assert!(within_limits("./dir/file.txt", &limits));
+        let deep = format!("./{}file.txt", "d/".repeat(limits.max_depth()));
+        assert!(!within_limits(&deep, &limits));
+        let long = format!("./{}", "n".repeat(limits.max_name_length() + 1));
+        assert!(!within_limits(&long, &limits));
+    }
+
+    #[test]
+    fn bad_event_record_is_skipped() {
+        let record = |seq: u64, path: &str| {
+            EventRecord::new(
+                seq,
+                0,
+                ChangeEvent::New {
+                    path: path.to_string(),
+                },
+            )
+        };
+        let mut bad = encode(&record(2, "./b"));
+        // Unknown kind
+        bad[4 + 16] = 9;
+        let partial = encode(&record(4, "./d"));
+        let mut buf = [encode(&record(1, "./a")), bad, encode(&record(3, "./c"))].concat();
+        let valid = buf.len();
+        buf.extend_from_slice(&partial[..partial.len() - 1]);
+
+        let (records, offset) = decode_all(&buf);
+        assert_eq!(records, vec![record(1, "./a"), record(3, "./c")]);
+        assert_eq!(offset, valid);
+    }
+
+    #[tokio::test]
+    async fn settling_waits_file_stops_changing() {
+        let root = test_dir("settling");
+        std::fs::create_dir_all(&root).unwrap();
+        let path = format!("{}/copied.bin", root);
+        std::fs::write(&path, b"part").unwrap();
+        let mut settling = Settling::new(Some(SETTLE));
+
+        assert!(settling.defer(&path));
+        assert!(settling.deadline().is_some());
+        assert!(settling.take_settled().is_empty());
+
+        // Still being written once it is due, deferred again
+        tokio::time::sleep(SETTLE).await;
+        std::fs::write(&path, b"part and rest").unwrap();
+        assert!(settling.take_settled().is_empty());
+        assert!(settling.deadline().is_some());
+
+        tokio::time::sleep(SETTLE).await;
+        assert_eq!(settling.take_settled(), vec![path]);
+        assert!(settling.deadline().is_none());
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn settling_skips_old_and_removed_file() {
+        let root = test_dir("settled");
+        std::fs::create_dir_all(&root).unwrap();
+        let old = format!("{}/old.bin", root);
+        std::fs::write(&old, b"old").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&old)
+            .unwrap()
+            .set_modified(std::time::UNIX_EPOCH)
+            .unwrap();
+        let removed = format!("{}/removed.bin", root);
+        std::fs::write(&removed, b"removed").unwrap();
+
+        let mut settling = Settling::new(Some(SETTLE));
+        assert!(!settling.defer(&old));
+        assert!(!settling.defer(&root));
+        assert!(!Settling::new(None).defer(&removed));
+
+        // Removed one is left to remove event
+        assert!(settling.defer(&removed));
+        std::fs::remove_file(&removed).unwrap();
+        tokio::time::sleep(SETTLE).await;
+        assert!(settling.take_settled().is_empty());
+        assert!(settling.deadline().is_none());
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn test_is_unchanged() {
+        let root = test_dir("unchanged");
+        std::fs::create_dir_all(&root).unwrap();
+        let path = format!("{}/file.txt", root);
+        std::fs::write(&path, b"file").unwrap();
+        let entry = hashed_entry(&path, "a");
+
+        assert!(is_unchanged(None, None));
+        assert!(is_unchanged(Some(&entry), Some(&entry.clone())));
+        assert!(!is_unchanged(Some(&entry), Some(&hashed_entry(&path, "b"))));
+        assert!(!is_unchanged(None, Some(&entry)));
+        assert!(!is_unchanged(Some(&entry), None));
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn scan_hash_updates_unchanged_row() {
+        let root = test_dir("scan-hash");
+        std::fs::create_dir_all(&root).unwrap();
+        let path = format!("{}/file.txt", root);
+        std::fs::write(&path, b"file").unwrap();
+        let mut conn = load_database(":memory:").await.unwrap();
+
+        apply_scan_hash(&mut conn, &path, None).await;
+        let stored = conn.query_path(path.as_ref()).await.unwrap().unwrap();
+        assert_eq!(stored.hash(), "scanned");
+
+        conn.update(hashed_entry(&path, "old")).await.unwrap();
+        let stored = conn.query_path(path.as_ref()).await.unwrap();
+        apply_scan_hash(&mut conn, &path, stored).await;
+        let entry = conn.query_path(path.as_ref()).await.unwrap().unwrap();
+        assert_eq!(entry.hash(), "scanned");
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn stale_scan_hash_keeps_newer_row() {
+        let root = test_dir("stale-hash");
+        std::fs::create_dir_all(&root).unwrap();
+        let path = format!("{}/file.txt", root);
+        let inserted = format!("{}/inserted.txt", root);
+        std::fs::write(&path, b"file").unwrap();
+        std::fs::write(&inserted, b"inserted").unwrap();
+        let mut conn = load_database(":memory:").await.unwrap();
+
+        // Watcher updates row while scan hashes file walked with `stored`
+        let stored = hashed_entry(&path, "old");
+        conn.insert(stored.clone()).await.unwrap();
+        conn.update(hashed_entry(&path, "watcher")).await.unwrap();
+        apply_scan_hash(&mut conn, &path, Some(stored)).await;
+        let entry = conn.query_path(path.as_ref()).await.unwrap().unwrap();
+        assert_eq!(entry.hash(), "watcher");
+
+        // Watcher inserts row of file walked as new one
+        conn.insert(hashed_entry(&inserted, "watcher"))
+            .await
+            .unwrap();
+        apply_scan_hash(&mut conn, &inserted, None).await;
+        let entry = conn.query_path(inserted.as_ref()).await.unwrap().unwrap();
+        assert_eq!(entry.hash(), "watcher");
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn case_variants_are_folded() {
+        let mut conn = load_database(":memory:").await.unwrap();
+        for path in ["./dir/FILE.txt", "./dir/File.txt", "./dir/other.txt"] {
+            conn.insert(FileEntry::new(path.to_string(), String::new(), 0, 1, false))
+                .await
+                .unwrap();
+        }
+
+        // Not indexed path is found by first variant in byte order
+        let found = query_folded(&mut conn, "./dir/file.txt", CaseSensitivity::Insensitive)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.path(), "./dir/FILE.txt");
+        assert!(
+            query_folded(&mut conn, "./dir/file.txt", CaseSensitivity::Sensitive)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        fold_case(&mut conn, "./dir/file.txt", CaseSensitivity::Sensitive)
+            .await
+            .unwrap();
+        assert_eq!(
+            conn.query_case_variants("./dir/file.txt")
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+
+        // Source of rename which only changes case is kept
+        fold_case_except(
+            &mut conn,
+            "./dir/file.txt",
+            "./dir/File.txt",
+            CaseSensitivity::Insensitive,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            conn.query_case_variants("./dir/file.txt").await.unwrap(),
+            ["./dir/File.txt"]
+        );
+
+        fold_case(&mut conn, "./dir/file.txt", CaseSensitivity::Insensitive)
+            .await
+            .unwrap();
+        assert!(conn
+            .query_case_variants("./dir/file.txt")
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(conn
+            .query_path("./dir/other.txt".as_ref())
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn scan_insert_replaces_case_variant() {
+        let root = test_dir("case");
+        std::fs::create_dir_all(&root).unwrap();
+        let path = format!("{}/file.txt", root);
+        std::fs::write(&path, b"file").unwrap();
+        let mut conn = load_database(":memory:").await.unwrap();
+        let variant = format!("{}/FILE.txt", root);
+        conn.insert(FileEntry::new(variant.clone(), String::new(), 0, 4, false))
+            .await
+            .unwrap();
+
+        apply_hash(
+            &mut conn,
+            Pending::new(&path, None),
+            (Some("scanned".to_string()), vec![], false),
+            CaseSensitivity::Insensitive,
+            &HashOption::default(),
+            &super::ScanProgress::default(),
+        )
+        .await
+        .unwrap();
+        assert!(conn.query_path(variant.as_ref()).await.unwrap().is_none());
+        let entry = conn.query_path(path.as_ref()).await.unwrap().unwrap();
+        assert_eq!(entry.hash(), "scanned");
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn tiering_apply_keeps_stub() {
+        let root = test_dir("tiering");
+        let (path, location) = prepare_cold(&root, "cold.txt", b"cold");
+        let (changed, changed_location) = prepare_cold(&root, "changed.txt", b"hot");
+        let mut conn = load_database(":memory:").await.unwrap();
+        let tiered = Tiered::default();
+
+        Tiering::apply(
+            &mut conn,
+            &tiered,
+            (entry_of(&path), location.clone(), Ok(())),
+        )
+        .await
+        .unwrap();
+        assert!(!Path::new(&path).exists());
+        assert_eq!(tiered.location(&path), Some(location.clone()));
+        assert_eq!(
+            conn.query_tiered().await.unwrap(),
+            [(path.clone(), location.to_str().unwrap().to_string())]
+        );
+
+        // Written after copy started, copy is stale
+        let entry = entry_of(&changed);
+        std::fs::write(&changed, b"hot again").unwrap();
+        Tiering::apply(
+            &mut conn,
+            &tiered,
+            (entry, changed_location.clone(), Ok(())),
+        )
+        .await
+        .unwrap();
+        assert!(Path::new(&changed).exists());
+        assert!(!changed_location.exists());
+        assert!(!tiered.contains(&changed));
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn recall_copies_file_back_once() {
+        let root = test_dir("recall");
+        let (path, location) = prepare_cold(&root, "recalled.txt", b"recalled");
+        std::fs::remove_file(&path).unwrap();
+        let tiered = Tiered::default();
+        tiered.insert(path.clone(), location.clone());
+
+        let (first, second) =
+            tokio::join!(tiered.recall(&path, ".part"), tiered.recall(&path, ".part"));
+        // Second call waits copy of first one
+        assert!(first.unwrap() != second.unwrap());
+        assert_eq!(std::fs::read(&path).unwrap(), b"recalled");
+        assert!(!Path::new(&format!("{}.part", path)).exists());
+        assert!(!tiered.is_recalling(&path));
+        // Listed until file daemon drops cold copy
+        assert!(tiered.contains(&path));
+        assert!(!tiered.recall("./missing", ".part").await.unwrap());
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn test_is_under() {
+        assert!(is_under("./a", "./a"));
+        assert!(is_under("./a/b/c", "./a"));
+        assert!(!is_under("./ab", "./a"));
+        assert!(!is_under("./a", "./a/b"));
+        assert!(is_under("./a", "."));
+    }
+
+    /// Remover of `policy` and file being downloaded under `root`
+    fn prepare_remove(root: &str, policy: &str) -> (Remover, Transfers, String) {
+        std::fs::create_dir_all(root).unwrap();
+        let path = format!("{}/file.txt", root);
+        std::fs::write(&path, b"file").unwrap();
+        let deletion: Deletion = toml::from_str(&format!(
+            "policy = \"{}\"\nwait_timeout = 1\ntrash_dir = \"{}/.trash\"",
+            policy, root
+        ))
+        .unwrap();
+        let transfers = Transfers::default();
+        let remover = Remover::new(&deletion, transfers.clone(), FileEventHelper::detached());
+        (remover, transfers, path)
+    }
+
+    async fn wait_gone(path: &str) {
+        for _ in 0..100 {
+            if !Path::new(path).exists() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("{} is not removed", path);
+    }
+
+    #[tokio::test]
+    async fn remover_removes_idle_path() {
+        let root = test_dir("remove-idle");
+        let (remover, _, path) = prepare_remove(&root, "defer");
+        assert_eq!(remover.remove(&path).await.unwrap(), Removal::Removed);
+        assert!(!Path::new(&path).exists());
+        assert!(!remover.is_pending(&path));
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn remover_waits_download() {
+        let root = test_dir("remove-wait");
+        let (remover, transfers, path) = prepare_remove(&root, "wait");
+
+        let guard = transfers.register(TransferKind::Download, "", &path);
+        let remove = tokio::spawn({
+            let (remover, path) = (remover.clone(), path.clone());
+            async move { remover.remove(&path).await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // New download is refused while removal waits
+        assert!(remover.is_pending(&path));
+        assert!(Path::new(&path).exists());
+        drop(guard);
+        assert_eq!(remove.await.unwrap().unwrap(), Removal::Removed);
+        assert!(!Path::new(&path).exists());
+        assert!(!remover.is_pending(&path));
+
+        // Download lasts longer than `wait_timeout`
+        std::fs::write(&path, b"file").unwrap();
+        let _guard = transfers.register(TransferKind::Download, "", &path);
+        assert!(matches!(
+            remover.remove(&path).await,
+            Err(RemoveError::Busy(1))
+        ));
+        assert!(Path::new(&path).exists());
+        assert!(!remover.is_pending(&path));
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn remover_defers_until_download_finish() {
+        let root = test_dir("remove-defer");
+        let (remover, transfers, path) = prepare_remove(&root, "defer");
+
+        let guard = transfers.register(TransferKind::Download, "", &path);
+        assert_eq!(remover.remove(&path).await.unwrap(), Removal::Deferred);
+        assert!(Path::new(&path).exists());
+        assert!(remover.is_pending(&path));
+        drop(guard);
+        wait_gone(&path).await;
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn remover_trashes_downloaded_path() {
+        let root = test_dir("remove-trash");
+        let (remover, transfers, path) = prepare_remove(&root, "trash");
+
+        let guard = transfers.register(TransferKind::Download, "", &path);
+        assert_eq!(remover.remove(&path).await.unwrap(), Removal::Trashed);
+        assert!(!Path::new(&path).exists());
+        assert!(!remover.is_pending(&path));
+        let trashed = std::fs::read_dir(format!("{}/.trash", root))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        assert_eq!(trashed.len(), 1);
+        assert_eq!(std::fs::read(&trashed[0]).unwrap(), b"file");
+        drop(guard);
+        wait_gone(trashed[0].to_str().unwrap()).await;
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    /// Events sent to file daemon by `f`, as `kind path...`
+    fn tracked(f: impl FnOnce(&mut RenameTracker, &FileEventHelper, &ExcludeSet)) -> Vec<String> {
+        let (helper, mut receiver) = FileEventHelper::new(None);
+        f(
+            &mut RenameTracker::default(),
+            &helper,
+            &ExcludeSet::default(),
+        );
+        std::iter::from_fn(|| receiver.try_recv().ok())
+            .map(|event| match event {
+                FileEvent::Rename(from, to) => format!("rename {} {}", from, to),
+                FileEvent::New(paths) => format!("new {}", paths.join(" ")),
+                FileEvent::Remove(paths) => format!("remove {}", paths.join(" ")),
+                _ => "other".to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rename_both_is_sent_once() {
+        let (from, to) = (PathBuf::from("./a.txt"), PathBuf::from("./b.txt"));
+        // inotify sends `From`, `To` then `Both`
+        let events = tracked(|tracker, helper, exclude| {
+            tracker.from(Some(1), from.clone(), helper, exclude);
+            tracker.to(Some(1), to.clone(), helper, exclude);
+            tracker.both(from.clone(), to.clone(), helper, exclude);
+        });
+        assert_eq!(events, ["rename ./a.txt ./b.txt"]);
+        // Backend sends `Both` only
+        let events = tracked(|tracker, helper, exclude| {
+            tracker.both(from.clone(), to.clone(), helper, exclude);
+            tracker.both(to.clone(), from.clone(), helper, exclude);
+        });
+        assert_eq!(events, ["rename ./a.txt ./b.txt", "rename ./b.txt ./a.txt"]);
+    }
+
+    #[test]
+    fn rename_both_replaces_pending_from() {
+        let (from, to) = (PathBuf::from("./a.txt"), PathBuf::from("./b.txt"));
+        let events = tracked(|tracker, helper, exclude| {
+            tracker.from(None, PathBuf::from("./c.txt"), helper, exclude);
+            tracker.from(None, from.clone(), helper, exclude);
+            tracker.both(from.clone(), to.clone(), helper, exclude);
+        });
+        assert_eq!(events, ["remove ./c.txt", "rename ./a.txt ./b.txt"]);
+    }
+}
@@ -0,0 +1,69 @@
+mod head {
+    use std::io::ErrorKind;
+    use std::path::Path;
+
+    /// Read file, `None` if it does not exist
+    async fn read_optional(path: &Path) -> std::io::Result<Option<String>> {
+        match tokio::fs::read_to_string(path).await {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Commit checked out in repository at `root`, `None` if `root` is not a git repository
+    /// or branch has no commit yet
+    pub async fn head_commit(root: &Path) -> anyhow::Result<Option<String>> {
+        let git_dir = root.join(".git");
+        let Some(head) = read_optional(&git_dir.join("HEAD")).await? else {
+            return Ok(None);
+        };
+        let head = head.trim();
+        let Some(reference) = head.strip_prefix("ref: ") else {
+            // Detached HEAD
+            return Ok(Some(head.to_string()));
+        };
+        if let Some(commit) = read_optional(&git_dir.join(reference)).await? {
+            return Ok(Some(commit.trim().to_string()));
+        }
+        // Reference is packed by `git gc`
+        let Some(packed) = read_optional(&git_dir.join("packed-refs")).await? else {
+            return Ok(None);
+        };
+        Ok(packed
+            .lines()
+            .filter(|line| !line.starts_with(['#', '^']))
+            .find_map(|line| {
+                let (commit, name) = line.split_once(' ')?;
+                (name == reference).then(|| commit.to_string())
+            }))
+    }
+}
+
+mod ignore_rules {
+    use anyhow::anyhow;
+    use ignore::gitignore::{Gitignore, GitignoreBuilder};
+    use std::path::Path;
+
+    /// Rules of `.gitignore` at `root` and `.git/info/exclude`, `.git` itself is always ignored
+    ///
+    /// `.gitignore` in subdirectories is not read
+    pub fn build_gitignore(root: &Path, case_insensitive: bool) -> anyhow::Result<Gitignore> {
+        let mut builder = GitignoreBuilder::new(root);
+        builder.case_insensitive(case_insensitive)?;
+        builder.add_line(None, ".git/")?;
+        for file in [".gitignore", ".git/info/exclude"] {
+            let path = root.join(file);
+            if !path.is_file() {
+                continue;
+            }
+            if let Some(e) = builder.add(&path) {
+                return Err(anyhow!("Unable to parse {:?}: {:?}", path, e));
+            }
+        }
+        Ok(builder.build()?)
+    }
+}
+
+pub use head::head_commit;
+pub use ignore_rules::build_gitignore;
@@ -0,0 +1,307 @@
+mod types {
+    use serde_derive::Deserialize;
+    use std::path::{Component, Path};
+
+    #[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ImportKind {
+        /// Html index page generated by upstream web server, subdirectories are followed
+        #[default]
+        Listing,
+        /// `sha256sum` output, paths are relative to manifest url
+        Manifest,
+    }
+
+    /// Relative path without `..`, can not escape directory it joined to
+    pub fn is_safe_relative(path: &str) -> bool {
+        !path.is_empty()
+            && Path::new(path)
+                .components()
+                .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    }
+}
+
+mod listing {
+    use reqwest::Url;
+
+    /// Links point to entries under listed directory, directories end with `/`
+    pub fn parse_listing(body: &str) -> Vec<String> {
+        let mut links = Vec::new();
+        let mut rest = body;
+        while let Some(pos) = rest.find("href=") {
+            rest = &rest[pos + "href=".len()..];
+            let quote = match rest.chars().next() {
+                Some(quote @ ('"' | '\'')) => quote,
+                _ => continue,
+            };
+            rest = &rest[1..];
+            let Some(end) = rest.find(quote) else {
+                break;
+            };
+            let link = rest[..end].replace("&amp;", "&");
+            rest = &rest[end..];
+            if is_child_link(&link) {
+                links.push(link);
+            }
+        }
+        links.sort();
+        links.dedup();
+        links
+    }
+
+    /// Skip sort links, anchors, parent directory, hidden entries and absolute urls
+    fn is_child_link(link: &str) -> bool {
+        !(link.is_empty()
+            || link.starts_with(['?', '#', '/', '.'])
+            || link.contains(['?', '#'])
+            || Url::parse(link).is_ok())
+    }
+}
+
+mod manifest {
+    /// Parse `sha256sum` output into `(hex digest, path)`, malformed lines are skipped
+    pub fn parse_manifest(body: &str) -> Vec<(String, String)> {
+        body.lines()
+            .filter_map(|line| {
+                let (digest, path) = line.trim_end().split_once(' ')?;
+                // Binary mode is marked with `*` instead of second space
+                let path = path.strip_prefix([' ', '*'])?;
+                (digest.len() == 64 && digest.bytes().all(|b| b.is_ascii_hexdigit()))
+                    .then(|| (digest.to_ascii_lowercase(), path.to_string()))
+            })
+            .collect()
+    }
+}
+
+mod importer {
+    use super::{is_safe_relative, parse_listing, parse_manifest, ImportKind};
+    use crate::configure::current::ImportOption;
+    use crate::file::{unix_now, Jobs};
+    use anyhow::anyhow;
+    use futures::StreamExt;
+    use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+    use publib::file::{get_file_hasher, HashAlgo, HashOption, DEFAULT_BUFFER_SIZE};
+    use reqwest::{Client, Url};
+    use std::path::{Path, PathBuf};
+    use tokio::io::AsyncWriteExt;
+    use tracing::{debug, info, warn};
+
+    /// Keep `/` of manifest path while escaping everything else not allowed in url path
+    const URL_PATH: &AsciiSet = &CONTROLS
+        .add(b' ')
+        .add(b'"')
+        .add(b'#')
+        .add(b'%')
+        .add(b'<')
+        .add(b'>')
+        .add(b'?')
+        .add(b'`')
+        .add(b'{')
+        .add(b'}');
+
+    /// File published by upstream
+    struct Remote {
+        /// Relative to import target
+        path: String,
+        url: Url,
+        /// Hex digest from manifest
+        sha256: Option<String>,
+    }
+
+    /// Mirror upstream into `target` under working directory, files written are indexed by
+    /// file watcher like any other change
+    pub struct Importer {
+        client: Client,
+        option: ImportOption,
+        base: Url,
+    }
+
+    impl Importer {
+        pub fn new(option: ImportOption) -> anyhow::Result<Self> {
+            if !is_safe_relative(option.target()) {
+                return Err(anyhow!(
+                    "Import target should be relative to working directory: {}",
+                    option.target()
+                ));
+            }
+            let mut base = option.url().to_string();
+            // Relative links in index page are resolved against directory
+            if option.kind() == ImportKind::Listing && !base.ends_with('/') {
+                base.push('/');
+            }
+            let base = Url::parse(&base)
+                .map_err(|e| anyhow!("Unable to parse import url {}: {:?}", option.url(), e))?;
+            let client = Client::builder()
+                .user_agent(concat!("fantastic-waffle/", env!("CARGO_PKG_VERSION")))
+                .build()?;
+            Ok(Self {
+                client,
+                option,
+                base,
+            })
+        }
+
+        /// Run import every `interval` as job of file daemon, first run starts immediately
+        pub async fn run_forever(self, jobs: Jobs) {
+            let mut interval = tokio::time::interval(self.option.interval());
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let id = jobs.create(&format!("import {}", self.base));
+                jobs.update(id, |job| job.start(unix_now()));
+                let result = self.run(&jobs, id).await;
+                match &result {
+                    Ok(()) => info!("Import from {} finished", self.base),
+                    Err(e) => warn!("Import from {} failure: {:?}", self.base, e),
+                }
+                jobs.update(id, |job| {
+                    job.finish(unix_now(), result.err().map(|e| e.to_string()))
+                });
+            }
+        }
+
+        async fn run(&self, jobs: &Jobs, id: u64) -> anyhow::Result<()> {
+            let remotes = match self.option.kind() {
+                ImportKind::Listing => self.collect_listing().await?,
+                ImportKind::Manifest => self.collect_manifest().await?,
+            };
+            for remote in remotes {
+                if self.fetch(&remote).await? {
+                    jobs.update(id, |job| job.add_processed(1));
+                }
+            }
+            Ok(())
+        }
+
+        async fn get_text(&self, url: &Url) -> anyhow::Result<String> {
+            Ok(self
+                .client
+                .get(url.clone())
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?)
+        }
+
+        async fn collect_listing(&self) -> anyhow::Result<Vec<Remote>> {
+            let mut remotes = Vec::new();
+            let mut pending = vec![(self.base.clone(), String::new(), 0)];
+            while let Some((url, prefix, depth)) = pending.pop() {
+                let body = self.get_text(&url).await?;
+                for link in parse_listing(&body) {
+                    let Ok(name) = percent_decode_str(&link).decode_utf8() else {
+                        continue;
+                    };
+                    let path = format!("{}{}", prefix, name);
+                    if !is_safe_relative(path.trim_end_matches('/')) {
+                        debug!("Skip unsafe link {:?} in {}", link, url);
+                        continue;
+                    }
+                    let url = url.join(&link)?;
+                    if path.ends_with('/') {
+                        if depth < self.option.max_depth() {
+                            pending.push((url, path, depth + 1));
+                        }
+                    } else {
+                        remotes.push(Remote {
+                            path,
+                            url,
+                            sha256: None,
+                        });
+                    }
+                }
+            }
+            Ok(remotes)
+        }
+
+        async fn collect_manifest(&self) -> anyhow::Result<Vec<Remote>> {
+            let body = self.get_text(&self.base).await?;
+            let mut remotes = Vec::new();
+            for (digest, path) in parse_manifest(&body) {
+                let path = path.trim_start_matches("./").to_string();
+                if !is_safe_relative(&path) {
+                    debug!("Skip unsafe manifest entry {:?}", path);
+                    continue;
+                }
+                let url = self
+                    .base
+                    .join(&utf8_percent_encode(&path, URL_PATH).to_string())?;
+                remotes.push(Remote {
+                    path,
+                    url,
+                    sha256: Some(digest),
+                });
+            }
+            Ok(remotes)
+        }
+
+        /// Whether local copy is same as upstream, only size is compared if no digest known
+        async fn is_current(&self, remote: &Remote, local: &Path) -> anyhow::Result<bool> {
+            let Ok(metadata) = tokio::fs::metadata(local).await else {
+                return Ok(false);
+            };
+            if let Some(digest) = &remote.sha256 {
+                let option = HashOption::new(HashAlgo::Sha256, DEFAULT_BUFFER_SIZE, false);
+                let hasher = get_file_hasher(local, &option).await?;
+                return Ok(hasher.digest() == format!("sha256:{}", digest));
+            }
+            let response = self
+                .client
+                .head(remote.url.clone())
+                .send()
+                .await?
+                .error_for_status()?;
+            Ok(response
+                .content_length()
+                .is_none_or(|length| length == metadata.len()))
+        }
+
+        /// Return `false` if local copy is up to date
+        async fn fetch(&self, remote: &Remote) -> anyhow::Result<bool> {
+            let local = PathBuf::from(self.option.target()).join(&remote.path);
+            if self.is_current(remote, &local).await? {
+                return Ok(false);
+            }
+            debug!("Import {} to {:?}", remote.url, local);
+
+            let response = self
+                .client
+                .get(remote.url.clone())
+                .send()
+                .await?
+                .error_for_status()?;
+            if let Some(parent) = local.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            let mut part = local.clone().into_os_string();
+            part.push(".part");
+            let part = PathBuf::from(part);
+
+            let mut file = tokio::fs::File::create(&part).await?;
+            let mut hasher = HashAlgo::Sha256.hasher();
+            let mut stream = response.bytes_stream();
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk?;
+                hasher.update(&chunk);
+                file.write_all(&chunk).await?;
+            }
+            file.flush().await?;
+            drop(file);
+
+            if let Some(digest) = &remote.sha256 {
+                if hasher.digest() != format!("sha256:{}", digest) {
+                    tokio::fs::remove_file(&part).await.ok();
+                    return Err(anyhow!("Checksum mismatch of {}", remote.url));
+                }
+            }
+            tokio::fs::rename(&part, &local).await?;
+            Ok(true)
+        }
+    }
+}
+
+pub use importer::Importer;
+pub use listing::parse_listing;
+pub use manifest::parse_manifest;
+pub use types::{is_safe_relative, ImportKind};
@@ -1,66 +1,106 @@
-#![feature(async_closure)]
 #![feature(result_option_inspect)]
+#![cfg_attr(test, feature(test))]
 
+mod bundle;
 mod configure;
+mod crypto;
 mod database;
 mod file;
+mod git;
+mod import;
+mod mount;
+mod runtime;
 mod server;
+mod sink;
+mod systemd;
+mod update;
 
-use crate::configure::current::Configure;
-use crate::database::load_database;
-use crate::file::{init_files, FileDaemon, FileWatcher};
-use crate::server::{router_start, DEFAULT_WAIT_TIME, DEFAULT_WAIT_TIME_STR};
+use crate::bundle::{export_bundle, import_bundle, read_manifest, ExportOption};
+use crate::configure::current::{Configure, LogFormat, LogOption};
+use crate::crypto::encrypt_tree;
+use crate::database::{open_database, BusyPolicy};
+use crate::file::{
+    replay_events, DaemonOptions, EventLog, FileDaemon, FileEventHelper, FileWatcher, Throttle,
+    WarmCache,
+};
+use crate::import::Importer;
+use crate::runtime::Runtime;
+use crate::server::{
+    router_start, ConcurrencyLimiter, LoadShedder, Services, DEFAULT_WAIT_TIME,
+    DEFAULT_WAIT_TIME_STR,
+};
+use crate::sink::{build_sink, run_sink};
+use crate::systemd::{notify, take_listener, Listener, READY, STOPPING};
+use crate::update::{check_update_periodically, fetch_release, CURRENT_VERSION};
 use anyhow::anyhow;
-use clap::{arg, command};
-use log::{debug, warn};
+use clap::{arg, command, ArgMatches, Command};
 use publib::append_current_path;
 use publib::types::ExitExt;
 use std::env;
-use std::future::Future;
+use std::path::PathBuf;
 use std::sync::Arc;
 use tap::TapOptional;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::RwLock;
-use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+use tracing_subscriber::EnvFilter;
 
 const DEFAULT_CONFIGURE_FILE: &str = "config.toml";
 
-async fn wait_to_stop<Fut>(kill: impl FnOnce() -> Fut) -> !
-where
-    Fut: Future<Output = ()>,
-{
-    use log::{error, info, trace};
-    tokio::signal::ctrl_c().await.unwrap();
-    info!("Recv SIGINT, send signal to thread.");
-    kill().await;
-    trace!("Send signal!");
-    tokio::signal::ctrl_c().await.unwrap();
-    error!("Force exit program.");
-    std::process::exit(137);
+/// Wait SIGINT or SIGTERM
+async fn wait_signal() {
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        ret = tokio::signal::ctrl_c() => {
+            ret.unwrap();
+            info!("Recv SIGINT, shut down.");
+        }
+        _ = terminate.recv() => {
+            info!("Recv SIGTERM, shut down.");
+        }
+    }
 }
 
-async fn server_handler_waiter(
-    web_server: JoinHandle<std::io::Result<()>>,
-    file_watcher: FileWatcher,
-    file_daemon: FileDaemon,
-) -> anyhow::Result<()> {
-    web_server.await??;
-
-    file_watcher.stop(|| warn!("File watcher thread not stopped"));
-
-    file_daemon.into_inner().await??;
+fn init_logger(option: &LogOption) {
+    let builder = tracing_subscriber::fmt().with_env_filter(EnvFilter::from_default_env());
+    match option.format() {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
 
-    Ok(())
+/// Reload configure file when receive SIGHUP
+async fn reload_on_hangup(helper: FileEventHelper, config_path: PathBuf) -> anyhow::Result<()> {
+    let mut hangup = signal(SignalKind::hangup()).unwrap();
+    loop {
+        hangup.recv().await;
+        info!("Recv SIGHUP, reload configure file.");
+        helper
+            .send_configure_updated(config_path.to_str().unwrap().to_string())
+            .await
+            .tap_none(|| warn!("Unable send event to file daemon, maybe consumer has dropped!"));
+    }
 }
 
 async fn async_main(
     config_path: String,
+    config: Configure,
+    listener: Option<Listener>,
     host: Option<&String>,
     port: Option<&u16>,
     skip_check: bool,
+    encrypt: Option<&String>,
 ) -> anyhow::Result<()> {
-    let config = Configure::load(config_path.clone()).await?;
+    init_logger(config.log());
 
-    let mut database = load_database(&config.database())
+    let sqlite = config.sqlite();
+    BusyPolicy::new(
+        sqlite.busy_timeout(),
+        sqlite.busy_retries(),
+        sqlite.busy_retry_delay(),
+    )
+    .install();
+    let (mut database, in_memory) = open_database(&config.database(), config.database_url())
         .await
         .map_err(|e| anyhow!("Unable to load database: {:?}", e))?;
 
@@ -69,40 +109,366 @@ async fn async_main(
     env::set_current_dir(shellexpand::tilde(config.working_directory()).as_ref())
         .map_err(|e| anyhow!("Unable change directory: {:?}", e))?;
 
+    config.features().validate()?;
+    if config.firewall().client_ip_header().is_some() && !config.firewall().has_trusted_proxies() {
+        warn!("client_ip_header is ignored since trusted_proxies is empty");
+    }
     let bind = config.parse_host_and_port(host, port);
-    let user_pool = Arc::new(RwLock::new(config.build_hashmap()));
+    let user_pool = Arc::new(RwLock::new(config.build_hashmap()?));
+    let exclude = Arc::new(config.build_exclude_set()?);
+    config
+        .build_mounts()
+        .map_err(|e| anyhow!("Unable to load mount: {:?}", e))?
+        .install();
+    let public = config.public().build_matcher()?;
+    let ring = config.cluster().build_ring()?;
+    let cors = config.cors().build_layer()?;
+    let warm_cache = WarmCache::new(config.cache().warm())
+        .map_err(|e| anyhow!("Unable to parse warm cache pattern: {:?}", e))?;
+    let key = config.encryption().load_key().await?;
+
+    if let Some(path) = encrypt {
+        let key = key.ok_or_else(|| anyhow!("Encryption key is not configured"))?;
+        let count = encrypt_tree(path.as_ref(), key, &exclude)
+            .await
+            .map_err(|e| anyhow!("Encrypt files failure: {:?}", e))?;
+        info!("Encrypted {} file(s)", count);
+        return Ok(());
+    }
 
     debug!("Current dir: {:?}", std::env::current_dir());
 
-    if !skip_check {
-        init_files(&mut database, ".")
+    let event_log = match config.event_log().path() {
+        Some(path) => {
+            let checkpoint = database.query_event_checkpoint().await?;
+            let event_log = EventLog::open(
+                shellexpand::tilde(path).as_ref(),
+                config.event_log().max_size(),
+                config.event_log().keep(),
+                checkpoint,
+            )
+            .map_err(|e| anyhow!("Unable to open event log: {:?}", e))?;
+            // In-memory index is built by scan, nothing to recover
+            if !in_memory {
+                let count = replay_events(
+                    database.as_mut(),
+                    &event_log,
+                    &exclude,
+                    &config.hash(),
+                    config.case_sensitivity(),
+                )
+                .await
+                .map_err(|e| anyhow!("Replay event log failure: {:?}", e))?;
+                if count > 0 {
+                    info!("Replayed {} event(s) from event log", count);
+                }
+            }
+            Some(event_log)
+        }
+        None => None,
+    };
+
+    let importers = config
+        .import()
+        .iter()
+        .map(|option| Importer::new(option.clone()))
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map_err(|e| anyhow!("Unable to start importer: {:?}", e))?;
+
+    let mut sinks = Vec::new();
+    for option in config.event_sink() {
+        let sink = build_sink(option)
             .await
-            .map_err(|e| anyhow!("Init files failure: {:?}", e))?;
+            .map_err(|e| anyhow!("Unable to start event sink: {:?}", e))?;
+        sinks.push((sink, option.format()));
     }
 
-    let (file_daemon, file_event_helper) = FileDaemon::start(database, user_pool.clone());
+    let commit = if config.git().enabled() {
+        database.query_git_commit().await?
+    } else {
+        None
+    };
 
-    let (web_server, server_handler) = router_start(bind, user_pool, file_event_helper.clone());
+    let (file_daemon, file_event_helper) = FileDaemon::start(
+        database,
+        user_pool.clone(),
+        event_log,
+        DaemonOptions {
+            exclude: exclude.clone(),
+            hash: config.hash(),
+            git: config.git().enabled(),
+            case: config.case_sensitivity(),
+            limits: config.limits().path(),
+            scrub: config.scrub(),
+            tiering: config.tiering().clone(),
+            settle: config.watcher().settle_interval(),
+            hash_workers: config.tuning().hash_workers(),
+            access_retention: config.access_log().retention(),
+        },
+    );
+    // Commit is updated once next scan finished
+    file_event_helper.scan_progress().set_commit(commit);
 
-    let file_watcher = FileWatcher::start(".", config_path, file_event_helper.clone());
+    let mut runtime = Runtime::new();
+    let helper = file_event_helper.clone();
+    // Requests still being drained by web server need file daemon
+    runtime.spawn_late("file daemon", |shutdown| async move {
+        let daemon = file_daemon.run();
+        tokio::pin!(daemon);
+        tokio::select! {
+            result = &mut daemon => return result,
+            _ = shutdown.cancelled() => {}
+        }
+        helper
+            .send_terminate()
+            .await
+            .tap_none(|| warn!("Unable send event to file daemon, maybe consumer has dropped!"));
+        daemon.await
+    });
 
-    tokio::select! {
-        _ =
-            wait_to_stop(async || {
-                server_handler.shutdown();
-                file_event_helper
-                    .send_terminate()
-                    .await
-                    .tap_none(|| warn!("Unable send event to file daemon, maybe consumer has dropped!"));
-            }) => {
-            unreachable!()
+    if listener.is_some() {
+        info!("Listen on socket passed by systemd, {} is ignored", bind);
+    }
+    let services = Services {
+        user_pool,
+        key,
+        throttle: Throttle::new(
+            config.limits().connection_rate(),
+            config.limits().global_rate(),
+        ),
+        features: config.features(),
+        limiter: ConcurrencyLimiter::new(config.limits().concurrent_requests()),
+        signer: config.signing().build_signer(),
+        shedder: LoadShedder::new(
+            config.limits().shed_queue_depth(),
+            config.limits().shed_in_flight(),
+            config.limits().retry_after(),
+        ),
+        public,
+        ring,
+        path_limits: config.limits().path(),
+        compression: config.compression(),
+        digest: config.digest(),
+        firewall: Arc::new(config.firewall().clone()),
+        tiering: config.tiering().clone(),
+        ..Services::new(
+            file_event_helper.clone(),
+            exclude.clone(),
+            config.hash(),
+            config.deletion(),
+        )
+    };
+    let (web_server, server_handler) = router_start(bind, listener, services, cors);
+
+    // Scan runs inside file daemon, requests are served while scanning
+    if in_memory {
+        if skip_check {
+            warn!("Index is in memory, --skip-check is ignored");
         }
+        info!("Index is in memory, queries are incomplete until initial scan finished");
+    } else {
+        file_event_helper.scan_progress().set_ready();
+    }
+    if !skip_check || in_memory {
+        file_event_helper
+            .send_rescan("initial scan")
+            .await
+            .tap_none(|| warn!("Unable send event to file daemon, maybe consumer has dropped!"));
+    }
 
-        ret = server_handler_waiter(web_server, file_watcher, file_daemon) => {
-            ret?;
+    let drain = config.server().shutdown_timeout();
+    runtime.spawn_with_shutdown("web server", |shutdown| async move {
+        tokio::pin!(web_server);
+        tokio::select! {
+            result = &mut web_server => return Ok(result?),
+            _ = shutdown.cancelled() => {}
+        }
+        let open = server_handler.connection_count();
+        if open > 0 {
+            info!("Wait {} connection(s) up to {:?}", open, drain);
+        }
+        server_handler.graceful_shutdown(Some(drain));
+        tokio::select! {
+            result = &mut web_server => return Ok(result?),
+            _ = tokio::time::sleep(drain) => {}
         }
+        // Server aborts connections still open once timeout elapsed
+        let aborted = server_handler.connection_count();
+        if aborted > 0 {
+            warn!("{} connection(s) aborted after drain timeout", aborted);
+        }
+        Ok(web_server.await?)
+    });
+
+    // Database is open and initial scan is queued
+    notify(READY);
+    runtime.spawn_with_shutdown("systemd notifier", |shutdown| async move {
+        shutdown.cancelled().await;
+        notify(STOPPING);
+        Ok(())
+    });
+
+    for (sink, format) in sinks {
+        let changes = file_event_helper.subscribe();
+        runtime.spawn("event sink", async move {
+            run_sink(sink, format, changes).await;
+            Ok(())
+        });
     }
 
+    for importer in importers {
+        let jobs = file_event_helper.jobs().clone();
+        runtime.spawn("importer", async move {
+            importer.run_forever(jobs).await;
+            Ok(())
+        });
+    }
+
+    if let (Some(url), Some(interval)) = (
+        config.update().manifest_url(),
+        config.update().check_interval(),
+    ) {
+        let url = url.to_string();
+        runtime.spawn("update check", async move {
+            check_update_periodically(url, interval).await;
+            Ok(())
+        });
+    }
+
+    if !warm_cache.is_empty() {
+        let (exclude, changes) = (exclude.clone(), file_event_helper.subscribe());
+        runtime.spawn("warm cache", async move {
+            warm_cache.run(exclude, changes).await;
+            Ok(())
+        });
+    }
+
+    let file_watcher = FileWatcher::start(
+        ".",
+        config_path.clone(),
+        file_event_helper.clone(),
+        exclude,
+        config.watcher(),
+    );
+    runtime.spawn_with_shutdown("file watcher", |shutdown| async move {
+        shutdown.cancelled().await;
+        tokio::task::spawn_blocking(move || {
+            file_watcher.stop(|| warn!("File watcher thread not stopped"))
+        })
+        .await?;
+        Ok(())
+    });
+
+    runtime.spawn(
+        "configure reloader",
+        reload_on_hangup(file_event_helper, config_path),
+    );
+    runtime.spawn("signal handler", async {
+        wait_signal().await;
+        Ok(())
+    });
+
+    // Second signal exits immediately if shutdown is stuck
+    runtime.run(wait_signal()).await
+}
+
+/// Print whether newer release is published, nothing is installed
+async fn check_update(config_path: String) -> anyhow::Result<()> {
+    let config = Configure::load(config_path).await?;
+    let url = config
+        .update()
+        .manifest_url()
+        .ok_or_else(|| anyhow!("Release manifest url is not configured"))?;
+    let release = fetch_release(url).await?;
+    if release.is_newer() {
+        println!(
+            "Release {} is available, running {}",
+            release.version(),
+            CURRENT_VERSION
+        );
+        if let Some(url) = release.url() {
+            println!("{}", url);
+        }
+    } else {
+        println!("Running latest release {}", CURRENT_VERSION);
+    }
+    Ok(())
+}
+
+/// Export files under prefix to single archive, or import such archive into working directory
+async fn bundle(config: Configure, command: &ArgMatches) -> anyhow::Result<()> {
+    init_logger(config.log());
+
+    // Paths are relative to directory where command is run
+    let paths = command.subcommand().map(|(name, sub)| {
+        let file = sub
+            .get_one::<String>(if name == "export" { "OUTPUT" } else { "INPUT" })
+            .unwrap();
+        (name, sub, append_current_path(file))
+    });
+    let base = command
+        .subcommand_matches("export")
+        .and_then(|sub| sub.get_one::<String>("base"))
+        .map(|path| append_current_path(path));
+    env::set_current_dir(shellexpand::tilde(config.working_directory()).as_ref())
+        .map_err(|e| anyhow!("Unable change directory: {:?}", e))?;
+    config
+        .build_mounts()
+        .map_err(|e| anyhow!("Unable to load mount: {:?}", e))?
+        .install();
+    let key = config.encryption().load_key().await?;
+
+    match paths {
+        Some(("export", sub, output)) => {
+            let base = match base {
+                Some(path) => Some(
+                    read_manifest(&path, key.clone())
+                        .await
+                        .map_err(|e| anyhow!("Unable to read base bundle: {:?}", e))?,
+                ),
+                None => None,
+            };
+            let bundle_key = if sub.get_flag("encrypt") {
+                Some(
+                    key.clone()
+                        .ok_or_else(|| anyhow!("Encryption key is not configured"))?,
+                )
+            } else {
+                None
+            };
+            let count = export_bundle(
+                sub.get_one::<String>("PREFIX").unwrap(),
+                output.as_ref(),
+                &config.hash(),
+                &config.build_exclude_set()?,
+                key.as_ref(),
+                ExportOption {
+                    compress: sub.get_flag("zstd"),
+                    key: bundle_key,
+                    base: base.as_ref(),
+                },
+            )
+            .await
+            .map_err(|e| anyhow!("Export bundle failure: {:?}", e))?;
+            println!("Exported {} file(s) to {}", count, output.display());
+        }
+        Some(("import", _, input)) => {
+            let suffix = config
+                .scanner()
+                .partial_suffixes()
+                .first()
+                .map(String::as_str)
+                .unwrap_or(".part");
+            let summary = import_bundle(input.as_ref(), key, suffix)
+                .await
+                .map_err(|e| anyhow!("Import bundle failure: {:?}", e))?;
+            println!(
+                "Imported {} file(s), {} unchanged, {} deleted",
+                summary.written, summary.skipped, summary.deleted
+            );
+        }
+        _ => unreachable!(),
+    }
     Ok(())
 }
 
@@ -113,13 +479,51 @@ fn main() -> anyhow::Result<()> {
                 .default_value(DEFAULT_CONFIGURE_FILE),
             arg!(-l --listen <HOST> "Override server listen host"),
             arg!(-p --port <PORT> "Override server port"),
-            arg!(--"skip-check" "Skip check existing files"),
+            arg!(--"skip-check" "Skip check existing files, ignored if index is in memory"),
+            arg!(--encrypt <PATH> "Encrypt existing files under path (relative to working directory) and exit"),
             arg!(--"server-timeout" <SERVER_TIMEOUT> "Override sever request timeout, if set more than 3, it will always set as 3")
                 .default_value(DEFAULT_WAIT_TIME_STR),
+            arg!(--"worker-threads" <COUNT> "Override worker threads of runtime")
+                .value_parser(clap::value_parser!(usize)),
+            arg!(--"blocking-threads" <COUNT> "Override max threads of file system operations")
+                .value_parser(clap::value_parser!(usize)),
+            arg!(--"hash-workers" <COUNT> "Override files hashed at once by scan")
+                .value_parser(clap::value_parser!(usize)),
         ])
+        .subcommand(
+            Command::new("check-update")
+                .about("Compare running version with release manifest in configure and exit"),
+        )
+        .subcommand(
+            Command::new("bundle")
+                .about("Transfer files between servers by single archive")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("export")
+                        .about("Write files under prefix with manifest to archive")
+                        .args(&[
+                            arg!(<PREFIX> "Index path, e.g. ./dist"),
+                            arg!(<OUTPUT> "Archive location"),
+                            arg!(--zstd "Compress archive by zstd"),
+                            arg!(--encrypt "Encrypt archive by configured key"),
+                            arg!(--base <BUNDLE> "Only archive changes since previous bundle"),
+                        ]),
+                )
+                .subcommand(
+                    Command::new("import")
+                        .about("Verify and extract archive into working directory")
+                        .arg(arg!(<INPUT> "Archive location")),
+                ),
+        )
         .get_matches();
-    env_logger::Builder::from_default_env().init();
-
+    let config_path = matches.get_one::<String>("config").unwrap().to_string();
+    if matches.subcommand_matches("check-update").is_some() {
+        return tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(check_update(config_path));
+    }
     server::WAIT_TIME
         .set({
             let set_time: u64 = match matches.get_one::<String>("server-timeout").unwrap().parse() {
@@ -134,15 +538,41 @@ fn main() -> anyhow::Result<()> {
         })
         .unwrap();
 
-    tokio::runtime::Builder::new_multi_thread()
+    // Environment is modified, no thread is started yet
+    let listener = take_listener()?;
+
+    // Thread counts are needed before runtime is built
+    let mut config = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()
         .unwrap()
-        .block_on(async_main(
-            matches.get_one::<String>("config").unwrap().to_string(),
-            matches.get_one::<String>("listen"),
-            matches.get_one::<u16>("port"),
-            matches.get_flag("skip-check"),
-        ))?;
+        .block_on(Configure::load(config_path.clone()))?;
+    config.override_tuning(
+        matches.get_one::<usize>("worker-threads").copied(),
+        matches.get_one::<usize>("blocking-threads").copied(),
+        matches.get_one::<usize>("hash-workers").copied(),
+    );
+    let tuning = config.tuning();
+
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    if let Some(threads) = tuning.worker_threads() {
+        builder.worker_threads(threads);
+    }
+    if let Some(threads) = tuning.blocking_threads() {
+        builder.max_blocking_threads(threads);
+    }
+    let runtime = builder.enable_all().build().unwrap();
+    if let Some(command) = matches.subcommand_matches("bundle") {
+        return runtime.block_on(bundle(config, command));
+    }
+    runtime.block_on(async_main(
+        config_path,
+        config,
+        listener,
+        matches.get_one::<String>("listen"),
+        matches.get_one::<u16>("port"),
+        matches.get_flag("skip-check"),
+        matches.get_one::<String>("encrypt"),
+    ))?;
     Ok(())
 }
@@ -0,0 +1,357 @@
+mod table {
+    use anyhow::anyhow;
+    use publib::{nfd_path, normalize_path, PATH_UTF8_ERROR};
+    use std::path::{Component, Path, PathBuf};
+    use std::sync::OnceLock;
+
+    static MOUNTS: OnceLock<Mounts> = OnceLock::new();
+
+    /// Directory outside of working directory, published as `./<name>`, name of placement
+    /// rule may contain `/`
+    #[derive(Clone, Debug)]
+    struct Mount {
+        name: String,
+        /// Canonical path
+        path: PathBuf,
+    }
+
+    /// Virtual roots beside working directory, index paths under `./<name>` are read from
+    /// mounted directory instead, longest name is used if names are nested
+    #[derive(Clone, Debug, Default)]
+    pub struct Mounts {
+        mounts: Vec<Mount>,
+    }
+
+    impl Mounts {
+        /// Should be called in working directory, name can not shadow entry of it
+        pub fn new<I: IntoIterator<Item = (String, PathBuf)>>(list: I) -> anyhow::Result<Self> {
+            let mut mounts: Vec<Mount> = Vec::new();
+            for (name, path) in list {
+                let name = name
+                    .trim_start_matches("./")
+                    .trim_end_matches('/')
+                    .to_string();
+                if name.is_empty()
+                    || !Path::new(&name)
+                        .components()
+                        .all(|component| matches!(component, Component::Normal(_)))
+                {
+                    return Err(anyhow!("Invalid mount name: {:?}", name));
+                }
+                if mounts.iter().any(|mount| mount.name == name) {
+                    return Err(anyhow!("Duplicate mount name: {:?}", name));
+                }
+                if Path::new(&name).exists() {
+                    return Err(anyhow!(
+                        "Mount {:?} shadows entry of working directory",
+                        name
+                    ));
+                }
+                let path = path
+                    .canonicalize()
+                    .map_err(|e| anyhow!("Unable to open mount {:?}: {:?}", name, e))?;
+                if !path.is_dir() {
+                    return Err(anyhow!("Mount {:?} is not a directory", name));
+                }
+                mounts.push(Mount { name, path });
+            }
+            Ok(Self { mounts })
+        }
+
+        /// Install as mounts used by scanner, watcher and web server, only first call works
+        pub fn install(self) {
+            MOUNTS.set(self).ok();
+        }
+
+        /// Installed mounts, empty if none configured
+        pub fn global() -> &'static Self {
+            MOUNTS.get_or_init(Self::default)
+        }
+
+        /// Mount and rest of relative index path under it
+        fn find<'a>(&self, path: &'a str) -> Option<(&Mount, &'a str)> {
+            let path = path.strip_prefix("./")?;
+            self.mounts
+                .iter()
+                .filter_map(|mount| {
+                    let rest = path.strip_prefix(mount.name.as_str())?;
+                    match rest.strip_prefix('/') {
+                        Some(rest) => Some((mount, rest)),
+                        None => rest.is_empty().then_some((mount, rest)),
+                    }
+                })
+                .max_by_key(|(mount, _)| mount.name.len())
+        }
+
+        /// File system path of index path (`./foo/bar`)
+        ///
+        /// Index path is composed (NFC), decomposed name is used for each component if only
+        /// it exists on disk
+        pub fn resolve(&self, path: &str) -> PathBuf {
+            let path = normalize_path(path);
+            let resolved = match self.find(&path) {
+                Some((mount, "")) => mount.path.clone(),
+                Some((mount, rest)) => mount.path.join(rest),
+                None => PathBuf::from(&path),
+            };
+            if path.is_ascii() || resolved.exists() {
+                return resolved;
+            }
+            // Directory created on macOS may hold file copied from other platforms, so
+            // names are not in same form along the path
+            let mut found = PathBuf::new();
+            for component in resolved.components() {
+                let composed = found.join(component);
+                let name = component.as_os_str().to_str().expect(PATH_UTF8_ERROR);
+                if name.is_ascii() || composed.exists() {
+                    found = composed;
+                    continue;
+                }
+                let decomposed = found.join(nfd_path(name));
+                found = if decomposed.exists() {
+                    decomposed
+                } else {
+                    composed
+                };
+            }
+            found
+        }
+
+        /// Index path of file system path, reverse of `resolve`
+        pub fn to_index<P: AsRef<Path>>(&self, path: P) -> String {
+            let path = path.as_ref();
+            self.mounts
+                .iter()
+                .filter_map(|mount| Some((mount, path.strip_prefix(&mount.path).ok()?)))
+                .max_by_key(|(mount, _)| mount.path.as_os_str().len())
+                .map(|(mount, rest)| normalize_path(Path::new(&mount.name).join(rest)))
+                .unwrap_or_else(|| normalize_path(path))
+        }
+
+        /// Index path of mount `path` is under, `.` if it is under working directory only
+        pub fn root_of(&self, path: &str) -> String {
+            match self.find(&normalize_path(path)) {
+                Some((mount, _)) => format!("./{}", mount.name),
+                None => ".".to_string(),
+            }
+        }
+
+        /// `(index path, file system path)` of each mount
+        pub fn roots(&self) -> impl Iterator<Item = (String, &Path)> {
+            self.mounts
+                .iter()
+                .map(|mount| (format!("./{}", mount.name), mount.path.as_path()))
+        }
+
+        /// Roots placed inside index directory `path`, they are not reached by walking it
+        pub fn roots_under<'a>(
+            &'a self,
+            path: &'a str,
+        ) -> impl Iterator<Item = (String, &'a Path)> {
+            self.roots().filter(move |(name, _)| {
+                path == "."
+                    || name
+                        .strip_prefix(path)
+                        .is_some_and(|rest| rest.starts_with('/'))
+            })
+        }
+
+        /// Like `publib::check_penetration`, but path under mount can not escape mount
+        pub fn check_penetration(&self, path: &str) -> bool {
+            let normalized = normalize_path(path);
+            let resolved = self.resolve(&normalized);
+            match self.find(&normalized) {
+                Some((mount, _)) => match resolved.canonicalize() {
+                    Ok(resolved) => resolved.starts_with(&mount.path),
+                    Err(_) => false,
+                },
+                None => publib::check_penetration(resolved.to_str().expect(PATH_UTF8_ERROR)),
+            }
+        }
+
+        /// Like `check_penetration` for path not created yet, `..` is rejected and nearest
+        /// existing ancestor is resolved instead
+        pub fn check_new_penetration(&self, path: &str) -> bool {
+            if Path::new(path)
+                .components()
+                .any(|component| component == Component::ParentDir)
+            {
+                return false;
+            }
+            let normalized = normalize_path(path);
+            let mut resolved = self.resolve(&normalized);
+            // Dangling symlink is kept, so it fails to canonicalize below
+            while resolved.symlink_metadata().is_err() {
+                if !resolved.pop() {
+                    return false;
+                }
+            }
+            match self.find(&normalized) {
+                Some((mount, _)) => match resolved.canonicalize() {
+                    Ok(resolved) => resolved.starts_with(&mount.path),
+                    Err(_) => false,
+                },
+                None => publib::check_penetration(resolved.to_str().expect(PATH_UTF8_ERROR)),
+            }
+        }
+    }
+}
+
+pub use table::Mounts;
+
+#[cfg(test)]
+mod test {
+    use super::Mounts;
+    use std::path::{Path, PathBuf};
+
+    fn test_dir(name: &str) -> PathBuf {
+        PathBuf::from(format!(
+            "./target/mount-test-{}-{}",
+            name,
+            std::process::id()
+        ))
+    }
+
+    /// `media` and nested `media/cold` mounted from separate directories under `root`
+    fn prepare(root: &Path) -> (Mounts, PathBuf, PathBuf) {
+        let (media, cold) = (root.join("media"), root.join("cold"));
+        std::fs::create_dir_all(&media).unwrap();
+        std::fs::create_dir_all(&cold).unwrap();
+        let mounts = Mounts::new([
+            ("./media/".to_string(), media.clone()),
+            ("media/cold".to_string(), cold.clone()),
+        ])
+        .unwrap();
+        (
+            mounts,
+            media.canonicalize().unwrap(),
+            cold.canonicalize().unwrap(),
+        )
+    }
+
+    #[test]
+    fn nested_mount_is_found_by_longest_name() {
+        let root = test_dir("nested");
+        let (mounts, media, cold) = prepare(&root);
+        assert_eq!(mounts.resolve("./media/a.txt"), media.join("a.txt"));
+        assert_eq!(mounts.resolve("./media/cold/b.txt"), cold.join("b.txt"));
+        assert_eq!(mounts.resolve("./media/cold"), cold);
+        assert_eq!(
+            mounts.resolve("./media/colder/c.txt"),
+            media.join("colder/c.txt")
+        );
+        assert_eq!(
+            mounts.resolve("./mediax/d.txt"),
+            PathBuf::from("./mediax/d.txt")
+        );
+        assert_eq!(mounts.root_of("./media/cold/b.txt"), "./media/cold");
+        assert_eq!(mounts.root_of("./media/colder"), "./media");
+        assert_eq!(mounts.root_of("./src/main.rs"), ".");
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn decomposed_name_is_resolved_per_component() {
+        let root = test_dir("decomposed");
+        let (mounts, media, _) = prepare(&root);
+        // Directory in NFD holds file in NFC
+        let dir = media.join("e\u{301}te\u{301}");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("caf\u{e9}.txt"), b"").unwrap();
+        std::fs::write(dir.join("re\u{301}sume\u{301}.txt"), b"").unwrap();
+
+        assert_eq!(
+            mounts.resolve("./media/\u{e9}t\u{e9}/caf\u{e9}.txt"),
+            dir.join("caf\u{e9}.txt")
+        );
+        assert_eq!(
+            mounts.resolve("./media/\u{e9}t\u{e9}/r\u{e9}sum\u{e9}.txt"),
+            dir.join("re\u{301}sume\u{301}.txt")
+        );
+        // Missing name is kept composed
+        assert_eq!(
+            mounts.resolve("./media/\u{e9}t\u{e9}/na\u{ef}ve.txt"),
+            dir.join("na\u{ef}ve.txt")
+        );
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn to_index_reverses_resolve() {
+        let root = test_dir("to-index");
+        let (mounts, _, _) = prepare(&root);
+        for path in [
+            "./media",
+            "./media/a.txt",
+            "./media/cold",
+            "./media/cold/b.txt",
+            "./src/main.rs",
+        ] {
+            assert_eq!(mounts.to_index(mounts.resolve(path)), path);
+        }
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn symlink_under_mount_cannot_escape() {
+        let root = test_dir("escape");
+        let (mounts, media, _) = prepare(&root);
+        let outside = root.join("outside");
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(outside.join("secret.txt"), b"").unwrap();
+        std::fs::write(media.join("a.txt"), b"").unwrap();
+        let outside = outside.canonicalize().unwrap();
+        std::os::unix::fs::symlink(&outside, media.join("link")).unwrap();
+        std::os::unix::fs::symlink(media.join("a.txt"), media.join("inner")).unwrap();
+        std::os::unix::fs::symlink(outside.join("missing"), media.join("dangling")).unwrap();
+
+        assert!(mounts.check_penetration("./media/a.txt"));
+        // Link to file inside mount is kept
+        assert!(mounts.check_penetration("./media/inner"));
+        assert!(!mounts.check_penetration("./media/link/secret.txt"));
+        assert!(!mounts.check_penetration("./media/../outside/secret.txt"));
+
+        assert!(mounts.check_new_penetration("./media/new.txt"));
+        assert!(!mounts.check_new_penetration("./media/link/new.txt"));
+        assert!(!mounts.check_new_penetration("./media/dangling"));
+        assert!(!mounts.check_new_penetration("./media/../outside/new.txt"));
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn roots_under_directory() {
+        let root = test_dir("roots-under");
+        let (mounts, _, _) = prepare(&root);
+        let under = |path| {
+            mounts
+                .roots_under(path)
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(under("."), ["./media", "./media/cold"]);
+        assert_eq!(under("./media"), ["./media/cold"]);
+        assert!(under("./med").is_empty());
+        assert!(under("./media/cold").is_empty());
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn invalid_mount_is_rejected() {
+        let root = test_dir("invalid");
+        std::fs::create_dir_all(&root).unwrap();
+        for list in [
+            vec![("../media", root.clone())],
+            vec![("", root.clone())],
+            vec![("media", root.clone()), ("./media", root.clone())],
+            // Shadows entry of working directory
+            vec![("src", root.clone())],
+            vec![("media", root.join("missing"))],
+        ] {
+            let list = list
+                .into_iter()
+                .map(|(name, path)| (name.to_string(), path));
+            assert!(Mounts::new(list).is_err());
+        }
+        std::fs::remove_dir_all(&root).ok();
+    }
+}
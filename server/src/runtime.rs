@@ -0,0 +1,196 @@
+mod group {
+    use anyhow::anyhow;
+    use futures::FutureExt;
+    use std::future::Future;
+    use std::panic::AssertUnwindSafe;
+    use std::pin::Pin;
+    use tokio::task::{JoinError, JoinSet};
+    use tokio_util::sync::CancellationToken;
+    use tracing::{error, info};
+
+    /// Name of task and its result, panic is converted to error
+    type TaskExit = (&'static str, anyhow::Result<()>);
+
+    /// Owns long-running subsystems, once any of them stopped or failed, the rest are shut down
+    #[derive(Debug, Default)]
+    pub struct Runtime {
+        tasks: JoinSet<TaskExit>,
+        /// Shut down once `tasks` stopped, they may still use these while they shut down
+        late: JoinSet<TaskExit>,
+        shutdown: CancellationToken,
+        late_shutdown: CancellationToken,
+    }
+
+    impl Runtime {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Task is dropped once shutdown started
+        pub fn spawn<F>(&mut self, name: &'static str, future: F)
+        where
+            F: Future<Output = anyhow::Result<()>> + Send + 'static,
+        {
+            self.spawn_with_shutdown(name, |shutdown| async move {
+                tokio::select! {
+                    result = future => result,
+                    _ = shutdown.cancelled() => Ok(()),
+                }
+            });
+        }
+
+        /// Task stops by itself once token is cancelled, e.g. to finish pending writes
+        pub fn spawn_with_shutdown<F, Fut>(&mut self, name: &'static str, task: F)
+        where
+            F: FnOnce(CancellationToken) -> Fut,
+            Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+        {
+            spawn_into(&mut self.tasks, name, task(self.shutdown.clone()));
+        }
+
+        /// Like `spawn_with_shutdown`, but token is cancelled once every other task stopped,
+        /// e.g. file daemon which requests still being drained depend on
+        pub fn spawn_late<F, Fut>(&mut self, name: &'static str, task: F)
+        where
+            F: FnOnce(CancellationToken) -> Fut,
+            Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+        {
+            spawn_into(&mut self.late, name, task(self.late_shutdown.clone()));
+        }
+
+        /// Wait until first task finished, then shut down others and wait them, late tasks
+        /// are shut down last
+        ///
+        /// Process exits immediately if `force` finished before all tasks stopped,
+        /// first failure is returned
+        pub async fn run<F: Future<Output = ()>>(mut self, force: F) -> anyhow::Result<()> {
+            let exit = tokio::select! {
+                Some(exit) = self.tasks.join_next() => exit,
+                Some(exit) = self.late.join_next() => exit,
+                else => return Ok(()),
+            };
+            let mut result = report(exit);
+            info!("Shutting down remaining tasks");
+            tokio::pin!(force);
+            self.shutdown.cancel();
+            drain(&mut self.tasks, force.as_mut(), &mut result).await;
+            self.late_shutdown.cancel();
+            drain(&mut self.late, force.as_mut(), &mut result).await;
+            result
+        }
+    }
+
+    fn spawn_into<Fut>(tasks: &mut JoinSet<TaskExit>, name: &'static str, future: Fut)
+    where
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        tasks.spawn(async move {
+            let result = AssertUnwindSafe(future)
+                .catch_unwind()
+                .await
+                .unwrap_or_else(|_| Err(anyhow!("Task panicked")));
+            (name, result)
+        });
+    }
+
+    /// Wait every task of `tasks`, first failure is kept in `result` if it is still `Ok`
+    async fn drain<F: Future<Output = ()>>(
+        tasks: &mut JoinSet<TaskExit>,
+        mut force: Pin<&mut F>,
+        result: &mut anyhow::Result<()>,
+    ) {
+        loop {
+            tokio::select! {
+                exit = tasks.join_next() => match exit {
+                    Some(exit) => {
+                        let exit = report(exit);
+                        if result.is_ok() {
+                            *result = exit;
+                        }
+                    }
+                    None => break,
+                },
+                _ = &mut force => {
+                    error!("Force exit program.");
+                    std::process::exit(137);
+                }
+            }
+        }
+    }
+
+    fn report(exit: Result<TaskExit, JoinError>) -> anyhow::Result<()> {
+        match exit {
+            Ok((name, Ok(()))) => {
+                info!("{} stopped", name);
+                Ok(())
+            }
+            Ok((name, Err(e))) => {
+                error!("{} failed: {:#}", name, e);
+                Err(e.context(format!("{} failed", name)))
+            }
+            Err(e) => Err(anyhow!("Unable to join task: {:?}", e)),
+        }
+    }
+}
+
+pub use group::Runtime;
+
+#[cfg(test)]
+mod test {
+    use super::Runtime;
+    use anyhow::anyhow;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn late_task_stops_after_others() {
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut runtime = Runtime::new();
+        runtime.spawn("first", async { Ok(()) });
+        let stopped = order.clone();
+        runtime.spawn_with_shutdown("web server", |shutdown| async move {
+            shutdown.cancelled().await;
+            // Draining connection
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            stopped.lock().unwrap().push("web server");
+            Ok(())
+        });
+        let stopped = order.clone();
+        runtime.spawn_late("file daemon", |shutdown| async move {
+            shutdown.cancelled().await;
+            stopped.lock().unwrap().push("file daemon");
+            Ok(())
+        });
+
+        runtime.run(std::future::pending()).await.unwrap();
+        assert_eq!(*order.lock().unwrap(), ["web server", "file daemon"]);
+    }
+
+    #[tokio::test]
+    async fn first_failure_is_returned() {
+        let mut runtime = Runtime::new();
+        runtime.spawn("failed", async { Err(anyhow!("broken")) });
+        runtime.spawn_with_shutdown("panicked", |shutdown| async move {
+            shutdown.cancelled().await;
+            panic!("panicked while shutting down");
+        });
+        runtime.spawn_late("late", |shutdown| async move {
+            shutdown.cancelled().await;
+            Err(anyhow!("late failure"))
+        });
+
+        let error = runtime.run(std::future::pending()).await.unwrap_err();
+        assert_eq!(format!("{:#}", error), "failed failed: broken");
+    }
+
+    #[tokio::test]
+    async fn failed_late_task_shuts_down_others() {
+        let mut runtime = Runtime::new();
+        runtime.spawn("web server", std::future::pending());
+        runtime.spawn_late("file daemon", |_| async { Err(anyhow!("broken")) });
+
+        let error = runtime.run(std::future::pending()).await.unwrap_err();
+        assert_eq!(format!("{:#}", error), "file daemon failed: broken");
+        assert!(Runtime::new().run(std::future::pending()).await.is_ok());
+    }
+}
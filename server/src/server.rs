@@ -1,289 +1,6340 @@
 pub mod v1 {
-    use crate::configure::RwPoolType;
-    use crate::file::FileEventHelper;
+    use crate::configure::current::{
+        AuthEntry, Compression, Features, PathLimits, RecallMode, TieringOption,
+    };
+    use crate::configure::{mask_token, ExcludeSet, PathMatcher, Permissions};
+    use crate::crypto::{
+        encrypt_to, is_encrypted, plaintext_len, spawn_decrypt, EncryptionKey, UrlSigner,
+    };
+    use crate::database::BusyPolicy;
+    use crate::file::{
+        spawn_archive, spawn_tail, unix_now, AppendError, ArchiveFormat, FileEventHelper, Removal,
+        RemoveError, Remover, ReportKind, SearchQuery, TransferGuard, Transfers, UploadSession,
+    };
+    use crate::mount::Mounts;
+    use crate::server::access::record_download;
     use crate::server::auth::AuthLayer;
-    use crate::server::{WebResponse, DEFAULT_WAIT_TIME};
+    use crate::server::client_ip::resolve_client_ip;
+    use crate::server::cluster::{route_to_owner, HashRing};
+    use crate::server::compression::{compression_layer, find_sidecar};
+    use crate::server::concurrency::limit_concurrency;
+    use crate::server::conditional::{
+        entity_tag, insert_validators, is_not_modified, requested_range, ByteRange,
+    };
+    use crate::server::digest::{
+        fresh_blake3, fresh_refused, insert_digests, is_current, wants_digest,
+    };
+    use crate::server::fields::{FieldsQuery, PartialFile, PartialFiles};
+    use crate::server::request_id::{request_span, RandomRequestId};
+    use crate::server::shedding::{shed_load, LoadShedder};
+    use crate::server::tenant::{strip_root, translate_root};
+    use crate::server::tree::build_tree;
+    use crate::server::unix_socket::{serve_unix, ServerHandle, UnixHandle};
+    use crate::server::ws::websocket;
+    use crate::server::{Services, WebResponse, DEFAULT_WAIT_TIME};
+    use crate::systemd::Listener;
     use anyhow::anyhow;
-    use axum::body::StreamBody;
-    use axum::extract::Path;
-    use axum::response::IntoResponse;
+    use axum::body::{Bytes, StreamBody};
+    use axum::extract::{Path, Query};
+    use axum::response::{IntoResponse, Response};
     use axum::{Extension, Router};
+    use futures::future::BoxFuture;
+    use futures::{FutureExt, TryStreamExt};
     use http::header::InvalidHeaderValue;
-    use http::{HeaderMap, HeaderValue, Request};
+    use http::{HeaderMap, HeaderValue, Request, StatusCode};
     use hyper::Body;
-    use publib::{check_penetration, PATH_UTF8_ERROR};
-    use serde_json::json;
+    use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+    use publib::file::{get_file_hash, get_hash, HashAlgo, HashOption};
+    use publib::types::{
+        ChangeEvent, Cursor, DirStats, DownloadFeatures, FileEntry, FileInfo, HoldInfo, LinkInfo,
+        ListOption, OptionFile, Page, Pagination, Scope, TokenInfo, TransferKind, UploadStatus,
+        VerifyResult,
+    };
+    use publib::{normalize_path, PATH_UTF8_ERROR};
+    use rand::distributions::Alphanumeric;
+    use rand::Rng;
+    use serde::de::Error as _;
+    use serde::{Deserialize as _, Deserializer};
+    use serde_derive::Deserialize;
+    use serde_json::{json, Value};
+    use std::net::SocketAddr;
+    use std::os::unix::fs::MetadataExt;
     use std::sync::Arc;
-    use std::time::Duration;
-    use tokio::task::JoinHandle;
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+    use tokio::sync::oneshot;
     use tokio::time::timeout;
     use tokio_util::io::ReaderStream;
     use tower::ServiceBuilder;
     use tower_http::auth::AsyncRequireAuthorizationLayer;
+    use tower_http::cors::CorsLayer;
+    use tower_http::request_id::{PropagateRequestIdLayer, SetRequestIdLayer};
     use tower_http::trace::TraceLayer;
+    use tracing::{error, info};
 
     pub fn router_start(
         bind: String,
-        user_pool: Arc<RwPoolType>,
-        helper: FileEventHelper,
-    ) -> (JoinHandle<std::io::Result<()>>, axum_server::Handle) {
-        let router = Router::new()
+        listener: Option<Listener>,
+        services: Services,
+        cors: Option<CorsLayer>,
+    ) -> (BoxFuture<'static, std::io::Result<()>>, ServerHandle) {
+        let features = services.features;
+        let compression = services.compression;
+        let capabilities = build_capabilities(
+            &services.hash_option,
+            &services.key,
+            &features,
+            &services.signer,
+            &services.public,
+            &compression,
+        );
+        let build = build_info(&features);
+        let started = Instant::now();
+        let file_route = axum::routing::get(get_file)
+            .layer(axum::middleware::from_fn(record_download))
+            .head(head_file);
+        let file_route = if features.uploads() {
+            file_route.patch(append_file).delete(delete_file)
+        } else {
+            file_route
+        };
+        let write_router = if features.uploads() {
+            Router::new()
+                .route("/dir/*path", axum::routing::delete(delete_dir))
+                .route("/mkdir/*path", axum::routing::post(make_dir))
+                .route("/upload", axum::routing::post(create_upload))
+                .route(
+                    "/upload/:id",
+                    axum::routing::get(get_upload_session).patch(upload_chunk),
+                )
+                .route("/move", axum::routing::post(move_path))
+        } else {
+            Router::new()
+        };
+        let admin_router = if features.admin() {
+            Router::new()
+                .route("/admin/duplicates", axum::routing::get(admin_duplicates))
+                .route("/admin/rescan", axum::routing::post(admin_rescan))
+                .route("/admin/jobs/:id", axum::routing::get(admin_job))
+                .route("/admin/connections", axum::routing::get(admin_connections))
+                .route("/admin/load", axum::routing::get(admin_load))
+                .route("/admin/events", axum::routing::get(admin_events))
+                .route("/admin/integrity", axum::routing::get(admin_integrity))
+                .route("/admin/errors", axum::routing::get(admin_errors))
+                .route("/admin/stats", axum::routing::get(admin_stats))
+                .route(
+                    "/admin/errors/retry",
+                    axum::routing::post(admin_retry_errors),
+                )
+                .route(
+                    "/admin/tokens",
+                    axum::routing::get(admin_tokens).post(admin_create_token),
+                )
+                .route(
+                    "/admin/tokens/:id",
+                    axum::routing::delete(admin_revoke_token),
+                )
+                .route(
+                    "/admin/connections/:id",
+                    axum::routing::delete(admin_cancel_connection),
+                )
+                .route(
+                    "/admin/holds",
+                    axum::routing::get(admin_holds).post(admin_create_hold),
+                )
+                .route(
+                    "/admin/holds/*path",
+                    axum::routing::delete(admin_release_hold),
+                )
+        } else {
+            Router::new()
+        };
+        // Metadata requests wait file daemon, shed them early if server is overloaded
+        let metadata_router = Router::new()
+            .route("/file-info/*path", axum::routing::get(get_file_info))
+            .route("/stat/*path", axum::routing::get(stat))
+            .route("/chunks/*path", axum::routing::get(get_chunks))
+            .route("/query", axum::routing::get(query).post(query_paths))
+            .route("/query/hash/:hash", axum::routing::get(query_hash))
+            .route("/manifest", axum::routing::get(manifest))
+            .route("/search", axum::routing::get(search))
+            .route("/list", axum::routing::get(list))
+            .route("/tree", axum::routing::get(tree))
+            .route("/duplicates", axum::routing::get(duplicates))
+            .route("/report/largest", axum::routing::get(report_largest))
+            .route("/report/stale", axum::routing::get(report_stale))
+            .route("/stats", axum::routing::get(stats))
+            .route_layer(axum::middleware::from_fn(shed_load));
+        let routes = Router::new()
             .route(
                 "/",
-                axum::routing::get(|| async {
-                    WebResponse::ok(Some(
-                        json!({"version": env!("CARGO_PKG_VERSION"), "status": 200}),
-                    ))
+                axum::routing::get(|Extension(helper): Extension<FileEventHelper>| async move {
+                    WebResponse::ok(Some(json!({
+                        "version": env!("CARGO_PKG_VERSION"),
+                        "status": 200,
+                        "commit": helper.scan_progress().commit(),
+                    })))
                 }),
             )
-            .route("/file/*path", axum::routing::get(get_file))
-            .route("/query", axum::routing::get(query))
-            .fallback(|| async { WebResponse::forbidden(None) })
-            .route_layer(AsyncRequireAuthorizationLayer::new(AuthLayer))
-            .layer(Extension(user_pool))
-            .layer(Extension(helper))
-            .layer(ServiceBuilder::new().layer(TraceLayer::new_for_http()));
-        let server_handler = axum_server::Handle::new();
-        let server = tokio::spawn(
-            axum_server::bind(bind.parse().unwrap())
-                .handle(server_handler.clone())
-                .serve(router.into_make_service()),
+            .route(
+                "/version",
+                axum::routing::get(move |Extension(helper): Extension<FileEventHelper>| {
+                    let mut info = build.clone();
+                    async move {
+                        info["uptime"] = json!(started.elapsed().as_secs());
+                        info["index_generation"] = json!(helper.scan_progress().generation());
+                        info["index_commit"] = json!(helper.scan_progress().commit());
+                        WebResponse::ok(Some(info))
+                    }
+                }),
+            )
+            .route(
+                "/capabilities",
+                axum::routing::get(move || async move { WebResponse::ok(Some(capabilities)) }),
+            )
+            .route("/file/*path", file_route)
+            .route("/archive/*path", axum::routing::get(get_archive))
+            .route("/tail/*path", axum::routing::get(get_tail))
+            .route("/ws", axum::routing::get(websocket))
+            .route("/sign", axum::routing::post(sign_url))
+            .route("/verify", axum::routing::post(verify_paths))
+            .route("/links", axum::routing::get(list_links))
+            .route("/links/:id", axum::routing::delete(revoke_link))
+            .route("/uploads/:id", axum::routing::get(get_upload))
+            .route("/mirrors", axum::routing::get(mirrors))
+            .route("/status", axum::routing::get(status))
+            .route("/events/ingest", axum::routing::post(ingest_events))
+            .merge(metadata_router)
+            .merge(write_router)
+            .merge(admin_router)
+            .fallback(|| async { WebResponse::forbidden(None) });
+        // Middleware runs before routing, so path extractors see path translated to token root
+        let router = Router::new()
+            .fallback_service(routes)
+            .layer(axum::middleware::from_fn(translate_root))
+            .layer(axum::middleware::from_fn(limit_concurrency))
+            .layer(axum::middleware::from_fn(route_to_owner))
+            .layer(AsyncRequireAuthorizationLayer::new(AuthLayer))
+            .layer(axum::middleware::from_fn(resolve_client_ip))
+            .layer(Extension(services.user_pool.clone()))
+            .layer(Extension(services.helper.clone()))
+            .layer(Extension(services.exclude.clone()))
+            .layer(Extension(services.hasher.clone()))
+            .layer(Extension(services.hash_option))
+            .layer(Extension(services.key.clone()))
+            .layer(Extension(services.throttle.clone()))
+            .layer(Extension(services.transfers.clone()))
+            .layer(Extension(services.remover.clone()))
+            .layer(Extension(services.tiering.clone()))
+            .layer(Extension(services.limiter.clone()))
+            .layer(Extension(services.signer.clone()))
+            .layer(Extension(services.shedder.clone()))
+            .layer(Extension(services.public.clone()))
+            .layer(Extension(services.ring.clone()))
+            .layer(Extension(services.path_limits))
+            .layer(Extension(compression))
+            .layer(Extension(services.digest))
+            .layer(Extension(services.firewall.clone()))
+            .layer(Extension(Arc::new(services)));
+        let router = match compression_layer(compression) {
+            Some(compression) => router.layer(compression),
+            None => router,
+        };
+        // Preflight request carries no token, it is answered before authorization
+        let router = match cors {
+            Some(cors) => router.layer(cors),
+            None => router,
+        };
+        let router = router.layer(
+            ServiceBuilder::new()
+                .layer(SetRequestIdLayer::x_request_id(RandomRequestId))
+                .layer(TraceLayer::new_for_http().make_span_with(request_span))
+                .layer(PropagateRequestIdLayer::x_request_id()),
         );
-        (server, server_handler)
+        let server = match listener {
+            Some(Listener::Unix(listener)) => {
+                let handle = UnixHandle::default();
+                let server = serve_unix(listener, router, handle.clone());
+                return (server.boxed(), ServerHandle::Unix(handle));
+            }
+            Some(Listener::Tcp(listener)) => axum_server::from_tcp(listener),
+            None => axum_server::bind(bind.parse().unwrap()),
+        };
+        let server_handler = axum_server::Handle::new();
+        let server = server
+            .handle(server_handler.clone())
+            .serve(router.into_make_service_with_connect_info::<SocketAddr>());
+        (server.boxed(), ServerHandle::Tcp(server_handler))
+    }
+
+    /// Optional features enabled by this server build and configure
+    pub(super) fn build_capabilities(
+        hash_option: &HashOption,
+        key: &Option<EncryptionKey>,
+        features: &Features,
+        signer: &Option<UrlSigner>,
+        public: &Option<PathMatcher>,
+        compression: &Compression,
+    ) -> Value {
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "uploads": features.uploads(),
+            "append": features.uploads(),
+            "delete": features.uploads(),
+            "move": features.uploads(),
+            "mkdir": features.uploads(),
+            "resumable_upload": features.uploads(),
+            "stat": true,
+            "chunks": true,
+            "admin": features.admin(),
+            "metrics": features.metrics(),
+            "web_ui": features.web_ui(),
+            "webhooks": features.webhooks(),
+            "tail": true,
+            "verify": true,
+            "query_hash": true,
+            "search": true,
+            "websocket": true,
+            "archive": ArchiveFormat::ALL
+                .iter()
+                .map(|format| format.extension())
+                .collect::<Vec<_>>(),
+            "ranges": true,
+            "compression": compression.encodings(),
+            "chunking": hash_option.chunk_size().is_some(),
+            "webdav": false,
+            "encryption_at_rest": key.is_some(),
+            "signed_urls": signer.is_some(),
+            "public": public.is_some(),
+            "hash_algo": hash_option.algo(),
+        })
+    }
+
+    /// Build of this server and features enabled in configure, compared by fleet tooling
+    fn build_info(features: &Features) -> Value {
+        let build_features = [
+            ("kafka", cfg!(feature = "kafka")),
+            ("mqtt", cfg!(feature = "mqtt")),
+            ("nats", cfg!(feature = "nats")),
+            ("postgres", cfg!(feature = "postgres")),
+        ];
+        let features = [
+            ("uploads", features.uploads()),
+            ("admin", features.admin()),
+            ("metrics", features.metrics()),
+            ("web_ui", features.web_ui()),
+            ("webhooks", features.webhooks()),
+        ];
+        let enabled = |list: &[(&'static str, bool)]| {
+            list.iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(name, _)| *name)
+                .collect::<Vec<_>>()
+        };
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "git_commit": option_env!("WAFFLE_GIT_COMMIT"),
+            "build_time": option_env!("WAFFLE_BUILD_TIME").and_then(|time| time.parse::<i64>().ok()),
+            "rustc": option_env!("WAFFLE_RUSTC_VERSION"),
+            "build_features": enabled(&build_features),
+            "features": enabled(&features),
+        })
+    }
+
+    /// Consistent hash ring of cluster, download of path is redirected to its owner
+    async fn mirrors(Extension(ring): Extension<Option<HashRing>>) -> WebResponse {
+        match ring {
+            Some(ring) => WebResponse::ok(Some(ring.to_value())),
+            None => WebResponse::new(
+                StatusCode::NOT_FOUND,
+                None,
+                Some("Cluster mode is not configured".into()),
+            ),
+        }
+    }
+
+    /// Progress of background scan, query result may be incomplete while scan is running
+    async fn status(Extension(sender): Extension<FileEventHelper>) -> WebResponse {
+        WebResponse::ok(Some(json!({
+            "scan": sender.scan_progress().status(),
+            "queue_depth": sender.queue_depth(),
+        })))
+    }
+
+    /// Wait file daemon response, `None` means daemon is not accept request
+    async fn wait_response<T>(receiver: Option<oneshot::Receiver<T>>) -> Result<T, WebResponse> {
+        let receiver = receiver.ok_or_else(|| WebResponse::forbidden(None))?;
+        match timeout(Duration::from_secs(DEFAULT_WAIT_TIME), receiver).await {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(e)) => Err(WebResponse::from(anyhow!("Query result error: {:?}", e))),
+            Err(_) => Err(WebResponse::gateway_timeout()),
+        }
+    }
+
+    fn files_response(files: Vec<OptionFile>, fields: FieldsQuery) -> WebResponse {
+        match fields.selector() {
+            Ok(None) => WebResponse::ok_serialize(&files),
+            Ok(Some(selector)) => WebResponse::ok_serialize(&PartialFiles::new(&files, &selector)),
+            Err(e) => WebResponse::bad_request_string(e),
+        }
     }
 
     async fn query(
         Extension(sender): Extension<FileEventHelper>,
+        Query(fields): Query<FieldsQuery>,
+        Extension(permissions): Extension<Permissions>,
         request: Request<Body>,
     ) -> WebResponse {
-        let paths = request.extensions().get::<Vec<String>>();
+        if let Err(response) = require_scope(permissions, Scope::List) {
+            return response;
+        }
+        let matcher = match authorized_matcher(&request) {
+            Ok(matcher) => matcher,
+            Err(response) => return response,
+        };
 
-        if paths.is_none() {
-            return WebResponse::internal_server_error_str(Some("Paths is None"));
+        if let Err(e) = fields.selector() {
+            return WebResponse::bad_request_string(e);
         }
 
-        if let Some(receiver) = sender.send_request(paths.unwrap().to_owned()).await {
-            return if let Ok(result) =
-                timeout(Duration::from_secs(DEFAULT_WAIT_TIME), receiver).await
-            {
-                match result {
-                    Ok(result) => WebResponse::ok(Some(serde_json::to_value(result).unwrap())),
-                    Err(e) => WebResponse::from(anyhow!("Query result error: {:?}", e)),
-                }
-            } else {
-                WebResponse::gateway_timeout()
-            };
+        match wait_response(sender.send_request(matcher.bases().clone()).await).await {
+            Ok(result) => files_response(retain_authorized(result, matcher), fields),
+            Err(response) => response,
         }
-        WebResponse::forbidden(None)
     }
 
-    fn build_filename_value(filename: &str) -> Result<HeaderValue, InvalidHeaderValue> {
-        HeaderValue::from_str(&format!("attachment; filename=\"{}\"", filename))
+    /// Authorized files with content `hash`, digest without `algo:` prefix is taken as
+    /// configured algorithm
+    async fn query_hash(
+        Path(hash): Path<String>,
+        Extension(sender): Extension<FileEventHelper>,
+        Extension(option): Extension<HashOption>,
+        Query(fields): Query<FieldsQuery>,
+        Extension(permissions): Extension<Permissions>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_scope(permissions, Scope::List) {
+            return response;
+        }
+        let matcher = match authorized_matcher(&request) {
+            Ok(matcher) => matcher,
+            Err(response) => return response,
+        };
+        if let Err(e) = fields.selector() {
+            return WebResponse::bad_request_string(e);
+        }
+
+        let hash = hash.to_ascii_lowercase();
+        let hash = if hash.contains(':') {
+            hash
+        } else {
+            format!("{}:{}", option.algo().name(), hash)
+        };
+        match wait_response(sender.send_hash(hash, matcher.bases().clone()).await).await {
+            Ok(result) => files_response(retain_authorized(result, matcher), fields),
+            Err(response) => response,
+        }
     }
 
-    async fn get_file(
-        Path(path): Path<String>,
+    /// Authorized files with signature, mirror checks its copy against it
+    ///
+    /// Index hash of file encrypted at rest is hash of ciphertext, so manifest is refused
+    /// when encryption is configured
+    pub(super) async fn manifest(
+        Extension(sender): Extension<FileEventHelper>,
+        Extension(signer): Extension<Option<UrlSigner>>,
+        Extension(key): Extension<Option<EncryptionKey>>,
+        Extension(permissions): Extension<Permissions>,
         request: Request<Body>,
-    ) -> Result<impl IntoResponse, WebResponse> {
-        let paths = request.extensions().get::<Vec<String>>();
-        let mut headers = HeaderMap::new();
-        headers.insert(
-            http::header::CONTENT_TYPE,
-            "application/octet-stream".parse().unwrap(),
-        );
-
-        if paths.is_none() {
-            return Err(WebResponse::internal_server_error_str(Some(
-                "Paths is None",
-            )));
+    ) -> WebResponse {
+        if let Err(response) = require_scope(permissions, Scope::List) {
+            return response;
+        }
+        let Some(signer) = signer else {
+            return WebResponse::new(
+                StatusCode::NOT_FOUND,
+                None,
+                Some("Manifest signing is not configured".into()),
+            );
+        };
+        if key.is_some() {
+            return WebResponse::new(
+                StatusCode::CONFLICT,
+                None,
+                Some("Manifest is not available with encryption at rest".into()),
+            );
         }
+        let matcher = match authorized_matcher(&request) {
+            Ok(matcher) => matcher,
+            Err(response) => return response,
+        };
 
-        // Check path penetration
-        if !check_penetration(&path) {
-            return Err(WebResponse::forbidden(None));
+        match wait_response(sender.send_request(matcher.bases().clone()).await).await {
+            Ok(result) => {
+                let mut files = retain_authorized(result, matcher);
+                files.retain(|file| file.meta().as_ref().is_some_and(|meta| !meta.is_dir()));
+                WebResponse::ok_serialize(&signer.sign_manifest(files, unix_now()))
+            }
+            Err(response) => response,
         }
+    }
 
-        // Check request path is valid
-        if !paths.unwrap().iter().any(|p| path.starts_with(p)) {
-            return Err(WebResponse::forbidden(None));
+    #[derive(Clone, Debug, Deserialize)]
+    pub(super) struct SearchParams {
+        /// Glob matched against whole path, substring if it has no wildcard
+        q: String,
+        limit: Option<i64>,
+        /// `cursor` of previous page
+        cursor: Option<Cursor>,
+    }
+
+    /// Paths under authorized prefixes match glob, in byte order
+    pub(super) async fn search(
+        Extension(sender): Extension<FileEventHelper>,
+        Query(params): Query<SearchParams>,
+        Query(fields): Query<FieldsQuery>,
+        Extension(permissions): Extension<Permissions>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_scope(permissions, Scope::List) {
+            return response;
+        }
+        let matcher = match authorized_matcher(&request) {
+            Ok(matcher) => matcher,
+            Err(response) => return response,
+        };
+        let selector = match fields.selector() {
+            Ok(selector) => selector,
+            Err(e) => return WebResponse::bad_request_string(e),
+        };
+        if params.q.is_empty() {
+            return WebResponse::bad_request(Some("Search pattern is empty"));
         }
+        let page = match Pagination::new(params.limit, None).validate() {
+            Ok(page) => page,
+            Err(e) => return WebResponse::bad_request_string(e),
+        };
 
-        let buf: &std::path::Path = path.as_ref();
-        if buf.is_dir() {
-            return Err(WebResponse::bad_request(Some("Request download directory")));
+        let query = SearchQuery {
+            glob: params.q,
+            cursor: params.cursor.map(String::from).unwrap_or_default(),
+            limit: page.limit(),
+        };
+        let limit = query.limit;
+        let result =
+            match wait_response(sender.send_search(matcher.bases().clone(), query).await).await {
+                Ok(result) => result,
+                Err(response) => return response,
+            };
+        // Rows dropped by pattern of token still move cursor forward
+        let next = Cursor::after(&result, limit, |file| file.path());
+        let files = retain_authorized(result, matcher);
+        match selector {
+            None => WebResponse::ok_serialize(&Page::new(files, next)),
+            Some(selector) => WebResponse::ok_serialize(&Page::new(
+                files
+                    .iter()
+                    .map(|file| PartialFile::new(file, &selector))
+                    .collect(),
+                next,
+            )),
         }
+    }
 
-        match buf.file_name() {
-            None => Err(WebResponse::internal_server_error_str(Some(
-                "Unable to get file name",
-            ))),
-            Some(filename) => {
-                headers.insert(
-                    http::header::CONTENT_DISPOSITION,
-                    build_filename_value(filename.to_str().expect(PATH_UTF8_ERROR)).unwrap(),
-                );
-                match tokio::fs::File::open(path).await {
-                    Ok(file) => {
-                        let body = StreamBody::new(ReaderStream::new(file));
+    #[derive(Clone, Debug, Deserialize)]
+    struct QueryBody {
+        paths: Vec<String>,
+    }
 
-                        Ok((headers, body))
-                    }
-                    Err(e) => Err(WebResponse::from(anyhow!("Unable to read file: {:?}", e))),
-                }
+    /// Query specify paths, fallback to query all authorized paths if body is empty
+    async fn query_paths(
+        Extension(sender): Extension<FileEventHelper>,
+        Extension(matcher): Extension<PathMatcher>,
+        Query(fields): Query<FieldsQuery>,
+        Extension(permissions): Extension<Permissions>,
+        body: Bytes,
+    ) -> WebResponse {
+        if let Err(response) = require_scope(permissions, Scope::List) {
+            return response;
+        }
+        if let Err(e) = fields.selector() {
+            return WebResponse::bad_request_string(e);
+        }
+
+        let paths = if body.is_empty() {
+            matcher.bases().clone()
+        } else {
+            let body: QueryBody = match serde_json::from_slice(&body) {
+                Ok(body) => body,
+                Err(e) => return WebResponse::bad_request_string(format!("Invalid body: {}", e)),
+            };
+            if let Some(path) = body.paths.iter().find(|path| !matcher.is_match(path)) {
+                return WebResponse::forbidden(Some(format!("Path {:?} is not authorized", path)));
             }
+            body.paths.into()
+        };
+
+        match wait_response(sender.send_request(paths).await).await {
+            Ok(result) => files_response(retain_authorized(result, &matcher), fields),
+            Err(response) => response,
         }
     }
-}
 
-mod types {
-    use axum::response::{IntoResponse, Response};
-    use axum::Json;
-    use http::StatusCode;
-    use serde_derive::Serialize;
-    use serde_json::Value;
+    /// Files checked by one verify request
+    const MAX_VERIFY_PATHS: usize = 1000;
+    /// Bytes read by one verify request, stored hashes reused are not counted
+    const MAX_VERIFY_BYTES: u64 = 4 << 30;
 
-    #[derive(Clone, Debug, Serialize)]
-    pub struct WebResponse {
-        status: u16,
-        result: Option<Value>,
-        reason: Option<String>,
+    #[derive(Clone, Debug, Deserialize)]
+    struct VerifyBody {
+        paths: Vec<String>,
+        /// Re-hash even if file is unchanged since indexed, finds silent corruption
+        #[serde(default)]
+        full: bool,
     }
 
-    impl WebResponse {
-        pub fn ok(result: Option<Value>) -> Self {
-            Self::new(StatusCode::OK, result, None)
+    /// Compare files on disk with index, finds corruption or changes missed by file watcher,
+    /// index is not updated
+    ///
+    /// Stored hash is reused if size and mtime of file still match index unless `full` is set
+    async fn verify_paths(
+        Extension(sender): Extension<FileEventHelper>,
+        Extension(matcher): Extension<PathMatcher>,
+        Extension(exclude): Extension<Arc<ExcludeSet>>,
+        Extension(option): Extension<HashOption>,
+        Extension(permissions): Extension<Permissions>,
+        body: Bytes,
+    ) -> WebResponse {
+        if let Err(response) = require_scope(permissions, Scope::Read) {
+            return response;
+        }
+        let body: VerifyBody = match serde_json::from_slice(&body) {
+            Ok(body) => body,
+            Err(e) => return WebResponse::bad_request_string(format!("Invalid body: {}", e)),
+        };
+        if body.paths.len() > MAX_VERIFY_PATHS {
+            return WebResponse::bad_request_string(format!(
+                "At most {} paths can be verified at once",
+                MAX_VERIFY_PATHS
+            ));
+        }
+        for path in &body.paths {
+            // Missing file can not be resolved, it is reported as missing
+            let exists = Mounts::global().resolve(&normalize_path(path)).exists();
+            if !matcher.is_match(path)
+                || exclude.is_match(path)
+                || (exists && !check_penetration(path))
+            {
+                return WebResponse::forbidden(Some(format!("Path {:?} is not authorized", path)));
+            }
         }
+        let paths = body
+            .paths
+            .iter()
+            .map(normalize_path)
+            .collect::<Arc<[String]>>();
 
-        pub fn forbidden(reason: Option<String>) -> Self {
-            Self::new(StatusCode::FORBIDDEN, None, reason)
+        let files = match wait_response(sender.send_request(paths.clone()).await).await {
+            Ok(files) => files,
+            Err(response) => return response,
+        };
+        // Decide what to read before hashing anything, so request over limit reads nothing
+        let mut checks = Vec::with_capacity(paths.len());
+        let mut total = 0u64;
+        for (path, file) in paths.iter().zip(files) {
+            let stored = file
+                .into_file_entry()
+                .filter(|entry| !entry.is_dir() && !entry.hash().is_empty());
+            let fs_path = Mounts::global().resolve(path);
+            let metadata = tokio::fs::metadata(&fs_path)
+                .await
+                .ok()
+                .filter(|metadata| metadata.is_file());
+            let unchanged = match (&stored, &metadata) {
+                (Some(stored), Some(metadata)) => {
+                    !body.full
+                        && FileEntry::from_metadata::<_, String>(path, metadata.clone(), None)
+                            == *stored
+                }
+                _ => false,
+            };
+            if !unchanged {
+                total += metadata.as_ref().map_or(0, |metadata| metadata.len());
+            }
+            checks.push((path, stored, fs_path, metadata.is_some(), unchanged));
+        }
+        if total > MAX_VERIFY_BYTES {
+            return WebResponse::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                None,
+                Some(format!(
+                    "At most {} bytes can be read at once, split paths into smaller requests",
+                    MAX_VERIFY_BYTES
+                )),
+            );
         }
 
-        #[allow(unused)]
-        pub fn forbidden_note(reason: &'static str) -> Self {
-            Self::new(StatusCode::FORBIDDEN, None, Some(reason.to_string()))
+        let mut results = Vec::with_capacity(checks.len());
+        for (path, stored, fs_path, exists, unchanged) in checks {
+            let expected = stored.map(|entry| entry.hash().to_string());
+            let actual = if unchanged {
+                expected.clone()
+            } else if exists {
+                // Hash computed by other algorithm can still be compared
+                let algo = expected
+                    .as_deref()
+                    .and_then(HashAlgo::from_digest)
+                    .unwrap_or(option.algo());
+                let option = HashOption::new(algo, option.buffer_size(), option.buffered());
+                match get_hash(&fs_path, &option).await {
+                    Ok(hash) => hash,
+                    Err(e) => {
+                        return WebResponse::from(anyhow!("Unable to hash {:?}: {:?}", path, e))
+                    }
+                }
+            } else {
+                None
+            };
+            results.push(VerifyResult::new(path.clone(), expected, actual));
         }
+        WebResponse::ok(Some(json!(results)))
+    }
 
-        pub fn internal_server_error(reason: Option<String>) -> Self {
-            Self::new(StatusCode::INTERNAL_SERVER_ERROR, None, reason)
+    #[derive(Clone, Debug, Deserialize)]
+    struct IngestBody {
+        events: Vec<ChangeEvent>,
+    }
+
+    /// Apply change events produced by other indexer (e.g. bridge of bucket notification),
+    /// file is read from working directory as if it is reported by file watcher
+    async fn ingest_events(
+        Extension(sender): Extension<FileEventHelper>,
+        Extension(matcher): Extension<PathMatcher>,
+        Extension(permissions): Extension<Permissions>,
+        body: Bytes,
+    ) -> WebResponse {
+        if let Err(response) = require_scope(permissions, Scope::Write) {
+            return response;
+        }
+        let body: IngestBody = match serde_json::from_slice(&body) {
+            Ok(body) => body,
+            Err(e) => return WebResponse::bad_request_string(format!("Invalid body: {}", e)),
+        };
+        let paths = body.events.iter().flat_map(|event| event.paths());
+        for path in paths {
+            if !check_penetration(path) {
+                return WebResponse::bad_request(Some("Path should be inside working directory"));
+            }
+            if !matcher.is_match(path) {
+                return WebResponse::forbidden(Some(format!("Path {:?} is not authorized", path)));
+            }
+            if let Err(response) = check_hold(&sender, path) {
+                return response;
+            }
         }
 
-        pub fn internal_server_error_str(reason: Option<&'static str>) -> Self {
-            Self::internal_server_error(reason.map(|s| s.to_string()))
+        let count = body.events.len();
+        match sender.send_changes(body.events).await {
+            Some(()) => {
+                WebResponse::new(StatusCode::ACCEPTED, Some(json!({ "count": count })), None)
+            }
+            None => WebResponse::internal_server_error_str(Some("File daemon stopped")),
         }
+    }
 
-        pub fn bad_request(reason: Option<&'static str>) -> Self {
-            Self::new(StatusCode::BAD_REQUEST, None, reason.map(|s| s.to_string()))
+    async fn list_files(
+        sender: &FileEventHelper,
+        option: ListOption,
+        page: Option<Pagination>,
+        request: &Request<Body>,
+    ) -> Result<Vec<OptionFile>, WebResponse> {
+        let matcher = authorized_matcher(request)?;
+        // Files not matched by pattern are dropped after query, page of pattern token is cut
+        // after that so it is never short, otherwise page is cut in SQL
+        let (query_page, page) = if matcher.has_pattern() {
+            (None, page)
+        } else {
+            (page, None)
+        };
+
+        wait_response(
+            sender
+                .send_list(matcher.bases().clone(), option, query_page)
+                .await,
+        )
+        .await
+        .map(|files| page_files(retain_authorized(files, matcher), page))
+    }
+
+    /// Cut `page` from all listed files, `None` keeps all
+    pub(super) fn page_files(files: Vec<OptionFile>, page: Option<Pagination>) -> Vec<OptionFile> {
+        match page {
+            Some(page) => files
+                .into_iter()
+                .skip(page.offset() as usize)
+                .take(page.limit() as usize)
+                .collect(),
+            None => files,
         }
+    }
 
-        pub fn gateway_timeout() -> Self {
-            Self::new(StatusCode::GATEWAY_TIMEOUT, None, None)
+    /// Authorized directories can be summarized by stats, glob bases are skipped since
+    /// files under them are not all authorized
+    fn stats_scope(matcher: &PathMatcher) -> Arc<[String]> {
+        matcher
+            .bases()
+            .iter()
+            .filter(|base| matcher.is_match(base))
+            .cloned()
+            .collect()
+    }
+
+    /// Sum of files and size, stats nested in other stats are not counted twice
+    fn total_stats(stats: &[DirStats]) -> (i64, i64) {
+        stats
+            .iter()
+            .filter(|inner| {
+                !stats.iter().any(|outer| {
+                    outer.path() != inner.path()
+                        && (outer.path() == "."
+                            || inner
+                                .path()
+                                .strip_prefix(outer.path())
+                                .is_some_and(|rest| rest.starts_with('/')))
+                })
+            })
+            .fold((0, 0), |(files, size), stats| {
+                (files + stats.files(), size + stats.size())
+            })
+    }
+
+    /// Git commit indexed by latest scan, set if git mode is enabled
+    fn insert_commit(headers: &mut HeaderMap, sender: &FileEventHelper) {
+        if let Some(value) = sender
+            .scan_progress()
+            .commit()
+            .and_then(|commit| HeaderValue::from_str(&commit).ok())
+        {
+            headers.insert("X-Git-Commit", value);
         }
+    }
 
-        pub fn new(status: StatusCode, result: Option<Value>, reason: Option<String>) -> Self {
-            Self {
-                status: status.as_u16().into(),
-                result,
-                reason,
-            }
+    /// Totals of authorized paths are set in `X-Total-Files` and `X-Total-Size` header,
+    /// they are not affected by filter in list option
+    ///
+    /// At most `limit` files are returned, `Pagination::DEFAULT_LIMIT` if absent
+    async fn list(
+        Extension(sender): Extension<FileEventHelper>,
+        Query(fields): Query<FieldsQuery>,
+        Query(option): Query<ListOption>,
+        Query(page): Query<Pagination>,
+        Extension(permissions): Extension<Permissions>,
+        request: Request<Body>,
+    ) -> Response {
+        if let Err(response) = require_scope(permissions, Scope::List) {
+            return response.into_response();
         }
-        #[allow(unused)]
-        pub fn result(&self) -> &Option<Value> {
-            &self.result
+        if let Err(e) = fields.selector() {
+            return WebResponse::bad_request_string(e).into_response();
         }
-        #[allow(unused)]
-        pub fn reason(&self) -> &Option<String> {
-            &self.reason
+
+        let page = match page.validate() {
+            Ok(page) => page,
+            Err(e) => return WebResponse::bad_request_string(e).into_response(),
+        };
+        let result = match list_files(&sender, option, Some(page), &request).await {
+            Ok(result) => result,
+            Err(response) => return response.into_response(),
+        };
+        let mut headers = HeaderMap::new();
+        if let Ok(matcher) = authorized_matcher(&request) {
+            if let Ok(stats) = wait_response(sender.send_stats(stats_scope(matcher)).await).await {
+                let (files, size) = total_stats(&stats);
+                headers.insert("X-Total-Files", HeaderValue::from(files));
+                headers.insert("X-Total-Size", HeaderValue::from(size));
+            }
         }
+        insert_commit(&mut headers, &sender);
+        (headers, files_response(result, fields)).into_response()
     }
 
-    impl IntoResponse for WebResponse {
-        fn into_response(self) -> Response {
-            (
-                StatusCode::from_u16(self.status).unwrap(),
-                Json(serde_json::to_string(&self).unwrap()),
-            )
-                .into_response()
+    #[derive(Clone, Debug, Default, Deserialize)]
+    struct StatsQuery {
+        path: Option<String>,
+    }
+
+    /// Aggregate stats of `path`, or all authorized directories if not set
+    async fn stats(
+        Extension(sender): Extension<FileEventHelper>,
+        Query(query): Query<StatsQuery>,
+        Extension(permissions): Extension<Permissions>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_scope(permissions, Scope::List) {
+            return response;
+        }
+        let matcher = match authorized_matcher(&request) {
+            Ok(matcher) => matcher,
+            Err(response) => return response,
+        };
+        let scope = match query.path {
+            None => stats_scope(matcher),
+            Some(path) => {
+                if !check_penetration(&path) || !matcher.is_match(&path) {
+                    return WebResponse::forbidden(None);
+                }
+                Arc::from([path])
+            }
+        };
+        match wait_response(sender.send_stats(scope).await).await {
+            Ok(stats) => WebResponse::ok_serialize(&stats),
+            Err(response) => response,
         }
     }
 
-    impl From<anyhow::Error> for WebResponse {
-        fn from(value: anyhow::Error) -> Self {
-            Self::internal_server_error(Some(value.to_string()))
+    async fn tree(
+        Extension(sender): Extension<FileEventHelper>,
+        Query(option): Query<ListOption>,
+        Extension(permissions): Extension<Permissions>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_scope(permissions, Scope::List) {
+            return response;
+        }
+        match list_files(&sender, option, None, &request).await {
+            Ok(result) => WebResponse::ok_serialize(&build_tree(result)),
+            Err(response) => response,
         }
     }
-}
 
-mod auth {
-    use axum::body::BoxBody;
-    use axum::Extension;
-    use log::warn;
-    use std::sync::Arc;
+    /// Characters allowed unescaped in RFC 5987 `attr-char`
+    const ATTR_CHAR: &AsciiSet = &NON_ALPHANUMERIC
+        .remove(b'!')
+        .remove(b'#')
+        .remove(b'$')
+        .remove(b'&')
+        .remove(b'+')
+        .remove(b'-')
+        .remove(b'.')
+        .remove(b'^')
+        .remove(b'_')
+        .remove(b'`')
+        .remove(b'|')
+        .remove(b'~');
 
-    use crate::configure::RwPoolType;
-    use futures_util::future::BoxFuture;
-    use http::StatusCode;
-    use hyper::{Request, Response};
-    use tower_http::auth::AsyncAuthorizeRequest;
+    /// Build `Content-Disposition`, non-ASCII filename is sent as RFC 5987 `filename*`
+    /// with ASCII fallback in `filename`
+    fn build_filename_value(
+        filename: &str,
+        attachment: bool,
+    ) -> Result<HeaderValue, InvalidHeaderValue> {
+        let disposition = if attachment { "attachment" } else { "inline" };
+        let fallback = filename
+            .chars()
+            .map(|c| match c {
+                '"' | '\\' => '_',
+                c if c.is_ascii() && !c.is_ascii_control() => c,
+                _ => '_',
+            })
+            .collect::<String>();
+        if fallback == filename {
+            return HeaderValue::from_str(&format!("{}; filename=\"{}\"", disposition, filename));
+        }
+        HeaderValue::from_str(&format!(
+            "{}; filename=\"{}\"; filename*=UTF-8''{}",
+            disposition,
+            fallback,
+            utf8_percent_encode(filename, ATTR_CHAR)
+        ))
+    }
 
-    #[derive(Clone, Copy)]
-    pub struct AuthLayer;
+    /// Query flag accept `1`, `true`, `yes` or empty value (e.g. `?download`)
+    fn deserialize_flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+        match String::deserialize(deserializer)?.as_str() {
+            "" | "1" | "true" | "yes" => Ok(true),
+            "0" | "false" | "no" => Ok(false),
+            value => Err(D::Error::custom(format!("Invalid flag: {:?}", value))),
+        }
+    }
 
-    impl<B> AsyncAuthorizeRequest<B> for AuthLayer
-    where
-        B: Send + Sync + 'static,
-    {
-        type RequestBody = B;
-        // https://github.com/tower-rs/tower-http/discussions/346
-        type ResponseBody = BoxBody;
-        type Future = BoxFuture<'static, Result<Request<B>, Response<Self::ResponseBody>>>;
+    fn register_transfer(
+        transfers: &Transfers,
+        kind: TransferKind,
+        request: &Request<Body>,
+        path: &str,
+    ) -> TransferGuard {
+        let token = request
+            .extensions()
+            .get::<Arc<AuthEntry>>()
+            .map(|entry| entry.token())
+            .unwrap_or_default();
+        transfers.register(kind, token, &normalize_path(path))
+    }
 
-        fn authorize(&mut self, mut request: Request<B>) -> Self::Future {
-            Box::pin(async {
-                let pool = request.extensions().get::<Arc<RwPoolType>>().unwrap();
-                if let Some(user_id) = check_auth(&request, pool).await {
-                    // Set `user_id` as a request extension so it can be accessed by other
-                    // services down the stack.
-                    request.extensions_mut().insert(Extension(user_id));
+    /// Download under path waiting to be removed is refused, checked after transfer is
+    /// registered, so removal started in between waits for it
+    fn register_download(
+        transfers: &Transfers,
+        remover: &Remover,
+        request: &Request<Body>,
+        path: &str,
+    ) -> Result<TransferGuard, WebResponse> {
+        let guard = register_transfer(transfers, TransferKind::Download, request, path);
+        if remover.is_pending(&normalize_path(path)) {
+            return Err(WebResponse::new(StatusCode::NOT_FOUND, None, None));
+        }
+        Ok(guard)
+    }
 
-                    Ok(request)
-                } else {
-                    let unauthorized_response = Response::builder()
-                        .status(StatusCode::UNAUTHORIZED)
-                        .body(BoxBody::default())
-                        .unwrap();
+    fn require_admin(request: &Request<Body>) -> Result<(), WebResponse> {
+        match request.extensions().get::<Permissions>() {
+            Some(permissions) if permissions.allows(Scope::Admin) => Ok(()),
+            _ => Err(WebResponse::forbidden_note("Admin token required")),
+        }
+    }
 
-                    Err(unauthorized_response)
-                }
-            })
+    pub(super) fn require_scope(permissions: Permissions, scope: Scope) -> Result<(), WebResponse> {
+        if permissions.allows(scope) {
+            Ok(())
+        } else {
+            Err(WebResponse::forbidden(Some(format!(
+                "Token has no {:?} scope",
+                scope
+            ))))
         }
     }
 
-    pub(super) async fn check_auth<B>(
-        request: &Request<B>,
-        pool: &Arc<RwPoolType>,
-    ) -> Option<Vec<String>> {
-        let client_map = pool.read().await;
-        if let Some(bearer) = request.headers().get("Authorization") {
-            let bearer = bearer
-                .to_str()
-                .inspect_err(|e| warn!("Unable decode authorization header: {:?}", e))
-                .ok()?;
-            if !bearer.starts_with("bearer ") {
-                return None;
+    pub(super) async fn duplicates(
+        Extension(sender): Extension<FileEventHelper>,
+        Query(page): Query<Pagination>,
+        Extension(permissions): Extension<Permissions>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_scope(permissions, Scope::List) {
+            return response;
+        }
+        let page = match page.validate() {
+            Ok(page) => page,
+            Err(e) => return WebResponse::bad_request_string(e),
+        };
+        let matcher = match authorized_matcher(&request) {
+            Ok(matcher) => matcher,
+            Err(response) => return response,
+        };
+        match wait_response(
+            sender
+                .send_duplicates(Some(matcher.bases().clone()), page)
+                .await,
+        )
+        .await
+        {
+            Ok(mut report) => {
+                if matcher.has_pattern() {
+                    report.retain_paths(|path| matcher.is_match(path));
+                }
+                WebResponse::ok_serialize(&report)
             }
-            let (_, bearer) = bearer.split_once("bearer ").unwrap();
-            let result = client_map.get(bearer)?;
-            return Some(result.clone());
+            Err(response) => response,
         }
-        None
     }
-}
 
-use std::sync::OnceLock;
-pub use v1 as current;
+    async fn admin_rescan(
+        Extension(sender): Extension<FileEventHelper>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_admin(&request) {
+            return response;
+        }
+        match sender.send_rescan("rescan").await {
+            Some(id) => WebResponse::new(StatusCode::ACCEPTED, Some(json!({ "id": id })), None),
+            None => WebResponse::internal_server_error_str(Some("File daemon stopped")),
+        }
+    }
 
-pub const DEFAULT_WAIT_TIME: u64 = 3;
-pub const DEFAULT_WAIT_TIME_STR: &str = "3";
-pub static WAIT_TIME: OnceLock<u64> = OnceLock::new();
-pub use current::router_start;
-pub use types::WebResponse;
+    async fn admin_job(
+        Path(id): Path<u64>,
+        Extension(sender): Extension<FileEventHelper>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_admin(&request) {
+            return response;
+        }
+        match sender.jobs().get(id) {
+            Some(job) => WebResponse::ok(Some(json!(job))),
+            None => WebResponse::new(StatusCode::NOT_FOUND, None, Some("Job not found".into())),
+        }
+    }
+
+    async fn admin_connections(
+        Extension(transfers): Extension<Transfers>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_admin(&request) {
+            return response;
+        }
+        WebResponse::ok(Some(json!(transfers.list())))
+    }
+
+    /// Hash mismatches found by background scrub
+    async fn admin_integrity(
+        Extension(sender): Extension<FileEventHelper>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_admin(&request) {
+            return response;
+        }
+        match wait_response(sender.send_integrity_issues().await).await {
+            Ok(issues) => WebResponse::ok(Some(json!(issues))),
+            Err(response) => response,
+        }
+    }
+
+    /// Paths failed to be indexed by scan or file events
+    async fn admin_errors(
+        Extension(sender): Extension<FileEventHelper>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_admin(&request) {
+            return response;
+        }
+        match wait_response(sender.send_list_errors().await).await {
+            Ok(errors) => WebResponse::ok(Some(json!(errors))),
+            Err(response) => response,
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct AccessStatsQuery {
+        days: Option<i64>,
+        limit: Option<i64>,
+    }
+
+    /// Downloads of recent days, `days` is 7 and `limit` of top files is 10 by default
+    async fn admin_stats(
+        Extension(sender): Extension<FileEventHelper>,
+        Query(query): Query<AccessStatsQuery>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_admin(&request) {
+            return response;
+        }
+        let days = query.days.unwrap_or(7).clamp(1, 36500);
+        // Start of UTC day, so first day is counted as a whole
+        let since = (unix_now() / 86400 - days + 1) * 86400;
+        let limit = match Pagination::new(query.limit.or(Some(10)), None).validate() {
+            Ok(page) => page.limit(),
+            Err(e) => return WebResponse::bad_request_string(e),
+        };
+        match wait_response(sender.send_access_stats(since, limit).await).await {
+            Ok(stats) => WebResponse::ok_serialize(&stats),
+            Err(response) => response,
+        }
+    }
+
+    /// Index paths in body again, or all recorded paths if body is empty, errors still
+    /// recorded are returned
+    async fn admin_retry_errors(
+        Extension(sender): Extension<FileEventHelper>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_admin(&request) {
+            return response;
+        }
+        let Ok(body) = hyper::body::to_bytes(request.into_body()).await else {
+            return WebResponse::bad_request(Some("Unable to read body"));
+        };
+        let paths = if body.is_empty() {
+            Vec::new()
+        } else {
+            match serde_json::from_slice::<QueryBody>(&body) {
+                Ok(body) => body.paths.iter().map(normalize_path).collect(),
+                Err(e) => return WebResponse::bad_request_string(format!("Invalid body: {}", e)),
+            }
+        };
+        match wait_response(sender.send_retry_errors(paths).await).await {
+            Ok(errors) => WebResponse::ok(Some(json!(errors))),
+            Err(response) => response,
+        }
+    }
+
+    const GENERATED_TOKEN_LENGTH: usize = 32;
+
+    #[derive(Clone, Debug, Deserialize)]
+    struct CreateTokenBody {
+        /// Random token is generated if not set
+        token: Option<String>,
+        paths: Vec<String>,
+        #[serde(default)]
+        admin: bool,
+        concurrent_requests: Option<usize>,
+        scopes: Option<Vec<Scope>>,
+    }
+
+    async fn admin_tokens(
+        Extension(sender): Extension<FileEventHelper>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_admin(&request) {
+            return response;
+        }
+        match wait_response(sender.send_list_tokens().await).await {
+            Ok(tokens) => WebResponse::ok(Some(json!(tokens
+                .into_iter()
+                .map(|token| {
+                    let masked = mask_token(token.token());
+                    token.with_token(masked)
+                })
+                .collect::<Vec<_>>()))),
+            Err(response) => response,
+        }
+    }
+
+    /// Full token is only returned on creation
+    async fn admin_create_token(
+        Extension(sender): Extension<FileEventHelper>,
+        Extension(permissions): Extension<Permissions>,
+        body: Bytes,
+    ) -> WebResponse {
+        if !permissions.allows(Scope::Admin) {
+            return WebResponse::forbidden_note("Admin token required");
+        }
+        let body: CreateTokenBody = match serde_json::from_slice(&body) {
+            Ok(body) => body,
+            Err(e) => return WebResponse::bad_request_string(format!("Invalid body: {}", e)),
+        };
+        if body.paths.is_empty() {
+            return WebResponse::bad_request(Some("Token should have at least one path"));
+        }
+        if body.paths.iter().any(|path| !check_penetration(path)) {
+            return WebResponse::bad_request(Some("Path should be inside working directory"));
+        }
+        if let Err(e) = PathMatcher::new(&body.paths) {
+            return WebResponse::bad_request_string(format!("Invalid path pattern: {}", e));
+        }
+        let token = match body.token {
+            Some(token) if token.is_empty() => {
+                return WebResponse::bad_request(Some("Token should not be empty"))
+            }
+            Some(token) => token,
+            None => rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(GENERATED_TOKEN_LENGTH)
+                .map(char::from)
+                .collect(),
+        };
+        let token = TokenInfo::new(
+            0,
+            token,
+            body.paths,
+            body.admin,
+            body.concurrent_requests,
+            unix_now(),
+        )
+        .with_scopes(body.scopes);
+        match wait_response(sender.send_create_token(token).await).await {
+            Ok(Ok(token)) => WebResponse::ok(Some(json!(token))),
+            Ok(Err(reason)) => WebResponse::new(StatusCode::CONFLICT, None, Some(reason)),
+            Err(response) => response,
+        }
+    }
+
+    async fn admin_revoke_token(
+        Path(id): Path<i64>,
+        Extension(sender): Extension<FileEventHelper>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_admin(&request) {
+            return response;
+        }
+        match wait_response(sender.send_revoke_token(id).await).await {
+            Ok(true) => WebResponse::ok(None),
+            Ok(false) => {
+                WebResponse::new(StatusCode::NOT_FOUND, None, Some("Token not found".into()))
+            }
+            Err(response) => response,
+        }
+    }
+
+    #[derive(Clone, Debug, Deserialize)]
+    struct CreateHoldBody {
+        path: String,
+        reason: Option<String>,
+    }
+
+    async fn admin_holds(
+        Extension(sender): Extension<FileEventHelper>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_admin(&request) {
+            return response;
+        }
+        WebResponse::ok(Some(json!(sender.holds().list())))
+    }
+
+    /// Path and everything under it can't be modified through server until released
+    async fn admin_create_hold(
+        Extension(sender): Extension<FileEventHelper>,
+        Extension(permissions): Extension<Permissions>,
+        body: Bytes,
+    ) -> WebResponse {
+        if !permissions.allows(Scope::Admin) {
+            return WebResponse::forbidden_note("Admin token required");
+        }
+        let body: CreateHoldBody = match serde_json::from_slice(&body) {
+            Ok(body) => body,
+            Err(e) => return WebResponse::bad_request_string(format!("Invalid body: {}", e)),
+        };
+        if !check_penetration(&body.path) {
+            return WebResponse::bad_request(Some("Path should be inside working directory"));
+        }
+        let hold = HoldInfo::new(normalize_path(&body.path), body.reason, unix_now());
+        match wait_response(sender.send_create_hold(hold.clone()).await).await {
+            Ok(Ok(())) => WebResponse::ok(Some(json!(hold))),
+            Ok(Err(reason)) => WebResponse::internal_server_error(Some(reason)),
+            Err(response) => response,
+        }
+    }
+
+    async fn admin_release_hold(
+        Path(path): Path<String>,
+        Extension(sender): Extension<FileEventHelper>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_admin(&request) {
+            return response;
+        }
+        match wait_response(sender.send_release_hold(normalize_path(&path)).await).await {
+            Ok(true) => WebResponse::ok(None),
+            Ok(false) => {
+                WebResponse::new(StatusCode::NOT_FOUND, None, Some("Hold not found".into()))
+            }
+            Err(response) => response,
+        }
+    }
+
+    /// Reject modification of path under legal hold with 423
+    fn check_hold(sender: &FileEventHelper, path: &str) -> Result<(), WebResponse> {
+        match sender.holds().find(&normalize_path(path)) {
+            Some(hold) => Err(WebResponse::new(
+                StatusCode::LOCKED,
+                None,
+                Some(format!("Path is under legal hold of {}", hold.path())),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    async fn admin_load(
+        Extension(sender): Extension<FileEventHelper>,
+        Extension(shedder): Extension<LoadShedder>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_admin(&request) {
+            return response;
+        }
+        WebResponse::ok(Some(json!({
+            "queue_depth": sender.queue_depth(),
+            "in_flight": shedder.in_flight(),
+            "shed": shedder.shed(),
+            "database_busy": BusyPolicy::global().stats(),
+        })))
+    }
+
+    const DEFAULT_EVENTS_LIMIT: usize = 100;
+
+    #[derive(Clone, Debug, Default, Deserialize)]
+    struct EventsQuery {
+        /// Only events touch this path or path under it
+        path: Option<String>,
+        /// Unix timestamp
+        since: Option<i64>,
+        limit: Option<usize>,
+    }
+
+    /// Latest records in event log, oldest first
+    async fn admin_events(
+        Extension(sender): Extension<FileEventHelper>,
+        Query(query): Query<EventsQuery>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_admin(&request) {
+            return response;
+        }
+        let Some(reader) = sender.event_log().cloned() else {
+            return WebResponse::new(
+                StatusCode::NOT_FOUND,
+                None,
+                Some("Event log is disabled".into()),
+            );
+        };
+        let records = match tokio::task::spawn_blocking(move || reader.read()).await {
+            Ok(Ok(records)) => records,
+            Ok(Err(e)) => {
+                return WebResponse::internal_server_error(Some(format!(
+                    "Unable read event log: {:?}",
+                    e
+                )))
+            }
+            Err(e) => {
+                return WebResponse::internal_server_error(Some(format!(
+                    "Read event log task failure: {:?}",
+                    e
+                )))
+            }
+        };
+        let prefix = query.path.as_ref().map(normalize_path);
+        let mut records = records
+            .into_iter()
+            .filter(|record| query.since.is_none_or(|since| record.timestamp() >= since))
+            .filter(|record| {
+                prefix.as_ref().is_none_or(|prefix| {
+                    record.event().paths().iter().any(|path| {
+                        path.strip_prefix(prefix.as_str())
+                            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        let limit = query.limit.unwrap_or(DEFAULT_EVENTS_LIMIT);
+        records.drain(..records.len().saturating_sub(limit));
+        WebResponse::ok(Some(json!(records)))
+    }
+
+    async fn admin_cancel_connection(
+        Path(id): Path<u64>,
+        Extension(transfers): Extension<Transfers>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_admin(&request) {
+            return response;
+        }
+        if transfers.cancel(id) {
+            WebResponse::ok(None)
+        } else {
+            WebResponse::new(
+                StatusCode::NOT_FOUND,
+                None,
+                Some("Transfer not found".into()),
+            )
+        }
+    }
+
+    async fn admin_duplicates(
+        Extension(sender): Extension<FileEventHelper>,
+        Query(page): Query<Pagination>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_admin(&request) {
+            return response;
+        }
+        let page = match page.validate() {
+            Ok(page) => page,
+            Err(e) => return WebResponse::bad_request_string(e),
+        };
+        match wait_response(sender.send_duplicates(None, page).await).await {
+            Ok(report) => WebResponse::ok_serialize(&report),
+            Err(response) => response,
+        }
+    }
+
+    const DEFAULT_STALE_DAYS: i64 = 30;
+
+    #[derive(Clone, Debug, Default, Deserialize)]
+    struct ReportQuery {
+        prefix: Option<String>,
+        limit: Option<i64>,
+        days: Option<i64>,
+    }
+
+    impl ReportQuery {
+        /// Bad request if `limit` is out of range
+        fn limit(&self) -> Result<i64, WebResponse> {
+            Pagination::new(self.limit, None)
+                .validate()
+                .map(|page| page.limit())
+                .map_err(WebResponse::bad_request_string)
+        }
+
+        /// Use `prefix` as scope if it is authorized, otherwise use all authorized paths
+        fn scope(&self, request: &Request<Body>) -> Result<Arc<[String]>, WebResponse> {
+            let matcher = authorized_matcher(request)?;
+            match self.prefix {
+                None => Ok(matcher.bases().clone()),
+                Some(ref prefix) => {
+                    if !check_penetration(prefix) || !matcher.is_match(prefix) {
+                        return Err(WebResponse::forbidden(None));
+                    }
+                    Ok(Arc::from([prefix.clone()]))
+                }
+            }
+        }
+    }
+
+    async fn report(
+        sender: &FileEventHelper,
+        query: &ReportQuery,
+        fields: FieldsQuery,
+        kind: ReportKind,
+        request: &Request<Body>,
+    ) -> WebResponse {
+        if let Err(e) = fields.selector() {
+            return WebResponse::bad_request_string(e);
+        }
+        let scope = match query.scope(request) {
+            Ok(scope) => scope,
+            Err(response) => return response,
+        };
+        let matcher = match authorized_matcher(request) {
+            Ok(matcher) => matcher,
+            Err(response) => return response,
+        };
+        match wait_response(sender.send_report(scope, kind).await).await {
+            Ok(result) => files_response(retain_authorized(result, matcher), fields),
+            Err(response) => response,
+        }
+    }
+
+    async fn report_largest(
+        Extension(sender): Extension<FileEventHelper>,
+        Query(query): Query<ReportQuery>,
+        Query(fields): Query<FieldsQuery>,
+        Extension(permissions): Extension<Permissions>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_scope(permissions, Scope::List) {
+            return response;
+        }
+        let limit = match query.limit() {
+            Ok(limit) => limit,
+            Err(response) => return response,
+        };
+        let kind = ReportKind::Largest { limit };
+        report(&sender, &query, fields, kind, &request).await
+    }
+
+    async fn report_stale(
+        Extension(sender): Extension<FileEventHelper>,
+        Query(query): Query<ReportQuery>,
+        Query(fields): Query<FieldsQuery>,
+        Extension(permissions): Extension<Permissions>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_scope(permissions, Scope::List) {
+            return response;
+        }
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let limit = match query.limit() {
+            Ok(limit) => limit,
+            Err(response) => return response,
+        };
+        let days = query.days.unwrap_or(DEFAULT_STALE_DAYS).max(0);
+        let kind = ReportKind::Stale {
+            before: now - days * 86400,
+            limit,
+        };
+        report(&sender, &query, fields, kind, &request).await
+    }
+
+    fn authorized_matcher(request: &Request<Body>) -> Result<&PathMatcher, WebResponse> {
+        request
+            .extensions()
+            .get::<PathMatcher>()
+            .ok_or_else(|| WebResponse::internal_server_error_str(Some("Path matcher is None")))
+    }
+
+    /// Index is queried by prefix, drop files not matched by pattern of token
+    pub(super) fn retain_authorized(
+        mut files: Vec<OptionFile>,
+        matcher: &PathMatcher,
+    ) -> Vec<OptionFile> {
+        if matcher.has_pattern() {
+            files.retain(|file| matcher.is_match(file.path()));
+        }
+        files
+    }
+
+    /// Check `path` is inside working directory, authorized by token and not excluded
+    fn check_access(
+        path: &str,
+        request: &Request<Body>,
+        exclude: &ExcludeSet,
+    ) -> Result<(), WebResponse> {
+        check_path(path, authorized_matcher(request)?, exclude)
+    }
+
+    /// Path under mount can not escape mount either
+    fn check_penetration(path: &str) -> bool {
+        Mounts::global().check_penetration(path)
+    }
+
+    fn check_path(
+        path: &str,
+        matcher: &PathMatcher,
+        exclude: &ExcludeSet,
+    ) -> Result<(), WebResponse> {
+        // Check path penetration
+        if !check_penetration(path) {
+            return Err(WebResponse::forbidden(None));
+        }
+        check_matched(path, matcher, exclude)
+    }
+
+    /// Like `check_access`, but tiered file is not on disk until recalled, so only its
+    /// ancestors are resolved
+    fn check_stored_access(
+        path: &str,
+        request: &Request<Body>,
+        exclude: &ExcludeSet,
+        sender: &FileEventHelper,
+    ) -> Result<(), WebResponse> {
+        check_stored_path(path, authorized_matcher(request)?, exclude, sender)
+    }
+
+    fn check_stored_path(
+        path: &str,
+        matcher: &PathMatcher,
+        exclude: &ExcludeSet,
+        sender: &FileEventHelper,
+    ) -> Result<(), WebResponse> {
+        if sender.tiered().contains(&normalize_path(path)) {
+            check_new_path(path, matcher, exclude)
+        } else {
+            check_path(path, matcher, exclude)
+        }
+    }
+
+    /// Like `check_access`, but path is created by request, so it does not exist yet
+    fn check_new_access(
+        path: &str,
+        request: &Request<Body>,
+        exclude: &ExcludeSet,
+    ) -> Result<(), WebResponse> {
+        check_new_path(path, authorized_matcher(request)?, exclude)
+    }
+
+    fn check_new_path(
+        path: &str,
+        matcher: &PathMatcher,
+        exclude: &ExcludeSet,
+    ) -> Result<(), WebResponse> {
+        if !Mounts::global().check_new_penetration(path) {
+            return Err(WebResponse::forbidden(None));
+        }
+        check_matched(path, matcher, exclude)
+    }
+
+    fn check_matched(
+        path: &str,
+        matcher: &PathMatcher,
+        exclude: &ExcludeSet,
+    ) -> Result<(), WebResponse> {
+        // Check request path is valid
+        if !matcher.is_match(path) {
+            return Err(WebResponse::forbidden(None));
+        }
+
+        if exclude.is_match(path) {
+            return Err(WebResponse::forbidden(None));
+        }
+        Ok(())
+    }
+
+    const DEFAULT_EXPIRES_IN: u64 = 60 * 60;
+
+    /// Characters kept unescaped in path of signed URL
+    const URL_PATH: &AsciiSet = &NON_ALPHANUMERIC
+        .remove(b'/')
+        .remove(b'-')
+        .remove(b'_')
+        .remove(b'.')
+        .remove(b'~');
+
+    #[derive(Clone, Debug, Deserialize)]
+    struct SignBody {
+        path: String,
+        expires_in: Option<u64>,
+    }
+
+    /// Sign download URL of authorized file, `expires_in` (seconds) is capped by configure
+    ///
+    /// Link is stored, so it can be listed and revoked by token minted it
+    async fn sign_url(
+        Extension(signer): Extension<Option<UrlSigner>>,
+        Extension(exclude): Extension<Arc<ExcludeSet>>,
+        Extension(sender): Extension<FileEventHelper>,
+        Extension(matcher): Extension<PathMatcher>,
+        Extension(permissions): Extension<Permissions>,
+        entry: Option<Extension<Arc<AuthEntry>>>,
+        body: Bytes,
+    ) -> WebResponse {
+        if let Err(response) = require_scope(permissions, Scope::Read) {
+            return response;
+        }
+        let Some(signer) = signer else {
+            return WebResponse::new(
+                StatusCode::NOT_FOUND,
+                None,
+                Some("URL signing is not configured".into()),
+            );
+        };
+        let body: SignBody = match serde_json::from_slice(&body) {
+            Ok(body) => body,
+            Err(e) => return WebResponse::bad_request_string(format!("Invalid body: {}", e)),
+        };
+        if let Err(e) = check_path(&body.path, &matcher, &exclude) {
+            return e;
+        }
+
+        let path = normalize_path(&body.path);
+        if !Mounts::global().resolve(&path).is_file() {
+            return WebResponse::bad_request(Some("Request sign non-file"));
+        }
+
+        let expires_in = body
+            .expires_in
+            .unwrap_or(DEFAULT_EXPIRES_IN)
+            .min(signer.max_expires_in());
+        let Some(Extension(entry)) = entry else {
+            return WebResponse::forbidden(None);
+        };
+        let now = unix_now();
+        let expires = now + expires_in as i64;
+        let signature = signer.sign(&path, expires);
+        let link = LinkInfo::new(0, entry.token().to_string(), path, signature, expires, now);
+        let link = match wait_response(sender.send_create_link(link).await).await {
+            Ok(Ok(link)) => link,
+            Ok(Err(reason)) => return WebResponse::internal_server_error(Some(reason)),
+            Err(response) => return response,
+        };
+        WebResponse::ok(Some(json!({
+            "id": link.id(),
+            "url": signed_url(&link, entry.root()),
+            "expires": expires,
+        })))
+    }
+
+    /// Path of URL is relative to `root` of token minted link, so root is not revealed
+    pub(super) fn signed_url(link: &LinkInfo, root: Option<&str>) -> String {
+        let path = root
+            .and_then(|root| strip_root(&normalize_path(root), link.path()))
+            .unwrap_or_else(|| link.path().to_string());
+        format!(
+            "/file/{}?exp={}&sig={}",
+            utf8_percent_encode(path.trim_start_matches("./"), URL_PATH),
+            link.expires(),
+            link.signature()
+        )
+    }
+
+    /// Links minted by request token, expired links are pruned by file daemon
+    async fn list_links(
+        Extension(sender): Extension<FileEventHelper>,
+        entry: Option<Extension<Arc<AuthEntry>>>,
+    ) -> WebResponse {
+        let Some(Extension(entry)) = entry else {
+            return WebResponse::forbidden(None);
+        };
+        let links = sender
+            .links()
+            .list(entry.token())
+            .iter()
+            .map(|link| {
+                json!({
+                    "id": link.id(),
+                    "path": link.path(),
+                    "url": signed_url(link, entry.root()),
+                    "expires": link.expires(),
+                    "created_at": link.created_at(),
+                })
+            })
+            .collect::<Vec<_>>();
+        WebResponse::ok(Some(json!(links)))
+    }
+
+    /// Revoke link minted by request token, signature is rejected from now on
+    async fn revoke_link(
+        Path(id): Path<i64>,
+        Extension(sender): Extension<FileEventHelper>,
+        entry: Option<Extension<Arc<AuthEntry>>>,
+    ) -> WebResponse {
+        let Some(Extension(entry)) = entry else {
+            return WebResponse::forbidden(None);
+        };
+        match wait_response(sender.send_revoke_link(entry.token().to_string(), id).await).await {
+            Ok(true) => WebResponse::ok(None),
+            Ok(false) => {
+                WebResponse::new(StatusCode::NOT_FOUND, None, Some("Link not found".into()))
+            }
+            Err(response) => response,
+        }
+    }
+
+    #[derive(Clone, Debug, Default, Deserialize)]
+    struct DownloadQuery {
+        #[serde(default, deserialize_with = "deserialize_flag")]
+        download: bool,
+    }
+
+    /// Copy tiered file back, file daemon drops cold copy once it is in place
+    async fn recall_file(
+        sender: FileEventHelper,
+        path: String,
+        suffix: String,
+    ) -> std::io::Result<()> {
+        if sender.tiered().recall(&path, &suffix).await? {
+            info!("{} is recalled from cold storage", path);
+            sender.send_recalled(path).await;
+        }
+        Ok(())
+    }
+
+    /// Bring tiered `path` (normalized) back before it is read, `Some` is response asks
+    /// client to retry while file is copied back in background
+    pub(super) async fn recall_tiered(
+        sender: &FileEventHelper,
+        path: &str,
+        exclude: &ExcludeSet,
+        tiering: &TieringOption,
+    ) -> Result<Option<Response>, WebResponse> {
+        if !sender.tiered().contains(path) || Mounts::global().resolve(path).exists() {
+            return Ok(None);
+        }
+        let suffix = exclude.partial_suffix().unwrap_or(".part").to_string();
+        let recall = recall_file(sender.clone(), path.to_string(), suffix);
+        match tiering.recall() {
+            RecallMode::Blocking => {
+                recall
+                    .await
+                    .map_err(|e| WebResponse::from(anyhow!("Unable to recall file: {:?}", e)))?;
+                Ok(None)
+            }
+            RecallMode::Retry => {
+                tokio::spawn(async move {
+                    recall
+                        .await
+                        .inspect_err(|e| error!("Unable to recall file: {:?}", e))
+                        .ok();
+                });
+                let mut response = WebResponse::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    None,
+                    Some("File is being recalled from cold storage".into()),
+                )
+                .into_response();
+                response.headers_mut().insert(
+                    http::header::RETRY_AFTER,
+                    HeaderValue::from(tiering.retry_after()),
+                );
+                Ok(Some(response))
+            }
+        }
+    }
+
+    async fn get_file(
+        Path(path): Path<String>,
+        Query(query): Query<DownloadQuery>,
+        Extension(services): Extension<Arc<Services>>,
+        Extension(permissions): Extension<Permissions>,
+        request: Request<Body>,
+    ) -> Result<Response, WebResponse> {
+        require_scope(permissions, Scope::Read)?;
+        let Services {
+            exclude,
+            helper: sender,
+            throttle,
+            transfers,
+            remover,
+            tiering,
+            compression,
+            digest,
+            ..
+        } = services.as_ref();
+        let mut headers = HeaderMap::new();
+
+        check_stored_access(&path, &request, exclude, sender)?;
+        // Directory is waiting downloads under it finish to be removed
+        if remover.is_pending(&normalize_path(&path)) {
+            return Err(WebResponse::new(StatusCode::NOT_FOUND, None, None));
+        }
+
+        if let Some(response) =
+            recall_tiered(sender, &normalize_path(&path), exclude, tiering).await?
+        {
+            return Ok(response);
+        }
+        let fs_path = Mounts::global().resolve(&path);
+        let buf = fs_path.as_path();
+        if buf.is_dir() {
+            return Err(WebResponse::bad_request(Some("Request download directory")));
+        }
+
+        match buf.file_name() {
+            None => Err(WebResponse::internal_server_error_str(Some(
+                "Unable to get file name",
+            ))),
+            Some(filename) => {
+                headers.insert(
+                    http::header::CONTENT_DISPOSITION,
+                    build_filename_value(filename.to_str().expect(PATH_UTF8_ERROR), query.download)
+                        .unwrap(),
+                );
+
+                // Serve without validators if file is not indexed or daemon is busy
+                let entry = wait_response(
+                    sender
+                        .send_request(Arc::from([normalize_path(&path)]))
+                        .await,
+                )
+                .await
+                .ok()
+                .and_then(|files| files.into_iter().next())
+                .and_then(|file| file.into_file_entry());
+
+                // Client encrypted file is opaque, guess from extension would be wrong
+                let mime = if entry.as_ref().is_some_and(|entry| entry.encrypted()) {
+                    mime_guess::mime::APPLICATION_OCTET_STREAM
+                } else {
+                    mime_guess::from_path(buf).first_or_octet_stream()
+                };
+                headers.insert(
+                    http::header::CONTENT_TYPE,
+                    HeaderValue::from_str(mime.as_ref()).unwrap(),
+                );
+
+                // Decrypt transparently if file is encrypted at rest
+                let key = match services.key.clone() {
+                    Some(key) => match is_encrypted(buf).await {
+                        Ok(true) => Some(key),
+                        Ok(false) => None,
+                        Err(e) => {
+                            return Err(WebResponse::from(anyhow!("Unable to read file: {:?}", e)))
+                        }
+                    },
+                    None => None,
+                };
+
+                // Client encrypted file does not compress, skip looking for sidecar
+                let sidecar = if compression.precompressed()
+                    && key.is_none()
+                    && !entry.as_ref().is_some_and(|entry| entry.encrypted())
+                {
+                    headers.insert(
+                        http::header::VARY,
+                        HeaderValue::from_static("accept-encoding"),
+                    );
+                    find_sidecar(buf, request.headers()).await
+                } else {
+                    None
+                };
+
+                // Stored hash of file encrypted at rest is digest of ciphertext, which is not
+                // served, so no validator is sent for it
+                if let Some(entry) = entry.as_ref().filter(|_| key.is_none()) {
+                    let encoding = sidecar.as_ref().map(|(_, encoding)| *encoding);
+                    insert_validators(&mut headers, entry, encoding);
+                    if is_not_modified(request.headers(), entry, encoding) {
+                        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+                    }
+                }
+
+                // Stored hash is digest of ciphertext if file is encrypted at rest, digest of
+                // file does not describe encoded sidecar, and it is stale if file is changed
+                // after indexed
+                let metadata = tokio::fs::metadata(buf)
+                    .await
+                    .map_err(|e| WebResponse::from(anyhow!("Unable to read file: {:?}", e)))?;
+                let mut digests = entry
+                    .iter()
+                    .filter(|entry| {
+                        key.is_none()
+                            && sidecar.is_none()
+                            && !entry.hash().is_empty()
+                            && is_current(entry, &metadata)
+                    })
+                    .map(|entry| entry.hash().to_string())
+                    .collect::<Vec<_>>();
+                if sidecar.is_none()
+                    && wants_digest(request.headers(), HashAlgo::Blake3)
+                    && !digests
+                        .iter()
+                        .any(|digest| HashAlgo::from_digest(digest) == Some(HashAlgo::Blake3))
+                {
+                    match fresh_refused(digest, metadata.len()) {
+                        Some(note) => {
+                            headers.insert("X-Digest-Note", HeaderValue::from_static(note));
+                        }
+                        None => match fresh_blake3(buf, key.clone()).await {
+                            Ok(digest) => digests.push(digest),
+                            Err(e) => {
+                                return Err(WebResponse::from(anyhow!(
+                                    "Unable to read file: {:?}",
+                                    e
+                                )))
+                            }
+                        },
+                    }
+                }
+                insert_digests(&mut headers, &digests);
+
+                if let Some(key) = key {
+                    let guard = register_download(transfers, remover, &request, &path)?;
+                    let reader = spawn_decrypt(buf.to_path_buf(), key);
+                    let body =
+                        StreamBody::new(guard.track(throttle.wrap(ReaderStream::new(reader))));
+                    return Ok((headers, body).into_response());
+                }
+
+                let buf = match sidecar {
+                    Some((ref sidecar, encoding)) => {
+                        headers.insert(
+                            http::header::CONTENT_ENCODING,
+                            HeaderValue::from_static(encoding),
+                        );
+                        sidecar.as_path()
+                    }
+                    None => buf,
+                };
+                let mut file = tokio::fs::File::open(buf)
+                    .await
+                    .map_err(|e| WebResponse::from(anyhow!("Unable to read file: {:?}", e)))?;
+                // Offset of encoded sidecar does not match offset of file
+                let range = if sidecar.is_none() {
+                    headers.insert(
+                        http::header::ACCEPT_RANGES,
+                        HeaderValue::from_static("bytes"),
+                    );
+                    let size = file
+                        .metadata()
+                        .await
+                        .map_err(|e| WebResponse::from(anyhow!("Unable to read file: {:?}", e)))?
+                        .len();
+                    match requested_range(request.headers(), entry.as_ref(), size) {
+                        ByteRange::Full => None,
+                        ByteRange::Partial(start, end) => Some((start, end, size)),
+                        ByteRange::Unsatisfiable => {
+                            headers.insert(
+                                http::header::CONTENT_RANGE,
+                                HeaderValue::from_str(&format!("bytes */{}", size)).unwrap(),
+                            );
+                            return Ok((StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response());
+                        }
+                    }
+                } else {
+                    None
+                };
+
+                let guard = register_download(transfers, remover, &request, &path)?;
+                let Some((start, end, size)) = range else {
+                    let body = StreamBody::new(guard.track(throttle.wrap(ReaderStream::new(file))));
+                    return Ok((headers, body).into_response());
+                };
+                file.seek(std::io::SeekFrom::Start(start))
+                    .await
+                    .map_err(|e| WebResponse::from(anyhow!("Unable to read file: {:?}", e)))?;
+                headers.insert(
+                    http::header::CONTENT_RANGE,
+                    HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, size)).unwrap(),
+                );
+                headers.insert(
+                    http::header::CONTENT_LENGTH,
+                    HeaderValue::from(end - start + 1),
+                );
+                let body = StreamBody::new(
+                    guard.track(throttle.wrap(ReaderStream::new(file.take(end - start + 1)))),
+                );
+                Ok((StatusCode::PARTIAL_CONTENT, headers, body).into_response())
+            }
+        }
+    }
+
+    /// Metadata of file in headers, body is never read
+    ///
+    /// Unindexed file is described by file system metadata without hash
+    pub(super) async fn head_file(
+        Path(path): Path<String>,
+        Extension(exclude): Extension<Arc<ExcludeSet>>,
+        Extension(sender): Extension<FileEventHelper>,
+        Extension(key): Extension<Option<EncryptionKey>>,
+        Extension(permissions): Extension<Permissions>,
+        request: Request<Body>,
+    ) -> Result<Response, WebResponse> {
+        require_scope(permissions, Scope::Read)?;
+        check_stored_access(&path, &request, &exclude, &sender)?;
+
+        let path = normalize_path(&path);
+        let mut fs_path = Mounts::global().resolve(&path);
+        let metadata = match tokio::fs::metadata(&fs_path).await {
+            Ok(metadata) if metadata.is_dir() => {
+                return Err(WebResponse::bad_request(Some("Request head of directory")))
+            }
+            Ok(metadata) => Some(metadata),
+            // Tiered stub is described by its row, content is read from cold copy
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                match sender.tiered().location(&path) {
+                    Some(location) => {
+                        fs_path = location;
+                        None
+                    }
+                    None => return Err(WebResponse::new(StatusCode::NOT_FOUND, None, None)),
+                }
+            }
+            Err(e) => return Err(WebResponse::from(anyhow!("Unable to read file: {:?}", e))),
+        };
+        let entry = wait_response(sender.send_request(Arc::from([path.clone()])).await)
+            .await
+            .ok()
+            .and_then(|files| files.into_iter().next())
+            .and_then(|file| file.into_file_entry())
+            .or_else(|| {
+                metadata.as_ref().map(|metadata| {
+                    FileEntry::from_metadata::<_, String>(&path, metadata.clone(), None)
+                })
+            })
+            .ok_or_else(WebResponse::gateway_timeout)?;
+
+        // Encrypted at rest file is served as plaintext, only header is read to detect it, its
+        // stored hash is digest of ciphertext so validators are omitted like GET does
+        let encrypted = key.is_some() && is_encrypted(&fs_path).await.unwrap_or(false);
+        let mut headers = HeaderMap::new();
+        if !encrypted {
+            insert_validators(&mut headers, &entry, None);
+            if is_not_modified(request.headers(), &entry, None) {
+                return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+            }
+        }
+        if !entry.hash().is_empty() {
+            if let Ok(value) = HeaderValue::from_str(entry.hash()) {
+                headers.insert("X-File-Hash", value);
+            }
+        }
+
+        let mut size = entry.size().max(0) as u64;
+        if encrypted {
+            size = plaintext_len(size).ok_or_else(|| {
+                WebResponse::internal_server_error_str(Some("Invalid encrypted file length"))
+            })?;
+        } else if !entry.hash().is_empty()
+            && metadata
+                .as_ref()
+                .is_none_or(|metadata| is_current(&entry, metadata))
+        {
+            // Fresh digest is computed by GET only, body is never read here
+            insert_digests(&mut headers, &[entry.hash().to_string()]);
+        }
+        headers.insert(http::header::CONTENT_LENGTH, HeaderValue::from(size));
+        let mime = if entry.encrypted() {
+            mime_guess::mime::APPLICATION_OCTET_STREAM
+        } else {
+            mime_guess::from_path(&path).first_or_octet_stream()
+        };
+        headers.insert(
+            http::header::CONTENT_TYPE,
+            HeaderValue::from_str(mime.as_ref()).unwrap(),
+        );
+        Ok((StatusCode::OK, headers).into_response())
+    }
+
+    /// Parse append offset from `Content-Range`, `bytes */*` (or `bytes */-`) means append to end
+    ///
+    /// `bytes 100-*/*` means append only if current file size is 100
+    fn parse_append_offset(value: &str) -> Option<Option<u64>> {
+        let (range, _) = value.strip_prefix("bytes ")?.trim().split_once('/')?;
+        if range == "*" {
+            return Some(None);
+        }
+        let (start, _) = range.split_once('-')?;
+        start.parse().ok().map(Some)
+    }
+
+    async fn append_file(
+        Path(path): Path<String>,
+        Extension(services): Extension<Arc<Services>>,
+        Extension(permissions): Extension<Permissions>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_scope(permissions, Scope::Write) {
+            return response;
+        }
+        let Services {
+            exclude,
+            helper: sender,
+            hasher,
+            key,
+            transfers,
+            path_limits,
+            ..
+        } = services.as_ref();
+        if let Err(e) = check_access(&path, &request, exclude) {
+            return e;
+        }
+
+        let path = normalize_path(&path);
+        if let Err(reason) = path_limits.check(&path) {
+            return WebResponse::bad_request_string(reason);
+        }
+        if Mounts::global().resolve(&path).is_dir() {
+            return WebResponse::bad_request(Some("Request append directory"));
+        }
+        if let Err(response) = check_hold(sender, &path) {
+            return response;
+        }
+        let guard = register_transfer(transfers, TransferKind::Upload, &request, &path);
+
+        let offset = match request.headers().get(http::header::CONTENT_RANGE) {
+            None => None,
+            Some(value) => match value.to_str().ok().and_then(parse_append_offset) {
+                Some(offset) => offset,
+                None => return WebResponse::bad_request(Some("Invalid Content-Range")),
+            },
+        };
+
+        let token = request
+            .extensions()
+            .get::<Arc<AuthEntry>>()
+            .map(|entry| entry.token().to_string())
+            .unwrap_or_default();
+        match hasher
+            .append(
+                &path,
+                offset,
+                Box::pin(guard.track(request.into_body().map_err(std::io::Error::other))),
+                key.as_ref(),
+            )
+            .await
+        {
+            Ok(entry) => {
+                let upload = UploadStatus::new(0, token, entry.path().to_string(), unix_now());
+                let mut file = json!(OptionFile::from(entry.clone()));
+                let upload = wait_response(sender.send_appended(entry, upload).await)
+                    .await
+                    .ok()
+                    .flatten();
+                // Poll `/uploads/:id` until file is visible to consumers
+                file["upload_id"] = json!(upload);
+                WebResponse::ok(Some(file))
+            }
+            Err(AppendError::OffsetMismatch(size)) => WebResponse::new(
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                Some(json!({ "size": size })),
+                Some("Offset is not current file size".to_string()),
+            ),
+            Err(AppendError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                WebResponse::new(StatusCode::NOT_FOUND, None, None)
+            }
+            Err(AppendError::Io(e)) => WebResponse::from(anyhow!("Unable to append file: {:?}", e)),
+        }
+    }
+
+    #[derive(Clone, Debug, Deserialize)]
+    struct UploadBody {
+        path: String,
+        size: u64,
+        /// Digest of whole file in hash algorithm of server
+        hash: String,
+    }
+
+    /// Session of token, other token can't see it
+    fn own_session(
+        sender: &FileEventHelper,
+        id: u64,
+        entry: &Option<Extension<Arc<AuthEntry>>>,
+    ) -> Result<UploadSession, WebResponse> {
+        sender
+            .upload_sessions()
+            .get(id)
+            .filter(|session| {
+                entry
+                    .as_ref()
+                    .is_some_and(|Extension(entry)| entry.token() == session.token())
+            })
+            .ok_or_else(|| {
+                WebResponse::new(
+                    StatusCode::NOT_FOUND,
+                    None,
+                    Some("Upload session not found".into()),
+                )
+            })
+    }
+
+    /// Start resumable upload, chunks are appended by `PATCH /upload/:id` to a partial file
+    /// which is renamed into place once it has expected size and hash
+    pub(super) async fn create_upload(
+        Extension(services): Extension<Arc<Services>>,
+        Extension(matcher): Extension<PathMatcher>,
+        Extension(permissions): Extension<Permissions>,
+        entry: Option<Extension<Arc<AuthEntry>>>,
+        body: Bytes,
+    ) -> WebResponse {
+        if let Err(response) = require_scope(permissions, Scope::Write) {
+            return response;
+        }
+        let Services {
+            exclude,
+            helper: sender,
+            path_limits,
+            ..
+        } = services.as_ref();
+        let body: UploadBody = match serde_json::from_slice(&body) {
+            Ok(body) => body,
+            Err(e) => return WebResponse::bad_request_string(format!("Invalid body: {}", e)),
+        };
+        if let Err(response) = check_new_path(&body.path, &matcher, exclude) {
+            return response;
+        }
+        let path = normalize_path(&body.path);
+        if let Err(reason) = path_limits.check(&path) {
+            return WebResponse::bad_request_string(reason);
+        }
+        if let Err(response) = check_hold(sender, &path) {
+            return response;
+        }
+        let fs_path = Mounts::global().resolve(&path);
+        if fs_path.exists() {
+            return WebResponse::new(
+                StatusCode::CONFLICT,
+                None,
+                Some("Path already exists".into()),
+            );
+        }
+
+        let temp = format!("{}{}", path, exclude.partial_suffix().unwrap_or(".part"));
+        let token = entry
+            .as_ref()
+            .map(|Extension(entry)| entry.token().to_string())
+            .unwrap_or_default();
+        let Some(session) = sender
+            .upload_sessions()
+            .create(&token, &path, &temp, body.size, &body.hash)
+        else {
+            return WebResponse::new(
+                StatusCode::CONFLICT,
+                None,
+                Some("Path is being uploaded".into()),
+            );
+        };
+        // Session owns `temp` now, leftover of session lost on restart is truncated
+        let result = async {
+            if let Some(parent) = fs_path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            tokio::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(Mounts::global().resolve(&temp))
+                .await
+        }
+        .await;
+        if let Err(e) = result {
+            sender.upload_sessions().remove(session.id());
+            return WebResponse::from(anyhow!("Unable to create upload file: {:?}", e));
+        }
+        WebResponse::new(StatusCode::CREATED, Some(json!(session)), None)
+    }
+
+    /// Received bytes of session, client resumes from `offset`
+    async fn get_upload_session(
+        Path(id): Path<u64>,
+        Extension(sender): Extension<FileEventHelper>,
+        entry: Option<Extension<Arc<AuthEntry>>>,
+    ) -> WebResponse {
+        match own_session(&sender, id, &entry) {
+            Ok(session) => WebResponse::ok(Some(json!(session))),
+            Err(response) => response,
+        }
+    }
+
+    /// Append chunk to upload session, `Content-Range` start must be bytes received so far
+    pub(super) async fn upload_chunk(
+        Path(id): Path<u64>,
+        Extension(services): Extension<Arc<Services>>,
+        Extension(permissions): Extension<Permissions>,
+        entry: Option<Extension<Arc<AuthEntry>>>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_scope(permissions, Scope::Write) {
+            return response;
+        }
+        let Services {
+            exclude,
+            helper: sender,
+            hasher,
+            key,
+            transfers,
+            ..
+        } = services.as_ref();
+        let session = match own_session(sender, id, &entry) {
+            Ok(session) => session,
+            Err(response) => return response,
+        };
+        let offset = match request.headers().get(http::header::CONTENT_RANGE) {
+            None => None,
+            Some(value) => match value.to_str().ok().and_then(parse_append_offset) {
+                Some(offset) => offset,
+                None => return WebResponse::bad_request(Some("Invalid Content-Range")),
+            },
+        };
+        let length = request
+            .headers()
+            .get(http::header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        if length.is_some_and(|length| offset.unwrap_or(session.offset()) + length > session.size())
+        {
+            return WebResponse::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                None,
+                Some("Chunk exceeds upload size".into()),
+            );
+        }
+
+        let guard = register_transfer(transfers, TransferKind::Upload, &request, session.path());
+        let body = Box::pin(guard.track(request.into_body().map_err(std::io::Error::other)));
+        let appended = match hasher.append(session.temp(), offset, body, None).await {
+            Ok(appended) => appended,
+            Err(AppendError::OffsetMismatch(size)) => {
+                return WebResponse::new(
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    Some(json!({ "size": size })),
+                    Some("Offset is not received size".to_string()),
+                )
+            }
+            Err(AppendError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                sender.upload_sessions().remove(id);
+                return WebResponse::new(StatusCode::NOT_FOUND, None, None);
+            }
+            Err(AppendError::Io(e)) => {
+                return WebResponse::from(anyhow!("Unable to write chunk: {:?}", e))
+            }
+        };
+
+        let received = appended.size() as u64;
+        if received < session.size() {
+            return match sender.upload_sessions().advance(id, received) {
+                Some(session) => WebResponse::ok(Some(json!(session))),
+                None => WebResponse::new(StatusCode::NOT_FOUND, None, None),
+            };
+        }
+        // Session is finished either way, file is dropped unless it is exactly as announced
+        sender.upload_sessions().remove(id);
+        hasher.forget(session.temp()).await;
+        let fs_temp = Mounts::global().resolve(session.temp());
+        if received > session.size() {
+            tokio::fs::remove_file(&fs_temp).await.ok();
+            return WebResponse::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                None,
+                Some("Upload exceeds announced size".into()),
+            );
+        }
+        if appended.hash() != session.hash() {
+            tokio::fs::remove_file(&fs_temp).await.ok();
+            return WebResponse::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Some(json!({ "hash": appended.hash() })),
+                Some("Hash mismatch".into()),
+            );
+        }
+        let fs_path = Mounts::global().resolve(session.path());
+        if fs_path.exists() {
+            tokio::fs::remove_file(&fs_temp).await.ok();
+            return WebResponse::new(
+                StatusCode::CONFLICT,
+                None,
+                Some("Path already exists".into()),
+            );
+        }
+        // Hash is verified against plaintext, index keeps digest of ciphertext like scan does
+        let result = async {
+            let hash = match key {
+                Some(key) => {
+                    let suffix = exclude.partial_suffix().unwrap_or(".part");
+                    let fs_encrypted =
+                        Mounts::global().resolve(&format!("{}{}", session.temp(), suffix));
+                    encrypt_to(&fs_temp, &fs_encrypted, key.clone()).await?;
+                    tokio::fs::remove_file(&fs_temp).await?;
+                    tokio::fs::rename(&fs_encrypted, &fs_path).await?;
+                    get_file_hash(&fs_path, hasher.option()).await?
+                }
+                None => {
+                    tokio::fs::rename(&fs_temp, &fs_path).await?;
+                    appended.hash().to_string()
+                }
+            };
+            Ok::<_, std::io::Error>((tokio::fs::metadata(&fs_path).await?, hash))
+        }
+        .await;
+        let (metadata, hash) = match result {
+            Ok(result) => result,
+            Err(e) => return WebResponse::from(anyhow!("Unable to finish upload: {:?}", e)),
+        };
+
+        let file = FileEntry::from_metadata(session.path(), metadata, Some(hash))
+            .with_encrypted(appended.encrypted());
+        let upload = UploadStatus::new(
+            0,
+            session.token().to_string(),
+            file.path().to_string(),
+            unix_now(),
+        );
+        let mut response = json!(OptionFile::from(file.clone()));
+        let upload = wait_response(sender.send_uploaded(file, upload).await)
+            .await
+            .ok()
+            .flatten();
+        // Poll `/uploads/:id` until file is visible to consumers
+        response["upload_id"] = json!(upload);
+        WebResponse::ok(Some(response))
+    }
+
+    #[derive(Clone, Debug, Deserialize)]
+    struct MoveBody {
+        from: String,
+        to: String,
+    }
+
+    /// Rename file or directory, index is updated by one rename event instead of remove and
+    /// create reported by watcher
+    pub(super) async fn move_path(
+        Extension(exclude): Extension<Arc<ExcludeSet>>,
+        Extension(sender): Extension<FileEventHelper>,
+        Extension(remover): Extension<Remover>,
+        Extension(matcher): Extension<PathMatcher>,
+        Extension(permissions): Extension<Permissions>,
+        body: Bytes,
+    ) -> WebResponse {
+        if let Err(response) = require_scope(permissions, Scope::Write) {
+            return response;
+        }
+        let body: MoveBody = match serde_json::from_slice(&body) {
+            Ok(body) => body,
+            Err(e) => return WebResponse::bad_request_string(format!("Invalid body: {}", e)),
+        };
+        // Destination does not exist unless it is refused below
+        if let Err(response) = check_stored_path(&body.from, &matcher, &exclude, &sender)
+            .and_then(|_| check_new_path(&body.to, &matcher, &exclude))
+            .and_then(|_| check_hold(&sender, &body.from))
+            .and_then(|_| check_hold(&sender, &body.to))
+        {
+            return response;
+        }
+
+        let (from, to) = (normalize_path(&body.from), normalize_path(&body.to));
+        if from == "." || to == "." {
+            return WebResponse::bad_request(Some("Request move working directory"));
+        }
+        if to
+            .strip_prefix(&from)
+            .is_some_and(|rest| rest.starts_with('/'))
+        {
+            return WebResponse::bad_request(Some("Request move directory into itself"));
+        }
+        if remover.is_pending(&from) {
+            return WebResponse::new(StatusCode::NOT_FOUND, None, None);
+        }
+        let (fs_from, fs_to) = (
+            Mounts::global().resolve(&from),
+            Mounts::global().resolve(&to),
+        );
+        // Stub of tiered file is moved by file daemon only, cold copy stays where it is
+        let stub = !fs_from.exists() && sender.tiered().contains(&from);
+        if !fs_from.exists() && !stub {
+            return WebResponse::new(StatusCode::NOT_FOUND, None, None);
+        }
+        if fs_to.exists() || sender.tiered().contains(&to) {
+            return WebResponse::new(
+                StatusCode::CONFLICT,
+                None,
+                Some("Destination already exists".into()),
+            );
+        }
+        if let Some(parent) = fs_to.parent() {
+            if let Err(e) = tokio::fs::create_dir_all(parent).await {
+                return WebResponse::from(anyhow!("Unable to create directory: {:?}", e));
+            }
+            // Rename is atomic only inside one file system, e.g. HDD and NVMe placements
+            let device = |path: &std::path::Path| path.metadata().ok().map(|meta| meta.dev());
+            if !stub && device(&fs_from) != device(parent) {
+                return WebResponse::bad_request(Some("Request move across storage"));
+            }
+        }
+        if !stub {
+            if let Err(e) = tokio::fs::rename(&fs_from, &fs_to).await {
+                return WebResponse::from(anyhow!("Unable to move path: {:?}", e));
+            }
+        }
+
+        let event = ChangeEvent::Rename {
+            from: from.clone(),
+            to: to.clone(),
+        };
+        match sender.send_changes(vec![event]).await {
+            Some(()) => WebResponse::ok(Some(json!({ "from": from, "to": to }))),
+            None => WebResponse::internal_server_error_str(Some("File daemon stopped")),
+        }
+    }
+
+    /// Remove file and return its entry, entry is read from file system if not indexed
+    pub(super) async fn delete_file(
+        Path(path): Path<String>,
+        Extension(exclude): Extension<Arc<ExcludeSet>>,
+        Extension(sender): Extension<FileEventHelper>,
+        Extension(remover): Extension<Remover>,
+        Extension(permissions): Extension<Permissions>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_scope(permissions, Scope::Write) {
+            return response;
+        }
+        if let Err(e) = check_stored_access(&path, &request, &exclude, &sender) {
+            return e;
+        }
+
+        let path = normalize_path(&path);
+        let fs_path = Mounts::global().resolve(&path);
+        let metadata = match tokio::fs::metadata(&fs_path).await {
+            Ok(metadata) if metadata.is_dir() => {
+                return WebResponse::bad_request(Some("Request delete directory"))
+            }
+            Ok(metadata) => Some(metadata),
+            // Stub of tiered file is removed with its cold copy
+            Err(_) if sender.tiered().contains(&path) => None,
+            Err(_) => return WebResponse::new(StatusCode::NOT_FOUND, None, None),
+        };
+        if let Err(response) = check_hold(&sender, &path) {
+            return response;
+        }
+        let entry = match wait_response(sender.send_request(Arc::from([path.clone()])).await).await
+        {
+            Ok(files) => files
+                .into_iter()
+                .next()
+                .and_then(|file| file.into_file_entry()),
+            Err(e) => return e,
+        };
+        let entry = match (entry, metadata) {
+            (Some(entry), _) => entry,
+            (None, Some(metadata)) => FileEntry::from_metadata(&path, metadata, None::<String>),
+            (None, None) => return WebResponse::new(StatusCode::NOT_FOUND, None, None),
+        };
+        if sender.tiered().contains(&path) && sender.send_untier(path.clone()).await.is_none() {
+            return WebResponse::internal_server_error_str(Some("File daemon stopped"));
+        }
+
+        match remover.remove(&path).await {
+            Ok(Removal::Removed) => WebResponse::ok(Some(json!(OptionFile::from(entry)))),
+            Ok(removal) => {
+                let mut file = json!(OptionFile::from(entry));
+                file["deferred"] = json!(removal == Removal::Deferred);
+                WebResponse::new(StatusCode::ACCEPTED, Some(file), None)
+            }
+            Err(RemoveError::Busy(count)) => WebResponse::new(
+                StatusCode::CONFLICT,
+                None,
+                Some(format!("{} download(s) of file in progress", count)),
+            ),
+            Err(RemoveError::Io(e)) => WebResponse::from(anyhow!("Unable to delete file: {:?}", e)),
+        }
+    }
+
+    /// Remove directory, downloads under it are handled by configured policy
+    async fn delete_dir(
+        Path(path): Path<String>,
+        Extension(exclude): Extension<Arc<ExcludeSet>>,
+        Extension(sender): Extension<FileEventHelper>,
+        Extension(remover): Extension<Remover>,
+        Extension(permissions): Extension<Permissions>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_scope(permissions, Scope::Write) {
+            return response;
+        }
+        if let Err(e) = check_access(&path, &request, &exclude) {
+            return e;
+        }
+
+        let path = normalize_path(&path);
+        if path == "." {
+            return WebResponse::bad_request(Some("Request delete working directory"));
+        }
+        if !Mounts::global().resolve(&path).is_dir() {
+            return WebResponse::new(StatusCode::NOT_FOUND, None, None);
+        }
+        if let Err(response) = check_hold(&sender, &path) {
+            return response;
+        }
+        match remover.remove(&path).await {
+            Ok(Removal::Removed) => WebResponse::ok(Some(json!({ "path": path }))),
+            Ok(removal) => WebResponse::new(
+                StatusCode::ACCEPTED,
+                Some(json!({ "path": path, "deferred": removal == Removal::Deferred })),
+                None,
+            ),
+            Err(RemoveError::Busy(count)) => WebResponse::new(
+                StatusCode::CONFLICT,
+                None,
+                Some(format!("{} download(s) under path in progress", count)),
+            ),
+            Err(RemoveError::Io(e)) => {
+                WebResponse::from(anyhow!("Unable to delete directory: {:?}", e))
+            }
+        }
+    }
+
+    /// Create directory with its parents, it is indexed once file daemon applied it
+    pub(super) async fn make_dir(
+        Path(path): Path<String>,
+        Extension(exclude): Extension<Arc<ExcludeSet>>,
+        Extension(sender): Extension<FileEventHelper>,
+        Extension(permissions): Extension<Permissions>,
+        Extension(path_limits): Extension<PathLimits>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_scope(permissions, Scope::Write) {
+            return response;
+        }
+        if let Err(e) = check_new_access(&path, &request, &exclude) {
+            return e;
+        }
+
+        let path = normalize_path(&path);
+        if let Err(reason) = path_limits.check(&path) {
+            return WebResponse::bad_request_string(reason);
+        }
+        if let Err(response) = check_hold(&sender, &path) {
+            return response;
+        }
+        let fs_path = Mounts::global().resolve(&path);
+        if fs_path.exists() {
+            return WebResponse::new(
+                StatusCode::CONFLICT,
+                None,
+                Some("Path already exists".into()),
+            );
+        }
+        if let Err(e) = tokio::fs::create_dir_all(&fs_path).await {
+            return WebResponse::from(anyhow!("Unable to create directory: {:?}", e));
+        }
+
+        let event = ChangeEvent::New { path: path.clone() };
+        match sender.send_changes(vec![event]).await {
+            Some(()) => WebResponse::new(StatusCode::CREATED, Some(json!({ "path": path })), None),
+            None => WebResponse::internal_server_error_str(Some("File daemon stopped")),
+        }
+    }
+
+    /// Indexed entry of file or directory, directory has count of its direct children
+    async fn stat(
+        Path(path): Path<String>,
+        Extension(exclude): Extension<Arc<ExcludeSet>>,
+        Extension(sender): Extension<FileEventHelper>,
+        Extension(permissions): Extension<Permissions>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_scope(permissions, Scope::List) {
+            return response;
+        }
+        if let Err(e) = check_access(&path, &request, &exclude) {
+            return e;
+        }
+
+        let stat = match wait_response(sender.send_stat(normalize_path(&path)).await).await {
+            Ok(stat) => stat,
+            Err(e) => return e,
+        };
+        let Some((entry, children)) = stat else {
+            return WebResponse::new(StatusCode::NOT_FOUND, None, Some("Path not indexed".into()));
+        };
+        let mut value = json!(entry);
+        value["children"] = json!(children);
+        WebResponse::ok(Some(value))
+    }
+
+    /// Chunk hashes of file, client compares them with its copy and fetches changed chunks
+    /// by `Range`
+    async fn get_chunks(
+        Path(path): Path<String>,
+        Extension(exclude): Extension<Arc<ExcludeSet>>,
+        Extension(sender): Extension<FileEventHelper>,
+        Extension(permissions): Extension<Permissions>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_scope(permissions, Scope::Read) {
+            return response;
+        }
+        if let Err(e) = check_access(&path, &request, &exclude) {
+            return e;
+        }
+
+        let chunks = match wait_response(sender.send_chunks(normalize_path(&path)).await).await {
+            Ok(chunks) => chunks,
+            Err(e) => return e,
+        };
+        match chunks {
+            Some(chunks) => WebResponse::ok(Some(json!(chunks))),
+            None => WebResponse::new(
+                StatusCode::NOT_FOUND,
+                None,
+                Some("Chunk hashes not available".into()),
+            ),
+        }
+    }
+
+    /// Post-processing status of upload, only visible to token uploaded it
+    async fn get_upload(
+        Path(id): Path<u64>,
+        Extension(sender): Extension<FileEventHelper>,
+        entry: Option<Extension<Arc<AuthEntry>>>,
+    ) -> WebResponse {
+        let upload = match wait_response(sender.send_upload(id).await).await {
+            Ok(upload) => upload,
+            Err(response) => return response,
+        };
+        let upload = upload.filter(|upload| {
+            entry
+                .as_ref()
+                .is_some_and(|Extension(entry)| entry.token() == upload.token())
+        });
+        match upload {
+            Some(upload) => WebResponse::ok(Some(json!(upload))),
+            None => WebResponse::new(StatusCode::NOT_FOUND, None, Some("Upload not found".into())),
+        }
+    }
+
+    async fn get_file_info(
+        Path(path): Path<String>,
+        Extension(exclude): Extension<Arc<ExcludeSet>>,
+        Extension(sender): Extension<FileEventHelper>,
+        Extension(key): Extension<Option<EncryptionKey>>,
+        Extension(compression): Extension<Compression>,
+        Extension(permissions): Extension<Permissions>,
+        request: Request<Body>,
+    ) -> WebResponse {
+        if let Err(response) = require_scope(permissions, Scope::List) {
+            return response;
+        }
+        if let Err(e) = check_access(&path, &request, &exclude) {
+            return e;
+        }
+
+        let path = normalize_path(&path);
+        if Mounts::global().resolve(&path).is_dir() {
+            return WebResponse::bad_request(Some("Request info of directory"));
+        }
+
+        let entry = match wait_response(sender.send_request(Arc::from([path.clone()])).await).await
+        {
+            Ok(files) => files
+                .into_iter()
+                .next()
+                .and_then(|file| file.into_file_entry()),
+            Err(e) => return e,
+        };
+        let Some(entry) = entry else {
+            return WebResponse::new(StatusCode::NOT_FOUND, None, Some("File not indexed".into()));
+        };
+
+        // Encrypted at rest file is served as plaintext
+        let mut size = entry.size();
+        let at_rest = key.is_some()
+            && is_encrypted(Mounts::global().resolve(&path))
+                .await
+                .unwrap_or(false);
+        if at_rest {
+            match plaintext_len(size as u64) {
+                Some(len) => size = len as i64,
+                None => {
+                    return WebResponse::internal_server_error_str(Some(
+                        "Invalid encrypted file length",
+                    ))
+                }
+            }
+        }
+
+        // Range of plaintext and chunks of ciphertext do not line up, so neither is offered
+        // for file encrypted at rest
+        let chunk_manifest = !at_rest
+            && match wait_response(sender.send_chunks(path.clone()).await).await {
+                Ok(chunks) => chunks.is_some(),
+                Err(e) => return e,
+            };
+        let features = DownloadFeatures::new(!at_rest, compression.encodings(), chunk_manifest);
+        // Stored hash of file encrypted at rest is digest of ciphertext, `/file` sends no
+        // validator for it
+        let etag = (!at_rest && !entry.hash().is_empty()).then(|| entity_tag(&entry));
+        let info = FileInfo::new(
+            entry.path().to_string(),
+            size,
+            entry.hash().to_string(),
+            entry.mtime(),
+            etag,
+            entry.encrypted(),
+            features,
+        );
+        WebResponse::ok(Some(json!(info)))
+    }
+
+    #[derive(Clone, Debug, Default, Deserialize)]
+    struct ArchiveQuery {
+        #[serde(default)]
+        format: ArchiveFormat,
+    }
+
+    /// Fallback name of archive if root directory has no name either (`/`)
+    const DEFAULT_ARCHIVE_NAME: &str = "archive";
+
+    /// Name of archive of index path `path` resolved to `root`, working directory (`.`) is
+    /// named after the directory it resolves to
+    pub(super) fn archive_name(path: &str, root: &std::path::Path) -> String {
+        let path = std::path::PathBuf::from(normalize_path(path));
+        path.file_name()
+            .map(|name| name.to_str().expect(PATH_UTF8_ERROR).to_string())
+            .or_else(|| {
+                root.canonicalize()
+                    .ok()?
+                    .file_name()
+                    .map(|name| name.to_str().expect(PATH_UTF8_ERROR).to_string())
+            })
+            .unwrap_or_else(|| DEFAULT_ARCHIVE_NAME.to_string())
+    }
+
+    const DEFAULT_TAIL_BYTES: u64 = 4096;
+
+    #[derive(Clone, Debug, Default, Deserialize)]
+    struct TailQuery {
+        #[serde(default)]
+        follow: bool,
+        bytes: Option<u64>,
+    }
+
+    async fn get_tail(
+        Path(path): Path<String>,
+        Extension(exclude): Extension<Arc<ExcludeSet>>,
+        Extension(sender): Extension<FileEventHelper>,
+        Query(query): Query<TailQuery>,
+        Extension(permissions): Extension<Permissions>,
+        request: Request<Body>,
+    ) -> Result<Response, WebResponse> {
+        require_scope(permissions, Scope::Read)?;
+        check_access(&path, &request, &exclude)?;
+
+        let path = normalize_path(&path);
+        if Mounts::global().resolve(&path).is_dir() {
+            return Err(WebResponse::bad_request(Some("Request tail directory")));
+        }
+        if is_encrypted(Mounts::global().resolve(&path))
+            .await
+            .unwrap_or(false)
+        {
+            return Err(WebResponse::bad_request(Some(
+                "Request tail encrypted file",
+            )));
+        }
+
+        // Subscribe before read file, so no change will be missed
+        let changes = query.follow.then(|| sender.subscribe());
+        let reader = spawn_tail(path, query.bytes.unwrap_or(DEFAULT_TAIL_BYTES), changes);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            http::header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; charset=utf-8"),
+        );
+        headers.insert(
+            http::header::CACHE_CONTROL,
+            HeaderValue::from_static("no-cache"),
+        );
+        Ok((headers, StreamBody::new(ReaderStream::new(reader))).into_response())
+    }
+
+    async fn get_archive(
+        Path(path): Path<String>,
+        Query(query): Query<ArchiveQuery>,
+        Extension(services): Extension<Arc<Services>>,
+        Extension(permissions): Extension<Permissions>,
+        request: Request<Body>,
+    ) -> Result<Response, WebResponse> {
+        require_scope(permissions, Scope::Read)?;
+        let Services {
+            exclude,
+            helper: sender,
+            transfers,
+            remover,
+            ..
+        } = services.as_ref();
+        check_access(&path, &request, exclude)?;
+
+        let root = Mounts::global().resolve(&path);
+        if !root.is_dir() {
+            return Err(WebResponse::bad_request(Some(
+                "Request archive non-directory",
+            )));
+        }
+
+        let filename = archive_name(&path, &root);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            http::header::CONTENT_TYPE,
+            HeaderValue::from_static(query.format.content_type()),
+        );
+        headers.insert(
+            http::header::CONTENT_DISPOSITION,
+            build_filename_value(&format!("{}.{}", filename, query.format.extension()), true)
+                .unwrap(),
+        );
+        insert_commit(&mut headers, sender);
+
+        let guard = register_download(transfers, remover, &request, &path)?;
+        let reader = spawn_archive(root, query.format, exclude.clone(), services.key.clone());
+        Ok((
+            headers,
+            StreamBody::new(guard.track(ReaderStream::new(reader))),
+        )
+            .into_response())
+    }
+}
+
+mod services {
+    use crate::configure::current::{
+        Compression, Deletion, DigestOption, Features, Firewall, PathLimits, TieringOption,
+    };
+    use crate::configure::{ExcludeSet, PathMatcher, RwPoolType};
+    use crate::crypto::{EncryptionKey, UrlSigner};
+    use crate::file::{AppendHasher, FileEventHelper, Remover, Throttle, Transfers};
+    use crate::server::cluster::HashRing;
+    use crate::server::concurrency::ConcurrencyLimiter;
+    use crate::server::shedding::LoadShedder;
+    use publib::file::HashOption;
+    use std::sync::Arc;
+
+    /// Handles shared by handlers and middleware, built once in main
+    ///
+    /// Options are off and limits are unset until fields are replaced, handlers read what
+    /// they need from `Extension<Arc<Services>>` instead of listing each handle
+    #[derive(Clone)]
+    pub struct Services {
+        pub user_pool: Arc<RwPoolType>,
+        pub helper: FileEventHelper,
+        pub exclude: Arc<ExcludeSet>,
+        pub hash_option: HashOption,
+        pub hasher: AppendHasher,
+        pub key: Option<EncryptionKey>,
+        pub throttle: Throttle,
+        pub features: Features,
+        pub limiter: ConcurrencyLimiter,
+        pub signer: Option<UrlSigner>,
+        pub shedder: LoadShedder,
+        pub public: Option<PathMatcher>,
+        pub ring: Option<HashRing>,
+        pub path_limits: PathLimits,
+        pub compression: Compression,
+        pub digest: DigestOption,
+        pub firewall: Arc<Firewall>,
+        pub tiering: TieringOption,
+        pub transfers: Transfers,
+        pub remover: Remover,
+    }
+
+    impl Services {
+        /// Transfers and remover are bound to `helper`, hasher follows `hash_option`
+        pub fn new(
+            helper: FileEventHelper,
+            exclude: Arc<ExcludeSet>,
+            hash_option: HashOption,
+            deletion: &Deletion,
+        ) -> Self {
+            let transfers = Transfers::default();
+            let remover = Remover::new(deletion, transfers.clone(), helper.clone());
+            Self {
+                user_pool: Default::default(),
+                helper,
+                exclude,
+                hash_option,
+                hasher: AppendHasher::new(hash_option),
+                key: None,
+                throttle: Default::default(),
+                features: Default::default(),
+                limiter: Default::default(),
+                signer: None,
+                shedder: Default::default(),
+                public: None,
+                ring: None,
+                path_limits: Default::default(),
+                compression: Default::default(),
+                digest: Default::default(),
+                firewall: Default::default(),
+                tiering: Default::default(),
+                transfers,
+                remover,
+            }
+        }
+    }
+}
+
+mod types {
+    use axum::response::{IntoResponse, Response};
+    use axum::Json;
+    use http::StatusCode;
+    use serde::Serialize;
+    use serde_json::value::{to_raw_value, RawValue};
+    use serde_json::Value;
+
+    /// `result` is kept serialized, so large result is not converted to `Value` first
+    #[derive(Clone, Debug, serde_derive::Serialize)]
+    pub struct WebResponse {
+        status: u16,
+        result: Option<Box<RawValue>>,
+        reason: Option<String>,
+    }
+
+    impl WebResponse {
+        pub fn ok(result: Option<Value>) -> Self {
+            Self::new(StatusCode::OK, result, None)
+        }
+
+        /// Serialize `result` directly, used by responses contain file list
+        pub fn ok_serialize<T: Serialize + ?Sized>(result: &T) -> Self {
+            match to_raw_value(result) {
+                Ok(result) => Self {
+                    status: StatusCode::OK.as_u16(),
+                    result: Some(result),
+                    reason: None,
+                },
+                Err(e) => Self::internal_server_error(Some(format!(
+                    "Unable to serialize result: {:?}",
+                    e
+                ))),
+            }
+        }
+
+        pub fn forbidden(reason: Option<String>) -> Self {
+            Self::new(StatusCode::FORBIDDEN, None, reason)
+        }
+
+        pub fn forbidden_note(reason: &'static str) -> Self {
+            Self::new(StatusCode::FORBIDDEN, None, Some(reason.to_string()))
+        }
+
+        pub fn internal_server_error(reason: Option<String>) -> Self {
+            Self::new(StatusCode::INTERNAL_SERVER_ERROR, None, reason)
+        }
+
+        pub fn internal_server_error_str(reason: Option<&'static str>) -> Self {
+            Self::internal_server_error(reason.map(|s| s.to_string()))
+        }
+
+        pub fn bad_request(reason: Option<&'static str>) -> Self {
+            Self::new(StatusCode::BAD_REQUEST, None, reason.map(|s| s.to_string()))
+        }
+
+        pub fn bad_request_string(reason: String) -> Self {
+            Self::new(StatusCode::BAD_REQUEST, None, Some(reason))
+        }
+
+        pub fn gateway_timeout() -> Self {
+            Self::new(StatusCode::GATEWAY_TIMEOUT, None, None)
+        }
+
+        pub fn new(status: StatusCode, result: Option<Value>, reason: Option<String>) -> Self {
+            Self {
+                status: status.as_u16(),
+                result: result.map(|value| to_raw_value(&value).unwrap()),
+                reason,
+            }
+        }
+        #[allow(unused)]
+        pub fn result(&self) -> Option<&RawValue> {
+            self.result.as_deref()
+        }
+        #[allow(unused)]
+        pub fn reason(&self) -> &Option<String> {
+            &self.reason
+        }
+    }
+
+    /// Body is serialized once, `result` is copied as is
+    impl IntoResponse for WebResponse {
+        fn into_response(self) -> Response {
+            (StatusCode::from_u16(self.status).unwrap(), Json(self)).into_response()
+        }
+    }
+
+    impl From<anyhow::Error> for WebResponse {
+        fn from(value: anyhow::Error) -> Self {
+            Self::internal_server_error(Some(value.to_string()))
+        }
+    }
+}
+
+mod fields {
+    use publib::types::OptionFile;
+    use serde::ser::SerializeMap;
+    use serde::{Serialize, Serializer};
+    use serde_derive::Deserialize;
+
+    pub const AVAILABLE_FIELDS: [&str; 8] = [
+        "path",
+        "hash",
+        "algo",
+        "mtime",
+        "size",
+        "is_dir",
+        "encrypted",
+        "children_scanned",
+    ];
+
+    #[derive(Clone, Debug, Default, Deserialize)]
+    pub struct FieldsQuery {
+        fields: Option<String>,
+    }
+
+    impl FieldsQuery {
+        /// Return `None` if client not specify any field (use default response)
+        pub fn selector(&self) -> Result<Option<FieldSelector>, String> {
+            self.fields
+                .as_ref()
+                .map(|fields| FieldSelector::parse(fields))
+                .transpose()
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct FieldSelector {
+        path: bool,
+        hash: bool,
+        algo: bool,
+        mtime: bool,
+        size: bool,
+        is_dir: bool,
+        encrypted: bool,
+        children_scanned: bool,
+    }
+
+    impl FieldSelector {
+        pub fn parse(fields: &str) -> Result<Self, String> {
+            let mut selector = Self::default();
+            for field in fields
+                .split(',')
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty())
+            {
+                match field {
+                    "path" => selector.path = true,
+                    "hash" => selector.hash = true,
+                    "algo" => selector.algo = true,
+                    "mtime" => selector.mtime = true,
+                    "size" => selector.size = true,
+                    "is_dir" => selector.is_dir = true,
+                    "encrypted" => selector.encrypted = true,
+                    "children_scanned" => selector.children_scanned = true,
+                    _ => {
+                        return Err(format!(
+                            "Unknown field {:?}, available fields: {}",
+                            field,
+                            AVAILABLE_FIELDS.join(",")
+                        ))
+                    }
+                }
+            }
+            Ok(selector)
+        }
+
+        fn meta_len(&self) -> usize {
+            [
+                self.hash,
+                self.algo,
+                self.mtime,
+                self.size,
+                self.is_dir,
+                self.encrypted,
+                self.children_scanned,
+            ]
+            .iter()
+            .filter(|x| **x)
+            .count()
+        }
+    }
+
+    pub struct PartialFile<'a> {
+        file: &'a OptionFile,
+        selector: &'a FieldSelector,
+    }
+
+    impl<'a> PartialFile<'a> {
+        pub fn new(file: &'a OptionFile, selector: &'a FieldSelector) -> Self {
+            Self { file, selector }
+        }
+    }
+
+    impl<'a> Serialize for PartialFile<'a> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let selector = self.selector;
+            let mut map = serializer.serialize_map(None)?;
+            if selector.path {
+                map.serialize_entry("path", self.file.path())?;
+            }
+            if selector.meta_len() > 0 {
+                map.serialize_entry(
+                    "meta",
+                    &self
+                        .file
+                        .meta()
+                        .as_ref()
+                        .map(|meta| PartialMeta { meta, selector }),
+                )?;
+            }
+            map.end()
+        }
+    }
+
+    struct PartialMeta<'a> {
+        meta: &'a publib::types::FileMeta,
+        selector: &'a FieldSelector,
+    }
+
+    impl<'a> Serialize for PartialMeta<'a> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut map = serializer.serialize_map(Some(self.selector.meta_len()))?;
+            if self.selector.hash {
+                map.serialize_entry("hash", self.meta.hash())?;
+            }
+            if self.selector.algo {
+                map.serialize_entry("algo", &self.meta.algo())?;
+            }
+            if self.selector.mtime {
+                map.serialize_entry("mtime", &self.meta.mtime())?;
+            }
+            if self.selector.size {
+                map.serialize_entry("size", &self.meta.size())?;
+            }
+            if self.selector.is_dir {
+                map.serialize_entry("is_dir", &self.meta.is_dir())?;
+            }
+            if self.selector.encrypted {
+                map.serialize_entry("encrypted", &self.meta.encrypted())?;
+            }
+            if self.selector.children_scanned {
+                map.serialize_entry("children_scanned", &self.meta.children_scanned())?;
+            }
+            map.end()
+        }
+    }
+
+    pub struct PartialFiles<'a> {
+        files: &'a [OptionFile],
+        selector: &'a FieldSelector,
+    }
+
+    impl<'a> PartialFiles<'a> {
+        pub fn new(files: &'a [OptionFile], selector: &'a FieldSelector) -> Self {
+            Self { files, selector }
+        }
+    }
+
+    impl<'a> Serialize for PartialFiles<'a> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_seq(
+                self.files
+                    .iter()
+                    .map(|file| PartialFile::new(file, self.selector)),
+            )
+        }
+    }
+}
+
+mod conditional {
+    use http::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE};
+    use http::{HeaderMap, HeaderValue};
+    use publib::types::FileEntry;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    pub fn entity_tag(entry: &FileEntry) -> String {
+        format!("\"{}\"", entry.hash())
+    }
+
+    /// Tag of file served in `Content-Encoding` `encoding`, encoded sidecar is other bytes,
+    /// so it never shares tag of file itself
+    pub fn encoded_tag(entry: &FileEntry, encoding: Option<&str>) -> String {
+        match encoding {
+            Some(encoding) => format!("\"{}-{}\"", entry.hash(), encoding),
+            None => entity_tag(entry),
+        }
+    }
+
+    fn modified_time(entry: &FileEntry) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(entry.mtime().max(0) as u64)
+    }
+
+    /// Insert `ETag` and `Last-Modified` header from indexed entry
+    pub fn insert_validators(headers: &mut HeaderMap, entry: &FileEntry, encoding: Option<&str>) {
+        if !entry.hash().is_empty() {
+            if let Ok(value) = HeaderValue::from_str(&encoded_tag(entry, encoding)) {
+                headers.insert(ETAG, value);
+            }
+        }
+        if let Ok(value) = HeaderValue::from_str(&httpdate::fmt_http_date(modified_time(entry))) {
+            headers.insert(LAST_MODIFIED, value);
+        }
+    }
+
+    /// Check `If-None-Match` and `If-Modified-Since` (ignored if `If-None-Match` present)
+    pub fn is_not_modified(request: &HeaderMap, entry: &FileEntry, encoding: Option<&str>) -> bool {
+        if let Some(value) = request.get(IF_NONE_MATCH) {
+            if entry.hash().is_empty() {
+                return false;
+            }
+            let etag = encoded_tag(entry, encoding);
+            return value.to_str().is_ok_and(|value| {
+                value
+                    .split(',')
+                    .map(|tag| tag.trim())
+                    .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+            });
+        }
+        if let Some(value) = request.get(IF_MODIFIED_SINCE) {
+            return value
+                .to_str()
+                .ok()
+                .and_then(|value| httpdate::parse_http_date(value).ok())
+                .is_some_and(|since| modified_time(entry) <= since);
+        }
+        false
+    }
+
+    /// Part of file to serve, bounds are inclusive
+    #[derive(Debug, PartialEq)]
+    pub enum ByteRange {
+        Full,
+        Partial(u64, u64),
+        Unsatisfiable,
+    }
+
+    /// `If-Range` must match indexed entry, so range of changed file is never mixed
+    fn range_applies(request: &HeaderMap, entry: Option<&FileEntry>) -> bool {
+        let Some(value) = request.get(IF_RANGE) else {
+            return true;
+        };
+        let (Some(entry), Ok(value)) = (entry, value.to_str()) else {
+            return false;
+        };
+        if value.starts_with('"') {
+            return !entry.hash().is_empty() && value == entity_tag(entry);
+        }
+        httpdate::parse_http_date(value).is_ok_and(|date| modified_time(entry) <= date)
+    }
+
+    /// Single range of `Range` header, multiple ranges are served as full file
+    pub fn requested_range(request: &HeaderMap, entry: Option<&FileEntry>, size: u64) -> ByteRange {
+        let Some(spec) = request
+            .get(RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().strip_prefix("bytes="))
+        else {
+            return ByteRange::Full;
+        };
+        if spec.contains(',') || !range_applies(request, entry) {
+            return ByteRange::Full;
+        }
+        let Some((start, end)) = spec.trim().split_once('-') else {
+            return ByteRange::Full;
+        };
+        let last = size.saturating_sub(1);
+        let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => (start, end.min(last)),
+            (Ok(start), Err(_)) if end.is_empty() => (start, last),
+            (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => {
+                (size.saturating_sub(suffix), last)
+            }
+            _ => return ByteRange::Full,
+        };
+        if start < size {
+            ByteRange::Partial(start, end)
+        } else {
+            ByteRange::Unsatisfiable
+        }
+    }
+}
+
+mod digest {
+    use crate::configure::current::DigestOption;
+    use crate::crypto::{parse_hex, spawn_decrypt, EncryptionKey};
+    use http::{HeaderMap, HeaderValue};
+    use publib::file::{get_file_hash, update_hasher, HashAlgo, HashOption, DEFAULT_BUFFER_SIZE};
+    use publib::types::FileEntry;
+    use std::fs::Metadata;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+
+    const BASE64_TABLE: &[u8; 64] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    pub(super) fn base64(bytes: &[u8]) -> String {
+        let mut output = String::with_capacity(bytes.len().div_ceil(3) * 4);
+        for chunk in bytes.chunks(3) {
+            let n = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |n, (i, b)| n | (u32::from(*b) << (16 - 8 * i)));
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    output.push(BASE64_TABLE[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+                } else {
+                    output.push('=');
+                }
+            }
+        }
+        output
+    }
+
+    /// Key in `Repr-Digest`, algorithm not registered by RFC 9530 uses its own name
+    fn digest_key(algo: HashAlgo) -> &'static str {
+        match algo {
+            HashAlgo::Sha256 => "sha-256",
+            algo => algo.name(),
+        }
+    }
+
+    /// Insert `Repr-Digest` (RFC 9530) and legacy `Digest` (RFC 3230) from stored `algo:hex`
+    /// digests, digest without known algorithm is skipped
+    pub fn insert_digests(headers: &mut HeaderMap, digests: &[String]) {
+        let digests = digests
+            .iter()
+            .filter_map(|digest| {
+                let algo = HashAlgo::from_digest(digest)?;
+                let (_, hex) = digest.split_once(':')?;
+                Some((digest_key(algo), base64(&parse_hex(hex)?)))
+            })
+            .collect::<Vec<_>>();
+        if digests.is_empty() {
+            return;
+        }
+        let repr = digests
+            .iter()
+            .map(|(key, value)| format!("{}=:{}:", key, value))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let legacy = digests
+            .iter()
+            .map(|(key, value)| format!("{}={}", key.to_uppercase(), value))
+            .collect::<Vec<_>>()
+            .join(",");
+        if let Ok(value) = HeaderValue::from_str(&repr) {
+            headers.insert("Repr-Digest", value);
+        }
+        if let Ok(value) = HeaderValue::from_str(&legacy) {
+            headers.insert("Digest", value);
+        }
+    }
+
+    /// Client asks for `algo` by `Want-Repr-Digest` or `Want-Digest`, weight 0 means not wanted
+    pub fn wants_digest(request: &HeaderMap, algo: HashAlgo) -> bool {
+        let key = digest_key(algo);
+        ["Want-Repr-Digest", "Want-Digest"]
+            .into_iter()
+            .filter_map(|name| request.get(name)?.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|item| {
+                let (name, weight) = item.split_once(['=', ';']).unwrap_or((item, ""));
+                name.trim().eq_ignore_ascii_case(key)
+                    && weight.trim().trim_start_matches("q=") != "0"
+            })
+    }
+
+    /// File is not changed since `entry` was indexed, so its stored hash describes content
+    pub fn is_current(entry: &FileEntry, metadata: &Metadata) -> bool {
+        entry.mtime() == metadata.mtime() && entry.size() == metadata.size() as i64
+    }
+
+    /// Reason blake3 of file of `size` bytes is not computed on request, sent to client as note
+    pub fn fresh_refused(option: &DigestOption, size: u64) -> Option<&'static str> {
+        if !option.fresh_blake3() {
+            Some("blake3 is only sent if file is indexed with it")
+        } else if !option.fresh_allowed(size) {
+            Some("blake3 is not computed for file larger than fresh_max_size")
+        } else {
+            None
+        }
+    }
+
+    /// Blake3 of served content, file encrypted at rest is hashed after decryption
+    pub async fn fresh_blake3(path: &Path, key: Option<EncryptionKey>) -> std::io::Result<String> {
+        let option = HashOption::new(HashAlgo::Blake3, DEFAULT_BUFFER_SIZE, false);
+        match key {
+            Some(key) => {
+                let mut hasher = HashAlgo::Blake3.hasher();
+                update_hasher(&mut hasher, spawn_decrypt(path.to_path_buf(), key), &option).await?;
+                Ok(hasher.digest())
+            }
+            None => get_file_hash(path, &option).await,
+        }
+    }
+}
+
+mod tree {
+    use publib::types::OptionFile;
+    use serde_derive::Serialize;
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    #[derive(Clone, Debug, Serialize)]
+    pub struct TreeNode {
+        #[serde(flatten)]
+        file: OptionFile,
+        children: Vec<TreeNode>,
+    }
+
+    fn build_node(
+        index: usize,
+        files: &mut [Option<OptionFile>],
+        children: &[Vec<usize>],
+    ) -> TreeNode {
+        TreeNode {
+            file: files[index].take().unwrap(),
+            children: children[index]
+                .iter()
+                .map(|child| build_node(*child, files, children))
+                .collect(),
+        }
+    }
+
+    /// Nest files by their parent directory, keep sibling order as input order
+    ///
+    /// Files whose parent directory is not in `files` become roots
+    pub fn build_tree(files: Vec<OptionFile>) -> Vec<TreeNode> {
+        let index = files
+            .iter()
+            .enumerate()
+            .map(|(i, file)| (file.path().to_string(), i))
+            .collect::<HashMap<_, _>>();
+
+        let mut roots = Vec::new();
+        let mut children = vec![Vec::new(); files.len()];
+        for (i, file) in files.iter().enumerate() {
+            match Path::new(file.path())
+                .parent()
+                .and_then(|parent| parent.to_str())
+                .and_then(|parent| index.get(parent))
+            {
+                Some(parent) => children[*parent].push(i),
+                None => roots.push(i),
+            }
+        }
+
+        let mut files = files.into_iter().map(Some).collect::<Vec<_>>();
+        roots
+            .into_iter()
+            .map(|root| build_node(root, &mut files, &children))
+            .collect()
+    }
+}
+
+mod ws {
+    use crate::configure::{PathMatcher, Permissions};
+    use crate::file::{Change, ChangeStream, FileEventHelper};
+    use crate::server::current::{require_scope, retain_authorized};
+    use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+    use axum::response::{IntoResponse, Response};
+    use axum::Extension;
+    use publib::types::{Scope, WsMessage, WsRequest};
+    use tracing::debug;
+
+    enum Incoming {
+        Request(String),
+        /// `None` if file daemon stopped
+        Change(Option<Change>),
+        Closed,
+    }
+
+    /// Persistent connection for query and change events, authorized once on upgrade
+    pub(super) async fn websocket(
+        upgrade: WebSocketUpgrade,
+        Extension(sender): Extension<FileEventHelper>,
+        Extension(matcher): Extension<PathMatcher>,
+        Extension(permissions): Extension<Permissions>,
+    ) -> Response {
+        if let Err(response) = require_scope(permissions, Scope::List) {
+            return response.into_response();
+        }
+        upgrade.on_upgrade(move |socket| session(socket, sender, matcher))
+    }
+
+    async fn recv_change(changes: &mut Option<ChangeStream>) -> Option<Change> {
+        match changes {
+            Some(receiver) => receiver.recv().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    async fn session(mut socket: WebSocket, sender: FileEventHelper, matcher: PathMatcher) {
+        let mut changes = None;
+        loop {
+            let incoming = tokio::select! {
+                message = socket.recv() => match message {
+                    Some(Ok(Message::Text(text))) => Incoming::Request(text),
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => Incoming::Closed,
+                    // Ping is answered by websocket library
+                    Some(Ok(_)) => continue,
+                },
+                event = recv_change(&mut changes) => Incoming::Change(event),
+            };
+
+            let reply = match incoming {
+                Incoming::Request(text) => {
+                    handle_request(&text, &sender, &matcher, &mut changes).await
+                }
+                Incoming::Change(Some(Change::Event(event))) => {
+                    if !event.paths().iter().any(|path| matcher.is_match(path)) {
+                        continue;
+                    }
+                    Some(WsMessage::Change { event })
+                }
+                Incoming::Change(Some(Change::Missed(count))) => Some(WsMessage::error(
+                    None,
+                    format!("Missed {} change event(s), query again to resync", count),
+                )),
+                Incoming::Change(None) => {
+                    changes = None;
+                    Some(WsMessage::error(None, "Change events is not available"))
+                }
+                Incoming::Closed => break,
+            };
+
+            if let Some(reply) = reply {
+                let reply = serde_json::to_string(&reply).unwrap();
+                if let Err(e) = socket.send(Message::Text(reply)).await {
+                    debug!("Unable send websocket message: {:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn handle_request(
+        text: &str,
+        sender: &FileEventHelper,
+        matcher: &PathMatcher,
+        changes: &mut Option<ChangeStream>,
+    ) -> Option<WsMessage> {
+        let request: WsRequest = match serde_json::from_str(text) {
+            Ok(request) => request,
+            Err(e) => return Some(WsMessage::error(None, format!("Invalid request: {}", e))),
+        };
+        match request {
+            WsRequest::Query { id, paths } => Some(query(id, paths, sender, matcher).await),
+            WsRequest::Subscribe => {
+                changes.get_or_insert_with(|| sender.subscribe());
+                None
+            }
+            WsRequest::Unsubscribe => {
+                changes.take();
+                None
+            }
+        }
+    }
+
+    /// Unlike HTTP query, wait daemon response without timeout since connection is persistent
+    async fn query(
+        id: u64,
+        paths: Vec<String>,
+        sender: &FileEventHelper,
+        matcher: &PathMatcher,
+    ) -> WsMessage {
+        let paths = if paths.is_empty() {
+            matcher.bases().clone()
+        } else {
+            if let Some(path) = paths.iter().find(|path| !matcher.is_match(path)) {
+                return WsMessage::error(Some(id), format!("Path {:?} is not authorized", path));
+            }
+            paths.into()
+        };
+        let Some(receiver) = sender.send_request(paths).await else {
+            return WsMessage::error(Some(id), "File daemon is not available");
+        };
+        match receiver.await {
+            Ok(files) => WsMessage::Result {
+                id,
+                files: retain_authorized(files, matcher),
+            },
+            Err(_) => WsMessage::error(Some(id), "File daemon is not available"),
+        }
+    }
+}
+
+mod access {
+    use crate::configure::current::AuthEntry;
+    use crate::file::{unix_now, FileEventHelper};
+    use axum::body::{boxed, Bytes, HttpBody};
+    use axum::extract::Path;
+    use axum::middleware::Next;
+    use axum::response::Response;
+    use axum::Extension;
+    use http::{HeaderMap, Request};
+    use publib::normalize_path;
+    use publib::types::AccessRecord;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::time::Instant;
+
+    /// Count bytes sent, record is sent to access log once body is dropped, also if client
+    /// disconnected before download finished
+    struct AccessBody<B> {
+        inner: B,
+        sender: FileEventHelper,
+        token: String,
+        path: String,
+        status: u16,
+        bytes: u64,
+        started: Instant,
+    }
+
+    impl<B> HttpBody for AccessBody<B>
+    where
+        B: HttpBody<Data = Bytes> + Unpin,
+    {
+        type Data = Bytes;
+        type Error = B::Error;
+
+        fn poll_data(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+            let poll = Pin::new(&mut self.inner).poll_data(cx);
+            if let Poll::Ready(Some(Ok(ref bytes))) = poll {
+                self.bytes += bytes.len() as u64;
+            }
+            poll
+        }
+
+        fn poll_trailers(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+            Pin::new(&mut self.inner).poll_trailers(cx)
+        }
+
+        fn is_end_stream(&self) -> bool {
+            self.inner.is_end_stream()
+        }
+
+        fn size_hint(&self) -> hyper::body::SizeHint {
+            self.inner.size_hint()
+        }
+    }
+
+    impl<B> Drop for AccessBody<B> {
+        fn drop(&mut self) {
+            self.sender.record_access(AccessRecord::new(
+                std::mem::take(&mut self.token),
+                std::mem::take(&mut self.path),
+                self.bytes,
+                self.started.elapsed().as_millis() as u64,
+                self.status,
+                unix_now(),
+            ));
+        }
+    }
+
+    /// Record download of `/file` in access log, rejected requests are recorded too
+    pub async fn record_download<B>(
+        Extension(sender): Extension<FileEventHelper>,
+        Path(path): Path<String>,
+        request: Request<B>,
+        next: Next<B>,
+    ) -> Response {
+        let token = request
+            .extensions()
+            .get::<Arc<AuthEntry>>()
+            .map(|entry| entry.label())
+            .unwrap_or_default();
+        let started = Instant::now();
+        let response = next.run(request).await;
+        let status = response.status().as_u16();
+        response.map(|body| {
+            boxed(AccessBody {
+                inner: body,
+                sender,
+                token,
+                path: normalize_path(&path),
+                status,
+                bytes: 0,
+                started,
+            })
+        })
+    }
+}
+
+mod compression {
+    use crate::configure::current::Compression;
+    use crate::configure::SIDECAR_ENCODINGS;
+    use axum::body::HttpBody;
+    use http::header::{ACCEPT_ENCODING, CONTENT_RANGE, CONTENT_TYPE};
+    use http::{HeaderMap, Response, StatusCode};
+    use std::path::{Path, PathBuf};
+    use tower_http::compression::predicate::{DefaultPredicate, Predicate};
+    use tower_http::compression::CompressionLayer;
+
+    /// Content types already compressed, compress them again only costs CPU
+    const COMPRESSED_TYPES: [&str; 13] = [
+        "application/gzip",
+        "application/x-gzip",
+        "application/zstd",
+        "application/zip",
+        "application/x-xz",
+        "application/x-bzip2",
+        "application/x-7z-compressed",
+        "application/vnd.rar",
+        "application/x-rar-compressed",
+        "application/pdf",
+        "application/octet-stream",
+        "audio/",
+        "video/",
+    ];
+
+    /// `DefaultPredicate` skips images and tiny bodies, files of compressed format are skipped
+    /// as well, unknown binary file is served as `application/octet-stream`
+    ///
+    /// Partial content is never compressed, its `Content-Range` counts identity bytes
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct Compressible;
+
+    impl Predicate for Compressible {
+        fn should_compress<B>(&self, response: &Response<B>) -> bool
+        where
+            B: HttpBody,
+        {
+            if response.status() == StatusCode::PARTIAL_CONTENT
+                || response.headers().contains_key(CONTENT_RANGE)
+            {
+                return false;
+            }
+            let compressed = response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|content_type| {
+                    COMPRESSED_TYPES
+                        .iter()
+                        .any(|compressed| content_type.starts_with(compressed))
+                });
+            !compressed && DefaultPredicate::new().should_compress(response)
+        }
+    }
+
+    fn accepts_encoding(headers: &HeaderMap, encoding: &str) -> bool {
+        headers
+            .get_all(ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|item| {
+                let (name, weight) = item.split_once(';').unwrap_or((item, ""));
+                let weight = weight.trim().trim_start_matches("q=");
+                name.trim().eq_ignore_ascii_case(encoding)
+                    && weight.parse::<f32>().map_or(true, |weight| weight > 0.0)
+            })
+    }
+
+    /// Sidecar of `path` in encoding accepted by client and its `Content-Encoding`, e.g.
+    /// `foo.txt.zst`, sidecar older than file is stale and never served
+    pub async fn find_sidecar(path: &Path, headers: &HeaderMap) -> Option<(PathBuf, &'static str)> {
+        let mtime = tokio::fs::metadata(path).await.ok()?.modified().ok()?;
+        for (suffix, encoding) in SIDECAR_ENCODINGS {
+            if !accepts_encoding(headers, encoding) {
+                continue;
+            }
+            let mut sidecar = path.as_os_str().to_os_string();
+            sidecar.push(suffix);
+            let sidecar = PathBuf::from(sidecar);
+            let fresh = tokio::fs::metadata(&sidecar)
+                .await
+                .ok()
+                .filter(|metadata| metadata.is_file())
+                .and_then(|metadata| metadata.modified().ok())
+                .is_some_and(|modified| modified >= mtime);
+            if fresh {
+                return Some((sidecar, encoding));
+            }
+        }
+        None
+    }
+
+    /// Return `None` if no encoding is enabled
+    pub fn compression_layer(option: Compression) -> Option<CompressionLayer<Compressible>> {
+        option.enabled().then(|| {
+            CompressionLayer::new()
+                .gzip(option.gzip())
+                .zstd(option.zstd())
+                .compress_when(Compressible)
+        })
+    }
+}
+
+mod concurrency {
+    use crate::configure::current::AuthEntry;
+    use crate::server::WebResponse;
+    use axum::body::{boxed, Bytes, HttpBody};
+    use axum::middleware::Next;
+    use axum::response::{IntoResponse, Response};
+    use axum::Extension;
+    use http::{HeaderMap, Request, StatusCode};
+    use std::collections::HashMap;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+    use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+    /// Limit and semaphore of each token
+    type Slots = HashMap<String, (usize, Arc<Semaphore>)>;
+
+    /// Simultaneous requests of each token, limit of token can be changed by reload configure
+    #[derive(Clone, Debug, Default)]
+    pub struct ConcurrencyLimiter {
+        default: Option<usize>,
+        slots: Arc<Mutex<Slots>>,
+    }
+
+    impl ConcurrencyLimiter {
+        pub fn new(default: Option<usize>) -> Self {
+            Self {
+                default,
+                slots: Default::default(),
+            }
+        }
+
+        /// `Ok(None)` if token is unlimited, `Err(())` if token reach its limit
+        fn try_acquire(&self, entry: &AuthEntry) -> Result<Option<OwnedSemaphorePermit>, ()> {
+            let limit = match entry.concurrent_requests().or(self.default) {
+                Some(limit) if limit > 0 => limit,
+                _ => return Ok(None),
+            };
+            let semaphore = {
+                let mut slots = self.slots.lock().unwrap();
+                let slot = slots
+                    .entry(entry.token().to_string())
+                    .or_insert_with(|| (limit, Arc::new(Semaphore::new(limit))));
+                if slot.0 != limit {
+                    *slot = (limit, Arc::new(Semaphore::new(limit)));
+                }
+                slot.1.clone()
+            };
+            semaphore.try_acquire_owned().map(Some).map_err(|_| ())
+        }
+    }
+
+    /// Hold permit until response body is sent, so streaming download is counted
+    struct PermitBody<B> {
+        inner: B,
+        _permit: OwnedSemaphorePermit,
+    }
+
+    impl<B> HttpBody for PermitBody<B>
+    where
+        B: HttpBody<Data = Bytes> + Unpin,
+    {
+        type Data = Bytes;
+        type Error = B::Error;
+
+        fn poll_data(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+            Pin::new(&mut self.inner).poll_data(cx)
+        }
+
+        fn poll_trailers(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+            Pin::new(&mut self.inner).poll_trailers(cx)
+        }
+
+        fn is_end_stream(&self) -> bool {
+            self.inner.is_end_stream()
+        }
+
+        fn size_hint(&self) -> hyper::body::SizeHint {
+            self.inner.size_hint()
+        }
+    }
+
+    /// Reject request with 429 if token already has too many requests in flight
+    pub async fn limit_concurrency<B>(
+        Extension(limiter): Extension<ConcurrencyLimiter>,
+        request: Request<B>,
+        next: Next<B>,
+    ) -> Response {
+        let Some(entry) = request.extensions().get::<Arc<AuthEntry>>() else {
+            return next.run(request).await;
+        };
+        match limiter.try_acquire(entry) {
+            Ok(None) => next.run(request).await,
+            Ok(Some(permit)) => next.run(request).await.map(|body| {
+                boxed(PermitBody {
+                    inner: body,
+                    _permit: permit,
+                })
+            }),
+            Err(()) => WebResponse::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                None,
+                Some("Too many concurrent requests".into()),
+            )
+            .into_response(),
+        }
+    }
+}
+
+mod shedding {
+    use crate::file::FileEventHelper;
+    use crate::server::WebResponse;
+    use axum::middleware::Next;
+    use axum::response::{IntoResponse, Response};
+    use axum::Extension;
+    use http::{HeaderValue, Request, StatusCode};
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Reject metadata requests early when server is overloaded,
+    /// instead of waiting until daemon response timeout
+    #[derive(Clone, Debug, Default)]
+    pub struct LoadShedder {
+        queue_depth: Option<usize>,
+        in_flight_limit: Option<usize>,
+        retry_after: u64,
+        in_flight: Arc<AtomicUsize>,
+        shed: Arc<AtomicU64>,
+    }
+
+    impl LoadShedder {
+        pub fn new(
+            queue_depth: Option<usize>,
+            in_flight_limit: Option<usize>,
+            retry_after: u64,
+        ) -> Self {
+            Self {
+                queue_depth,
+                in_flight_limit,
+                retry_after,
+                ..Default::default()
+            }
+        }
+
+        /// Metadata requests being handled
+        pub fn in_flight(&self) -> usize {
+            self.in_flight.load(Ordering::Relaxed)
+        }
+
+        /// Requests rejected since server started
+        pub fn shed(&self) -> u64 {
+            self.shed.load(Ordering::Relaxed)
+        }
+
+        fn is_overloaded(&self, queue_depth: usize, in_flight: usize) -> bool {
+            self.queue_depth.is_some_and(|limit| queue_depth >= limit)
+                || self.in_flight_limit.is_some_and(|limit| in_flight > limit)
+        }
+    }
+
+    struct InFlightGuard(Arc<AtomicUsize>);
+
+    impl Drop for InFlightGuard {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    pub async fn shed_load<B>(
+        Extension(shedder): Extension<LoadShedder>,
+        Extension(helper): Extension<FileEventHelper>,
+        request: Request<B>,
+        next: Next<B>,
+    ) -> Response {
+        let in_flight = shedder.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+        let _guard = InFlightGuard(shedder.in_flight.clone());
+        if shedder.is_overloaded(helper.queue_depth(), in_flight) {
+            shedder.shed.fetch_add(1, Ordering::Relaxed);
+            let mut response = WebResponse::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                None,
+                Some("Server is overloaded".into()),
+            )
+            .into_response();
+            response.headers_mut().insert(
+                http::header::RETRY_AFTER,
+                HeaderValue::from(shedder.retry_after),
+            );
+            return response;
+        }
+        next.run(request).await
+    }
+}
+
+mod cluster {
+    use crate::configure::current::AuthEntry;
+    use crate::server::WebResponse;
+    use axum::extract::OriginalUri;
+    use axum::middleware::Next;
+    use axum::response::{IntoResponse, Response};
+    use axum::Extension;
+    use http::{HeaderValue, Method, Request, StatusCode, Uri};
+    use percent_encoding::percent_decode_str;
+    use publib::normalize_path;
+    use serde_json::{json, Value};
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use xxhash_rust::xxh3::xxh3_64;
+
+    /// Query parameter added to redirect, request has it is served locally, so nodes disagree
+    /// on ring never send client back and forth
+    const HOP_PARAM: &str = "hop";
+
+    /// Consistent hash ring of nodes serving same backend, each node owns a partition of paths
+    /// so its page cache stays hot
+    ///
+    /// Node is placed at `xxh3("{node}#{i}")` for `i` in `0..replicas`,
+    /// path (normalized, without leading `./`) is owned by first node clockwise from `xxh3(path)`
+    #[derive(Clone, Debug)]
+    pub struct HashRing {
+        current: String,
+        nodes: Arc<Vec<String>>,
+        replicas: usize,
+        points: Arc<BTreeMap<u64, usize>>,
+    }
+
+    impl HashRing {
+        /// `nodes` are base URLs (e.g. `http://node-a:24146`), `current` should be one of them
+        pub fn new(current: String, nodes: Vec<String>, replicas: usize) -> Self {
+            let mut points = BTreeMap::new();
+            for (index, node) in nodes.iter().enumerate() {
+                for i in 0..replicas {
+                    points.insert(xxh3_64(format!("{}#{}", node, i).as_bytes()), index);
+                }
+            }
+            Self {
+                current,
+                nodes: Arc::new(nodes),
+                replicas,
+                points: Arc::new(points),
+            }
+        }
+
+        pub fn owner(&self, path: &str) -> Option<&str> {
+            let hash = xxh3_64(path.trim_start_matches("./").as_bytes());
+            let (_, index) = self
+                .points
+                .range(hash..)
+                .next()
+                .or_else(|| self.points.iter().next())?;
+            Some(self.nodes[*index].as_str())
+        }
+
+        /// Published on `/mirrors`, so client can request owner directly
+        pub fn to_value(&self) -> Value {
+            json!({
+                "self": self.current,
+                "nodes": *self.nodes,
+                "replicas": self.replicas,
+                "hash": "xxh3",
+            })
+        }
+    }
+
+    pub(super) fn is_redirected(uri: &Uri) -> bool {
+        uri.query().is_some_and(|query| {
+            query
+                .split('&')
+                .any(|pair| pair.split_once('=').map_or(pair, |(key, _)| key) == HOP_PARAM)
+        })
+    }
+
+    /// Path and query of `uri` marked by `HOP_PARAM`
+    pub(super) fn hop_target(uri: &Uri) -> String {
+        match uri.query().filter(|query| !query.is_empty()) {
+            Some(query) => format!("{}?{}&{}=1", uri.path(), query, HOP_PARAM),
+            None => format!("{}?{}=1", uri.path(), HOP_PARAM),
+        }
+    }
+
+    /// Redirect download to node owns the path, request is served locally if ring is not set
+    ///
+    /// Runs after authorization, redirect can't carry `Authorization`, so only request
+    /// authorized by URL (signed or public) is redirected, token client finds owner on
+    /// `/mirrors` instead
+    pub async fn route_to_owner<B>(
+        Extension(ring): Extension<Option<HashRing>>,
+        request: Request<B>,
+        next: Next<B>,
+    ) -> Response {
+        let Some(ring) = ring else {
+            return next.run(request).await;
+        };
+        if request.method() != Method::GET && request.method() != Method::HEAD {
+            return next.run(request).await;
+        }
+        if request.extensions().get::<Arc<AuthEntry>>().is_some() {
+            return next.run(request).await;
+        }
+        // Path of signed request is rewritten to signed path, URI of client is redirected
+        let uri = request
+            .extensions()
+            .get::<OriginalUri>()
+            .map_or(request.uri(), |OriginalUri(uri)| uri);
+        if is_redirected(uri) {
+            return next.run(request).await;
+        }
+        let Some(path) = request.uri().path().strip_prefix("/file/") else {
+            return next.run(request).await;
+        };
+        let Ok(path) = percent_decode_str(path).decode_utf8() else {
+            return next.run(request).await;
+        };
+        let path = normalize_path(path.as_ref());
+        match ring.owner(&path) {
+            Some(owner) if owner != ring.current => {
+                let location = format!("{}{}", owner.trim_end_matches('/'), hop_target(uri));
+                let Ok(location) = HeaderValue::from_str(&location) else {
+                    return next.run(request).await;
+                };
+                let mut response =
+                    WebResponse::new(StatusCode::TEMPORARY_REDIRECT, None, None).into_response();
+                response
+                    .headers_mut()
+                    .insert(http::header::LOCATION, location);
+                response
+            }
+            _ => next.run(request).await,
+        }
+    }
+}
+
+mod tenant {
+    use crate::configure::current::AuthEntry;
+    use crate::server::WebResponse;
+    use axum::body::{boxed, Body};
+    use axum::extract::Query;
+    use axum::middleware::Next;
+    use axum::response::{IntoResponse, Response};
+    use http::{header, Method, Request, Uri};
+    use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+    use publib::normalize_path;
+    use serde_json::Value;
+    use std::path::{Component, Path};
+    use std::sync::Arc;
+    use tracing::error;
+
+    /// Routes take path after prefix
+    const PATH_ROUTES: &[&str] = &[
+        "/file/",
+        "/file-info/",
+        "/stat/",
+        "/chunks/",
+        "/archive/",
+        "/tail/",
+        "/dir/",
+        "/mkdir/",
+    ];
+
+    /// Routes take paths in JSON body
+    const BODY_ROUTES: &[&str] = &[
+        "/query",
+        "/sign",
+        "/verify",
+        "/events/ingest",
+        "/move",
+        "/upload",
+    ];
+
+    /// Routes expose paths outside of root, or sign paths so they can't be rewritten, not
+    /// available to token with root
+    const DENIED_ROUTES: &[&str] = &["/admin/", "/ws", "/manifest"];
+
+    /// Query parameters contain path
+    const PATH_PARAMS: &[&str] = &["path", "prefix", "cursor"];
+
+    /// Fields of JSON body contain path or paths
+    const PATH_FIELDS: &[&str] = &["path", "paths", "from", "to", "prefix", "cursor", "next"];
+
+    pub(super) const URL_PATH: &AsciiSet = &NON_ALPHANUMERIC
+        .remove(b'/')
+        .remove(b'-')
+        .remove(b'_')
+        .remove(b'.')
+        .remove(b'~');
+
+    /// Path relative to token root as path relative to working directory
+    pub fn join_root(root: &str, path: &str) -> String {
+        match normalize_path(path).strip_prefix('.') {
+            Some(rest) => format!("{}{}", normalize_path(root), rest),
+            None => normalize_path(root),
+        }
+    }
+
+    /// Reverse of `join_root`, `None` if path is outside of root
+    pub(super) fn strip_root(root: &str, path: &str) -> Option<String> {
+        match path.strip_prefix(root) {
+            Some("") => Some(".".to_string()),
+            Some(rest) if rest.starts_with('/') => Some(format!(".{}", rest)),
+            _ => None,
+        }
+    }
+
+    /// `..` could climb out of token root
+    fn climbs(path: &str) -> bool {
+        Path::new(path)
+            .components()
+            .any(|component| matches!(component, Component::ParentDir | Component::RootDir))
+    }
+
+    /// Apply `f` to every path in JSON value, `None` if any path is rejected
+    fn map_paths(value: &mut Value, f: &impl Fn(&str) -> Option<String>) -> Option<()> {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    match value {
+                        Value::String(path) if PATH_FIELDS.contains(&key.as_str()) => {
+                            *path = f(path)?;
+                        }
+                        Value::Array(paths) if key == "paths" => {
+                            for path in paths.iter_mut() {
+                                if let Value::String(path) = path {
+                                    *path = f(path)?;
+                                }
+                            }
+                        }
+                        value => map_paths(value, f)?,
+                    }
+                }
+            }
+            Value::Array(values) => {
+                for value in values {
+                    map_paths(value, f)?;
+                }
+            }
+            _ => {}
+        }
+        Some(())
+    }
+
+    /// Apply `f` to JSON body, body encoded as JSON string (by `WebResponse`) is unwrapped
+    fn map_body(body: &[u8], f: &impl Fn(&str) -> Option<String>) -> Option<Vec<u8>> {
+        let mut value: Value = serde_json::from_slice(body).ok()?;
+        if let Value::String(ref inner) = value {
+            if let Ok(mut inner) = serde_json::from_str::<Value>(inner) {
+                map_paths(&mut inner, f)?;
+                value = Value::String(inner.to_string());
+                return serde_json::to_vec(&value).ok();
+            }
+        }
+        map_paths(&mut value, f)?;
+        serde_json::to_vec(&value).ok()
+    }
+
+    pub(super) fn translate_uri(root: &str, uri: &Uri) -> Option<Uri> {
+        let mut path = uri.path().to_string();
+        if let Some(prefix) = PATH_ROUTES
+            .iter()
+            .find(|prefix| uri.path().starts_with(**prefix))
+        {
+            let rest = percent_decode_str(&uri.path()[prefix.len()..])
+                .decode_utf8()
+                .ok()?;
+            if climbs(&rest) {
+                return None;
+            }
+            let real = join_root(root, &rest);
+            path = format!(
+                "{}{}",
+                prefix,
+                utf8_percent_encode(real.trim_start_matches("./"), URL_PATH)
+            );
+        }
+        let query = match uri.query() {
+            Some(_) => {
+                let Query(params) = Query::<Vec<(String, String)>>::try_from_uri(uri).ok()?;
+                let mut pairs = Vec::with_capacity(params.len());
+                for (key, value) in params {
+                    let value = if PATH_PARAMS.contains(&key.as_str()) {
+                        if climbs(&value) {
+                            return None;
+                        }
+                        join_root(root, &value)
+                    } else {
+                        value
+                    };
+                    pairs.push(format!(
+                        "{}={}",
+                        utf8_percent_encode(&key, NON_ALPHANUMERIC),
+                        utf8_percent_encode(&value, NON_ALPHANUMERIC)
+                    ));
+                }
+                format!("?{}", pairs.join("&"))
+            }
+            None => String::new(),
+        };
+        format!("{}{}", path, query).parse().ok()
+    }
+
+    /// Token with `root` sees its subtree as whole working directory, paths of request are
+    /// joined with root and paths of JSON response are stripped
+    pub async fn translate_root(request: Request<Body>, next: Next<Body>) -> Response {
+        let Some(root) = request
+            .extensions()
+            .get::<Arc<AuthEntry>>()
+            .and_then(|entry| entry.root().map(normalize_path))
+        else {
+            return next.run(request).await;
+        };
+        let route = request.uri().path().to_string();
+        if DENIED_ROUTES.iter().any(|prefix| route.starts_with(prefix)) {
+            return WebResponse::forbidden_note("Not available to token with root").into_response();
+        }
+
+        let (mut parts, body) = request.into_parts();
+        let Some(uri) = translate_uri(&root, &parts.uri) else {
+            return WebResponse::bad_request(Some("Path should be inside token root"))
+                .into_response();
+        };
+        parts.uri = uri;
+        let body = if parts.method == Method::POST && BODY_ROUTES.contains(&route.as_str()) {
+            let Ok(bytes) = hyper::body::to_bytes(body).await else {
+                return WebResponse::bad_request(Some("Unable to read body")).into_response();
+            };
+            let to_real = |path: &str| (!climbs(path)).then(|| join_root(&root, path));
+            match map_body(&bytes, &to_real) {
+                Some(bytes) => {
+                    parts.headers.remove(header::CONTENT_LENGTH);
+                    Body::from(bytes)
+                }
+                // Not JSON, handler will reject it
+                None if serde_json::from_slice::<Value>(&bytes).is_err() => Body::from(bytes),
+                None => {
+                    return WebResponse::bad_request(Some("Path should be inside token root"))
+                        .into_response()
+                }
+            }
+        } else {
+            body
+        };
+
+        let response = next.run(Request::from_parts(parts, body)).await;
+        let is_json = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .is_some_and(|value| value == "application/json");
+        if !is_json {
+            return response;
+        }
+        let (mut parts, body) = response.into_parts();
+        let Ok(bytes) = hyper::body::to_bytes(body).await else {
+            return WebResponse::internal_server_error_str(Some("Unable to read response"))
+                .into_response();
+        };
+        // Empty cursor is the last page, not a path
+        let to_tenant = |path: &str| match path {
+            "" => Some(String::new()),
+            path => strip_root(&root, path),
+        };
+        let bytes = match map_body(&bytes, &to_tenant) {
+            Some(bytes) => bytes,
+            None if serde_json::from_slice::<Value>(&bytes).is_err() => bytes.to_vec(),
+            // Real path is never sent to token
+            None => {
+                error!("Response of {} contains path outside token root", route);
+                return WebResponse::internal_server_error_str(Some("Unable to translate paths"))
+                    .into_response();
+            }
+        };
+        parts.headers.remove(header::CONTENT_LENGTH);
+        Response::from_parts(parts, boxed(Body::from(bytes)))
+    }
+}
+
+mod request_id {
+    use http::{HeaderValue, Request};
+    use rand::distributions::Alphanumeric;
+    use rand::Rng;
+    use tower_http::request_id::{MakeRequestId, RequestId};
+    use tracing::Span;
+
+    const REQUEST_ID_LENGTH: usize = 16;
+
+    /// Request id is kept if client already set `x-request-id`
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct RandomRequestId;
+
+    impl MakeRequestId for RandomRequestId {
+        fn make_request_id<B>(&mut self, _request: &Request<B>) -> Option<RequestId> {
+            let id = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(REQUEST_ID_LENGTH)
+                .map(char::from)
+                .collect::<String>();
+            HeaderValue::from_str(&id).ok().map(RequestId::new)
+        }
+    }
+
+    /// Span of each request, `client_ip` and `token` are recorded once resolved
+    pub fn request_span<B>(request: &Request<B>) -> Span {
+        let id = request
+            .headers()
+            .get("x-request-id")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        tracing::info_span!(
+            "request",
+            id = %id,
+            method = %request.method(),
+            uri = %request.uri(),
+            client_ip = tracing::field::Empty,
+            token = tracing::field::Empty,
+        )
+    }
+}
+
+mod client_ip {
+    use crate::configure::current::Firewall;
+    use axum::extract::ConnectInfo;
+    use axum::middleware::Next;
+    use axum::response::Response;
+    use http::{header, HeaderMap, Request};
+    use std::net::{IpAddr, SocketAddr};
+    use std::sync::Arc;
+    use tracing::Span;
+
+    /// Address of client, peer address unless it is a trusted proxy
+    #[derive(Clone, Copy, Debug)]
+    pub struct ClientIp(pub Option<IpAddr>);
+
+    /// Node of `Forwarded` (RFC 7239) `for=`, e.g. `"[2001:db8::1]:8080"` or `192.0.2.1:80`
+    pub(super) fn parse_node(node: &str) -> Option<IpAddr> {
+        let node = node.trim().trim_matches('"');
+        if let Some(rest) = node.strip_prefix('[') {
+            return rest.split(']').next()?.parse().ok();
+        }
+        node.parse()
+            .ok()
+            .or_else(|| node.rsplit_once(':')?.0.parse().ok())
+    }
+
+    /// Addresses appended by proxies, nearest proxy is last
+    pub(super) fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+        let forwarded = headers
+            .get_all(header::FORWARDED)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.split_once('=')?;
+                    key.trim()
+                        .eq_ignore_ascii_case("for")
+                        .then(|| parse_node(value))
+                })
+            })
+            .collect::<Vec<_>>();
+        if !forwarded.is_empty() {
+            return forwarded;
+        }
+        headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(parse_node)
+            .collect()
+    }
+
+    /// Walk chain from nearest proxy, first address not trusted is client
+    ///
+    /// Headers are only honored if peer is trusted proxy, `None` if chain has unknown
+    /// node so network checks fail closed
+    pub(super) fn resolve(
+        firewall: &Firewall,
+        peer: Option<IpAddr>,
+        headers: &HeaderMap,
+    ) -> Option<IpAddr> {
+        let Some(peer) = peer.filter(|ip| firewall.is_trusted_proxy(*ip)) else {
+            return peer;
+        };
+        if let Some(name) = firewall.client_ip_header() {
+            return headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_node);
+        }
+        let mut client = peer;
+        for ip in forwarded_chain(headers).into_iter().rev() {
+            // Unknown or obfuscated node, client behind it is unknown too
+            client = ip?;
+            if !firewall.is_trusted_proxy(client) {
+                break;
+            }
+        }
+        Some(client)
+    }
+
+    /// Insert `ClientIp` for logging and network checks down the stack
+    pub async fn resolve_client_ip<B>(mut request: Request<B>, next: Next<B>) -> Response {
+        let firewall = request.extensions().get::<Arc<Firewall>>().unwrap();
+        let peer = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        let ip = resolve(firewall, peer, request.headers());
+        if let Some(ip) = ip {
+            Span::current().record("client_ip", ip.to_string().as_str());
+        }
+        request.extensions_mut().insert(ClientIp(ip));
+        next.run(request).await
+    }
+}
+
+mod auth {
+    use axum::body::BoxBody;
+    use std::sync::Arc;
+    use tracing::{warn, Span};
+
+    use crate::configure::current::{AuthEntry, Firewall};
+    use crate::configure::{mask_token, PathMatcher, Permissions, RwPoolType};
+    use crate::crypto::UrlSigner;
+    use crate::file::{unix_now, FileEventHelper};
+    use crate::server::client_ip::ClientIp;
+    use crate::server::tenant::URL_PATH;
+    use axum::extract::{OriginalUri, Query};
+    use futures_util::future::BoxFuture;
+    use http::{Method, StatusCode, Uri};
+    use hyper::{Request, Response};
+    use percent_encoding::{percent_decode_str, utf8_percent_encode};
+    use publib::normalize_path;
+    use publib::types::Scope;
+    use serde_derive::Deserialize;
+    use tower_http::auth::AsyncAuthorizeRequest;
+
+    #[derive(Clone, Copy)]
+    pub struct AuthLayer;
+
+    impl<B> AsyncAuthorizeRequest<B> for AuthLayer
+    where
+        B: Send + Sync + 'static,
+    {
+        type RequestBody = B;
+        // https://github.com/tower-rs/tower-http/discussions/346
+        type ResponseBody = BoxBody;
+        type Future = BoxFuture<'static, Result<Request<B>, Response<Self::ResponseBody>>>;
+
+        fn authorize(&mut self, mut request: Request<B>) -> Self::Future {
+            Box::pin(async {
+                let firewall = request.extensions().get::<Arc<Firewall>>().unwrap();
+                let ClientIp(ip) = request.extensions().get::<ClientIp>().copied().unwrap();
+                if !firewall.permits(ip) {
+                    warn!("Reject request from {:?} by firewall", ip);
+                    return Err(reject(StatusCode::FORBIDDEN));
+                }
+                let pool = request.extensions().get::<Arc<RwPoolType>>().unwrap();
+                if let Some(entry) = check_auth(&request, pool).await {
+                    Span::current().record("token", mask_token(entry.token()).as_str());
+                    if !entry.permits(ip) {
+                        warn!("Reject token from {:?} outside its allowed networks", ip);
+                        return Err(reject(StatusCode::FORBIDDEN));
+                    }
+                    // Set authorized paths as a request extension so it can be accessed by other
+                    // services down the stack, matcher is shared so no path is copied
+                    request.extensions_mut().insert(entry.matcher().clone());
+                    request.extensions_mut().insert(entry.permissions());
+                    request.extensions_mut().insert(entry);
+
+                    Ok(request)
+                } else if let Some(path) = check_signature(&request) {
+                    Span::current().record("token", "signed");
+                    if let Some(uri) = signed_uri(&path, request.uri()) {
+                        let original = std::mem::replace(request.uri_mut(), uri);
+                        request.extensions_mut().insert(OriginalUri(original));
+                    }
+                    // Signed URL only grant access to the signed file
+                    request
+                        .extensions_mut()
+                        .insert(PathMatcher::prefix(vec![path]));
+                    request
+                        .extensions_mut()
+                        .insert(Permissions::new(&[Scope::Read]));
+
+                    Ok(request)
+                } else if let Some(matcher) = check_public(&request) {
+                    Span::current().record("token", "anonymous");
+                    // Anonymous request can only download and query public paths
+                    request.extensions_mut().insert(matcher);
+                    request
+                        .extensions_mut()
+                        .insert(Permissions::new(&[Scope::Read, Scope::List]));
+
+                    Ok(request)
+                } else {
+                    Err(reject(StatusCode::UNAUTHORIZED))
+                }
+            })
+        }
+    }
+
+    fn reject(status: StatusCode) -> Response<BoxBody> {
+        Response::builder()
+            .status(status)
+            .body(BoxBody::default())
+            .unwrap()
+    }
+
+    #[derive(Deserialize)]
+    struct SignatureQuery {
+        exp: i64,
+        sig: String,
+    }
+
+    /// Return signed path if request is `GET /file/*path` with valid signature
+    pub(super) fn check_signature<B>(request: &Request<B>) -> Option<String> {
+        if request.method() != Method::GET && request.method() != Method::HEAD {
+            return None;
+        }
+        let signer = request.extensions().get::<Option<UrlSigner>>()?.as_ref()?;
+        let requested = request.uri().path().strip_prefix("/file/")?;
+        let requested = normalize_path(percent_decode_str(requested).decode_utf8().ok()?.as_ref());
+        let Query(query) = Query::<SignatureQuery>::try_from_uri(request.uri()).ok()?;
+        // Revoked or pruned link is rejected even if signature is valid
+        let links = request.extensions().get::<FileEventHelper>()?.links();
+        let path = links.path_of(&query.sig)?;
+        // URL of link minted by token with root is relative to that root
+        let matched = path == requested
+            || requested
+                .strip_prefix('.')
+                .is_some_and(|rest| rest.starts_with('/') && path.ends_with(rest));
+        (matched && signer.verify(&path, query.exp, &query.sig, unix_now())).then_some(path)
+    }
+
+    /// `/file/*path` of signed path, query of request is kept
+    fn signed_uri(path: &str, uri: &Uri) -> Option<Uri> {
+        format!(
+            "/file/{}?{}",
+            utf8_percent_encode(path.trim_start_matches("./"), URL_PATH),
+            uri.query().unwrap_or_default()
+        )
+        .parse()
+        .ok()
+    }
+
+    /// Return public paths if request has no token and route is download or query
+    fn check_public<B>(request: &Request<B>) -> Option<PathMatcher> {
+        if request.headers().contains_key("Authorization") {
+            return None;
+        }
+        let matcher = request
+            .extensions()
+            .get::<Option<PathMatcher>>()?
+            .as_ref()?;
+        let path = request.uri().path();
+        let public_route = match *request.method() {
+            Method::GET | Method::HEAD => {
+                path.starts_with("/file/") || path.starts_with("/file-info/") || path == "/query"
+            }
+            Method::POST => path == "/query",
+            _ => false,
+        };
+        public_route.then(|| matcher.clone())
+    }
+
+    pub(super) async fn check_auth<B>(
+        request: &Request<B>,
+        pool: &Arc<RwPoolType>,
+    ) -> Option<Arc<AuthEntry>> {
+        let client_map = pool.read().await;
+        if let Some(bearer) = request.headers().get("Authorization") {
+            let bearer = bearer
+                .to_str()
+                .inspect_err(|e| warn!("Unable decode authorization header: {:?}", e))
+                .ok()?;
+            if !bearer.starts_with("bearer ") {
+                return None;
+            }
+            let (_, bearer) = bearer.split_once("bearer ").unwrap();
+            let result = client_map.get(bearer)?;
+            return Some(result.clone());
+        }
+        None
+    }
+}
+
+mod unix_socket {
+    use axum::Router;
+    use hyper::server::conn::Http;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::net::UnixListener;
+    use tokio::task::JoinSet;
+    use tokio_util::sync::CancellationToken;
+    use tracing::{debug, warn};
+
+    /// Shutdown of Unix socket server, like `axum_server::Handle` of TCP one
+    #[derive(Clone, Debug, Default)]
+    pub struct UnixHandle {
+        connections: Arc<AtomicUsize>,
+        shutdown: CancellationToken,
+        /// Connections still open are aborted once it elapsed, wait forever if `None`
+        timeout: Arc<Mutex<Option<Duration>>>,
+    }
+
+    impl UnixHandle {
+        pub fn connection_count(&self) -> usize {
+            self.connections.load(Ordering::Relaxed)
+        }
+
+        pub fn graceful_shutdown(&self, timeout: Option<Duration>) {
+            *self.timeout.lock().unwrap() = timeout;
+            self.shutdown.cancel();
+        }
+    }
+
+    /// Open connection, counted until it is dropped or aborted
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Counted {
+        fn new(connections: &Arc<AtomicUsize>) -> Self {
+            connections.fetch_add(1, Ordering::Relaxed);
+            Self(connections.clone())
+        }
+    }
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Handle of web server listening on TCP or on Unix socket passed by systemd
+    #[derive(Clone, Debug)]
+    pub enum ServerHandle {
+        Tcp(axum_server::Handle),
+        Unix(UnixHandle),
+    }
+
+    impl ServerHandle {
+        pub fn connection_count(&self) -> usize {
+            match self {
+                Self::Tcp(handle) => handle.connection_count(),
+                Self::Unix(handle) => handle.connection_count(),
+            }
+        }
+
+        pub fn graceful_shutdown(&self, timeout: Option<Duration>) {
+            match self {
+                Self::Tcp(handle) => handle.graceful_shutdown(timeout),
+                Self::Unix(handle) => handle.graceful_shutdown(timeout),
+            }
+        }
+    }
+
+    /// Serve `router` on Unix socket, axum_server only listens on TCP. Peer has no IP
+    /// address, so client address is only taken from forwarding headers
+    pub async fn serve_unix(
+        listener: std::os::unix::net::UnixListener,
+        router: Router,
+        handle: UnixHandle,
+    ) -> std::io::Result<()> {
+        let listener = UnixListener::from_std(listener)?;
+        let mut connections = JoinSet::new();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let stream = match accepted {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            warn!("Unable to accept connection: {:?}", e);
+                            continue;
+                        }
+                    };
+                    let counted = Counted::new(&handle.connections);
+                    let router = router.clone();
+                    let shutdown = handle.shutdown.clone();
+                    connections.spawn(async move {
+                        let _counted = counted;
+                        let connection = Http::new()
+                            .serve_connection(stream, router)
+                            .with_upgrades();
+                        tokio::pin!(connection);
+                        let result = tokio::select! {
+                            result = &mut connection => result,
+                            _ = shutdown.cancelled() => {
+                                connection.as_mut().graceful_shutdown();
+                                connection.await
+                            }
+                        };
+                        if let Err(e) = result {
+                            debug!("Connection closed: {:?}", e);
+                        }
+                    });
+                }
+                Some(_) = connections.join_next() => {}
+                _ = handle.shutdown.cancelled() => break,
+            }
+        }
+        drop(listener);
+        let drain = async { while connections.join_next().await.is_some() {} };
+        let timeout = *handle.timeout.lock().unwrap();
+        match timeout {
+            Some(timeout) => {
+                if tokio::time::timeout(timeout, drain).await.is_err() {
+                    connections.abort_all();
+                }
+            }
+            None => drain.await,
+        }
+        Ok(())
+    }
+}
+
+use std::sync::OnceLock;
+pub use v1 as current;
+
+pub const DEFAULT_WAIT_TIME: u64 = 3;
+pub const DEFAULT_WAIT_TIME_STR: &str = "3";
+pub static WAIT_TIME: OnceLock<u64> = OnceLock::new();
+pub use cluster::HashRing;
+pub use concurrency::ConcurrencyLimiter;
+pub use current::router_start;
+pub use services::Services;
+pub use shedding::LoadShedder;
+pub use tenant::join_root;
+pub use types::WebResponse;
+
+#[cfg(test)]
+mod test {
+    extern crate test;
+
+    use crate::configure::current::{
+        AuthEntry, Compression, Deletion, DigestOption, Features, Firewall, PathLimits,
+        TieringOption,
+    };
+    use crate::configure::{ExcludeSet, PathMatcher, Permissions, RwPoolType};
+    use crate::crypto::UrlSigner;
+    use crate::file::unix_now;
+    use crate::file::{FileEventHelper, Remover, Transfers};
+    use crate::server::auth::{check_auth, check_signature};
+    use crate::server::client_ip::{forwarded_chain, parse_node, resolve};
+    use crate::server::cluster::{hop_target, is_redirected, route_to_owner, HashRing};
+    use crate::server::compression::{find_sidecar, Compressible};
+    use crate::server::conditional::{
+        encoded_tag, entity_tag, is_not_modified, requested_range, ByteRange,
+    };
+    use crate::server::current::{
+        archive_name, build_capabilities, create_upload, delete_file, duplicates, head_file,
+        make_dir, manifest, move_path, page_files, recall_tiered, retain_authorized, search,
+        signed_url, upload_chunk,
+    };
+    use crate::server::digest::{base64, fresh_refused, insert_digests, is_current, wants_digest};
+    use crate::server::tenant::{strip_root, translate_root, translate_uri};
+    use crate::server::unix_socket::{serve_unix, UnixHandle};
+    use crate::server::{Services, WebResponse};
+    use axum::body::Bytes;
+    use axum::extract::{Path, Query};
+    use axum::response::{IntoResponse, Response};
+    use axum::Extension;
+    use http::{HeaderMap, Request, StatusCode};
+    use hyper::Body;
+    use publib::file::{HashAlgo, HashOption};
+    use publib::mirror::verify_manifest;
+    use publib::normalize_path;
+    use publib::types::{FileEntry, FileMeta, LinkInfo, Manifest, OptionFile, Pagination, Scope};
+    use serde_json::{json, Value};
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::sync::Arc;
+    use test::Bencher;
+    use tower::ServiceExt;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// Count allocations per thread, since tests are run in parallel
+    struct CountingAllocator;
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS
+                .try_with(|count| count.set(count.get() + 1))
+                .ok();
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn allocations() -> usize {
+        ALLOCATIONS.with(Cell::get)
+    }
+
+    /// Allocations of resolving token and propagating its scope to handler
+    fn authorize_allocations(prefixes: usize) -> usize {
+        let token = "token".to_string();
+        let paths = (0..prefixes)
+            .map(|i| format!("./dir{}", i))
+            .collect::<Vec<_>>();
+        let entry = AuthEntry::new(token.clone(), paths, false, None, None).unwrap();
+        let pool = Arc::new(RwPoolType::new(
+            [(token, Arc::new(entry))].into_iter().collect(),
+        ));
+        let request = http::Request::builder()
+            .header("Authorization", "bearer token")
+            .body(())
+            .unwrap();
+        futures::executor::block_on(async {
+            let before = allocations();
+            let entry = check_auth(&request, &pool).await.unwrap();
+            let matcher = entry.matcher().clone();
+            let permissions = entry.permissions();
+            let bases = matcher.bases().clone();
+            std::hint::black_box((matcher, permissions, bases));
+            allocations() - before
+        })
+    }
+
+    #[test]
+    fn test_authorize_allocations() {
+        assert_eq!(authorize_allocations(1), 0);
+        assert_eq!(authorize_allocations(1000), 0);
+    }
+
+    /// Result of `/query` with `count` indexed files
+    fn query_result(count: usize) -> Vec<OptionFile> {
+        (0..count)
+            .map(|i| {
+                OptionFile::new(
+                    format!("./dir{}/file{}.txt", i % 100, i),
+                    Some(FileMeta::new(
+                        format!("sha256:{:064x}", i),
+                        1_690_000_000 + i as i64,
+                        i as i64 * 1024,
+                        false,
+                    )),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_page_after_pattern() {
+        let paths = |files: Vec<OptionFile>| {
+            files
+                .iter()
+                .map(|file| file.path().to_string())
+                .collect::<Vec<_>>()
+        };
+        // Page of pattern token is counted in matched files only
+        let matcher = PathMatcher::new(&["./dir7/*.txt"]).unwrap();
+        let files = retain_authorized(query_result(400), &matcher);
+        assert_eq!(
+            paths(page_files(files, Some(Pagination::new(Some(2), Some(1))))),
+            ["./dir7/file107.txt", "./dir7/file207.txt"]
+        );
+        let files = page_files(query_result(10), Some(Pagination::default()));
+        assert_eq!(files.len(), 10);
+        let files = page_files(query_result(400), Some(Pagination::new(None, Some(390))));
+        assert_eq!(paths(files)[0], "./dir90/file390.txt");
+        assert_eq!(page_files(query_result(400), None).len(), 400);
+    }
+
+    #[tokio::test]
+    async fn invalid_page_is_rejected() {
+        let sender = FileEventHelper::detached();
+        for page in [
+            Pagination::new(Some(0), None),
+            Pagination::new(Some(Pagination::MAX_LIMIT + 1), None),
+            Pagination::new(None, Some(-1)),
+        ] {
+            let response = duplicates(
+                Extension(sender.clone()),
+                Query(page),
+                Extension(Permissions::new(&[Scope::List])),
+                authorized_request(),
+            )
+            .await;
+            assert_eq!(response.into_response().status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    fn response_body(response: WebResponse) -> hyper::body::Bytes {
+        futures::executor::block_on(hyper::body::to_bytes(response.into_response().into_body()))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_response_is_json_object() {
+        let files = query_result(2);
+        let body = response_body(WebResponse::ok_serialize(&files));
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["status"], 200);
+        assert_eq!(value["result"], serde_json::to_value(&files).unwrap());
+    }
+
+    /// Directory under `./target` for files created by handler test
+    fn test_dir(name: &str) -> String {
+        format!("./target/handler-test-{}-{}", name, std::process::id())
+    }
+
+    /// Request of token authorized to `./target`
+    fn authorized_request() -> Request<Body> {
+        let mut request = Request::builder().body(Body::empty()).unwrap();
+        request
+            .extensions_mut()
+            .insert(PathMatcher::prefix(vec!["./target".to_string()]));
+        request
+    }
+
+    fn no_exclude() -> Extension<Arc<ExcludeSet>> {
+        Extension(Arc::new(ExcludeSet::new::<&str>(&[], false).unwrap()))
+    }
+
+    async fn mkdir(path: &str, sender: &FileEventHelper) -> StatusCode {
+        make_dir(
+            Path(path.to_string()),
+            no_exclude(),
+            Extension(sender.clone()),
+            Extension(Permissions::new(&[Scope::Write])),
+            Extension(PathLimits::default()),
+            authorized_request(),
+        )
+        .await
+        .into_response()
+        .status()
+    }
+
+    #[tokio::test]
+    async fn make_dir_creates_new_directory() {
+        let root = test_dir("mkdir");
+        let path = format!("{}/a/b", root);
+        let sender = FileEventHelper::detached();
+        assert_eq!(mkdir(&path, &sender).await, StatusCode::CREATED);
+        assert!(std::path::Path::new(&path).is_dir());
+        assert_eq!(mkdir(&path, &sender).await, StatusCode::CONFLICT);
+        assert_eq!(
+            mkdir(&format!("{}/../../escape", root), &sender).await,
+            StatusCode::FORBIDDEN
+        );
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn token_without_scopes_can_not_delete() {
+        let root = test_dir("legacy-token");
+        std::fs::create_dir_all(&root).unwrap();
+        let path = format!("{}/a.txt", root);
+        std::fs::write(&path, b"a").unwrap();
+        // Configured before scopes existed
+        let legacy: AuthEntry = toml::from_str(
+            r#"
+            token = "legacy"
+            path = ["./target"]
+            "#,
+        )
+        .unwrap();
+        let sender = FileEventHelper::detached();
+        let response = delete_file(
+            Path(path.clone()),
+            no_exclude(),
+            Extension(sender.clone()),
+            Extension(Remover::new(
+                &Deletion::default(),
+                Transfers::default(),
+                sender.clone(),
+            )),
+            Extension(legacy.permissions()),
+            authorized_request(),
+        )
+        .await;
+        assert_eq!(response.into_response().status(), StatusCode::FORBIDDEN);
+        assert!(std::path::Path::new(&path).exists());
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn manifest_is_signed() {
+        let file = FileEntry::new("./target/a".to_string(), "xxh3:1".to_string(), 0, 1, false);
+        let dir = FileEntry::new("./target/dir".to_string(), String::new(), 0, 0, true);
+        let sender = FileEventHelper::serving(vec![file, dir]);
+        let request = || {
+            let mut request = Request::builder().body(Body::empty()).unwrap();
+            request.extensions_mut().insert(PathMatcher::prefix(vec![
+                "./target/a".to_string(),
+                "./target/dir".to_string(),
+            ]));
+            request
+        };
+        let signer = UrlSigner::new(b"secret", 60);
+        let permissions = Permissions::new(&[Scope::List]);
+
+        let response = manifest(
+            Extension(sender.clone()),
+            Extension(None),
+            Extension(None),
+            Extension(permissions),
+            request(),
+        )
+        .await;
+        assert_eq!(response.into_response().status(), StatusCode::NOT_FOUND);
+
+        let response = manifest(
+            Extension(sender),
+            Extension(Some(signer)),
+            Extension(None),
+            Extension(permissions),
+            request(),
+        )
+        .await;
+        let body: Value = serde_json::from_slice(&response_body(response)).unwrap();
+        let manifest: Manifest = serde_json::from_value(body["result"].clone()).unwrap();
+        assert!(verify_manifest(b"secret", &manifest));
+        // Directory is not mirrored
+        let paths = manifest
+            .files()
+            .iter()
+            .map(OptionFile::path)
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["./target/a"]);
+    }
+
+    /// Page of search with `cursor`, status and body
+    async fn search_page(
+        sender: &FileEventHelper,
+        query: &str,
+        fields: &str,
+    ) -> (StatusCode, Value) {
+        let uri = format!("/search?q=txt&{}", query);
+        let uri = uri.parse::<http::Uri>().unwrap();
+        let fields_uri = format!("/search?{}", fields).parse::<http::Uri>().unwrap();
+        let response = search(
+            Extension(sender.clone()),
+            Query::try_from_uri(&uri).unwrap(),
+            Query::try_from_uri(&fields_uri).unwrap(),
+            Extension(Permissions::new(&[Scope::List])),
+            authorized_request(),
+        )
+        .await;
+        let response = response.into_response();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        (status, body["result"].clone())
+    }
+
+    #[tokio::test]
+    async fn search_pages_follow_cursor() {
+        let entries = ["a", "b", "c"]
+            .map(|name| {
+                FileEntry::new(format!("./target/{}.txt", name), String::new(), 0, 1, false)
+            })
+            .to_vec();
+        let sender = FileEventHelper::serving(entries);
+
+        let (status, page) = search_page(&sender, "limit=2", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(page["items"].as_array().unwrap().len(), 2);
+        assert_eq!(page["next"], json!("./target/b.txt"));
+
+        let (_, page) = search_page(&sender, "limit=2&cursor=./target/b.txt", "fields=path").await;
+        assert_eq!(
+            page,
+            json!({ "items": [{ "path": "./target/c.txt" }], "next": null })
+        );
+
+        for limit in [0, Pagination::MAX_LIMIT + 1] {
+            let (status, _) = search_page(&sender, &format!("limit={}", limit), "").await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+    }
+
+    async fn move_to(from: &str, to: &str, sender: &FileEventHelper) -> StatusCode {
+        let remover = Remover::new(&Deletion::default(), Transfers::default(), sender.clone());
+        let body = json!({ "from": from, "to": to });
+        move_path(
+            no_exclude(),
+            Extension(sender.clone()),
+            Extension(remover),
+            Extension(PathMatcher::prefix(vec!["./target".to_string()])),
+            Extension(Permissions::new(&[Scope::Write])),
+            Bytes::from(body.to_string()),
+        )
+        .await
+        .into_response()
+        .status()
+    }
+
+    #[tokio::test]
+    async fn move_path_to_new_destination() {
+        let root = test_dir("move");
+        std::fs::create_dir_all(&root).unwrap();
+        let (from, to) = (format!("{}/a.txt", root), format!("{}/dir/b.txt", root));
+        std::fs::write(&from, b"a").unwrap();
+        std::fs::write(format!("{}/c.txt", root), b"c").unwrap();
+        let sender = FileEventHelper::detached();
+
+        assert_eq!(move_to(&from, &to, &sender).await, StatusCode::OK);
+        assert!(!std::path::Path::new(&from).exists());
+        assert_eq!(std::fs::read(&to).unwrap(), b"a");
+        assert_eq!(
+            move_to(&to, &format!("{}/c.txt", root), &sender).await,
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            move_to(&to, &format!("{}/../../b.txt", root), &sender).await,
+            StatusCode::FORBIDDEN
+        );
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    /// Tiered file of `root`, only its cold copy exists
+    fn tiered_file(root: &str, name: &str, sender: &FileEventHelper) -> String {
+        let cold = std::path::Path::new(root).join("cold");
+        std::fs::create_dir_all(&cold).unwrap();
+        std::fs::write(cold.join(name), name).unwrap();
+        let path = format!("{}/{}", root, name);
+        sender.tiered().insert(path.clone(), cold.join(name));
+        path
+    }
+
+    async fn head(path: &str, sender: &FileEventHelper, if_none_match: Option<&str>) -> Response {
+        let mut request = authorized_request();
+        if let Some(etag) = if_none_match {
+            request
+                .headers_mut()
+                .insert(http::header::IF_NONE_MATCH, etag.parse().unwrap());
+        }
+        match head_file(
+            Path(path.to_string()),
+            no_exclude(),
+            Extension(sender.clone()),
+            Extension(None),
+            Extension(Permissions::new(&[Scope::Read])),
+            request,
+        )
+        .await
+        {
+            Ok(response) => response,
+            Err(response) => response.into_response(),
+        }
+    }
+
+    #[tokio::test]
+    async fn head_file_describes_file() {
+        let root = test_dir("head");
+        std::fs::create_dir_all(&root).unwrap();
+        let path = format!("{}/file.txt", root);
+        std::fs::write(&path, b"content").unwrap();
+
+        // Not indexed file is described by file system metadata
+        let sender = FileEventHelper::detached();
+        let response = head(&path, &sender, None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[http::header::CONTENT_LENGTH], "7");
+        assert_eq!(response.headers()[http::header::CONTENT_TYPE], "text/plain");
+        assert!(!response.headers().contains_key("X-File-Hash"));
+        assert!(!response.headers().contains_key(http::header::ETAG));
+
+        let hash = "sha256:666f6f";
+        let entry = FileEntry::from_metadata(
+            normalize_path(&path),
+            std::fs::metadata(&path).unwrap(),
+            Some(hash),
+        );
+        let sender = FileEventHelper::serving(vec![entry.clone()]);
+        let response = head(&path, &sender, None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["X-File-Hash"], hash);
+        assert_eq!(response.headers()["Repr-Digest"], "sha-256=:Zm9v:");
+        let etag = response.headers()[http::header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let response = head(&path, &sender, Some(&etag)).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        // Changed after indexed, stored digest is stale
+        std::fs::write(&path, b"changed content").unwrap();
+        let response = head(&path, &sender, None).await;
+        assert!(!response.headers().contains_key("Repr-Digest"));
+
+        assert_eq!(
+            head(&root, &sender, None).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+        // Missing path can't be resolved, rejected by penetration check like GET
+        assert_eq!(
+            head(&format!("{}/missing.txt", root), &sender, None)
+                .await
+                .status(),
+            StatusCode::FORBIDDEN
+        );
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn head_file_describes_tiered_stub() {
+        let root = test_dir("head-tiered");
+        let path = normalize_path(format!("{}/cold.txt", root));
+        let entry = FileEntry::new(path.clone(), "sha256:666f6f".to_string(), 0, 8, false);
+        let sender = FileEventHelper::serving(vec![entry]);
+        tiered_file(&root, "cold.txt", &sender);
+        assert!(!std::path::Path::new(&path).exists());
+
+        let response = head(&path, &sender, None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[http::header::CONTENT_LENGTH], "8");
+        assert_eq!(response.headers()["X-File-Hash"], "sha256:666f6f");
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn recall_tiered_file() {
+        let root = test_dir("recall");
+        let sender = FileEventHelper::detached();
+        let exclude = ExcludeSet::new::<&str>(&[], false).unwrap();
+
+        let path = tiered_file(&root, "retry.txt", &sender);
+        let tiering: TieringOption = toml::from_str("retry_after = 30").unwrap();
+        let response = recall_tiered(&sender, &path, &exclude, &tiering)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[http::header::RETRY_AFTER], "30");
+        // Copied back in background, next request is served
+        for _ in 0..100 {
+            if std::path::Path::new(&path).exists() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(std::fs::read(&path).unwrap(), b"retry.txt");
+        assert!(recall_tiered(&sender, &path, &exclude, &tiering)
+            .await
+            .unwrap()
+            .is_none());
+
+        let path = tiered_file(&root, "blocking.txt", &sender);
+        let tiering: TieringOption = toml::from_str(r#"recall = "blocking""#).unwrap();
+        assert!(recall_tiered(&sender, &path, &exclude, &tiering)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(std::fs::read(&path).unwrap(), b"blocking.txt");
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    fn token_entry() -> Option<Extension<Arc<AuthEntry>>> {
+        let entry = AuthEntry::new(
+            "token".to_string(),
+            vec!["./target".to_string()],
+            false,
+            None,
+            None,
+        )
+        .unwrap();
+        Some(Extension(Arc::new(entry)))
+    }
+
+    fn split_response(response: WebResponse) -> (StatusCode, Value) {
+        let status = response.clone().into_response().status();
+        let result = response
+            .result()
+            .map(|result| serde_json::from_str(result.get()).unwrap())
+            .unwrap_or_default();
+        (status, result)
+    }
+
+    fn upload_services(sender: &FileEventHelper, option: HashOption) -> Arc<Services> {
+        Arc::new(Services::new(
+            sender.clone(),
+            no_exclude().0,
+            option,
+            &Deletion::default(),
+        ))
+    }
+
+    async fn send_chunk(
+        id: u64,
+        offset: u64,
+        chunk: &'static [u8],
+        services: &Arc<Services>,
+    ) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .header(
+                http::header::CONTENT_RANGE,
+                format!("bytes {}-{}/*", offset, offset + chunk.len() as u64 - 1),
+            )
+            .header(http::header::CONTENT_LENGTH, chunk.len())
+            .body(Body::from(chunk))
+            .unwrap();
+        let response = upload_chunk(
+            Path(id),
+            Extension(services.clone()),
+            Extension(Permissions::new(&[Scope::Write])),
+            token_entry(),
+            request,
+        )
+        .await;
+        split_response(response)
+    }
+
+    #[tokio::test]
+    async fn resumable_upload_renames_verified_file() {
+        let root = test_dir("upload");
+        let path = format!("{}/file.bin", root);
+        let option = HashOption::default();
+        let mut digest = option.algo().hasher();
+        digest.update(b"hello resumable upload");
+
+        let sender = FileEventHelper::detached();
+        let services = upload_services(&sender, option);
+        let body = json!({ "path": path, "size": 22, "hash": digest.digest() });
+        let response = create_upload(
+            Extension(services.clone()),
+            Extension(PathMatcher::prefix(vec!["./target".to_string()])),
+            Extension(Permissions::new(&[Scope::Write])),
+            token_entry(),
+            Bytes::from(body.to_string()),
+        )
+        .await;
+        let (status, session) = split_response(response);
+        assert_eq!(status, StatusCode::CREATED);
+        let id = session["id"].as_u64().unwrap();
+
+        let (status, session) = send_chunk(id, 0, b"hello ", &services).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(session["offset"], 6);
+        // Chunk resent after lost response is rejected with received size
+        let (status, result) = send_chunk(id, 0, b"hello ", &services).await;
+        assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(result["size"], 6);
+        let (status, file) = send_chunk(id, 6, b"resumable upload", &services).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(file["meta"]["hash"], digest.digest());
+
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            b"hello resumable upload".to_vec()
+        );
+        assert!(!std::path::Path::new(&format!("{}.part", path)).exists());
+        assert!(sender.upload_sessions().get(id).is_none());
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn upload_truncates_stale_partial() {
+        let root = test_dir("upload-stale");
+        let path = format!("{}/file.bin", root);
+        std::fs::create_dir_all(&root).unwrap();
+        // Left behind by session lost on restart
+        std::fs::write(format!("{}.part", path), b"stale bytes").unwrap();
+        let option = HashOption::default();
+        let mut digest = option.algo().hasher();
+        digest.update(b"fresh");
+
+        let sender = FileEventHelper::detached();
+        let services = upload_services(&sender, option);
+        let body = json!({ "path": path, "size": 5, "hash": digest.digest() });
+        let response = create_upload(
+            Extension(services.clone()),
+            Extension(PathMatcher::prefix(vec!["./target".to_string()])),
+            Extension(Permissions::new(&[Scope::Write])),
+            token_entry(),
+            Bytes::from(body.to_string()),
+        )
+        .await;
+        let (status, session) = split_response(response);
+        assert_eq!(status, StatusCode::CREATED);
+        let id = session["id"].as_u64().unwrap();
+
+        let (status, file) = send_chunk(id, 0, b"fresh", &services).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(file["meta"]["hash"], digest.digest());
+        assert_eq!(std::fs::read(&path).unwrap(), b"fresh".to_vec());
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    fn range(pairs: &[(&'static str, &str)], entry: Option<&FileEntry>, size: u64) -> ByteRange {
+        requested_range(&headers(pairs), entry, size)
+    }
+
+    #[test]
+    fn test_requested_range() {
+        assert_eq!(range(&[], None, 100), ByteRange::Full);
+        assert_eq!(
+            range(&[("range", "bytes=0-9")], None, 100),
+            ByteRange::Partial(0, 9)
+        );
+        assert_eq!(
+            range(&[("range", "bytes=50-500")], None, 100),
+            ByteRange::Partial(50, 99)
+        );
+        // Open-ended and suffix range
+        assert_eq!(
+            range(&[("range", "bytes=90-")], None, 100),
+            ByteRange::Partial(90, 99)
+        );
+        assert_eq!(
+            range(&[("range", "bytes=-10")], None, 100),
+            ByteRange::Partial(90, 99)
+        );
+        assert_eq!(
+            range(&[("range", "bytes=-200")], None, 100),
+            ByteRange::Partial(0, 99)
+        );
+        // Start beyond end of file
+        assert_eq!(
+            range(&[("range", "bytes=100-")], None, 100),
+            ByteRange::Unsatisfiable
+        );
+        assert_eq!(
+            range(&[("range", "bytes=150-160")], None, 100),
+            ByteRange::Unsatisfiable
+        );
+        // Empty file has no satisfiable range
+        assert_eq!(
+            range(&[("range", "bytes=0-")], None, 0),
+            ByteRange::Unsatisfiable
+        );
+        assert_eq!(
+            range(&[("range", "bytes=-5")], None, 0),
+            ByteRange::Unsatisfiable
+        );
+        // Invalid and multiple ranges are served as full file
+        for spec in [
+            "bytes=9-0",
+            "bytes=-0",
+            "bytes=a-b",
+            "bytes=0-1,5-6",
+            "items=0-9",
+        ] {
+            assert_eq!(range(&[("range", spec)], None, 100), ByteRange::Full);
+        }
+    }
+
+    #[test]
+    fn test_base64() {
+        for (input, expected) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(base64(input.as_bytes()), expected);
+        }
+        assert_eq!(base64(&[0xfb, 0xff]), "+/8=");
+    }
+
+    #[test]
+    fn test_fresh_digest_is_opt_in() {
+        let size = 1024 * 1024 * 1024;
+        assert!(fresh_refused(&DigestOption::default(), 1).is_some());
+
+        let option: DigestOption = toml::from_str("fresh_blake3 = true").unwrap();
+        assert_eq!(fresh_refused(&option, 1), None);
+        assert!(fresh_refused(&option, size).is_some());
+
+        let option: DigestOption =
+            toml::from_str("fresh_blake3 = true\nfresh_max_size = 0").unwrap();
+        assert_eq!(fresh_refused(&option, size), None);
+        let option: DigestOption =
+            toml::from_str("fresh_blake3 = true\nfresh_max_size = 10").unwrap();
+        assert_eq!(fresh_refused(&option, 10), None);
+        assert!(fresh_refused(&option, 11).is_some());
+    }
+
+    #[test]
+    fn test_archive_name() {
+        let root = std::path::Path::new(".");
+        assert_eq!(archive_name("./src", root), "src");
+        assert_eq!(archive_name("src/", root), "src");
+        // Working directory is named after the directory it resolves to
+        let current = std::env::current_dir().unwrap();
+        let expected = current.file_name().unwrap().to_str().unwrap();
+        assert_eq!(archive_name(".", root), expected);
+        assert_eq!(archive_name("./", root), expected);
+        assert_eq!(archive_name(".", std::path::Path::new("/")), "archive");
+    }
+
+    #[test]
+    fn test_insert_digests() {
+        let mut headers = HeaderMap::new();
+        insert_digests(
+            &mut headers,
+            &["sha256:666f6f".to_string(), "unknown".to_string()],
+        );
+        assert_eq!(headers["Repr-Digest"], "sha-256=:Zm9v:");
+        assert_eq!(headers["Digest"], "SHA-256=Zm9v");
+
+        let mut headers = HeaderMap::new();
+        insert_digests(&mut headers, &["unknown".to_string()]);
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn test_wants_digest() {
+        let wants = |pairs: &[(&'static str, &str)], algo| wants_digest(&headers(pairs), algo);
+        assert!(wants(
+            &[("want-repr-digest", "sha-256=1")],
+            HashAlgo::Sha256
+        ));
+        assert!(wants(&[("want-digest", "SHA-256;q=0.5")], HashAlgo::Sha256));
+        assert!(wants(
+            &[("want-repr-digest", "sha-512=3, blake3=10")],
+            HashAlgo::Blake3
+        ));
+        assert!(!wants(
+            &[("want-repr-digest", "sha-256=0")],
+            HashAlgo::Sha256
+        ));
+        assert!(!wants(&[("want-digest", "sha-256;q=0")], HashAlgo::Sha256));
+        assert!(!wants(
+            &[("want-repr-digest", "sha-256=1")],
+            HashAlgo::Blake3
+        ));
+        assert!(!wants(&[], HashAlgo::Sha256));
+    }
+
+    #[test]
+    fn test_digest_is_current() {
+        let path = format!("./target/server-test-digest-{}", std::process::id());
+        std::fs::write(&path, b"content").unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        let entry = FileEntry::from_metadata(&path, metadata.clone(), Some("sha256:00"));
+        assert!(is_current(&entry, &metadata));
+
+        // Changed after indexed
+        std::fs::write(&path, b"changed content").unwrap();
+        assert!(!is_current(&entry, &std::fs::metadata(&path).unwrap()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_if_range() {
+        let entry = FileEntry::new("./a".to_string(), "xxh3:01", 1_000_000, 100, false);
+        let date = |secs: u64| {
+            httpdate::fmt_http_date(std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs))
+        };
+        let etag = entity_tag(&entry);
+        let partial = ByteRange::Partial(0, 9);
+        assert_eq!(
+            range(
+                &[("range", "bytes=0-9"), ("if-range", etag.as_str())],
+                Some(&entry),
+                100
+            ),
+            partial
+        );
+        assert_eq!(
+            range(
+                &[("range", "bytes=0-9"), ("if-range", "\"xxh3:02\"")],
+                Some(&entry),
+                100
+            ),
+            ByteRange::Full
+        );
+        assert_eq!(
+            range(
+                &[
+                    ("range", "bytes=0-9"),
+                    ("if-range", date(1_000_000).as_str())
+                ],
+                Some(&entry),
+                100
+            ),
+            partial
+        );
+        // File modified after date of client copy
+        assert_eq!(
+            range(
+                &[("range", "bytes=0-9"), ("if-range", date(999_999).as_str())],
+                Some(&entry),
+                100
+            ),
+            ByteRange::Full
+        );
+        // Not indexed file can't be validated
+        assert_eq!(
+            range(
+                &[("range", "bytes=0-9"), ("if-range", etag.as_str())],
+                None,
+                100
+            ),
+            ByteRange::Full
+        );
+    }
+
+    #[test]
+    fn test_capabilities_follow_configure() {
+        let features: Features = serde_json::from_value(json!({ "uploads": true })).unwrap();
+        let compression: Compression = serde_json::from_value(json!({ "zstd": true })).unwrap();
+        let capabilities = build_capabilities(
+            &HashOption::default().with_chunk_size(1024),
+            &None,
+            &features,
+            &None,
+            &None,
+            &compression,
+        );
+        assert_eq!(capabilities["uploads"], json!(true));
+        assert_eq!(capabilities["admin"], json!(false));
+        assert_eq!(capabilities["compression"], json!(["zstd"]));
+        assert_eq!(capabilities["chunking"], json!(true));
+
+        let features: Features = serde_json::from_value(json!({})).unwrap();
+        let capabilities = build_capabilities(
+            &HashOption::default(),
+            &None,
+            &features,
+            &None,
+            &None,
+            &Compression::default(),
+        );
+        assert_eq!(capabilities["uploads"], json!(false));
+        assert_eq!(capabilities["resumable_upload"], json!(false));
+        assert_eq!(capabilities["compression"], json!([]));
+        assert_eq!(capabilities["chunking"], json!(false));
+    }
+
+    #[test]
+    fn test_partial_content_is_not_compressed() {
+        use tower_http::compression::predicate::Predicate;
+        let response = |status: StatusCode, content_range: Option<&str>| {
+            let mut builder = http::Response::builder()
+                .status(status)
+                .header("content-type", "text/plain");
+            if let Some(content_range) = content_range {
+                builder = builder.header("content-range", content_range);
+            }
+            builder.body(Body::from("a".repeat(4096))).unwrap()
+        };
+        assert!(Compressible.should_compress(&response(StatusCode::OK, None)));
+        assert!(!Compressible.should_compress(&response(
+            StatusCode::PARTIAL_CONTENT,
+            Some("bytes 0-4095/8192")
+        )));
+        assert!(!Compressible.should_compress(&response(
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            Some("bytes */8192")
+        )));
+    }
+
+    #[tokio::test]
+    async fn sidecar_is_found_by_accepted_encoding() {
+        let root = test_dir("sidecar");
+        std::fs::create_dir_all(&root).unwrap();
+        let path = std::path::PathBuf::from(format!("{}/a.txt", root));
+        std::fs::write(&path, b"a").unwrap();
+        std::fs::write(format!("{}/a.txt.gz", root), b"gzip").unwrap();
+        std::fs::write(format!("{}/a.txt.zst", root), b"zstd").unwrap();
+
+        let found = |pairs: &'static [(&'static str, &'static str)]| {
+            let path = path.clone();
+            async move {
+                find_sidecar(&path, &headers(pairs))
+                    .await
+                    .map(|(_, encoding)| encoding)
+            }
+        };
+        assert_eq!(
+            found(&[("accept-encoding", "gzip, zstd")]).await,
+            Some("zstd")
+        );
+        assert_eq!(
+            found(&[("accept-encoding", "gzip, zstd;q=0")]).await,
+            Some("gzip")
+        );
+        assert_eq!(found(&[("accept-encoding", "br")]).await, None);
+        assert_eq!(found(&[]).await, None);
+
+        // Sidecar older than file is stale
+        for suffix in [".gz", ".zst"] {
+            std::fs::File::options()
+                .write(true)
+                .open(format!("{}/a.txt{}", root, suffix))
+                .unwrap()
+                .set_modified(std::time::UNIX_EPOCH)
+                .unwrap();
+        }
+        assert_eq!(found(&[("accept-encoding", "gzip, zstd")]).await, None);
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn sidecar_has_own_entity_tag() {
+        let entry = FileEntry::new("./a".to_string(), "xxh3:01", 1_000_000, 100, false);
+        let etag = entity_tag(&entry);
+        let zstd = encoded_tag(&entry, Some("zstd"));
+        assert_eq!(encoded_tag(&entry, None), etag);
+        assert_eq!(zstd, "\"xxh3:01-zstd\"");
+        let request = |tag: &str| headers(&[("if-none-match", tag)]);
+
+        assert!(is_not_modified(&request(&zstd), &entry, Some("zstd")));
+        assert!(!is_not_modified(&request(&etag), &entry, Some("zstd")));
+        assert!(!is_not_modified(&request(&zstd), &entry, None));
+        assert!(!is_not_modified(&request(&zstd), &entry, Some("gzip")));
+    }
+
+    #[tokio::test]
+    async fn unix_socket_is_served_until_shutdown() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let root = test_dir("unix");
+        std::fs::create_dir_all(&root).unwrap();
+        let path = format!("{}/web.sock", root);
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let router = axum::Router::new().route("/", axum::routing::get(|| async { "served" }));
+        let handle = UnixHandle::default();
+        let server = tokio::spawn(serve_unix(listener, router, handle.clone()));
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.ends_with("served"));
+
+        // Idle connection is closed by graceful shutdown, or aborted once grace period ends if
+        // it was accepted after shutdown started and hyper still waits for its first request
+        let _idle = tokio::net::UnixStream::connect(&path).await.unwrap();
+        handle.graceful_shutdown(Some(std::time::Duration::from_secs(1)));
+        tokio::time::timeout(std::time::Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(handle.connection_count(), 0);
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    fn translate(root: &str, uri: &str) -> Option<String> {
+        translate_uri(root, &uri.parse().unwrap()).map(|uri| uri.to_string())
+    }
+
+    #[test]
+    fn delete_dir_route_is_translated() {
+        assert_eq!(
+            translate("./tenant", "/dir/a/b").as_deref(),
+            Some("/dir/tenant/a/b")
+        );
+        assert_eq!(translate("./tenant", "/dir/../b"), None);
+    }
+
+    #[test]
+    fn test_strip_root() {
+        assert_eq!(strip_root("./tenant", "./tenant/a").as_deref(), Some("./a"));
+        assert_eq!(strip_root("./tenant", "./tenant").as_deref(), Some("."));
+        assert_eq!(strip_root("./tenant", "./tenant2/a"), None);
+        assert_eq!(strip_root("./tenant", "./other/a"), None);
+    }
+
+    /// Router of token with root `clients/acme`, `/file` echoes path handler reads as `real`
+    fn tenant_router() -> axum::Router {
+        let entry: AuthEntry = toml::from_str(
+            r#"
+            token = "tenant"
+            path = ["**"]
+            root = "clients/acme"
+            "#,
+        )
+        .unwrap();
+        let routes = axum::Router::new()
+            .route(
+                "/file/*path",
+                axum::routing::get(|Path(path): Path<String>| async move {
+                    let path = publib::normalize_path(path);
+                    WebResponse::ok(Some(json!({ "path": path, "real": path })))
+                }),
+            )
+            .route(
+                "/stat/*path",
+                axum::routing::get(|| async {
+                    WebResponse::ok(Some(json!({ "path": "./clients/other/a.txt" })))
+                }),
+            );
+        axum::Router::new()
+            .fallback_service(routes)
+            .layer(axum::middleware::from_fn(translate_root))
+            .layer(Extension(Arc::new(entry)))
+    }
+
+    async fn tenant_get(uri: &str) -> (StatusCode, Value) {
+        let response = tenant_router()
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn tenant_sees_paths_relative_to_root() {
+        let (status, body) = tenant_get("/file/report.pdf").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["result"]["real"], "./clients/acme/report.pdf");
+        assert_eq!(body["result"]["path"], "./report.pdf");
+        let (status, _) = tenant_get("/file/../other/a.txt").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn tenant_never_sees_path_outside_root() {
+        let (status, body) = tenant_get("/stat/a.txt").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!body.to_string().contains("other"));
+    }
+
+    #[tokio::test]
+    async fn tenant_link_is_relative_to_root() {
+        let signer = UrlSigner::new(b"secret", 3600);
+        let expires = unix_now() + 60;
+        let path = "./clients/acme/report.pdf";
+        let signature = signer.sign(path, expires);
+        let sender = FileEventHelper::detached();
+        sender.links().set(vec![LinkInfo::new(
+            1,
+            "tenant".to_string(),
+            path.to_string(),
+            signature.clone(),
+            expires,
+            0,
+        )]);
+        let check = |uri: String| {
+            let mut request = Request::get(uri).body(Body::empty()).unwrap();
+            request.extensions_mut().insert(Some(signer.clone()));
+            request.extensions_mut().insert(sender.clone());
+            check_signature(&request)
+        };
+        let query = format!("?exp={}&sig={}", expires, signature);
+        assert_eq!(
+            signed_url(&sender.links().list("tenant")[0], Some("clients/acme")),
+            format!("/file/report.pdf{}", query)
+        );
+        for uri in ["/file/report.pdf", "/file/clients/acme/report.pdf"] {
+            assert_eq!(check(format!("{}{}", uri, query)).as_deref(), Some(path));
+        }
+        assert_eq!(check(format!("/file/port.pdf{}", query)), None);
+        assert_eq!(check(format!("/file/other.pdf{}", query)), None);
+        assert_eq!(
+            check(format!(
+                "/file/report.pdf?exp={}&sig={}",
+                expires + 1,
+                signature
+            )),
+            None
+        );
+    }
+
+    fn ip(s: &str) -> Option<std::net::IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    const NODE_A: &str = "http://node-a";
+    const NODE_B: &str = "http://node-b";
+
+    /// Router of `NODE_A`, local download answers `local`
+    fn cluster_router() -> axum::Router {
+        let ring = HashRing::new(
+            NODE_A.to_string(),
+            vec![NODE_A.to_string(), NODE_B.to_string()],
+            16,
+        );
+        axum::Router::new()
+            .route("/file/*path", axum::routing::get(|| async { "local" }))
+            .layer(axum::middleware::from_fn(route_to_owner))
+            .layer(Extension(Some(ring)))
+    }
+
+    /// Path owned by `NODE_B`
+    fn remote_path() -> String {
+        let ring = HashRing::new(
+            NODE_A.to_string(),
+            vec![NODE_A.to_string(), NODE_B.to_string()],
+            16,
+        );
+        (0..)
+            .map(|i| format!("f{}.txt", i))
+            .find(|path| ring.owner(&normalize_path(path)) == Some(NODE_B))
+            .unwrap()
+    }
+
+    async fn cluster_get(request: Request<Body>) -> (StatusCode, Option<String>) {
+        let response = cluster_router().oneshot(request).await.unwrap();
+        let location = response
+            .headers()
+            .get(http::header::LOCATION)
+            .map(|value| value.to_str().unwrap().to_string());
+        (response.status(), location)
+    }
+
+    #[tokio::test]
+    async fn download_is_redirected_to_owner() {
+        let path = remote_path();
+        let request = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+        let (status, location) = cluster_get(request(&format!("/file/{}?exp=1&sig=s", path))).await;
+        assert_eq!(status, StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(
+            location.unwrap(),
+            format!("{}/file/{}?exp=1&sig=s&hop=1", NODE_B, path)
+        );
+
+        // Redirected once already, nodes may disagree on ring
+        let (status, _) = cluster_get(request(&format!("/file/{}?hop=1", path))).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Token in header would be dropped by client following redirect
+        let mut with_token = request(&format!("/file/{}", path));
+        with_token.extensions_mut().insert(token_entry().unwrap().0);
+        let (status, _) = cluster_get(with_token).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Signed request is redirected by URI of client, not rewritten one
+        let mut signed = request(&format!("/file/{}", path));
+        signed.extensions_mut().insert(axum::extract::OriginalUri(
+            "/file/b.txt?sig=s".parse().unwrap(),
+        ));
+        let (_, location) = cluster_get(signed).await;
+        assert_eq!(
+            location.unwrap(),
+            format!("{}/file/b.txt?sig=s&hop=1", NODE_B)
+        );
+    }
+
+    #[test]
+    fn test_is_redirected() {
+        let redirected = |uri: &str| is_redirected(&uri.parse().unwrap());
+        assert!(redirected("/file/a?hop=1"));
+        assert!(redirected("/file/a?sig=s&hop"));
+        assert!(!redirected("/file/a?shop=1"));
+        assert!(!redirected("/file/a"));
+        assert_eq!(hop_target(&"/file/a".parse().unwrap()), "/file/a?hop=1");
+    }
+
+    #[test]
+    fn test_parse_node() {
+        assert_eq!(parse_node("192.0.2.1"), ip("192.0.2.1"));
+        assert_eq!(parse_node(" 192.0.2.1:8080 "), ip("192.0.2.1"));
+        assert_eq!(parse_node("\"192.0.2.1:80\""), ip("192.0.2.1"));
+        assert_eq!(parse_node("2001:db8::1"), ip("2001:db8::1"));
+        assert_eq!(parse_node("\"[2001:db8::1]:8080\""), ip("2001:db8::1"));
+        assert_eq!(parse_node("[2001:db8::1]"), ip("2001:db8::1"));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+    }
+
+    #[test]
+    fn test_forwarded_chain() {
+        let chain = forwarded_chain(&headers(&[
+            (
+                "forwarded",
+                "for=192.0.2.1;proto=https, For=\"[2001:db8::1]:80\"",
+            ),
+            ("forwarded", "by=10.0.0.1;for=unknown"),
+            ("x-forwarded-for", "203.0.113.9"),
+        ]));
+        assert_eq!(chain, [ip("192.0.2.1"), ip("2001:db8::1"), None]);
+        // X-Forwarded-For is only read without Forwarded
+        let chain = forwarded_chain(&headers(&[
+            ("x-forwarded-for", "203.0.113.9, 10.0.0.2"),
+            ("x-forwarded-for", "10.0.0.3"),
+        ]));
+        assert_eq!(chain, [ip("203.0.113.9"), ip("10.0.0.2"), ip("10.0.0.3")]);
+        assert!(forwarded_chain(&HeaderMap::new()).is_empty());
+    }
+
+    #[test]
+    fn test_resolve_client_ip() {
+        let firewall: Firewall = toml::from_str(r#"trusted_proxies = ["10.0.0.0/8"]"#).unwrap();
+        let proxy = ip("10.0.0.1");
+        let forwarded = headers(&[("x-forwarded-for", "203.0.113.9, 10.0.0.2")]);
+        // Chain is walked through trusted proxies only
+        assert_eq!(resolve(&firewall, proxy, &forwarded), ip("203.0.113.9"));
+        let spoofed = headers(&[("x-forwarded-for", "10.0.0.5, 198.51.100.1, 10.0.0.2")]);
+        assert_eq!(resolve(&firewall, proxy, &spoofed), ip("198.51.100.1"));
+        // Header of untrusted peer is ignored
+        assert_eq!(
+            resolve(&firewall, ip("192.0.2.7"), &forwarded),
+            ip("192.0.2.7")
+        );
+        assert_eq!(resolve(&firewall, None, &forwarded), None);
+        // Unknown node fails closed
+        let unknown = headers(&[("forwarded", "for=unknown, for=10.0.0.2")]);
+        assert_eq!(resolve(&firewall, proxy, &unknown), None);
+        assert_eq!(resolve(&firewall, proxy, &HeaderMap::new()), proxy);
+
+        let firewall: Firewall = toml::from_str(
+            r#"
+            client_ip_header = "x-real-ip"
+            trusted_proxies = ["10.0.0.1/32"]
+            "#,
+        )
+        .unwrap();
+        let real_ip = headers(&[("x-real-ip", "203.0.113.9")]);
+        assert_eq!(resolve(&firewall, proxy, &real_ip), ip("203.0.113.9"));
+        assert_eq!(resolve(&firewall, proxy, &forwarded), None);
+        assert_eq!(resolve(&firewall, ip("10.0.0.2"), &real_ip), ip("10.0.0.2"));
+
+        // Without trusted proxies header is never honored
+        let firewall: Firewall = toml::from_str(r#"client_ip_header = "x-real-ip""#).unwrap();
+        assert_eq!(
+            resolve(&firewall, ip("192.0.2.7"), &real_ip),
+            ip("192.0.2.7")
+        );
+    }
+
+    #[bench]
+    fn bench_query_response_10k(b: &mut Bencher) {
+        let files = query_result(10_000);
+        b.iter(|| response_body(WebResponse::ok_serialize(test::black_box(&files))));
+    }
+
+    /// Baseline of `bench_query_response_10k`: result converted to `Value` first and whole
+    /// response encoded again as JSON string, as `WebResponse` did before `ok_serialize`
+    #[bench]
+    fn bench_query_response_10k_encoded_twice(b: &mut Bencher) {
+        let files = query_result(10_000);
+        b.iter(|| {
+            let response = WebResponse::ok(Some(json!(test::black_box(&files))));
+            let response = (
+                StatusCode::OK,
+                axum::Json(serde_json::to_string(&response).unwrap()),
+            )
+                .into_response();
+            futures::executor::block_on(hyper::body::to_bytes(response.into_body())).unwrap()
+        });
+    }
+
+    #[bench]
+    fn bench_serialize_files_10k(b: &mut Bencher) {
+        let files = query_result(10_000);
+        b.iter(|| serde_json::to_vec(test::black_box(&files)).unwrap());
+    }
+}
@@ -0,0 +1,230 @@
+mod types {
+    use async_trait::async_trait;
+    use publib::types::ChangeEvent;
+    use serde_derive::Deserialize;
+
+    #[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum SinkKind {
+        Kafka,
+        Nats,
+        Mqtt,
+    }
+
+    /// Serialization of change event in message payload
+    #[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum SinkFormat {
+        #[default]
+        Json,
+        /// MessagePack with field names, same layout as json
+        Msgpack,
+    }
+
+    impl SinkFormat {
+        pub fn encode(&self, event: &ChangeEvent) -> anyhow::Result<Vec<u8>> {
+            Ok(match self {
+                SinkFormat::Json => serde_json::to_vec(event)?,
+                SinkFormat::Msgpack => rmp_serde::to_vec_named(event)?,
+            })
+        }
+    }
+
+    /// Destination of change events published by file daemon
+    #[async_trait]
+    pub trait EventSink: Send + Sync {
+        /// `key` is path of event (`to` if renamed), can be used to partition messages
+        async fn publish(&self, key: &str, payload: Vec<u8>) -> anyhow::Result<()>;
+    }
+}
+
+#[cfg(feature = "kafka")]
+mod kafka {
+    use super::EventSink;
+    use anyhow::anyhow;
+    use async_trait::async_trait;
+    use rdkafka::config::ClientConfig;
+    use rdkafka::producer::{FutureProducer, FutureRecord};
+    use std::time::Duration;
+
+    const QUEUE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    pub struct KafkaSink {
+        producer: FutureProducer,
+        topic: String,
+    }
+
+    impl KafkaSink {
+        /// `brokers` is comma separated list of `host:port`
+        pub fn new(brokers: &str, topic: String) -> anyhow::Result<Self> {
+            let producer = ClientConfig::new()
+                .set("bootstrap.servers", brokers)
+                .create()
+                .map_err(|e| anyhow!("Unable to create kafka producer: {:?}", e))?;
+            Ok(Self { producer, topic })
+        }
+    }
+
+    #[async_trait]
+    impl EventSink for KafkaSink {
+        async fn publish(&self, key: &str, payload: Vec<u8>) -> anyhow::Result<()> {
+            self.producer
+                .send(
+                    FutureRecord::to(&self.topic).key(key).payload(&payload),
+                    QUEUE_TIMEOUT,
+                )
+                .await
+                .map_err(|(e, _)| anyhow!("Unable to deliver kafka message: {:?}", e))?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "nats")]
+mod nats {
+    use super::EventSink;
+    use anyhow::anyhow;
+    use async_trait::async_trait;
+
+    pub struct NatsSink {
+        client: async_nats::Client,
+        subject: String,
+    }
+
+    impl NatsSink {
+        pub async fn connect(url: &str, subject: String) -> anyhow::Result<Self> {
+            let client = async_nats::connect(url)
+                .await
+                .map_err(|e| anyhow!("Unable to connect nats server: {:?}", e))?;
+            Ok(Self { client, subject })
+        }
+    }
+
+    #[async_trait]
+    impl EventSink for NatsSink {
+        async fn publish(&self, _key: &str, payload: Vec<u8>) -> anyhow::Result<()> {
+            self.client
+                .publish(self.subject.clone(), payload.into())
+                .await
+                .map_err(|e| anyhow!("Unable to publish nats message: {:?}", e))
+        }
+    }
+}
+
+#[cfg(feature = "mqtt")]
+mod mqtt {
+    use super::EventSink;
+    use anyhow::anyhow;
+    use async_trait::async_trait;
+    use rumqttc::{AsyncClient, MqttOptions, QoS};
+    use std::time::Duration;
+    use tracing::warn;
+
+    const REQUEST_CAPACITY: usize = 64;
+    const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+    pub struct MqttSink {
+        client: AsyncClient,
+        topic: String,
+    }
+
+    impl MqttSink {
+        /// `url` should contain client id, e.g. `mqtt://localhost:1883?client_id=waffle`
+        pub fn new(url: &str, topic: String) -> anyhow::Result<Self> {
+            let options = MqttOptions::parse_url(url)
+                .map_err(|e| anyhow!("Unable to parse mqtt url: {:?}", e))?;
+            let (client, mut event_loop) = AsyncClient::new(options, REQUEST_CAPACITY);
+            // Requests are only sent while event loop is polled
+            tokio::spawn(async move {
+                loop {
+                    if let Err(e) = event_loop.poll().await {
+                        warn!("Mqtt connection error: {:?}", e);
+                        tokio::time::sleep(RECONNECT_DELAY).await;
+                    }
+                }
+            });
+            Ok(Self { client, topic })
+        }
+    }
+
+    #[async_trait]
+    impl EventSink for MqttSink {
+        async fn publish(&self, _key: &str, payload: Vec<u8>) -> anyhow::Result<()> {
+            self.client
+                .publish(&self.topic, QoS::AtLeastOnce, false, payload)
+                .await
+                .map_err(|e| anyhow!("Unable to publish mqtt message: {:?}", e))
+        }
+    }
+}
+
+mod dispatch {
+    use super::{EventSink, SinkFormat};
+    use crate::configure::current::EventSinkOption;
+    use crate::file::{Change, ChangeStream};
+    use anyhow::anyhow;
+    use publib::types::ChangeEvent;
+    use tracing::{error, warn};
+
+    /// Connect sink described by `option`, fail if its feature is not enabled in this build
+    pub async fn build_sink(option: &EventSinkOption) -> anyhow::Result<Box<dyn EventSink>> {
+        match option.kind() {
+            #[cfg(feature = "kafka")]
+            super::SinkKind::Kafka => Ok(Box::new(super::kafka::KafkaSink::new(
+                option.url(),
+                option.topic().to_string(),
+            )?)),
+            #[cfg(feature = "nats")]
+            super::SinkKind::Nats => Ok(Box::new(
+                super::nats::NatsSink::connect(option.url(), option.topic().to_string()).await?,
+            )),
+            #[cfg(feature = "mqtt")]
+            super::SinkKind::Mqtt => Ok(Box::new(super::mqtt::MqttSink::new(
+                option.url(),
+                option.topic().to_string(),
+            )?)),
+            #[allow(unreachable_patterns)]
+            kind => Err(anyhow!(
+                "Event sink {:?} is configured but not supported by this build",
+                kind
+            )),
+        }
+    }
+
+    fn event_key(event: &ChangeEvent) -> &str {
+        match event {
+            ChangeEvent::New { path }
+            | ChangeEvent::Update { path }
+            | ChangeEvent::Remove { path } => path,
+            ChangeEvent::Rename { to, .. } => to,
+        }
+    }
+
+    /// Publish changes applied by file daemon to `sink` until daemon stopped
+    ///
+    /// Failed message is dropped, sink is expected to retry by itself
+    pub async fn run_sink(sink: Box<dyn EventSink>, format: SinkFormat, mut changes: ChangeStream) {
+        while let Some(change) = changes.recv().await {
+            match change {
+                Change::Event(event) => {
+                    let payload = match format.encode(&event) {
+                        Ok(payload) => payload,
+                        Err(e) => {
+                            error!("Unable to encode change event: {:?}", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = sink.publish(event_key(&event), payload).await {
+                        warn!("{}", e);
+                    }
+                }
+                Change::Missed(count) => {
+                    warn!("Event sink missed {} change event(s)", count);
+                }
+            }
+        }
+    }
+}
+
+pub use dispatch::{build_sink, run_sink};
+pub use types::{EventSink, SinkFormat, SinkKind};
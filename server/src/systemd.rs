@@ -0,0 +1,167 @@
+mod activation {
+    use anyhow::anyhow;
+    use std::env;
+    use std::ffi::OsStr;
+    use std::net::TcpListener;
+    use std::os::fd::{FromRawFd, IntoRawFd, RawFd};
+    #[cfg(target_os = "linux")]
+    use std::os::linux::net::SocketAddrExt;
+    #[cfg(target_os = "linux")]
+    use std::os::unix::ffi::OsStrExt;
+    #[cfg(target_os = "linux")]
+    use std::os::unix::net::SocketAddr;
+    use std::os::unix::net::{UnixDatagram, UnixListener};
+    use tracing::warn;
+
+    /// First file descriptor passed by systemd, see `sd_listen_fds(3)`
+    const LISTEN_FDS_START: RawFd = 3;
+
+    pub const READY: &str = "READY=1";
+    pub const STOPPING: &str = "STOPPING=1";
+
+    /// Stream socket passed by systemd, `ListenStream=` accepts both port and path
+    #[derive(Debug)]
+    pub enum Listener {
+        Tcp(TcpListener),
+        Unix(UnixListener),
+    }
+
+    /// Take ownership of listening socket `fd`, its type is told by its address family
+    pub(super) fn listener_of(fd: RawFd) -> anyhow::Result<Listener> {
+        // Safety: descriptor is owned by this process once systemd passed it
+        let tcp = unsafe { TcpListener::from_raw_fd(fd) };
+        let listener = if tcp.local_addr().is_ok() {
+            Listener::Tcp(tcp)
+        } else {
+            // Safety: descriptor is released by TCP listener above
+            let unix = unsafe { UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+            unix.local_addr()
+                .map_err(|e| anyhow!("Passed socket is neither TCP nor Unix socket: {:?}", e))?;
+            Listener::Unix(unix)
+        };
+        match &listener {
+            Listener::Tcp(listener) => listener.set_nonblocking(true),
+            Listener::Unix(listener) => listener.set_nonblocking(true),
+        }
+        .map_err(|e| anyhow!("Passed socket is not usable: {:?}", e))?;
+        Ok(listener)
+    }
+
+    /// Listener passed by systemd socket activation, `None` if process is not activated,
+    /// only first socket is used
+    ///
+    /// Should be called before any thread is started, variables are removed so they are
+    /// not inherited by child processes
+    pub fn take_listener() -> anyhow::Result<Option<Listener>> {
+        let pid = env::var("LISTEN_PID")
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok());
+        let count = env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|count| count.parse::<i32>().ok())
+            .unwrap_or(0);
+        for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            env::remove_var(name);
+        }
+        if pid != Some(std::process::id()) || count < 1 {
+            return Ok(None);
+        }
+        listener_of(LISTEN_FDS_START).map(Some)
+    }
+
+    /// Path starts with `@` is abstract socket, which only Linux has
+    #[cfg(target_os = "linux")]
+    fn connect(socket: &UnixDatagram, path: &OsStr) -> std::io::Result<()> {
+        match path.as_bytes().strip_prefix(b"@") {
+            Some(name) => socket.connect_addr(&SocketAddr::from_abstract_name(name)?),
+            None => socket.connect(path),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn connect(socket: &UnixDatagram, path: &OsStr) -> std::io::Result<()> {
+        socket.connect(path)
+    }
+
+    pub(super) fn send(path: &OsStr, state: &str) -> std::io::Result<()> {
+        let socket = UnixDatagram::unbound()?;
+        connect(&socket, path)?;
+        socket.send(state.as_bytes())?;
+        Ok(())
+    }
+
+    /// Send `state` to service manager, nothing is done if not started by systemd
+    pub fn notify(state: &str) {
+        let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+            return;
+        };
+        if let Err(e) = send(&path, state) {
+            warn!("Unable to notify systemd {}: {:?}", state, e);
+        }
+    }
+}
+
+pub use activation::{notify, take_listener, Listener, READY, STOPPING};
+
+#[cfg(test)]
+mod test {
+    use super::activation::{listener_of, send};
+    use super::Listener;
+    use std::os::fd::IntoRawFd;
+    use std::os::unix::net::{UnixDatagram, UnixListener};
+
+    /// Directory under `./target` for sockets created by test
+    fn test_dir(name: &str) -> String {
+        let dir = format!("./target/systemd-test-{}-{}", name, std::process::id());
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn passed_socket_type_is_detected() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        match listener_of(tcp.into_raw_fd()).unwrap() {
+            Listener::Tcp(listener) => assert_eq!(listener.local_addr().unwrap(), addr),
+            listener => panic!("Expect TCP listener, got {:?}", listener),
+        }
+
+        let dir = test_dir("listener");
+        let path = format!("{}/web.sock", dir);
+        let unix = UnixListener::bind(&path).unwrap();
+        match listener_of(unix.into_raw_fd()).unwrap() {
+            Listener::Unix(listener) => assert_eq!(
+                listener.local_addr().unwrap().as_pathname(),
+                Some(std::path::Path::new(&path))
+            ),
+            listener => panic!("Expect Unix listener, got {:?}", listener),
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn state_is_sent_to_notify_socket() {
+        let dir = test_dir("notify");
+        let path = format!("{}/notify.sock", dir);
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        send(path.as_ref(), super::READY).unwrap();
+        let mut buffer = [0u8; 64];
+        let len = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], super::READY.as_bytes());
+        assert!(send(format!("{}/missing.sock", dir).as_ref(), super::READY).is_err());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn state_is_sent_to_abstract_socket() {
+        use std::os::linux::net::SocketAddrExt;
+        let name = format!("fantastic-waffle-test-{}", std::process::id());
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap();
+        let receiver = UnixDatagram::bind_addr(&addr).unwrap();
+        send(format!("@{}", name).as_ref(), super::STOPPING).unwrap();
+        let mut buffer = [0u8; 64];
+        let len = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], super::STOPPING.as_bytes());
+    }
+}
@@ -0,0 +1,92 @@
+mod check {
+    use anyhow::anyhow;
+    use serde_derive::Deserialize;
+    use std::time::Duration;
+    use tracing::{debug, warn};
+
+    pub const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Latest release described by manifest, e.g. `{"version": "0.2.0", "url": "..."}`
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct Release {
+        version: String,
+        /// Download or release notes page
+        url: Option<String>,
+    }
+
+    impl Release {
+        pub fn version(&self) -> &str {
+            &self.version
+        }
+        pub fn url(&self) -> Option<&str> {
+            self.url.as_deref()
+        }
+        /// Release is newer than running server
+        pub fn is_newer(&self) -> bool {
+            is_newer(&self.version, CURRENT_VERSION)
+        }
+    }
+
+    /// Numeric components of `x.y.z`, leading `v` and pre-release suffix are ignored
+    fn parse_version(version: &str) -> Vec<u64> {
+        version
+            .trim()
+            .trim_start_matches('v')
+            .split(['-', '+'])
+            .next()
+            .unwrap_or_default()
+            .split('.')
+            .map(|part| part.parse().unwrap_or(0))
+            .collect()
+    }
+
+    fn is_newer(latest: &str, current: &str) -> bool {
+        let (latest, current) = (parse_version(latest), parse_version(current));
+        let length = latest.len().max(current.len());
+        let pad = |version: Vec<u64>| {
+            version
+                .into_iter()
+                .chain(std::iter::repeat(0))
+                .take(length)
+                .collect::<Vec<_>>()
+        };
+        pad(latest) > pad(current)
+    }
+
+    pub async fn fetch_release(url: &str) -> anyhow::Result<Release> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        let body = client
+            .get(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| anyhow!("Unable to fetch release manifest: {:?}", e))?
+            .bytes()
+            .await?;
+        serde_json::from_slice(&body)
+            .map_err(|e| anyhow!("Unable to parse release manifest: {:?}", e))
+    }
+
+    /// Log notice every `interval` while newer release is published, nothing is installed
+    pub async fn check_update_periodically(url: String, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            match fetch_release(&url).await {
+                Ok(release) if release.is_newer() => warn!(
+                    "Release {} is available, running {}",
+                    release.version(),
+                    CURRENT_VERSION
+                ),
+                Ok(_) => debug!("Running latest release {}", CURRENT_VERSION),
+                Err(e) => warn!("Unable to check update: {:?}", e),
+            }
+        }
+    }
+}
+
+pub use check::{check_update_periodically, fetch_release, CURRENT_VERSION};